@@ -0,0 +1,125 @@
+use crate::models::ClientResponseMessage;
+use cdktr_core::{
+    exceptions::GenericError, models::ZMQArgs, utils::get_request_timeout,
+    zmq_helpers::send_recv_with_timeout,
+};
+use std::fmt::Display;
+use zeromq::ZmqMessage;
+
+/// Commands from the principal to an agent. Agents that pull their work don't run a
+/// server of their own, so these are passed back to the agent as the payload of the
+/// response to its heartbeat. Agents in push mode also listen for commands on the
+/// address they registered with
+#[derive(Debug, Clone, PartialEq)]
+pub enum AgentAPI {
+    /// Stop fetching new workflows while letting the in-flight ones finish, or start
+    /// fetching again
+    /// Args:
+    ///     drained: bool
+    SetDrain(bool),
+    /// Run a workflow pushed to the agent by the principal. Agents that are full or
+    /// drained answer with a Retryable response so the principal queues it again
+    /// Args:
+    ///     workflow: the workflow JSON, as sent in response to FETCHWORKFLOW
+    Run(String),
+    /// Stop fetching new workflows and exit once the in-flight ones finish
+    Shutdown,
+    /// How long the principal waits for a heartbeat before it treats the agent as gone,
+    /// sent when the agent registers so it can send heartbeats often enough
+    /// Args:
+    ///     timeout_ms: u64
+    HeartbeatTimeout(u64),
+}
+
+impl AgentAPI {
+    /// Sends the command to the agent listening on `address`
+    pub async fn send_to(self, address: &str) -> Result<ClientResponseMessage, GenericError> {
+        let zmq_msg = send_recv_with_timeout(
+            address.to_string(),
+            ZmqMessage::from(self.to_string()),
+            get_request_timeout(),
+        )
+        .await?;
+        Ok(ClientResponseMessage::from(zmq_msg))
+    }
+}
+
+impl TryFrom<ZMQArgs> for AgentAPI {
+    type Error = GenericError;
+    fn try_from(mut args: ZMQArgs) -> Result<Self, Self::Error> {
+        match args.next().as_deref() {
+            Some("SETDRAIN") => match args.next().as_deref() {
+                Some("true") => Ok(Self::SetDrain(true)),
+                Some("false") => Ok(Self::SetDrain(false)),
+                _ => Err(GenericError::ParseError(
+                    "Arg DRAINED must be true or false".to_string(),
+                )),
+            },
+            Some("SHUTDOWN") => Ok(Self::Shutdown),
+            Some("HEARTBEATTIMEOUT") => match args.next().map(|ms| ms.parse::<u64>()) {
+                Some(Ok(timeout_ms)) => Ok(Self::HeartbeatTimeout(timeout_ms)),
+                _ => Err(GenericError::ParseError(
+                    "Arg TIMEOUT_MS must be a number of milliseconds".to_string(),
+                )),
+            },
+            Some("RUN") => match args.next() {
+                Some(workflow) => Ok(Self::Run(workflow)),
+                None => Err(GenericError::ParseError("Missing arg WORKFLOW".to_string())),
+            },
+            Some(other) => Err(GenericError::ParseError(format!(
+                "Unrecognised agent command: {}",
+                other
+            ))),
+            None => Err(GenericError::ParseError("Empty message".to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for AgentAPI {
+    type Error = GenericError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let args: ZMQArgs = s.into();
+        Self::try_from(args)
+    }
+}
+
+impl TryFrom<ZmqMessage> for AgentAPI {
+    type Error = GenericError;
+    fn try_from(zmq_msg: ZmqMessage) -> Result<Self, Self::Error> {
+        let args: ZMQArgs = zmq_msg.into();
+        Self::try_from(args)
+    }
+}
+
+impl Display for AgentAPI {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SetDrain(drained) => write!(f, "SETDRAIN\x01{drained}"),
+            Self::Run(workflow) => write!(f, "RUN\x01{workflow}"),
+            Self::Shutdown => write!(f, "SHUTDOWN"),
+            Self::HeartbeatTimeout(timeout_ms) => write!(f, "HEARTBEATTIMEOUT\x01{timeout_ms}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_api_round_trip() {
+        for cmd in [
+            AgentAPI::SetDrain(true),
+            AgentAPI::SetDrain(false),
+            AgentAPI::Run(r#"{"name":"etl","tasks":{}}"#.to_string()),
+            AgentAPI::Shutdown,
+            AgentAPI::HeartbeatTimeout(30_000),
+        ] {
+            assert_eq!(AgentAPI::try_from(cmd.to_string()).unwrap(), cmd);
+        }
+        assert!(AgentAPI::try_from("SETDRAIN\x01maybe".to_string()).is_err());
+        assert!(AgentAPI::try_from("REBOOT".to_string()).is_err());
+        assert!(AgentAPI::try_from("RUN".to_string()).is_err());
+        assert!(AgentAPI::try_from("HEARTBEATTIMEOUT\x01soon".to_string()).is_err());
+    }
+}
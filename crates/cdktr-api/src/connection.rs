@@ -0,0 +1,221 @@
+use cdktr_core::exceptions::{ErrorKind, GenericError};
+use log::{info, warn};
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::sleep;
+
+#[derive(Debug)]
+struct ConnectionState {
+    connected: AtomicBool,
+    losses: AtomicUsize,
+    reconnects: AtomicUsize,
+}
+
+/// Tracks the connection of a client with the principal across requests so that a
+/// flapping connection shows up in the logs and counters rather than only as retries.
+/// Clones share the same state
+#[derive(Debug, Clone)]
+pub struct ConnectionMonitor {
+    state: Arc<ConnectionState>,
+}
+
+impl Default for ConnectionMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionMonitor {
+    /// Starts out assuming the connection is up
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(ConnectionState {
+                connected: AtomicBool::new(true),
+                losses: AtomicUsize::new(0),
+                reconnects: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.connected.load(Ordering::SeqCst)
+    }
+
+    /// Number of times the connection has gone from up to down
+    pub fn losses(&self) -> usize {
+        self.state.losses.load(Ordering::SeqCst)
+    }
+
+    /// Number of times the connection has come back after being lost
+    pub fn reconnects(&self) -> usize {
+        self.state.reconnects.load(Ordering::SeqCst)
+    }
+
+    fn record_failure(&self, error: &GenericError) {
+        if self.state.connected.swap(false, Ordering::SeqCst) {
+            let losses = self.state.losses.fetch_add(1, Ordering::SeqCst) + 1;
+            warn!(
+                "connection_lost: lost connection with principal ({}). Connection lost {} time(s)",
+                error, losses
+            );
+        }
+    }
+
+    fn record_success(&self) {
+        if !self.state.connected.swap(true, Ordering::SeqCst) {
+            let reconnects = self.state.reconnects.fetch_add(1, Ordering::SeqCst) + 1;
+            info!(
+                "connection_restored: re-connected with principal. Reconnected {} time(s)",
+                reconnects
+            );
+        }
+    }
+}
+
+/// Whether the error means the principal couldn't be reached, as opposed to the
+/// principal rejecting the request, so the request is worth retrying
+pub fn is_connection_error(error: &GenericError) -> bool {
+    matches!(error, GenericError::PrincipalTimeoutError) || error.kind() == ErrorKind::Connection
+}
+
+/// Makes a request, retrying up to `max_attempts` times with `delay` in between while the
+/// principal can't be reached. Connection losses and recoveries are recorded on `monitor`.
+/// Any other error is returned straight away
+pub async fn retry_with_monitor<T, F, Fut>(
+    monitor: &ConnectionMonitor,
+    max_attempts: usize,
+    delay: Duration,
+    mut request: F,
+) -> Result<T, GenericError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, GenericError>>,
+{
+    let mut attempts = 0;
+    loop {
+        match request().await {
+            Ok(response) => {
+                if attempts > 0 {
+                    info!(
+                        "Successfully re-connected with principal after {} attempt(s)",
+                        attempts
+                    );
+                }
+                monitor.record_success();
+                return Ok(response);
+            }
+            Err(e) if is_connection_error(&e) => {
+                monitor.record_failure(&e);
+                attempts += 1;
+                if attempts >= max_attempts {
+                    warn!(
+                        "Max retry attempts ({}) reached - connection with principal has been lost",
+                        max_attempts
+                    );
+                    return Err(e);
+                }
+                warn!(
+                    "Failed to communicate to principal ({}) - trying again in {} ms (attempt {} of {})",
+                    e,
+                    delay.as_millis(),
+                    attempts,
+                    max_attempts
+                );
+                sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails with a timeout `failures` times before succeeding
+    fn flaky(failures: usize) -> impl FnMut() -> std::future::Ready<Result<(), GenericError>> {
+        let mut calls = 0;
+        move || {
+            calls += 1;
+            std::future::ready(if calls <= failures {
+                Err(GenericError::PrincipalTimeoutError)
+            } else {
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_counted_once_per_outage() {
+        let monitor = ConnectionMonitor::new();
+        retry_with_monitor(&monitor, 5, Duration::ZERO, flaky(3))
+            .await
+            .unwrap();
+        assert_eq!(monitor.losses(), 1);
+        assert_eq!(monitor.reconnects(), 1);
+        assert!(monitor.is_connected());
+
+        // a healthy request doesn't count as a reconnect
+        retry_with_monitor(&monitor, 5, Duration::ZERO, flaky(0))
+            .await
+            .unwrap();
+        assert_eq!(monitor.reconnects(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connection_lost_across_requests() {
+        let monitor = ConnectionMonitor::new();
+        let shared = monitor.clone();
+        assert!(
+            retry_with_monitor(&monitor, 2, Duration::ZERO, flaky(2))
+                .await
+                .is_err()
+        );
+        assert!(!shared.is_connected());
+        // still down on the next request so it isn't a new loss
+        assert!(
+            retry_with_monitor(&monitor, 2, Duration::ZERO, flaky(2))
+                .await
+                .is_err()
+        );
+        assert_eq!(shared.losses(), 1);
+        retry_with_monitor(&monitor, 2, Duration::ZERO, flaky(0))
+            .await
+            .unwrap();
+        assert_eq!(shared.reconnects(), 1);
+        // flapping again is a second loss
+        retry_with_monitor(&monitor, 2, Duration::ZERO, flaky(1))
+            .await
+            .unwrap();
+        assert_eq!(shared.losses(), 2);
+        assert_eq!(shared.reconnects(), 2);
+    }
+
+    #[test]
+    fn test_connection_errors_matched_on_kind() {
+        let dropped: GenericError =
+            zeromq::ZmqError::Network(std::io::Error::from(std::io::ErrorKind::ConnectionReset))
+                .into();
+        assert!(is_connection_error(&dropped));
+        assert!(is_connection_error(&GenericError::PrincipalTimeoutError));
+        assert!(!is_connection_error(&GenericError::ParseError(
+            "Connection reset by peer".to_string()
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_other_errors_not_retried() {
+        let monitor = ConnectionMonitor::new();
+        let mut calls = 0;
+        let res: Result<(), GenericError> = retry_with_monitor(&monitor, 5, Duration::ZERO, || {
+            calls += 1;
+            std::future::ready(Err(GenericError::RuntimeError("bad".to_string())))
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+        assert_eq!(monitor.losses(), 0);
+    }
+}
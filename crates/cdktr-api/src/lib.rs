@@ -1,6 +1,22 @@
+mod agent;
+mod connection;
 mod principal;
 mod traits;
 
 pub mod models;
+pub use agent::AgentAPI;
+pub use connection::{ConnectionMonitor, is_connection_error, retry_with_monitor};
 pub use principal::PrincipalAPI;
 pub use traits::{API, APIMeta};
+
+/// Version of the wire format of the principal API. Agents send this when they register
+/// so the principal can reject agents that speak a different version. Bump this
+/// whenever a message of the `PrincipalAPI` changes in a way older versions can't parse.
+///
+/// New arguments are appended as optional trailing arguments, so a message from an older
+/// agent without them still parses, but an older principal can't parse them or the escaped
+/// arguments. Versions:
+///  - 1: the first versioned wire format
+///  - 2: escaped arguments, the `NOTFOUND` response, the executors sent by `REGISTERAGENT`
+///    and the `truncated` flag of `TASKSTATUSUPDATE`
+pub const PROTOCOL_VERSION: u32 = 2;
@@ -1,14 +1,23 @@
+use std::collections::HashMap;
+
 use cdktr_db::impl_dbrecordbatch;
 use serde::{Deserialize, Serialize};
 use zeromq::ZmqMessage;
 
-use cdktr_core::models::ZMQArgs;
+use cdktr_core::exceptions::{ErrorKind, GenericError};
+use cdktr_core::models::{RunStatus, TaskInstanceId, WorkflowInstanceId, ZMQArgs};
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct AgentInfo {
     pub agent_id: String,
     pub last_ping_timestamp: i64,
     pub running_tasks: usize,
+    /// Drained agents finish their running workflows but don't fetch new ones
+    #[serde(default)]
+    pub drained: bool,
+    /// Latest metrics pushed by the agent. None until the agent first reports them
+    #[serde(default)]
+    pub metrics: Option<AgentMetrics>,
 }
 
 impl AgentInfo {
@@ -17,21 +26,62 @@ impl AgentInfo {
             agent_id,
             last_ping_timestamp,
             running_tasks,
+            drained: false,
+            metrics: None,
         }
     }
+
+    pub fn with_drained(mut self, drained: bool) -> Self {
+        self.drained = drained;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Option<AgentMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+}
+
+/// How loaded an agent is, pushed by the agent to the principal periodically
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct AgentMetrics {
+    /// When the agent collected the metrics
+    pub timestamp_ms: i64,
+    pub running_workflows: usize,
+    /// CPU usage of the agent's host across all cores, from 0 to 100
+    pub cpu_percent: f32,
+    /// Memory in use on the agent's host
+    pub memory_used_bytes: u64,
+    pub memory_total_bytes: u64,
+    /// Number of running instances of each task, keyed by `workflow_id/task_id`
+    pub running_tasks: HashMap<String, usize>,
+}
+
+/// Aggregate capacity of the agent fleet and the pressure on it, which is what an
+/// autoscaler scales the agents on
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ClusterCapacity {
+    pub agents: usize,
+    /// workflows the registered agents can run at once. Agents that don't report
+    /// their max concurrency don't add any slots
+    pub total_slots: usize,
+    /// workflows currently running across the agents
+    pub used_slots: usize,
+    /// workflows waiting on the principal queue for an agent
+    pub queued: usize,
 }
 
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct WorkflowStatusUpdate {
     workflow_id: String,
-    workflow_instance_id: String,
+    workflow_instance_id: WorkflowInstanceId,
     status: String,
     timestamp_ms: u64,
 }
 impl WorkflowStatusUpdate {
     pub fn new(
         workflow_id: String,
-        workflow_instance_id: String,
+        workflow_instance_id: WorkflowInstanceId,
         status: String,
         timestamp_ms: u64,
     ) -> Self {
@@ -47,7 +97,7 @@ impl WorkflowStatusUpdate {
         &self.workflow_id
     }
 
-    pub fn workflow_instance_id(&self) -> &str {
+    pub fn workflow_instance_id(&self) -> &WorkflowInstanceId {
         &self.workflow_instance_id
     }
 
@@ -71,16 +121,16 @@ impl_dbrecordbatch!(
 #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct TaskStatusUpdate {
     task_id: String,
-    task_instance_id: String,
-    workflow_instance_id: String,
+    task_instance_id: TaskInstanceId,
+    workflow_instance_id: WorkflowInstanceId,
     status: String,
     timestamp_ms: u64,
 }
 impl TaskStatusUpdate {
     pub fn new(
         task_id: String,
-        task_instance_id: String,
-        workflow_instance_id: String,
+        task_instance_id: TaskInstanceId,
+        workflow_instance_id: WorkflowInstanceId,
         status: String,
         timestamp_ms: u64,
     ) -> Self {
@@ -97,11 +147,11 @@ impl TaskStatusUpdate {
         &self.task_id
     }
 
-    pub fn task_instance_id(&self) -> &str {
+    pub fn task_instance_id(&self) -> &TaskInstanceId {
         &self.task_instance_id
     }
 
-    pub fn workflow_instance_id(&self) -> &str {
+    pub fn workflow_instance_id(&self) -> &WorkflowInstanceId {
         &self.workflow_instance_id
     }
 
@@ -123,18 +173,193 @@ impl_dbrecordbatch!(
     }
 );
 
-#[derive(Debug)]
+/// An administrative action taken on the principal, e.g. draining an agent or flushing
+/// the queue
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp_ms: u64,
+    /// Who took the action as sent by the client, e.g. `user@host`. `unknown` for
+    /// clients that don't send one
+    pub actor: String,
+    pub action: String,
+    /// What the action was taken on, e.g. an agent id. Empty for actions on the
+    /// principal itself
+    pub target: String,
+    /// Response code the principal answered the request with
+    pub result: String,
+    /// Identity of the connection the request came in on
+    pub client: String,
+}
+impl_dbrecordbatch!(
+    AuditEntry, Vec<AuditEntry>, {
+        timestamp_ms => UInt64,
+        actor => Utf8,
+        action => Utf8,
+        target => Utf8,
+        result => Utf8,
+        client => Utf8,
+    }
+);
+
+/// Outcome of a single task within a workflow run
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TaskResult {
+    pub task_id: String,
+    pub task_instance_id: String,
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub duration_ms: Option<i64>,
+    /// Last lines of output from the task, oldest first
+    pub output_tail: Vec<String>,
+    /// Latest progress reported by the task, if it has reported any
+    #[serde(default)]
+    pub progress: Option<TaskProgress>,
+    /// Files matching the `artifacts` globs of the task once it finished
+    #[serde(default)]
+    pub artifacts: Vec<ArtifactInfo>,
+    /// Whether output of the task was dropped for going over the agent's output limit
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+/// A file a task left behind that matched one of its `artifacts` globs
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ArtifactInfo {
+    /// Path of the file on the agent that ran the task
+    pub path: String,
+    pub size_bytes: u64,
+}
+
+/// Progress reported by a long-running task while it runs
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TaskProgress {
+    /// How far through the task is, from 0 to 100
+    pub percent: u8,
+    pub message: String,
+}
+
+/// Number of the tasks of a workflow run that have finished, reported by the agent
+/// as the run goes
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct WorkflowProgress {
+    /// Tasks that have succeeded, failed or been skipped
+    pub tasks_completed: usize,
+    pub tasks_total: usize,
+}
+
+/// Aggregated outcome of a workflow run and each of its tasks
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct WorkflowResult {
+    pub workflow_id: String,
+    pub workflow_instance_id: String,
+    pub status: String,
+    /// Time from the run starting to it finishing, if it has finished
+    #[serde(default)]
+    pub duration_ms: Option<i64>,
+    /// Whether the run took longer than the SLA of its workflow. None if the
+    /// workflow has no SLA or the run hasn't finished
+    #[serde(default)]
+    pub sla_breached: Option<bool>,
+    /// Instance id of the first run of the workflow when this run is a retry of it
+    #[serde(default)]
+    pub retry_of: Option<String>,
+    /// Attempt number of the run when it is a retry, from 2
+    #[serde(default)]
+    pub attempt: Option<u32>,
+    /// Notes added to the run by operators after it was submitted, e.g. an incident reference
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+    /// Latest task counts reported by the agent running the workflow, if it has reported any
+    #[serde(default)]
+    pub progress: Option<WorkflowProgress>,
+    pub tasks: Vec<TaskResult>,
+}
+
+impl WorkflowResult {
+    /// Whether the run has completed, failed or crashed
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            RunStatus::try_from(self.status.clone()),
+            Ok(RunStatus::COMPLETED | RunStatus::FAILED | RunStatus::CRASHED)
+        )
+    }
+}
+
+/// Why a run hasn't been sent to an agent yet
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct DispatchDiagnosis {
+    pub workflow_id: String,
+    pub workflow_instance_id: String,
+    /// Position of the run on the queue, from 1. None if it isn't queued
+    pub queue_position: Option<usize>,
+    pub queue_length: usize,
+    /// Latest status of the run when it isn't queued
+    #[serde(default)]
+    pub status: Option<String>,
+    /// Registered agents
+    pub agents: usize,
+    /// Agents that run every executor the tasks of the workflow need
+    pub matching_agents: usize,
+    /// Matching agents the run could be sent to now
+    pub available_agents: usize,
+    /// Executors the workflow needs that no registered agent runs
+    #[serde(default)]
+    pub missing_executors: Vec<String>,
+    /// Instance id of the run of the singleton workflow the run is waiting on
+    #[serde(default)]
+    pub blocked_by: Option<String>,
+    /// Everything holding the run back, in words
+    pub reasons: Vec<String>,
+}
+
+/// A page of a log query, ordered by timestamp
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LogPage {
+    /// Number of log records to skip
+    pub offset: usize,
+    /// Maximum number of log records in the page
+    pub limit: usize,
+}
+
+impl LogPage {
+    /// Reads the optional trailing offset and limit args of a log query
+    pub fn from_args(args: &mut ZMQArgs) -> Result<Option<Self>, GenericError> {
+        match (args.next(), args.next()) {
+            (None, None) => Ok(None),
+            (Some(offset), Some(limit)) => Ok(Some(Self {
+                offset: offset.parse().map_err(|_e| {
+                    GenericError::ParseError("Not a valid page offset".to_string())
+                })?,
+                limit: limit
+                    .parse()
+                    .map_err(|_e| GenericError::ParseError("Not a valid page limit".to_string()))?,
+            })),
+            _ => Err(GenericError::ParseError(
+                "A page needs both an offset and a limit".to_string(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
 pub enum RepReqError {
+    #[error("PARSE ERROR: {0}")]
     ParseError(String),
+    #[error("UNPROCESSABLE: {0}")]
     Unprocessable(String),
+    #[error("SERVER ERROR: {0}")]
     ServerError(String),
+    /// An error from below the API layer, kept as is so its kind isn't lost
+    #[error(transparent)]
+    Generic(#[from] GenericError),
 }
 impl RepReqError {
-    pub fn to_string(&self) -> String {
+    pub fn kind(&self) -> ErrorKind {
         match self {
-            Self::ParseError(pl) => format!("PARSE ERROR: {}", pl),
-            Self::Unprocessable(pl) => format!("UNPROCESSABLE: {}", pl),
-            Self::ServerError(pl) => format!("SERVER ERROR: {}", pl),
+            Self::ParseError(_) => ErrorKind::Parse,
+            Self::Unprocessable(_) => ErrorKind::Unprocessable,
+            Self::ServerError(_) => ErrorKind::Server,
+            Self::Generic(e) => e.kind(),
         }
     }
 }
@@ -143,8 +368,12 @@ impl RepReqError {
 #[derive(PartialEq, Debug)]
 pub enum ClientResponseMessage {
     ClientError(String),
+    /// The id in the request (of a workflow, workflow run, agent etc) doesn't exist
+    NotFound(String),
     ServerError(String),
     Unprocessable(String),
+    /// The request can't be processed right now but may succeed if sent again later
+    Retryable(String),
     Pong,
     Success,
     SuccessWithPayload(String),
@@ -159,12 +388,29 @@ impl ClientResponseMessage {
             Self::SuccessWithPayload(payload) => format!("SUCCESS\x01{payload}"),
 
             Self::ClientError(payload) => format!("CLIENTERROR\x01{payload}"),
+            Self::NotFound(payload) => format!("NOTFOUND\x01{payload}"),
             Self::ServerError(payload) => format!("SERVERERROR\x01{payload}"),
             Self::Unprocessable(payload) => format!("UNPROC\x01{payload}"),
+            Self::Retryable(payload) => format!("RETRYABLE\x01{payload}"),
             Self::NetworkError(payload) => format!("NETWORKERROR\x01{payload}"),
         }
     }
 
+    /// The token that denotes the message type on the wire, e.g. `OK` or `CLIENTERROR`
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Pong => "PONG",
+            Self::Success => "OK",
+            Self::SuccessWithPayload(_) => "SUCCESS",
+            Self::ClientError(_) => "CLIENTERROR",
+            Self::NotFound(_) => "NOTFOUND",
+            Self::ServerError(_) => "SERVERERROR",
+            Self::Unprocessable(_) => "UNPROC",
+            Self::Retryable(_) => "RETRYABLE",
+            Self::NetworkError(_) => "NETWORKERROR",
+        }
+    }
+
     /// Convenience method used to unpack a client message payload into just the string without
     /// the initial token that's used to denote the message type. If the message does not have a
     /// payload then just an empty string is returned
@@ -175,8 +421,10 @@ impl ClientResponseMessage {
             Self::SuccessWithPayload(pl) => pl.clone(),
 
             Self::ClientError(pl) => pl.clone(),
+            Self::NotFound(pl) => pl.clone(),
             Self::ServerError(pl) => pl.clone(),
             Self::Unprocessable(pl) => pl.clone(),
+            Self::Retryable(pl) => pl.clone(),
             Self::NetworkError(pl) => pl.clone(),
         }
     }
@@ -192,8 +440,10 @@ impl From<ZmqMessage> for ClientResponseMessage {
         };
         match msg_type.as_str() {
             "CLIENTERROR" => Self::ClientError(args.to_string()),
+            "NOTFOUND" => Self::NotFound(args.to_string()),
             "SERVERERROR" => Self::ServerError(args.to_string()),
             "UNPROC" => Self::Unprocessable(args.to_string()),
+            "RETRYABLE" => Self::Retryable(args.to_string()),
             "PONG" => Self::Pong,
             "OK" => Self::Success,
             "SUCCESS" => Self::SuccessWithPayload(args.to_string()),
@@ -219,7 +469,30 @@ impl Into<ZmqMessage> for ClientResponseMessage {
 mod tests {
     use zeromq::ZmqMessage;
 
-    use super::{AgentInfo, ClientResponseMessage};
+    use super::{
+        AgentInfo, ClientResponseMessage, RepReqError, TaskStatusUpdate, WorkflowStatusUpdate,
+    };
+    use crate::{APIMeta, PrincipalAPI};
+    use cdktr_core::exceptions::{ErrorKind, GenericError};
+
+    #[test]
+    fn test_rep_req_error_keeps_kind_of_cause() {
+        let err: RepReqError = GenericError::PrincipalTimeoutError.into();
+        assert_eq!(err.kind(), ErrorKind::Timeout);
+        assert_eq!(
+            err.to_string(),
+            GenericError::PrincipalTimeoutError.to_string()
+        );
+
+        // a parse failure raised in the core layer surfaces through the API as a parse error
+        let meta = APIMeta::new("NOTAMESSAGE".to_string(), String::new());
+        let err = meta.try_to_api::<PrincipalAPI>().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+        assert!(matches!(
+            err,
+            RepReqError::Generic(GenericError::ParseError(_))
+        ));
+    }
 
     #[test]
     fn test_agent_info_serialization() {
@@ -269,4 +542,62 @@ mod tests {
             _ => panic!("Expected only success payload for this test"),
         }
     }
+
+    #[test]
+    fn test_client_message_not_found_round_trip() {
+        let msg = ClientResponseMessage::NotFound("No workflow exists with id my.flow".to_string());
+        let zmq_m: ZmqMessage = msg.into();
+        let cli_msg = ClientResponseMessage::from(zmq_m);
+        assert_eq!(
+            cli_msg,
+            ClientResponseMessage::NotFound("No workflow exists with id my.flow".to_string())
+        );
+        assert_eq!(cli_msg.payload(), "No workflow exists with id my.flow");
+    }
+
+    #[test]
+    fn test_client_message_retryable_round_trip() {
+        let msg = ClientResponseMessage::Retryable("already running".to_string());
+        let zmq_m: ZmqMessage = msg.into();
+        assert_eq!(
+            ClientResponseMessage::from(zmq_m),
+            ClientResponseMessage::Retryable("already running".to_string())
+        );
+    }
+
+    #[test]
+    fn test_status_updates_serialise_instance_ids_as_strings() {
+        let update = WorkflowStatusUpdate::new(
+            "my.flow".to_string(),
+            "jumping-monkey-0".into(),
+            "RUNNING".to_string(),
+            1_000,
+        );
+        let json = serde_json::to_string(&update).unwrap();
+        assert_eq!(
+            json,
+            r#"{"workflow_id":"my.flow","workflow_instance_id":"jumping-monkey-0","status":"RUNNING","timestamp_ms":1000}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<WorkflowStatusUpdate>(&json).unwrap(),
+            update
+        );
+
+        let update = TaskStatusUpdate::new(
+            "extract".to_string(),
+            "task-ins-1".into(),
+            "jumping-monkey-0".into(),
+            "COMPLETED".to_string(),
+            2_000,
+        );
+        let json = serde_json::to_string(&update).unwrap();
+        assert_eq!(
+            json,
+            r#"{"task_id":"extract","task_instance_id":"task-ins-1","workflow_instance_id":"jumping-monkey-0","status":"COMPLETED","timestamp_ms":2000}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<TaskStatusUpdate>(&json).unwrap(),
+            update
+        );
+    }
 }
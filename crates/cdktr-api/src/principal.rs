@@ -1,10 +1,14 @@
+use super::models::{AgentMetrics, ArtifactInfo, LogPage};
 use super::traits::{API, APIMeta};
+use std::collections::HashMap;
+use std::time::Duration;
 use zeromq::ZmqMessage;
 
 use cdktr_core::{
     exceptions::GenericError,
-    models::{RunStatus, ZMQArgs},
-    utils::get_principal_uri,
+    models::{RunStatus, TaskInstanceId, WorkflowInstanceId, ZMQArgs},
+    utils::{get_principal_uri, get_request_timeout},
+    zmq_helpers::escape_zmq_arg,
 };
 
 #[derive(Debug, Clone)]
@@ -17,31 +21,97 @@ pub enum PrincipalAPI {
     /// work queue to be picked up by a agent worker.
     /// Args:
     ///     task_id: String
-    RunTask(String),
+    ///     params: values for the params declared by the workflow. Sent as a
+    ///         JSON object and omitted from the message when empty
+    ///     reject_without_agents: refuse the run with a Retryable response when no
+    ///         agents are registered rather than holding it on the queue until one is.
+    ///         Omitted from the message when false
+    ///     environment: one of the `environments` of the workflow to run it as. Omitted
+    ///         from the message when not given
+    RunTask(String, HashMap<String, String>, bool, Option<String>),
+    /// Queues a dry run of a workflow. The agent that picks it up logs the resolved
+    /// command of each task in DAG order instead of running it, and the run is
+    /// reported as COMPLETED
+    /// Args:
+    ///     task_id: String
+    ///     params: as for RunTask
+    DryRunTask(String, HashMap<String, String>),
     /// Allows an agent to register itself with the principal
     /// can register its presence. If the agent
     /// is already registered then this behaves in a similar way to
     /// a PING/PONG
     /// Args:
     ///     agent_id
-    RegisterAgent(String),
+    ///     protocol_version (optional): the `PROTOCOL_VERSION` the agent was built with.
+    ///         Registrations from a different version are rejected
+    ///     max_concurrency (optional): number of workflows the agent runs at once
+    ///     executors (optional): comma-separated executors the agent runs tasks with.
+    ///         Agents that don't send them are assumed to run every executor
+    ///     push_address (optional): address the agent listens on for workflows pushed
+    ///         to it by a principal in push mode. Agents without one fetch their work
+    ///     session (optional): token picked by the agent process when it starts, so two
+    ///         agents registering with the same id can be told apart
+    RegisterAgent(
+        String,
+        Option<u32>,
+        Option<usize>,
+        Option<Vec<String>>,
+        Option<String>,
+        Option<String>,
+    ),
     /// Allows an agent to update the principal with the status of a specific
     /// workflow
     /// Args:
     ///     agent_id, workflow_id, workflow_instance_id, status
-    WorkflowStatusUpdate(String, String, String, RunStatus),
+    WorkflowStatusUpdate(String, String, WorkflowInstanceId, RunStatus),
     /// Allows an agent to update the principal with the status of a specific
     /// task
     /// Args:
-    ///     agent_id, task_id, task_execution_id, workflow_instance_id, status
-    TaskStatusUpdate(String, String, String, String, RunStatus),
+    ///     agent_id, task_id, task_execution_id, workflow_instance_id, status,
+    ///     exit_code (optional): exit code of the task process once it has finished,
+    ///     truncated (optional): whether output of the task was dropped for going over
+    ///     the agent's output limit. Defaults to false
+    TaskStatusUpdate(
+        String,
+        String,
+        TaskInstanceId,
+        WorkflowInstanceId,
+        RunStatus,
+        Option<i32>,
+        bool,
+    ),
+    /// Allows an agent to report the progress of a running task. The latest progress
+    /// of each task is included in its `GetWorkflowResult`
+    /// Args:
+    ///     agent_id, task_execution_id, percent (0-100), message
+    TaskProgress(String, TaskInstanceId, u8, String),
+    /// Allows an agent to report the files matching the `artifacts` globs of a task
+    /// once it has finished. They are listed in its `GetWorkflowResult`
+    /// Args:
+    ///     agent_id, task_execution_id, artifacts: sent as a JSON array
+    TaskArtifacts(String, TaskInstanceId, Vec<ArtifactInfo>),
+    /// Allows an agent to report how many of the tasks of a running workflow have
+    /// finished. The latest counts are included in its `GetWorkflowResult`
+    /// Args:
+    ///     agent_id, workflow_instance_id, tasks_completed, tasks_total
+    WorkflowProgress(String, WorkflowInstanceId, usize, usize),
+    /// Allows an agent to report the named outputs of a successful workflow run. The
+    /// principal keeps them to substitute into the next run of the workflow
+    /// Args:
+    ///     agent_id, workflow_id, workflow_instance_id,
+    ///     outputs: output name to value. Sent as a JSON object
+    WorkflowOutputs(String, String, WorkflowInstanceId, HashMap<String, String>),
     /// An endpoint that can be polled for work by Agents. Agents provide their
     /// instance id token (agent_id) and if there is work available on the task queue
     /// then the principal will pop a task from the global queue and provide it to the agent
     /// if not, it will just send a simple Success (OK) message
     /// Args:
     ///     agent_id
-    FetchWorkflow(String),
+    ///     long_poll_timeout_ms (optional): hold the request open for up to this long
+    ///         waiting for a workflow to become available before responding.
+    ///         Responds immediately if not set. The principal holds the request for
+    ///         no longer than its CDKTR_MAX_LONG_POLL_MS.
+    FetchWorkflow(String, Option<u64>),
     /// Run a query to read logs from the database
     /// Args:
     ///     end_timestamp_ms (optional): filter to results older than this timestamp.
@@ -52,18 +122,111 @@ pub enum PrincipalAPI {
     ///         if not set.
     ///     workflow_instance_id (optional): filter results by a specific workflow instance.
     ///         returns any if not set.
-    ///     verbose: Full instance names in logs
+    ///     verbose: Full instance names in logs. Logs are returned as records, so this is
+    ///         only used by the client formatting them
+    ///     page (optional): only return this page of the logs, ordered by timestamp
     QueryLogs(
         Option<u64>,
         Option<u64>,
         Option<String>,
-        Option<String>,
+        Option<WorkflowInstanceId>,
         bool,
+        Option<LogPage>,
     ),
     /// Get recent workflow status updates (last 10 workflows)
     GetRecentWorkflowStatuses,
     /// Get list of all registered agents with their metadata
     GetRegisteredAgents,
+    /// Get the aggregated result of a workflow run, including the status, exit code,
+    /// duration and a tail of the output of each task
+    /// Args:
+    ///     workflow_instance_id
+    GetWorkflowResult(WorkflowInstanceId),
+    /// Get the most recent log lines of a workflow run, oldest first. Useful for a quick
+    /// look at a running workflow without subscribing to its logs
+    /// Args:
+    ///     workflow_instance_id, n: number of lines
+    GetWorkflowTail(WorkflowInstanceId, usize),
+    /// Get the size and enqueue/dequeue rates of the principal task queue, and the number
+    /// of workflow runs that breached their SLA since the principal started
+    GetQueueMetrics,
+    /// Get the aggregate workflow slots of the registered agents and the number of
+    /// workflows waiting on the queue, for autoscaling the agent fleet
+    GetClusterCapacity,
+    /// Drains an agent so it finishes its running workflows but doesn't fetch new ones,
+    /// or makes a drained agent schedulable again. The agent is told on its next heartbeat
+    /// Args:
+    ///     agent_id, drained
+    ///     actor (optional): who is taking the action, e.g. `user@host`, for the audit log
+    DrainAgent(String, bool, Option<String>),
+    /// Removes every workflow waiting on the queue, e.g. after a bad bulk submit.
+    /// Returns the number of workflows removed. Workflows already running are unaffected
+    /// Args:
+    ///     actor (optional): as for DrainAgent
+    FlushQueue(Option<String>),
+    /// Adds an annotation to a workflow run, e.g. a reference to the incident it's being
+    /// investigated under. Annotating a key again replaces its value. Annotations are
+    /// included in the `GetWorkflowResult` of the run
+    /// Args:
+    ///     workflow_instance_id, key, value
+    AnnotateRun(WorkflowInstanceId, String, String),
+    /// Allows an agent to upload a chunk of a file produced by a task so tasks later in
+    /// the workflow run can consume it. Chunks are base64 encoded and sent in order; a chunk
+    /// at offset 0 replaces any earlier upload of the artifact
+    /// Args:
+    ///     workflow_instance_id, path, offset, base64 chunk
+    PutArtifact(WorkflowInstanceId, String, usize, String),
+    /// Allows an agent to download the chunk of an artifact starting at an offset.
+    /// Returns the base64 encoded chunk, or a success message with no payload once
+    /// the offset reaches the end of the artifact
+    /// Args:
+    ///     workflow_instance_id, path, offset
+    GetArtifact(WorkflowInstanceId, String, usize),
+    /// Allows an agent to periodically report how loaded it is. The principal keeps the
+    /// latest metrics of each agent and includes them in `GetRegisteredAgents`
+    /// Args:
+    ///     agent_id, metrics: sent as a JSON object
+    AgentMetrics(String, AgentMetrics),
+    /// Runs a past workflow run again exactly as it ran, from the snapshot of the workflow
+    /// and params taken when it was queued rather than the current workflow definition.
+    /// Returns the instance id of the new run, which is annotated as a `replay_of` the
+    /// original
+    /// Args:
+    ///     workflow_instance_id
+    ReplayRun(WorkflowInstanceId),
+    /// Re-reads the config file and env of the principal and applies the settings that can
+    /// be changed without a restart. Returns the settings that changed as a JSON array,
+    /// with whether each was applied or needs a restart
+    /// Args:
+    ///     actor (optional): as for DrainAgent
+    ReloadConfig(Option<String>),
+    /// Explains why a queued run hasn't been sent to an agent yet: where it is on the
+    /// queue, which agents could run it and what is holding it back. Returned as JSON
+    /// Args:
+    ///     workflow_instance_id
+    DiagnoseDispatch(WorkflowInstanceId),
+    /// Returns the latest administrative actions taken on the principal, newest first,
+    /// as a JSON array
+    /// Args:
+    ///     limit
+    GetAuditLog(usize),
+    /// Stops an agent remotely. The agent is drained and told to shut down with the response
+    /// to its next heartbeat, then exits once its running workflows finish
+    /// Args:
+    ///     agent_id
+    ///     actor (optional): as for DrainAgent
+    StopAgent(String, Option<String>),
+    /// Allows an agent that was stopped to confirm it has finished its running workflows
+    /// and is exiting, so the principal deregisters it
+    /// Args:
+    ///     agent_id
+    AgentStopped(String),
+    /// Allows a scheduler to take or renew the lease that lets it fire scheduled workflows,
+    /// so that only one scheduler fires them at a time. The lease is granted if it is free,
+    /// has expired or is already held by the scheduler, and lasts for ttl_ms
+    /// Args:
+    ///     scheduler_id, ttl_ms
+    AcquireSchedulerLease(String, u64),
 }
 
 impl TryFrom<ZMQArgs> for PrincipalAPI {
@@ -77,33 +240,88 @@ impl TryFrom<ZMQArgs> for PrincipalAPI {
         match msg_type.as_str() {
             "PING" => Ok(Self::Ping),
             "LSWORKFLOWS" => Ok(Self::ListWorkflowStore),
-            "RUNTASK" => Ok(Self::RunTask(helpers::create_run_task_payload(args)?)),
+            "RUNTASK" => {
+                let (task_id, params) = helpers::create_run_task_payload(&mut args)?;
+                let reject_without_agents = match args.next().as_deref() {
+                    None | Some("false") => false,
+                    Some("true") => true,
+                    Some(other) => {
+                        return Err(GenericError::ParseError(format!(
+                            "Invalid REJECT_WITHOUT_AGENTS '{other}' - expected true or false"
+                        )));
+                    }
+                };
+                let environment = args.next().filter(|environment| !environment.is_empty());
+                Ok(Self::RunTask(
+                    task_id,
+                    params,
+                    reject_without_agents,
+                    environment,
+                ))
+            }
+            "DRYRUNTASK" => {
+                let (task_id, params) = helpers::create_run_task_payload(&mut args)?;
+                Ok(Self::DryRunTask(task_id, params))
+            }
             "REGISTERAGENT" => match args.next() {
-                Some(agent_id) => Ok(Self::RegisterAgent(agent_id)),
+                Some(agent_id) => {
+                    let protocol_version = match args.next() {
+                        Some(version) if !version.is_empty() => {
+                            Some(version.parse().map_err(|_e| {
+                                GenericError::ParseError("Not a valid protocol version".to_string())
+                            })?)
+                        }
+                        _ => None,
+                    };
+                    let max_concurrency = match args.next() {
+                        Some(max) if !max.is_empty() => Some(max.parse().map_err(|_e| {
+                            GenericError::ParseError("Not a valid max concurrency".to_string())
+                        })?),
+                        _ => None,
+                    };
+                    let executors = match args.next() {
+                        Some(executors) if !executors.is_empty() => {
+                            Some(executors.split(',').map(str::to_string).collect())
+                        }
+                        _ => None,
+                    };
+                    let push_address = args.next().filter(|address| !address.is_empty());
+                    let session = args.next().filter(|session| !session.is_empty());
+                    Ok(Self::RegisterAgent(
+                        agent_id,
+                        protocol_version,
+                        max_concurrency,
+                        executors,
+                        push_address,
+                        session,
+                    ))
+                }
                 None => Err(GenericError::ParseError("Missing arg AGENT_ID".to_string())),
             },
             "AGENTWORKFLOWSTATUS" => match args.next() {
                 Some(agent_id) => match args.next() {
-                    Some(task_id) => match args.next() {
-                        Some(task_exe_id) => match args.next() {
+                    Some(workflow_id) => match args.next() {
+                        Some(workflow_instance_id) => match args.next() {
                             Some(status) => {
                                 let status = RunStatus::try_from(status)?;
                                 Ok(Self::WorkflowStatusUpdate(
                                     agent_id,
-                                    task_id,
-                                    task_exe_id,
+                                    workflow_id,
+                                    workflow_instance_id.into(),
                                     status,
                                 ))
                             }
                             None => Err(GenericError::ParseError(
-                                "Missing arg TASK_STATUS".to_string(),
+                                "Missing arg WORKFLOW_STATUS".to_string(),
                             )),
                         },
                         None => Err(GenericError::ParseError(
-                            "Missing arg TASK_EXECUTION_ID".to_string(),
+                            "Missing arg WORKFLOW_INSTANCE_ID".to_string(),
                         )),
                     },
-                    None => Err(GenericError::ParseError("Missing arg TASK_ID".to_string())),
+                    None => Err(GenericError::ParseError(
+                        "Missing arg WORKFLOW_ID".to_string(),
+                    )),
                 },
                 None => Err(GenericError::ParseError("Missing arg AGENT_ID".to_string())),
             },
@@ -114,12 +332,33 @@ impl TryFrom<ZMQArgs> for PrincipalAPI {
                             Some(workflow_instance_id) => match args.next() {
                                 Some(status) => {
                                     let status = RunStatus::try_from(status)?;
+                                    let exit_code = match args.next() {
+                                        Some(code) if !code.is_empty() => {
+                                            Some(code.parse().map_err(|_e| {
+                                                GenericError::ParseError(
+                                                    "Not a valid exit code".to_string(),
+                                                )
+                                            })?)
+                                        }
+                                        _ => None,
+                                    };
+                                    let truncated = match args.next().as_deref() {
+                                        None | Some("false") => false,
+                                        Some("true") => true,
+                                        Some(other) => {
+                                            return Err(GenericError::ParseError(format!(
+                                                "Invalid TRUNCATED '{other}' - expected true or false"
+                                            )));
+                                        }
+                                    };
                                     Ok(Self::TaskStatusUpdate(
                                         agent_id,
                                         task_id,
-                                        task_exe_id,
-                                        workflow_instance_id,
+                                        task_exe_id.into(),
+                                        workflow_instance_id.into(),
                                         status,
+                                        exit_code,
+                                        truncated,
                                     ))
                                 }
                                 None => Err(GenericError::ParseError(
@@ -138,8 +377,121 @@ impl TryFrom<ZMQArgs> for PrincipalAPI {
                 },
                 None => Err(GenericError::ParseError("Missing arg AGENT_ID".to_string())),
             },
+            "TASKPROGRESS" => {
+                let agent_id = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg AGENT_ID".to_string()))?;
+                let task_exe_id = args.next().ok_or(GenericError::ParseError(
+                    "Missing arg TASK_EXECUTION_ID".to_string(),
+                ))?;
+                let percent = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg PERCENT".to_string()))?
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|percent| *percent <= 100)
+                    .ok_or(GenericError::ParseError(
+                        "Not a valid percent - expected a whole number from 0 to 100".to_string(),
+                    ))?;
+                let message = Into::<Vec<String>>::into(args).join(" ");
+                Ok(Self::TaskProgress(
+                    agent_id,
+                    task_exe_id.into(),
+                    percent,
+                    message,
+                ))
+            }
+            "TASKARTIFACTS" => {
+                let agent_id = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg AGENT_ID".to_string()))?;
+                let task_exe_id = args.next().ok_or(GenericError::ParseError(
+                    "Missing arg TASK_EXECUTION_ID".to_string(),
+                ))?;
+                let artifacts = args.next().ok_or(GenericError::ParseError(
+                    "Missing arg ARTIFACTS".to_string(),
+                ))?;
+                let artifacts = serde_json::from_str(&artifacts).map_err(|e| {
+                    GenericError::ParseError(format!(
+                        "Artifacts must be a JSON array of paths and sizes: {e}"
+                    ))
+                })?;
+                Ok(Self::TaskArtifacts(agent_id, task_exe_id.into(), artifacts))
+            }
+            "WORKFLOWPROGRESS" => {
+                let agent_id = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg AGENT_ID".to_string()))?;
+                let workflow_instance_id = args.next().ok_or(GenericError::ParseError(
+                    "Missing arg WORKFLOW_INSTANCE_ID".to_string(),
+                ))?;
+                let tasks_completed = args
+                    .next()
+                    .ok_or(GenericError::ParseError(
+                        "Missing arg TASKS_COMPLETED".to_string(),
+                    ))?
+                    .parse::<usize>()
+                    .map_err(|e| {
+                        GenericError::ParseError(format!("Invalid TASKS_COMPLETED: {e}"))
+                    })?;
+                let tasks_total = args
+                    .next()
+                    .ok_or(GenericError::ParseError(
+                        "Missing arg TASKS_TOTAL".to_string(),
+                    ))?
+                    .parse::<usize>()
+                    .map_err(|e| GenericError::ParseError(format!("Invalid TASKS_TOTAL: {e}")))?;
+                if tasks_completed > tasks_total {
+                    return Err(GenericError::ParseError(
+                        "TASKS_COMPLETED can't be more than TASKS_TOTAL".to_string(),
+                    ));
+                }
+                Ok(Self::WorkflowProgress(
+                    agent_id,
+                    workflow_instance_id.into(),
+                    tasks_completed,
+                    tasks_total,
+                ))
+            }
+            "AGENTWORKFLOWOUTPUTS" => {
+                let agent_id = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg AGENT_ID".to_string()))?;
+                let workflow_id = args.next().ok_or(GenericError::ParseError(
+                    "Missing arg WORKFLOW_ID".to_string(),
+                ))?;
+                let workflow_instance_id = args.next().ok_or(GenericError::ParseError(
+                    "Missing arg WORKFLOW_INSTANCE_ID".to_string(),
+                ))?;
+                let outputs = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg OUTPUTS".to_string()))?;
+                let outputs = serde_json::from_str(&outputs).map_err(|e| {
+                    GenericError::ParseError(format!(
+                        "Outputs must be a JSON object of string values: {e}"
+                    ))
+                })?;
+                Ok(Self::WorkflowOutputs(
+                    agent_id,
+                    workflow_id,
+                    workflow_instance_id.into(),
+                    outputs,
+                ))
+            }
             "FETCHWORKFLOW" => match args.next() {
-                Some(agent_id) => Ok(Self::FetchWorkflow(agent_id)),
+                Some(agent_id) => {
+                    let long_poll_timeout_ms = match args.next() {
+                        Some(timeout_ms) if !timeout_ms.is_empty() => {
+                            Some(timeout_ms.parse().map_err(|_e| {
+                                GenericError::ParseError(
+                                    "Not a valid long poll timeout".to_string(),
+                                )
+                            })?)
+                        }
+                        _ => None,
+                    };
+                    Ok(Self::FetchWorkflow(agent_id, long_poll_timeout_ms))
+                }
                 None => Err(GenericError::ParseError("Missing agent id".to_string())),
             },
             "QUERYLOGS" => match args.next() {
@@ -169,19 +521,21 @@ impl TryFrom<ZMQArgs> for PrincipalAPI {
                                     match args.next() {
                                         Some(wf_ins_id) => {
                                             let wf_ins_id_opt = if wf_ins_id.len() > 0 {
-                                                Some(wf_ins_id)
+                                                Some(wf_ins_id.into())
                                             } else {
                                                 None
                                             };
+                                            let verbose = match args.next() {
+                                                Some(v) => v.len() > 0,
+                                                None => false,
+                                            };
                                             Ok(Self::QueryLogs(
                                                 end_ts_opt,
                                                 start_ts_opt,
                                                 wf_id_opt,
                                                 wf_ins_id_opt,
-                                                match args.next() {
-                                                    Some(v) => v.len() > 0,
-                                                    None => false,
-                                                },
+                                                verbose,
+                                                LogPage::from_args(&mut args)?,
                                             ))
                                         }
                                         None => Err(GenericError::ParseError(
@@ -205,6 +559,115 @@ impl TryFrom<ZMQArgs> for PrincipalAPI {
             },
             "GETRECENTSTATUSES" => Ok(Self::GetRecentWorkflowStatuses),
             "GETREGISTEREDAGENTS" => Ok(Self::GetRegisteredAgents),
+            "GETWORKFLOWRESULT" => match args.next() {
+                Some(workflow_instance_id) => {
+                    Ok(Self::GetWorkflowResult(workflow_instance_id.into()))
+                }
+                None => Err(GenericError::ParseError(
+                    "Missing arg WORKFLOW_INSTANCE_ID".to_string(),
+                )),
+            },
+            "GETWORKFLOWTAIL" => {
+                let workflow_instance_id = args.next().ok_or(GenericError::ParseError(
+                    "Missing arg WORKFLOW_INSTANCE_ID".to_string(),
+                ))?;
+                let n = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg N".to_string()))?
+                    .parse::<usize>()
+                    .map_err(|e| GenericError::ParseError(format!("Invalid N: {e}")))?;
+                Ok(Self::GetWorkflowTail(workflow_instance_id.into(), n))
+            }
+            "GETAUDITLOG" => {
+                let limit = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg LIMIT".to_string()))?
+                    .parse::<usize>()
+                    .map_err(|e| GenericError::ParseError(format!("Invalid LIMIT: {e}")))?;
+                Ok(Self::GetAuditLog(limit))
+            }
+            "ACQUIRESCHEDULERLEASE" => {
+                let scheduler_id = args.next().ok_or(GenericError::ParseError(
+                    "Missing arg SCHEDULER_ID".to_string(),
+                ))?;
+                let ttl_ms = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg TTL_MS".to_string()))?
+                    .parse::<u64>()
+                    .map_err(|e| GenericError::ParseError(format!("Invalid TTL_MS: {e}")))?;
+                Ok(Self::AcquireSchedulerLease(scheduler_id, ttl_ms))
+            }
+            "GETQUEUEMETRICS" => Ok(Self::GetQueueMetrics),
+            "GETCLUSTERCAPACITY" => Ok(Self::GetClusterCapacity),
+            "FLUSHQUEUE" => Ok(Self::FlushQueue(actor_arg(&mut args))),
+            "RELOADCONFIG" => Ok(Self::ReloadConfig(actor_arg(&mut args))),
+            "DRAINAGENT" => match args.next() {
+                Some(agent_id) => match args.next().as_deref() {
+                    Some("true") => Ok(Self::DrainAgent(agent_id, true, actor_arg(&mut args))),
+                    Some("false") => Ok(Self::DrainAgent(agent_id, false, actor_arg(&mut args))),
+                    _ => Err(GenericError::ParseError(
+                        "Arg DRAINED must be true or false".to_string(),
+                    )),
+                },
+                None => Err(GenericError::ParseError("Missing arg AGENT_ID".to_string())),
+            },
+            "ANNOTATERUN" => {
+                let workflow_instance_id = args.next().ok_or(GenericError::ParseError(
+                    "Missing arg WORKFLOW_INSTANCE_ID".to_string(),
+                ))?;
+                let key = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg KEY".to_string()))?;
+                let value = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg VALUE".to_string()))?;
+                Ok(Self::AnnotateRun(workflow_instance_id.into(), key, value))
+            }
+            "PUTARTIFACT" => {
+                let (workflow_instance_id, path, offset) = artifact_args(&mut args)?;
+                let chunk = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg CHUNK".to_string()))?;
+                Ok(Self::PutArtifact(workflow_instance_id, path, offset, chunk))
+            }
+            "GETARTIFACT" => {
+                let (workflow_instance_id, path, offset) = artifact_args(&mut args)?;
+                Ok(Self::GetArtifact(workflow_instance_id, path, offset))
+            }
+            "AGENTMETRICS" => {
+                let agent_id = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg AGENT_ID".to_string()))?;
+                let metrics = args
+                    .next()
+                    .ok_or(GenericError::ParseError("Missing arg METRICS".to_string()))?;
+                let metrics = serde_json::from_str(&metrics).map_err(|e| {
+                    GenericError::ParseError(format!("Metrics are not valid: {}", e))
+                })?;
+                Ok(Self::AgentMetrics(agent_id, metrics))
+            }
+            "REPLAYRUN" => match args.next() {
+                Some(workflow_instance_id) => Ok(Self::ReplayRun(workflow_instance_id.into())),
+                None => Err(GenericError::ParseError(
+                    "Missing arg WORKFLOW_INSTANCE_ID".to_string(),
+                )),
+            },
+            "DIAGNOSEDISPATCH" => match args.next() {
+                Some(workflow_instance_id) => {
+                    Ok(Self::DiagnoseDispatch(workflow_instance_id.into()))
+                }
+                None => Err(GenericError::ParseError(
+                    "Missing arg WORKFLOW_INSTANCE_ID".to_string(),
+                )),
+            },
+            "STOPAGENT" => match args.next() {
+                Some(agent_id) => Ok(Self::StopAgent(agent_id, actor_arg(&mut args))),
+                None => Err(GenericError::ParseError("Missing arg AGENT_ID".to_string())),
+            },
+            "AGENTSTOPPED" => match args.next() {
+                Some(agent_id) => Ok(Self::AgentStopped(agent_id)),
+                None => Err(GenericError::ParseError("Missing arg AGENT_ID".to_string())),
+            },
             _ => Err(GenericError::ParseError(format!(
                 "Unrecognised message type: {}",
                 msg_type
@@ -217,8 +680,17 @@ impl API for PrincipalAPI {
     fn get_tcp_uri(&self) -> String {
         get_principal_uri()
     }
+    fn get_timeout(&self) -> Duration {
+        match self {
+            // allow for the principal holding the request open
+            Self::FetchWorkflow(_, Some(timeout_ms)) => {
+                get_request_timeout() + Duration::from_millis(*timeout_ms)
+            }
+            _ => get_request_timeout(),
+        }
+    }
     fn get_meta(&self) -> Vec<APIMeta> {
-        const META: [(&'static str, &'static str); 9] = [
+        const META: [(&'static str, &'static str); 31] = [
             ("PING", "Check server is online"),
             (
                 "LSWORKFLOWS",
@@ -238,7 +710,7 @@ impl API for PrincipalAPI {
             ),
             (
                 "FETCHWORKFLOW",
-                "Allows an agent to fetch a unit of work from the principal task queue. Returns a success message if there is no work to do.",
+                "Allows an agent to fetch a unit of work from the principal task queue, optionally waiting up to a timeout for work to arrive. Returns a success message if there is no work to do.",
             ),
             ("QUERYLOGS", "Queries logs from the main principal database"),
             (
@@ -249,23 +721,179 @@ impl API for PrincipalAPI {
                 "GETREGISTEREDAGENTS",
                 "Get list of all registered agents with their metadata",
             ),
+            (
+                "GETWORKFLOWRESULT",
+                "Get the aggregated task results of a workflow run (workflow_instance_id)",
+            ),
+            (
+                "GETWORKFLOWTAIL",
+                "Get the most recent log lines of a workflow run (workflow_instance_id, n)",
+            ),
+            (
+                "GETAUDITLOG",
+                "Get the latest administrative actions taken on the principal and who took them (limit)",
+            ),
+            (
+                "GETQUEUEMETRICS",
+                "Get the size and enqueue/dequeue rates of the principal task queue",
+            ),
+            (
+                "GETCLUSTERCAPACITY",
+                "Get the total and used workflow slots of the registered agents and the number of queued workflows",
+            ),
+            (
+                "DRAINAGENT",
+                "Stop an agent fetching new workflows while its running workflows finish, or re-enable it (agent_id, drained)",
+            ),
+            (
+                "DRYRUNTASK",
+                "Queue a run of a workflow that logs the resolved command of each task instead of running it",
+            ),
+            (
+                "FLUSHQUEUE",
+                "Remove every workflow waiting on the queue and get the number removed",
+            ),
+            (
+                "TASKPROGRESS",
+                "Allows an agent to report the progress of a running task",
+            ),
+            (
+                "TASKARTIFACTS",
+                "Allows an agent to report the files matching the artifact globs of a finished task",
+            ),
+            (
+                "WORKFLOWPROGRESS",
+                "Allows an agent to report how many tasks of a running workflow have finished",
+            ),
+            (
+                "AGENTWORKFLOWOUTPUTS",
+                "Allows an agent to report the named outputs of a successful workflow run",
+            ),
+            (
+                "ANNOTATERUN",
+                "Add or replace an annotation of a workflow run (workflow_instance_id, key, value)",
+            ),
+            (
+                "PUTARTIFACT",
+                "Allows an agent to upload a chunk of an artifact produced by a task (workflow_instance_id, path, offset, chunk)",
+            ),
+            (
+                "GETARTIFACT",
+                "Allows an agent to download a chunk of an artifact consumed by a task (workflow_instance_id, path, offset)",
+            ),
+            (
+                "AGENTMETRICS",
+                "Allows an agent to report its running workflows, host CPU and memory and running tasks",
+            ),
+            (
+                "REPLAYRUN",
+                "Run a past workflow run again with the workflow definition and params it ran with (workflow_instance_id)",
+            ),
+            (
+                "RELOADCONFIG",
+                "Re-read the principal's config and apply the settings that don't need a restart",
+            ),
+            (
+                "DIAGNOSEDISPATCH",
+                "Explain why a queued workflow run hasn't been sent to an agent yet (workflow_instance_id)",
+            ),
+            (
+                "STOPAGENT",
+                "Tell an agent to exit once its running workflows finish (agent_id)",
+            ),
+            (
+                "AGENTSTOPPED",
+                "Allows a stopped agent to confirm it is exiting so it is deregistered",
+            ),
+            (
+                "ACQUIRESCHEDULERLEASE",
+                "Allows a scheduler to take or renew the lease to fire scheduled workflows (scheduler_id, ttl_ms)",
+            ),
         ];
         META.iter()
             .map(|(action, desc)| APIMeta::new(action.to_string(), desc.to_string()))
             .collect()
     }
+    fn message_type(&self) -> &'static str {
+        match self {
+            Self::Ping => "Ping",
+            Self::ListWorkflowStore => "ListWorkflowStore",
+            Self::RunTask(..) => "RunTask",
+            Self::DryRunTask(..) => "DryRunTask",
+            Self::RegisterAgent(..) => "RegisterAgent",
+            Self::WorkflowStatusUpdate(..) => "WorkflowStatusUpdate",
+            Self::TaskStatusUpdate(..) => "TaskStatusUpdate",
+            Self::TaskProgress(..) => "TaskProgress",
+            Self::TaskArtifacts(..) => "TaskArtifacts",
+            Self::WorkflowProgress(..) => "WorkflowProgress",
+            Self::WorkflowOutputs(..) => "WorkflowOutputs",
+            Self::FetchWorkflow(..) => "FetchWorkflow",
+            Self::QueryLogs(..) => "QueryLogs",
+            Self::GetRecentWorkflowStatuses => "GetRecentWorkflowStatuses",
+            Self::GetRegisteredAgents => "GetRegisteredAgents",
+            Self::GetWorkflowResult(..) => "GetWorkflowResult",
+            Self::GetWorkflowTail(..) => "GetWorkflowTail",
+            Self::GetAuditLog(..) => "GetAuditLog",
+            Self::GetQueueMetrics => "GetQueueMetrics",
+            Self::GetClusterCapacity => "GetClusterCapacity",
+            Self::DrainAgent(..) => "DrainAgent",
+            Self::FlushQueue(_) => "FlushQueue",
+            Self::AnnotateRun(..) => "AnnotateRun",
+            Self::PutArtifact(..) => "PutArtifact",
+            Self::GetArtifact(..) => "GetArtifact",
+            Self::AgentMetrics(..) => "AgentMetrics",
+            Self::ReplayRun(..) => "ReplayRun",
+            Self::ReloadConfig(_) => "ReloadConfig",
+            Self::DiagnoseDispatch(..) => "DiagnoseDispatch",
+            Self::StopAgent(..) => "StopAgent",
+            Self::AgentStopped(..) => "AgentStopped",
+            Self::AcquireSchedulerLease(..) => "AcquireSchedulerLease",
+        }
+    }
     fn to_string(&self) -> String {
         match self {
             Self::Ping => "PING".to_string(),
-            Self::RunTask(task_id) => format!("RUNTASK\x01{task_id}"),
+            Self::RunTask(task_id, params, false, None) => {
+                run_task_message("RUNTASK", task_id, params)
+            }
+            Self::RunTask(task_id, params, reject_without_agents, environment) => {
+                let msg = format!(
+                    "RUNTASK\x01{task_id}\x01{}\x01{reject_without_agents}",
+                    serde_json::to_string(params).expect("params are always serialisable")
+                );
+                match environment {
+                    Some(environment) => format!("{msg}\x01{environment}"),
+                    None => msg,
+                }
+            }
+            Self::DryRunTask(task_id, params) => run_task_message("DRYRUNTASK", task_id, params),
             Self::ListWorkflowStore => "LSWORKFLOWS".to_string(),
-            Self::RegisterAgent(agent_id) => {
-                format!("REGISTERAGENT\x01{agent_id}")
+            Self::RegisterAgent(
+                agent_id,
+                protocol_version,
+                max_concurrency,
+                executors,
+                push_address,
+                session,
+            ) => {
+                let mut args = vec![
+                    agent_id.clone(),
+                    protocol_version.map(|v| v.to_string()).unwrap_or_default(),
+                    max_concurrency.map(|m| m.to_string()).unwrap_or_default(),
+                    executors.as_ref().map(|e| e.join(",")).unwrap_or_default(),
+                    push_address.clone().unwrap_or_default(),
+                    session.clone().unwrap_or_default(),
+                ];
+                // optional args left unset at the end aren't sent
+                while args.len() > 1 && args.last().is_some_and(|arg| arg.is_empty()) {
+                    args.pop();
+                }
+                format!("REGISTERAGENT\x01{}", args.join("\x01"))
             }
-            Self::WorkflowStatusUpdate(agent_id, task_id, task_exe_id, status) => {
+            Self::WorkflowStatusUpdate(agent_id, workflow_id, workflow_instance_id, status) => {
                 let status = status.to_string();
                 format!(
-                    "AGENTWORKFLOWSTATUS\x01{agent_id}\x01{task_id}\x01{task_exe_id}\x01{status}"
+                    "AGENTWORKFLOWSTATUS\x01{agent_id}\x01{workflow_id}\x01{workflow_instance_id}\x01{status}"
                 )
             }
             Self::TaskStatusUpdate(
@@ -274,18 +902,27 @@ impl API for PrincipalAPI {
                 task_exe_id,
                 workflow_instance_id,
                 status,
+                exit_code,
+                truncated,
             ) => {
                 let status = status.to_string();
-                format!(
+                let msg = format!(
                     "AGENTTASKSTATUS\x01{agent_id}\x01{task_id}\x01{task_exe_id}\x01{workflow_instance_id}\x01{status}"
-                )
-            }
-            Self::FetchWorkflow(agent_id) => {
-                format!("FETCHWORKFLOW\x01{agent_id}")
+                );
+                let code = exit_code.map(|code| code.to_string());
+                match (code, truncated) {
+                    (code, true) => format!("{msg}\x01{}\x01true", code.unwrap_or_default()),
+                    (Some(code), false) => format!("{msg}\x01{code}"),
+                    (None, false) => msg,
+                }
             }
-            Self::QueryLogs(end_ts, start_ts, wf_id, wf_ins_id, verbose) => {
+            Self::FetchWorkflow(agent_id, long_poll_timeout_ms) => match long_poll_timeout_ms {
+                Some(timeout_ms) => format!("FETCHWORKFLOW\x01{agent_id}\x01{timeout_ms}"),
+                None => format!("FETCHWORKFLOW\x01{agent_id}"),
+            },
+            Self::QueryLogs(end_ts, start_ts, wf_id, wf_ins_id, verbose, page) => {
                 format!(
-                    "QUERYLOGS\x01{}\x01{}\x01{}\x01{}\x01{}",
+                    "QUERYLOGS\x01{}\x01{}\x01{}\x01{}\x01{}{}",
                     if let Some(ts) = end_ts {
                         ts.to_string()
                     } else {
@@ -297,16 +934,144 @@ impl API for PrincipalAPI {
                         "".to_string()
                     },
                     wf_id.clone().unwrap_or("".to_string()),
-                    wf_ins_id.clone().unwrap_or("".to_string()),
-                    if *verbose { "v" } else { "" }
+                    wf_ins_id.as_ref().map(|id| id.as_str()).unwrap_or(""),
+                    if *verbose { "v" } else { "" },
+                    match page {
+                        Some(page) => format!("\x01{}\x01{}", page.offset, page.limit),
+                        None => "".to_string(),
+                    }
                 )
             }
             Self::GetRecentWorkflowStatuses => "GETRECENTSTATUSES".to_string(),
             Self::GetRegisteredAgents => "GETREGISTEREDAGENTS".to_string(),
+            Self::GetWorkflowResult(workflow_instance_id) => {
+                format!("GETWORKFLOWRESULT\x01{workflow_instance_id}")
+            }
+            Self::GetWorkflowTail(workflow_instance_id, n) => {
+                format!("GETWORKFLOWTAIL\x01{workflow_instance_id}\x01{n}")
+            }
+            Self::GetAuditLog(limit) => format!("GETAUDITLOG\x01{limit}"),
+            Self::GetQueueMetrics => "GETQUEUEMETRICS".to_string(),
+            Self::GetClusterCapacity => "GETCLUSTERCAPACITY".to_string(),
+            Self::FlushQueue(actor) => format!("FLUSHQUEUE{}", actor_suffix(actor)),
+            Self::ReloadConfig(actor) => format!("RELOADCONFIG{}", actor_suffix(actor)),
+            Self::TaskProgress(agent_id, task_exe_id, percent, message) => {
+                format!(
+                    "TASKPROGRESS\x01{agent_id}\x01{task_exe_id}\x01{percent}\x01{}",
+                    escape_zmq_arg(message)
+                )
+            }
+            Self::TaskArtifacts(agent_id, task_exe_id, artifacts) => {
+                format!(
+                    "TASKARTIFACTS\x01{agent_id}\x01{task_exe_id}\x01{}",
+                    serde_json::to_string(artifacts).expect("artifacts are always serialisable")
+                )
+            }
+            Self::WorkflowProgress(
+                agent_id,
+                workflow_instance_id,
+                tasks_completed,
+                tasks_total,
+            ) => {
+                format!(
+                    "WORKFLOWPROGRESS\x01{agent_id}\x01{workflow_instance_id}\x01{tasks_completed}\x01{tasks_total}"
+                )
+            }
+            Self::WorkflowOutputs(agent_id, workflow_id, workflow_instance_id, outputs) => {
+                format!(
+                    "AGENTWORKFLOWOUTPUTS\x01{agent_id}\x01{workflow_id}\x01{workflow_instance_id}\x01{}",
+                    serde_json::to_string(outputs).expect("outputs are always serialisable")
+                )
+            }
+            Self::DrainAgent(agent_id, drained, actor) => {
+                format!(
+                    "DRAINAGENT\x01{agent_id}\x01{drained}{}",
+                    actor_suffix(actor)
+                )
+            }
+            Self::AnnotateRun(workflow_instance_id, key, value) => {
+                format!(
+                    "ANNOTATERUN\x01{workflow_instance_id}\x01{}\x01{}",
+                    escape_zmq_arg(key),
+                    escape_zmq_arg(value)
+                )
+            }
+            Self::PutArtifact(workflow_instance_id, path, offset, chunk) => {
+                format!(
+                    "PUTARTIFACT\x01{workflow_instance_id}\x01{}\x01{offset}\x01{}",
+                    escape_zmq_arg(path),
+                    escape_zmq_arg(chunk)
+                )
+            }
+            Self::GetArtifact(workflow_instance_id, path, offset) => {
+                format!(
+                    "GETARTIFACT\x01{workflow_instance_id}\x01{}\x01{offset}",
+                    escape_zmq_arg(path)
+                )
+            }
+            Self::AgentMetrics(agent_id, metrics) => {
+                format!(
+                    "AGENTMETRICS\x01{agent_id}\x01{}",
+                    serde_json::to_string(metrics).expect("metrics are always serialisable")
+                )
+            }
+            Self::ReplayRun(workflow_instance_id) => format!("REPLAYRUN\x01{workflow_instance_id}"),
+            Self::DiagnoseDispatch(workflow_instance_id) => {
+                format!("DIAGNOSEDISPATCH\x01{workflow_instance_id}")
+            }
+            Self::StopAgent(agent_id, actor) => {
+                format!("STOPAGENT\x01{agent_id}{}", actor_suffix(actor))
+            }
+            Self::AgentStopped(agent_id) => format!("AGENTSTOPPED\x01{agent_id}"),
+            Self::AcquireSchedulerLease(scheduler_id, ttl_ms) => {
+                format!("ACQUIRESCHEDULERLEASE\x01{scheduler_id}\x01{ttl_ms}")
+            }
         }
     }
 }
 
+/// Formats a run of a workflow, leaving the params off the message when there aren't any
+fn run_task_message(msg_type: &str, task_id: &str, params: &HashMap<String, String>) -> String {
+    if params.is_empty() {
+        format!("{msg_type}\x01{task_id}")
+    } else {
+        format!(
+            "{msg_type}\x01{task_id}\x01{}",
+            serde_json::to_string(params).expect("params are always serialisable")
+        )
+    }
+}
+
+/// Formats the optional actor trailing an administrative message
+fn actor_suffix(actor: &Option<String>) -> String {
+    match actor {
+        Some(actor) => format!("\x01{}", escape_zmq_arg(actor)),
+        None => String::new(),
+    }
+}
+
+/// Parses the optional actor trailing an administrative message. Clients from before
+/// the actor was sent leave it off
+fn actor_arg(args: &mut ZMQArgs) -> Option<String> {
+    args.next().filter(|actor| !actor.is_empty())
+}
+
+/// Parses the workflow instance id, path and offset shared by the artifact messages
+fn artifact_args(args: &mut ZMQArgs) -> Result<(WorkflowInstanceId, String, usize), GenericError> {
+    let workflow_instance_id = args.next().ok_or(GenericError::ParseError(
+        "Missing arg WORKFLOW_INSTANCE_ID".to_string(),
+    ))?;
+    let path = args
+        .next()
+        .ok_or(GenericError::ParseError("Missing arg PATH".to_string()))?;
+    let offset = args
+        .next()
+        .ok_or(GenericError::ParseError("Missing arg OFFSET".to_string()))?
+        .parse()
+        .map_err(|_| GenericError::ParseError("Arg OFFSET must be an integer".to_string()))?;
+    Ok((workflow_instance_id.into(), path, offset))
+}
+
 impl TryFrom<ZmqMessage> for PrincipalAPI {
     type Error = GenericError;
     fn try_from(zmq_msg: ZmqMessage) -> Result<Self, Self::Error> {
@@ -328,9 +1093,13 @@ impl Into<ZmqMessage> for PrincipalAPI {
 }
 
 mod helpers {
+    use std::collections::HashMap;
+
     use cdktr_core::{exceptions::GenericError, models::ZMQArgs};
 
-    pub fn create_run_task_payload(mut args: ZMQArgs) -> Result<String, GenericError> {
+    pub fn create_run_task_payload(
+        args: &mut ZMQArgs,
+    ) -> Result<(String, HashMap<String, String>), GenericError> {
         let task_id = if let Some(task_id) = args.next() {
             task_id
         } else {
@@ -338,14 +1107,25 @@ mod helpers {
                 "Request is missing task_id".to_string(),
             ));
         };
-        match task_id.parse() {
-            Ok(v) => Ok(v),
-            Err(e) => Err(GenericError::ParseError(format!(
-                "Unable to create integer from task_id '{}'. Error: {}",
-                &task_id,
-                e.to_string()
-            ))),
-        }
+        let task_id: String = match task_id.parse() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(GenericError::ParseError(format!(
+                    "Unable to create integer from task_id '{}'. Error: {}",
+                    &task_id, e
+                )));
+            }
+        };
+        let params = match args.next() {
+            Some(params_json) => serde_json::from_str(&params_json).map_err(|e| {
+                GenericError::ParseError(format!(
+                    "Params must be a JSON object of string values. Error: {}",
+                    e
+                ))
+            })?,
+            None => HashMap::new(),
+        };
+        Ok((task_id, params))
     }
 
     #[cfg(test)]
@@ -354,7 +1134,8 @@ mod helpers {
 
 #[cfg(test)]
 mod tests {
-    use super::PrincipalAPI;
+    use super::{AgentMetrics, ArtifactInfo, LogPage, PrincipalAPI, RunStatus};
+    use crate::API;
     use zeromq::ZmqMessage;
 
     #[test]
@@ -365,4 +1146,594 @@ mod tests {
                 .expect(&format!("Failed to create AgentAPI from {}", rt));
         }
     }
+
+    #[test]
+    fn test_fetch_workflow_long_poll_round_trip() {
+        let msg = PrincipalAPI::FetchWorkflow("1234".to_string(), Some(5000));
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::FetchWorkflow(agent_id, Some(5000)) if agent_id == "1234"
+        ));
+        // clients that still poll don't send a timeout
+        let parsed = PrincipalAPI::try_from("FETCHWORKFLOW\x011234".to_string()).unwrap();
+        assert!(matches!(parsed, PrincipalAPI::FetchWorkflow(_, None)));
+        assert!(msg.get_timeout() > PrincipalAPI::Ping.get_timeout());
+    }
+
+    #[test]
+    fn test_register_agent_version_round_trip() {
+        let msg = PrincipalAPI::RegisterAgent(
+            "agent".to_string(),
+            Some(crate::PROTOCOL_VERSION),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(
+            msg.to_string(),
+            format!("REGISTERAGENT\x01agent\x01{}", crate::PROTOCOL_VERSION)
+        );
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::RegisterAgent(agent_id, Some(v), None, None, None, None) if agent_id == "agent" && v == crate::PROTOCOL_VERSION
+        ));
+        // agents from before version negotiation don't send a version
+        let parsed = PrincipalAPI::try_from("REGISTERAGENT\x01agent".to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::RegisterAgent(_, None, None, None, None, None)
+        ));
+        assert!(PrincipalAPI::try_from("REGISTERAGENT\x01agent\x01abc".to_string()).is_err());
+        // the max concurrency can be sent without a version
+        let msg = PrincipalAPI::RegisterAgent("agent".to_string(), None, Some(4), None, None, None);
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::RegisterAgent(_, None, Some(4), None, None, None)
+        ));
+        // and the executors without either
+        let msg = PrincipalAPI::RegisterAgent(
+            "agent".to_string(),
+            None,
+            None,
+            Some(vec!["Subprocess".to_string(), "UvPython".to_string()]),
+            None,
+            None,
+        );
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::RegisterAgent(_, None, None, Some(executors), None, None) if executors == vec!["Subprocess", "UvPython"]
+        ));
+        // and the push address without the rest
+        let msg = PrincipalAPI::RegisterAgent(
+            "agent".to_string(),
+            None,
+            None,
+            None,
+            Some("tcp://10.0.0.5:5564".to_string()),
+            None,
+        );
+        assert_eq!(
+            msg.to_string(),
+            "REGISTERAGENT\x01agent\x01\x01\x01\x01tcp://10.0.0.5:5564"
+        );
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::RegisterAgent(_, None, None, None, Some(address), None) if address == "tcp://10.0.0.5:5564"
+        ));
+        // and the session of the agent process last
+        let msg = PrincipalAPI::RegisterAgent(
+            "agent".to_string(),
+            Some(crate::PROTOCOL_VERSION),
+            None,
+            None,
+            None,
+            Some("01J0SESSION".to_string()),
+        );
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::RegisterAgent(_, Some(_), None, None, None, Some(session)) if session == "01J0SESSION"
+        ));
+    }
+
+    #[test]
+    fn test_drain_agent_round_trip() {
+        let msg = PrincipalAPI::DrainAgent("agent".to_string(), true, None);
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::DrainAgent(agent_id, true, None) if agent_id == "agent"
+        ));
+        assert!(PrincipalAPI::try_from("DRAINAGENT\x01agent".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_admin_messages_send_actor() {
+        let actor = Some("alice@host-1".to_string());
+        for msg in [
+            PrincipalAPI::DrainAgent("agent".to_string(), false, actor.clone()),
+            PrincipalAPI::StopAgent("agent".to_string(), actor.clone()),
+            PrincipalAPI::FlushQueue(actor.clone()),
+            PrincipalAPI::ReloadConfig(actor.clone()),
+        ] {
+            let wire = msg.to_string();
+            assert!(wire.ends_with("\x01alice@host-1"), "{wire}");
+            let parsed = PrincipalAPI::try_from(wire).unwrap();
+            assert_eq!(parsed.to_string(), msg.to_string());
+        }
+        // clients that don't send an actor
+        assert!(matches!(
+            PrincipalAPI::try_from("FLUSHQUEUE".to_string()).unwrap(),
+            PrincipalAPI::FlushQueue(None)
+        ));
+        assert!(matches!(
+            PrincipalAPI::try_from("STOPAGENT\x01agent".to_string()).unwrap(),
+            PrincipalAPI::StopAgent(_, None)
+        ));
+    }
+
+    #[test]
+    fn test_annotate_run_round_trip() {
+        let msg = PrincipalAPI::AnnotateRun(
+            "run-1".into(),
+            "incident".to_string(),
+            "investigating failure #123\x01see logs".to_string(),
+        );
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::AnnotateRun(id, key, value)
+                if id == "run-1" && key == "incident" && value == "investigating failure #123\x01see logs"
+        ));
+        assert!(PrincipalAPI::try_from("ANNOTATERUN\x01run-1\x01incident".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_replay_run_round_trip() {
+        let msg = PrincipalAPI::ReplayRun("run-1".into());
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(parsed, PrincipalAPI::ReplayRun(id) if id == "run-1"));
+        assert!(PrincipalAPI::try_from("REPLAYRUN".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_diagnose_dispatch_round_trip() {
+        let msg = PrincipalAPI::DiagnoseDispatch("run-1".into());
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(parsed, PrincipalAPI::DiagnoseDispatch(id) if id == "run-1"));
+        assert!(PrincipalAPI::try_from("DIAGNOSEDISPATCH".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_stop_agent_round_trip() {
+        let parsed =
+            PrincipalAPI::try_from(PrincipalAPI::StopAgent("agent".to_string(), None).to_string())
+                .unwrap();
+        assert!(matches!(parsed, PrincipalAPI::StopAgent(id, None) if id == "agent"));
+        let parsed =
+            PrincipalAPI::try_from(PrincipalAPI::AgentStopped("agent".to_string()).to_string())
+                .unwrap();
+        assert!(matches!(parsed, PrincipalAPI::AgentStopped(id) if id == "agent"));
+        assert!(PrincipalAPI::try_from("STOPAGENT".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_artifact_round_trip() {
+        let msg = PrincipalAPI::PutArtifact(
+            "run-1".into(),
+            "out/data.csv".to_string(),
+            4,
+            "aGVsbG8=".to_string(),
+        );
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::PutArtifact(id, path, 4, chunk)
+                if id == "run-1" && path == "out/data.csv" && chunk == "aGVsbG8="
+        ));
+        let msg = PrincipalAPI::GetArtifact("run-1".into(), "out/data.csv".to_string(), 0);
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::GetArtifact(id, path, 0) if id == "run-1" && path == "out/data.csv"
+        ));
+        // paths can hold the delimiter and escape characters
+        let awkward_path = "out/a\x01b\x10c.csv".to_string();
+        let msg = PrincipalAPI::PutArtifact(
+            "run-1".into(),
+            awkward_path.clone(),
+            0,
+            "aGVsbG8=".to_string(),
+        );
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::PutArtifact(_, path, 0, chunk) if path == awkward_path && chunk == "aGVsbG8="
+        ));
+        let msg = PrincipalAPI::GetArtifact("run-1".into(), awkward_path.clone(), 8);
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::GetArtifact(_, path, 8) if path == awkward_path
+        ));
+        assert!(
+            PrincipalAPI::try_from("GETARTIFACT\x01run-1\x01data.csv\x01start".to_string())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_query_logs_page_round_trip() {
+        let msg = PrincipalAPI::QueryLogs(
+            Some(2000),
+            None,
+            Some("my.flow".to_string()),
+            None,
+            false,
+            Some(LogPage {
+                offset: 1000,
+                limit: 500,
+            }),
+        );
+        let parsed = PrincipalAPI::try_from(msg.to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::QueryLogs(Some(2000), None, Some(id), None, false, Some(page))
+                if id == "my.flow" && page == LogPage { offset: 1000, limit: 500 }
+        ));
+        // the page is optional on the wire
+        let parsed = PrincipalAPI::try_from("QUERYLOGS\x01\x01\x01\x01\x01v".to_string()).unwrap();
+        assert!(matches!(
+            parsed,
+            PrincipalAPI::QueryLogs(None, None, None, None, true, None)
+        ));
+        assert!(PrincipalAPI::try_from("QUERYLOGS\x01\x01\x01\x01\x01\x010".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_status_updates_send_instance_ids_as_plain_strings() {
+        let msg = PrincipalAPI::WorkflowStatusUpdate(
+            "agent-1".to_string(),
+            "my.flow".to_string(),
+            "jumping-monkey-0".into(),
+            RunStatus::RUNNING,
+        );
+        let wire = "AGENTWORKFLOWSTATUS\x01agent-1\x01my.flow\x01jumping-monkey-0\x01RUNNING";
+        assert_eq!(msg.to_string(), wire);
+        assert!(matches!(
+            PrincipalAPI::try_from(wire.to_string()).unwrap(),
+            PrincipalAPI::WorkflowStatusUpdate(_, _, id, RunStatus::RUNNING) if id == "jumping-monkey-0"
+        ));
+
+        let msg = PrincipalAPI::TaskStatusUpdate(
+            "agent-1".to_string(),
+            "extract".to_string(),
+            "task-ins-1".into(),
+            "jumping-monkey-0".into(),
+            RunStatus::FAILED,
+            Some(2),
+            false,
+        );
+        let wire = "AGENTTASKSTATUS\x01agent-1\x01extract\x01task-ins-1\x01jumping-monkey-0\x01FAILED\x012";
+        assert_eq!(msg.to_string(), wire);
+        assert!(matches!(
+            PrincipalAPI::try_from(wire.to_string()).unwrap(),
+            PrincipalAPI::TaskStatusUpdate(_, _, task_ins, wf_ins, RunStatus::FAILED, Some(2), false)
+                if task_ins == "task-ins-1" && wf_ins == "jumping-monkey-0"
+        ));
+
+        // a truncated task without an exit code leaves the exit code empty
+        let msg = PrincipalAPI::TaskStatusUpdate(
+            "agent-1".to_string(),
+            "extract".to_string(),
+            "task-ins-1".into(),
+            "jumping-monkey-0".into(),
+            RunStatus::CRASHED,
+            None,
+            true,
+        );
+        let wire = "AGENTTASKSTATUS\x01agent-1\x01extract\x01task-ins-1\x01jumping-monkey-0\x01CRASHED\x01\x01true";
+        assert_eq!(msg.to_string(), wire);
+        assert!(matches!(
+            PrincipalAPI::try_from(wire.to_string()).unwrap(),
+            PrincipalAPI::TaskStatusUpdate(_, _, _, _, RunStatus::CRASHED, None, true)
+        ));
+        // the exit code and truncated flag are optional trailing arguments
+        let wire =
+            "AGENTTASKSTATUS\x01agent-1\x01extract\x01task-ins-1\x01jumping-monkey-0\x01RUNNING";
+        assert!(matches!(
+            PrincipalAPI::try_from(wire.to_string()).unwrap(),
+            PrincipalAPI::TaskStatusUpdate(_, _, _, _, RunStatus::RUNNING, None, false)
+        ));
+        assert!(PrincipalAPI::try_from(format!("{wire}\x01\x01yes")).is_err());
+    }
+
+    #[test]
+    fn test_run_requests_send_instance_ids_as_plain_strings() {
+        let cases = [
+            (
+                PrincipalAPI::TaskProgress(
+                    "agent-1".to_string(),
+                    "task-ins-1".into(),
+                    50,
+                    "half".to_string(),
+                ),
+                "TASKPROGRESS\x01agent-1\x01task-ins-1\x0150\x01half",
+            ),
+            (
+                PrincipalAPI::TaskArtifacts("agent-1".to_string(), "task-ins-1".into(), vec![]),
+                "TASKARTIFACTS\x01agent-1\x01task-ins-1\x01[]",
+            ),
+            (
+                PrincipalAPI::WorkflowProgress(
+                    "agent-1".to_string(),
+                    "jumping-monkey-0".into(),
+                    1,
+                    2,
+                ),
+                "WORKFLOWPROGRESS\x01agent-1\x01jumping-monkey-0\x011\x012",
+            ),
+            (
+                PrincipalAPI::WorkflowOutputs(
+                    "agent-1".to_string(),
+                    "my.flow".to_string(),
+                    "jumping-monkey-0".into(),
+                    std::collections::HashMap::new(),
+                ),
+                "AGENTWORKFLOWOUTPUTS\x01agent-1\x01my.flow\x01jumping-monkey-0\x01{}",
+            ),
+            (
+                PrincipalAPI::GetWorkflowResult("jumping-monkey-0".into()),
+                "GETWORKFLOWRESULT\x01jumping-monkey-0",
+            ),
+            (
+                PrincipalAPI::GetWorkflowTail("jumping-monkey-0".into(), 10),
+                "GETWORKFLOWTAIL\x01jumping-monkey-0\x0110",
+            ),
+            (
+                PrincipalAPI::AnnotateRun(
+                    "jumping-monkey-0".into(),
+                    "incident".to_string(),
+                    "INC-1".to_string(),
+                ),
+                "ANNOTATERUN\x01jumping-monkey-0\x01incident\x01INC-1",
+            ),
+            (
+                PrincipalAPI::PutArtifact(
+                    "jumping-monkey-0".into(),
+                    "data.csv".to_string(),
+                    0,
+                    "aGk=".to_string(),
+                ),
+                "PUTARTIFACT\x01jumping-monkey-0\x01data.csv\x010\x01aGk=",
+            ),
+            (
+                PrincipalAPI::GetArtifact("jumping-monkey-0".into(), "data.csv".to_string(), 0),
+                "GETARTIFACT\x01jumping-monkey-0\x01data.csv\x010",
+            ),
+            (
+                PrincipalAPI::ReplayRun("jumping-monkey-0".into()),
+                "REPLAYRUN\x01jumping-monkey-0",
+            ),
+            (
+                PrincipalAPI::DiagnoseDispatch("jumping-monkey-0".into()),
+                "DIAGNOSEDISPATCH\x01jumping-monkey-0",
+            ),
+        ];
+        for (msg, wire) in cases {
+            assert_eq!(msg.to_string(), wire);
+            let parsed = PrincipalAPI::try_from(wire.to_string()).unwrap();
+            assert_eq!(parsed.to_string(), wire);
+            match parsed {
+                PrincipalAPI::TaskProgress(_, id, ..) | PrincipalAPI::TaskArtifacts(_, id, _) => {
+                    assert_eq!(id, "task-ins-1")
+                }
+                PrincipalAPI::WorkflowProgress(_, id, ..)
+                | PrincipalAPI::WorkflowOutputs(_, _, id, _)
+                | PrincipalAPI::GetWorkflowResult(id)
+                | PrincipalAPI::GetWorkflowTail(id, _)
+                | PrincipalAPI::AnnotateRun(id, ..)
+                | PrincipalAPI::PutArtifact(id, ..)
+                | PrincipalAPI::GetArtifact(id, ..)
+                | PrincipalAPI::ReplayRun(id)
+                | PrincipalAPI::DiagnoseDispatch(id) => assert_eq!(id, "jumping-monkey-0"),
+                other => panic!("{wire:?} parsed as {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_agent_metrics_round_trip() {
+        let metrics = AgentMetrics {
+            timestamp_ms: 1_700_000_000_000,
+            running_workflows: 2,
+            cpu_percent: 37.5,
+            memory_used_bytes: 1_024,
+            memory_total_bytes: 4_096,
+            running_tasks: std::collections::HashMap::from([("etl/extract".to_string(), 2)]),
+        };
+        let msg = PrincipalAPI::AgentMetrics("agent-1".to_string(), metrics.clone());
+        match PrincipalAPI::try_from(msg.to_string()).unwrap() {
+            PrincipalAPI::AgentMetrics(agent_id, parsed) => {
+                assert_eq!(agent_id, "agent-1");
+                assert_eq!(parsed, metrics);
+            }
+            other => panic!("Expected AgentMetrics, got {:?}", other),
+        }
+        assert!(PrincipalAPI::try_from("AGENTMETRICS\x01agent-1\x01{}".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_run_task_params_round_trip() {
+        let params = std::collections::HashMap::from([("table".to_string(), "orders".to_string())]);
+        let msg = PrincipalAPI::RunTask("my.flow".to_string(), params.clone(), false, None);
+        match PrincipalAPI::try_from(msg.to_string()).unwrap() {
+            PrincipalAPI::RunTask(task_id, parsed, false, None) => {
+                assert_eq!(task_id, "my.flow");
+                assert_eq!(parsed, params);
+            }
+            other => panic!("Expected RunTask, got {:?}", other),
+        }
+        // params are optional on the wire
+        let parsed = PrincipalAPI::try_from("RUNTASK\x01my.flow".to_string()).unwrap();
+        assert!(matches!(parsed, PrincipalAPI::RunTask(_, p, false, None) if p.is_empty()));
+        assert!(PrincipalAPI::try_from("RUNTASK\x01my.flow\x01not json".to_string()).is_err());
+
+        let msg = PrincipalAPI::RunTask("my.flow".to_string(), Default::default(), true, None);
+        assert!(matches!(
+            PrincipalAPI::try_from(msg.to_string()).unwrap(),
+            PrincipalAPI::RunTask(_, p, true, None) if p.is_empty()
+        ));
+
+        let msg = PrincipalAPI::RunTask(
+            "my.flow".to_string(),
+            Default::default(),
+            false,
+            Some("prod".to_string()),
+        );
+        assert!(matches!(
+            PrincipalAPI::try_from(msg.to_string()).unwrap(),
+            PrincipalAPI::RunTask(_, _, false, Some(environment)) if environment == "prod"
+        ));
+        assert!(PrincipalAPI::try_from("RUNTASK\x01my.flow\x01{}\x01maybe".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_task_progress_round_trip() {
+        let msg = PrincipalAPI::TaskProgress(
+            "agent".to_string(),
+            "task-ins".into(),
+            50,
+            "step 3 of 10".to_string(),
+        );
+        assert!(matches!(
+            PrincipalAPI::try_from(msg.to_string()).unwrap(),
+            PrincipalAPI::TaskProgress(agent_id, task_exe_id, 50, message)
+                if agent_id == "agent" && task_exe_id == "task-ins" && message == "step 3 of 10"
+        ));
+        assert!(
+            PrincipalAPI::try_from("TASKPROGRESS\x01agent\x01task-ins\x01101".to_string()).is_err()
+        );
+        assert!(
+            PrincipalAPI::try_from("TASKPROGRESS\x01agent\x01task-ins\x01half".to_string())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_task_artifacts_round_trip() {
+        let artifacts = vec![ArtifactInfo {
+            path: "out/orders.csv".to_string(),
+            size_bytes: 1024,
+        }];
+        let msg =
+            PrincipalAPI::TaskArtifacts("agent".to_string(), "task-ins".into(), artifacts.clone());
+        assert!(matches!(
+            PrincipalAPI::try_from(msg.to_string()).unwrap(),
+            PrincipalAPI::TaskArtifacts(agent_id, task_exe_id, parsed)
+                if agent_id == "agent" && task_exe_id == "task-ins" && parsed == artifacts
+        ));
+        assert!(
+            PrincipalAPI::try_from("TASKARTIFACTS\x01agent\x01task-ins\x01{}".to_string()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_get_workflow_tail_round_trip() {
+        let msg = PrincipalAPI::GetWorkflowTail("wf-ins".into(), 100);
+        assert!(matches!(
+            PrincipalAPI::try_from(msg.to_string()).unwrap(),
+            PrincipalAPI::GetWorkflowTail(workflow_instance_id, 100) if workflow_instance_id == "wf-ins"
+        ));
+        assert!(PrincipalAPI::try_from("GETWORKFLOWTAIL\x01wf-ins".to_string()).is_err());
+        assert!(PrincipalAPI::try_from("GETWORKFLOWTAIL\x01wf-ins\x01-1".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_get_audit_log_round_trip() {
+        let msg = PrincipalAPI::GetAuditLog(50);
+        assert!(matches!(
+            PrincipalAPI::try_from(msg.to_string()).unwrap(),
+            PrincipalAPI::GetAuditLog(50)
+        ));
+        assert!(PrincipalAPI::try_from("GETAUDITLOG".to_string()).is_err());
+        assert!(PrincipalAPI::try_from("GETAUDITLOG\x01all".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_acquire_scheduler_lease_round_trip() {
+        let msg = PrincipalAPI::AcquireSchedulerLease("host/SCHED".to_string(), 15_000);
+        assert!(matches!(
+            PrincipalAPI::try_from(msg.to_string()).unwrap(),
+            PrincipalAPI::AcquireSchedulerLease(scheduler_id, 15_000) if scheduler_id == "host/SCHED"
+        ));
+        assert!(PrincipalAPI::try_from("ACQUIRESCHEDULERLEASE\x01host/SCHED".to_string()).is_err());
+        assert!(
+            PrincipalAPI::try_from("ACQUIRESCHEDULERLEASE\x01host/SCHED\x01soon".to_string())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_workflow_progress_round_trip() {
+        let msg = PrincipalAPI::WorkflowProgress("agent".to_string(), "wf-ins".into(), 2, 5);
+        assert!(matches!(
+            PrincipalAPI::try_from(msg.to_string()).unwrap(),
+            PrincipalAPI::WorkflowProgress(agent_id, workflow_instance_id, 2, 5)
+                if agent_id == "agent" && workflow_instance_id == "wf-ins"
+        ));
+        assert!(
+            PrincipalAPI::try_from("WORKFLOWPROGRESS\x01agent\x01wf-ins\x016\x015".to_string())
+                .is_err()
+        );
+        assert!(
+            PrincipalAPI::try_from("WORKFLOWPROGRESS\x01agent\x01wf-ins\x01two\x015".to_string())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_workflow_outputs_round_trip() {
+        let outputs = std::collections::HashMap::from([(
+            "watermark".to_string(),
+            "2025-06-01\x01x".to_string(),
+        )]);
+        let msg = PrincipalAPI::WorkflowOutputs(
+            "agent".to_string(),
+            "my.flow".to_string(),
+            "wf-ins".into(),
+            outputs.clone(),
+        );
+        assert!(matches!(
+            PrincipalAPI::try_from(msg.to_string()).unwrap(),
+            PrincipalAPI::WorkflowOutputs(agent_id, workflow_id, workflow_instance_id, parsed)
+                if agent_id == "agent"
+                    && workflow_id == "my.flow"
+                    && workflow_instance_id == "wf-ins"
+                    && parsed == outputs
+        ));
+        assert!(
+            PrincipalAPI::try_from(
+                "AGENTWORKFLOWOUTPUTS\x01agent\x01my.flow\x01wf-ins\x01[1]".to_string()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_dry_run_task_round_trip() {
+        let params = std::collections::HashMap::from([("table".to_string(), "orders".to_string())]);
+        let msg = PrincipalAPI::DryRunTask("my.flow".to_string(), params.clone());
+        assert!(msg.to_string().starts_with("DRYRUNTASK\x01my.flow\x01"));
+        assert!(matches!(
+            PrincipalAPI::try_from(msg.to_string()).unwrap(),
+            PrincipalAPI::DryRunTask(task_id, parsed) if task_id == "my.flow" && parsed == params
+        ));
+    }
 }
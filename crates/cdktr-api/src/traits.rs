@@ -1,17 +1,17 @@
 use std::time::Duration;
 
+use crate::connection::{ConnectionMonitor, retry_with_monitor};
 use crate::models::{ClientResponseMessage, RepReqError};
 use cdktr_core::{
-    exceptions::{GenericError, ZMQParseError},
+    exceptions::GenericError,
     get_cdktr_setting,
     models::ZMQArgs,
-    utils::get_default_zmq_timeout,
+    utils::{get_default_zmq_timeout, get_request_timeout},
     zmq_helpers::send_recv_with_timeout,
 };
 
 use async_trait::async_trait;
-use log::{info, trace, warn};
-use tokio::time::sleep;
+use log::trace;
 use zeromq::ZmqMessage;
 
 pub struct APIMeta {
@@ -46,13 +46,22 @@ pub trait API: Into<ZmqMessage> + TryFrom<ZmqMessage> + TryFrom<String> + TryFro
     /// Convert the message to a string to pass on ZMQ
     fn to_string(&self) -> String;
 
+    /// Name of the message's variant, used to identify requests in the server's access log
+    fn message_type(&self) -> &'static str;
+
     fn get_tcp_uri(&self) -> String;
 
+    /// Timeout to wait for a response to this message. Messages the server may
+    /// hold open should override this to allow for the extra wait
+    fn get_timeout(&self) -> Duration {
+        get_request_timeout()
+    }
+
     /// Default implementation for sending the message to a destination REP socket
     async fn send(self) -> Result<ClientResponseMessage, GenericError> {
         let tcp_uri = self.get_tcp_uri();
         trace!("Requesting @ {} with msg: {}", tcp_uri, self.to_string());
-        let timeout = get_default_zmq_timeout();
+        let timeout = self.get_timeout();
         let zmq_m = send_recv_with_timeout(tcp_uri.to_string(), self.into(), timeout)
             .await
             .map_err(|e| {
@@ -70,12 +79,10 @@ pub trait API: Into<ZmqMessage> + TryFrom<ZmqMessage> + TryFrom<String> + TryFro
 
     /// Send a message with retry logic for PrincipalTimeoutError
     ///
-    /// This method will retry sending the message up to max_retries times if a
-    /// PrincipalTimeoutError occurs. Other errors are returned immediately.
+    /// This method will retry sending the message up to max_retries times if the
+    /// principal can't be reached. Other errors are returned immediately.
     ///
     /// # Arguments
-    /// * `tcp_uri` - The URI to send the message to
-    /// * `timeout` - Timeout for each individual send attempt
     /// * `max_retries` - Maximum number of retry attempts (defaults to CDKTR_RETRY_ATTEMPTS if None)
     /// * `retry_delay` - Delay between retry attempts (defaults to timeout if None)
     async fn send_with_retry(
@@ -83,68 +90,28 @@ pub trait API: Into<ZmqMessage> + TryFrom<ZmqMessage> + TryFrom<String> + TryFro
         max_retries: Option<usize>,
         retry_delay: Option<Duration>,
     ) -> Result<ClientResponseMessage, GenericError>
+    where
+        Self: Sized + Clone,
+    {
+        self.send_monitored(&ConnectionMonitor::new(), max_retries, retry_delay)
+            .await
+    }
+
+    /// Same as `send_with_retry` but records connection losses and recoveries on
+    /// `monitor`, so a client that shares one monitor across its requests can report
+    /// how often its connection with the principal flaps
+    async fn send_monitored(
+        self,
+        monitor: &ConnectionMonitor,
+        max_retries: Option<usize>,
+        retry_delay: Option<Duration>,
+    ) -> Result<ClientResponseMessage, GenericError>
     where
         Self: Sized + Clone,
     {
         let max_attempts =
             max_retries.unwrap_or_else(|| get_cdktr_setting!(CDKTR_RETRY_ATTEMPTS, usize));
         let delay = retry_delay.unwrap_or(get_default_zmq_timeout());
-        let mut attempts = 0;
-
-        loop {
-            let result = self.clone().send().await;
-
-            match result {
-                Ok(response) => {
-                    if attempts > 0 {
-                        info!(
-                            "Successfully re-connected with principal after {} attempt(s)",
-                            attempts
-                        );
-                    }
-                    return Ok(response);
-                }
-                Err(GenericError::PrincipalTimeoutError) => {
-                    attempts += 1;
-                    if attempts >= max_attempts {
-                        warn!(
-                            "Max retry attempts ({}) reached - connection with principal has been lost",
-                            max_attempts
-                        );
-                        return Err(GenericError::PrincipalTimeoutError);
-                    }
-                    warn!(
-                        "Failed to communicate to principal - trying again in {} ms (attempt {} of {})",
-                        delay.as_millis(),
-                        attempts,
-                        max_attempts
-                    );
-                    sleep(delay).await;
-                }
-                Err(GenericError::ZMQParseError(ZMQParseError::ParseError(ref msg)))
-                    if msg.contains("Connection reset by peer") || msg.contains("Codec Error") =>
-                {
-                    attempts += 1;
-                    if attempts >= max_attempts {
-                        warn!(
-                            "Max retry attempts ({}) reached - connection with principal has been lost",
-                            max_attempts
-                        );
-                        return Err(GenericError::ZMQParseError(ZMQParseError::ParseError(
-                            msg.clone(),
-                        )));
-                    }
-                    warn!(
-                        "Connection error ({}), trying again in {} ms (attempt {} of {})",
-                        msg,
-                        delay.as_millis(),
-                        attempts,
-                        max_attempts
-                    );
-                    sleep(delay).await;
-                }
-                Err(e) => return Err(e),
-            }
-        }
+        retry_with_monitor(monitor, max_attempts, delay, move || self.clone().send()).await
     }
 }
@@ -1,9 +1,45 @@
+use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage};
+use log::error;
+use std::collections::HashMap;
+
+use super::failure::{EXIT_REJECTED, RequestFailure};
 use super::models::TaskAction;
 
 #[derive(clap::Args)]
 #[command(version, about, long_about = None)]
 pub struct TaskArgs {
-    /// Create a new scheduled task
+    /// What to do with the workflow
     #[arg(long, short)]
     action: TaskAction,
+
+    /// Id of the workflow to act on
+    workflow_id: String,
+}
+
+pub async fn handle_task(args: TaskArgs) {
+    match args.action {
+        TaskAction::Create => {
+            error!(
+                "Workflows can't be created through the principal - add a workflow file for {} to CDKTR_WORKFLOW_DIR instead",
+                args.workflow_id
+            );
+            std::process::exit(EXIT_REJECTED);
+        }
+        TaskAction::Trigger => {
+            let action = format!("trigger workflow {}", args.workflow_id);
+            match PrincipalAPI::RunTask(args.workflow_id.clone(), HashMap::new(), false, None)
+                .send()
+                .await
+            {
+                Ok(ClientResponseMessage::SuccessWithPayload(instance_id)) => {
+                    println!("Triggered workflow {} as {}", args.workflow_id, instance_id)
+                }
+                Ok(ClientResponseMessage::Success) => {
+                    println!("Triggered workflow {}", args.workflow_id)
+                }
+                Ok(other) => RequestFailure::from_response(other).exit(&action),
+                Err(e) => RequestFailure::from_error(e).exit(&action),
+            }
+        }
+    }
 }
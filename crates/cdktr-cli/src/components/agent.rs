@@ -0,0 +1,47 @@
+use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage};
+use cdktr_core::utils::get_instance_id;
+use log::error;
+
+/// Manage the agents registered with the principal
+#[derive(clap::Args)]
+#[command(version, about, long_about = None)]
+pub struct AgentArgs {
+    #[command(subcommand)]
+    pub action: AgentAction,
+}
+
+#[derive(clap::Subcommand)]
+pub enum AgentAction {
+    /// Stop an agent remotely. It takes no new workflows and exits once
+    /// its running workflows finish
+    Stop {
+        /// Id of the agent to stop
+        agent_id: String,
+    },
+}
+
+pub async fn handle_agent(args: AgentArgs) {
+    match args.action {
+        AgentAction::Stop { agent_id } => stop_agent(agent_id).await,
+    }
+}
+
+async fn stop_agent(agent_id: String) {
+    match PrincipalAPI::StopAgent(agent_id.clone(), Some(get_instance_id()))
+        .send()
+        .await
+    {
+        Ok(ClientResponseMessage::Success) => println!(
+            "Stopping agent {} - it exits once its running workflows finish",
+            agent_id
+        ),
+        Ok(other) => {
+            error!("Failed to stop agent: {}", other.payload());
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
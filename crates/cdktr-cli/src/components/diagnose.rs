@@ -0,0 +1,52 @@
+use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage, models::DispatchDiagnosis};
+use cdktr_core::models::WorkflowInstanceId;
+use log::error;
+
+/// Explain why a workflow run hasn't been sent to an agent yet
+#[derive(clap::Args)]
+#[command(version, about, long_about = None)]
+pub struct DiagnoseArgs {
+    /// Instance id of the queued workflow run
+    pub workflow_instance_id: WorkflowInstanceId,
+}
+
+pub async fn handle_diagnose(args: DiagnoseArgs) {
+    let payload = match PrincipalAPI::DiagnoseDispatch(args.workflow_instance_id)
+        .send()
+        .await
+    {
+        Ok(ClientResponseMessage::SuccessWithPayload(payload)) => payload,
+        Ok(other) => {
+            error!("Failed to diagnose run: {}", other.payload());
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let diagnosis: DispatchDiagnosis = match serde_json::from_str(&payload) {
+        Ok(diagnosis) => diagnosis,
+        Err(e) => {
+            error!("Unexpected response from principal: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "Run {} of {}",
+        diagnosis.workflow_instance_id, diagnosis.workflow_id
+    );
+    match diagnosis.queue_position {
+        Some(position) => {
+            println!("Queue position: {} of {}", position, diagnosis.queue_length);
+            println!(
+                "Agents: {} registered, {} can run it, {} available now",
+                diagnosis.agents, diagnosis.matching_agents, diagnosis.available_agents
+            );
+        }
+        None => println!("Not queued"),
+    }
+    for reason in diagnosis.reasons {
+        println!("- {}", reason);
+    }
+}
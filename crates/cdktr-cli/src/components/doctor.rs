@@ -0,0 +1,315 @@
+use cdktr_api::{PrincipalAPI, models::ClientResponseMessage};
+use cdktr_core::{
+    config::parse_port,
+    get_cdktr_setting,
+    utils::get_request_timeout,
+    zmq_helpers::{get_server_tcp_uri, send_recv_with_timeout},
+};
+use std::path::Path;
+use std::time::Duration;
+
+/// Settings that are read as unsigned integers with `get_cdktr_setting!(.., usize)`.
+/// The macro silently falls back to the default on a bad value so these are
+/// checked explicitly
+const USIZE_SETTINGS: &[&str] = &[
+    "CDKTR_AGENT_MAX_CONCURRENCY",
+    "CDKTR_RETRY_ATTEMPTS",
+    "CDKTR_DEFAULT_ZMQ_TIMEOUT_MS",
+    "CDKTR_CONNECT_TIMEOUT_MS",
+    "CDKTR_REQUEST_TIMEOUT_MS",
+    "CDKTR_DEFAULT_ZMQ_REP_FREFRESH_INTERVAL_MS",
+    "CDKTR_WORKFLOW_FETCH_LONG_POLL_MS",
+    "CDKTR_MAX_LONG_POLL_MS",
+    "CDKTR_AGENT_MIN_POLL_INTERVAL_MS",
+    "CDKTR_AGENT_PUSH_PORT",
+    "CDKTR_AGENT_TASK_CACHE_TTL_S",
+    "CDKTR_AGENT_MAX_TASK_OUTPUT_BYTES",
+    "CDKTR_AGENT_TERM_GRACE_S",
+    "CDKTR_PRINCIPAL_PORT",
+    "CDKTR_LOGS_LISTENING_PORT",
+    "CDKTR_LOGS_PUBLISHING_PORT",
+    "CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S",
+    "CDKTR_WORKFLOW_MAX_FILE_BYTES",
+    "CDKTR_WORKFLOW_MAX_TASKS",
+    "CDKTR_WORKFLOW_MAX_DEPTH",
+    "CDKTR_SCHEDULER_START_POLL_FREQUENCY_MS",
+    "CDKTR_SCHEDULER_LEASE_TTL_MS",
+    "CDKTR_Q_PERSISTENCE_INTERVAL_MS",
+    "CDKTR_TUI_STATUS_REFRESH_INTERVAL_MS",
+    "CDKTR_TUI_RECONNECT_ATTEMPTS",
+    "CDKTR_AGENT_HEARTBEAT_TIMEOUT_MS",
+    "CDKTR_AGENT_METRICS_INTERVAL_S",
+    "CDKTR_QUEUE_SLOW_CONSUMER_WARNING_S",
+    "CDKTR_MAX_ARTIFACT_BYTES",
+    "CDKTR_MAX_ARTIFACT_STORE_BYTES",
+    "CDKTR_MAX_MESSAGE_BYTES",
+    "CDKTR_PRINCIPAL_QUERY_WORKERS",
+];
+
+/// Check the local environment for common setup problems such as
+/// an unwritable data directory, missing executables, an unreachable
+/// principal or invalid config values
+#[derive(clap::Args)]
+#[command(version, about, long_about = None)]
+pub struct DoctorArgs {
+    /// Skip checking that the principal is reachable, e.g. when
+    /// setting up the principal itself
+    #[arg(long, default_value_t = false)]
+    pub skip_principal: bool,
+}
+
+#[derive(Debug)]
+struct CheckResult {
+    name: String,
+    passed: bool,
+    detail: String,
+    hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: &str, detail: String) -> Self {
+        Self {
+            name: name.to_string(),
+            passed: true,
+            detail,
+            hint: None,
+        }
+    }
+
+    fn fail(name: &str, detail: String, hint: String) -> Self {
+        Self {
+            name: name.to_string(),
+            passed: false,
+            detail,
+            hint: Some(hint),
+        }
+    }
+}
+
+/// Checks the application data directory can be created and written to
+fn check_data_dir(app_data_dir: &Path) -> CheckResult {
+    let name = "data directory writable";
+    let probe = app_data_dir.join(".cdktr-doctor");
+    let res = std::fs::create_dir_all(app_data_dir)
+        .and_then(|_| std::fs::write(&probe, b"ok"))
+        .and_then(|_| std::fs::remove_file(&probe));
+    match res {
+        Ok(()) => CheckResult::pass(name, app_data_dir.display().to_string()),
+        Err(e) => CheckResult::fail(
+            name,
+            format!("{}: {}", app_data_dir.display(), e),
+            "Check the permissions of the directory or point CDKTR_APP_DATA_DIRECTORY \
+            at a writable location"
+                .to_string(),
+        ),
+    }
+}
+
+/// Checks an executable can be found on the PATH
+fn check_executable(executable: &str, hint: &str) -> CheckResult {
+    let name = format!("{} installed", executable);
+    let found = std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(executable))
+            .find(|path| path.is_file())
+    });
+    match found {
+        Some(path) => CheckResult::pass(&name, path.display().to_string()),
+        None => CheckResult::fail(
+            &name,
+            format!("{} was not found on the PATH", executable),
+            hint.to_string(),
+        ),
+    }
+}
+
+/// Checks the principal responds to a ping
+async fn check_principal(host: &str, port: usize, timeout: Duration) -> CheckResult {
+    let name = "principal reachable";
+    let uri = get_server_tcp_uri(host, port);
+    let hint = "Start the principal with `cdktr start principal` or check \
+        CDKTR_PRINCIPAL_HOST and CDKTR_PRINCIPAL_PORT"
+        .to_string();
+    match send_recv_with_timeout(uri.clone(), PrincipalAPI::Ping.into(), timeout).await {
+        Ok(msg) => match ClientResponseMessage::from(msg) {
+            ClientResponseMessage::Pong => CheckResult::pass(name, uri),
+            other => CheckResult::fail(
+                name,
+                format!("{} responded with an unexpected message: {:?}", uri, other),
+                hint,
+            ),
+        },
+        Err(e) => CheckResult::fail(name, format!("{}: {}", uri, e), hint),
+    }
+}
+
+/// Checks every workflow in the workflow directory parses, so that broken workflows are
+/// caught before the principal skips them
+async fn check_workflows(workflow_dir: &str) -> CheckResult {
+    let name = "workflows valid";
+    match cdktr_workflow::try_get_yaml_map::<cdktr_workflow::Workflow>(workflow_dir).await {
+        Ok(workflows) => CheckResult::pass(
+            name,
+            format!("{} workflow(s) in {}", workflows.len(), workflow_dir),
+        ),
+        Err(e) => CheckResult::fail(
+            name,
+            e.to_string(),
+            "Fix the listed workflow definitions. The principal skips them, or refuses \
+            to start with CDKTR_WORKFLOW_STRICT_LOADING=true"
+                .to_string(),
+        ),
+    }
+}
+
+/// Checks a setting that is read as an unsigned integer is valid, if it is set. Port
+/// settings must also be in the range of a valid port
+fn check_usize_setting(setting: &str, value: Option<String>) -> CheckResult {
+    let name = format!("{} valid", setting);
+    match value {
+        None => CheckResult::pass(&name, "not set, using default".to_string()),
+        Some(v) if setting.ends_with("_PORT") => match parse_port(setting, &v) {
+            Ok(_) => CheckResult::pass(&name, v),
+            Err(e) => CheckResult::fail(
+                &name,
+                e.to_string(),
+                format!(
+                    "Set {} to a free port or unset it to use the default",
+                    setting
+                ),
+            ),
+        },
+        Some(v) => match v.parse::<usize>() {
+            Ok(_) => CheckResult::pass(&name, v),
+            Err(_) => CheckResult::fail(
+                &name,
+                format!("'{}' is not a valid unsigned integer", v),
+                format!(
+                    "Set {} to a whole number or unset it to use the default",
+                    setting
+                ),
+            ),
+        },
+    }
+}
+
+pub async fn handle_doctor(args: DoctorArgs, app_data_dir: &Path) {
+    let mut results = vec![
+        check_data_dir(app_data_dir),
+        check_executable(
+            "uv",
+            "Install uv (https://docs.astral.sh/uv/) to run UvPython tasks",
+        ),
+    ];
+    if !get_cdktr_setting!(CDKTR_WORKFLOW_GIT_URL).is_empty() {
+        results.push(check_executable(
+            "git",
+            "Install git or unset CDKTR_WORKFLOW_GIT_URL to load workflows from CDKTR_WORKFLOW_DIR",
+        ));
+    } else {
+        results.push(check_workflows(&get_cdktr_setting!(CDKTR_WORKFLOW_DIR)).await);
+    }
+    results.extend(
+        USIZE_SETTINGS
+            .iter()
+            .map(|setting| check_usize_setting(setting, std::env::var(setting).ok())),
+    );
+    if !args.skip_principal {
+        results.push(
+            check_principal(
+                &get_cdktr_setting!(CDKTR_PRINCIPAL_HOST),
+                get_cdktr_setting!(CDKTR_PRINCIPAL_PORT, usize),
+                get_request_timeout(),
+            )
+            .await,
+        );
+    }
+
+    for result in results.iter() {
+        let mark = if result.passed { "PASS" } else { "FAIL" };
+        println!("[{}] {} ({})", mark, result.name, result.detail);
+        if let Some(hint) = &result.hint {
+            println!("       hint: {}", hint);
+        }
+    }
+    let failures = results.iter().filter(|r| !r.passed).count();
+    if failures > 0 {
+        println!("\n{} check(s) failed", failures);
+        std::process::exit(1);
+    }
+    println!("\nAll checks passed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "cdktr-doctor-{name}-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ))
+    }
+
+    #[test]
+    fn test_check_data_dir() {
+        let dir = temp_path("data");
+        assert!(check_data_dir(&dir).passed);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // a directory can't be created beneath a regular file
+        let file = temp_path("file");
+        std::fs::write(&file, b"").unwrap();
+        let result = check_data_dir(&file.join("data"));
+        assert!(!result.passed);
+        assert!(result.hint.is_some());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_check_executable() {
+        assert!(check_executable("sh", "").passed);
+        assert!(!check_executable("cdktr-definitely-not-installed", "").passed);
+    }
+
+    #[test]
+    fn test_check_usize_setting() {
+        assert!(check_usize_setting("CDKTR_PRINCIPAL_PORT", None).passed);
+        assert!(check_usize_setting("CDKTR_PRINCIPAL_PORT", Some("5561".to_string())).passed);
+        assert!(!check_usize_setting("CDKTR_PRINCIPAL_PORT", Some("abc".to_string())).passed);
+        assert!(!check_usize_setting("CDKTR_PRINCIPAL_PORT", Some("-1".to_string())).passed);
+        assert!(!check_usize_setting("CDKTR_PRINCIPAL_PORT", Some("0".to_string())).passed);
+        assert!(!check_usize_setting("CDKTR_AGENT_PUSH_PORT", Some("70000".to_string())).passed);
+        assert!(check_usize_setting("CDKTR_RETRY_ATTEMPTS", Some("70000".to_string())).passed);
+    }
+
+    #[tokio::test]
+    async fn test_check_workflows() {
+        let dir = temp_path("workflows");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("good.yml"),
+            "name: Good\nstart_time: 2025-01-20T12:30:00+00:00\ntasks:\n  task1:\n    name: Task 1\n    config: !Subprocess\n      cmd: echo\n      args: [\"hello\"]\n",
+        )
+        .unwrap();
+        assert!(check_workflows(dir.to_str().unwrap()).await.passed);
+
+        std::fs::write(dir.join("broken.yml"), "name: [not valid").unwrap();
+        let result = check_workflows(dir.to_str().unwrap()).await;
+        assert!(!result.passed);
+        assert!(result.detail.contains("broken.yml"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_check_principal_unreachable() {
+        // nothing listens on this port
+        let result = check_principal("127.0.0.1", 1, Duration::from_millis(200)).await;
+        assert!(!result.passed);
+        assert!(result.hint.is_some());
+    }
+}
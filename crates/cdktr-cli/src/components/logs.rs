@@ -1,4 +1,5 @@
 use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage};
+use cdktr_core::models::WorkflowInstanceId;
 use cdktr_ipc::log_manager::{client::LogsClient, model::LogMessage};
 use log::error;
 use log::info;
@@ -32,7 +33,7 @@ pub struct LogArgs {
     /// Filter logs by a specific workflow instance
     /// id
     #[arg(long, short('i'))]
-    pub workflow_instance_id: Option<String>,
+    pub workflow_instance_id: Option<WorkflowInstanceId>,
 
     /// The number of log lines to return. Returns all
     /// if not provided
@@ -60,7 +61,7 @@ pub async fn handle_logs(args: LogArgs) {
         tail_logs(args, print_func).await
     } else {
         info!("Querying logs from db");
-        query_logs(args).await
+        query_logs(args, print_func).await
     }
 }
 
@@ -73,7 +74,7 @@ async fn tail_logs(args: LogArgs, print_func: impl Fn(LogMessage)) {
     {
         Ok(client) => client,
         Err(e) => {
-            println!("{}", e.to_string());
+            println!("{}", e);
             return;
         }
     };
@@ -84,7 +85,7 @@ async fn tail_logs(args: LogArgs, print_func: impl Fn(LogMessage)) {
     }
 }
 
-async fn query_logs(args: LogArgs) {
+async fn query_logs(args: LogArgs, print_func: impl Fn(LogMessage)) {
     let api = PrincipalAPI::QueryLogs(
         match args.end_datetime_utc {
             Some(dt) => Some(
@@ -105,21 +106,22 @@ async fn query_logs(args: LogArgs) {
         args.workflow_id,
         args.workflow_instance_id,
         args.verbose,
+        None,
     );
     let api_result = api.send().await;
     match api_result {
         Ok(msg) => match msg {
             ClientResponseMessage::SuccessWithPayload(payload) => {
-                let logs: Vec<String> =
+                let logs: Vec<LogMessage> =
                     serde_json::from_str(&payload).expect("Unable to read logs from API response");
                 for log_msg in logs {
-                    println!("{}", log_msg)
+                    print_func(log_msg)
                 }
             }
             other => error!("Unexpected response: {}", other.to_string()),
         },
         Err(e) => {
-            error!("{}", e.to_string())
+            error!("{}", e)
         }
     }
 }
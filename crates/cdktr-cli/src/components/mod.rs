@@ -1,2 +1,9 @@
+pub mod agent;
+pub mod diagnose;
+pub mod doctor;
 pub mod init;
 pub mod logs;
+pub mod queue;
+pub mod reload;
+pub mod replay;
+pub mod run;
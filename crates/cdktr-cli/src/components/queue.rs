@@ -0,0 +1,47 @@
+use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage};
+use cdktr_core::utils::get_instance_id;
+use log::error;
+
+/// Manage the principal's queue of workflows waiting for an agent
+#[derive(clap::Args)]
+#[command(version, about, long_about = None)]
+pub struct QueueArgs {
+    #[command(subcommand)]
+    pub action: QueueAction,
+}
+
+#[derive(clap::Subcommand)]
+pub enum QueueAction {
+    /// Remove every workflow waiting on the queue. Workflows that are
+    /// already running are unaffected
+    Flush {
+        /// Confirm the flush. Required as flushed workflows can't be recovered
+        #[arg(long, default_value_t = false)]
+        confirm: bool,
+    },
+}
+
+pub async fn handle_queue(args: QueueArgs) {
+    match args.action {
+        QueueAction::Flush { confirm } => flush_queue(confirm).await,
+    }
+}
+
+async fn flush_queue(confirm: bool) {
+    if !confirm {
+        eprintln!(
+            "Flushing removes every workflow waiting on the queue and can't be undone. Re-run with --confirm to flush"
+        );
+        std::process::exit(1);
+    }
+    match PrincipalAPI::FlushQueue(Some(get_instance_id()))
+        .send()
+        .await
+    {
+        Ok(ClientResponseMessage::SuccessWithPayload(count)) => {
+            println!("Flushed {} workflow(s) from the queue", count)
+        }
+        Ok(other) => error!("Unexpected response: {}", other.to_string()),
+        Err(e) => error!("{}", e),
+    }
+}
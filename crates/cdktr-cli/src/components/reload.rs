@@ -0,0 +1,47 @@
+use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage};
+use cdktr_core::{config::ConfigChange, utils::get_instance_id};
+use log::error;
+
+/// Reload the config of the running principal from its config file and env. Settings
+/// that can't be changed without a restart are listed but not applied
+#[derive(clap::Args)]
+#[command(version, about, long_about = None)]
+pub struct ReloadArgs {}
+
+pub async fn handle_reload(_args: ReloadArgs) {
+    let payload = match PrincipalAPI::ReloadConfig(Some(get_instance_id()))
+        .send()
+        .await
+    {
+        Ok(ClientResponseMessage::SuccessWithPayload(payload)) => payload,
+        Ok(other) => {
+            error!("Failed to reload config: {}", other.payload());
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let changes: Vec<ConfigChange> = match serde_json::from_str(&payload) {
+        Ok(changes) => changes,
+        Err(e) => {
+            error!("Unexpected response from principal: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if changes.is_empty() {
+        println!("Config reloaded - no settings changed");
+    }
+    for change in changes {
+        let effect = if change.applied {
+            "applied"
+        } else {
+            "needs a restart"
+        };
+        println!(
+            "{}: {} -> {} ({})",
+            change.setting, change.old, change.new, effect
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage};
+use cdktr_core::models::WorkflowInstanceId;
+use log::error;
+
+/// Run a past workflow run again with the exact workflow definition and params it ran
+/// with, even if the workflow has changed since
+#[derive(clap::Args)]
+#[command(version, about, long_about = None)]
+pub struct ReplayArgs {
+    /// Instance id of the workflow run to replay
+    pub workflow_instance_id: WorkflowInstanceId,
+}
+
+pub async fn handle_replay(args: ReplayArgs) {
+    match PrincipalAPI::ReplayRun(args.workflow_instance_id.clone())
+        .send()
+        .await
+    {
+        Ok(ClientResponseMessage::SuccessWithPayload(replay_instance_id)) => println!(
+            "Replaying run {} as {}",
+            args.workflow_instance_id, replay_instance_id
+        ),
+        Ok(other) => {
+            error!("Failed to replay run: {}", other.payload());
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage};
+use cdktr_ipc::{
+    client::PrincipalClient,
+    log_manager::{client::LogsClient, model::LogMessage},
+};
+use log::error;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::failure::RequestFailure;
+
+/// How long a followed run is waited on when no timeout is given
+const NO_TIMEOUT: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+/// How long log lines of a finished run are still printed for, as they can arrive
+/// after its final status
+const LOG_DRAIN: Duration = Duration::from_millis(500);
+
+/// Run a workflow, optionally following its logs until it finishes
+#[derive(clap::Args)]
+#[command(version, about, long_about = None)]
+pub struct RunArgs {
+    /// Id of the workflow to run
+    pub workflow_id: String,
+
+    /// Value of a param of the workflow, as KEY=VALUE. Can be given more than once
+    #[arg(long, short, value_parser = parse_param)]
+    pub param: Vec<(String, String)>,
+
+    /// One of the environments of the workflow to run it as
+    #[arg(long, short)]
+    pub environment: Option<String>,
+
+    /// Stream the logs of the run until it finishes, exiting non-zero unless it completes
+    #[arg(long, short)]
+    pub follow: bool,
+
+    /// How long to follow the run for before giving up, in seconds. Follows it until it
+    /// finishes if not set
+    #[arg(long, short)]
+    pub timeout_s: Option<u64>,
+}
+
+fn parse_param(param: &str) -> Result<(String, String), String> {
+    match param.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("'{}' is not a KEY=VALUE pair", param)),
+    }
+}
+
+pub async fn handle_run(args: RunArgs) {
+    // subscribed before the run is submitted so its first lines aren't missed
+    let logs = if args.follow {
+        match LogsClient::new("cdktr-cli".to_string(), &args.workflow_id).await {
+            Ok(client) => Some(client),
+            Err(e) => RequestFailure::from_error(e).exit("subscribe to the logs of the run"),
+        }
+    } else {
+        None
+    };
+    let params: HashMap<String, String> = args.param.into_iter().collect();
+    let action = format!("run workflow {}", args.workflow_id);
+    let instance_id =
+        match PrincipalAPI::RunTask(args.workflow_id.clone(), params, false, args.environment)
+            .send()
+            .await
+        {
+            Ok(ClientResponseMessage::SuccessWithPayload(instance_id)) => instance_id,
+            Ok(other) => RequestFailure::from_response(other).exit(&action),
+            Err(e) => RequestFailure::from_error(e).exit(&action),
+        };
+    println!("Running workflow {} as {}", args.workflow_id, instance_id);
+    let Some(mut logs) = logs else {
+        return;
+    };
+
+    let (tx, mut rx) = mpsc::channel::<LogMessage>(100);
+    tokio::spawn(async move { logs.listen(tx, None).await });
+    let print_log = |msg: LogMessage| {
+        if msg.workflow_instance_id == instance_id {
+            println!("{}", msg.format());
+        }
+    };
+    let timeout = args.timeout_s.map_or(NO_TIMEOUT, Duration::from_secs);
+    let finished = PrincipalClient::wait_for_completion(&instance_id, timeout);
+    tokio::pin!(finished);
+    let result = loop {
+        tokio::select! {
+            result = &mut finished => break result,
+            Some(msg) = rx.recv() => print_log(msg),
+        }
+    };
+    while let Ok(Some(msg)) = tokio::time::timeout(LOG_DRAIN, rx.recv()).await {
+        print_log(msg);
+    }
+    match result {
+        Ok(result) if result.status == "COMPLETED" => {
+            println!("Run {} COMPLETED", instance_id);
+        }
+        Ok(result) if result.is_finished() => {
+            println!("Run {} {}", instance_id, result.status);
+            std::process::exit(1);
+        }
+        Ok(result) => {
+            error!(
+                "Gave up following run {} - it is still {}",
+                instance_id, result.status
+            );
+            std::process::exit(1);
+        }
+        Err(e) => RequestFailure::from_error(e).exit(&format!("follow run {}", instance_id)),
+    }
+}
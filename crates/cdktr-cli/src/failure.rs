@@ -0,0 +1,121 @@
+use cdktr_api::models::ClientResponseMessage;
+use cdktr_core::exceptions::{ErrorKind, GenericError};
+use log::error;
+
+/// Exit code of a request the principal refused or failed, e.g. an invalid param
+pub const EXIT_REJECTED: i32 = 1;
+/// Exit code of a request for a workflow, run or agent the principal has no record of
+pub const EXIT_NOT_FOUND: i32 = 2;
+/// Exit code of a request the principal couldn't be reached for or can't serve right now
+pub const EXIT_UNAVAILABLE: i32 = 3;
+
+/// Why a request to the principal failed. Each has its own exit code so that a mistyped
+/// id can be told apart from a principal or agents being down
+#[derive(Debug, PartialEq)]
+pub enum RequestFailure {
+    NotFound(String),
+    Unavailable(String),
+    Rejected(String),
+}
+
+impl RequestFailure {
+    /// Classifies a response to a request that wasn't successful
+    pub fn from_response(response: ClientResponseMessage) -> Self {
+        match response {
+            ClientResponseMessage::NotFound(msg) => Self::NotFound(msg),
+            ClientResponseMessage::Retryable(msg)
+            | ClientResponseMessage::ServerError(msg)
+            | ClientResponseMessage::NetworkError(msg) => Self::Unavailable(msg),
+            other => Self::Rejected(other.payload()),
+        }
+    }
+
+    /// Classifies an error sending a request. Only errors reaching the principal mean it
+    /// is unavailable
+    pub fn from_error(error: GenericError) -> Self {
+        match error.kind() {
+            ErrorKind::Timeout | ErrorKind::Connection | ErrorKind::Zmq => {
+                Self::Unavailable(error.to_string())
+            }
+            _ => Self::Rejected(error.to_string()),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::NotFound(_) => EXIT_NOT_FOUND,
+            Self::Unavailable(_) => EXIT_UNAVAILABLE,
+            Self::Rejected(_) => EXIT_REJECTED,
+        }
+    }
+
+    /// What went wrong with `action`, e.g. "run workflow my-flow", and what to check
+    pub fn message(&self, action: &str) -> String {
+        match self {
+            Self::NotFound(msg) => format!("Failed to {action}: {msg}. Check the id is right"),
+            Self::Unavailable(msg) => format!(
+                "Failed to {action}: {msg}. Check the principal is running and reachable and has agents registered"
+            ),
+            Self::Rejected(msg) => format!("Failed to {action}: {msg}"),
+        }
+    }
+
+    /// Logs the failure and exits with its exit code
+    pub fn exit(self, action: &str) -> ! {
+        error!("{}", self.message(action));
+        std::process::exit(self.exit_code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_classification() {
+        let not_found = RequestFailure::from_response(ClientResponseMessage::NotFound(
+            "No workflow exists with id my.flow".to_string(),
+        ));
+        assert_eq!(not_found.exit_code(), EXIT_NOT_FOUND);
+        assert!(
+            not_found
+                .message("run workflow my.flow")
+                .contains("No workflow exists with id my.flow")
+        );
+
+        for response in [
+            ClientResponseMessage::Retryable("Workflow my.flow is already running".to_string()),
+            ClientResponseMessage::ServerError("Database query failed".to_string()),
+            ClientResponseMessage::NetworkError("Connection reset".to_string()),
+        ] {
+            let failure = RequestFailure::from_response(response);
+            assert_eq!(failure.exit_code(), EXIT_UNAVAILABLE, "{failure:?}");
+        }
+        for error in [
+            GenericError::PrincipalTimeoutError,
+            GenericError::ConnectionError("Connection refused".to_string()),
+            GenericError::ZMQError("socket closed".to_string()),
+        ] {
+            let failure = RequestFailure::from_error(error);
+            assert_eq!(failure.exit_code(), EXIT_UNAVAILABLE, "{failure:?}");
+            assert!(
+                failure
+                    .message("run workflow my.flow")
+                    .contains("principal is running")
+            );
+        }
+
+        let rejected = RequestFailure::from_response(ClientResponseMessage::ClientError(
+            "Invalid param".to_string(),
+        ));
+        assert_eq!(
+            rejected,
+            RequestFailure::Rejected("Invalid param".to_string())
+        );
+        assert_eq!(rejected.exit_code(), EXIT_REJECTED);
+        assert_eq!(
+            RequestFailure::from_error(GenericError::ParseError("bad".to_string())).exit_code(),
+            EXIT_REJECTED
+        );
+    }
+}
@@ -1,20 +1,33 @@
-use cdktr_core::{get_cdktr_setting, utils};
-use cdktr_ipc::instance::{start_agent, start_principal};
+use cdktr_core::{
+    config::{Config, data_dir_settings},
+    models::AgentId,
+    utils,
+};
+use cdktr_ipc::instance::{
+    start_agent, start_principal, start_scheduler_instance, start_standalone,
+};
 use cdktr_tui::tui_main;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
 use log::{debug, info, warn};
 use models::InstanceType;
-use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::components::{
+    agent::{AgentArgs, handle_agent},
+    diagnose::{DiagnoseArgs, handle_diagnose},
+    doctor::{DoctorArgs, handle_doctor},
     init::{InitArgs, handle_init},
     logs::{LogArgs, handle_logs},
+    queue::{QueueArgs, handle_queue},
+    reload::{ReloadArgs, handle_reload},
+    replay::{ReplayArgs, handle_replay},
+    run::{RunArgs, handle_run},
 };
 
 mod api;
 mod components;
+mod failure;
 mod models;
 
 /// CDKTR Command Line Interface.
@@ -23,6 +36,17 @@ mod models;
 #[command(name = "cdktr")]
 #[command(bin_name = "cdktr")]
 #[command(version, about, long_about = None)]
+struct Cli {
+    /// Application data directory to use instead of CDKTR_APP_DATA_DIRECTORY, e.g. to run
+    /// isolated instances on one machine. The database is kept in it too
+    #[arg(long, global = true)]
+    data_dir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: CdktrCli,
+}
+
+#[derive(Subcommand)]
 enum CdktrCli {
     /// Open up the main CDKTR TUI
     Ui,
@@ -31,7 +55,7 @@ enum CdktrCli {
     /// for task management
     Task(api::TaskArgs),
 
-    /// Start a principal or agent node
+    /// Start a principal or agent node, or both in one process for local development
     Start(StartArgs),
 
     /// Log management CLI
@@ -39,12 +63,33 @@ enum CdktrCli {
 
     /// Init a baseline project structure with example workflow
     Init(InitArgs),
+
+    /// Check the local environment for common setup problems
+    Doctor(DoctorArgs),
+
+    /// Manage the queue of workflows waiting to run
+    Queue(QueueArgs),
+
+    /// Run a workflow, optionally following its logs until it finishes
+    Run(RunArgs),
+
+    /// Run a past workflow run again exactly as it ran
+    Replay(ReplayArgs),
+
+    /// Reload the config of the running principal without restarting it
+    Reload(ReloadArgs),
+
+    /// Explain why a queued workflow run hasn't started
+    Diagnose(DiagnoseArgs),
+
+    /// Manage the agents registered with the principal
+    Agent(AgentArgs),
 }
 
 #[derive(clap::Args)]
 #[command(version, about, long_about = None)]
 struct StartArgs {
-    /// Instance type: principal or agent
+    /// Instance type: principal, agent, standalone or scheduler
     instance_type: models::InstanceType,
 
     #[arg(long, short)]
@@ -53,6 +98,7 @@ struct StartArgs {
     #[arg(long, short)]
     max_concurrent_workflows: Option<usize>,
 
+    /// TOML config file with settings to use where the matching env var isn't set
     #[arg(long, short)]
     config: Option<std::path::PathBuf>,
 
@@ -66,78 +112,104 @@ struct StartArgs {
     with_agent: bool,
 }
 
-fn setup() {
-    let path_str_setting = &get_cdktr_setting!(CDKTR_APP_DATA_DIRECTORY);
-    let path_str = if path_str_setting.contains("$HOME") {
-        &path_str_setting.replace("$HOME", &env::var("HOME").expect(
-            format!(
-                "$HOME in app dir path so attempted to create app data directory at {path_str_setting} but cannot determine home directory from env vars."
-            ).as_str()
-        ))
-    } else {
-        path_str_setting
-    };
-    let app_data_dir = Path::new(&path_str);
+impl CdktrCli {
+    /// Whether the command can't run without a writable application data directory.
+    /// Instances keep their database and logs there, other commands only read
+    fn needs_data_dir(&self) -> bool {
+        matches!(self, CdktrCli::Start(_))
+    }
+}
 
+/// Creates the application data directory and checks it can be written to. A failure is
+/// fatal for commands that need the directory, returning the message to exit with.
+/// Other commands only warn
+fn setup(cli_instance: &CdktrCli, app_data_dir: &Path) -> Result<(), String> {
     debug!("Using application data directory: {:?}", app_data_dir);
-    if let Err(e) = std::fs::create_dir_all(&app_data_dir) {
-        warn!(
-            "Failed to create application data directory {:?}: {}",
-            app_data_dir, e
-        );
+    let probe = app_data_dir.join(".cdktr-setup");
+    let res = std::fs::create_dir_all(app_data_dir)
+        .and_then(|_| std::fs::write(&probe, b"ok"))
+        .and_then(|_| std::fs::remove_file(&probe));
+    match res {
+        Ok(()) => Ok(()),
+        Err(e) if cli_instance.needs_data_dir() => Err(format!(
+            "Application data directory {} can't be created or written to: {}. Check its permissions or set CDKTR_APP_DATA_DIRECTORY to a writable location",
+            app_data_dir.display(),
+            e
+        )),
+        Err(e) => {
+            warn!(
+                "Failed to create application data directory {:?}: {}",
+                app_data_dir, e
+            );
+            Ok(())
+        }
     }
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     dotenv().ok();
 
     // Parse CLI args first to check if we're running TUI
-    let cli_instance = CdktrCli::parse();
+    let cli = Cli::parse();
+    if let Some(data_dir) = &cli.data_dir {
+        for (setting, path) in data_dir_settings(data_dir) {
+            // SAFETY: no other threads have been started yet
+            unsafe { std::env::set_var(setting, path) };
+        }
+    }
+    let cli_instance = cli.command;
+
+    let config_path = match &cli_instance {
+        CdktrCli::Start(args) => args.config.as_deref(),
+        _ => None,
+    };
+    let config = match Config::load(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration - {}", e);
+            std::process::exit(1);
+        }
+    };
 
     // Only initialize env_logger for non-TUI commands
     // TUI will use its own custom in-memory logger
     if !matches!(cli_instance, CdktrCli::Ui) {
-        let log_level = match get_cdktr_setting!(CDKTR_LOG_LEVEL).as_str() {
-            "TRACE" => log::LevelFilter::Trace,
-            "DEBUG" => log::LevelFilter::Debug,
-            "INFO" => log::LevelFilter::Info,
-            "WARN" => log::LevelFilter::Warn,
-            "ERROR" => log::LevelFilter::Error,
-            _ => log::LevelFilter::Info,
-        };
+        // the logger lets everything through so that the level can be raised as well as
+        // lowered when the config is reloaded
         env_logger::builder()
-            .filter_level(log_level)
+            .filter_level(log::LevelFilter::Trace)
             .format_target(true)
             .init();
+        log::set_max_level(config.log_level);
+    }
+    if let Err(e) = setup(&cli_instance, &config.app_data_directory) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
-    setup();
-    _main(cli_instance).await;
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the tokio runtime")
+        .block_on(_main(cli_instance, config));
 }
 
-async fn _start_agent(instance_id: String, max_concurrent_workflows: Option<usize>) {
+async fn _start_agent(instance_id: String, max_concurrent_workflows: usize) {
     info!("Starting AGENT instance: {}", &instance_id);
-    let max_concurrent_workflows =
-        max_concurrent_workflows.unwrap_or(get_cdktr_setting!(CDKTR_AGENT_MAX_CONCURRENCY, usize));
     info!("Agent max concurrency: {}", max_concurrent_workflows);
     start_agent(instance_id, max_concurrent_workflows).await
 }
 
-async fn _main(cli_instance: CdktrCli) {
-    let principal_host = get_cdktr_setting!(CDKTR_PRINCIPAL_HOST);
-    let principal_port: usize = get_cdktr_setting!(CDKTR_PRINCIPAL_PORT, usize);
-
+async fn _main(cli_instance: CdktrCli, config: Config) {
     match cli_instance {
         CdktrCli::Start(args) => {
             let instance_type = &args.instance_type;
+            let max_concurrent_workflows = args
+                .max_concurrent_workflows
+                .unwrap_or(config.agent_max_concurrency);
             match instance_type {
                 InstanceType::AGENT => {
-                    let instance_id = format!(
-                        "{}/AG{}",
-                        utils::get_instance_id(),
-                        args.suffix.unwrap_or(String::new())
-                    );
-                    _start_agent(instance_id, args.max_concurrent_workflows).await;
+                    let instance_id = AgentId::local(args.suffix).to_string();
+                    _start_agent(instance_id, max_concurrent_workflows).await;
                 }
 
                 InstanceType::PRINCIPAL => {
@@ -146,20 +218,54 @@ async fn _main(cli_instance: CdktrCli) {
                     let no_scheduler = args.no_scheduler;
                     if args.with_agent {
                         info!("Starting AGENT alongside PRINCIPAL");
-                        let ag_instance_id = format!(
-                            "{}/AG{}",
-                            utils::get_instance_id(),
-                            args.suffix.unwrap_or(String::new())
-                        );
+                        let ag_instance_id = AgentId::local(args.suffix).to_string();
                         tokio::spawn(async move {
-                            _start_agent(ag_instance_id, args.max_concurrent_workflows).await
+                            _start_agent(ag_instance_id, max_concurrent_workflows).await
                         });
                     }
-                    if let Err(e) =
-                        start_principal(principal_host, principal_port, instance_id, no_scheduler)
-                            .await
+                    if let Err(e) = start_principal(
+                        config.principal_host,
+                        config.principal_port,
+                        instance_id,
+                        no_scheduler,
+                    )
+                    .await
+                    {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+
+                InstanceType::STANDALONE => {
+                    let prin_instance_id = format!("{}/PRIN", utils::get_instance_id());
+                    let ag_instance_id = AgentId::local(args.suffix).to_string();
+                    info!(
+                        "Starting STANDALONE instance: {} with agent {}",
+                        &prin_instance_id, &ag_instance_id
+                    );
+                    if let Err(e) = start_standalone(
+                        config.principal_host,
+                        config.principal_port,
+                        prin_instance_id,
+                        ag_instance_id,
+                        max_concurrent_workflows,
+                        args.no_scheduler,
+                    )
+                    .await
                     {
-                        println!("{}", e.to_string())
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+
+                InstanceType::SCHEDULER => {
+                    // schedulers on the same host each need their own id to hold the lease
+                    let instance_id =
+                        format!("{}/SCHED/{}", utils::get_instance_id(), std::process::id());
+                    info!("Starting SCHEDULER instance: {}", &instance_id);
+                    if let Err(e) = start_scheduler_instance(instance_id).await {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
                     }
                 }
             }
@@ -168,8 +274,48 @@ async fn _main(cli_instance: CdktrCli) {
             let _ = tui_main().await;
             ()
         }
-        CdktrCli::Task(_args) => todo!(),
+        CdktrCli::Task(args) => api::handle_task(args).await,
         CdktrCli::Logs(args) => handle_logs(args).await,
         CdktrCli::Init(args) => handle_init(args),
+        CdktrCli::Doctor(args) => handle_doctor(args, &config.app_data_directory).await,
+        CdktrCli::Queue(args) => handle_queue(args).await,
+        CdktrCli::Run(args) => handle_run(args).await,
+        CdktrCli::Replay(args) => handle_replay(args).await,
+        CdktrCli::Reload(args) => handle_reload(args).await,
+        CdktrCli::Diagnose(args) => handle_diagnose(args).await,
+        CdktrCli::Agent(args) => handle_agent(args).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unwritable_data_dir_fatal_for_start() {
+        // a directory can't be created beneath a regular file, even as root
+        let file = std::env::temp_dir().join(format!(
+            "cdktr-setup-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::write(&file, b"").unwrap();
+        let data_dir = file.join("data");
+
+        let start = Cli::parse_from(["cdktr", "start", "agent"]).command;
+        let err = setup(&start, &data_dir).unwrap_err();
+        assert!(err.contains(&data_dir.display().to_string()));
+
+        // read-only commands carry on
+        let doctor = Cli::parse_from(["cdktr", "doctor"]).command;
+        assert!(setup(&doctor, &data_dir).is_ok());
+
+        let writable = file.with_extension("dir");
+        assert!(setup(&start, &writable).is_ok());
+        std::fs::remove_dir_all(&writable).unwrap();
+        std::fs::remove_file(&file).unwrap();
     }
 }
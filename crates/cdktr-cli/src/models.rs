@@ -5,6 +5,10 @@ use serde::Serialize;
 pub enum InstanceType {
     PRINCIPAL,
     AGENT,
+    /// A principal and one agent in the same process, for local development
+    STANDALONE,
+    /// A scheduler in its own process that queues scheduled runs with the principal
+    SCHEDULER,
 }
 impl InstanceType {
     #[allow(dead_code)]
@@ -12,6 +16,8 @@ impl InstanceType {
         match self {
             Self::AGENT => String::from("AGENT"),
             Self::PRINCIPAL => String::from("PRINCIPAL"),
+            Self::STANDALONE => String::from("STANDALONE"),
+            Self::SCHEDULER => String::from("SCHEDULER"),
         }
     }
 }
@@ -21,5 +27,6 @@ impl InstanceType {
 pub enum TaskAction {
     /// action to create a new task in the principal database
     Create,
+    /// action to queue a run of the workflow
     Trigger,
 }
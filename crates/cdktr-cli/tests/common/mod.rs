@@ -0,0 +1,71 @@
+//! Helpers shared by the tests that run the `cdktr` binary
+
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use cdktr_api::PrincipalAPI;
+use cdktr_api::models::ClientResponseMessage;
+use cdktr_core::zmq_helpers::send_recv_with_timeout;
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Kills the node if the test fails before it is stopped
+pub struct Node(pub Child);
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+/// Starts `cdktr start standalone` without a scheduler, with the workflows written to a
+/// workflow directory in `dir` as `<id>.yml`. Returns the node with the env it was started
+/// with, for pointing other `cdktr` commands at it
+#[allow(dead_code)]
+pub fn start_standalone(
+    dir: &Path,
+    principal_port: u16,
+    workflows: &[(&str, &str)],
+) -> (Node, Vec<(&'static str, String)>) {
+    let workflow_dir = dir.join("workflows");
+    std::fs::create_dir_all(&workflow_dir).unwrap();
+    for (id, workflow) in workflows {
+        std::fs::write(workflow_dir.join(format!("{id}.yml")), workflow).unwrap();
+    }
+    let env = vec![
+        ("CDKTR_PRINCIPAL_HOST", "127.0.0.1".to_string()),
+        ("CDKTR_PRINCIPAL_PORT", principal_port.to_string()),
+        ("CDKTR_LOGS_LISTENING_PORT", free_port().to_string()),
+        ("CDKTR_LOGS_PUBLISHING_PORT", free_port().to_string()),
+        ("CDKTR_APP_DATA_DIRECTORY", dir.display().to_string()),
+        ("CDKTR_DB_PATH", dir.join("app.db").display().to_string()),
+    ];
+    let child = Command::new(env!("CARGO_BIN_EXE_cdktr"))
+        .args(["start", "standalone", "--no-scheduler"])
+        .envs(env.iter().cloned())
+        .env("CDKTR_WORKFLOW_DIR", &workflow_dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start standalone node");
+    (Node(child), env)
+}
+
+pub async fn request(principal_port: u16, msg: PrincipalAPI) -> Option<ClientResponseMessage> {
+    send_recv_with_timeout(
+        format!("tcp://127.0.0.1:{}", principal_port),
+        msg.into(),
+        Duration::from_secs(2),
+    )
+    .await
+    .ok()
+    .map(ClientResponseMessage::from)
+}
@@ -0,0 +1,50 @@
+//! `--data-dir` keeps everything a principal writes in the given directory, even when
+//! the environment points elsewhere
+
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use cdktr_api::PrincipalAPI;
+use cdktr_api::models::ClientResponseMessage;
+
+mod common;
+use common::{Node, free_port, request};
+
+#[tokio::test]
+async fn test_data_dir_overrides_env() {
+    let dir = std::env::temp_dir().join(format!("cdktr-data-dir-{}", std::process::id()));
+    let data_dir = dir.join("instance");
+    let shared_dir = dir.join("shared");
+    let workflow_dir = dir.join("workflows");
+    std::fs::create_dir_all(&workflow_dir).unwrap();
+    let principal_port = free_port();
+    let mut node = Node(
+        Command::new(env!("CARGO_BIN_EXE_cdktr"))
+            .arg("--data-dir")
+            .arg(&data_dir)
+            .args(["start", "principal", "--no-scheduler"])
+            .env("CDKTR_PRINCIPAL_HOST", "127.0.0.1")
+            .env("CDKTR_PRINCIPAL_PORT", principal_port.to_string())
+            .env("CDKTR_LOGS_LISTENING_PORT", free_port().to_string())
+            .env("CDKTR_LOGS_PUBLISHING_PORT", free_port().to_string())
+            .env("CDKTR_APP_DATA_DIRECTORY", &shared_dir)
+            .env("CDKTR_DB_PATH", shared_dir.join("app.db"))
+            .env("CDKTR_WORKFLOW_DIR", &workflow_dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start principal"),
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while request(principal_port, PrincipalAPI::Ping).await != Some(ClientResponseMessage::Pong) {
+        assert!(Instant::now() < deadline, "principal never came up");
+        assert!(node.0.try_wait().unwrap().is_none(), "principal exited");
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    assert!(data_dir.join("app.db").exists());
+    assert!(!shared_dir.exists());
+    drop(node);
+    let _ = std::fs::remove_dir_all(&dir);
+}
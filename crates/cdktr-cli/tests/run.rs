@@ -0,0 +1,81 @@
+//! `cdktr run --follow` submits a workflow to a standalone node, streams its logs and
+//! exits with whether the run completed
+
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
+
+use cdktr_api::PrincipalAPI;
+use cdktr_api::models::ClientResponseMessage;
+
+mod common;
+use common::{free_port, request, start_standalone};
+
+const PASSING: &str = r#"
+name: Passing
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  greet:
+    name: Greet
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello from the run"]
+"#;
+
+const FAILING: &str = r#"
+name: Failing
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  fail:
+    name: Fail
+    config:
+      !Subprocess
+      cmd: "false"
+      args: []
+"#;
+
+fn run_follow(env: &[(&'static str, String)], workflow_id: &str) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cdktr"))
+        .args(["run", workflow_id, "--follow", "--timeout-s", "30"])
+        .envs(env.iter().cloned())
+        .output()
+        .expect("failed to run cdktr run")
+}
+
+#[tokio::test]
+async fn test_run_follow() {
+    let dir = std::env::temp_dir().join(format!("cdktr-run-follow-{}", std::process::id()));
+    let principal_port = free_port();
+    let (node, env) = start_standalone(
+        &dir,
+        principal_port,
+        &[("passing", PASSING), ("failing", FAILING)],
+    );
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while request(principal_port, PrincipalAPI::Ping).await != Some(ClientResponseMessage::Pong) {
+        assert!(Instant::now() < deadline, "standalone node never came up");
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    let output = run_follow(&env, "passing");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("hello from the run"), "{stdout}");
+    assert!(stdout.contains("COMPLETED"), "{stdout}");
+
+    let output = run_follow(&env, "failing");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{stdout}");
+    assert!(stdout.contains("FAILED"), "{stdout}");
+
+    // a workflow that doesn't exist fails straight away with its own exit code
+    let output = run_follow(&env, "missing");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No workflow exists with id missing"));
+    drop(node);
+
+    // as does a principal that can't be reached
+    let output = run_follow(&env, "passing");
+    assert_eq!(output.status.code(), Some(3));
+    let _ = std::fs::remove_dir_all(&dir);
+}
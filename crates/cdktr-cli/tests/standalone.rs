@@ -0,0 +1,86 @@
+//! Smoke test of `cdktr start standalone`: a trivial workflow is submitted to the
+//! standalone node and run end-to-end by its agent
+
+use std::collections::HashMap;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use cdktr_api::PrincipalAPI;
+use cdktr_api::models::{ClientResponseMessage, WorkflowStatusUpdate};
+
+mod common;
+use common::{free_port, request, start_standalone};
+
+const WORKFLOW: &str = r#"
+name: Smoke
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#;
+
+#[tokio::test]
+async fn test_standalone_runs_workflow() {
+    let dir = std::env::temp_dir().join(format!("cdktr-standalone-{}", std::process::id()));
+    let principal_port = free_port();
+    let (mut node, _) = start_standalone(&dir, principal_port, &[("smoke", WORKFLOW)]);
+
+    // wait for the principal to come up, then submit the workflow
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        let resp = request(
+            principal_port,
+            PrincipalAPI::RunTask("smoke".to_string(), HashMap::new(), false, None),
+        )
+        .await;
+        if let Some(ClientResponseMessage::SuccessWithPayload(_)) = resp {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "workflow was never queued: {:?}",
+            resp
+        );
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    // the agent in the same process picks it up and runs it to completion
+    loop {
+        if let Some(ClientResponseMessage::SuccessWithPayload(payload)) =
+            request(principal_port, PrincipalAPI::GetRecentWorkflowStatuses).await
+        {
+            let statuses: Vec<WorkflowStatusUpdate> = serde_json::from_str(&payload).unwrap();
+            if statuses
+                .iter()
+                .any(|s| s.workflow_id() == "smoke" && s.status() == "COMPLETED")
+            {
+                break;
+            }
+        }
+        assert!(Instant::now() < deadline, "workflow never completed");
+        tokio::time::sleep(Duration::from_millis(250)).await;
+    }
+
+    // Ctrl-C stops both the principal and the agent
+    let interrupted = Command::new("kill")
+        .args(["-INT", &node.0.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(interrupted.success());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = node.0.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            panic!("standalone node didn't stop on Ctrl-C");
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    };
+    assert!(status.success());
+    let _ = std::fs::remove_dir_all(&dir);
+}
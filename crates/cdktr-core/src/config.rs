@@ -1,3 +1,8 @@
+use crate::exceptions::GenericError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+
 /// This config file lists out all the default values for the main CDKTR env configs
 /// All can be overridden by either an ENV var of the same name. Some can also be overridden
 /// from the command line. These should only be primitive types
@@ -7,18 +12,108 @@
 /// default log level
 pub static CDKTR_LOG_LEVEL: &'static str = "INFO";
 
+/// Timezone log timestamps are displayed in. One of `UTC`, `local` or a fixed
+/// offset such as `+05:30`. Stored timestamps are always epoch milliseconds
+pub static CDKTR_LOG_TIMEZONE: &str = "UTC";
+
+/// strftime pattern log timestamps are displayed with, e.g. `%Y-%m-%d %H:%M:%S`.
+/// Leave empty to display RFC 3339 timestamps
+pub static CDKTR_LOG_TIMESTAMP_FORMAT: &str = "";
+
 /// default max number of concurrent workflows an agent can handle
 pub static CDKTR_AGENT_MAX_CONCURRENCY: usize = 5;
 
+/// Comma-separated executors an agent runs tasks with, e.g. `Subprocess,UvPython`.
+/// Agents are only sent workflows whose tasks all use these executors. Leave empty
+/// to run every executor
+pub static CDKTR_AGENT_EXECUTORS: &str = "";
+
 /// number of times to re-attempt a zmq request
 pub static CDKTR_RETRY_ATTEMPTS: usize = 20;
 
-/// default timeout for a zmq request
+/// default timeout for zmq operations other than requests, such as connecting push
+/// sockets, and the delay between retries of a request
 pub static CDKTR_DEFAULT_ZMQ_TIMEOUT_MS: usize = 3_000;
 
+/// Maximum time to connect to a server before a request to it fails. Kept short so that
+/// a server that is down is noticed quickly
+pub static CDKTR_CONNECT_TIMEOUT_MS: usize = 1_000;
+
+/// Maximum time to wait for the reply to a request once connected to the server
+pub static CDKTR_REQUEST_TIMEOUT_MS: usize = 3_000;
+
+/// Level the principal logs each request it handles at, with its type, client, response
+/// and latency. One of `ERROR`, `WARN`, `INFO`, `DEBUG`, `TRACE` or `OFF`
+pub static CDKTR_ACCESS_LOG_LEVEL: &str = "DEBUG";
+
 /// default refresh interval for the REP server
 pub static CDKTR_DEFAULT_ZMQ_REP_FREFRESH_INTERVAL_MS: usize = 3_000;
 
+/// Maximum time the principal holds an agent's workflow fetch request open while
+/// waiting for work to arrive on the queue. Set to 0 to disable long-polling
+pub static CDKTR_WORKFLOW_FETCH_LONG_POLL_MS: usize = 5_000;
+
+/// Longest the principal holds a workflow fetch request open, whatever long-poll
+/// timeout the agent asks for
+pub static CDKTR_MAX_LONG_POLL_MS: usize = 30_000;
+
+/// How long an agent running its maximum number of workflows waits after one of them
+/// finishes before fetching again
+pub static CDKTR_AGENT_MIN_POLL_INTERVAL_MS: usize = 50;
+
+/// How queued workflows reach agents. `pull` has agents fetch their work from the
+/// principal. `push` has the principal send each run to the least loaded agent as soon
+/// as it is queued. Principal and agents should use the same mode
+pub static CDKTR_DISPATCH_MODE: &str = "pull";
+
+/// Host the principal reaches an agent on to push workflows to it in push mode
+pub static CDKTR_AGENT_PUSH_HOST: &str = "localhost";
+
+/// Port an agent listens on for workflows pushed to it in push mode
+pub static CDKTR_AGENT_PUSH_PORT: usize = 5564;
+
+/// Whether agents may run subprocess tasks as a different OS user via `run_as_user`.
+/// Disabled by default; switching users also requires the agent to run as root
+pub static CDKTR_AGENT_ALLOW_RUN_AS_USER: &str = "false";
+
+/// Shell that subprocess tasks with `shell: true` are run through
+#[cfg(not(windows))]
+pub static CDKTR_AGENT_DEFAULT_SHELL: &str = "sh";
+/// Shell that subprocess tasks with `shell: true` are run through
+#[cfg(windows)]
+pub static CDKTR_AGENT_DEFAULT_SHELL: &str = "cmd";
+
+/// Seconds a task process is given to exit after SIGTERM when it is stopped, e.g. on
+/// timeout or agent shutdown, before it is killed. Tasks can override it with `term_grace_s`
+pub static CDKTR_AGENT_TERM_GRACE_S: usize = 10;
+
+/// Python interpreters available on the agent as comma-separated `version=path` pairs,
+/// e.g. `3.11=/usr/bin/python3.11`. UvPython tasks asking for one of these versions are
+/// run with the mapped interpreter
+pub static CDKTR_AGENT_PYTHON_INTERPRETERS: &str = "";
+
+/// How long the output of a task marked with `cache: true` is replayed from the
+/// agent's cache before the task is run again
+pub static CDKTR_AGENT_TASK_CACHE_TTL_S: usize = 3_600;
+
+/// Maximum bytes of combined stdout and stderr forwarded from a single task. Output past
+/// the limit is dropped and replaced by a truncation marker. Set to 0 for no limit
+pub static CDKTR_AGENT_MAX_TASK_OUTPUT_BYTES: usize = 10_485_760;
+
+/// Where agents resolve `${secret.NAME}` references in tasks from. One of `env_file`,
+/// `keyring` or `command`
+pub static CDKTR_AGENT_SECRETS_SOURCE: &str = "env_file";
+
+/// Env file of `NAME=value` lines that secrets are read from with the `env_file` source
+pub static CDKTR_AGENT_SECRETS_ENV_FILE: &str = "$HOME/.cdktr/secrets.env";
+
+/// Service name secrets are stored under in the OS keyring with the `keyring` source
+pub static CDKTR_AGENT_SECRETS_KEYRING_SERVICE: &str = "cdktr";
+
+/// Shell command that prints the value of the secret named by its first argument,
+/// used with the `command` source
+pub static CDKTR_AGENT_SECRETS_COMMAND: &str = "";
+
 /// hostname of the principal instance
 pub static CDKTR_PRINCIPAL_HOST: &'static str = "0.0.0.0";
 
@@ -39,10 +134,49 @@ pub static CDKTR_WORKFLOW_DIR: &'static str = "workflows";
 /// having to bounce any services
 pub static CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S: usize = 60;
 
+/// Whether the principal refuses to start when any workflow in the workflow directory
+/// fails to parse, instead of skipping it with a warning
+pub static CDKTR_WORKFLOW_STRICT_LOADING: &str = "false";
+
+/// Largest workflow definition that is loaded, in bytes
+pub static CDKTR_WORKFLOW_MAX_FILE_BYTES: usize = 1_048_576;
+
+/// Most tasks a workflow can have, counting each expansion of a matrix task
+pub static CDKTR_WORKFLOW_MAX_TASKS: usize = 1_000;
+
+/// Most tasks in a single chain of dependencies of a workflow
+pub static CDKTR_WORKFLOW_MAX_DEPTH: usize = 100;
+
+/// URL of a Git repository to sync workflows from instead of `CDKTR_WORKFLOW_DIR`.
+/// Leave empty to load workflows from the local directory
+pub static CDKTR_WORKFLOW_GIT_URL: &str = "";
+
+/// Branch, tag or commit of the workflow repository to check out
+pub static CDKTR_WORKFLOW_GIT_REF: &str = "main";
+
+/// Directory within the workflow repository containing the workflow definitions
+pub static CDKTR_WORKFLOW_GIT_SUBDIR: &str = "";
+
+/// Local directory the workflow repository is checked out into
+pub static CDKTR_WORKFLOW_GIT_CACHE_DIR: &str = "$HOME/.cdktr/workflow_repo";
+
+/// Path to an SSH deploy key used to access a private workflow repository
+pub static CDKTR_WORKFLOW_GIT_DEPLOY_KEY: &str = "";
+
 /// Interval at which the Scheduler should check whether
 /// a workflow is ready to start
 pub static CDKTR_SCHEDULER_START_POLL_FREQUENCY_MS: usize = 500;
 
+/// Comma-separated times of day, e.g. `09:00-17:00`, that no scheduled workflow fires
+/// during. Fires inside a window are deferred to its end. Times are UTC unless a window
+/// ends with an offset, e.g. `09:00-17:00 +01:00`
+pub static CDKTR_SCHEDULER_BLACKOUT: &str = "";
+
+/// How long a scheduler's lease to fire scheduled workflows lasts without being renewed.
+/// A scheduler renews its lease three times per period, so a standby scheduler takes over
+/// within this long of the one holding it stopping
+pub static CDKTR_SCHEDULER_LEASE_TTL_MS: usize = 15_000;
+
 /// Task queue persistence interval. Used in case of failure of the principal
 /// so it can pick up where it left off. Stored in APP DATA directory.
 pub static CDKTR_Q_PERSISTENCE_INTERVAL_MS: usize = 1000;
@@ -56,6 +190,630 @@ pub static CDKTR_DB_PATH: &'static str = "$HOME/.cdktr/app.db";
 /// TUI refresh interval for principal status checks (in milliseconds)
 pub static CDKTR_TUI_STATUS_REFRESH_INTERVAL_MS: usize = 1000;
 
+/// Number of times the TUI tries a request while the principal can't be reached, e.g.
+/// while it restarts, before showing it as disconnected
+pub static CDKTR_TUI_RECONNECT_ATTEMPTS: usize = 3;
+
 /// Agent heartbeat timeout in milliseconds. If an agent hasn't sent a heartbeat
 /// within this duration, any running workflows will be marked as CRASHED
 pub static CDKTR_AGENT_HEARTBEAT_TIMEOUT_MS: usize = 30_000;
+
+/// What the principal does when an agent registers with the id of another agent that is
+/// still sending heartbeats, e.g. two agents on one host started without a `--suffix`.
+/// `reject` refuses the new agent and `warn` logs it and lets it take over the id
+pub static CDKTR_AGENT_ID_COLLISION: &str = "reject";
+
+/// How often agents push their load metrics (running workflows and tasks, host CPU and
+/// memory) to the principal, in seconds. 0 stops agents pushing metrics
+pub static CDKTR_AGENT_METRICS_INTERVAL_S: usize = 15;
+
+/// Number of seconds a queue can keep growing without being drained before a
+/// warning is logged that its consumers are slow or missing
+pub static CDKTR_QUEUE_SLOW_CONSUMER_WARNING_S: usize = 120;
+
+/// Maximum size of a single artifact a task can hand to the tasks after it
+/// through the principal
+pub static CDKTR_MAX_ARTIFACT_BYTES: usize = 10_485_760;
+
+/// Maximum size of all the artifacts the principal holds at once, across every
+/// running workflow
+pub static CDKTR_MAX_ARTIFACT_STORE_BYTES: usize = 268_435_456;
+
+/// Largest request the principal, or an agent receiving pushed workflows, accepts.
+/// Bigger requests are rejected before they are parsed
+pub static CDKTR_MAX_MESSAGE_BYTES: usize = 16_777_216;
+
+/// Number of read-only queries (logs, recent statuses and run results) the principal
+/// handles at once outside of its request loop, so a slow query doesn't hold up other
+/// requests. 0 handles them in the request loop
+pub static CDKTR_PRINCIPAL_QUERY_WORKERS: usize = 4;
+
+/// Whether messages in the deprecated pipe-delimited format, e.g. `REGISTERAGENT|8999|2`,
+/// are still accepted from clients that haven't been upgraded
+pub static CDKTR_ACCEPT_LEGACY_DELIMITER: &str = "true";
+
+/// Settings that can be set in a config file passed with `--config`. Keys in the file are
+/// the setting names without the `CDKTR_` prefix in lowercase, e.g. `principal_port = 5561`.
+/// An env var of the same name takes precedence over the file
+const CONFIG_FILE_SETTINGS: &[&str] = &[
+    "CDKTR_LOG_LEVEL",
+    "CDKTR_APP_DATA_DIRECTORY",
+    "CDKTR_PRINCIPAL_HOST",
+    "CDKTR_PRINCIPAL_PORT",
+    "CDKTR_LOGS_LISTENING_PORT",
+    "CDKTR_LOGS_PUBLISHING_PORT",
+    "CDKTR_AGENT_MAX_CONCURRENCY",
+    "CDKTR_DEFAULT_ZMQ_TIMEOUT_MS",
+    "CDKTR_WORKFLOW_DIR",
+    "CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S",
+    "CDKTR_SCHEDULER_START_POLL_FREQUENCY_MS",
+];
+
+/// Settings that take effect straight away when the config of a running instance is
+/// reloaded. Changes to the other settings in the config file need a restart
+pub const RELOADABLE_SETTINGS: &[&str] = &[
+    "CDKTR_LOG_LEVEL",
+    "CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S",
+    "CDKTR_SCHEDULER_START_POLL_FREQUENCY_MS",
+];
+
+/// Config the instance is running with and the config file it was read from, if any
+static ACTIVE_CONFIG: RwLock<Option<(Config, Option<std::path::PathBuf>)>> = RwLock::new(None);
+
+/// Values of the settings of the config in effect. `get_cdktr_setting!` reads these ahead
+/// of the env as they have already been resolved from the env and config file, so every
+/// part of the instance sees the values set in the config file
+static CONFIG_SETTINGS: LazyLock<RwLock<HashMap<String, String>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Value of a setting of the config in effect, if the config has been loaded
+pub fn config_setting(setting: &str) -> Option<String> {
+    CONFIG_SETTINGS
+        .read()
+        .expect("reloaded settings lock poisoned")
+        .get(setting)
+        .cloned()
+}
+
+/// A setting with a different value in the reloaded config than in the running one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigChange {
+    pub setting: String,
+    pub old: String,
+    pub new: String,
+    /// Whether the new value is in effect. False for settings that need a restart
+    pub applied: bool,
+}
+
+/// Re-reads the env and the config file the instance was started with, and applies the
+/// reloadable settings in place. Returns every setting that changed, including those that
+/// need a restart to take effect, which are left as they are
+pub fn reload_config() -> Result<Vec<ConfigChange>, GenericError> {
+    let path = ACTIVE_CONFIG
+        .read()
+        .expect("active config lock poisoned")
+        .as_ref()
+        .and_then(|(_, path)| path.clone());
+    let reloaded = Config::read(path.as_deref())?;
+    Ok(reloaded.apply(path))
+}
+
+/// The main settings of a cdktr instance, loaded and validated once at startup so that a
+/// bad value stops the instance straight away rather than surfacing later on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub log_level: log::LevelFilter,
+    pub app_data_directory: std::path::PathBuf,
+    pub principal_host: String,
+    pub principal_port: usize,
+    pub logs_listening_port: usize,
+    pub logs_publishing_port: usize,
+    pub agent_max_concurrency: usize,
+    pub default_zmq_timeout_ms: usize,
+    pub workflow_dir: String,
+    pub workflow_dir_refresh_frequency_s: usize,
+    pub scheduler_start_poll_frequency_ms: usize,
+}
+
+impl Config {
+    /// Loads the config from the env, falling back to the optional TOML config file and then
+    /// to the defaults in this module. The config becomes the one the instance runs with
+    pub fn load(path: Option<&std::path::Path>) -> Result<Self, GenericError> {
+        let config = Self::read(path)?;
+        config.clone().apply(path.map(|path| path.to_path_buf()));
+        Ok(config)
+    }
+
+    fn read(path: Option<&std::path::Path>) -> Result<Self, GenericError> {
+        let file = match path {
+            Some(path) => read_config_file(path)?,
+            None => HashMap::new(),
+        };
+        Self::from_sources(&file, |setting| std::env::var(setting).ok())
+    }
+
+    /// Each setting in the config with its value
+    fn settings(&self) -> [(&'static str, String); 11] {
+        [
+            ("CDKTR_LOG_LEVEL", self.log_level.to_string()),
+            (
+                "CDKTR_APP_DATA_DIRECTORY",
+                self.app_data_directory.display().to_string(),
+            ),
+            ("CDKTR_PRINCIPAL_HOST", self.principal_host.clone()),
+            ("CDKTR_PRINCIPAL_PORT", self.principal_port.to_string()),
+            (
+                "CDKTR_LOGS_LISTENING_PORT",
+                self.logs_listening_port.to_string(),
+            ),
+            (
+                "CDKTR_LOGS_PUBLISHING_PORT",
+                self.logs_publishing_port.to_string(),
+            ),
+            (
+                "CDKTR_AGENT_MAX_CONCURRENCY",
+                self.agent_max_concurrency.to_string(),
+            ),
+            (
+                "CDKTR_DEFAULT_ZMQ_TIMEOUT_MS",
+                self.default_zmq_timeout_ms.to_string(),
+            ),
+            ("CDKTR_WORKFLOW_DIR", self.workflow_dir.clone()),
+            (
+                "CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S",
+                self.workflow_dir_refresh_frequency_s.to_string(),
+            ),
+            (
+                "CDKTR_SCHEDULER_START_POLL_FREQUENCY_MS",
+                self.scheduler_start_poll_frequency_ms.to_string(),
+            ),
+        ]
+    }
+
+    /// Makes this the config the instance runs with. Only the reloadable settings of a
+    /// config that replaces a running one are applied. Returns the settings that changed
+    fn apply(self, path: Option<std::path::PathBuf>) -> Vec<ConfigChange> {
+        let mut active = ACTIVE_CONFIG.write().expect("active config lock poisoned");
+        let (config, changes) = match active.take() {
+            None => (self, Vec::new()),
+            Some((running, _)) => {
+                let changes: Vec<ConfigChange> = running
+                    .settings()
+                    .into_iter()
+                    .zip(self.settings())
+                    .filter(|((_, old), (_, new))| old != new)
+                    .map(|((setting, old), (_, new))| ConfigChange {
+                        setting: setting.to_string(),
+                        old,
+                        new,
+                        applied: RELOADABLE_SETTINGS.contains(&setting),
+                    })
+                    .collect();
+                let config = Self {
+                    log_level: self.log_level,
+                    workflow_dir_refresh_frequency_s: self.workflow_dir_refresh_frequency_s,
+                    scheduler_start_poll_frequency_ms: self.scheduler_start_poll_frequency_ms,
+                    ..running
+                };
+                log::set_max_level(config.log_level);
+                (config, changes)
+            }
+        };
+        // settings that need a restart keep their running values
+        let mut settings = CONFIG_SETTINGS
+            .write()
+            .expect("config settings lock poisoned");
+        for (setting, value) in config.settings() {
+            settings.insert(setting.to_string(), value);
+        }
+        *active = Some((config, path));
+        changes
+    }
+
+    fn from_sources(
+        file: &HashMap<String, String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, GenericError> {
+        let get = |setting: &str, default: String| -> String {
+            env(setting)
+                .or_else(|| file.get(&file_key(setting)).cloned())
+                .unwrap_or(default)
+        };
+        let home = env("HOME");
+        Ok(Self {
+            log_level: parse_log_level(
+                "CDKTR_LOG_LEVEL",
+                &get("CDKTR_LOG_LEVEL", CDKTR_LOG_LEVEL.to_string()),
+            )?,
+            app_data_directory: parse_path(
+                "CDKTR_APP_DATA_DIRECTORY",
+                &get(
+                    "CDKTR_APP_DATA_DIRECTORY",
+                    CDKTR_APP_DATA_DIRECTORY.to_string(),
+                ),
+                home.as_deref(),
+            )?,
+            principal_host: parse_non_empty(
+                "CDKTR_PRINCIPAL_HOST",
+                get("CDKTR_PRINCIPAL_HOST", CDKTR_PRINCIPAL_HOST.to_string()),
+            )?,
+            principal_port: parse_port(
+                "CDKTR_PRINCIPAL_PORT",
+                &get("CDKTR_PRINCIPAL_PORT", CDKTR_PRINCIPAL_PORT.to_string()),
+            )?,
+            logs_listening_port: parse_port(
+                "CDKTR_LOGS_LISTENING_PORT",
+                &get(
+                    "CDKTR_LOGS_LISTENING_PORT",
+                    CDKTR_LOGS_LISTENING_PORT.to_string(),
+                ),
+            )?,
+            logs_publishing_port: parse_port(
+                "CDKTR_LOGS_PUBLISHING_PORT",
+                &get(
+                    "CDKTR_LOGS_PUBLISHING_PORT",
+                    CDKTR_LOGS_PUBLISHING_PORT.to_string(),
+                ),
+            )?,
+            agent_max_concurrency: parse_positive(
+                "CDKTR_AGENT_MAX_CONCURRENCY",
+                &get(
+                    "CDKTR_AGENT_MAX_CONCURRENCY",
+                    CDKTR_AGENT_MAX_CONCURRENCY.to_string(),
+                ),
+            )?,
+            default_zmq_timeout_ms: parse_positive(
+                "CDKTR_DEFAULT_ZMQ_TIMEOUT_MS",
+                &get(
+                    "CDKTR_DEFAULT_ZMQ_TIMEOUT_MS",
+                    CDKTR_DEFAULT_ZMQ_TIMEOUT_MS.to_string(),
+                ),
+            )?,
+            workflow_dir: parse_non_empty(
+                "CDKTR_WORKFLOW_DIR",
+                get("CDKTR_WORKFLOW_DIR", CDKTR_WORKFLOW_DIR.to_string()),
+            )?,
+            workflow_dir_refresh_frequency_s: parse_positive(
+                "CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S",
+                &get(
+                    "CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S",
+                    CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S.to_string(),
+                ),
+            )?,
+            scheduler_start_poll_frequency_ms: parse_positive(
+                "CDKTR_SCHEDULER_START_POLL_FREQUENCY_MS",
+                &get(
+                    "CDKTR_SCHEDULER_START_POLL_FREQUENCY_MS",
+                    CDKTR_SCHEDULER_START_POLL_FREQUENCY_MS.to_string(),
+                ),
+            )?,
+        })
+    }
+}
+
+/// Settings that default to a location in the application data directory, pointed at
+/// `data_dir` instead. Used by `--data-dir` so that instances run side by side on one
+/// machine don't share a database or any other files
+pub fn data_dir_settings(data_dir: &std::path::Path) -> [(&'static str, std::path::PathBuf); 3] {
+    [
+        ("CDKTR_APP_DATA_DIRECTORY", data_dir.to_path_buf()),
+        ("CDKTR_DB_PATH", data_dir.join("app.db")),
+        (
+            "CDKTR_WORKFLOW_GIT_CACHE_DIR",
+            data_dir.join("workflow_repo"),
+        ),
+    ]
+}
+
+/// Key of a setting in the config file, e.g. `principal_port` for `CDKTR_PRINCIPAL_PORT`
+fn file_key(setting: &str) -> String {
+    setting.trim_start_matches("CDKTR_").to_lowercase()
+}
+
+/// Reads a TOML config file into its raw setting values, keyed as in the file
+fn read_config_file(path: &std::path::Path) -> Result<HashMap<String, String>, GenericError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        GenericError::ConfigError(format!(
+            "Unable to read config file {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    parse_config_file(&contents).map_err(|e| {
+        GenericError::ConfigError(format!("Invalid config file {}: {}", path.display(), e))
+    })
+}
+
+fn parse_config_file(contents: &str) -> Result<HashMap<String, String>, String> {
+    let table: toml::Table = contents
+        .parse()
+        .map_err(|e: toml::de::Error| e.to_string())?;
+    let known: Vec<String> = CONFIG_FILE_SETTINGS.iter().map(|s| file_key(s)).collect();
+    let mut values = HashMap::new();
+    for (key, value) in table {
+        if !known.contains(&key) {
+            return Err(format!(
+                "'{}' is not a setting that can be set in the config file. Expected one of: {}",
+                key,
+                known.join(", ")
+            ));
+        }
+        let value = match value {
+            toml::Value::String(s) => s,
+            toml::Value::Integer(i) => i.to_string(),
+            other => {
+                return Err(format!(
+                    "'{}' must be a string or an integer, not {}",
+                    key,
+                    other.type_str()
+                ));
+            }
+        };
+        values.insert(key, value);
+    }
+    Ok(values)
+}
+
+fn parse_log_level(setting: &str, value: &str) -> Result<log::LevelFilter, GenericError> {
+    match value.to_uppercase().as_str() {
+        "TRACE" => Ok(log::LevelFilter::Trace),
+        "DEBUG" => Ok(log::LevelFilter::Debug),
+        "INFO" => Ok(log::LevelFilter::Info),
+        "WARN" => Ok(log::LevelFilter::Warn),
+        "ERROR" => Ok(log::LevelFilter::Error),
+        _ => Err(GenericError::ConfigError(format!(
+            "{}: '{}' is not a valid log level. Expected one of TRACE, DEBUG, INFO, WARN or ERROR",
+            setting, value
+        ))),
+    }
+}
+
+fn parse_non_empty(setting: &str, value: String) -> Result<String, GenericError> {
+    if value.trim().is_empty() {
+        return Err(GenericError::ConfigError(format!(
+            "{}: a value is required",
+            setting
+        )));
+    }
+    Ok(value)
+}
+
+fn parse_usize(setting: &str, value: &str) -> Result<usize, GenericError> {
+    value.trim().parse().map_err(|_| {
+        GenericError::ConfigError(format!(
+            "{}: '{}' is not a valid unsigned integer",
+            setting, value
+        ))
+    })
+}
+
+fn parse_positive(setting: &str, value: &str) -> Result<usize, GenericError> {
+    match parse_usize(setting, value)? {
+        0 => Err(GenericError::ConfigError(format!(
+            "{}: must be greater than 0",
+            setting
+        ))),
+        n => Ok(n),
+    }
+}
+
+/// Parses a port setting, rejecting anything that isn't a number between 1 and 65535
+pub fn parse_port(setting: &str, value: &str) -> Result<usize, GenericError> {
+    match value.trim().parse::<usize>() {
+        Ok(port) if (1..=u16::MAX as usize).contains(&port) => Ok(port),
+        _ => Err(GenericError::ConfigError(format!(
+            "{} must be a number between 1 and {}, got '{}'",
+            setting,
+            u16::MAX,
+            value
+        ))),
+    }
+}
+
+/// Resolves a path setting, expanding `$HOME`
+fn parse_path(
+    setting: &str,
+    value: &str,
+    home: Option<&str>,
+) -> Result<std::path::PathBuf, GenericError> {
+    let value = parse_non_empty(setting, value.to_string())?;
+    if !value.contains("$HOME") {
+        return Ok(std::path::PathBuf::from(value));
+    }
+    match home {
+        Some(home) => Ok(std::path::PathBuf::from(value.replace("$HOME", home))),
+        None => Err(GenericError::ConfigError(format!(
+            "{}: '{}' uses $HOME but the home directory cannot be determined from the env",
+            setting, value
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(file: &str, env: &[(&str, &str)]) -> Result<Config, GenericError> {
+        let file = parse_config_file(file).map_err(GenericError::ConfigError)?;
+        Config::from_sources(&file, |setting| {
+            env.iter()
+                .chain([("HOME", "/home/cdktr")].iter())
+                .find(|(k, _)| *k == setting)
+                .map(|(_, v)| v.to_string())
+        })
+    }
+
+    fn load_err(file: &str, env: &[(&str, &str)]) -> String {
+        load(file, env).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = load("", &[]).unwrap();
+        assert_eq!(config.log_level, log::LevelFilter::Info);
+        assert_eq!(
+            config.app_data_directory,
+            std::path::PathBuf::from("/home/cdktr/.cdktr")
+        );
+        assert_eq!(config.principal_host, "0.0.0.0");
+        assert_eq!(config.principal_port, 5561);
+        assert_eq!(config.agent_max_concurrency, 5);
+        assert_eq!(config.workflow_dir, "workflows");
+    }
+
+    #[test]
+    fn test_file_and_env_overrides() {
+        let file = r#"
+log_level = "debug"
+principal_port = 6000
+agent_max_concurrency = 2
+workflow_dir = "/srv/workflows"
+"#;
+        let config = load(file, &[("CDKTR_PRINCIPAL_PORT", "7000")]).unwrap();
+        assert_eq!(config.log_level, log::LevelFilter::Debug);
+        // env takes precedence over the file
+        assert_eq!(config.principal_port, 7000);
+        assert_eq!(config.agent_max_concurrency, 2);
+        assert_eq!(config.workflow_dir, "/srv/workflows");
+    }
+
+    #[test]
+    fn test_invalid_config_file() {
+        assert!(load_err("principal_port = ", &[]).contains("ConfigError"));
+        assert!(
+            load_err("principal_prot = 5561", &[]).contains("'principal_prot' is not a setting")
+        );
+        assert!(load_err("principal_port = true", &[]).contains("must be a string or an integer"));
+    }
+
+    #[test]
+    fn test_invalid_log_level() {
+        let err = load_err("", &[("CDKTR_LOG_LEVEL", "LOUD")]);
+        assert!(err.contains("CDKTR_LOG_LEVEL: 'LOUD' is not a valid log level"));
+    }
+
+    #[test]
+    fn test_invalid_app_data_directory() {
+        assert!(
+            load_err("", &[("CDKTR_APP_DATA_DIRECTORY", "")])
+                .contains("CDKTR_APP_DATA_DIRECTORY: a value is required")
+        );
+        let file = parse_config_file("").unwrap();
+        let err = Config::from_sources(&file, |_| None).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("home directory cannot be determined")
+        );
+    }
+
+    #[test]
+    fn test_invalid_principal_host() {
+        assert!(
+            load_err("principal_host = ' '", &[])
+                .contains("CDKTR_PRINCIPAL_HOST: a value is required")
+        );
+    }
+
+    #[test]
+    fn test_invalid_ports() {
+        for setting in [
+            "CDKTR_PRINCIPAL_PORT",
+            "CDKTR_LOGS_LISTENING_PORT",
+            "CDKTR_LOGS_PUBLISHING_PORT",
+        ] {
+            for port in ["abc", "-1", "0", "70000"] {
+                assert!(load_err("", &[(setting, port)]).contains(&format!(
+                    "{setting} must be a number between 1 and 65535, got '{port}'"
+                )));
+            }
+        }
+        assert_eq!(parse_port("CDKTR_PRINCIPAL_PORT", "65535").unwrap(), 65535);
+    }
+
+    #[test]
+    fn test_invalid_agent_max_concurrency() {
+        assert!(
+            load_err("", &[("CDKTR_AGENT_MAX_CONCURRENCY", "-1")])
+                .contains("CDKTR_AGENT_MAX_CONCURRENCY: '-1' is not a valid unsigned integer")
+        );
+        assert!(
+            load_err("agent_max_concurrency = 0", &[])
+                .contains("CDKTR_AGENT_MAX_CONCURRENCY: must be greater than 0")
+        );
+    }
+
+    #[test]
+    fn test_invalid_default_zmq_timeout() {
+        assert!(
+            load_err("", &[("CDKTR_DEFAULT_ZMQ_TIMEOUT_MS", "soon")])
+                .contains("CDKTR_DEFAULT_ZMQ_TIMEOUT_MS: 'soon' is not a valid unsigned integer")
+        );
+    }
+
+    #[test]
+    fn test_reload_changes_log_level() {
+        let path = std::env::temp_dir().join(format!("cdktr-reload-{}.toml", std::process::id()));
+        std::fs::write(&path, "log_level = \"info\"\nprincipal_port = 6000\n").unwrap();
+        Config::load(Some(&path)).unwrap();
+        assert_eq!(config_setting("CDKTR_LOG_LEVEL"), Some("INFO".to_string()));
+        // settings that aren't reloadable are read from the file as well
+        assert_eq!(
+            config_setting("CDKTR_PRINCIPAL_PORT"),
+            Some("6000".to_string())
+        );
+
+        std::fs::write(
+            &path,
+            "log_level = \"debug\"\nprincipal_port = 6001\nworkflow_dir_refresh_frequency_s = 5\n",
+        )
+        .unwrap();
+        let changes = reload_config().unwrap();
+        assert_eq!(log::max_level(), log::LevelFilter::Debug);
+        assert_eq!(config_setting("CDKTR_LOG_LEVEL"), Some("DEBUG".to_string()));
+        assert_eq!(
+            config_setting("CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S"),
+            Some("5".to_string())
+        );
+        // but keep their running value until a restart
+        assert_eq!(
+            config_setting("CDKTR_PRINCIPAL_PORT"),
+            Some("6000".to_string())
+        );
+        let port_change = ConfigChange {
+            setting: "CDKTR_PRINCIPAL_PORT".to_string(),
+            old: "6000".to_string(),
+            new: "6001".to_string(),
+            applied: false,
+        };
+        assert_eq!(
+            changes,
+            vec![
+                ConfigChange {
+                    setting: "CDKTR_LOG_LEVEL".to_string(),
+                    old: "INFO".to_string(),
+                    new: "DEBUG".to_string(),
+                    applied: true,
+                },
+                port_change.clone(),
+                ConfigChange {
+                    setting: "CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S".to_string(),
+                    old: "60".to_string(),
+                    new: "5".to_string(),
+                    applied: true,
+                },
+            ]
+        );
+
+        // the port isn't changed until a restart so it is reported again
+        assert_eq!(reload_config().unwrap(), vec![port_change]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_workflow_dir() {
+        assert!(
+            load_err("", &[("CDKTR_WORKFLOW_DIR", "")])
+                .contains("CDKTR_WORKFLOW_DIR: a value is required")
+        );
+    }
+}
@@ -1,72 +1,115 @@
-use std::{fmt, num::ParseIntError};
+use std::num::ParseIntError;
 
-#[derive(Debug, PartialEq)]
+use thiserror::Error;
+use zeromq::ZmqError;
+
+#[derive(Debug, PartialEq, Error)]
 pub enum ZMQParseError {
+    #[error("ParseError: {0}")]
     ParseError(String),
     // InvalidMessageType,
+    #[error("Invalid task type")]
     InvalidTaskType,
 }
-impl ZMQParseError {
-    pub fn to_string(&self) -> String {
-        match self {
-            Self::ParseError(msg) => format!("ParseError: {msg}"),
-            // Self::InvalidMessageType => String::from("Invalid message type"),
-            Self::InvalidTaskType => String::from("Invalid task type"),
-        }
-    }
+
+/// Broad category of an error, for callers that need to decide what to do about an
+/// error (e.g. whether to retry) without matching on its message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    MissingAgents,
+    Timeout,
+    /// The peer couldn't be reached or dropped the connection
+    Connection,
+    Parse,
+    Zmq,
+    Runtime,
+    Workflow,
+    NoData,
+    Database,
+    Config,
+    /// The request was understood but can't be processed
+    Unprocessable,
+    /// The server failed to handle the request
+    Server,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Error)]
 pub enum GenericError {
+    #[error("Missing agents: No running agents found")]
     MissingAgents,
+    #[error("Call timed out - check principal instance is running")]
     PrincipalTimeoutError,
+    #[error("Call timed out")]
     ZMQTimeoutError,
-    ZMQParseError(ZMQParseError),
+    #[error("ConnectionError: {0}")]
+    ConnectionError(String),
+    #[error("ZMQ Error: {0}")]
+    ZMQParseError(#[from] ZMQParseError),
+    #[error("ZMQError: {0}")]
     ZMQError(String),
+    #[error("ParseError: {0}")]
     ParseError(String),
+    #[error("Runtime Error: {0}")]
     RuntimeError(String),
+    #[error("WorkflowError: {0}")]
     WorkflowError(String),
+    #[error("NoDataException: {0}")]
     NoDataException(String), // APIError(String),
+    #[error("DBError: {0}")]
     DBError(String),
+    #[error("DBError: {0}")]
     DBQueryStatementError(String),
+    #[error("ConfigError: {0}")]
+    ConfigError(String),
 }
+
 impl GenericError {
-    pub fn to_string(&self) -> String {
+    pub fn kind(&self) -> ErrorKind {
         match self {
-            Self::MissingAgents => String::from("Missing agents: No running agents found"),
-            Self::PrincipalTimeoutError => {
-                String::from("Call timed out - check principal instance is running")
-            }
-            Self::ZMQTimeoutError => String::from("Call timed out"),
-            Self::ZMQParseError(zmq_e) => format!("ZMQ Error: {}", zmq_e.to_string()),
-            Self::RuntimeError(s) => format!("Runtime Error: {}", s.clone()),
-            Self::NoDataException(s) => format!("NoDataException: {}", s.clone()),
-            Self::ParseError(s) => format!("ParseError: {}", s.clone()),
-            Self::WorkflowError(s) => format!("WorkflowError: {}", s.clone()),
-            Self::ZMQError(s) => format!("ZMQError: {}", s.clone()),
-            Self::DBError(s) => format!("DBError: {}", s.clone()),
-            Self::DBQueryStatementError(s) => format!("DBError: {}", s.clone()),
-            // Self::APIError(s) => s.clone(),
+            Self::MissingAgents => ErrorKind::MissingAgents,
+            Self::PrincipalTimeoutError | Self::ZMQTimeoutError => ErrorKind::Timeout,
+            Self::ConnectionError(_) => ErrorKind::Connection,
+            Self::ZMQParseError(_) | Self::ParseError(_) => ErrorKind::Parse,
+            Self::ZMQError(_) => ErrorKind::Zmq,
+            Self::RuntimeError(_) => ErrorKind::Runtime,
+            Self::WorkflowError(_) => ErrorKind::Workflow,
+            Self::NoDataException(_) => ErrorKind::NoData,
+            Self::DBError(_) | Self::DBQueryStatementError(_) => ErrorKind::Database,
+            Self::ConfigError(_) => ErrorKind::Config,
         }
     }
 }
-impl fmt::Display for GenericError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.to_string())
-    }
-}
 
-impl Into<GenericError> for zeromq::ZmqError {
-    fn into(self) -> GenericError {
-        GenericError::ZMQError(self.to_string())
+/// Errors where the peer couldn't be reached or went away mid-request are kept apart
+/// from other ZMQ failures so that callers can retry them
+impl From<ZmqError> for GenericError {
+    fn from(e: ZmqError) -> Self {
+        match &e {
+            ZmqError::Network(io_err)
+                if matches!(
+                    io_err.kind(),
+                    std::io::ErrorKind::ConnectionRefused
+                        | std::io::ErrorKind::ConnectionReset
+                        | std::io::ErrorKind::ConnectionAborted
+                        | std::io::ErrorKind::NotConnected
+                        | std::io::ErrorKind::BrokenPipe
+                        | std::io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                GenericError::ConnectionError(e.to_string())
+            }
+            // the peer closing the connection mid-message surfaces as a codec error
+            ZmqError::Codec(_) => GenericError::ConnectionError(e.to_string()),
+            _ => GenericError::ZMQError(e.to_string()),
+        }
     }
 }
 
-impl Into<GenericError> for ParseIntError {
-    fn into(self) -> GenericError {
+impl From<ParseIntError> for GenericError {
+    fn from(e: ParseIntError) -> Self {
         GenericError::ParseError(format!(
             "Value is not a valid integer. Original error: {}",
-            self.to_string()
+            e
         ))
     }
 }
@@ -77,3 +120,31 @@ pub fn cdktr_result<T, E: Into<GenericError>>(r: Result<T, E>) -> Result<T, Gene
         Err(e) => Err(e.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_zmq_errors_keep_their_kind() {
+        let refused: GenericError =
+            ZmqError::Network(std::io::Error::from(std::io::ErrorKind::ConnectionRefused)).into();
+        assert_eq!(refused.kind(), ErrorKind::Connection);
+        let other: GenericError = ZmqError::NoMessage.into();
+        assert_eq!(other.kind(), ErrorKind::Zmq);
+        assert_eq!(other.to_string(), "ZMQError: No message received");
+    }
+
+    #[test]
+    fn test_parse_errors_keep_their_cause() {
+        let err: GenericError = ZMQParseError::InvalidTaskType.into();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            ZMQParseError::InvalidTaskType.to_string()
+        );
+        let err: GenericError = "abc".parse::<usize>().unwrap_err().into();
+        assert_eq!(err.kind(), ErrorKind::Parse);
+    }
+}
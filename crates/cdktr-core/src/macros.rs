@@ -1,10 +1,14 @@
 #[macro_export]
 macro_rules! get_cdktr_setting {
     ($setting:ident) => {
-        ::std::env::var(stringify!($setting)).unwrap_or(cdktr_core::config::$setting.to_string())
+        cdktr_core::config::config_setting(stringify!($setting))
+            .or_else(|| ::std::env::var(stringify!($setting)).ok())
+            .unwrap_or(cdktr_core::config::$setting.to_string())
     };
     ($setting:ident, usize) => {
-        match ::std::env::var(stringify!($setting)) {
+        match cdktr_core::config::config_setting(stringify!($setting))
+            .map_or_else(|| ::std::env::var(stringify!($setting)), Ok)
+        {
             Ok(v) => match v.parse() {
                 Ok(i) => i,
                 Err(e) => {
@@ -22,10 +26,14 @@ macro_rules! get_cdktr_setting {
 
 macro_rules! internal_get_cdktr_setting {
     ($setting:ident) => {
-        env::var(stringify!($setting)).unwrap_or(crate::config::$setting.to_string())
+        crate::config::config_setting(stringify!($setting))
+            .or_else(|| env::var(stringify!($setting)).ok())
+            .unwrap_or(crate::config::$setting.to_string())
     };
     ($setting:ident, usize) => {
-        match ::std::env::var(stringify!($setting)) {
+        match crate::config::config_setting(stringify!($setting))
+            .map_or_else(|| ::std::env::var(stringify!($setting)), Ok)
+        {
             Ok(v) => match v.parse() {
                 Ok(i) => i,
                 Err(_e) => {
@@ -2,6 +2,7 @@ use crate::{
     exceptions,
     utils::{arg_str_to_vecd, vecd_to_arg_str},
 };
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use zeromq::ZmqMessage;
 pub mod traits;
@@ -10,7 +11,8 @@ pub mod traits;
 pub enum FlowExecutionResult {
     SUCCESS,
     CRASHED(String),
-    FAILURE(String),
+    /// Error message and the exit code of the process, if it exited with one
+    FAILURE(String, Option<i32>),
     // ABORTED(String),
 }
 
@@ -18,7 +20,7 @@ impl FlowExecutionResult {
     pub fn _to_string(self) -> String {
         match self {
             Self::CRASHED(v) => v,
-            Self::FAILURE(v) => v,
+            Self::FAILURE(v, _) => v,
             _ => "".to_string(), // Self::ABORTED(v) => v,
                                  // Self::FAILURE(v) => v,
         }
@@ -43,10 +45,13 @@ impl RunType {
 pub enum RunStatus {
     PENDING,
     RUNNING,
+    /// the run was queued while no agents were registered and is held until one is
     WAITING,
     COMPLETED,
     FAILED,
     CRASHED,
+    /// the task's `when` condition did not hold so it was not run
+    SKIPPED,
 }
 impl TryFrom<String> for RunStatus {
     type Error = exceptions::GenericError;
@@ -58,6 +63,7 @@ impl TryFrom<String> for RunStatus {
             "COMPLETED" => Ok(RunStatus::COMPLETED),
             "FAILED" => Ok(RunStatus::FAILED),
             "CRASHED" => Ok(RunStatus::CRASHED),
+            "SKIPPED" => Ok(RunStatus::SKIPPED),
             _ => Err(exceptions::GenericError::ParseError(format!(
                 "Unrecognised task status: {}",
                 value
@@ -74,15 +80,15 @@ impl RunStatus {
             RunStatus::COMPLETED => String::from("COMPLETED"),
             RunStatus::FAILED => String::from("FAILED"),
             RunStatus::CRASHED => String::from("CRASHED"),
+            RunStatus::SKIPPED => String::from("SKIPPED"),
         }
     }
 }
 
 /// The ZMQArgs struct acts as an iterator of arguments that other
-/// functions and structs can use to iterate over the pipe-delimited
-/// messages sent over ZMQ. To avoid clashing with pipes, the \ character
-/// is used as an escape. Any intended \ character should be doubled \\ in
-/// order to avoid potential parsing issues.
+/// functions and structs can use to iterate over the SOH-delimited
+/// messages sent over ZMQ. See `zmq_helpers::format_zmq_msg_str` for
+/// how values containing SOH are escaped.
 #[derive(Debug, Clone)]
 pub struct ZMQArgs {
     inner: VecDeque<String>,
@@ -113,7 +119,7 @@ impl Into<String> for ZMQArgs {
     }
 }
 
-/// creating ZMQArgs from string automatically escapes pipes
+/// creating ZMQArgs from string automatically unescapes the arguments
 impl From<String> for ZMQArgs {
     fn from(value: String) -> Self {
         Self {
@@ -147,6 +153,158 @@ impl Into<ZMQArgs> for ZmqMessage {
     }
 }
 
+/// Identity of an agent, sent over the wire as `{user}@{host}/AG{suffix}`. The parts are
+/// split on the first `@` and the first `/` after it as neither can appear in a username
+/// or a host, so hosts with dashes or IPv6 literals like `fe80::1` round trip safely
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AgentId {
+    user: String,
+    host: String,
+    suffix: String,
+}
+
+impl AgentId {
+    pub fn new(user: String, host: String, suffix: String) -> Self {
+        Self { user, host, suffix }
+    }
+
+    /// Identity of an agent started by the current user on this machine
+    pub fn local(suffix: Option<String>) -> Self {
+        Self::new(
+            whoami::username(),
+            whoami::devicename(),
+            suffix.unwrap_or_default(),
+        )
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn suffix(&self) -> &str {
+        &self.suffix
+    }
+}
+
+impl std::fmt::Display for AgentId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}/AG{}", self.user, self.host, self.suffix)
+    }
+}
+
+impl std::str::FromStr for AgentId {
+    type Err = exceptions::GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            exceptions::GenericError::ParseError(format!(
+                "Invalid agent id `{}` - expected user@host/AG<suffix>",
+                s
+            ))
+        };
+        let (user, rest) = s.split_once('@').ok_or_else(invalid)?;
+        let (host, rest) = rest.split_once('/').ok_or_else(invalid)?;
+        let suffix = rest.strip_prefix("AG").ok_or_else(invalid)?;
+        if user.is_empty() || host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self::new(
+            user.to_string(),
+            host.to_string(),
+            suffix.to_string(),
+        ))
+    }
+}
+
+/// Defines a newtype over the `String` of an id. It is sent and stored exactly as the
+/// bare string, so the type only exists to keep ids of different things apart in code
+macro_rules! string_id {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn new(id: impl Into<String>) -> Self {
+                Self(id.into())
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            pub fn into_inner(self) -> String {
+                self.0
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = std::convert::Infallible;
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Ok(Self::new(s))
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(id: String) -> Self {
+                Self(id)
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(id: &str) -> Self {
+                Self::new(id)
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+    };
+}
+
+string_id!(
+    /// Id of a single run of a workflow, e.g. `jumping-monkey-0`, as opposed to the id of
+    /// the workflow it is a run of
+    WorkflowInstanceId
+);
+
+string_id!(
+    /// Id of a single execution of a task within a workflow run, as opposed to the id of
+    /// the task in the workflow
+    TaskInstanceId
+);
+
 /// Agent metadata held by principal that is used by the task router
 /// to decide which agent to route tasks to and by the server to determine
 /// status
@@ -154,6 +312,20 @@ impl Into<ZMQArgs> for ZmqMessage {
 pub struct AgentMeta {
     agent_id: String,
     running_tasks: usize,
+    /// maximum number of workflows the agent runs at once. None for agents that
+    /// don't report it
+    max_concurrency: Option<usize>,
+    /// drained agents finish their running workflows but aren't given new ones
+    drained: bool,
+    /// executors the agent runs tasks with. None for agents that don't report them,
+    /// which are assumed to run every executor
+    executors: Option<Vec<String>>,
+    /// address the agent listens on for pushed workflows. None for agents that fetch
+    /// their work
+    push_address: Option<String>,
+    /// token the agent process registered with, to tell it apart from another agent
+    /// registering with the same id. None for agents that don't send one
+    session: Option<String>,
     pub last_ping_timestamp: i64,
 }
 impl AgentMeta {
@@ -162,8 +334,29 @@ impl AgentMeta {
             agent_id,
             last_ping_timestamp,
             running_tasks: 0,
+            max_concurrency: None,
+            drained: false,
+            executors: None,
+            push_address: None,
+            session: None,
         }
     }
+    pub fn with_max_concurrency(mut self, max_concurrency: Option<usize>) -> Self {
+        self.max_concurrency = max_concurrency;
+        self
+    }
+    pub fn with_executors(mut self, executors: Option<Vec<String>>) -> Self {
+        self.executors = executors;
+        self
+    }
+    pub fn with_push_address(mut self, push_address: Option<String>) -> Self {
+        self.push_address = push_address;
+        self
+    }
+    pub fn with_session(mut self, session: Option<String>) -> Self {
+        self.session = session;
+        self
+    }
     pub fn agent_id(&self) -> String {
         self.agent_id.clone()
     }
@@ -189,6 +382,42 @@ impl AgentMeta {
     pub fn get_last_ping_ts(&self) -> i64 {
         self.last_ping_timestamp
     }
+    pub fn max_concurrency(&self) -> Option<usize> {
+        self.max_concurrency
+    }
+    pub fn set_max_concurrency(&mut self, max_concurrency: usize) {
+        self.max_concurrency = Some(max_concurrency)
+    }
+    pub fn is_drained(&self) -> bool {
+        self.drained
+    }
+    pub fn set_drained(&mut self, drained: bool) {
+        self.drained = drained
+    }
+    pub fn executors(&self) -> Option<&Vec<String>> {
+        self.executors.as_ref()
+    }
+    pub fn set_executors(&mut self, executors: Option<Vec<String>>) {
+        self.executors = executors
+    }
+    pub fn push_address(&self) -> Option<&String> {
+        self.push_address.as_ref()
+    }
+    pub fn set_push_address(&mut self, push_address: Option<String>) {
+        self.push_address = push_address
+    }
+    pub fn session(&self) -> Option<&String> {
+        self.session.as_ref()
+    }
+    pub fn set_session(&mut self, session: Option<String>) {
+        self.session = session
+    }
+    /// Whether the agent can run tasks with the given executor
+    pub fn supports_executor(&self, executor: &str) -> bool {
+        self.executors
+            .as_ref()
+            .is_none_or(|executors| executors.iter().any(|e| e == executor))
+    }
 }
 
 #[cfg(test)]
@@ -215,6 +444,55 @@ mod tests {
 
         agent.update_timestamp(10);
         assert_eq!(agent.get_last_ping_ts(), 10);
+
+        // agents that don't report executors run all of them
+        assert!(agent.supports_executor("UvPython"));
+        agent.set_executors(Some(vec!["Subprocess".to_string()]));
+        assert!(agent.supports_executor("Subprocess"));
+        assert!(!agent.supports_executor("UvPython"));
+    }
+
+    #[test]
+    fn test_agent_id_round_trip() {
+        for (user, host, suffix) in [
+            ("cdktr", "localhost", ""),
+            ("cdktr", "my-build-host-01", "-2"),
+            ("cdktr", "fe80::1", "worker-1"),
+            ("cdktr", "2001:db8::ff00:42:8329", "a/b"),
+        ] {
+            let agent_id = AgentId::new(user.to_string(), host.to_string(), suffix.to_string());
+            let parsed: AgentId = agent_id.to_string().parse().unwrap();
+            assert_eq!(parsed, agent_id);
+            assert_eq!(parsed.host(), host);
+            assert_eq!(parsed.suffix(), suffix);
+        }
+        assert_eq!(
+            "cdktr@my-host/AG".parse::<AgentId>().unwrap().host(),
+            "my-host"
+        );
+        for invalid in ["my-host", "cdktr@my-host", "cdktr@my-host/PRIN", "@host/AG"] {
+            assert!(invalid.parse::<AgentId>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_instance_ids_serialise_as_plain_strings() {
+        let workflow_instance_id = WorkflowInstanceId::new("jumping-monkey-0");
+        let task_instance_id: TaskInstanceId = "sleepy-otter-3".parse().unwrap();
+        assert_eq!(workflow_instance_id.to_string(), "jumping-monkey-0");
+        assert_eq!(
+            serde_json::to_string(&workflow_instance_id).unwrap(),
+            serde_json::to_string("jumping-monkey-0").unwrap()
+        );
+        assert_eq!(
+            serde_json::to_string(&task_instance_id).unwrap(),
+            "\"sleepy-otter-3\""
+        );
+        assert_eq!(
+            serde_json::from_str::<WorkflowInstanceId>("\"jumping-monkey-0\"").unwrap(),
+            workflow_instance_id
+        );
+        assert_eq!(String::from(task_instance_id), "sleepy-otter-3");
     }
 
     #[test]
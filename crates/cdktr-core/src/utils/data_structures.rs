@@ -1,4 +1,6 @@
-use crate::{exceptions::GenericError, models::AgentMeta};
+use crate::{exceptions::GenericError, macros::internal_get_cdktr_setting, models::AgentMeta};
+use log::warn;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{BinaryHeap, HashMap, VecDeque},
     sync::{
@@ -7,33 +9,223 @@ use std::{
     },
 };
 use tokio::{
-    sync::Mutex,
-    time::{Duration, sleep},
+    sync::{Mutex, Notify},
+    time::{Duration, Instant, timeout_at},
 };
 
+/// Time constant of the exponentially weighted enqueue/dequeue rates. Events older
+/// than this have contributed roughly a third of their original weight
+const QUEUE_RATE_DECAY_S: f64 = 60.0;
+
+/// Point-in-time snapshot of the throughput of an `AsyncQueue`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct QueueMetrics {
+    pub size: usize,
+    pub total_enqueued: u64,
+    pub total_dequeued: u64,
+    /// exponentially weighted items per second
+    pub enqueue_rate_per_s: f64,
+    /// exponentially weighted items per second
+    pub dequeue_rate_per_s: f64,
+    /// how long the queue has been non-empty with items arriving faster than
+    /// they are consumed
+    pub growing_for_ms: Option<u64>,
+    pub slow_consumer_warnings: u64,
+}
+
+/// Tracks enqueue and dequeue rates so that a queue that keeps growing
+/// without being drained can be flagged before it wedges the system
+#[derive(Debug)]
+struct QueueRateTracker {
+    slow_consumer_threshold: Duration,
+    total_enqueued: u64,
+    total_dequeued: u64,
+    enqueue_rate: f64,
+    dequeue_rate: f64,
+    last_event: Instant,
+    growing_since: Option<Instant>,
+    // only warn once per growth episode
+    warned: bool,
+    slow_consumer_warnings: u64,
+}
+
+impl QueueRateTracker {
+    fn new(slow_consumer_threshold: Duration) -> Self {
+        Self {
+            slow_consumer_threshold,
+            total_enqueued: 0,
+            total_dequeued: 0,
+            enqueue_rate: 0.0,
+            dequeue_rate: 0.0,
+            last_event: Instant::now(),
+            growing_since: None,
+            warned: false,
+            slow_consumer_warnings: 0,
+        }
+    }
+
+    fn decay(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_event).as_secs_f64();
+        let factor = (-elapsed / QUEUE_RATE_DECAY_S).exp();
+        self.enqueue_rate *= factor;
+        self.dequeue_rate *= factor;
+        self.last_event = now;
+    }
+
+    fn record_enqueue(&mut self, count: usize, queue_len: usize) {
+        if count == 0 {
+            return;
+        }
+        let now = Instant::now();
+        self.decay(now);
+        self.total_enqueued += count as u64;
+        self.enqueue_rate += count as f64 / QUEUE_RATE_DECAY_S;
+        if self.growing_since.is_none() && self.enqueue_rate > self.dequeue_rate {
+            self.growing_since = Some(now);
+        }
+        self.check_slow_consumer(now, queue_len);
+    }
+
+    fn record_dequeue(&mut self, count: usize, queue_len: usize) {
+        if count == 0 {
+            return;
+        }
+        self.decay(Instant::now());
+        self.total_dequeued += count as u64;
+        self.dequeue_rate += count as f64 / QUEUE_RATE_DECAY_S;
+        if queue_len == 0 || self.dequeue_rate >= self.enqueue_rate {
+            self.growing_since = None;
+            self.warned = false;
+        }
+    }
+
+    fn check_slow_consumer(&mut self, now: Instant, queue_len: usize) {
+        if let Some(since) = self.growing_since {
+            let growing_for = now.duration_since(since);
+            if !self.warned && queue_len > 0 && growing_for >= self.slow_consumer_threshold {
+                warn!(
+                    "Queue has been growing for {}s without being drained - {} items pending (enqueue rate {:.3}/s, dequeue rate {:.3}/s). Check that there are healthy consumers",
+                    growing_for.as_secs(),
+                    queue_len,
+                    self.enqueue_rate,
+                    self.dequeue_rate
+                );
+                self.warned = true;
+                self.slow_consumer_warnings += 1;
+            }
+        }
+    }
+
+    fn snapshot(&self, size: usize) -> QueueMetrics {
+        // decay a copy of the rates so an idle queue reports falling rates
+        // without mutating the tracker
+        let elapsed = Instant::now().duration_since(self.last_event).as_secs_f64();
+        let factor = (-elapsed / QUEUE_RATE_DECAY_S).exp();
+        QueueMetrics {
+            size,
+            total_enqueued: self.total_enqueued,
+            total_dequeued: self.total_dequeued,
+            enqueue_rate_per_s: self.enqueue_rate * factor,
+            dequeue_rate_per_s: self.dequeue_rate * factor,
+            growing_for_ms: self
+                .growing_since
+                .map(|since| since.elapsed().as_millis() as u64),
+            slow_consumer_warnings: self.slow_consumer_warnings,
+        }
+    }
+}
+
 /// A simple queue that can be accessed across threads. The queue
 /// holds an internal Arc<Mutex<T>> to abstract the verbose handling
-/// of the mutex away from the consumer
+/// of the mutex away from the consumer. Waiters are woken via a shared
+/// `Notify` whenever items are put on the queue. Enqueue and dequeue
+/// rates are tracked so that slow or missing consumers are logged
 #[derive(Clone, Debug)]
 pub struct AsyncQueue<T> {
     inner: Arc<Mutex<VecDeque<T>>>,
+    notify: Arc<Notify>,
+    rates: Arc<std::sync::Mutex<QueueRateTracker>>,
 }
 impl<T> AsyncQueue<T> {
     pub fn new() -> Self {
+        let threshold_s =
+            internal_get_cdktr_setting!(CDKTR_QUEUE_SLOW_CONSUMER_WARNING_S, usize) as u64;
+        Self::with_slow_consumer_threshold(Duration::from_secs(threshold_s))
+    }
+
+    /// Creates a queue that warns when it has been growing for longer than `threshold`
+    pub fn with_slow_consumer_threshold(threshold: Duration) -> Self {
         Self {
             inner: Arc::new(Mutex::new(VecDeque::new())),
+            notify: Arc::new(Notify::new()),
+            rates: Arc::new(std::sync::Mutex::new(QueueRateTracker::new(threshold))),
         }
     }
+
+    fn record_enqueue(&self, count: usize, queue_len: usize) {
+        self.rates
+            .lock()
+            .expect("Queue rate tracker lock poisoned")
+            .record_enqueue(count, queue_len);
+    }
+
+    fn record_dequeue(&self, count: usize, queue_len: usize) {
+        self.rates
+            .lock()
+            .expect("Queue rate tracker lock poisoned")
+            .record_dequeue(count, queue_len);
+    }
+
+    /// Warns if the queue has been growing for longer than the slow consumer threshold.
+    /// This is checked on every enqueue, so it's only needed to catch a queue that
+    /// stopped being drained after its last enqueue
+    pub async fn check_slow_consumer(&self) {
+        let size = self.size().await;
+        self.rates
+            .lock()
+            .expect("Queue rate tracker lock poisoned")
+            .check_slow_consumer(Instant::now(), size);
+    }
+
+    /// Returns a snapshot of the queue size and its enqueue/dequeue rates
+    pub async fn metrics(&self) -> QueueMetrics {
+        let size = self.size().await;
+        self.rates
+            .lock()
+            .expect("Queue rate tracker lock poisoned")
+            .snapshot(size)
+    }
+
     /// Gets the next item from the queue.
     pub async fn get(&mut self) -> Option<T> {
         let mut queue = self.inner.lock().await;
-        queue.pop_front()
+        let item = queue.pop_front();
+        if item.is_some() {
+            self.record_dequeue(1, queue.len());
+        }
+        item
+    }
+
+    /// Gets the first item on the queue that matches the predicate, leaving the items
+    /// before it where they are
+    pub async fn get_first_where<F: Fn(&T) -> bool>(&mut self, predicate: F) -> Option<T> {
+        let mut queue = self.inner.lock().await;
+        let item = queue
+            .iter()
+            .position(predicate)
+            .and_then(|ix| queue.remove(ix));
+        if item.is_some() {
+            self.record_dequeue(1, queue.len());
+        }
+        item
     }
 
     /// Puts an item on the queue
     pub async fn put(&mut self, item: T) {
         let mut queue = self.inner.lock().await;
         queue.push_back(item);
+        self.record_enqueue(1, queue.len());
+        self.notify.notify_waiters();
     }
 
     /// Puts a block of items on the queue
@@ -42,15 +234,20 @@ impl<T> AsyncQueue<T> {
         I: IntoIterator<Item = T>,
     {
         let mut queue = self.inner.lock().await;
+        let before = queue.len();
         for item in items {
             queue.push_back(item);
         }
+        self.record_enqueue(queue.len() - before, queue.len());
+        self.notify.notify_waiters();
     }
 
     // Puts an item at the front of the queue
     pub async fn put_front(&mut self, item: T) {
         let mut queue = self.inner.lock().await;
         queue.push_front(item);
+        self.record_enqueue(1, queue.len());
+        self.notify.notify_waiters();
     }
 
     // Puts a block of items at the front of the queue
@@ -59,9 +256,12 @@ impl<T> AsyncQueue<T> {
         I: IntoIterator<Item = T>,
     {
         let mut queue = self.inner.lock().await;
+        let before = queue.len();
         for item in items {
             queue.push_front(item);
         }
+        self.record_enqueue(queue.len() - before, queue.len());
+        self.notify.notify_waiters();
     }
 
     /// Checks whether the queue ois empty
@@ -73,21 +273,44 @@ impl<T> AsyncQueue<T> {
         self.inner.lock().await.len()
     }
 
-    /// Similar to .get() but intead of returning an Option<T> it repeatedly polls
-    /// the inner VecDeque until an item T is available
-    /// TODO: loop currently set to 500 millis. this dependency needs to be inverted
+    /// Similar to .get() but intead of returning an Option<T> it waits
+    /// until an item T is available
     pub async fn get_wait(&mut self) -> T {
         loop {
+            // register interest before checking so a put between the check
+            // and the wait is not missed
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
             let item_res = {
                 // scoped to release the lock before waiting
                 let mut queue = self.inner.lock().await;
-                queue.pop_front()
+                let item = queue.pop_front();
+                if item.is_some() {
+                    self.record_dequeue(1, queue.len());
+                }
+                item
             };
             if let Some(t) = item_res {
                 return t;
-            } else {
-                sleep(Duration::from_millis(500)).await;
-                continue;
+            }
+            notified.await;
+        }
+    }
+
+    /// Waits until the queue has at least one item or the duration elapses without
+    /// consuming anything. Returns whether an item is available
+    pub async fn wait_for_item(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if !self.is_empty().await {
+                return true;
+            }
+            if timeout_at(deadline, notified).await.is_err() {
+                return false;
             }
         }
     }
@@ -96,12 +319,27 @@ impl<T> AsyncQueue<T> {
     /// trying to acquire a snapshot of the queue while other processes are writing to it
     /// which would occur if retrieving one-by-one
     pub async fn dump(&mut self) -> Vec<T> {
+        self.drain().await
+    }
+
+    /// Removes every item from the queue, returning them in queue order
+    pub async fn drain(&mut self) -> Vec<T> {
         let mut queue = self.inner.lock().await;
-        let mut dumped = Vec::new();
-        while queue.len() > 0 {
-            dumped.push((*queue).pop_front().unwrap());
-        }
-        dumped
+        let drained: Vec<T> = queue.drain(..).collect();
+        self.record_dequeue(drained.len(), 0);
+        drained
+    }
+
+    /// Removes every item from the queue, returning how many were removed
+    pub async fn clear(&mut self) -> usize {
+        self.drain().await.len()
+    }
+}
+
+impl<T: Clone> AsyncQueue<T> {
+    /// Copies the items on the queue in queue order, leaving them where they are
+    pub async fn snapshot(&self) -> Vec<T> {
+        self.inner.lock().await.iter().cloned().collect()
     }
 }
 
@@ -152,68 +390,54 @@ impl AgentPriorityQueue {
     /// the agent_id as the key so that the unique id can be easily retrieved when only the
     /// agent id is know like incoming requests from the agents to update their status
     pub async fn push(&mut self, agent_meta: AgentMeta) {
-        let next_id = UNIQUENESS_COUNTER.fetch_add(1, Ordering::Relaxed);
-        let agent_id = agent_meta.agent_id();
-        // add utilisation and agent_id tuple to the max heap
-        {
-            let mut heap = self.heap.lock().await;
-            (*heap).push((agent_meta.utilisation(), next_id));
-        }
+        // every method that locks more than one of the structures locks them in the order
+        // heap, u_map, node_map and holds them until it is done, so concurrent calls can
+        // neither deadlock nor see the structures out of step with each other
+        let mut heap = self.heap.lock().await;
+        let mut u_map = self.u_map.lock().await;
+        let mut node_map = self.node_map.lock().await;
+        Self::push_locked(&mut heap, &mut u_map, &mut node_map, agent_meta);
+    }
 
-        // move agentmeta node to internal map
-        {
-            let mut node_map = self.node_map.lock().await;
-            (*node_map).insert(next_id, agent_meta);
-
-            // remove the now stale entry if it exists to avoid mem leak
-            (*node_map).remove(&{
-                let u_map = self.u_map.lock().await;
-                match u_map.get(&agent_id) {
-                    Some(id) => *id,
-                    None => 0, // no stale entry to remove
-                }
-            });
-        }
-        // move agent id to uniqueness map
-        {
-            let mut u_map = self.u_map.lock().await;
-            (*u_map).insert(agent_id, next_id);
+    fn push_locked(
+        heap: &mut BinaryHeap<(usize, usize)>,
+        u_map: &mut HashMap<String, usize>,
+        node_map: &mut HashMap<usize, AgentMeta>,
+        agent_meta: AgentMeta,
+    ) {
+        let next_id = UNIQUENESS_COUNTER.fetch_add(1, Ordering::Relaxed);
+        heap.push((agent_meta.utilisation(), next_id));
+        // remove the now stale entry if it exists to avoid mem leak
+        if let Some(stale_id) = u_map.insert(agent_meta.agent_id(), next_id) {
+            node_map.remove(&stale_id);
         }
+        node_map.insert(next_id, agent_meta);
     }
 
     /// O(1) pop of the item at the top of the queue and O(1) removals of the items respective
     /// items in both hashmaps
     pub async fn pop(&mut self) -> Result<AgentMeta, GenericError> {
         let mut heap = self.heap.lock().await;
-        loop {
-            if let Some((_utilisation, unique_id)) = (*heap).pop() {
-                // remove item from both the heap and agent_meta hashmap and return the agentmeta
-                let agent_meta = {
-                    let mut node_map = self.node_map.lock().await;
-
-                    // check if item is stale, if so, skip and pop again
-                    if !node_map.contains_key(&unique_id) {
-                        continue;
-                    };
-
-                    node_map
-                        .remove(&unique_id)
-                        .expect("Failed to find node in map when it appeared in max heap")
-                };
-
-                let agent_id = agent_meta.agent_id();
-                // remove from the uniqueness map
-                {
-                    let mut u_map = self.u_map.lock().await;
-                    u_map.remove(&agent_id).expect(
-                        "Failed to remove agent_id entry from u_map when is reference in max-heap",
-                    );
+        let mut u_map = self.u_map.lock().await;
+        let mut node_map = self.node_map.lock().await;
+        while let Some((_utilisation, unique_id)) = heap.pop() {
+            // items no longer in the node map are stale so are skipped
+            let Some(agent_meta) = node_map.remove(&unique_id) else {
+                continue;
+            };
+            let agent_id = agent_meta.agent_id();
+            match u_map.get(&agent_id) {
+                Some(id) if *id == unique_id => {
+                    u_map.remove(&agent_id);
                 }
-                return Ok(agent_meta);
-            } else {
-                return Err(GenericError::MissingAgents);
+                other => warn!(
+                    "Agent queue out of step: popped entry {} of agent {} but its latest entry is {:?}",
+                    unique_id, agent_id, other
+                ),
             }
+            return Ok(agent_meta);
         }
+        Err(GenericError::MissingAgents)
     }
 
     /// O(1) lookup to update the timestamp. This update doesn't affect its position in the queue
@@ -244,20 +468,24 @@ impl AgentPriorityQueue {
     /// leak if the agent_ids changed regularly and thus the same ids were not re-used in this queue once the agentmeta
     /// is pushed back
     pub async fn remove(&mut self, agent_id: &str) -> Result<AgentMeta, GenericError> {
-        let unique_id = {
-            let mut u_map = self.u_map.lock().await;
-            if let Some(id) = u_map.remove(agent_id) {
-                id
-            } else {
-                return Err(GenericError::MissingAgents);
-            }
-        };
+        let mut u_map = self.u_map.lock().await;
         let mut node_map = self.node_map.lock().await;
-        let agent_meta_res = node_map.remove(&unique_id);
-        match agent_meta_res {
-            Some(agent_meta) => Ok(agent_meta),
-            None => return Err(GenericError::MissingAgents),
-        }
+        Self::remove_locked(&mut u_map, &mut node_map, agent_id)
+    }
+
+    fn remove_locked(
+        u_map: &mut HashMap<String, usize>,
+        node_map: &mut HashMap<usize, AgentMeta>,
+        agent_id: &str,
+    ) -> Result<AgentMeta, GenericError> {
+        let unique_id = u_map.remove(agent_id).ok_or(GenericError::MissingAgents)?;
+        node_map.remove(&unique_id).ok_or_else(|| {
+            warn!(
+                "Agent queue out of step: agent {} has no entry {} to remove",
+                agent_id, unique_id
+            );
+            GenericError::MissingAgents
+        })
     }
 
     /// Check if an agent's last heartbeat timestamp is older than the given threshold
@@ -285,6 +513,113 @@ impl AgentPriorityQueue {
         }
     }
 
+    /// O(1) lookup to update the number of workflows an agent can run at once. Like the
+    /// timestamp this doesn't affect its position in the queue
+    pub async fn update_max_concurrency(
+        &self,
+        agent_id: &str,
+        max_concurrency: usize,
+    ) -> Result<(), GenericError> {
+        let u_map = self.u_map.lock().await;
+        let unique_id = u_map.get(agent_id).ok_or(GenericError::MissingAgents)?;
+        let mut node_map = self.node_map.lock().await;
+        match node_map.get_mut(unique_id) {
+            Some(agent_meta) => {
+                agent_meta.set_max_concurrency(max_concurrency);
+                Ok(())
+            }
+            None => Err(GenericError::MissingAgents),
+        }
+    }
+
+    /// O(1) lookup to update the executors an agent runs tasks with. Like the timestamp
+    /// this doesn't affect its position in the queue
+    pub async fn update_executors(
+        &self,
+        agent_id: &str,
+        executors: Option<Vec<String>>,
+    ) -> Result<(), GenericError> {
+        let u_map = self.u_map.lock().await;
+        let unique_id = u_map.get(agent_id).ok_or(GenericError::MissingAgents)?;
+        let mut node_map = self.node_map.lock().await;
+        match node_map.get_mut(unique_id) {
+            Some(agent_meta) => {
+                agent_meta.set_executors(executors);
+                Ok(())
+            }
+            None => Err(GenericError::MissingAgents),
+        }
+    }
+
+    /// O(1) lookup to update the address an agent listens on for pushed workflows. Like
+    /// the timestamp this doesn't affect its position in the queue
+    pub async fn update_push_address(
+        &self,
+        agent_id: &str,
+        push_address: Option<String>,
+    ) -> Result<(), GenericError> {
+        let u_map = self.u_map.lock().await;
+        let unique_id = u_map.get(agent_id).ok_or(GenericError::MissingAgents)?;
+        let mut node_map = self.node_map.lock().await;
+        match node_map.get_mut(unique_id) {
+            Some(agent_meta) => {
+                agent_meta.set_push_address(push_address);
+                Ok(())
+            }
+            None => Err(GenericError::MissingAgents),
+        }
+    }
+
+    /// O(1) lookup to update the session an agent process registered with. Like the
+    /// timestamp this doesn't affect its position in the queue
+    pub async fn update_session(
+        &self,
+        agent_id: &str,
+        session: Option<String>,
+    ) -> Result<(), GenericError> {
+        let u_map = self.u_map.lock().await;
+        let unique_id = u_map.get(agent_id).ok_or(GenericError::MissingAgents)?;
+        let mut node_map = self.node_map.lock().await;
+        match node_map.get_mut(unique_id) {
+            Some(agent_meta) => {
+                agent_meta.set_session(session);
+                Ok(())
+            }
+            None => Err(GenericError::MissingAgents),
+        }
+    }
+
+    /// O(1) lookup to drain an agent, or make it schedulable again. Like the timestamp
+    /// this doesn't affect its position in the queue
+    pub async fn set_drained(&self, agent_id: &str, drained: bool) -> Result<(), GenericError> {
+        let u_map = self.u_map.lock().await;
+        let unique_id = u_map.get(agent_id).ok_or(GenericError::MissingAgents)?;
+        let mut node_map = self.node_map.lock().await;
+        match node_map.get_mut(unique_id) {
+            Some(agent_meta) => {
+                agent_meta.set_drained(drained);
+                Ok(())
+            }
+            None => Err(GenericError::MissingAgents),
+        }
+    }
+
+    /// Aggregate capacity of the registered agents as `(agents, total_slots, used_slots)`.
+    /// Only the agent map is read so the heap isn't locked. Agents that don't report
+    /// their concurrency add no slots but their running tasks still count as used
+    pub async fn capacity(&self) -> (usize, usize, usize) {
+        let node_map = self.node_map.lock().await;
+        node_map
+            .values()
+            .fold((0, 0, 0), |(agents, total, used), agent_meta| {
+                (
+                    agents + 1,
+                    total + agent_meta.max_concurrency().unwrap_or(0),
+                    used + agent_meta.utilisation(),
+                )
+            })
+    }
+
     /// Get all registered agents. Returns a vector of cloned AgentMeta objects.
     /// This is useful for reporting/monitoring purposes.
     pub async fn get_all_agents(&self) -> Vec<AgentMeta> {
@@ -294,18 +629,22 @@ impl AgentPriorityQueue {
 
     /// O(log n) ID lookup to update agent utilisation which directly affects its position in the queue. This is
     /// time complexity can be acheived because we use the hashmap to mutably access the AgentMeta, make the update
-    /// and then push back using .push() which is a O(log n) insert.
+    /// and then push back which is a O(log n) insert. All three structures stay locked in between so other
+    /// callers never see the agent missing
     pub async fn update_running_tasks(
         &mut self,
         agent_id: &str,
         up: bool,
     ) -> Result<(), GenericError> {
-        let mut agent_meta = self.remove(agent_id).await?;
+        let mut heap = self.heap.lock().await;
+        let mut u_map = self.u_map.lock().await;
+        let mut node_map = self.node_map.lock().await;
+        let mut agent_meta = Self::remove_locked(&mut u_map, &mut node_map, agent_id)?;
         match up {
             true => agent_meta.inc_running_tasks(),
             false => agent_meta.dec_running_tasks(),
         };
-        self.push(agent_meta).await;
+        Self::push_locked(&mut heap, &mut u_map, &mut node_map, agent_meta);
         Ok(())
     }
 
@@ -346,6 +685,15 @@ mod tests {
         assert!(queue.is_empty().await);
     }
 
+    #[tokio::test]
+    async fn test_async_queue_get_first_where() {
+        let mut queue: AsyncQueue<i32> = AsyncQueue::new();
+        queue.put_multiple([1, 2, 3, 4]).await;
+        assert_eq!(queue.get_first_where(|i| i % 2 == 0).await, Some(2));
+        assert_eq!(queue.get_first_where(|i| *i > 10).await, None);
+        assert_eq!(queue.drain().await, vec![1, 3, 4]);
+    }
+
     #[tokio::test]
     async fn test_async_queue_put_and_get_wait() {
         let mut queue: AsyncQueue<i32> = AsyncQueue::new();
@@ -370,6 +718,96 @@ mod tests {
         assert!(queue.is_empty().await);
     }
 
+    #[tokio::test]
+    async fn test_async_queue_wait_for_item() {
+        let queue: AsyncQueue<i32> = AsyncQueue::new();
+        let mut q_clone = queue.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            q_clone.put(1).await;
+        });
+        let start = Instant::now();
+        assert!(queue.wait_for_item(Duration::from_secs(5)).await);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // waiting does not consume the item
+        assert_eq!(queue.size().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_async_queue_slow_consumer_warning() {
+        let mut queue: AsyncQueue<i32> =
+            AsyncQueue::with_slow_consumer_threshold(Duration::from_millis(50));
+        queue.put(1).await;
+        assert_eq!(queue.metrics().await.slow_consumer_warnings, 0);
+
+        // keep enqueuing with nothing consuming past the threshold
+        sleep(Duration::from_millis(60)).await;
+        queue.put(2).await;
+        queue.put(3).await;
+        let metrics = queue.metrics().await;
+        assert_eq!(metrics.slow_consumer_warnings, 1);
+        assert_eq!(metrics.size, 3);
+        assert_eq!(metrics.total_enqueued, 3);
+        assert_eq!(metrics.total_dequeued, 0);
+        assert!(metrics.enqueue_rate_per_s > metrics.dequeue_rate_per_s);
+        assert!(metrics.growing_for_ms.unwrap() >= 50);
+
+        // draining the queue resets the growth episode
+        queue.dump().await;
+        let metrics = queue.metrics().await;
+        assert_eq!(metrics.total_dequeued, 3);
+        assert!(metrics.growing_for_ms.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_async_queue_slow_consumer_checked_without_enqueue() {
+        let mut queue: AsyncQueue<i32> =
+            AsyncQueue::with_slow_consumer_threshold(Duration::from_millis(50));
+        queue.put(1).await;
+        queue.check_slow_consumer().await;
+        assert_eq!(queue.metrics().await.slow_consumer_warnings, 0);
+
+        // nothing is enqueued after the threshold passes
+        sleep(Duration::from_millis(60)).await;
+        queue.check_slow_consumer().await;
+        queue.check_slow_consumer().await;
+        assert_eq!(queue.metrics().await.slow_consumer_warnings, 1);
+    }
+
+    #[tokio::test]
+    async fn test_async_queue_drain_and_clear() {
+        let mut queue: AsyncQueue<i32> = AsyncQueue::new();
+        queue.put_multiple([1, 2, 3]).await;
+        assert_eq!(queue.drain().await, vec![1, 2, 3]);
+        assert!(queue.is_empty().await);
+        assert!(queue.drain().await.is_empty());
+
+        queue.put_multiple([4, 5]).await;
+        assert_eq!(queue.clear().await, 2);
+        assert_eq!(queue.size().await, 0);
+        assert_eq!(queue.metrics().await.total_dequeued, 5);
+    }
+
+    #[tokio::test]
+    async fn test_async_queue_no_warning_when_drained() {
+        let mut queue: AsyncQueue<i32> =
+            AsyncQueue::with_slow_consumer_threshold(Duration::from_millis(20));
+        for i in 0..5 {
+            queue.put(i).await;
+            sleep(Duration::from_millis(10)).await;
+            assert_eq!(queue.get().await, Some(i));
+        }
+        let metrics = queue.metrics().await;
+        assert_eq!(metrics.slow_consumer_warnings, 0);
+        assert!(metrics.growing_for_ms.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_async_queue_wait_for_item_times_out() {
+        let queue: AsyncQueue<i32> = AsyncQueue::new();
+        assert!(!queue.wait_for_item(Duration::from_millis(50)).await);
+    }
+
     #[tokio::test]
     async fn test_async_queue_is_empty() {
         let queue: AsyncQueue<i32> = AsyncQueue::new();
@@ -801,4 +1239,58 @@ mod tests {
         assert!(!u_map.contains_key(agent_id));
         assert!(!node_map.values().any(|am| am.agent_id() == agent_id));
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_concurrent_access_keeps_queue_consistent() {
+        let pq = AgentPriorityQueue::new();
+        let mut handles = Vec::new();
+        for worker in 0..16usize {
+            let mut pq = pq.clone();
+            handles.push(tokio::spawn(async move {
+                for i in 0..500usize {
+                    // workers share agents so they contend for the same entries
+                    let agent_id = format!("agent-{}", (worker + i) % 4);
+                    match i % 6 {
+                        0 => pq.push(AgentMeta::new(agent_id.clone(), 0)).await,
+                        1 => {
+                            let _ = pq.update_running_tasks(&agent_id, true).await;
+                        }
+                        2 => {
+                            let _ = pq.update_running_tasks(&agent_id, false).await;
+                        }
+                        3 => {
+                            let _ = pq.update_timestamp(&agent_id, i as i64).await;
+                        }
+                        4 => {
+                            let _ = pq.remove(&agent_id).await;
+                        }
+                        _ => {
+                            if let Ok(agent) = pq.pop().await {
+                                pq.push(agent).await;
+                            }
+                        }
+                    }
+                }
+            }));
+        }
+        // a deadlock shows up as a timeout and a panic as a failed join
+        timeout(Duration::from_secs(30), async {
+            for handle in handles {
+                handle.await.expect("queue operation panicked");
+            }
+        })
+        .await
+        .expect("queue operations deadlocked");
+
+        // every agent has exactly one live entry, and that entry is on the heap
+        let heap = pq.heap.lock().await;
+        let u_map = pq.u_map.lock().await;
+        let node_map = pq.node_map.lock().await;
+        assert_eq!(u_map.len(), node_map.len());
+        for (agent_id, unique_id) in u_map.iter() {
+            let agent = node_map.get(unique_id).expect("u_map entry has no node");
+            assert_eq!(&agent.agent_id(), agent_id);
+            assert!(heap.iter().any(|(_, id)| id == unique_id));
+        }
+    }
 }
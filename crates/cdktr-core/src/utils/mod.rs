@@ -1,26 +1,29 @@
-use std::{collections::VecDeque, env, time::Duration};
+use std::{
+    collections::VecDeque,
+    env,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    ZMQ_MESSAGE_DELIMITER,
     macros::internal_get_cdktr_setting,
-    zmq_helpers::{format_zmq_msg_str, get_server_tcp_uri},
+    zmq_helpers::{decode_zmq_msg_str, format_zmq_msg_str, get_server_tcp_uri},
 };
 use log::warn;
 pub mod data_structures;
 
 /// helper function to convert a SOH delimited string
-/// into a vecdeque of string tokens. No escape characters
-/// are used for SOH delimited strings so any messages containing
-/// SOH as values will be invalid
+/// into a vecdeque of string tokens, unescaping any SOH in the
+/// values. Messages in the legacy pipe-delimited format are also
+/// split while `CDKTR_ACCEPT_LEGACY_DELIMITER` is set
 pub fn arg_str_to_vecd(s: &String) -> VecDeque<String> {
-    s.split(ZMQ_MESSAGE_DELIMITER as char)
-        .map(|s| s.to_string())
-        .collect::<VecDeque<String>>()
+    let accept_legacy =
+        internal_get_cdktr_setting!(CDKTR_ACCEPT_LEGACY_DELIMITER).to_lowercase() == "true";
+    decode_zmq_msg_str(s, accept_legacy)
 }
 
 /// similar helper function to arg_str_to_vecd to do the inverse and
-/// encode a series of string arguments as a pipe-delimited string
-/// adding escape \ where necessary
+/// encode a series of string arguments as a SOH delimited string
+/// escaping any SOH in the values
 pub fn vecd_to_arg_str(vecd: &VecDeque<String>) -> String {
     format_zmq_msg_str(vecd.iter().map(|v| v.as_str()).collect::<Vec<&str>>())
 }
@@ -47,9 +50,64 @@ pub fn get_default_zmq_timeout() -> Duration {
     Duration::from_millis(internal_get_cdktr_setting!(CDKTR_DEFAULT_ZMQ_TIMEOUT_MS, usize) as u64)
 }
 
+pub fn get_connect_timeout() -> Duration {
+    Duration::from_millis(internal_get_cdktr_setting!(CDKTR_CONNECT_TIMEOUT_MS, usize) as u64)
+}
+
+pub fn get_request_timeout() -> Duration {
+    Duration::from_millis(internal_get_cdktr_setting!(CDKTR_REQUEST_TIMEOUT_MS, usize) as u64)
+}
+
+/// Minimum time between warnings about malformed messages received on a socket
+pub const MALFORMED_MESSAGE_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Limits how often a recurring warning is logged so that one misbehaving peer, such as
+/// another application sending garbage to a cdktr port, can't flood the logs. Warnings in
+/// between are counted and the count is included in the next warning that is logged
+#[derive(Debug)]
+pub struct LogRateLimiter {
+    interval: Duration,
+    last_logged: Option<Instant>,
+    suppressed: usize,
+}
+
+impl LogRateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_logged: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Returns the number of warnings suppressed since the last one was logged if a
+    /// warning may be logged now, or None if this one should be suppressed
+    pub fn admit(&mut self) -> Option<usize> {
+        match self.last_logged {
+            Some(last) if last.elapsed() < self.interval => {
+                self.suppressed += 1;
+                None
+            }
+            _ => {
+                self.last_logged = Some(Instant::now());
+                Some(std::mem::take(&mut self.suppressed))
+            }
+        }
+    }
+
+    pub fn warn(&mut self, msg: &str) {
+        match self.admit() {
+            Some(0) => warn!("{}", msg),
+            Some(suppressed) => warn!("{} ({} similar warning(s) suppressed)", msg, suppressed),
+            None => (),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ZMQ_MESSAGE_DELIMITER;
 
     #[test]
     fn test_arg_to_vecd() {
@@ -119,6 +177,19 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_log_rate_limiter() {
+        let mut limiter = LogRateLimiter::new(Duration::from_secs(60));
+        assert_eq!(limiter.admit(), Some(0));
+        for _ in 0..5 {
+            assert_eq!(limiter.admit(), None);
+        }
+        // once the interval has passed the suppressed warnings are reported
+        limiter.last_logged = Some(Instant::now() - Duration::from_secs(61));
+        assert_eq!(limiter.admit(), Some(5));
+        assert_eq!(limiter.admit(), None);
+    }
+
     #[test]
     fn test_arg_to_vecd_escape_outer_single_quote() {
         let args = format!(
@@ -1,23 +1,74 @@
+use std::collections::VecDeque;
+use std::sync::{LazyLock, Mutex};
 use std::time::Duration;
 
 use crate::{
-    exceptions::{GenericError, ZMQParseError},
+    exceptions::GenericError,
     macros,
+    utils::{LogRateLimiter, get_connect_timeout},
 };
 use log::warn;
 use tokio::time::timeout;
 use zeromq::{
-    PubSocket, PullSocket, PushSocket, RepSocket, ReqSocket, Socket, SocketRecv, SocketSend,
-    SubSocket, ZmqMessage,
+    PubSocket, PullSocket, PushSocket, RepSocket, ReqSocket, RouterSocket, Socket, SocketRecv,
+    SocketSend, SubSocket, ZmqError, ZmqMessage,
 };
 
+/// Separates the arguments of a message. Arguments containing it, or the escape
+/// character, have it prefixed with `ZMQ_ESCAPE` so any value can be sent
 pub static ZMQ_MESSAGE_DELIMITER: u8 = b'\x01';
 
+/// Escapes a literal `ZMQ_MESSAGE_DELIMITER` or `ZMQ_ESCAPE` inside an argument
+pub static ZMQ_ESCAPE: u8 = b'\x10';
+
+/// Delimiter of the pipe-delimited format used before SOH, where `\|` and `\\` escape a
+/// literal pipe and backslash. Still accepted while `CDKTR_ACCEPT_LEGACY_DELIMITER` is set
+/// so that old clients keep working until they are upgraded
+pub static LEGACY_ZMQ_DELIMITER: char = '|';
+
+static LEGACY_FORMAT_WARNINGS: LazyLock<Mutex<LogRateLimiter>> =
+    LazyLock::new(|| Mutex::new(LogRateLimiter::new(Duration::from_secs(60))));
+
+fn port_in_use_error(endpoint_uri: &str) -> GenericError {
+    GenericError::RuntimeError(format!(
+        "Unable to bind to {endpoint_uri}: port already in use. Check whether another cdktr instance is already running on this port"
+    ))
+}
+
+/// Maps a failure to bind a socket to a GenericError, calling out the common case
+/// of the port already being taken by another process
+fn bind_error(endpoint_uri: &str, e: ZmqError) -> GenericError {
+    match e {
+        ZmqError::Network(io_err) if io_err.kind() == std::io::ErrorKind::AddrInUse => {
+            port_in_use_error(endpoint_uri)
+        }
+        e => e.into(),
+    }
+}
+
+/// Checks that nothing is already listening on the host and port so that a server
+/// can fail fast with a clear error before starting any of its other services
+pub fn check_port_available(host: &str, port: usize) -> Result<(), GenericError> {
+    let port = u16::try_from(port)
+        .map_err(|_| GenericError::RuntimeError(format!("Invalid port number: {port}")))?;
+    match std::net::TcpListener::bind((host, port)) {
+        Ok(_listener) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            Err(port_in_use_error(&get_server_tcp_uri(host, port as usize)))
+        }
+        Err(e) => Err(GenericError::RuntimeError(format!(
+            "Unable to bind to {}: {}",
+            get_server_tcp_uri(host, port as usize),
+            e
+        ))),
+    }
+}
+
 pub async fn get_zmq_req(endpoint_uri: &str) -> Result<ReqSocket, GenericError> {
     let mut req = ReqSocket::new();
     req.connect(endpoint_uri)
         .await
-        .map_err(|e| GenericError::ZMQParseError(ZMQParseError::ParseError(e.to_string())))?;
+        .map_err(GenericError::from)?;
     Ok(req)
 }
 
@@ -25,16 +76,38 @@ pub async fn get_zmq_rep(endpoint_uri: &str) -> Result<RepSocket, GenericError>
     let mut rep = RepSocket::new();
     rep.bind(endpoint_uri)
         .await
-        .map_err(|e| GenericError::ZMQParseError(ZMQParseError::ParseError(e.to_string())))?;
+        .map_err(|e| bind_error(endpoint_uri, e))?;
     Ok(rep)
 }
 
+pub async fn get_zmq_router(endpoint_uri: &str) -> Result<RouterSocket, GenericError> {
+    let mut router = RouterSocket::new();
+    router
+        .bind(endpoint_uri)
+        .await
+        .map_err(|e| bind_error(endpoint_uri, e))?;
+    Ok(router)
+}
+
+/// Splits a message received on a ROUTER socket from a REQ client into its routing
+/// envelope (peer identity and empty delimiter frame) and the request body. The
+/// envelope must be prepended to the reply so the ROUTER can route it back
+pub fn split_router_envelope(mut zmq_msg: ZmqMessage) -> (ZmqMessage, ZmqMessage) {
+    let body_start = zmq_msg
+        .iter()
+        .position(|frame| frame.is_empty())
+        .map(|i| i + 1)
+        .unwrap_or(1);
+    let body = zmq_msg.split_off(body_start);
+    (zmq_msg, body)
+}
+
 pub async fn get_zmq_pub(endpoint_uri: &str) -> Result<PubSocket, GenericError> {
     let mut pub_socket = PubSocket::new();
     pub_socket
         .bind(endpoint_uri)
         .await
-        .map_err(|e| GenericError::ZMQParseError(ZMQParseError::ParseError(e.to_string())))?;
+        .map_err(|e| bind_error(endpoint_uri, e))?;
     Ok(pub_socket)
 }
 
@@ -43,11 +116,11 @@ pub async fn get_zmq_sub(endpoint_uri: &str, topic: &str) -> Result<SubSocket, G
     sub_socket
         .connect(endpoint_uri)
         .await
-        .map_err(|e| GenericError::ZMQParseError(ZMQParseError::ParseError(e.to_string())))?;
+        .map_err(GenericError::from)?;
     sub_socket
         .subscribe(topic)
         .await
-        .map_err(|e| GenericError::ZMQParseError(ZMQParseError::ParseError(e.to_string())))?;
+        .map_err(GenericError::from)?;
     Ok(sub_socket)
 }
 
@@ -56,7 +129,7 @@ pub async fn get_zmq_pull(endpoint_uri: &str) -> Result<PullSocket, GenericError
     pull_socket
         .bind(endpoint_uri)
         .await
-        .map_err(|e| GenericError::ZMQParseError(ZMQParseError::ParseError(e.to_string())))?;
+        .map_err(|e| bind_error(endpoint_uri, e))?;
     Ok(pull_socket)
 }
 
@@ -67,7 +140,7 @@ pub async fn get_zmq_push(endpoint_uri: &str) -> Result<PushSocket, GenericError
         push_socket
             .connect(endpoint_uri)
             .await
-            .map_err(|e| GenericError::ZMQParseError(ZMQParseError::ParseError(e.to_string())))?;
+            .map_err(GenericError::from)?;
         Ok(push_socket)
     })
     .await
@@ -83,29 +156,39 @@ pub fn get_server_tcp_uri(host: &str, port: usize) -> String {
 /// so this function spawns the recv in a separate coroutine and
 /// the calling process waits on a responds from the join handle. Given a certain
 /// duration if no response is received it kills the spawned coroutine and
-/// returns an error
+/// returns an error. Connecting is limited to `CDKTR_CONNECT_TIMEOUT_MS` on top of that
 pub async fn send_recv_with_timeout(
     tcp_uri: String,
     zmq_msg: ZmqMessage,
     duration: Duration,
 ) -> Result<ZmqMessage, GenericError> {
+    send_recv_with_timeouts(tcp_uri, zmq_msg, get_connect_timeout(), duration).await
+}
+
+/// Same as `send_recv_with_timeout` with the connect timeout given explicitly. Connecting
+/// to a port nothing is listening on doesn't error but keeps retrying, so without its own
+/// timeout a server that is down would only be noticed once the whole request times out
+pub async fn send_recv_with_timeouts(
+    tcp_uri: String,
+    zmq_msg: ZmqMessage,
+    connect_timeout: Duration,
+    duration: Duration,
+) -> Result<ZmqMessage, GenericError> {
+    let mut req = timeout(connect_timeout, get_zmq_req(&tcp_uri))
+        .await
+        .map_err(|_e| GenericError::ZMQTimeoutError)??;
     // spawn the timeout coroutine
     let join_res = tokio::spawn(timeout(duration, async move {
-        let mut req = get_zmq_req(&tcp_uri).await?;
         let send_res = req.send(zmq_msg).await;
         match send_res {
             Ok(_) => {
                 let recv_res = req.recv().await;
                 match recv_res {
                     Ok(zmq_msg) => Ok(zmq_msg),
-                    Err(e) => Err(GenericError::ZMQParseError(ZMQParseError::ParseError(
-                        e.to_string(),
-                    ))),
+                    Err(e) => Err(e.into()),
                 }
             }
-            Err(e) => Err(GenericError::ZMQParseError(ZMQParseError::ParseError(
-                e.to_string(),
-            ))),
+            Err(e) => Err(e.into()),
         }
     }))
     .await;
@@ -115,9 +198,7 @@ pub async fn send_recv_with_timeout(
         Ok(time_r) => match time_r {
             Ok(zmq_r) => match zmq_r {
                 Ok(msg) => Ok(msg),
-                Err(e) => Err(GenericError::ZMQParseError(ZMQParseError::ParseError(
-                    format!("ZMQ failure: {}", e.to_string()),
-                ))),
+                Err(e) => Err(e),
             },
             Err(_e) => Err(GenericError::ZMQTimeoutError),
         },
@@ -134,9 +215,7 @@ pub async fn push_with_timeout(
     match push_res {
         Ok(r) => match r {
             Ok(()) => Ok(()),
-            Err(e) => Err(GenericError::ZMQParseError(ZMQParseError::ParseError(
-                e.to_string(),
-            ))),
+            Err(e) => Err(e.into()),
         },
         Err(_e) => Err(GenericError::ZMQTimeoutError),
     }
@@ -150,31 +229,76 @@ pub async fn sub_with_timeout(
     match push_res {
         Ok(r) => match r {
             Ok(zmq_msg) => Ok(zmq_msg),
-            Err(e) => Err(GenericError::ZMQParseError(ZMQParseError::ParseError(
-                e.to_string(),
-            ))),
+            Err(e) => Err(e.into()),
         },
         Err(_e) => Err(GenericError::ZMQTimeoutError),
     }
 }
 
-pub fn format_zmq_msg_str(args: Vec<&str>) -> String {
-    let mut zmq_str = String::new();
-    match args.len() {
-        0 => zmq_str,
-        1 => {
-            zmq_str.push_str(args[0]);
-            zmq_str
+/// Escapes any delimiter or escape characters in an argument so it can be sent as
+/// part of a message
+pub fn escape_zmq_arg(arg: &str) -> String {
+    let mut escaped = String::with_capacity(arg.len());
+    for c in arg.chars() {
+        if c == ZMQ_MESSAGE_DELIMITER as char || c == ZMQ_ESCAPE as char {
+            escaped.push(ZMQ_ESCAPE as char);
         }
-        _ => {
-            zmq_str.push_str(args[0]);
-            for arg in &args[1..] {
-                zmq_str.push(ZMQ_MESSAGE_DELIMITER as char);
-                zmq_str.push_str(arg);
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Joins arguments into a message, escaping them with `escape_zmq_arg`
+pub fn format_zmq_msg_str(args: Vec<&str>) -> String {
+    args.into_iter()
+        .map(escape_zmq_arg)
+        .collect::<Vec<String>>()
+        .join(&(ZMQ_MESSAGE_DELIMITER as char).to_string())
+}
+
+/// Whether a message looks like it was sent in the legacy pipe-delimited format,
+/// e.g. `REGISTERAGENT|8999|2`
+fn is_legacy_msg(s: &str) -> bool {
+    !s.contains(ZMQ_MESSAGE_DELIMITER as char)
+        && s.split_once(LEGACY_ZMQ_DELIMITER)
+            .is_some_and(|(msg_type, _)| {
+                !msg_type.is_empty() && msg_type.chars().all(|c| c.is_ascii_uppercase())
+            })
+}
+
+/// Splits a message into its arguments, the inverse of `format_zmq_msg_str`. Messages in
+/// the legacy pipe-delimited format are split on unescaped pipes when `accept_legacy`
+/// is set, and are otherwise treated as a single argument
+pub fn decode_zmq_msg_str(s: &str, accept_legacy: bool) -> VecDeque<String> {
+    let (delimiter, escape) = if accept_legacy && is_legacy_msg(s) {
+        LEGACY_FORMAT_WARNINGS
+            .lock()
+            .expect("legacy format warning lock poisoned")
+            .warn(
+                "Received a message in the deprecated pipe-delimited format. Upgrade the client \
+                 sending it as support will be removed in a future release",
+            );
+        (LEGACY_ZMQ_DELIMITER, '\\')
+    } else {
+        (ZMQ_MESSAGE_DELIMITER as char, ZMQ_ESCAPE as char)
+    };
+    let mut args = VecDeque::new();
+    let mut arg = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            // legacy messages only escape pipes and backslashes, so other backslashes
+            // such as in windows paths are kept as they are
+            Some(&next) if c == escape && (next == delimiter || next == escape) => {
+                arg.push(next);
+                chars.next();
             }
-            zmq_str
+            _ if c == delimiter => args.push_back(std::mem::take(&mut arg)),
+            _ => arg.push(c),
         }
     }
+    args.push_back(arg);
+    args
 }
 
 #[cfg(test)]
@@ -204,6 +328,20 @@ mod tests {
         get_req_timeout(host, port, Duration::from_millis(500)).await
     }
 
+    #[tokio::test]
+    async fn test_bind_port_in_use() {
+        let uri = get_server_tcp_uri("0.0.0.0", 32145);
+        let _router = get_zmq_router(&uri).await.unwrap();
+        match get_zmq_router(&uri).await {
+            Err(GenericError::RuntimeError(msg)) => assert!(msg.contains("port already in use")),
+            other => panic!("Expected port in use error, got {:?}", other.err()),
+        }
+        assert!(matches!(
+            check_port_available("0.0.0.0", 32145),
+            Err(GenericError::RuntimeError(_))
+        ));
+    }
+
     #[tokio::test]
     async fn test_get_req_ok() {
         let host = String::from("0.0.0.0");
@@ -241,6 +379,21 @@ mod tests {
         )
     }
 
+    #[tokio::test]
+    async fn test_send_recv_to_closed_port_fails_at_connect_timeout() {
+        let endpoint = get_server_tcp_uri("127.0.0.1", 9993);
+        let started = std::time::Instant::now();
+        let res = send_recv_with_timeouts(
+            endpoint,
+            ZmqMessage::from("hello"),
+            Duration::from_millis(200),
+            Duration::from_secs(30),
+        )
+        .await;
+        assert!(matches!(res, Err(GenericError::ZMQTimeoutError)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
     #[tokio::test]
     async fn test_send_recv_with_timeout_times_out() {
         let host = String::from("0.0.0.0");
@@ -297,6 +450,26 @@ mod tests {
         assert!(get_zmq_push(&endpoint).await.is_err())
     }
 
+    #[tokio::test]
+    async fn test_router_replies_to_req() {
+        let host = String::from("0.0.0.0");
+        let port = 9994;
+        let endpoint = get_server_tcp_uri(&host, port);
+        let mut router = get_zmq_router(&endpoint).await.unwrap();
+        tokio::spawn(async move {
+            let (envelope, body) = split_router_envelope(router.recv().await.unwrap());
+            assert_eq!(String::try_from(body).unwrap(), "hello");
+            let mut reply = ZmqMessage::from("OK");
+            reply.prepend(&envelope);
+            router.send(reply).await.unwrap()
+        });
+        let resp =
+            send_recv_with_timeout(endpoint, ZmqMessage::from("hello"), Duration::from_secs(1))
+                .await
+                .unwrap();
+        assert_eq!(String::try_from(resp).unwrap(), "OK")
+    }
+
     #[test]
     fn test_format_zmq_msg() {
         assert_eq!(
@@ -304,4 +477,51 @@ mod tests {
             String::from_utf8(b"abc1\x01de1f".to_vec()).unwrap()
         )
     }
+
+    #[test]
+    fn test_decode_legacy_format() {
+        assert_eq!(
+            decode_zmq_msg_str("REGISTERAGENT|8999|2", true),
+            vec!["REGISTERAGENT", "8999", "2"]
+        );
+        assert_eq!(
+            decode_zmq_msg_str(r"ANNOTATERUN|123|note|a\|b \\ c:\tmp", true),
+            vec!["ANNOTATERUN", "123", "note", r"a|b \ c:\tmp"]
+        );
+        // once legacy support is turned off the pipes are part of the argument
+        assert_eq!(
+            decode_zmq_msg_str("REGISTERAGENT|8999|2", false),
+            vec!["REGISTERAGENT|8999|2"]
+        );
+    }
+
+    #[test]
+    fn test_decode_canonical_format() {
+        assert_eq!(
+            decode_zmq_msg_str("REGISTERAGENT\x018999\x012", true),
+            vec!["REGISTERAGENT", "8999", "2"]
+        );
+        // pipes in canonical messages are never delimiters
+        assert_eq!(
+            decode_zmq_msg_str("ANNOTATERUN\x01123\x01cmd\x01a|b", true),
+            vec!["ANNOTATERUN", "123", "cmd", "a|b"]
+        );
+        assert_eq!(decode_zmq_msg_str("PING", true), vec!["PING"]);
+        assert_eq!(decode_zmq_msg_str("Not|LEGACY", true), vec!["Not|LEGACY"]);
+    }
+
+    #[test]
+    fn test_canonical_format_round_trip() {
+        let args = vec![
+            "ANNOTATERUN",
+            "",
+            "has\x01delimiter",
+            "has\x10escape\x10\x01",
+            r"back\slash|pipe",
+        ];
+        let msg = format_zmq_msg_str(args.clone());
+        assert_eq!(msg.matches(ZMQ_MESSAGE_DELIMITER as char).count(), 6);
+        assert_eq!(decode_zmq_msg_str(&msg, true), args);
+        assert_eq!(decode_zmq_msg_str(&msg, false), args);
+    }
 }
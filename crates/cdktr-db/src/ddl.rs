@@ -1,4 +1,4 @@
-pub static DDL: [&'static str; 5] = [
+pub static DDL: [&'static str; 17] = [
     // TYPES
 
     // should match rust enum RunStatus
@@ -9,7 +9,8 @@ pub static DDL: [&'static str; 5] = [
         'WAITING',
         'COMPLETED',
         'FAILED',
-        'CRASHED'
+        'CRASHED',
+        'SKIPPED'
     )
     ",
     // type of run
@@ -49,4 +50,102 @@ pub static DDL: [&'static str; 5] = [
         status RunStatus,
         timestamp_ms BIGINT,
     );",
+    // Create the task exit code table - insert only. Only populated
+    // for tasks whose process exited with a code
+    "create table IF NOT EXISTS task_exit_codes
+    (
+        task_instance_id TEXT,
+        workflow_instance_id TEXT,
+        exit_code INTEGER,
+    );",
+    // Create the task output truncations table - insert only. Only populated
+    // for tasks whose output went over the agent's output limit
+    "create table IF NOT EXISTS task_output_truncations
+    (
+        task_instance_id TEXT,
+        workflow_instance_id TEXT,
+    );",
+    // Create the workflow SLA results table - insert only. Only populated
+    // for finished runs of workflows that declare an SLA
+    "create table IF NOT EXISTS workflow_sla_results
+    (
+        workflow_instance_id TEXT,
+        sla_ms BIGINT,
+        breached BOOLEAN,
+    );",
+    // Create the workflow run attempts table - insert only. Only populated
+    // for runs that are retries of a failed run
+    "create table IF NOT EXISTS workflow_run_attempts
+    (
+        workflow_instance_id TEXT,
+        retry_of TEXT,
+        attempt INTEGER,
+    );",
+    // Create the task progress table - insert only. Only populated
+    // for tasks that report their progress
+    "create table IF NOT EXISTS task_progress
+    (
+        task_instance_id TEXT,
+        percent UTINYINT,
+        message TEXT,
+        timestamp_ms BIGINT,
+    );",
+    // files matching the artifact globs of finished tasks - insert only. One row
+    // per file, only populated for tasks with artifact globs
+    "create table IF NOT EXISTS task_artifacts
+    (
+        task_instance_id TEXT,
+        path TEXT,
+        size_bytes UBIGINT,
+    );",
+    // Create the workflow progress table - insert only. One row each time
+    // the agent running a workflow reports more of its tasks finished
+    "create table IF NOT EXISTS workflow_progress
+    (
+        workflow_instance_id TEXT,
+        tasks_completed UBIGINT,
+        tasks_total UBIGINT,
+        timestamp_ms BIGINT,
+    );",
+    // named outputs of successful workflow runs, read back by the next run
+    "create table IF NOT EXISTS workflow_outputs
+    (
+        workflow_id TEXT,
+        workflow_instance_id TEXT,
+        name TEXT,
+        value TEXT,
+        timestamp_ms BIGINT,
+    );",
+    // workflow runs waiting on the principal queue, restored when the principal restarts
+    "create table IF NOT EXISTS queued_workflows
+    (
+        workflow_instance_id TEXT,
+        workflow TEXT,
+        queued_at_ms BIGINT,
+    );",
+    // notes added to workflow runs by operators, the latest value of each key wins
+    "create table IF NOT EXISTS run_annotations
+    (
+        workflow_instance_id TEXT,
+        key TEXT,
+        value TEXT,
+        timestamp_ms BIGINT,
+    );",
+    // the workflow each run was queued with, params included, so the run can be replayed
+    "create table IF NOT EXISTS workflow_run_snapshots
+    (
+        workflow_instance_id TEXT,
+        workflow TEXT,
+        timestamp_ms BIGINT,
+    );",
+    // administrative actions taken on the principal and who took them - insert only
+    "create table IF NOT EXISTS audit_log
+    (
+        timestamp_ms UBIGINT,
+        actor TEXT,
+        action TEXT,
+        target TEXT,
+        result TEXT,
+        client TEXT,
+    );",
 ];
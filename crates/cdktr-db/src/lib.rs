@@ -1,18 +1,27 @@
 use cdktr_core::exceptions::GenericError;
 use duckdb::{Connection, Params, arrow};
-use log::warn;
+use log::{error, info, warn};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{Mutex, MutexGuard};
 
 mod ddl;
 
+/// Number of times opening a database locked by another process is retried before giving up
+const OPEN_LOCKED_ATTEMPTS: u32 = 5;
+const OPEN_LOCKED_RETRY_DELAY: Duration = Duration::from_millis(500);
+
 pub trait DBRecordBatch<T> {
     fn from_record_batch(batch: arrow::array::RecordBatch) -> Result<Vec<T>, GenericError>;
     fn to_record_batch(&self) -> Result<arrow::array::RecordBatch, GenericError>;
 }
 
 /// Proc macro to conveniently provide the implementation for converting
-/// a given struct to a record batch for easy loading into the database
+/// a given struct to a record batch for easy loading into the database.
+/// Supported column types and the field types they map to are `Utf8` (String),
+/// `UInt64` (u64), `Int64` (i64), `Float64` (f64), `Boolean` (bool) and `Timestamp`
+/// (i64 milliseconds since the epoch)
 #[macro_export]
 macro_rules! impl_dbrecordbatch {
     (
@@ -22,23 +31,51 @@ macro_rules! impl_dbrecordbatch {
     ) => {
         macro_rules! builder_path {
             (UInt64) => { ::duckdb::arrow::array::UInt64Builder };
+            (Int64) => { ::duckdb::arrow::array::Int64Builder };
+            (Float64) => { ::duckdb::arrow::array::Float64Builder };
+            (Boolean) => { ::duckdb::arrow::array::BooleanBuilder };
+            (Timestamp) => { ::duckdb::arrow::array::TimestampMillisecondBuilder };
             (Utf8) => { ::duckdb::arrow::array::StringBuilder };
-            // add more types
         }
 
         macro_rules! array_builder {
             (UInt64) => { ::duckdb::arrow::array::UInt64Array };
+            (Int64) => { ::duckdb::arrow::array::Int64Array };
+            (Float64) => { ::duckdb::arrow::array::Float64Array };
+            (Boolean) => { ::duckdb::arrow::array::BooleanArray };
+            (Timestamp) => { ::duckdb::arrow::array::TimestampMillisecondArray };
             (Utf8) => { ::duckdb::arrow::array::StringArray };
-            // add more types
+        }
+
+        macro_rules! data_type {
+            (Timestamp) => {
+                ::duckdb::arrow::datatypes::DataType::Timestamp(
+                    ::duckdb::arrow::datatypes::TimeUnit::Millisecond,
+                    None,
+                )
+            };
+            ($other:ident) => { ::duckdb::arrow::datatypes::DataType::$other };
         }
         impl ::cdktr_db::DBRecordBatch<$struct> for $vec {
             fn from_record_batch(batch: ::duckdb::arrow::array::RecordBatch) -> Result<$vec, ::cdktr_core::exceptions::GenericError> {
                 $(
                     let $field = batch
-                        .column(batch.schema().index_of(stringify!($field)).unwrap())
-                        .as_any()
-                        .downcast_ref::<array_builder!($arrow_ty)>()
-                        .unwrap();
+                        .schema()
+                        .index_of(stringify!($field))
+                        .ok()
+                        .and_then(|i| {
+                            batch
+                                .column(i)
+                                .as_any()
+                                .downcast_ref::<array_builder!($arrow_ty)>()
+                        })
+                        .ok_or_else(|| {
+                            ::cdktr_core::exceptions::GenericError::DBError(format!(
+                                "Record batch has no {} column of type {}",
+                                stringify!($field),
+                                stringify!($arrow_ty)
+                            ))
+                        })?;
                 )*
 
                 Ok((0..batch.num_rows())
@@ -52,7 +89,7 @@ macro_rules! impl_dbrecordbatch {
 
             fn to_record_batch(&self) -> Result<::duckdb::arrow::array::RecordBatch, ::cdktr_core::exceptions::GenericError> {
                 let schema = ::std::sync::Arc::new(::duckdb::arrow::datatypes::Schema::new(vec![
-                    $(::duckdb::arrow::datatypes::Field::new(stringify!($field), ::duckdb::arrow::datatypes::DataType::$arrow_ty, false)),*
+                    $(::duckdb::arrow::datatypes::Field::new(stringify!($field), data_type!($arrow_ty), false)),*
                 ]));
 
                 $(
@@ -69,12 +106,12 @@ macro_rules! impl_dbrecordbatch {
                     $(::std::sync::Arc::new($field.finish()) as _),*
                 ];
 
-                Ok(::duckdb::arrow::array::RecordBatch::try_new(schema, arrays).map_err(|e| {
+                ::duckdb::arrow::array::RecordBatch::try_new(schema, arrays).map_err(|e| {
                     ::cdktr_core::exceptions::GenericError::DBError(format!(
                         "Failed to create arrow record batch for db insertion. Orig error: {}",
                         e
                     ))
-                })?)
+                })
             }
         }
     };
@@ -89,14 +126,15 @@ pub struct DBClient {
 }
 
 impl DBClient {
+    /// Opens the database at the path, or an in-memory database without one. A database
+    /// locked by another process is retried for a short while before giving up. A file
+    /// that isn't a readable database is moved aside and replaced with a new, empty one
     pub fn new(app_db_path: Option<&str>) -> Result<Self, GenericError> {
-        let inner_cnxn = if let Some(path) = app_db_path {
-            Connection::open(path).expect(&format!(
-                "No connectable database can be found at: {}",
-                path
-            ))
-        } else {
-            Connection::open_in_memory().expect("Failed to open in-memory duckdb connection")
+        let inner_cnxn = match app_db_path {
+            Some(path) => open_file(Path::new(path))?,
+            None => Connection::open_in_memory().map_err(|e| {
+                GenericError::DBError(format!("Failed to open in-memory database: {}", e))
+            })?,
         };
         // idempotently run any new ddl
         gen_ddl(&inner_cnxn)?;
@@ -111,6 +149,27 @@ impl DBClient {
             .map_err(|e| GenericError::DBError(e.to_string()))
     }
 
+    /// Runs a SELECT and reads its rows with the `DBRecordBatch` conversion of `T`. Columns
+    /// are matched to fields by name, so the query has to select them with the names and
+    /// types the conversion expects
+    pub async fn query_as<T, P: Params>(&self, q: &str, params: P) -> Result<Vec<T>, GenericError>
+    where
+        Vec<T>: DBRecordBatch<T>,
+    {
+        let lock = self.cnxn.lock().await;
+        let mut stmt = lock
+            .prepare(q)
+            .map_err(|e| GenericError::DBQueryStatementError(e.to_string()))?;
+        let batches = stmt
+            .query_arrow(params)
+            .map_err(|e| GenericError::DBError(e.to_string()))?;
+        let mut rows = Vec::new();
+        for batch in batches {
+            rows.extend(Vec::<T>::from_record_batch(batch)?);
+        }
+        Ok(rows)
+    }
+
     // Loads a batch of records into the database. Returns the input batch as the Err variant for additional
     // error processing outside of the function
     pub async fn batch_load<T, V: DBRecordBatch<T> + Clone>(
@@ -119,15 +178,22 @@ impl DBClient {
         batch: V,
     ) -> Result<(), V> {
         let lock = self.cnxn.lock().await;
-        let mut app: duckdb::Appender<'_> = lock
-            .appender(table_name)
-            .expect("Unable to create appender to db - todo: handle");
+        let mut app: duckdb::Appender<'_> = match lock.appender(table_name) {
+            Ok(app) => app,
+            Err(e) => {
+                warn!(
+                    "Unable to create appender to table {} - aborting insert. Orig error: {}",
+                    table_name, e
+                );
+                return Err(batch);
+            }
+        };
         let rb = match batch.to_record_batch() {
             Ok(bt) => bt,
             Err(e) => {
                 warn!(
                     "Could not create record batch from records - aborting insert. Orig error: {}",
-                    e.to_string()
+                    e
                 );
                 return Err(batch);
             }
@@ -137,7 +203,7 @@ impl DBClient {
             Err(e) => {
                 warn!(
                     "Failed to insert record batch into database. Orig error: {}",
-                    e.to_string()
+                    e
                 );
                 Err(batch)
             }
@@ -149,12 +215,135 @@ impl DBClient {
     }
 }
 
+/// Opens a database file, recovering from the file being locked or corrupt where possible
+fn open_file(path: &Path) -> Result<Connection, GenericError> {
+    let mut attempt = 1;
+    loop {
+        let e = match Connection::open(path) {
+            Ok(cnxn) => return Ok(cnxn),
+            Err(e) => e.to_string(),
+        };
+        if is_locked(&e) && attempt < OPEN_LOCKED_ATTEMPTS {
+            warn!(
+                "Database {} is locked, retrying ({}/{}): {}",
+                path.display(),
+                attempt,
+                OPEN_LOCKED_ATTEMPTS,
+                e
+            );
+            attempt += 1;
+            std::thread::sleep(OPEN_LOCKED_RETRY_DELAY);
+        } else if is_locked(&e) {
+            return Err(GenericError::DBError(format!(
+                "Database {} is locked by another process - check no other principal is using it: {}",
+                path.display(),
+                e
+            )));
+        } else if is_corrupt(&e) {
+            let moved_to = move_aside(path)?;
+            error!(
+                "Database {} is corrupt and has been moved to {}. Starting with an empty database - run history from before now is only in the moved file. Orig error: {}",
+                path.display(),
+                moved_to.display(),
+                e
+            );
+            return Connection::open(path).map_err(|e| {
+                GenericError::DBError(format!(
+                    "Failed to create a new database at {}: {}",
+                    path.display(),
+                    e
+                ))
+            });
+        } else {
+            return Err(GenericError::DBError(format!(
+                "Failed to open database at {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    }
+}
+
+fn is_locked(e: &str) -> bool {
+    e.contains("Could not set lock on file") || e.contains("Conflicting lock")
+}
+
+fn is_corrupt(e: &str) -> bool {
+    e.contains("not a valid DuckDB database file")
+        || e.contains("Corrupt database file")
+        || e.contains("checksum")
+}
+
+/// Renames a database file, along with its write-ahead log, so a new database can be
+/// created in its place. Returns where the database was moved to
+fn move_aside(path: &Path) -> Result<PathBuf, GenericError> {
+    let suffix = format!(
+        "corrupt-{}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    );
+    let moved_to = PathBuf::from(format!("{}.{}", path.display(), suffix));
+    std::fs::rename(path, &moved_to).map_err(|e| {
+        GenericError::DBError(format!(
+            "Database {} is corrupt and could not be moved aside: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let wal = PathBuf::from(format!("{}.wal", path.display()));
+    if wal.exists()
+        && let Err(e) = std::fs::rename(&wal, format!("{}.{}", wal.display(), suffix))
+    {
+        warn!(
+            "Failed to move aside write-ahead log {}: {}",
+            wal.display(),
+            e
+        );
+    }
+    Ok(moved_to)
+}
+
 fn gen_ddl<'a>(cnxn: &'a Connection) -> Result<(), GenericError> {
     for ddl_statement in ddl::DDL {
         cnxn.execute(ddl_statement, [])
             .map_err(|e| GenericError::DBQueryStatementError(e.to_string()))?;
     }
-    Ok(())
+    migrate_run_status_type(cnxn)
+}
+
+/// `create type if not exists` leaves the RunStatus type of existing databases as it was,
+/// so statuses added since have to be migrated in. DuckDB can't add values to an enum
+/// so the status columns are converted to text while the type is recreated
+fn migrate_run_status_type(cnxn: &Connection) -> Result<(), GenericError> {
+    let up_to_date = cnxn.query_row(
+        "SELECT list_contains(enum_range(NULL::RunStatus), 'SKIPPED')",
+        [],
+        |row| row.get::<_, bool>(0),
+    );
+    match up_to_date {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            info!("Migrating RunStatus type of the database to add new statuses");
+            cnxn.execute_batch(&format!(
+                "BEGIN TRANSACTION;
+                ALTER TABLE workflow_run_status ALTER COLUMN status TYPE VARCHAR;
+                ALTER TABLE task_run_status ALTER COLUMN status TYPE VARCHAR;
+                DROP TYPE RunStatus;
+                {};
+                ALTER TABLE workflow_run_status ALTER COLUMN status TYPE RunStatus;
+                ALTER TABLE task_run_status ALTER COLUMN status TYPE RunStatus;
+                COMMIT;",
+                ddl::DDL[0]
+            ))
+            .map_err(|e| GenericError::DBQueryStatementError(e.to_string()))
+        }
+        Err(e) => {
+            warn!("Unable to check the RunStatus type of the database: {}", e);
+            Ok(())
+        }
+    }
 }
 
 #[cfg(test)]
@@ -166,4 +355,91 @@ mod tests {
         let cli = DBClient::new(None).unwrap();
         assert!(cli.execute("select 1", params![]).await.is_ok());
     }
+
+    fn temp_db_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cdktr-db-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("app.db")
+    }
+
+    #[test]
+    fn test_open_failure_is_an_error() {
+        let path = temp_db_path("open-failure").join("missing").join("app.db");
+        let res = DBClient::new(Some(path.to_str().unwrap()));
+        assert!(matches!(res, Err(GenericError::DBError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_db_moved_aside() {
+        let path = temp_db_path("corrupt");
+        std::fs::write(&path, b"this is not a duckdb database file").unwrap();
+        let cli = DBClient::new(Some(path.to_str().unwrap())).unwrap();
+        assert!(cli.execute("select 1", params![]).await.is_ok());
+        let moved: Vec<_> = std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("app.db.corrupt-"))
+            .collect();
+        assert_eq!(moved.len(), 1);
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_appender_failure_returns_batch() {
+        #[derive(Clone)]
+        struct Row {
+            value: String,
+        }
+        impl DBRecordBatch<Row> for Vec<Row> {
+            fn from_record_batch(
+                _batch: arrow::array::RecordBatch,
+            ) -> Result<Vec<Row>, GenericError> {
+                Ok(Vec::new())
+            }
+            fn to_record_batch(&self) -> Result<arrow::array::RecordBatch, GenericError> {
+                let schema = Arc::new(arrow::datatypes::Schema::new(vec![
+                    arrow::datatypes::Field::new("value", arrow::datatypes::DataType::Utf8, false),
+                ]));
+                let values = arrow::array::StringArray::from(
+                    self.iter().map(|r| r.value.clone()).collect::<Vec<_>>(),
+                );
+                arrow::array::RecordBatch::try_new(schema, vec![Arc::new(values)])
+                    .map_err(|e| GenericError::DBError(e.to_string()))
+            }
+        }
+        let cli = DBClient::new(None).unwrap();
+        let batch = vec![Row {
+            value: "a".to_string(),
+        }];
+        let res = cli.batch_load("missing_table", batch).await;
+        assert_eq!(res.unwrap_err()[0].value, "a");
+    }
+
+    #[test]
+    fn test_migrate_run_status_type() {
+        let cnxn = Connection::open_in_memory().unwrap();
+        cnxn.execute_batch(
+            "create type RunStatus as ENUM ('PENDING', 'RUNNING', 'WAITING', 'COMPLETED', 'FAILED', 'CRASHED');
+            create table task_run_status (task_id TEXT, task_instance_id TEXT, workflow_instance_id TEXT, status RunStatus, timestamp_ms BIGINT);
+            insert into task_run_status values ('t', 't-ins', 'wf-ins', 'COMPLETED', 1);",
+        )
+        .unwrap();
+        gen_ddl(&cnxn).unwrap();
+        cnxn.execute(
+            "insert into task_run_status values ('t2', 't2-ins', 'wf-ins', 'SKIPPED', 2)",
+            [],
+        )
+        .unwrap();
+        let statuses: String = cnxn
+            .query_row(
+                "select string_agg(CAST(status AS VARCHAR), ',' ORDER BY timestamp_ms) from task_run_status",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(statuses, "COMPLETED,SKIPPED");
+        // running the ddl again is a no-op
+        gen_ddl(&cnxn).unwrap();
+    }
 }
@@ -0,0 +1,145 @@
+//! Structs using each column type `impl_dbrecordbatch!` supports come back unchanged
+//! from a round trip through a record batch, and can be read back from a query
+
+use cdktr_db::{DBClient, DBRecordBatch, impl_dbrecordbatch};
+
+#[derive(Clone, Debug, PartialEq)]
+struct TaskRun {
+    task_id: String,
+    attempt: u64,
+    exit_code: i64,
+    duration_s: f64,
+    cached: bool,
+    started_at_ms: i64,
+}
+impl_dbrecordbatch!(
+    TaskRun, Vec<TaskRun>, {
+        task_id => Utf8,
+        attempt => UInt64,
+        exit_code => Int64,
+        duration_s => Float64,
+        cached => Boolean,
+        started_at_ms => Timestamp,
+    }
+);
+
+#[derive(Clone, Debug, PartialEq)]
+struct TaskName {
+    task_id: i64,
+}
+impl_dbrecordbatch!(
+    TaskName, Vec<TaskName>, {
+        task_id => Int64,
+    }
+);
+
+#[test]
+fn test_record_batch_round_trip() {
+    let runs = vec![
+        TaskRun {
+            task_id: "extract".to_string(),
+            attempt: 1,
+            exit_code: 0,
+            duration_s: 12.5,
+            cached: false,
+            started_at_ms: 1_737_374_400_000,
+        },
+        TaskRun {
+            task_id: "load".to_string(),
+            attempt: 2,
+            exit_code: -9,
+            duration_s: 0.0,
+            cached: true,
+            started_at_ms: -1_000,
+        },
+    ];
+    let batch = runs.to_record_batch().unwrap();
+    assert_eq!(batch.num_rows(), 2);
+    assert_eq!(
+        batch
+            .schema()
+            .field_with_name("started_at_ms")
+            .unwrap()
+            .data_type(),
+        &duckdb::arrow::datatypes::DataType::Timestamp(
+            duckdb::arrow::datatypes::TimeUnit::Millisecond,
+            None
+        )
+    );
+    assert_eq!(Vec::<TaskRun>::from_record_batch(batch).unwrap(), runs);
+}
+
+#[test]
+fn test_record_batch_column_of_wrong_type_is_an_error() {
+    let runs = vec![TaskRun {
+        task_id: "extract".to_string(),
+        attempt: 1,
+        exit_code: 0,
+        duration_s: 1.0,
+        cached: false,
+        started_at_ms: 0,
+    }];
+    let batch = runs.to_record_batch().unwrap();
+    let err = Vec::<TaskName>::from_record_batch(batch).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "DBError: Record batch has no task_id column of type Int64"
+    );
+}
+
+#[tokio::test]
+async fn test_query_as_reads_typed_rows() {
+    let client = DBClient::new(None).unwrap();
+    client
+        .execute(
+            "CREATE TABLE task_runs (task_id TEXT, attempt UBIGINT, exit_code BIGINT, duration_s DOUBLE, cached BOOLEAN, started_at TIMESTAMP)",
+            [],
+        )
+        .await
+        .unwrap();
+    client
+        .execute(
+            "INSERT INTO task_runs VALUES ('extract', 1, 0, 12.5, false, make_timestamp(1737374400000000)), ('load', 2, -9, 0.0, true, make_timestamp(0))",
+            [],
+        )
+        .await
+        .unwrap();
+    let runs: Vec<TaskRun> = client
+        .query_as(
+            "SELECT task_id, attempt, exit_code, duration_s, cached, CAST(started_at AS TIMESTAMP_MS) AS started_at_ms FROM task_runs WHERE attempt >= ? ORDER BY task_id",
+            duckdb::params![1],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        runs,
+        vec![
+            TaskRun {
+                task_id: "extract".to_string(),
+                attempt: 1,
+                exit_code: 0,
+                duration_s: 12.5,
+                cached: false,
+                started_at_ms: 1_737_374_400_000,
+            },
+            TaskRun {
+                task_id: "load".to_string(),
+                attempt: 2,
+                exit_code: -9,
+                duration_s: 0.0,
+                cached: true,
+                started_at_ms: 0,
+            },
+        ]
+    );
+
+    // a column of the wrong type is reported rather than read as a default
+    let err = client
+        .query_as::<TaskName, _>("SELECT task_id FROM task_runs", [])
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("no task_id column of type Int64"),
+        "{err}"
+    );
+}
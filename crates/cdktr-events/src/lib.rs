@@ -3,14 +3,20 @@ use log::info;
 
 use crate::traits::EventListener;
 
+mod overlap;
 mod scheduler;
 mod traits;
 
-/// Spawns the Scheduler in a separate coroutine
-pub async fn start_scheduler() -> Result<(), GenericError> {
-    let mut scheduler = scheduler::Scheduler::new().await?;
+/// Spawns the Scheduler in a separate coroutine. The scheduler identifies itself to the
+/// principal with `scheduler_id` when taking the scheduler lease, so each scheduler
+/// running against a principal needs its own
+pub async fn start_scheduler(scheduler_id: String) -> Result<(), GenericError> {
+    let mut scheduler = scheduler::Scheduler::new(scheduler_id).await?;
     info!("Starting scheduler");
     scheduler.spawn_refresh_loop().await;
-    scheduler.start_listening().await?;
-    Ok(())
+    let lease_scheduler = scheduler.clone();
+    tokio::select! {
+        res = scheduler.start_listening() => res,
+        _ = lease_scheduler.lease_loop() => Ok(()),
+    }
 }
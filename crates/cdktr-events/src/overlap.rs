@@ -0,0 +1,165 @@
+use cdktr_api::models::{ClientResponseMessage, DispatchDiagnosis};
+use cdktr_api::{API, PrincipalAPI};
+use cdktr_core::models::{RunStatus, WorkflowInstanceId};
+use cdktr_workflow::OverlapPolicy;
+use log::warn;
+use std::collections::HashMap;
+
+/// What the scheduler does with a fire that has come due
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FireAction {
+    Fire,
+    Skip,
+    Defer,
+}
+
+/// The last run the scheduler fired of each workflow, so that a fire that comes due while
+/// it is still running can be handled as the `overlap_policy` of the workflow says
+#[derive(Debug, Default)]
+pub struct ScheduledRuns {
+    last_fired: HashMap<String, WorkflowInstanceId>,
+}
+
+impl ScheduledRuns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decides what to do with a fire of the workflow, asking `is_active` whether the run
+    /// it last fired is still queued or running. Only workflows that don't allow overlapping
+    /// runs are checked
+    pub async fn on_fire<F, Fut>(
+        &self,
+        workflow_id: &str,
+        policy: OverlapPolicy,
+        is_active: F,
+    ) -> FireAction
+    where
+        F: FnOnce(WorkflowInstanceId) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        if policy == OverlapPolicy::Allow {
+            return FireAction::Fire;
+        }
+        let running = match self.last_fired.get(workflow_id) {
+            Some(instance_id) => is_active(instance_id.clone()).await,
+            None => false,
+        };
+        if !running {
+            return FireAction::Fire;
+        }
+        match policy {
+            OverlapPolicy::Allow => FireAction::Fire,
+            OverlapPolicy::Skip => FireAction::Skip,
+            OverlapPolicy::Queue => FireAction::Defer,
+        }
+    }
+
+    /// Records the run a fire of the workflow queued
+    pub fn fired(&mut self, workflow_id: &str, instance_id: Option<WorkflowInstanceId>) {
+        match instance_id {
+            Some(instance_id) => {
+                self.last_fired.insert(workflow_id.to_string(), instance_id);
+            }
+            None => {
+                self.last_fired.remove(workflow_id);
+            }
+        }
+    }
+
+    /// Instance id of the last run fired of the workflow
+    pub fn last_fired(&self, workflow_id: &str) -> Option<&WorkflowInstanceId> {
+        self.last_fired.get(workflow_id)
+    }
+}
+
+/// Asks the principal whether a run is still on its queue or running. A run the principal
+/// has no record of, e.g. one flushed from the queue, doesn't count as running
+pub async fn run_is_active(instance_id: WorkflowInstanceId) -> bool {
+    match PrincipalAPI::DiagnoseDispatch(instance_id.clone())
+        .send()
+        .await
+    {
+        Ok(ClientResponseMessage::SuccessWithPayload(json)) => {
+            match serde_json::from_str::<DispatchDiagnosis>(&json) {
+                Ok(diagnosis) => {
+                    diagnosis.queue_position.is_some()
+                        || diagnosis.status.is_some_and(|status| {
+                            matches!(
+                                RunStatus::try_from(status),
+                                Ok(RunStatus::PENDING | RunStatus::RUNNING | RunStatus::WAITING)
+                            )
+                        })
+                }
+                Err(e) => {
+                    warn!("Failed to read the status of run {}: {}", instance_id, e);
+                    false
+                }
+            }
+        }
+        Ok(ClientResponseMessage::NotFound(_)) => false,
+        Ok(other) => {
+            warn!(
+                "Failed to read the status of run {}: {}",
+                instance_id,
+                other.to_string()
+            );
+            false
+        }
+        Err(e) => {
+            warn!("Failed to read the status of run {}: {}", instance_id, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn test_skip_overlapping_fires() {
+        let mut scheduled = ScheduledRuns::new();
+        let running = RefCell::new(HashSet::new());
+        let is_active = |instance_id: WorkflowInstanceId| {
+            let active = running.borrow().contains(instance_id.as_str());
+            async move { active }
+        };
+        let mut runs = Vec::new();
+        // the first run is still running when the next two fires come due
+        for fire in 0..4 {
+            if fire == 3 {
+                running.borrow_mut().clear();
+            }
+            match scheduled
+                .on_fire("long", OverlapPolicy::Skip, &is_active)
+                .await
+            {
+                FireAction::Fire => {
+                    let instance_id = format!("run-{fire}");
+                    running.borrow_mut().insert(instance_id.clone());
+                    scheduled.fired("long", Some(instance_id.clone().into()));
+                    runs.push(instance_id);
+                }
+                action => assert_eq!(action, FireAction::Skip),
+            }
+        }
+        assert_eq!(runs, vec!["run-0", "run-3"]);
+        assert_eq!(scheduled.last_fired("long").unwrap(), "run-3");
+
+        assert_eq!(
+            scheduled
+                .on_fire("long", OverlapPolicy::Queue, &is_active)
+                .await,
+            FireAction::Defer
+        );
+        assert_eq!(
+            scheduled
+                .on_fire("long", OverlapPolicy::Allow, &is_active)
+                .await,
+            FireAction::Fire
+        );
+    }
+}
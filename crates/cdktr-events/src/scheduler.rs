@@ -1,12 +1,12 @@
 use async_trait::async_trait;
 use cdktr_api::models::ClientResponseMessage;
 use cdktr_api::{API, PrincipalAPI};
-use cdktr_core::exceptions::GenericError;
+use cdktr_core::exceptions::{ErrorKind, GenericError};
 use cdktr_core::get_cdktr_setting;
-use cdktr_workflow::Workflow;
+use cdktr_workflow::{BlackoutWindow, Workflow, defer_past_blackout, parse_blackout_windows};
 use chrono::{DateTime, Utc};
 use cron::Schedule;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use std::collections::{BinaryHeap, HashMap};
 use std::str::FromStr;
 use std::sync::Arc;
@@ -14,6 +14,7 @@ use std::time::Duration;
 use tokio::sync::Mutex;
 use tokio::time::sleep;
 
+use crate::overlap::{FireAction, ScheduledRuns, run_is_active};
 use crate::traits::EventListener;
 
 /// Main scheduling component. This component has an internal task queue for tasks
@@ -25,20 +26,24 @@ use crate::traits::EventListener;
 /// to read diesel async) to poll the DB for schedules and when it finds flows that
 /// are supposed to start within the next poll interval it queues them in order of
 /// earliest to latest
+///
+/// Scheduled workflows are only fired while the scheduler holds the principal's scheduler
+/// lease, so that several schedulers can run against one principal without firing the same
+/// run twice
 #[derive(Clone)]
 pub struct Scheduler {
+    scheduler_id: String,
+    /// Whether the scheduler held the lease when it last asked for it, if it has asked yet
+    holds_lease: Arc<Mutex<Option<bool>>>,
     workflows_ptr: Arc<Mutex<HashMap<String, Workflow>>>,
     schedule_priority_queue_ptr: Arc<Mutex<BinaryHeap<(i64, String)>>>,
     next_peek: Arc<Mutex<(String, i64, bool)>>, // task_id, unix timestamp for start, has been logged
+    scheduled_runs: Arc<Mutex<ScheduledRuns>>,
 }
 
 #[async_trait]
 impl EventListener for Scheduler {
     async fn start_listening(&mut self) -> Result<(), GenericError> {
-        let poll_duration = Duration::from_millis(get_cdktr_setting!(
-            CDKTR_SCHEDULER_START_POLL_FREQUENCY_MS,
-            usize
-        ) as u64);
         loop {
             while !self.next_workflow_ready().await {
                 let mut next_peek_lock = self.next_peek.lock().await;
@@ -54,11 +59,12 @@ impl EventListener for Scheduler {
                     );
                 };
                 drop(next_peek_lock); // release the lock before sleeping
-                sleep(poll_duration).await;
+                sleep(Self::poll_duration()).await;
             }
-            let workflow_id = {
+            let (scheduled_ts, workflow_id) = {
                 let mut pqlock = self.schedule_priority_queue_ptr.lock().await;
-                pqlock.pop().unwrap().1
+                let (neg_ts, workflow_id) = pqlock.pop().unwrap();
+                (-neg_ts, workflow_id)
             };
             {
                 let next_peek_lock = self.next_peek.lock().await;
@@ -69,8 +75,61 @@ impl EventListener for Scheduler {
                     )));
                 }
             };
-            info!("Staging scheduled task: {}", &workflow_id);
-            self.run_workflow(&workflow_id).await?;
+            match self.overlap_action(&workflow_id).await {
+                FireAction::Fire => match self.acquire_lease().await {
+                    Ok(true) => {
+                        info!("Staging scheduled task: {}", &workflow_id);
+                        match self.run_workflow(&workflow_id).await {
+                            Ok(instance_id) => self
+                                .scheduled_runs
+                                .lock()
+                                .await
+                                .fired(&workflow_id, instance_id),
+                            Err(e) if Self::principal_unreachable(&e) => {
+                                warn!(
+                                    "Unable to reach principal to run workflow {} - retrying: {}",
+                                    &workflow_id, e
+                                );
+                                self.retry_on_next_poll(workflow_id).await;
+                                continue;
+                            }
+                            Err(e) => {
+                                error!("Failed to run scheduled workflow {}: {}", &workflow_id, e)
+                            }
+                        }
+                    }
+                    Ok(false) => {
+                        debug!(
+                            "Not firing scheduled run of workflow {} - another scheduler holds the lease",
+                            &workflow_id
+                        );
+                    }
+                    Err(e) if Self::principal_unreachable(&e) => {
+                        warn!(
+                            "Unable to reach principal to fire workflow {} - retrying: {}",
+                            &workflow_id, e
+                        );
+                        self.retry_on_next_poll(workflow_id).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to acquire scheduler lease to fire workflow {}: {}",
+                            &workflow_id, e
+                        )
+                    }
+                },
+                FireAction::Skip => {
+                    info!(
+                        "Skipping scheduled run of workflow {} - its last run is still running",
+                        &workflow_id
+                    );
+                }
+                FireAction::Defer => {
+                    self.retry_on_next_poll(workflow_id).await;
+                    continue;
+                }
+            }
 
             // add the next run of the same workflow back to priority queue
             {
@@ -79,15 +138,19 @@ impl EventListener for Scheduler {
                 let workflow = workflows.get(&workflow_id).unwrap();
                 match workflow.cron() {
                     Some(cron) => {
-                        let next_run = Self::next_run_from_cron(cron, Ok(Utc::now()))?;
-                        // invert the timestamp to make a min heap
-                        let q_top = {
-                            let mut pqlock = self.schedule_priority_queue_ptr.lock().await;
-                            pqlock.push((-next_run.timestamp_millis(), workflow_id));
-                            // update the peek
-                            pqlock.peek().unwrap().clone()
-                        };
-                        *self.next_peek.lock().await = (q_top.1, -q_top.0, false);
+                        let scheduled = DateTime::from_timestamp_millis(scheduled_ts)
+                            .expect("scheduled timestamps come from valid datetimes");
+                        let (next_run, missed) =
+                            Self::next_run_after_fire(cron, scheduled, Utc::now())?;
+                        if missed > 0 {
+                            warn!(
+                                "Scheduler fell behind - skipped {} run(s) of workflow {} that were due while it was late",
+                                missed, workflow_id
+                            );
+                        }
+                        let next_run = Self::defer_run(&workflow_id, workflow, next_run);
+                        self.push_run(workflow_id, next_run.timestamp_millis(), false)
+                            .await;
                     }
                     None => continue,
                 };
@@ -96,7 +159,7 @@ impl EventListener for Scheduler {
     }
 }
 impl Scheduler {
-    pub async fn new() -> Result<Self, GenericError> {
+    pub async fn new(scheduler_id: String) -> Result<Self, GenericError> {
         let workflows = Self::get_workflows().await?;
         let workflows_len = workflows.len();
         info!(
@@ -115,12 +178,118 @@ impl Scheduler {
         let next_peek = Arc::new(Mutex::new((q_top.1.clone(), -q_top.0, false)));
         let schedule_priority_queue_ptr = Arc::new(Mutex::new(schedule_priority_queue));
         Ok(Self {
+            scheduler_id,
+            holds_lease: Arc::new(Mutex::new(None)),
             workflows_ptr,
             schedule_priority_queue_ptr,
             next_peek,
+            scheduled_runs: Arc::new(Mutex::new(ScheduledRuns::new())),
         })
     }
 
+    /// Read on every poll so a reloaded config takes effect straight away
+    fn poll_duration() -> Duration {
+        Duration::from_millis(
+            get_cdktr_setting!(CDKTR_SCHEDULER_START_POLL_FREQUENCY_MS, usize) as u64,
+        )
+    }
+
+    /// Read on every renewal so a reloaded config takes effect straight away
+    fn lease_ttl() -> Duration {
+        Duration::from_millis(get_cdktr_setting!(CDKTR_SCHEDULER_LEASE_TTL_MS, usize) as u64)
+    }
+
+    /// Whether a request failed because the principal couldn't be reached, e.g. while it
+    /// is restarting, rather than because it refused it
+    fn principal_unreachable(e: &GenericError) -> bool {
+        matches!(e.kind(), ErrorKind::Timeout | ErrorKind::Connection)
+    }
+
+    /// Takes or renews the principal's scheduler lease, returning whether this scheduler
+    /// holds it
+    async fn acquire_lease(&self) -> Result<bool, GenericError> {
+        let api = PrincipalAPI::AcquireSchedulerLease(
+            self.scheduler_id.clone(),
+            Self::lease_ttl().as_millis() as u64,
+        );
+        let (held, reason) = match api.send().await? {
+            ClientResponseMessage::Success => (true, String::new()),
+            ClientResponseMessage::Retryable(reason) => (false, reason),
+            other => {
+                return Err(GenericError::RuntimeError(format!(
+                    "Unexpected response to scheduler lease request: {}",
+                    other.to_string()
+                )));
+            }
+        };
+        let mut holds_lease = self.holds_lease.lock().await;
+        if *holds_lease != Some(held) {
+            if held {
+                info!(
+                    "Scheduler {} holds the scheduler lease - firing scheduled workflows",
+                    self.scheduler_id
+                );
+            } else {
+                info!(
+                    "Scheduler {} is standing by - {}",
+                    self.scheduler_id, reason
+                );
+            }
+            *holds_lease = Some(held);
+        }
+        Ok(held)
+    }
+
+    /// Keeps the scheduler lease renewed while this scheduler holds it, or takes it over once
+    /// the scheduler holding it stops renewing it
+    pub async fn lease_loop(&self) {
+        loop {
+            if let Err(e) = self.acquire_lease().await {
+                warn!("Failed to renew scheduler lease with principal: {}", e);
+            }
+            sleep(Self::lease_ttl() / 3).await;
+        }
+    }
+
+    /// Tries a run again on the next poll, keeping the runs after it on the cron
+    async fn retry_on_next_poll(&self, workflow_id: String) {
+        let retry_at = Utc::now() + Self::poll_duration();
+        self.push_run(workflow_id, retry_at.timestamp_millis(), true)
+            .await;
+    }
+
+    /// Adds a run of a workflow to the priority queue and updates the peek
+    async fn push_run(&self, workflow_id: String, run_at_ms: i64, logged: bool) {
+        // invert the timestamp to make a min heap
+        let q_top = {
+            let mut pqlock = self.schedule_priority_queue_ptr.lock().await;
+            pqlock.push((-run_at_ms, workflow_id));
+            pqlock.peek().unwrap().clone()
+        };
+        *self.next_peek.lock().await = (q_top.1, -q_top.0, logged);
+    }
+
+    /// Checks a fire that has come due against the `overlap_policy` of its workflow
+    async fn overlap_action(&self, workflow_id: &str) -> FireAction {
+        let policy = match self.workflows_ptr.lock().await.get(workflow_id) {
+            Some(workflow) => workflow.overlap_policy(),
+            None => return FireAction::Fire,
+        };
+        let scheduled_runs = self.scheduled_runs.lock().await;
+        let action = scheduled_runs
+            .on_fire(workflow_id, policy, run_is_active)
+            .await;
+        if action == FireAction::Defer
+            && let Some(instance_id) = scheduled_runs.last_fired(workflow_id)
+        {
+            debug!(
+                "Holding back scheduled run of workflow {} until its run {} finishes",
+                workflow_id, instance_id
+            );
+        }
+        action
+    }
+
     /// Build the schedules using a min-heap so that we always are looking at the latest schedule
     fn build_schedule_queue(
         workflows: &HashMap<String, Workflow>,
@@ -130,6 +299,7 @@ impl Scheduler {
             match workflow.cron() {
                 Some(cron) => {
                     let next_run = Self::next_run_from_cron(cron, workflow.start_time_utc())?;
+                    let next_run = Self::defer_run(workflow_id, workflow, next_run);
                     // invert the timestamp to make a min heap
                     heap.push((-next_run.timestamp_millis(), workflow_id.clone()));
                 }
@@ -146,8 +316,7 @@ impl Scheduler {
         let schedule = Schedule::from_str(cron).map_err(|e| {
             GenericError::ParseError(format!(
                 "Schedule {} is not a valid crontab. Error: {}",
-                cron,
-                e.to_string()
+                cron, e
             ))
         })?;
         let now = Utc::now();
@@ -162,6 +331,60 @@ impl Scheduler {
         Ok(next_run)
     }
 
+    /// Windows no scheduled workflow fires during, from CDKTR_SCHEDULER_BLACKOUT. Invalid
+    /// windows are ignored with a warning
+    fn global_blackout() -> Vec<BlackoutWindow> {
+        let setting = get_cdktr_setting!(CDKTR_SCHEDULER_BLACKOUT);
+        parse_blackout_windows(&setting).unwrap_or_else(|e| {
+            warn!("Ignoring CDKTR_SCHEDULER_BLACKOUT - {}", e);
+            Vec::new()
+        })
+    }
+
+    /// Defers a run of a workflow that falls inside one of its blackout windows, or one of
+    /// those set for every workflow, to the end of the window
+    fn defer_run(workflow_id: &str, workflow: &Workflow, next_run: DateTime<Utc>) -> DateTime<Utc> {
+        let mut windows = Self::global_blackout();
+        windows.extend(workflow.blackout().iter().cloned());
+        let deferred = defer_past_blackout(next_run, &windows);
+        if deferred != next_run {
+            info!(
+                "Run of workflow {} due at {} falls in a blackout window - deferred to {}",
+                workflow_id,
+                next_run.to_rfc2822(),
+                deferred.to_rfc2822()
+            );
+        }
+        deferred
+    }
+
+    /// Works out the next run of a workflow that has just fired. The next run follows the
+    /// time the workflow was *scheduled* to fire rather than the time it actually fired, so
+    /// a late poll doesn't shift the runs after it. If the scheduler was so late that later
+    /// runs are already due, they are skipped rather than fired in a burst. Returns the next
+    /// run and the number of runs skipped
+    fn next_run_after_fire(
+        cron: &str,
+        scheduled: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<(DateTime<Utc>, usize), GenericError> {
+        let schedule = Schedule::from_str(cron).map_err(|e| {
+            GenericError::ParseError(format!(
+                "Schedule {} is not a valid crontab. Error: {}",
+                cron, e
+            ))
+        })?;
+        for (missed, next_run) in schedule.after(&scheduled).enumerate() {
+            if next_run > now {
+                return Ok((next_run, missed));
+            }
+        }
+        Err(GenericError::RuntimeError(format!(
+            "Unable to determine next run schedule for cron `{}`. Perhaps can only be in past?",
+            cron
+        )))
+    }
+
     async fn get_workflows() -> Result<HashMap<String, Workflow>, GenericError> {
         let api = PrincipalAPI::ListWorkflowStore;
         let response = api.send().await?;
@@ -170,7 +393,7 @@ impl Scheduler {
                 serde_json::from_str(&wfs).map_err(|e| {
                     GenericError::ParseError(format!(
                         "Failed to read workflows from principal message. Not valid JSON: {}",
-                        e.to_string()
+                        e
                     ))
                 })
             }
@@ -220,9 +443,9 @@ impl Scheduler {
 }
 
 async fn refresh_loop(mut scheduler: Scheduler) -> Result<(), GenericError> {
-    let workflow_refresh_seconds =
-        get_cdktr_setting!(CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S, usize) as u64;
     loop {
+        let workflow_refresh_seconds =
+            get_cdktr_setting!(CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S, usize) as u64;
         let _ = sleep(Duration::from_secs(workflow_refresh_seconds)).await;
         debug!("checking internal workflow store for new workflows defs");
         match Scheduler::get_workflows().await {
@@ -234,10 +457,7 @@ async fn refresh_loop(mut scheduler: Scheduler) -> Result<(), GenericError> {
                 }
             }
             Err(e) => {
-                error!(
-                    "Failed to retrieve workflows from principal: {}",
-                    e.to_string()
-                )
+                error!("Failed to retrieve workflows from principal: {}", e)
             }
         }
     }
@@ -340,6 +560,75 @@ tasks:
         assert!(result.unwrap() > Utc::now());
     }
 
+    #[test]
+    fn test_next_run_after_late_fires_stays_aligned() {
+        let cron = "*/10 * * * * *";
+        let mut scheduled = Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap();
+        // polls that are late by less than the cron interval, in varying amounts
+        for late_ms in [1, 499, 2_500, 9_999, 750, 5_000] {
+            let fired_at = scheduled + chrono::Duration::milliseconds(late_ms);
+            let (next_run, missed) =
+                Scheduler::next_run_after_fire(cron, scheduled, fired_at).unwrap();
+            assert_eq!(missed, 0);
+            assert_eq!(next_run, scheduled + chrono::Duration::seconds(10));
+            scheduled = next_run;
+        }
+        assert_eq!(
+            scheduled,
+            Utc.with_ymd_and_hms(2025, 1, 1, 0, 1, 0).unwrap()
+        );
+
+        // a poll late by more than the interval skips the runs that were due, staying on
+        // the cron rather than restarting the interval from the late fire
+        let fired_at = scheduled + chrono::Duration::milliseconds(25_300);
+        let (next_run, missed) = Scheduler::next_run_after_fire(cron, scheduled, fired_at).unwrap();
+        assert_eq!(missed, 2);
+        assert_eq!(
+            next_run,
+            Utc.with_ymd_and_hms(2025, 1, 1, 0, 1, 30).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fire_inside_blackout_deferred() {
+        let yaml = r#"
+name: Batch Job
+cron: "0 0 * * * *"
+start_time: 2025-01-20T00:00:00+00:00
+blackout: ["09:00-17:00"]
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["batch"]
+        "#;
+        let workflow = Workflow::new("fake/path.yml".to_string(), yaml).unwrap();
+        let cron = workflow.cron().unwrap();
+
+        // the 09:00 fire is inside the window so waits until it ends
+        let scheduled = Utc.with_ymd_and_hms(2025, 1, 20, 8, 0, 0).unwrap();
+        let (next_run, _) = Scheduler::next_run_after_fire(cron, scheduled, scheduled).unwrap();
+        assert_eq!(
+            next_run,
+            Utc.with_ymd_and_hms(2025, 1, 20, 9, 0, 0).unwrap()
+        );
+        let deferred = Scheduler::defer_run("batch", &workflow, next_run);
+        assert_eq!(
+            deferred,
+            Utc.with_ymd_and_hms(2025, 1, 20, 17, 0, 0).unwrap()
+        );
+
+        // the runs after the deferred one carry on along the cron
+        let (next_run, _) = Scheduler::next_run_after_fire(cron, deferred, deferred).unwrap();
+        assert_eq!(
+            next_run,
+            Utc.with_ymd_and_hms(2025, 1, 20, 18, 0, 0).unwrap()
+        );
+        assert_eq!(Scheduler::defer_run("batch", &workflow, next_run), next_run);
+    }
+
     #[tokio::test]
     async fn test_scheduler_new_no_workflows() {
         // Patch get_principal_uri and PrincipalAPI::ListWorkflowStore to return empty workflows
@@ -1,18 +1,29 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage};
 use cdktr_core::exceptions::GenericError;
+use cdktr_core::models::WorkflowInstanceId;
 
 /// The event listener trait is for implementing components that
 /// listen to external events and send to the principal to trigger workflows
 #[async_trait]
 pub trait EventListener {
     async fn start_listening(&mut self) -> Result<(), GenericError>;
-    async fn run_workflow(&mut self, workflow_id: &str) -> Result<(), GenericError> {
-        let api = PrincipalAPI::RunTask(workflow_id.to_string());
+    /// Asks the principal to run the workflow, returning the instance id of the queued run
+    /// when the principal gives one
+    async fn run_workflow(
+        &mut self,
+        workflow_id: &str,
+    ) -> Result<Option<WorkflowInstanceId>, GenericError> {
+        let api = PrincipalAPI::RunTask(workflow_id.to_string(), HashMap::new(), false, None);
         let result = api.send().await;
         match result {
             Ok(r) => match r {
-                ClientResponseMessage::Success => Ok(()),
+                ClientResponseMessage::Success => Ok(None),
+                ClientResponseMessage::SuccessWithPayload(instance_id) => {
+                    Ok(Some(instance_id.into()))
+                }
                 other => Err(GenericError::WorkflowError(format!(
                     "Failed to start workflow {}. Response from principal: {}",
                     workflow_id,
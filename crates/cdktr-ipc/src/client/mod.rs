@@ -1,9 +1,24 @@
-use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage};
+use cdktr_api::{
+    API, AgentAPI, ConnectionMonitor, PROTOCOL_VERSION, PrincipalAPI,
+    models::{AgentMetrics, ClientResponseMessage, WorkflowResult},
+};
 use cdktr_core::exceptions::GenericError;
-use cdktr_workflow::Workflow;
+use cdktr_workflow::{Workflow, agent_executors};
 use log::{debug, error, info, trace, warn};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::Duration;
-use tokio::time::sleep;
+use tokio::time::{Instant, sleep};
+
+/// How often `wait_for_completion` asks the principal for the result of the run
+const COMPLETION_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often heartbeats are sent until the principal says how long it waits for them
+const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 5_000;
+
+/// Heartbeats are sent this many times within the heartbeat timeout of the principal, so
+/// an agent has to miss several in a row before it is treated as gone
+const HEARTBEATS_PER_TIMEOUT: u64 = 6;
 
 /// This client is used to house utility functions at a slightly higher level than the raw API
 /// implemented by the PrincipalAPI.
@@ -11,26 +26,160 @@ use tokio::time::sleep;
 pub struct PrincipalClient {
     /// ID of the principal currently subscribed to
     instance_id: String,
+    /// Number of workflows the agent runs at once, reported to the principal when registering
+    max_concurrency: usize,
+    /// Executors the agent runs tasks with, reported to the principal when registering.
+    /// `None` if the agent runs every executor
+    executors: Option<Vec<String>>,
+    /// Address the agent listens on for pushed workflows, reported to the principal when
+    /// registering. `None` if the agent fetches its work
+    push_address: Option<String>,
+    /// Picked when the agent starts and sent with every registration, so the principal
+    /// can tell this agent apart from another one started with the same id
+    session: String,
+    /// Shared by every request of this client and its clones, including the heartbeat
+    connection: ConnectionMonitor,
+    /// Set by the principal through the heartbeat to stop the agent fetching new workflows
+    drained: Arc<AtomicBool>,
+    /// Set by the principal through the heartbeat to have the agent exit once its running
+    /// workflows finish
+    stopping: Arc<AtomicBool>,
+    /// How often the heartbeat is sent, derived from the heartbeat timeout the principal
+    /// sends back when the agent registers
+    heartbeat_interval_ms: Arc<AtomicU64>,
 }
 
 impl PrincipalClient {
-    pub fn new(instance_id: String) -> Self {
-        Self { instance_id }
+    pub fn new(instance_id: String, max_concurrency: usize) -> Self {
+        Self {
+            instance_id,
+            max_concurrency,
+            executors: agent_executors(),
+            push_address: None,
+            session: ulid::Ulid::new().to_string(),
+            connection: ConnectionMonitor::new(),
+            drained: Arc::new(AtomicBool::new(false)),
+            stopping: Arc::new(AtomicBool::new(false)),
+            heartbeat_interval_ms: Arc::new(AtomicU64::new(DEFAULT_HEARTBEAT_INTERVAL_MS)),
+        }
+    }
+
+    /// Registers the agent to have workflows pushed to it at `push_address` rather than
+    /// fetching them
+    pub fn with_push_address(mut self, push_address: String) -> Self {
+        self.push_address = Some(push_address);
+        self
+    }
+
+    fn registration(&self) -> PrincipalAPI {
+        PrincipalAPI::RegisterAgent(
+            self.instance_id.clone(),
+            Some(PROTOCOL_VERSION),
+            Some(self.max_concurrency),
+            self.executors.clone(),
+            self.push_address.clone(),
+            Some(self.session.clone()),
+        )
+    }
+
+    /// State of the connection with the principal and how often it has been lost
+    pub fn connection(&self) -> &ConnectionMonitor {
+        &self.connection
+    }
+
+    /// Whether the principal has drained this agent, in which case it shouldn't fetch
+    /// new workflows
+    pub fn is_drained(&self) -> bool {
+        self.drained.load(Ordering::SeqCst)
+    }
+
+    /// Whether the principal has told this agent to exit once its running workflows finish
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// How long to wait between heartbeats
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms.load(Ordering::SeqCst))
     }
+
+    /// Applies the drain state the principal sends back with a registration or heartbeat.
+    /// A plain success means the agent isn't drained, as does the heartbeat timeout sent
+    /// to an agent registering afresh. Agents told to shut down stay drained until they
+    /// exit
+    pub(crate) fn handle_registration_response(&self, response: &ClientResponseMessage) {
+        let drained = match response {
+            ClientResponseMessage::Success => false,
+            ClientResponseMessage::SuccessWithPayload(payload) => {
+                match AgentAPI::try_from(payload.clone()) {
+                    Ok(AgentAPI::SetDrain(drained)) => drained,
+                    Ok(AgentAPI::HeartbeatTimeout(timeout_ms)) => {
+                        let interval_ms = (timeout_ms / HEARTBEATS_PER_TIMEOUT).max(1);
+                        if self
+                            .heartbeat_interval_ms
+                            .swap(interval_ms, Ordering::SeqCst)
+                            != interval_ms
+                        {
+                            info!(
+                                "Principal times out agents after {timeout_ms}ms without a heartbeat - sending heartbeats every {interval_ms}ms"
+                            );
+                        }
+                        false
+                    }
+                    Ok(AgentAPI::Shutdown) => {
+                        if !self.stopping.swap(true, Ordering::SeqCst) {
+                            info!(
+                                "Agent stopped by principal - exiting once running workflows finish"
+                            );
+                        }
+                        true
+                    }
+                    Ok(other) => {
+                        warn!("Unexpected command from principal in heartbeat response: {other}");
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Unexpected command from principal: {}", e);
+                        return;
+                    }
+                }
+            }
+            _ => return,
+        };
+        if self.is_stopping() {
+            self.drained.store(true, Ordering::SeqCst);
+            return;
+        }
+        if self.drained.swap(drained, Ordering::SeqCst) != drained {
+            if drained {
+                info!(
+                    "Agent drained by principal - finishing running workflows but not fetching new ones"
+                );
+            } else {
+                info!("Agent no longer drained - fetching new workflows");
+            }
+        }
+    }
+
     pub async fn register_with_principal(&mut self) -> Result<(), GenericError> {
         debug!(
             "Registering agent with principal with {}",
             &self.instance_id
         );
 
-        let request = PrincipalAPI::RegisterAgent(self.instance_id.clone());
-        let cli_msg = request.send_with_retry(None, None).await?;
+        let request = self.registration();
+        let cli_msg = request.send_monitored(&self.connection, None, None).await?;
+        self.handle_registration_response(&cli_msg);
 
         match cli_msg {
-            ClientResponseMessage::Success => {
+            ClientResponseMessage::Success | ClientResponseMessage::SuccessWithPayload(_) => {
                 info!("Successfully registered agent with principal");
                 Ok(())
             }
+            ClientResponseMessage::ClientError(msg) => {
+                error!("Principal rejected agent registration: {}", msg);
+                Err(GenericError::RuntimeError(msg))
+            }
             other => {
                 warn!("Non-success message -> {}", other.to_string());
                 Ok(())
@@ -40,10 +189,14 @@ impl PrincipalClient {
 
     /// Sends a heartbeat to the principal to keep this agent registered
     pub async fn send_heartbeat(&self) -> Result<(), GenericError> {
-        let request = PrincipalAPI::RegisterAgent(self.instance_id.clone());
-        match request.send_with_retry(None, None).await {
-            Ok(ClientResponseMessage::Success) => {
+        let request = self.registration();
+        match request.send_monitored(&self.connection, None, None).await {
+            Ok(
+                resp @ (ClientResponseMessage::Success
+                | ClientResponseMessage::SuccessWithPayload(_)),
+            ) => {
                 debug!("Heartbeat sent successfully");
+                self.handle_registration_response(&resp);
                 Ok(())
             }
             Ok(other) => {
@@ -51,25 +204,56 @@ impl PrincipalClient {
                 Ok(())
             }
             Err(e) => {
-                error!("Failed to send heartbeat: {}", e.to_string());
+                error!("Failed to send heartbeat: {}", e);
                 Err(e)
             }
         }
     }
 
-    /// waits indefinitely for a workflow from the principal
+    /// Tells the principal that the stopped agent has finished its running workflows and
+    /// is exiting, so it is deregistered straight away rather than timing out
+    pub async fn confirm_stopped(&self) -> Result<(), GenericError> {
+        let request = PrincipalAPI::AgentStopped(self.instance_id.clone());
+        match request.send().await? {
+            ClientResponseMessage::Success => Ok(()),
+            other => Err(GenericError::RuntimeError(format!(
+                "Principal did not deregister the agent: {}",
+                other.payload()
+            ))),
+        }
+    }
+
+    /// Pushes the agent's latest metrics to the principal
+    pub async fn send_metrics(&self, metrics: AgentMetrics) -> Result<(), GenericError> {
+        let request = PrincipalAPI::AgentMetrics(self.instance_id.clone(), metrics);
+        match request.send().await? {
+            ClientResponseMessage::Success => Ok(()),
+            other => Err(GenericError::RuntimeError(format!(
+                "Principal rejected metrics: {}",
+                other.payload()
+            ))),
+        }
+    }
+
+    /// waits indefinitely for a workflow from the principal. If `long_poll` is set the principal
+    /// holds each fetch open for up to that long, so the client only sleeps between fetches if
+    /// the principal responds early without work (e.g. a principal that doesn't support long-polling)
     pub async fn wait_next_workflow(
         &self,
         sleep_interval: Duration,
+        long_poll: Option<Duration>,
     ) -> Result<Workflow, GenericError> {
         loop {
-            let workflow_res = self.fetch_next_workflow().await;
+            let fetch_start = Instant::now();
+            let workflow_res = self.fetch_next_workflow(long_poll).await;
             let workflow = match workflow_res {
                 Ok(workflow) => workflow,
                 Err(e) => match e {
                     GenericError::NoDataException(_err_msg) => {
                         trace!("No work on global workflow queue - waiting");
-                        sleep(sleep_interval).await;
+                        if long_poll.is_none_or(|timeout| fetch_start.elapsed() < timeout) {
+                            sleep(sleep_interval).await;
+                        }
                         continue;
                     }
                     other_error => return Err(other_error),
@@ -79,9 +263,15 @@ impl PrincipalClient {
         }
     }
 
-    pub async fn fetch_next_workflow(&self) -> Result<Workflow, GenericError> {
-        let request = PrincipalAPI::FetchWorkflow(self.instance_id.clone());
-        match request.send_with_retry(None, None).await {
+    pub async fn fetch_next_workflow(
+        &self,
+        long_poll: Option<Duration>,
+    ) -> Result<Workflow, GenericError> {
+        let request = PrincipalAPI::FetchWorkflow(
+            self.instance_id.clone(),
+            long_poll.map(|timeout| timeout.as_millis() as u64),
+        );
+        match request.send_monitored(&self.connection, None, None).await {
             Ok(cli_resp) => match cli_resp {
                 ClientResponseMessage::Success => {
                     Err(GenericError::NoDataException("Queue empty".to_string()))
@@ -96,7 +286,7 @@ impl PrincipalClient {
                         Err(e) => {
                             return Err(GenericError::ParseError(format!(
                                 "Failed to read Workflow JSON from ZMQ string. Error: {}",
-                                e.to_string()
+                                e
                             )));
                         }
                     };
@@ -112,10 +302,184 @@ impl PrincipalClient {
             Err(e) => Err(e),
         }
     }
+
+    /// Waits for a workflow run to complete, fail or crash and returns its result. If it
+    /// hasn't finished within `timeout` the last result the principal gave is returned, so
+    /// the caller can tell from its status that it is still going. Errors if the principal
+    /// never had a result for the run
+    pub async fn wait_for_completion(
+        workflow_instance_id: &str,
+        timeout: Duration,
+    ) -> Result<WorkflowResult, GenericError> {
+        wait_for_result(
+            || PrincipalAPI::GetWorkflowResult(workflow_instance_id.into()).send(),
+            timeout,
+            COMPLETION_POLL_INTERVAL,
+        )
+        .await
+        .map_err(|e| {
+            GenericError::NoDataException(format!(
+                "No result for workflow run {workflow_instance_id}: {e}"
+            ))
+        })
+    }
+}
+
+/// Polls for the result of a run until it has finished or `timeout` has passed. Runs
+/// that are queued but haven't started yet aren't found, so not found responses and
+/// errors reaching the principal are retried until the timeout
+async fn wait_for_result<F, Fut>(
+    mut fetch: F,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<WorkflowResult, GenericError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<ClientResponseMessage, GenericError>>,
+{
+    let deadline = Instant::now() + timeout;
+    let mut last_result = None;
+    let mut last_error = GenericError::ZMQTimeoutError;
+    loop {
+        match fetch().await {
+            Ok(ClientResponseMessage::SuccessWithPayload(payload)) => {
+                match serde_json::from_str::<WorkflowResult>(&payload) {
+                    Ok(result) if result.is_finished() => return Ok(result),
+                    Ok(result) => last_result = Some(result),
+                    Err(e) => {
+                        return Err(GenericError::ParseError(format!(
+                            "Invalid workflow result from principal: {e}"
+                        )));
+                    }
+                }
+            }
+            Ok(other) => last_error = GenericError::NoDataException(other.payload()),
+            Err(e) => last_error = e,
+        }
+        if Instant::now() + interval > deadline {
+            return last_result.ok_or(last_error);
+        }
+        sleep(interval).await;
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use cdktr_core::models::RunStatus;
+
+    #[test]
+    fn test_drain_state_from_heartbeat_response() {
+        let client = PrincipalClient::new("agent".to_string(), 2);
+        let shared = client.clone();
+        client.handle_registration_response(&ClientResponseMessage::SuccessWithPayload(
+            AgentAPI::SetDrain(true).to_string(),
+        ));
+        assert!(shared.is_drained());
+        // errors leave the state as it was
+        client
+            .handle_registration_response(&ClientResponseMessage::ServerError("oops".to_string()));
+        assert!(shared.is_drained());
+        client.handle_registration_response(&ClientResponseMessage::Success);
+        assert!(!shared.is_drained());
+    }
+
+    #[test]
+    fn test_shutdown_from_heartbeat_response() {
+        let client = PrincipalClient::new("agent".to_string(), 2);
+        client.handle_registration_response(&ClientResponseMessage::SuccessWithPayload(
+            AgentAPI::Shutdown.to_string(),
+        ));
+        assert!(client.is_stopping());
+        assert!(client.is_drained());
+        // a stopped agent stays drained whatever later heartbeats say
+        client.handle_registration_response(&ClientResponseMessage::Success);
+        assert!(client.is_drained());
+    }
+
+    #[test]
+    fn test_heartbeat_interval_from_principal_timeout() {
+        let client = PrincipalClient::new("agent".to_string(), 2);
+        let heartbeat = client.clone();
+        assert_eq!(
+            heartbeat.heartbeat_interval(),
+            Duration::from_millis(DEFAULT_HEARTBEAT_INTERVAL_MS)
+        );
+        client.handle_registration_response(&ClientResponseMessage::SuccessWithPayload(
+            AgentAPI::SetDrain(true).to_string(),
+        ));
+        client.handle_registration_response(&ClientResponseMessage::SuccessWithPayload(
+            AgentAPI::HeartbeatTimeout(12_000).to_string(),
+        ));
+        assert_eq!(heartbeat.heartbeat_interval(), Duration::from_secs(2));
+        // a principal that has just registered the agent hasn't drained it
+        assert!(!heartbeat.is_drained());
+        // later heartbeats keep the interval
+        client.handle_registration_response(&ClientResponseMessage::Success);
+        assert_eq!(heartbeat.heartbeat_interval(), Duration::from_secs(2));
+    }
+
+    fn result(status: RunStatus) -> Result<ClientResponseMessage, GenericError> {
+        let result = WorkflowResult {
+            workflow_id: "flows.etl".to_string(),
+            workflow_instance_id: "run-1".to_string(),
+            status: status.to_string(),
+            duration_ms: None,
+            sla_breached: None,
+            retry_of: None,
+            attempt: None,
+            annotations: Default::default(),
+            progress: None,
+            tasks: Vec::new(),
+        };
+        Ok(ClientResponseMessage::SuccessWithPayload(
+            serde_json::to_string(&result).unwrap(),
+        ))
+    }
 
-    // TODO: add tests
+    #[tokio::test]
+    async fn test_wait_for_completion_returns_final_result() {
+        // queued, then running, then finished
+        let mut responses = vec![
+            Ok(ClientResponseMessage::NotFound("run-1".to_string())),
+            result(RunStatus::RUNNING),
+            result(RunStatus::FAILED),
+        ]
+        .into_iter();
+        let result = wait_for_result(
+            || std::future::ready(responses.next().unwrap()),
+            Duration::from_secs(5),
+            Duration::from_millis(10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.status, "FAILED");
+        assert!(result.is_finished());
+        assert!(responses.next().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_completion_times_out_with_last_status() {
+        let started = Instant::now();
+        let result = wait_for_result(
+            || std::future::ready(result(RunStatus::RUNNING)),
+            Duration::from_millis(200),
+            Duration::from_millis(20),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.status, "RUNNING");
+        assert!(!result.is_finished());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // a run the principal never had a result for is an error
+        let err = wait_for_result(
+            || std::future::ready(Ok(ClientResponseMessage::NotFound("no run".to_string()))),
+            Duration::from_millis(100),
+            Duration::from_millis(20),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("no run"), "{err}");
+    }
 }
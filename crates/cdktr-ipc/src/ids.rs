@@ -0,0 +1,52 @@
+use rustyrs::EternalSlugGenerator;
+use ulid::{Generator, Ulid};
+
+/// Generates the ids of workflow runs and task executions. Each id is a ULID, which is
+/// unique across the cluster and sorts in the order the ids were generated, followed by a
+/// two word slug that is easier to tell apart at a glance than the ULID, e.g.
+/// `01JA2X5V8W3ZQK6R9M4T7B1C0D-brave-otter-0`. The ULID is always 26 characters, so ids
+/// sort by the time they were generated when compared as strings
+pub struct InstanceIdGenerator {
+    ulids: Generator,
+    slugs: EternalSlugGenerator,
+}
+
+impl Default for InstanceIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InstanceIdGenerator {
+    pub fn new() -> Self {
+        Self {
+            ulids: Generator::new(),
+            slugs: EternalSlugGenerator::new(2).unwrap(),
+        }
+    }
+
+    pub fn next(&mut self) -> String {
+        // ULIDs generated in the same millisecond are incremented from the last one, which
+        // only fails once the random part overflows
+        let ulid = self.ulids.generate().unwrap_or_else(|_e| Ulid::new());
+        format!("{}-{}", ulid, self.slugs.next())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_ids_unique_and_ordered() {
+        let mut id_gen = InstanceIdGenerator::new();
+        let ids: Vec<String> = (0..100_000).map(|_| id_gen.next()).collect();
+        let unique: HashSet<&String> = ids.iter().collect();
+        assert_eq!(unique.len(), ids.len());
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        let (ulid, slug) = ids[0].split_at(26);
+        assert!(Ulid::from_string(ulid).is_ok());
+        assert!(slug.starts_with('-') && slug.len() > 1);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{collections::HashSet, env::home_dir, time::Duration};
+use std::{collections::HashSet, env::home_dir, sync::Arc, time::Duration};
 
 use crate::{
     log_manager::{
@@ -6,31 +6,62 @@ use crate::{
         persister::{start_listener, start_persistence_loop},
     },
     server::{
-        principal::{PrincipalServer, helpers},
+        principal::{
+            PrincipalServer, helpers, reservations::AgentReservations, singletons::SingletonRuns,
+        },
         traits::Server,
     },
+    store::StatusStore,
     taskmanager,
 };
 use cdktr_core::{
-    exceptions::GenericError,
+    exceptions::{ErrorKind, GenericError},
     get_cdktr_setting,
     utils::data_structures::{AgentPriorityQueue, AsyncQueue},
+    zmq_helpers::check_port_available,
 };
 use cdktr_db::DBClient;
 use cdktr_events::start_scheduler;
-use cdktr_workflow::WorkflowStore;
+use cdktr_workflow::{Workflow, WorkflowStore, stop_running_tasks};
 use chrono::Utc;
 use log::{error, info, warn};
-use tokio::{task::JoinSet, time::sleep};
+use tokio::{sync::Notify, task::JoinSet, time::sleep};
 
-/// Starts the main agent loop
+/// Starts the main agent loop. On Ctrl-C or SIGTERM the agent stops its running tasks,
+/// giving each its grace period to exit, before returning. An agent stopped by the
+/// principal returns once its running workflows finish
 pub async fn start_agent(instance_id: String, max_concurrent_workflows: usize) {
     let mut tm = taskmanager::TaskManager::new(instance_id, max_concurrent_workflows).await;
-    let loop_res = tm.start().await;
-    if let Err(e) = loop_res {
-        error!("{}", e.to_string());
-        std::process::exit(1);
-    };
+    tokio::select! {
+        loop_res = tm.start() => {
+            if let Err(e) = loop_res {
+                error!("{}", e);
+                std::process::exit(1);
+            };
+        }
+        signal_res = shutdown_signal() => {
+            if let Err(e) = signal_res {
+                error!("Failed to listen for shutdown signals: {}", e);
+            }
+            info!("Shutting down agent - stopping running tasks");
+            stop_running_tasks().await;
+        }
+    }
+}
+
+/// Waits for Ctrl-C or, on unix, SIGTERM
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut sigterm =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            _ = sigterm.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
 }
 
 /// Starts the main principal loop
@@ -40,6 +71,9 @@ pub async fn start_principal(
     instance_id: String,
     no_scheduler: bool,
 ) -> Result<(), GenericError> {
+    // fail fast before starting any other services if another instance holds the port
+    check_port_available(&instance_host, instance_port)?;
+
     let db_path = get_cdktr_setting!(CDKTR_DB_PATH);
     let db_path_str = if db_path.contains("$HOME") {
         db_path.replace(
@@ -52,43 +86,63 @@ pub async fn start_principal(
     } else {
         db_path
     };
-    let db_client =
-        DBClient::new(Some(&db_path_str)).expect("Failed to create DB client on start up");
-    let workflows = WorkflowStore::from_dir(get_cdktr_setting!(CDKTR_WORKFLOW_DIR).as_str())
+    let store: Arc<dyn StatusStore> = Arc::new(DBClient::new(Some(&db_path_str))?);
+    let git_url = get_cdktr_setting!(CDKTR_WORKFLOW_GIT_URL);
+    let workflows = if !git_url.is_empty() {
+        info!("Syncing workflows from git repo {}", git_url);
+        WorkflowStore::from_git(
+            &git_url,
+            &get_cdktr_setting!(CDKTR_WORKFLOW_GIT_REF),
+            &get_cdktr_setting!(CDKTR_WORKFLOW_GIT_SUBDIR),
+        )
         .await
-        .expect("Failed to load workflow store on load");
+    } else if get_cdktr_setting!(CDKTR_WORKFLOW_STRICT_LOADING).to_lowercase() == "true" {
+        WorkflowStore::from_dir_strict(get_cdktr_setting!(CDKTR_WORKFLOW_DIR).as_str()).await
+    } else {
+        WorkflowStore::from_dir(get_cdktr_setting!(CDKTR_WORKFLOW_DIR).as_str()).await
+    }?;
     info!("Loaded {} workflows into store", workflows.count().await);
     let mut principal_server =
-        PrincipalServer::new(instance_id.clone(), workflows.clone(), db_client.clone());
+        PrincipalServer::new(instance_id.clone(), workflows.clone(), store.clone());
+    if let Err(e) = principal_server.restore_queue().await {
+        warn!("Failed to restore the queue of the last principal: {}", e);
+    }
 
     // Get agent tracking structures for heartbeat monitoring before server is moved
-    let (live_agents, agent_workflows, db_for_monitoring) = principal_server.get_agent_tracking();
+    let (live_agents, agent_workflows, store_for_monitoring) =
+        principal_server.get_agent_tracking();
+    let singleton_runs = principal_server.get_singleton_runs();
+    let agent_reservations = principal_server.get_agent_reservations();
+    let task_queue = principal_server.get_task_queue();
 
     let mut m_joined: JoinSet<Result<(), GenericError>> = JoinSet::new();
 
     // start workflow refresh loop
     m_joined.spawn(async move {
-        admin_refresh_loop(workflows).await;
+        admin_refresh_loop(workflows, task_queue).await;
         Ok::<(), GenericError>(())
     });
 
-    // start logs manager
+    // start logs manager. It holds log messages back until the persistence
+    // listener confirms it is subscribed
+    let logs_subscribed = Arc::new(Notify::new());
+    let manager_subscribed = logs_subscribed.clone();
     m_joined.spawn(async move {
-        LogManager::new().await?.start().await;
+        LogManager::new(manager_subscribed).await?.start().await;
         Ok::<(), GenericError>(())
     });
 
     // logs persistence to db
     let logs_queue = AsyncQueue::new();
     let lq_clone = logs_queue.clone();
-    let db_clone = db_client.clone();
+    let store_clone = store.clone();
 
     // start logs persistence listener
-    m_joined.spawn(async move { start_listener(lq_clone).await });
+    m_joined.spawn(async move { start_listener(lq_clone, logs_subscribed).await });
 
     // start logs persistence db job
     m_joined.spawn(async move {
-        start_persistence_loop(db_clone, logs_queue).await;
+        start_persistence_loop(store_clone, logs_queue).await;
         Ok::<(), GenericError>(())
     });
 
@@ -96,8 +150,7 @@ pub async fn start_principal(
     m_joined.spawn(async move {
         principal_server
             .start(&instance_host, instance_port)
-            .await
-            .expect("CDKTR: Unable to start client server");
+            .await?;
         Ok::<(), GenericError>(())
     });
 
@@ -106,33 +159,124 @@ pub async fn start_principal(
         warn!("Scheduler is disabled for this principal instance");
     } else {
         info!("Scheduler is enabled for this principal instance");
+        let scheduler_id = instance_id.clone();
         m_joined.spawn(async move {
             // give the rest of the app 2 seconds to start up before activating schedules
             sleep(Duration::from_millis(2_000)).await;
-            start_scheduler().await
+            start_scheduler(scheduler_id).await
         });
     }
 
     // start agent heartbeat monitor
     m_joined.spawn(async move {
-        agent_heartbeat_monitor(live_agents, agent_workflows, db_for_monitoring).await;
+        agent_heartbeat_monitor(
+            live_agents,
+            agent_workflows,
+            store_for_monitoring,
+            singleton_runs,
+            agent_reservations,
+        )
+        .await;
         Ok::<(), GenericError>(())
     });
 
-    let _ = m_joined.join_all().await;
+    while let Some(join_res) = m_joined.join_next().await {
+        if let Ok(Err(e)) = join_res {
+            error!("Principal service failed: {}", e);
+            return Err(e);
+        }
+    }
     std::process::exit(1); // loop has broken
 }
 
-/// Runs regular refresh tasks within the principal like persisting the task queue
-/// and refreshing workflows from the main directory.
-async fn admin_refresh_loop(mut workflows: WorkflowStore) {
-    let interval = Duration::from_secs(get_cdktr_setting!(
-        CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S,
-        usize
-    ) as u64);
+/// Starts a principal and a single agent in the same process for local development. The
+/// agent talks to the principal over localhost like any other agent. Both are stopped
+/// on Ctrl-C or SIGTERM, once the agent's running tasks have been stopped
+pub async fn start_standalone(
+    instance_host: String,
+    instance_port: usize,
+    principal_instance_id: String,
+    agent_instance_id: String,
+    max_concurrent_workflows: usize,
+    no_scheduler: bool,
+) -> Result<(), GenericError> {
+    let mut nodes: JoinSet<Result<(), GenericError>> = JoinSet::new();
+    nodes.spawn(async move {
+        start_principal(
+            instance_host,
+            instance_port,
+            principal_instance_id,
+            no_scheduler,
+        )
+        .await
+    });
+    nodes.spawn(async move {
+        start_agent(agent_instance_id, max_concurrent_workflows).await;
+        Ok::<(), GenericError>(())
+    });
+    let result = tokio::select! {
+        signal_res = shutdown_signal() => {
+            info!("Shutting down standalone principal and agent");
+            stop_running_tasks().await;
+            signal_res.map_err(|e| {
+                GenericError::RuntimeError(format!("Failed to listen for shutdown signals: {}", e))
+            })
+        }
+        Some(join_res) = nodes.join_next() => match join_res {
+            Ok(Err(e)) => {
+                error!("Standalone node failed: {}", e);
+                Err(e)
+            }
+            _ => Err(GenericError::RuntimeError(
+                "Standalone node stopped unexpectedly".to_string(),
+            )),
+        },
+    };
+    nodes.shutdown().await;
+    result
+}
+
+/// Starts a scheduler in its own process that fires scheduled workflows by queuing runs
+/// with the principal over its API, so either can be restarted without the other. Only
+/// the scheduler holding the principal's scheduler lease fires, so a standby can be run
+/// alongside it. Waits for the principal while it can't be reached and stops on Ctrl-C
+/// or SIGTERM
+pub async fn start_scheduler_instance(instance_id: String) -> Result<(), GenericError> {
+    let scheduler = async {
+        loop {
+            match start_scheduler(instance_id.clone()).await {
+                Err(e) if matches!(e.kind(), ErrorKind::Timeout | ErrorKind::Connection) => {
+                    warn!("Unable to reach principal - retrying in 5 seconds: {}", e);
+                    sleep(Duration::from_secs(5)).await;
+                }
+                res => return res,
+            }
+        }
+    };
+    tokio::select! {
+        res = scheduler => res,
+        signal_res = shutdown_signal() => {
+            info!("Shutting down scheduler");
+            signal_res.map_err(|e| {
+                GenericError::RuntimeError(format!("Failed to listen for shutdown signals: {}", e))
+            })
+        }
+    }
+}
+
+/// Runs regular refresh tasks within the principal like refreshing workflows from the
+/// main directory and checking the task queue for slow consumers.
+async fn admin_refresh_loop(mut workflows: WorkflowStore, task_queue: AsyncQueue<Workflow>) {
     loop {
+        // read on every pass so a reloaded config takes effect straight away
+        let interval = Duration::from_secs(get_cdktr_setting!(
+            CDKTR_WORKFLOW_DIR_REFRESH_FREQUENCY_S,
+            usize
+        ) as u64);
         sleep(interval).await;
-        workflows.refresh_workflows().await
+        workflows.refresh_workflows().await;
+        // a queue nothing is enqueued on isn't checked otherwise
+        task_queue.check_slow_consumer().await;
     }
 }
 
@@ -142,7 +286,9 @@ async fn agent_heartbeat_monitor(
     agent_workflows: std::sync::Arc<
         tokio::sync::Mutex<std::collections::HashMap<String, HashSet<String>>>,
     >,
-    db_client: DBClient,
+    store: Arc<dyn StatusStore>,
+    singleton_runs: SingletonRuns,
+    agent_reservations: AgentReservations,
 ) {
     let timeout_ms = get_cdktr_setting!(CDKTR_AGENT_HEARTBEAT_TIMEOUT_MS, usize) as i64;
     let timeout_micros = timeout_ms * 1000; // convert to microseconds for comparison with timestamps
@@ -173,6 +319,8 @@ async fn agent_heartbeat_monitor(
                     // Agent has timed out - mark all its workflows as CRASHED
                     if let Some(workflow_instance_ids) = agent_wf_map.remove(&agent_id) {
                         let wf_count = workflow_instance_ids.len();
+                        singleton_runs.release(&workflow_instance_ids);
+                        agent_reservations.release_agent(&agent_id);
                         warn!(
                             "Agent {} timed out with {} active workflow(s). Marking as CRASHED.",
                             agent_id, wf_count
@@ -180,7 +328,7 @@ async fn agent_heartbeat_monitor(
 
                         // Mark all workflows as crashed
                         if let Err(e) = helpers::mark_workflows_as_crashed(
-                            db_client.clone(),
+                            store.as_ref(),
                             workflow_instance_ids,
                         )
                         .await
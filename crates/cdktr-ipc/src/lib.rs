@@ -1,8 +1,12 @@
-mod client;
+pub mod client;
+mod ids;
 // mod events; TODO: reinclude once the main runner is working
 pub mod log_manager;
 mod server;
+pub mod store;
 mod taskmanager;
+#[cfg(test)]
+mod testing;
 
 // public api
 pub mod instance;
@@ -19,6 +23,24 @@ mod tests {
         start_agent("fake-instance-id".to_string(), 1).await
     }
 
+    #[tokio::test]
+    async fn test_principal_port_in_use() {
+        let _listener = std::net::TcpListener::bind(("0.0.0.0", 32146)).unwrap();
+        let result = start_principal(
+            "0.0.0.0".to_string(),
+            32146,
+            "test_instance".to_string(),
+            true,
+        )
+        .await;
+        match result {
+            Err(cdktr_core::exceptions::GenericError::RuntimeError(msg)) => {
+                assert!(msg.contains("port already in use"))
+            }
+            other => panic!("Expected port in use error, got {:?}", other),
+        }
+    }
+
     #[ignore]
     #[tokio::test]
     async fn test_principal() -> Result<(), cdktr_core::exceptions::GenericError> {
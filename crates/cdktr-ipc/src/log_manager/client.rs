@@ -3,13 +3,17 @@ use std::time::Duration;
 use cdktr_core::{
     exceptions::{GenericError, cdktr_result},
     get_cdktr_setting,
+    utils::{LogRateLimiter, MALFORMED_MESSAGE_WARNING_INTERVAL},
     zmq_helpers::{get_server_tcp_uri, get_zmq_sub},
 };
 use log::warn;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{Notify, mpsc::Sender};
 use zeromq::{SocketRecv, SubSocket};
 
-use crate::log_manager::model::LogMessage;
+use crate::log_manager::{
+    manager::{SUBSCRIBED_PROBE, is_subscribed_probe},
+    model::LogMessage,
+};
 
 pub struct LogsClient {
     client_name: String,
@@ -32,6 +36,16 @@ impl LogsClient {
         })
     }
 
+    /// Waits until a probe from the log manager arrives and confirms it through
+    /// `subscribed`, so that a log manager in the same process doesn't publish log
+    /// messages before this client receives them
+    pub async fn confirm_subscribed(&mut self, subscribed: &Notify) -> Result<(), GenericError> {
+        cdktr_result(self.sub_socket.subscribe(SUBSCRIBED_PROBE).await)?;
+        while !is_subscribed_probe(&cdktr_result(self.sub_socket.recv().await)?) {}
+        subscribed.notify_one();
+        cdktr_result(self.sub_socket.unsubscribe(SUBSCRIBED_PROBE).await)
+    }
+
     pub async fn listen(
         &mut self,
         tx: Sender<LogMessage>,
@@ -49,8 +63,20 @@ impl LogsClient {
     }
 
     async fn listen_loop(&mut self, tx: Sender<LogMessage>) -> Result<(), GenericError> {
+        let mut malformed_warnings = LogRateLimiter::new(MALFORMED_MESSAGE_WARNING_INTERVAL);
         loop {
-            let msg = LogMessage::try_from(cdktr_result(self.sub_socket.recv().await)?)?;
+            let msg = cdktr_result(self.sub_socket.recv().await)?;
+            // probes published before the client unsubscribed from them
+            if is_subscribed_probe(&msg) {
+                continue;
+            }
+            let msg = match LogMessage::try_from(msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    malformed_warnings.warn(&format!("Dropped malformed log message: {}", e));
+                    continue;
+                }
+            };
             match tx.send(msg).await {
                 Ok(_) => (),
                 Err(_e) => {
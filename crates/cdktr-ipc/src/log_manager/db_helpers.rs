@@ -1,17 +1,19 @@
 use std::time::{Duration, SystemTime};
 
+use cdktr_api::models::LogPage;
 use cdktr_core::exceptions::GenericError;
-use cdktr_db::DBClient;
-use log::{debug, warn};
+use cdktr_core::models::WorkflowInstanceId;
 
 use crate::log_manager::model::LogMessage;
+use crate::store::StatusStore;
 
-pub async fn read_logs<'a>(
-    db_client: DBClient,
+pub async fn read_logs(
+    store: &dyn StatusStore,
     start_timestamp_ms: Option<u64>,
     end_timestamp_ms: Option<u64>,
     workflow_id: Option<String>,
-    workflow_instance_id: Option<String>,
+    workflow_instance_id: Option<WorkflowInstanceId>,
+    page: Option<LogPage>,
 ) -> Result<Vec<LogMessage>, GenericError> {
     let end_timestamp_ms = if let Some(ts) = end_timestamp_ms {
         ts
@@ -26,50 +28,21 @@ pub async fn read_logs<'a>(
     } else {
         end_timestamp_ms - Duration::from_secs(86400).as_millis() as u64 // default to previous 24 hours of end time
     };
-    let mut stmt_str = format!(
-        "SELECT * FROM logstore WHERE timestamp_ms >= {start_timestamp_ms} AND timestamp_ms < {end_timestamp_ms} "
-    );
-    if let Some(wf_id) = workflow_id {
-        stmt_str.push_str(&format!("AND workflow_id = '{wf_id}' "));
-    };
-    if let Some(wf_ins_id) = workflow_instance_id {
-        stmt_str.push_str(&format!("AND workflow_instance_id = '{wf_ins_id}' "));
-    };
-    debug!("stmt_str: {}", &stmt_str);
-    let results = {
-        let locked_client = db_client.lock_inner_client().await;
-        let mut stmt = locked_client.prepare(&stmt_str).unwrap();
-        stmt.query_map([], |row| {
-            Ok(LogMessage {
-                workflow_id: row.get(0).unwrap(),
-                workflow_name: row.get(1).unwrap(),
-                workflow_instance_id: row.get(2).unwrap(),
-                task_name: row.get(3).unwrap(),
-                task_instance_id: row.get(4).unwrap(),
-                timestamp_ms: row.get(5).unwrap(),
-                level: row.get(6).unwrap(),
-                payload: row.get(7).unwrap(),
-            })
-        })
-        .map_err(|e| GenericError::DBError(e.to_string()))?
-        .map(|msg_res| msg_res.map_err(|e| GenericError::DBError(e.to_string())))
-        .collect::<Vec<Result<LogMessage, GenericError>>>()
-    };
-    let mut msgs = Vec::new();
-    for res in results {
-        match res {
-            Ok(msg) => msgs.push(msg),
-            Err(e) => {
-                warn!("Failed to read msg {:?}", e)
-            }
-        }
-    }
-    Ok(msgs)
+    store
+        .read_logs(
+            start_timestamp_ms,
+            end_timestamp_ms,
+            workflow_id,
+            workflow_instance_id,
+            page,
+        )
+        .await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use cdktr_db::DBClient;
 
     #[tokio::test]
     async fn test_read_logs() {
@@ -129,11 +102,12 @@ mod tests {
                 .unwrap();
         }
         let messages = read_logs(
-            db_client,
+            &db_client,
             Some(0),
             Some(3000000000),
             Some("test_workflow_id".to_string()),
-            Some("test_workflow_instance_id".to_string()),
+            Some("test_workflow_instance_id".into()),
+            None,
         )
         .await
         .expect("Failed to read logs");
@@ -1,65 +1,218 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
 use cdktr_core::{
     exceptions::GenericError,
     get_cdktr_setting,
+    utils::{LogRateLimiter, MALFORMED_MESSAGE_WARNING_INTERVAL},
     zmq_helpers::{get_server_tcp_uri, get_zmq_pub, get_zmq_pull},
 };
-use log::{debug, info, trace, warn};
-use zeromq::{PubSocket, PullSocket, SocketRecv, SocketSend};
+use log::{info, trace, warn};
+use tokio::sync::Notify;
+use tokio::time::{interval, sleep};
+use zeromq::{PubSocket, PullSocket, SocketRecv, SocketSend, ZmqMessage};
 
 use crate::log_manager::model::LogMessage;
 
+/// Published by the log manager while it waits for a subscriber. No workflow id starts
+/// with it, so subscribers to a single workflow only get it if they ask for it
+pub const SUBSCRIBED_PROBE: &str = "\x02cdktr-logs-probe";
+const PROBE_INTERVAL: Duration = Duration::from_millis(50);
+/// Longest log messages are held back waiting for a subscriber before they are published anyway
+const SUBSCRIBER_WAIT: Duration = Duration::from_secs(10);
+/// Most log messages held back waiting for a subscriber
+const MAX_HELD_MESSAGES: usize = 10_000;
+
+/// Whether a message on the pub socket is a probe rather than a log message
+pub fn is_subscribed_probe(msg: &ZmqMessage) -> bool {
+    msg.get(0)
+        .is_some_and(|frame| frame.as_ref() == SUBSCRIBED_PROBE.as_bytes())
+}
+
 /// This module provides the LogManager which is responsible for managing
 /// the logging system of the CDKTR application.
 /// Each agent will publish log messages to the log manager pull socket,
 /// and the log manager will consolidate these messages by workflow ID topics
 /// and publish them to the pub socket.
+/// A PUB socket drops messages nobody is subscribed to yet, so until a subscriber
+/// confirms it is receiving the probes the manager publishes, by notifying `subscribed`,
+/// log messages are held back rather than published
 pub struct LogManager {
     pub_socket: PubSocket,
     pull_socket: PullSocket,
+    subscribed: Arc<Notify>,
 }
 
 impl LogManager {
-    pub async fn new() -> Result<Self, GenericError> {
-        Ok(LogManager {
-            pull_socket: get_zmq_pull(&get_server_tcp_uri(
+    pub async fn new(subscribed: Arc<Notify>) -> Result<Self, GenericError> {
+        Self::bind(
+            &get_server_tcp_uri(
                 get_cdktr_setting!(CDKTR_PRINCIPAL_HOST).as_str(),
                 get_cdktr_setting!(CDKTR_LOGS_LISTENING_PORT, usize),
-            ))
-            .await?,
-            pub_socket: get_zmq_pub(&get_server_tcp_uri(
+            ),
+            &get_server_tcp_uri(
                 get_cdktr_setting!(CDKTR_PRINCIPAL_HOST).as_str(),
                 get_cdktr_setting!(CDKTR_LOGS_PUBLISHING_PORT, usize),
-            ))
-            .await?,
+            ),
+            subscribed,
+        )
+        .await
+    }
+
+    async fn bind(
+        listen_uri: &str,
+        publish_uri: &str,
+        subscribed: Arc<Notify>,
+    ) -> Result<Self, GenericError> {
+        Ok(LogManager {
+            pull_socket: get_zmq_pull(listen_uri).await?,
+            pub_socket: get_zmq_pub(publish_uri).await?,
+            subscribed,
         })
     }
 
     pub async fn start(&mut self) {
         info!("LogManager started, listening for log messages from agents...");
+        let mut malformed_warnings = LogRateLimiter::new(MALFORMED_MESSAGE_WARNING_INTERVAL);
+        self.wait_for_subscriber(&mut malformed_warnings).await;
         loop {
-            match self.pull_socket.recv().await {
-                Ok(msg) => {
-                    let log_message: LogMessage = match LogMessage::try_from(msg) {
-                        Ok(log_msg) => log_msg,
-                        Err(e) => {
-                            debug!("Failed to parse log message: {}", e);
-                            dbg!(e);
-                            continue;
-                        }
-                    };
+            if let Some(log_message) = self.recv_log_message(&mut malformed_warnings).await {
+                self.publish(log_message).await
+            }
+        }
+    }
+
+    /// Publishes probes, holding back the log messages received meanwhile, until a
+    /// subscriber confirms it receives them. The held messages are then published in
+    /// the order they came in
+    async fn wait_for_subscriber(&mut self, malformed_warnings: &mut LogRateLimiter) {
+        let subscribed = self.subscribed.clone();
+        let mut held = VecDeque::new();
+        let mut probes = interval(PROBE_INTERVAL);
+        let gave_up = sleep(SUBSCRIBER_WAIT);
+        tokio::pin!(gave_up);
+        loop {
+            tokio::select! {
+                _ = subscribed.notified() => {
+                    info!("Log subscriber confirmed - publishing log messages");
+                    break;
+                }
+                _ = &mut gave_up => {
+                    warn!(
+                        "No log subscriber confirmed after {}s - publishing {} held log messages",
+                        SUBSCRIBER_WAIT.as_secs(),
+                        held.len()
+                    );
+                    break;
+                }
+                _ = probes.tick() => {
+                    if let Err(e) = self.pub_socket.send(ZmqMessage::from(SUBSCRIBED_PROBE)).await {
+                        warn!("Failed to publish log subscriber probe: {}", e);
+                    }
+                }
+                log_message = self.recv_log_message(malformed_warnings) => {
+                    if let Some(log_message) = log_message {
+                        held.push_back(log_message);
+                    }
+                    if held.len() >= MAX_HELD_MESSAGES {
+                        warn!(
+                            "Held {} log messages without a subscriber - publishing them",
+                            held.len()
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        for log_message in held {
+            self.publish(log_message).await
+        }
+    }
+
+    async fn recv_log_message(
+        &mut self,
+        malformed_warnings: &mut LogRateLimiter,
+    ) -> Option<LogMessage> {
+        match self.pull_socket.recv().await {
+            Ok(msg) => match LogMessage::try_from(msg) {
+                Ok(log_message) => {
                     trace!(
                         "Received log message on topic {}: {}",
                         &log_message.workflow_instance_id, &log_message.payload
                     );
-                    // Publish the log message to the pub socket
-                    if let Err(e) = self.pub_socket.send(log_message.into()).await {
-                        warn!("Failed to publish log message: {}", e);
-                    }
+                    Some(log_message)
                 }
                 Err(e) => {
-                    warn!("Error receiving message: {}", e);
+                    malformed_warnings.warn(&format!("Dropped malformed log message: {}", e));
+                    None
                 }
+            },
+            Err(e) => {
+                malformed_warnings.warn(&format!("Error receiving message: {}", e));
+                None
             }
         }
     }
+
+    async fn publish(&mut self, log_message: LogMessage) {
+        if let Err(e) = self.pub_socket.send(log_message.into()).await {
+            warn!("Failed to publish log message: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdktr_core::zmq_helpers::{get_zmq_push, get_zmq_sub};
+    use std::net::TcpListener;
+
+    fn free_uri() -> String {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        get_server_tcp_uri("127.0.0.1", port as usize)
+    }
+
+    #[tokio::test]
+    async fn test_first_log_line_not_dropped() {
+        let (listen_uri, publish_uri) = (free_uri(), free_uri());
+        let subscribed = Arc::new(Notify::new());
+        let mut manager = LogManager::bind(&listen_uri, &publish_uri, subscribed.clone())
+            .await
+            .unwrap();
+        tokio::spawn(async move { manager.start().await });
+
+        // the line is sent before anything subscribes to the manager
+        let mut push = get_zmq_push(&listen_uri).await.unwrap();
+        let first_line = LogMessage::new(
+            "wf".to_string(),
+            "Workflow".to_string(),
+            "wf-ins".to_string(),
+            "task".to_string(),
+            "task-ins".to_string(),
+            1,
+            "INFO".to_string(),
+            "first line".to_string(),
+        );
+        push.send(first_line.clone().into()).await.unwrap();
+
+        let mut sub = get_zmq_sub(&publish_uri, "").await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let msg = sub.recv().await.unwrap();
+                if is_subscribed_probe(&msg) {
+                    subscribed.notify_one();
+                } else {
+                    return LogMessage::try_from(msg).unwrap();
+                }
+            }
+        })
+        .await
+        .expect("first log line was dropped");
+        assert_eq!(received, first_line);
+    }
 }
@@ -11,8 +11,9 @@ pub use db_helpers::read_logs;
 mod tests {
     use cdktr_core::exceptions::GenericError;
     use regex::Regex;
+    use std::sync::Arc;
     use std::time::{Duration, SystemTime};
-    use tokio::task::JoinSet;
+    use tokio::{sync::Notify, task::JoinSet};
 
     use super::{
         client::LogsClient, manager::LogManager, model::LogMessage, publisher::LogsPublisher,
@@ -84,8 +85,10 @@ mod tests {
 
         let mut join_set: JoinSet<Result<(), GenericError>> = JoinSet::new();
 
+        let subscribed = Arc::new(Notify::new());
+        let manager_subscribed = subscribed.clone();
         join_set.spawn(async move {
-            let mut log_manager = LogManager::new().await?;
+            let mut log_manager = LogManager::new(manager_subscribed).await?;
             log_manager.start().await;
             Ok(())
         });
@@ -95,6 +98,7 @@ mod tests {
         join_set.spawn(async move {
             let mut logs_client =
                 LogsClient::new("test_client".to_string(), test_workflow_id).await?;
+            logs_client.confirm_subscribed(&subscribed).await?;
             let _ = logs_client
                 .listen(tx, Some(Duration::from_millis(4000)))
                 .await
@@ -102,8 +106,8 @@ mod tests {
             Ok(())
         });
 
+        // the log manager holds the messages until the client is subscribed
         join_set.spawn(async move {
-            tokio::time::sleep(Duration::from_secs(1)).await;
             let mut logs_publisher = LogsPublisher::new(
                 test_workflow_id.to_string(),
                 test_workflow_name.to_string(),
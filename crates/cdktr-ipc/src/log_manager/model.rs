@@ -1,12 +1,115 @@
 use cdktr_core::{
     exceptions::{GenericError, ZMQParseError, cdktr_result},
+    get_cdktr_setting,
     models::ZMQArgs,
     zmq_helpers::format_zmq_msg_str,
 };
 use cdktr_db::impl_dbrecordbatch;
+use chrono::{DateTime, FixedOffset, Local, Utc, format::Item, format::StrftimeItems};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
 use zeromq::ZmqMessage;
 
 #[derive(Clone, PartialEq, Debug)]
+enum LogTimezone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl LogTimezone {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            tz if tz.eq_ignore_ascii_case("utc") => Some(Self::Utc),
+            tz if tz.eq_ignore_ascii_case("local") => Some(Self::Local),
+            tz => tz.parse::<FixedOffset>().ok().map(Self::Fixed),
+        }
+    }
+}
+
+/// How log timestamps are displayed. Only the display changes, log messages always
+/// carry their timestamp as epoch milliseconds
+#[derive(Clone, PartialEq, Debug)]
+pub struct TimestampFormat {
+    timezone: LogTimezone,
+    /// strftime pattern, or RFC 3339 if not set
+    pattern: Option<String>,
+}
+
+impl Default for TimestampFormat {
+    fn default() -> Self {
+        Self {
+            timezone: LogTimezone::Utc,
+            pattern: None,
+        }
+    }
+}
+
+impl TimestampFormat {
+    /// `timezone` is one of `UTC`, `local` or a fixed offset such as `+05:30`, and
+    /// `pattern` a strftime pattern or empty for RFC 3339
+    pub fn new(timezone: &str, pattern: &str) -> Result<Self, GenericError> {
+        let timezone = LogTimezone::parse(timezone).ok_or(GenericError::ConfigError(format!(
+            "'{}' is not a valid log timezone. Expected UTC, local or an offset such as +05:30",
+            timezone
+        )))?;
+        let pattern = if pattern.is_empty() {
+            None
+        } else if StrftimeItems::new(pattern).any(|item| item == Item::Error) {
+            return Err(GenericError::ConfigError(format!(
+                "'{}' is not a valid strftime pattern for log timestamps",
+                pattern
+            )));
+        } else {
+            Some(pattern.to_string())
+        };
+        Ok(Self { timezone, pattern })
+    }
+
+    /// The format from the settings of this process, resolved the first time it's needed
+    /// so logs can be formatted a line at a time without reading the settings for each
+    pub fn configured() -> &'static Self {
+        static CONFIGURED: OnceLock<TimestampFormat> = OnceLock::new();
+        CONFIGURED.get_or_init(Self::from_settings)
+    }
+
+    /// Reads the format from `CDKTR_LOG_TIMEZONE` and `CDKTR_LOG_TIMESTAMP_FORMAT`,
+    /// falling back to RFC 3339 in UTC if either is invalid
+    pub fn from_settings() -> Self {
+        Self::new(
+            &get_cdktr_setting!(CDKTR_LOG_TIMEZONE),
+            &get_cdktr_setting!(CDKTR_LOG_TIMESTAMP_FORMAT),
+        )
+        .unwrap_or_else(|e| {
+            warn!("{} - displaying log timestamps as RFC 3339 in UTC", e);
+            Self::default()
+        })
+    }
+
+    pub fn render(&self, timestamp_ms: u64) -> String {
+        let Some(utc) = DateTime::<Utc>::from_timestamp_millis(timestamp_ms as i64) else {
+            return timestamp_ms.to_string();
+        };
+        match &self.timezone {
+            LogTimezone::Utc => self.render_in(utc),
+            LogTimezone::Local => self.render_in(utc.with_timezone(&Local)),
+            LogTimezone::Fixed(offset) => self.render_in(utc.with_timezone(offset)),
+        }
+    }
+
+    fn render_in<Tz: chrono::TimeZone>(&self, dt: DateTime<Tz>) -> String
+    where
+        Tz::Offset: std::fmt::Display,
+    {
+        match &self.pattern {
+            Some(pattern) => dt.format(pattern).to_string(),
+            None => dt.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct LogMessage {
     pub workflow_id: String,
     pub workflow_name: String,
@@ -51,10 +154,14 @@ impl LogMessage {
             payload,
         }
     }
+    /// format the message with its timestamp displayed in the configured format
     pub fn format(&self) -> String {
-        let timestring = chrono::DateTime::from_timestamp_millis(self.timestamp_ms as i64)
-            .unwrap()
-            .to_rfc3339();
+        self.format_with(TimestampFormat::configured())
+    }
+
+    /// format the message with its timestamp displayed in the given format
+    pub fn format_with(&self, timestamp_format: &TimestampFormat) -> String {
+        let timestring = timestamp_format.render(self.timestamp_ms);
         format!(
             "[{} {}] [{}/{}] {}",
             timestring, self.level, self.workflow_instance_id, self.task_instance_id, self.payload
@@ -63,9 +170,13 @@ impl LogMessage {
 
     /// format the message including the workflow id
     pub fn format_full(&self) -> String {
-        let timestring = chrono::DateTime::from_timestamp_millis(self.timestamp_ms as i64)
-            .unwrap()
-            .to_rfc3339();
+        self.format_full_with(TimestampFormat::configured())
+    }
+
+    /// format the message including the workflow id, with its timestamp displayed in
+    /// the given format
+    pub fn format_full_with(&self, timestamp_format: &TimestampFormat) -> String {
+        let timestring = timestamp_format.render(self.timestamp_ms);
         format!(
             "[{} {}] [{}={} / {}={}] {}",
             timestring,
@@ -83,9 +194,9 @@ impl TryFrom<ZmqMessage> for LogMessage {
     type Error = GenericError;
     fn try_from(msg: ZmqMessage) -> Result<Self, Self::Error> {
         let mut zmq_args: ZMQArgs = msg.into();
-        if zmq_args.len() < 4 {
+        if zmq_args.len() < 8 {
             return Err(GenericError::ZMQParseError(ZMQParseError::ParseError(
-                "LogMessage must have at least 4 parts: topic, timestamp, level and payload"
+                "LogMessage must have 8 parts: workflow id, workflow name, workflow instance id, task name, task instance id, timestamp, level and payload"
                     .to_string(),
             )));
         }
@@ -116,3 +227,73 @@ impl Into<ZmqMessage> for LogMessage {
         ]))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_malformed_log_message() {
+        // fewer parts than a log message has, or a bad timestamp, is an error rather than a panic
+        for raw in [
+            "",
+            "wf\x01Workflow\x01wf-ins\x01Task",
+            "wf\x01Workflow\x01wf-ins\x01Task\x01task-ins\x01123\x01INFO",
+            "wf\x01Workflow\x01wf-ins\x01Task\x01task-ins\x01soon\x01INFO\x01hello",
+        ] {
+            assert!(
+                LogMessage::try_from(ZmqMessage::from(raw)).is_err(),
+                "{raw}"
+            );
+        }
+        let msg = LogMessage::try_from(ZmqMessage::from(
+            "wf\x01Workflow\x01wf-ins\x01Task\x01task-ins\x01123\x01INFO\x01hello",
+        ))
+        .unwrap();
+        assert_eq!(msg.payload, "hello");
+    }
+
+    fn message_at(timestamp_ms: u64) -> LogMessage {
+        LogMessage::new(
+            "wf".to_string(),
+            "Workflow".to_string(),
+            "wf-ins".to_string(),
+            "Task".to_string(),
+            "task-ins".to_string(),
+            timestamp_ms,
+            "INFO".to_string(),
+            "hello".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_timestamp_format() {
+        // 2025-01-20T12:30:00.250Z
+        let msg = message_at(1_737_376_200_250);
+        assert_eq!(
+            msg.format_with(&TimestampFormat::default()),
+            "[2025-01-20T12:30:00.250+00:00 INFO] [wf-ins/task-ins] hello"
+        );
+        assert_eq!(
+            msg.format_with(&TimestampFormat::new("+05:30", "").unwrap()),
+            "[2025-01-20T18:00:00.250+05:30 INFO] [wf-ins/task-ins] hello"
+        );
+        assert_eq!(
+            msg.format_full_with(&TimestampFormat::new("-08:00", "%Y-%m-%d %H:%M:%S %z").unwrap()),
+            "[2025-01-20 04:30:00 -0800 INFO] [Workflow=wf-ins / Task=task-ins] hello"
+        );
+        assert_eq!(
+            TimestampFormat::new("utc", "%d/%m/%Y %H:%M")
+                .unwrap()
+                .render(1_737_376_200_250),
+            "20/01/2025 12:30"
+        );
+    }
+
+    #[test]
+    fn test_invalid_timestamp_format() {
+        assert!(TimestampFormat::new("Mars/Olympus", "").is_err());
+        assert!(TimestampFormat::new("UTC", "%Y-%Q").is_err());
+        assert!(TimestampFormat::new("local", "%H:%M").is_ok());
+    }
+}
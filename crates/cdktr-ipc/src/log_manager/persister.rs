@@ -1,21 +1,28 @@
 use std::time::Duration;
 
-use crate::log_manager::model::LogMessage;
+use crate::log_manager::{manager::is_subscribed_probe, model::LogMessage};
+use crate::store::StatusStore;
 use cdktr_core::{
     exceptions::{GenericError, cdktr_result},
     get_cdktr_setting,
-    utils::data_structures::AsyncQueue,
+    utils::{LogRateLimiter, MALFORMED_MESSAGE_WARNING_INTERVAL, data_structures::AsyncQueue},
     zmq_helpers::{get_server_tcp_uri, get_zmq_sub},
 };
-use cdktr_db::DBClient;
 use log::{info, warn};
+use std::sync::Arc;
+use tokio::sync::Notify;
 use tokio::time::{Instant, sleep_until};
 use zeromq::SocketRecv;
 
 // write logs to the database every 30 seconds
 static CACHE_PERSISTENCE_INTERVAL_MS: u64 = 30_000;
 
-pub async fn start_listener(mut logs_queue: AsyncQueue<LogMessage>) -> Result<(), GenericError> {
+/// Queues the log messages published by the log manager to be persisted, confirming
+/// to the manager through `subscribed` once its probes arrive
+pub async fn start_listener(
+    mut logs_queue: AsyncQueue<LogMessage>,
+    subscribed: Arc<Notify>,
+) -> Result<(), GenericError> {
     let mut logs_sub_socket = get_zmq_sub(
         &get_server_tcp_uri(
             get_cdktr_setting!(CDKTR_PRINCIPAL_HOST).as_str(),
@@ -24,17 +31,28 @@ pub async fn start_listener(mut logs_queue: AsyncQueue<LogMessage>) -> Result<()
         "",
     )
     .await?;
+    let mut malformed_warnings = LogRateLimiter::new(MALFORMED_MESSAGE_WARNING_INTERVAL);
     loop {
         let log_msg = cdktr_result(logs_sub_socket.recv().await)?;
-        logs_queue.put(LogMessage::try_from(log_msg)?).await
+        if is_subscribed_probe(&log_msg) {
+            subscribed.notify_one();
+            continue;
+        }
+        match LogMessage::try_from(log_msg) {
+            Ok(log_msg) => logs_queue.put(log_msg).await,
+            Err(e) => malformed_warnings.warn(&format!("Dropped malformed log message: {}", e)),
+        }
     }
 }
 
-pub async fn start_persistence_loop(db_client: DBClient, mut logs_queue: AsyncQueue<LogMessage>) {
+pub async fn start_persistence_loop(
+    store: Arc<dyn StatusStore>,
+    mut logs_queue: AsyncQueue<LogMessage>,
+) {
     loop {
         sleep_until(Instant::now() + Duration::from_millis(CACHE_PERSISTENCE_INTERVAL_MS)).await;
         let logs_to_persist = logs_queue.dump().await;
-        match persist_cache(&db_client, logs_to_persist).await {
+        match persist_cache(store.as_ref(), logs_to_persist).await {
             Ok(()) => (),
             Err(failed_batch) => {
                 logs_queue.put_front_multiple(failed_batch).await;
@@ -46,11 +64,11 @@ pub async fn start_persistence_loop(db_client: DBClient, mut logs_queue: AsyncQu
 }
 
 pub async fn persist_cache(
-    db_client: &DBClient,
+    store: &dyn StatusStore,
     logs_to_persist: Vec<LogMessage>,
 ) -> Result<(), Vec<LogMessage>> {
     info!("Saving {} logs to db", logs_to_persist.len());
-    db_client.batch_load("logstore", logs_to_persist).await
+    store.persist_logs(logs_to_persist).await
 }
 
 #[cfg(test)]
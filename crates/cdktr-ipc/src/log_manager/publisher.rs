@@ -46,6 +46,22 @@ impl<'a, 'b> TaskLogger<'a, 'b> {
             .pub_msg("ERROR", self.task_name, self.task_instance_id, msg)
             .await
     }
+
+    pub async fn debug(&mut self, msg: &str) {
+        self.publisher
+            .pub_msg("DEBUG", self.task_name, self.task_instance_id, msg)
+            .await
+    }
+
+    /// Publishes a message at the given level. Trace messages are published as DEBUG
+    pub async fn log(&mut self, level: log::Level, msg: &str) {
+        match level {
+            log::Level::Error => self.error(msg).await,
+            log::Level::Warn => self.warn(msg).await,
+            log::Level::Info => self.info(msg).await,
+            log::Level::Debug | log::Level::Trace => self.debug(msg).await,
+        }
+    }
 }
 
 pub struct LogsPublisher {
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+use cdktr_core::exceptions::GenericError;
+
+/// Largest chunk of an artifact sent in a single message, before base64 encoding
+pub const ARTIFACT_CHUNK_BYTES: usize = 256 * 1024;
+
+/// Files produced by the tasks of running workflows, held by the principal so that tasks
+/// later in the same run can consume them on whichever agent they run. Artifacts are
+/// kept in memory and dropped as soon as their workflow run finishes
+pub struct ArtifactStore {
+    /// Limit on the size of a single artifact
+    max_bytes: usize,
+    /// Limit on the size of all the artifacts held, across every run
+    max_total_bytes: usize,
+    total_bytes: usize,
+    /// Maps workflow_instance_id to the artifacts of that run by path
+    artifacts: HashMap<String, HashMap<String, Vec<u8>>>,
+}
+
+impl ArtifactStore {
+    pub fn new(max_bytes: usize, max_total_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            max_total_bytes,
+            total_bytes: 0,
+            artifacts: HashMap::new(),
+        }
+    }
+
+    /// Appends a chunk to an artifact. Chunks must arrive in order and a chunk at
+    /// offset 0 starts the artifact over, so a task that is run again replaces
+    /// what it produced before
+    pub fn put_chunk(
+        &mut self,
+        workflow_instance_id: &str,
+        path: &str,
+        offset: usize,
+        chunk: &[u8],
+    ) -> Result<(), GenericError> {
+        let run_artifacts = self
+            .artifacts
+            .entry(workflow_instance_id.to_string())
+            .or_default();
+        if offset == 0
+            && let Some(previous) = run_artifacts.insert(path.to_string(), Vec::new())
+        {
+            self.total_bytes -= previous.len();
+        }
+        let artifact = match run_artifacts.get_mut(path) {
+            Some(artifact) if artifact.len() == offset => artifact,
+            Some(artifact) => {
+                return Err(GenericError::RuntimeError(format!(
+                    "Expected the chunk of artifact {} at offset {} but got offset {}",
+                    path,
+                    artifact.len(),
+                    offset
+                )));
+            }
+            None => {
+                return Err(GenericError::RuntimeError(format!(
+                    "Artifact {} must be uploaded from offset 0",
+                    path
+                )));
+            }
+        };
+        let error = if artifact.len() + chunk.len() > self.max_bytes {
+            format!(
+                "Artifact {} is larger than the limit of {} bytes",
+                path, self.max_bytes
+            )
+        } else if self.total_bytes + chunk.len() > self.max_total_bytes {
+            format!(
+                "Artifact {} doesn't fit in the artifact store, which is limited to {} bytes",
+                path, self.max_total_bytes
+            )
+        } else {
+            artifact.extend_from_slice(chunk);
+            self.total_bytes += chunk.len();
+            return Ok(());
+        };
+        // a partial artifact is no use to a consumer
+        if let Some(partial) = run_artifacts.remove(path) {
+            self.total_bytes -= partial.len();
+        }
+        Err(GenericError::RuntimeError(error))
+    }
+
+    /// Gets the chunk of an artifact starting at an offset. The chunk is empty once the
+    /// offset reaches the end of the artifact and `None` if the artifact doesn't exist
+    pub fn get_chunk(
+        &self,
+        workflow_instance_id: &str,
+        path: &str,
+        offset: usize,
+    ) -> Option<&[u8]> {
+        let artifact = self.artifacts.get(workflow_instance_id)?.get(path)?;
+        let start = offset.min(artifact.len());
+        let end = (start + ARTIFACT_CHUNK_BYTES).min(artifact.len());
+        Some(&artifact[start..end])
+    }
+
+    /// Drops the artifacts of a finished workflow run
+    pub fn clear(&mut self, workflow_instance_id: &str) {
+        if let Some(run_artifacts) = self.artifacts.remove(workflow_instance_id) {
+            self.total_bytes -= run_artifacts.values().map(Vec::len).sum::<usize>();
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use cdktr_core::get_cdktr_setting;
+use cdktr_core::models::AgentMeta;
+use log::warn;
+
+/// What the principal does when an agent registers with the id of another live agent,
+/// from `CDKTR_AGENT_ID_COLLISION`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AgentIdCollision {
+    /// The new agent is refused and the registered one keeps the id
+    #[default]
+    Reject,
+    /// The new agent takes over the id after a warning is logged
+    Warn,
+}
+
+impl AgentIdCollision {
+    pub fn from_config() -> Self {
+        let setting = get_cdktr_setting!(CDKTR_AGENT_ID_COLLISION);
+        match setting.to_lowercase().as_str() {
+            "reject" => Self::Reject,
+            "warn" => Self::Warn,
+            _ => {
+                warn!(
+                    "Invalid CDKTR_AGENT_ID_COLLISION '{setting}' - agents registering with the id of a live agent will be rejected"
+                );
+                Self::Reject
+            }
+        }
+    }
+}
+
+/// Whether a registration with `session` comes from a different process than the agent
+/// registered with the same id, while that agent is still sending heartbeats. Agents that
+/// don't send a session can't be told apart so never collide, and an agent that has
+/// stopped sending heartbeats is taken to have been restarted
+pub fn is_collision(
+    registered: &AgentMeta,
+    session: Option<&String>,
+    now_micros: i64,
+    timeout_micros: i64,
+) -> bool {
+    match (registered.session(), session) {
+        (Some(registered_session), Some(session)) => {
+            registered_session != session
+                && now_micros - registered.get_last_ping_ts() <= timeout_micros
+        }
+        _ => false,
+    }
+}
+
+/// Message a rejected agent is sent back, telling it how to register with a unique id
+pub fn collision_message(agent_id: &str) -> String {
+    format!(
+        "agent id collision: another agent is already registered as {agent_id} and is still sending heartbeats. Start each agent on a host with a unique --suffix, or wait for CDKTR_AGENT_HEARTBEAT_TIMEOUT_MS if the agent was restarted"
+    )
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use cdktr_api::AgentAPI;
+use cdktr_api::models::ClientResponseMessage;
+use cdktr_core::get_cdktr_setting;
+use cdktr_core::utils::data_structures::AsyncQueue;
+use cdktr_core::zmq_helpers::get_server_tcp_uri;
+use cdktr_workflow::Workflow;
+use log::{info, warn};
+
+use super::helpers;
+use super::reservations::AgentReservations;
+use super::singletons::SingletonRuns;
+use crate::store::StatusStore;
+
+/// How queued workflows reach agents, from `CDKTR_DISPATCH_MODE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DispatchMode {
+    /// Agents fetch their work from the principal
+    #[default]
+    Pull,
+    /// The principal sends each run to an agent as soon as it is queued
+    Push,
+}
+
+impl DispatchMode {
+    pub fn from_config() -> Self {
+        let setting = get_cdktr_setting!(CDKTR_DISPATCH_MODE);
+        match setting.to_lowercase().as_str() {
+            "pull" => Self::Pull,
+            "push" => Self::Push,
+            _ => {
+                warn!("Invalid CDKTR_DISPATCH_MODE '{setting}' - agents will fetch their work");
+                Self::Pull
+            }
+        }
+    }
+}
+
+/// Address an agent in push mode listens on, as it is reported to the principal
+pub fn agent_push_address() -> String {
+    get_server_tcp_uri(
+        &get_cdktr_setting!(CDKTR_AGENT_PUSH_HOST),
+        get_cdktr_setting!(CDKTR_AGENT_PUSH_PORT, usize),
+    )
+}
+
+/// Sends a run taken off the queue to the agent listening on `address`. Runs the agent
+/// can't be reached for or refuses are put back at the front of the queue
+pub async fn push_workflow(
+    agent_id: String,
+    address: String,
+    workflow: Workflow,
+    store: Arc<dyn StatusStore>,
+    mut task_queue: AsyncQueue<Workflow>,
+    singletons: SingletonRuns,
+    reservations: AgentReservations,
+) {
+    let reason = match AgentAPI::Run(workflow.to_string()).send_to(&address).await {
+        Ok(ClientResponseMessage::Success) => {
+            info!("Pushed workflow {} to agent {}", workflow.name(), agent_id);
+            return;
+        }
+        Ok(other) => other.payload(),
+        Err(e) => e.to_string(),
+    };
+    warn!(
+        "Failed to push workflow {} to agent {}: {} - queueing it again",
+        workflow.name(),
+        agent_id,
+        reason
+    );
+    helpers::requeue_workflow(
+        store.as_ref(),
+        &mut task_queue,
+        &singletons,
+        &reservations,
+        workflow,
+    )
+    .await;
+}
@@ -1,18 +1,40 @@
-use std::collections::HashSet;
-use std::time::SystemTime;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
 
-use cdktr_api::models::{AgentInfo, ClientResponseMessage, TaskStatusUpdate, WorkflowStatusUpdate};
+/// API module to provide all of the principal message handling
+/// utilities
+///
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use cdktr_api::PrincipalAPI;
+use cdktr_api::models::{
+    AgentInfo, AgentMetrics, ArtifactInfo, AuditEntry, ClientResponseMessage, ClusterCapacity,
+    DispatchDiagnosis, LogPage, TaskStatusUpdate, WorkflowStatusUpdate,
+};
 use cdktr_core::{
+    config,
     exceptions::GenericError,
-    models::RunStatus,
-    utils::data_structures::{AgentPriorityQueue, AsyncQueue},
+    models::{AgentMeta, RunStatus, TaskInstanceId, WorkflowInstanceId},
+    utils::data_structures::{AgentPriorityQueue, AsyncQueue, QueueMetrics},
 };
-use cdktr_db::DBClient;
 use cdktr_workflow::{Workflow, WorkflowStore};
-/// API module to provide all of the principal message handling
-/// utilities
-///
-use log::{info, trace};
+use log::{error, info, trace, warn};
+use serde::Serialize;
+
+use super::artifacts::ArtifactStore;
+use super::reservations::AgentReservations;
+use super::retries::WorkflowRetries;
+use super::singletons::SingletonRuns;
+use crate::log_manager::read_logs;
+use crate::store::StatusStore;
+
+/// Number of trailing output lines included for each task in a workflow result
+const WORKFLOW_RESULT_OUTPUT_TAIL_LINES: usize = 20;
+
+/// Longest annotation key accepted, in characters
+const MAX_ANNOTATION_KEY_LEN: usize = 64;
+
+/// Longest annotation value accepted, in characters
+const MAX_ANNOTATION_VALUE_LEN: usize = 1024;
 
 pub async fn handle_list_workflows(workflows: &WorkflowStore) -> (ClientResponseMessage, usize) {
     (
@@ -22,11 +44,13 @@ pub async fn handle_list_workflows(workflows: &WorkflowStore) -> (ClientResponse
 }
 
 pub async fn handle_agent_task_status_update(
-    db_client: DBClient,
+    store: &dyn StatusStore,
     task_id: String,
-    task_instance_id: String,
-    workflow_instance_id: String,
+    task_instance_id: TaskInstanceId,
+    workflow_instance_id: WorkflowInstanceId,
     status: RunStatus,
+    exit_code: Option<i32>,
+    truncated: bool,
 ) -> (ClientResponseMessage, usize) {
     let item = TaskStatusUpdate::new(
         task_id,
@@ -38,8 +62,7 @@ pub async fn handle_agent_task_status_update(
             .unwrap()
             .as_millis() as u64,
     );
-    let batch = vec![item];
-    match db_client.batch_load("task_run_status", batch).await {
+    match store.record_task_status(item, exit_code, truncated).await {
         Ok(()) => (ClientResponseMessage::Success, 0),
         Err(e) => (
             ClientResponseMessage::ServerError(format!("Failed to update task statuses: {:?}", e)),
@@ -48,10 +71,158 @@ pub async fn handle_agent_task_status_update(
     }
 }
 
+pub async fn handle_task_progress(
+    store: &dyn StatusStore,
+    task_instance_id: TaskInstanceId,
+    percent: u8,
+    message: String,
+) -> (ClientResponseMessage, usize) {
+    match store
+        .record_task_progress(&task_instance_id, percent, &message)
+        .await
+    {
+        Ok(()) => (ClientResponseMessage::Success, 0),
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!("Failed to record task progress: {:?}", e)),
+            0,
+        ),
+    }
+}
+
+pub async fn handle_task_artifacts(
+    store: &dyn StatusStore,
+    task_instance_id: TaskInstanceId,
+    artifacts: Vec<ArtifactInfo>,
+) -> (ClientResponseMessage, usize) {
+    match store
+        .record_task_artifacts(&task_instance_id, &artifacts)
+        .await
+    {
+        Ok(()) => (ClientResponseMessage::Success, 0),
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!("Failed to record task artifacts: {:?}", e)),
+            0,
+        ),
+    }
+}
+
+/// The action, target and actor an administrative request is recorded in the audit log
+/// under. None for requests that aren't audited
+pub fn audited_action(cli_msg: &PrincipalAPI) -> Option<(&'static str, String, Option<String>)> {
+    match cli_msg {
+        PrincipalAPI::DrainAgent(agent_id, true, actor) => {
+            Some(("DrainAgent", agent_id.clone(), actor.clone()))
+        }
+        PrincipalAPI::DrainAgent(agent_id, false, actor) => {
+            Some(("UndrainAgent", agent_id.clone(), actor.clone()))
+        }
+        PrincipalAPI::StopAgent(agent_id, actor) => {
+            Some(("StopAgent", agent_id.clone(), actor.clone()))
+        }
+        PrincipalAPI::FlushQueue(actor) => Some(("FlushQueue", String::new(), actor.clone())),
+        PrincipalAPI::ReloadConfig(actor) => Some(("ReloadConfig", String::new(), actor.clone())),
+        _ => None,
+    }
+}
+
+/// Records an administrative action in the audit log. Failing to record it is logged
+/// rather than failing the action, which has already been taken
+pub async fn record_audit_entry(
+    store: &dyn StatusStore,
+    actor: Option<String>,
+    client: &str,
+    action: &str,
+    target: String,
+    response: &ClientResponseMessage,
+) {
+    let entry = AuditEntry {
+        timestamp_ms: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64,
+        actor: actor.unwrap_or_else(|| "unknown".to_string()),
+        action: action.to_string(),
+        target,
+        result: response.code().to_string(),
+        client: client.to_string(),
+    };
+    info!(
+        "AUDIT action={} target={} actor={} client={} result={}",
+        entry.action, entry.target, entry.actor, entry.client, entry.result
+    );
+    if let Err(e) = store.record_audit_entry(entry).await {
+        error!("Failed to record {action} in the audit log: {e:?}");
+    }
+}
+
+pub async fn handle_get_audit_log(
+    store: &dyn StatusStore,
+    limit: usize,
+) -> (ClientResponseMessage, usize) {
+    match store.read_audit_log(limit).await {
+        Ok(entries) => match serde_json::to_string(&entries) {
+            Ok(json) => (ClientResponseMessage::SuccessWithPayload(json), 0),
+            Err(e) => (
+                ClientResponseMessage::ServerError(format!(
+                    "Failed to serialize audit log: {:?}",
+                    e
+                )),
+                0,
+            ),
+        },
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!("Failed to read audit log: {:?}", e)),
+            0,
+        ),
+    }
+}
+
+pub async fn handle_workflow_progress(
+    store: &dyn StatusStore,
+    workflow_instance_id: WorkflowInstanceId,
+    tasks_completed: usize,
+    tasks_total: usize,
+) -> (ClientResponseMessage, usize) {
+    match store
+        .record_workflow_progress(&workflow_instance_id, tasks_completed, tasks_total)
+        .await
+    {
+        Ok(()) => (ClientResponseMessage::Success, 0),
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!(
+                "Failed to record workflow progress: {:?}",
+                e
+            )),
+            0,
+        ),
+    }
+}
+
+pub async fn handle_workflow_outputs(
+    store: &dyn StatusStore,
+    workflow_id: String,
+    workflow_instance_id: WorkflowInstanceId,
+    outputs: HashMap<String, String>,
+) -> (ClientResponseMessage, usize) {
+    match store
+        .record_workflow_outputs(&workflow_id, &workflow_instance_id, &outputs)
+        .await
+    {
+        Ok(()) => (ClientResponseMessage::Success, 0),
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!(
+                "Failed to record workflow outputs: {:?}",
+                e
+            )),
+            0,
+        ),
+    }
+}
+
 pub async fn handle_agent_workflow_status_update(
-    db_client: DBClient,
+    store: &dyn StatusStore,
     workflow_id: String,
-    workflow_instance_id: String,
+    workflow_instance_id: WorkflowInstanceId,
     status: RunStatus,
 ) -> (ClientResponseMessage, usize) {
     let item = WorkflowStatusUpdate::new(
@@ -63,8 +234,7 @@ pub async fn handle_agent_workflow_status_update(
             .unwrap()
             .as_millis() as u64,
     );
-    let batch = vec![item];
-    match db_client.batch_load("workflow_run_status", batch).await {
+    match store.record_workflow_statuses(vec![item]).await {
         Ok(()) => (ClientResponseMessage::Success, 0),
         Err(e) => (
             ClientResponseMessage::ServerError(format!(
@@ -76,61 +246,64 @@ pub async fn handle_agent_workflow_status_update(
     }
 }
 
+/// Records whether a finished workflow run took longer than the SLA of its workflow.
+/// The duration comes from the persisted start and finish timestamps of the run so
+/// it isn't thrown off by delays on the agent. Returns None if the run's duration
+/// isn't known
+pub async fn record_workflow_sla(
+    store: &dyn StatusStore,
+    workflow_instance_id: &WorkflowInstanceId,
+    sla: Duration,
+) -> Result<Option<bool>, GenericError> {
+    let duration_ms = match store.get_workflow_duration_ms(workflow_instance_id).await? {
+        Some(duration_ms) => duration_ms,
+        None => return Ok(None),
+    };
+    let sla_ms = sla.as_millis() as u64;
+    let breached = duration_ms > sla_ms as i64;
+    store
+        .record_sla_result(workflow_instance_id, sla_ms, breached)
+        .await?;
+    Ok(Some(breached))
+}
+
 /// handler to get the latest status updates for the 10 most recent workflows
-pub async fn handle_get_recent_workflow_statuses(
-    db_client: DBClient,
+/// Reads the logs matching the query as records, which clients format with their own
+/// timestamp settings
+pub async fn handle_query_logs(
+    store: &dyn StatusStore,
+    end_ts: Option<u64>,
+    start_ts: Option<u64>,
+    workflow_id: Option<String>,
+    workflow_instance_id: Option<WorkflowInstanceId>,
+    page: Option<LogPage>,
 ) -> (ClientResponseMessage, usize) {
-    // Query to get the latest status for each workflow, limited to 10 most recent
-    // Using a window function to get the latest record per object_id
-    let query = "
-        WITH ranked_statuses AS (
-            SELECT
-                workflow_id,
-                workflow_instance_id,
-                status,
-                timestamp_ms,
-                ROW_NUMBER() OVER (PARTITION BY workflow_id ORDER BY timestamp_ms DESC) as rn
-            FROM workflow_run_status
-        )
-        SELECT
-            workflow_id,
-            workflow_instance_id,
-            CAST(status AS VARCHAR) as status,
-            timestamp_ms
-        FROM ranked_statuses
-        WHERE rn = 1
-        ORDER BY timestamp_ms DESC
-        LIMIT 10
-    ";
-
-    let result = {
-        let locked_client = db_client.lock_inner_client().await;
-        let mut stmt = match locked_client.prepare(query) {
-            Ok(s) => s,
-            Err(e) => {
-                return (
-                    ClientResponseMessage::ServerError(format!("Failed to prepare query: {:?}", e)),
-                    0,
-                );
-            }
-        };
-
-        stmt.query_map([], |row| {
-            Ok(WorkflowStatusUpdate::new(
-                row.get(0)?,
-                row.get(1)?,
-                row.get(2)?,
-                row.get(3)?,
-            ))
-        })
-        .map_err(|e| GenericError::DBError(e.to_string()))
-        .and_then(|rows| {
-            rows.map(|r| r.map_err(|e| GenericError::DBError(e.to_string())))
-                .collect::<Result<Vec<WorkflowStatusUpdate>, GenericError>>()
-        })
-    };
+    info!("Fetching logs");
+    let logs_result = read_logs(
+        store,
+        start_ts,
+        end_ts,
+        workflow_id,
+        workflow_instance_id,
+        page,
+    )
+    .await;
+    let serialized = logs_result.and_then(|logs| {
+        serde_json::to_string(&logs).map_err(|e| GenericError::ParseError(e.to_string()))
+    });
+    match serialized {
+        Ok(str_result) => (ClientResponseMessage::SuccessWithPayload(str_result), 0),
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!("Failed to read logs from db: {}", e)),
+            0,
+        ),
+    }
+}
 
-    match result {
+pub async fn handle_get_recent_workflow_statuses(
+    store: &dyn StatusStore,
+) -> (ClientResponseMessage, usize) {
+    match store.get_recent_workflow_statuses(10).await {
         Ok(status_updates) => match serde_json::to_string(&status_updates) {
             Ok(json) => (ClientResponseMessage::SuccessWithPayload(json), 0),
             Err(e) => (
@@ -148,20 +321,400 @@ pub async fn handle_get_recent_workflow_statuses(
     }
 }
 
+/// handler to get the aggregated result of a workflow run from the persisted status
+/// and log history. Returns a client error if the instance id has never been seen
+pub async fn handle_get_workflow_result(
+    store: &dyn StatusStore,
+    workflow_instance_id: WorkflowInstanceId,
+) -> (ClientResponseMessage, usize) {
+    match store
+        .get_workflow_result(&workflow_instance_id, WORKFLOW_RESULT_OUTPUT_TAIL_LINES)
+        .await
+    {
+        Ok(Some(result)) => match serde_json::to_string(&result) {
+            Ok(json) => (ClientResponseMessage::SuccessWithPayload(json), 0),
+            Err(e) => (
+                ClientResponseMessage::ServerError(format!(
+                    "Failed to serialize workflow result: {:?}",
+                    e
+                )),
+                0,
+            ),
+        },
+        Ok(None) => (
+            ClientResponseMessage::NotFound(format!(
+                "No workflow run found with instance id {}",
+                workflow_instance_id
+            )),
+            0,
+        ),
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!("Database query failed: {:?}", e)),
+            0,
+        ),
+    }
+}
+
+pub async fn handle_get_workflow_tail(
+    store: &dyn StatusStore,
+    workflow_instance_id: WorkflowInstanceId,
+    n: usize,
+) -> (ClientResponseMessage, usize) {
+    match store.get_workflow_id(&workflow_instance_id).await {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            return (
+                ClientResponseMessage::NotFound(format!(
+                    "No workflow run found with instance id {}",
+                    workflow_instance_id
+                )),
+                0,
+            );
+        }
+        Err(e) => {
+            return (
+                ClientResponseMessage::ServerError(format!("Database query failed: {:?}", e)),
+                0,
+            );
+        }
+    }
+    match store.read_log_tail(&workflow_instance_id, n).await {
+        Ok(logs) => {
+            let lines: Vec<String> = logs.iter().map(|l| l.format()).collect();
+            match serde_json::to_string(&lines) {
+                Ok(json) => (ClientResponseMessage::SuccessWithPayload(json), 0),
+                Err(e) => (
+                    ClientResponseMessage::ServerError(format!(
+                        "Failed to serialize log tail: {:?}",
+                        e
+                    )),
+                    0,
+                ),
+            }
+        }
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!("Failed to read logs from db: {:?}", e)),
+            0,
+        ),
+    }
+}
+
+/// handler to get the current size and throughput of the task queue so that
+/// a backlog building up without healthy agents can be spotted early
+/// Metrics of the task queue along with the number of runs that breached their SLA
+/// since the principal started
+#[derive(Serialize)]
+struct QueueMetricsPayload {
+    #[serde(flatten)]
+    queue: QueueMetrics,
+    sla_breaches: u64,
+}
+
+pub async fn handle_get_queue_metrics<T>(
+    task_queue: &AsyncQueue<T>,
+    sla_breaches: u64,
+) -> (ClientResponseMessage, usize) {
+    let payload = QueueMetricsPayload {
+        queue: task_queue.metrics().await,
+        sla_breaches,
+    };
+    match serde_json::to_string(&payload) {
+        Ok(json) => (ClientResponseMessage::SuccessWithPayload(json), 0),
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!(
+                "Failed to serialize queue metrics: {:?}",
+                e
+            )),
+            0,
+        ),
+    }
+}
+
+/// handler to remove every workflow waiting on the queue. The flushed workflows are logged
+/// so there's a record of what was dropped
+pub async fn handle_flush_queue(
+    store: &dyn StatusStore,
+    task_queue: &mut AsyncQueue<Workflow>,
+    retries: &mut WorkflowRetries,
+) -> (ClientResponseMessage, usize) {
+    let flushed = task_queue.drain().await;
+    for workflow in flushed.iter() {
+        if let Some(instance_id) = workflow.instance_id() {
+            retries.forget(instance_id);
+            unpersist_queued_workflow(store, instance_id).await;
+        }
+        warn!(
+            "Flushed workflow {} ({}) from the queue",
+            workflow.id(),
+            workflow.name()
+        );
+    }
+    info!("Flushed {} workflow(s) from the queue", flushed.len());
+    (
+        ClientResponseMessage::SuccessWithPayload(flushed.len().to_string()),
+        0,
+    )
+}
+
+/// handler to reload the config of the principal. Each changed setting is logged, with a
+/// warning for those that only take effect after a restart
+pub fn handle_reload_config() -> (ClientResponseMessage, usize) {
+    let changes = match config::reload_config() {
+        Ok(changes) => changes,
+        Err(e) => {
+            warn!(
+                "Failed to reload config - keeping the running config: {}",
+                e
+            );
+            return (ClientResponseMessage::ClientError(e.to_string()), 0);
+        }
+    };
+    for change in changes.iter() {
+        if change.applied {
+            info!(
+                "Reloaded {}: {} -> {}",
+                change.setting, change.old, change.new
+            );
+        } else {
+            warn!(
+                "{} changed from {} to {} but needs a restart to take effect",
+                change.setting, change.old, change.new
+            );
+        }
+    }
+    info!("Reloaded config with {} changed setting(s)", changes.len());
+    match serde_json::to_string(&changes) {
+        Ok(payload) => (ClientResponseMessage::SuccessWithPayload(payload), 0),
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!(
+                "Failed to serialise config changes: {}",
+                e
+            )),
+            0,
+        ),
+    }
+}
+
+/// handler to get the aggregate capacity of the agents and the number of queued
+/// workflows. Kept cheap as autoscalers poll it: neither the agent heap nor the
+/// queue contents are walked
+pub async fn handle_get_cluster_capacity<T>(
+    live_agents: &AgentPriorityQueue,
+    task_queue: &AsyncQueue<T>,
+) -> (ClientResponseMessage, usize) {
+    let (agents, total_slots, used_slots) = live_agents.capacity().await;
+    let capacity = ClusterCapacity {
+        agents,
+        total_slots,
+        used_slots,
+        queued: task_queue.size().await,
+    };
+    match serde_json::to_string(&capacity) {
+        Ok(json) => (ClientResponseMessage::SuccessWithPayload(json), 0),
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!(
+                "Failed to serialize cluster capacity: {:?}",
+                e
+            )),
+            0,
+        ),
+    }
+}
+
+/// Explains why a run hasn't been sent to an agent yet by checking it against the same
+/// constraints as `take_workflow`. Runs that aren't queued report their latest status
+pub async fn handle_diagnose_dispatch(
+    store: &dyn StatusStore,
+    task_queue: &AsyncQueue<Workflow>,
+    live_agents: &AgentPriorityQueue,
+    singletons: &SingletonRuns,
+    reservations: &AgentReservations,
+    workflow_instance_id: &WorkflowInstanceId,
+) -> (ClientResponseMessage, usize) {
+    let queued = task_queue.snapshot().await;
+    let agents = live_agents.get_all_agents().await;
+    let position = queued.iter().position(|workflow| {
+        workflow
+            .instance_id()
+            .is_some_and(|id| id == workflow_instance_id.as_str())
+    });
+    let diagnosis = match position {
+        Some(ix) => diagnose_queued_run(&queued[ix], ix, &agents, singletons, reservations),
+        None => match store.get_workflow_result(workflow_instance_id, 0).await {
+            Ok(Some(result)) => DispatchDiagnosis {
+                workflow_id: result.workflow_id,
+                workflow_instance_id: workflow_instance_id.to_string(),
+                agents: agents.len(),
+                reasons: vec![format!(
+                    "The run isn't queued - its latest status is {}",
+                    result.status
+                )],
+                status: Some(result.status),
+                ..Default::default()
+            },
+            Ok(None) => {
+                return (
+                    ClientResponseMessage::NotFound(format!(
+                        "No workflow run found with instance id {}",
+                        workflow_instance_id
+                    )),
+                    0,
+                );
+            }
+            Err(e) => {
+                return (
+                    ClientResponseMessage::ServerError(format!("Database query failed: {:?}", e)),
+                    0,
+                );
+            }
+        },
+    };
+    let diagnosis = DispatchDiagnosis {
+        queue_length: queued.len(),
+        ..diagnosis
+    };
+    match serde_json::to_string(&diagnosis) {
+        Ok(json) => (ClientResponseMessage::SuccessWithPayload(json), 0),
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!(
+                "Failed to serialize dispatch diagnosis: {:?}",
+                e
+            )),
+            0,
+        ),
+    }
+}
+
+fn diagnose_queued_run(
+    workflow: &Workflow,
+    ix: usize,
+    agents: &[AgentMeta],
+    singletons: &SingletonRuns,
+    reservations: &AgentReservations,
+) -> DispatchDiagnosis {
+    let mut reasons = Vec::new();
+    let matching: Vec<&AgentMeta> = agents
+        .iter()
+        .filter(|agent| can_run(agent, workflow))
+        .collect();
+    let missing_executors: Vec<String> = workflow
+        .executors()
+        .into_iter()
+        .filter(|executor| {
+            !agents.is_empty() && !agents.iter().any(|agent| agent.supports_executor(executor))
+        })
+        .map(str::to_string)
+        .collect();
+    if agents.is_empty() {
+        reasons.push("No agents are registered".to_string());
+    } else if !missing_executors.is_empty() {
+        reasons.push(format!(
+            "No registered agent runs the {} executor",
+            missing_executors.join(", ")
+        ));
+    } else if matching.is_empty() {
+        let executors: Vec<&str> = workflow.executors().into_iter().collect();
+        reasons.push(format!(
+            "No registered agent runs all of the {} executors",
+            executors.join(", ")
+        ));
+    }
+
+    let drained = matching.iter().filter(|agent| agent.is_drained()).count();
+    if drained > 0 {
+        reasons.push(format!("{drained} of the matching agents are drained"));
+    }
+    let full = matching
+        .iter()
+        .filter(|agent| {
+            !agent.is_drained()
+                && agent
+                    .max_concurrency()
+                    .is_some_and(|max| agent.utilisation() >= max)
+        })
+        .count();
+    if full > 0 {
+        reasons.push(format!(
+            "{full} of the matching agents are running as many workflows as they can"
+        ));
+    }
+    let reserved = matching
+        .iter()
+        .filter(|agent| !agent.is_drained() && !reservations.can_start(agent, workflow))
+        .count();
+    if reserved > 0 && workflow.exclusive_agent() {
+        reasons.push(format!(
+            "The workflow needs an agent to itself and {reserved} of the matching agents are running other workflows"
+        ));
+    } else if reserved > 0 {
+        reasons.push(format!(
+            "{reserved} of the matching agents are reserved by an exclusive workflow"
+        ));
+    }
+    let available_agents = matching
+        .iter()
+        .filter(|agent| {
+            !agent.is_drained()
+                && agent
+                    .max_concurrency()
+                    .is_none_or(|max| agent.utilisation() < max)
+                && reservations.can_start(agent, workflow)
+        })
+        .count();
+
+    let blocked_by = if singletons.can_start(workflow) {
+        None
+    } else {
+        singletons.running_instance(workflow.id())
+    };
+    if let Some(running) = &blocked_by {
+        reasons.push(format!(
+            "The workflow is a singleton and its run {running} is still running"
+        ));
+    }
+    match ix {
+        0 => (),
+        1 => reasons.push("1 run is ahead of it on the queue".to_string()),
+        ix => reasons.push(format!("{ix} runs are ahead of it on the queue")),
+    }
+    if reasons.is_empty() {
+        reasons.push(
+            "Nothing is holding it back - it is sent to the next agent that asks for work"
+                .to_string(),
+        );
+    }
+    DispatchDiagnosis {
+        workflow_id: workflow.id().clone(),
+        workflow_instance_id: workflow.instance_id().cloned().unwrap_or_default(),
+        queue_position: Some(ix + 1),
+        agents: agents.len(),
+        matching_agents: matching.len(),
+        available_agents,
+        missing_executors,
+        blocked_by,
+        reasons,
+        ..Default::default()
+    }
+}
+
 /// Handler to get all registered agents with their metadata
 pub async fn handle_get_registered_agents(
     live_agents: AgentPriorityQueue,
+    agent_metrics: &HashMap<String, AgentMetrics>,
 ) -> (ClientResponseMessage, usize) {
     let agents = live_agents.get_all_agents().await;
 
     let agent_infos: Vec<AgentInfo> = agents
         .into_iter()
         .map(|agent| {
+            let metrics = agent_metrics.get(&agent.agent_id()).cloned();
             AgentInfo::new(
                 agent.agent_id(),
                 agent.get_last_ping_ts(),
                 agent.utilisation(),
             )
+            .with_drained(agent.is_drained())
+            .with_metrics(metrics)
         })
         .collect();
 
@@ -174,36 +727,499 @@ pub async fn handle_get_registered_agents(
     }
 }
 
+/// handler to add or replace an annotation of a workflow run the principal has seen
+pub async fn handle_annotate_run(
+    store: &dyn StatusStore,
+    workflow_instance_id: &WorkflowInstanceId,
+    key: &str,
+    value: &str,
+) -> (ClientResponseMessage, usize) {
+    if key.trim().is_empty() {
+        return (
+            ClientResponseMessage::Unprocessable("Annotation key can't be empty".to_string()),
+            0,
+        );
+    }
+    if key.chars().count() > MAX_ANNOTATION_KEY_LEN {
+        return (
+            ClientResponseMessage::Unprocessable(format!(
+                "Annotation key is longer than {} characters",
+                MAX_ANNOTATION_KEY_LEN
+            )),
+            0,
+        );
+    }
+    if value.chars().count() > MAX_ANNOTATION_VALUE_LEN {
+        return (
+            ClientResponseMessage::Unprocessable(format!(
+                "Annotation value is longer than {} characters",
+                MAX_ANNOTATION_VALUE_LEN
+            )),
+            0,
+        );
+    }
+    match store.get_workflow_id(workflow_instance_id).await {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            return (
+                ClientResponseMessage::NotFound(format!(
+                    "No workflow run found with instance id {}",
+                    workflow_instance_id
+                )),
+                0,
+            );
+        }
+        Err(e) => {
+            return (
+                ClientResponseMessage::ServerError(format!("Database query failed: {:?}", e)),
+                0,
+            );
+        }
+    }
+    match store.annotate_run(workflow_instance_id, key, value).await {
+        Ok(()) => {
+            info!("Annotated run {workflow_instance_id} with {key}");
+            (ClientResponseMessage::Success, 0)
+        }
+        Err(e) => (
+            ClientResponseMessage::ServerError(format!("Failed to annotate run: {:?}", e)),
+            0,
+        ),
+    }
+}
+
+/// Stores a chunk of an artifact uploaded by an agent
+pub fn handle_put_artifact(
+    artifacts: &mut ArtifactStore,
+    workflow_instance_id: &WorkflowInstanceId,
+    path: &str,
+    offset: usize,
+    chunk: &str,
+) -> (ClientResponseMessage, usize) {
+    let chunk = match BASE64.decode(chunk) {
+        Ok(chunk) => chunk,
+        Err(e) => {
+            return (
+                ClientResponseMessage::Unprocessable(format!(
+                    "Artifact chunk is not valid base64: {}",
+                    e
+                )),
+                0,
+            );
+        }
+    };
+    match artifacts.put_chunk(workflow_instance_id.as_str(), path, offset, &chunk) {
+        Ok(()) => {
+            trace!(
+                "Stored {} bytes of artifact {} of run {}",
+                chunk.len(),
+                path,
+                workflow_instance_id
+            );
+            (ClientResponseMessage::Success, 0)
+        }
+        Err(e) => (ClientResponseMessage::Unprocessable(e.to_string()), 0),
+    }
+}
+
+/// Gets a chunk of an artifact for an agent to download, or an empty success once
+/// the whole artifact has been sent
+pub fn handle_get_artifact(
+    artifacts: &ArtifactStore,
+    workflow_instance_id: &WorkflowInstanceId,
+    path: &str,
+    offset: usize,
+) -> (ClientResponseMessage, usize) {
+    match artifacts.get_chunk(workflow_instance_id.as_str(), path, offset) {
+        Some([]) => (ClientResponseMessage::Success, 0),
+        Some(chunk) => (
+            ClientResponseMessage::SuccessWithPayload(BASE64.encode(chunk)),
+            0,
+        ),
+        None => (
+            ClientResponseMessage::NotFound(format!(
+                "No artifact {} found for workflow run {}",
+                path, workflow_instance_id
+            )),
+            0,
+        ),
+    }
+}
+
+/// Drains an agent or makes it schedulable again
+pub async fn handle_drain_agent(
+    live_agents: &AgentPriorityQueue,
+    agent_id: &str,
+    drained: bool,
+) -> (ClientResponseMessage, usize) {
+    match live_agents.set_drained(agent_id, drained).await {
+        Ok(()) => {
+            if drained {
+                info!("Draining agent {agent_id} - it won't be given new workflows");
+            } else {
+                info!("Agent {agent_id} is no longer drained");
+            }
+            (ClientResponseMessage::Success, 0)
+        }
+        Err(_e) => (
+            ClientResponseMessage::NotFound(format!("No agent registered with id {agent_id}")),
+            0,
+        ),
+    }
+}
+
+/// handler to record the latest metrics pushed by an agent. Metrics of agents that are
+/// no longer registered are dropped at the same time
+pub async fn handle_agent_metrics(
+    live_agents: &AgentPriorityQueue,
+    agent_metrics: &mut HashMap<String, AgentMetrics>,
+    agent_id: String,
+    metrics: AgentMetrics,
+) -> (ClientResponseMessage, usize) {
+    let registered: HashSet<String> = live_agents
+        .get_all_agents()
+        .await
+        .iter()
+        .map(|agent| agent.agent_id())
+        .collect();
+    agent_metrics.retain(|id, _| registered.contains(id));
+    if !registered.contains(&agent_id) {
+        return (
+            ClientResponseMessage::NotFound(format!("No agent registered with id {agent_id}")),
+            0,
+        );
+    }
+    trace!("Metrics from agent {agent_id}: {:?}", metrics);
+    agent_metrics.insert(agent_id, metrics);
+    (ClientResponseMessage::Success, 0)
+}
+
 /// handler for the principal to place a workflow task on the queue ready for pick-up by a worker
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_run_task(
     workflow_id: &str,
+    params: &HashMap<String, String>,
+    environment: Option<&str>,
     workflows: &WorkflowStore,
+    store: &dyn StatusStore,
     queue: &mut AsyncQueue<Workflow>,
+    retries: &mut WorkflowRetries,
+    dry_run: bool,
+    no_agents: bool,
 ) -> (ClientResponseMessage, usize) {
     let task_id = workflow_id.to_string();
     let wf_res = workflows.get(&workflow_id).await;
     if let Some(wf) = wf_res {
+        let wf = match environment.map(|environment| wf.for_environment(environment)) {
+            None => wf,
+            Some(Ok(wf)) => wf,
+            Some(Err(e)) => {
+                info!("Rejected run of workflow {}: {}", task_id, e);
+                return (ClientResponseMessage::Unprocessable(e.to_string()), 0);
+            }
+        };
+        // validate params before anything is queued so typos are caught up front
+        let wf = match wf.with_params(params) {
+            Ok(wf) => wf,
+            Err(e) => {
+                info!("Rejected run of workflow {}: {}", task_id, e);
+                return (ClientResponseMessage::Unprocessable(e.to_string()), 0);
+            }
+        };
+        let wf = if wf.outputs().is_empty() {
+            wf
+        } else {
+            // an incremental run must not silently fall back to the defaults
+            let last_run = match store.get_last_workflow_outputs(workflow_id).await {
+                Ok(last_run) => last_run,
+                Err(e) => {
+                    return (
+                        ClientResponseMessage::ServerError(format!(
+                            "Failed to read the outputs of the last run of workflow {}: {}",
+                            task_id, e
+                        )),
+                        0,
+                    );
+                }
+            };
+            match wf.with_last_run(&last_run) {
+                Ok(wf) => wf,
+                Err(e) => return (ClientResponseMessage::Unprocessable(e.to_string()), 0),
+            }
+        };
         info!("Staging task -> {}", &workflow_id);
-        queue.put(wf).await;
+        // a dry run can't fail so there's nothing to retry
+        let wf = if dry_run {
+            retries.assign_instance_id(wf.with_dry_run(true))
+        } else {
+            retries.track(wf)
+        };
+        if no_agents {
+            record_waiting_for_agents(store, &wf).await;
+        }
+        let instance_id = wf.instance_id().cloned();
+        enqueue_workflow(store, queue, wf).await;
         info!("Current task queue size: {}", queue.size().await);
-        (ClientResponseMessage::Success, 0)
+        // the instance id lets the caller follow the run
+        match instance_id {
+            Some(instance_id) => (ClientResponseMessage::SuccessWithPayload(instance_id), 0),
+            None => (ClientResponseMessage::Success, 0),
+        }
     } else {
         info!("No workflow found with id {}. Cannot stage task", task_id);
         (
-            ClientResponseMessage::ClientError(format!("No workflow exists with id {}", task_id)),
+            ClientResponseMessage::NotFound(format!("No workflow exists with id {}", task_id)),
             0,
         )
     }
 }
 
+/// Records a run queued while no agents are registered as WAITING so it shows up in the
+/// run history as held rather than missing until an agent registers and fetches it
+async fn record_waiting_for_agents(store: &dyn StatusStore, workflow: &Workflow) {
+    let Some(instance_id) = workflow.instance_id() else {
+        return;
+    };
+    info!(
+        "No agents are registered - run {} of workflow {} is held on the queue until one is",
+        instance_id,
+        workflow.id()
+    );
+    let update = WorkflowStatusUpdate::new(
+        workflow.id().clone(),
+        instance_id.clone().into(),
+        RunStatus::WAITING.to_string(),
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64,
+    );
+    if let Err(e) = store.record_workflow_statuses(vec![update]).await {
+        warn!(
+            "Failed to record run {} of workflow {} as waiting for agents: {}",
+            instance_id,
+            workflow.id(),
+            e
+        );
+    }
+}
+
+/// Persists a workflow run and places it on the queue. The run is still queued if it
+/// can't be persisted as losing it on a restart is better than not running it at all
+pub async fn enqueue_workflow(
+    store: &dyn StatusStore,
+    queue: &mut AsyncQueue<Workflow>,
+    workflow: Workflow,
+) {
+    if let Some(instance_id) = workflow.instance_id() {
+        let workflow_str = workflow.to_string();
+        if let Err(e) = store
+            .record_run_snapshot(&instance_id.as_str().into(), &workflow_str)
+            .await
+        {
+            warn!(
+                "Failed to record the snapshot of run {} of workflow {} - it can't be replayed: {}",
+                instance_id,
+                workflow.id(),
+                e
+            );
+        }
+        persist_queued_workflow(store, &workflow, instance_id, &workflow_str, false).await;
+    }
+    queue.put(workflow).await;
+}
+
+/// Persists a run placed on the queue. Failing to persist it is logged rather than
+/// failing the run, which is still queued in memory
+async fn persist_queued_workflow(
+    store: &dyn StatusStore,
+    workflow: &Workflow,
+    instance_id: &str,
+    workflow_str: &str,
+    front: bool,
+) {
+    if let Err(e) = store
+        .record_queued_workflow(&instance_id.into(), workflow_str, front)
+        .await
+    {
+        warn!(
+            "Failed to persist queued run {} of workflow {} - it won't survive a restart: {}",
+            instance_id,
+            workflow.id(),
+            e
+        );
+    }
+}
+
+/// Queues a past run again from the snapshot of the workflow it was queued with, so it
+/// runs with the same definition and params even if the workflow has changed since.
+/// Responds with the instance id of the new run
+pub async fn handle_replay_run(
+    store: &dyn StatusStore,
+    queue: &mut AsyncQueue<Workflow>,
+    retries: &mut WorkflowRetries,
+    workflow_instance_id: &WorkflowInstanceId,
+) -> (ClientResponseMessage, usize) {
+    let snapshot = match store.get_run_snapshot(workflow_instance_id).await {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => {
+            return (
+                ClientResponseMessage::NotFound(format!(
+                    "No snapshot of workflow run {} to replay",
+                    workflow_instance_id
+                )),
+                0,
+            );
+        }
+        Err(e) => {
+            return (
+                ClientResponseMessage::ServerError(format!(
+                    "Failed to read the snapshot of workflow run {}: {}",
+                    workflow_instance_id, e
+                )),
+                0,
+            );
+        }
+    };
+    let workflow = match Workflow::try_from(snapshot) {
+        Ok(workflow) => workflow,
+        Err(e) => {
+            return (
+                ClientResponseMessage::ServerError(format!(
+                    "Snapshot of workflow run {} could not be read: {}",
+                    workflow_instance_id, e
+                )),
+                0,
+            );
+        }
+    };
+    let replay = workflow.replay();
+    let replay = if replay.dry_run() {
+        retries.assign_instance_id(replay)
+    } else {
+        retries.track(replay)
+    };
+    let replay_instance_id =
+        WorkflowInstanceId::from(replay.instance_id().cloned().unwrap_or_default());
+    if let Err(e) = store
+        .annotate_run(
+            &replay_instance_id,
+            "replay_of",
+            workflow_instance_id.as_str(),
+        )
+        .await
+    {
+        warn!(
+            "Failed to tag run {} as a replay of {}: {}",
+            replay_instance_id, workflow_instance_id, e
+        );
+    }
+    info!(
+        "Replaying run {} of workflow {} as {}",
+        workflow_instance_id,
+        replay.id(),
+        replay_instance_id
+    );
+    enqueue_workflow(store, queue, replay).await;
+    (
+        ClientResponseMessage::SuccessWithPayload(replay_instance_id.into_inner()),
+        0,
+    )
+}
+
+/// Removes a run that has left the queue from the persisted queue
+async fn unpersist_queued_workflow(store: &dyn StatusStore, workflow_instance_id: &str) {
+    if let Err(e) = store
+        .remove_queued_workflow(&workflow_instance_id.into())
+        .await
+    {
+        warn!(
+            "Failed to remove run {} from the persisted queue: {}",
+            workflow_instance_id, e
+        );
+    }
+}
+
+/// Puts the runs left on the persisted queue by the last principal back on the queue.
+/// Returns the restored runs so those with a retry policy can be tracked again
+pub async fn restore_queued_workflows(
+    store: &dyn StatusStore,
+    queue: &mut AsyncQueue<Workflow>,
+) -> Result<Vec<Workflow>, GenericError> {
+    let mut restored = Vec::new();
+    for workflow_str in store.get_queued_workflows().await? {
+        match Workflow::try_from(workflow_str) {
+            Ok(workflow) => {
+                queue.put(workflow.clone()).await;
+                restored.push(workflow);
+            }
+            Err(e) => warn!("Skipping queued workflow that could not be restored: {}", e),
+        }
+    }
+    Ok(restored)
+}
+
+/// Whether the agent runs every executor the tasks of the workflow use
+pub fn can_run(agent: &AgentMeta, workflow: &Workflow) -> bool {
+    workflow
+        .executors()
+        .into_iter()
+        .all(|executor| agent.supports_executor(executor))
+}
+
+/// Sends the first run on the queue that can start to the agent. Runs of singleton
+/// workflows are left on the queue while another run of their workflow is running, and
+/// runs the agent has no executor for are left for other agents
+/// Takes the first run off the queue that the agent can run, if there is one. Runs of
+/// exclusive workflows are left on the queue until the agent is running nothing else
+pub async fn take_workflow(
+    store: &dyn StatusStore,
+    task_queue: &mut AsyncQueue<Workflow>,
+    singletons: &SingletonRuns,
+    reservations: &AgentReservations,
+    agent: &AgentMeta,
+) -> Option<Workflow> {
+    let task = task_queue
+        .get_first_where(|workflow| {
+            singletons.can_start(workflow)
+                && reservations.can_start(agent, workflow)
+                && can_run(agent, workflow)
+        })
+        .await?;
+    singletons.start(&task);
+    reservations.start(&agent.agent_id(), &task);
+    if let Some(instance_id) = task.instance_id() {
+        unpersist_queued_workflow(store, instance_id).await;
+    }
+    Some(task)
+}
+
+/// Puts a run that was taken off the queue but couldn't be sent to an agent back at
+/// the front of the queue, where it is also restored after a restart
+pub async fn requeue_workflow(
+    store: &dyn StatusStore,
+    task_queue: &mut AsyncQueue<Workflow>,
+    singletons: &SingletonRuns,
+    reservations: &AgentReservations,
+    workflow: Workflow,
+) {
+    if let Some(instance_id) = workflow.instance_id() {
+        singletons.release([instance_id]);
+        reservations.release([instance_id]);
+        persist_queued_workflow(store, &workflow, instance_id, &workflow.to_string(), true).await;
+    }
+    task_queue.put_front(workflow).await;
+}
+
 pub async fn handle_fetch_task(
+    store: &dyn StatusStore,
     task_queue: &mut AsyncQueue<Workflow>,
-    agent_id: String,
+    singletons: &SingletonRuns,
+    reservations: &AgentReservations,
+    agent: &AgentMeta,
 ) -> (ClientResponseMessage, usize) {
-    // TODO: do something with the agent ID like this agent is allowed to
-    // process this type of task
-    let task_res = task_queue.get().await;
-    if let Some(task) = task_res {
+    let agent_id = agent.agent_id();
+    if let Some(task) = take_workflow(store, task_queue, singletons, reservations, agent).await {
         info!(
             "Agent {agent_id} requested workflow | Sending workflow -> {}",
             task.name(),
@@ -221,7 +1237,7 @@ pub async fn handle_fetch_task(
 
 /// Helper function to batch update workflows to CRASHED status when agent dies
 pub async fn mark_workflows_as_crashed(
-    db_client: DBClient,
+    store: &dyn StatusStore,
     workflow_instance_ids: HashSet<String>,
 ) -> Result<(), GenericError> {
     if workflow_instance_ids.is_empty() {
@@ -234,29 +1250,11 @@ pub async fn mark_workflows_as_crashed(
         .as_millis() as u64;
 
     // We need to insert new status records for each workflow instance
-    // Since we don't have the workflow_id readily available, we'll query it first
-    // For each workflow_instance_id, find the latest workflow_id from existing records
-
+    // Since we don't have the workflow_id readily available, we'll look it up first
+    // from the existing records of each workflow_instance_id
     for wf_instance_id in workflow_instance_ids {
-        // Query to get the workflow_id for this instance
-        let query = format!(
-            "SELECT workflow_id FROM workflow_run_status
-             WHERE workflow_instance_id = '{}'
-             LIMIT 1",
-            wf_instance_id
-        );
-
-        let workflow_id: Option<String> = {
-            let lock = db_client.lock_inner_client().await;
-            let mut stmt = lock
-                .prepare(&query)
-                .map_err(|e| GenericError::DBError(e.to_string()))?;
-
-            let result: Option<String> = stmt.query_row([], |row| row.get(0)).ok();
-            drop(stmt);
-            drop(lock);
-            result
-        };
+        let wf_instance_id = WorkflowInstanceId::from(wf_instance_id);
+        let workflow_id = store.get_workflow_id(&wf_instance_id).await?;
 
         if let Some(wf_id) = workflow_id {
             let item = WorkflowStatusUpdate::new(
@@ -265,11 +1263,10 @@ pub async fn mark_workflows_as_crashed(
                 RunStatus::CRASHED.to_string(),
                 timestamp_ms,
             );
-            let batch = vec![item.clone()];
 
-            match db_client.batch_load("workflow_run_status", batch).await {
+            match store.record_workflow_statuses(vec![item]).await {
                 Ok(()) => {}
-                Err(_failed_batch) => {
+                Err(_e) => {
                     log::error!(
                         "Failed to mark workflow instance {} as CRASHED",
                         wf_instance_id
@@ -288,9 +1285,11 @@ pub async fn mark_workflows_as_crashed(
 #[cfg(test)]
 mod tests {
 
+    use cdktr_api::models::WorkflowResult;
     use cdktr_core::utils::data_structures::AsyncQueue;
 
     use super::*;
+    use crate::store::InMemoryStatusStore;
 
     #[test]
     fn test_handle_list_tasks_1_in_db() {
@@ -307,48 +1306,97 @@ mod tests {
         let mut task_queue: AsyncQueue<Workflow> = AsyncQueue::new();
         assert_eq!(task_queue.size().await, 0);
 
-        let (cli_msg, code) = handle_fetch_task(&mut task_queue, "1234".to_string()).await;
+        let (cli_msg, code) = handle_fetch_task(
+            &InMemoryStatusStore::new(),
+            &mut task_queue,
+            &SingletonRuns::new(),
+            &AgentReservations::new(),
+            &AgentMeta::new("1234".to_string(), 0),
+        )
+        .await;
 
         assert_eq!(task_queue.size().await, 0);
         assert_eq!(cli_msg, ClientResponseMessage::Success);
         assert_eq!(code, 0);
     }
 
+    #[tokio::test]
+    async fn test_requeued_run_restored_first() {
+        let store = InMemoryStatusStore::new();
+        let mut task_queue: AsyncQueue<Workflow> = AsyncQueue::new();
+        let workflow = Workflow::new(
+            "fake/path/simple.yml".to_string(),
+            r#"
+name: Simple
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )
+        .unwrap();
+        for instance_id in ["run-1", "run-2"] {
+            let run = workflow.clone().with_instance_id(instance_id.to_string());
+            enqueue_workflow(&store, &mut task_queue, run).await;
+        }
+        let (singletons, reservations) = (SingletonRuns::new(), AgentReservations::new());
+        let agent = AgentMeta::new("agent-1".to_string(), 0);
+        let taken = take_workflow(&store, &mut task_queue, &singletons, &reservations, &agent)
+            .await
+            .unwrap();
+        assert_eq!(taken.instance_id().unwrap(), "run-1");
+        requeue_workflow(&store, &mut task_queue, &singletons, &reservations, taken).await;
+
+        // the requeued run is restored at the front as it was on the queue
+        let mut restored_queue = AsyncQueue::new();
+        let restored = restore_queued_workflows(&store, &mut restored_queue)
+            .await
+            .unwrap();
+        let restored_ids: Vec<&str> = restored
+            .iter()
+            .map(|wf| wf.instance_id().unwrap().as_str())
+            .collect();
+        assert_eq!(restored_ids, vec!["run-1", "run-2"]);
+    }
+
     #[tokio::test]
     async fn test_get_recent_workflow_statuses() {
         use cdktr_core::models::RunStatus;
 
-        let db_client = DBClient::new(None).unwrap();
+        let store = InMemoryStatusStore::new();
 
         // Insert multiple workflow status updates
         let status_updates = vec![
             WorkflowStatusUpdate::new(
                 "workflow_1".to_string(),
-                "instance_1a".to_string(),
+                "instance_1a".into(),
                 RunStatus::RUNNING.to_string(),
                 1234567890_u64,
             ),
             WorkflowStatusUpdate::new(
                 "workflow_1".to_string(),
-                "instance_1b".to_string(),
+                "instance_1b".into(),
                 RunStatus::COMPLETED.to_string(),
                 1234567900_u64, // More recent
             ),
             WorkflowStatusUpdate::new(
                 "workflow_2".to_string(),
-                "instance_2a".to_string(),
+                "instance_2a".into(),
                 RunStatus::FAILED.to_string(),
                 1234567895_u64,
             ),
         ];
 
-        db_client
-            .batch_load("workflow_run_status", status_updates.clone())
+        store
+            .record_workflow_statuses(status_updates.clone())
             .await
             .expect("Failed to insert status updates");
 
         // Test retrieving recent statuses
-        let (response, code) = handle_get_recent_workflow_statuses(db_client.clone()).await;
+        let (response, code) = handle_get_recent_workflow_statuses(&store).await;
 
         assert_eq!(code, 0);
         match response {
@@ -380,9 +1428,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_recent_workflow_statuses_no_results() {
-        let db_client = DBClient::new(None).unwrap();
+        let store = InMemoryStatusStore::new();
 
-        let (response, code) = handle_get_recent_workflow_statuses(db_client).await;
+        let (response, code) = handle_get_recent_workflow_statuses(&store).await;
 
         assert_eq!(code, 0);
         match response {
@@ -394,24 +1442,279 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_get_workflow_result() {
+        use crate::log_manager::model::LogMessage;
+        use cdktr_core::models::RunStatus;
+
+        let store = InMemoryStatusStore::new();
+        handle_agent_workflow_status_update(
+            &store,
+            "wf".to_string(),
+            "wf-ins".into(),
+            RunStatus::RUNNING,
+        )
+        .await;
+        for (task_id, task_ins_id, final_status, exit_code, truncated) in [
+            ("task1", "task1-ins", RunStatus::COMPLETED, Some(0), false),
+            ("task2", "task2-ins", RunStatus::FAILED, Some(2), true),
+        ] {
+            for (status, code, truncated) in [
+                (RunStatus::RUNNING, None, false),
+                (final_status, exit_code, truncated),
+            ] {
+                let (resp, _) = handle_agent_task_status_update(
+                    &store,
+                    task_id.to_string(),
+                    task_ins_id.to_string().into(),
+                    "wf-ins".into(),
+                    status,
+                    code,
+                    truncated,
+                )
+                .await;
+                assert_eq!(resp, ClientResponseMessage::Success);
+            }
+        }
+        handle_agent_workflow_status_update(
+            &store,
+            "wf".to_string(),
+            "wf-ins".into(),
+            RunStatus::FAILED,
+        )
+        .await;
+        let logs: Vec<LogMessage> = (0..30)
+            .map(|i| {
+                LogMessage::new(
+                    "wf".to_string(),
+                    "Workflow".to_string(),
+                    "wf-ins".to_string(),
+                    "Task 1".to_string(),
+                    "task1-ins".to_string(),
+                    1_000 + i as u64,
+                    "INFO".to_string(),
+                    format!("STDOUT line {i}"),
+                )
+            })
+            .collect();
+        store.persist_logs(logs).await.unwrap();
+
+        let (response, code) = handle_get_workflow_result(&store, "wf-ins".into()).await;
+        assert_eq!(code, 0);
+        let result: WorkflowResult = match response {
+            ClientResponseMessage::SuccessWithPayload(payload) => {
+                serde_json::from_str(&payload).unwrap()
+            }
+            other => panic!("Expected SuccessWithPayload, got {:?}", other),
+        };
+        assert_eq!(result.workflow_id, "wf");
+        assert_eq!(result.status, RunStatus::FAILED.to_string());
+        assert_eq!(result.tasks.len(), 2);
+
+        let task1 = result.tasks.iter().find(|t| t.task_id == "task1").unwrap();
+        assert_eq!(task1.status, RunStatus::COMPLETED.to_string());
+        assert_eq!(task1.exit_code, Some(0));
+        assert!(task1.duration_ms.is_some());
+        assert_eq!(task1.output_tail.len(), WORKFLOW_RESULT_OUTPUT_TAIL_LINES);
+        assert_eq!(task1.output_tail.last().unwrap(), "STDOUT line 29");
+        assert!(!task1.truncated);
+
+        let task2 = result.tasks.iter().find(|t| t.task_id == "task2").unwrap();
+        assert_eq!(task2.status, RunStatus::FAILED.to_string());
+        assert_eq!(task2.exit_code, Some(2));
+        assert!(task2.output_tail.is_empty());
+        assert!(task2.truncated);
+    }
+
+    #[tokio::test]
+    async fn test_get_queue_metrics() {
+        let mut task_queue: AsyncQueue<i32> = AsyncQueue::new();
+        task_queue.put(1).await;
+        task_queue.put(2).await;
+        task_queue.get().await;
+
+        let (response, code) = handle_get_queue_metrics(&task_queue, 3).await;
+        assert_eq!(code, 0);
+        let payload = match response {
+            ClientResponseMessage::SuccessWithPayload(payload) => payload,
+            other => panic!("Expected SuccessWithPayload, got {:?}", other),
+        };
+        let metrics: QueueMetrics = serde_json::from_str(&payload).unwrap();
+        let sla_breaches =
+            serde_json::from_str::<serde_json::Value>(&payload).unwrap()["sla_breaches"].as_u64();
+        assert_eq!(sla_breaches, Some(3));
+        assert_eq!(metrics.size, 1);
+        assert_eq!(metrics.total_enqueued, 2);
+        assert_eq!(metrics.total_dequeued, 1);
+    }
+
+    #[tokio::test]
+    async fn test_record_workflow_sla() {
+        let store = InMemoryStatusStore::new();
+        // both runs take 2s going by the persisted timestamps
+        for wf_ins_id in ["slow-ins", "fast-ins"] {
+            store
+                .record_workflow_statuses(vec![
+                    WorkflowStatusUpdate::new(
+                        "wf".to_string(),
+                        wf_ins_id.to_string().into(),
+                        RunStatus::RUNNING.to_string(),
+                        1_000,
+                    ),
+                    WorkflowStatusUpdate::new(
+                        "wf".to_string(),
+                        wf_ins_id.to_string().into(),
+                        RunStatus::COMPLETED.to_string(),
+                        3_000,
+                    ),
+                ])
+                .await
+                .unwrap();
+        }
+
+        let breached = record_workflow_sla(&store, &"slow-ins".into(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(breached, Some(true));
+        let breached = record_workflow_sla(&store, &"fast-ins".into(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(breached, Some(false));
+
+        let slow = store
+            .get_workflow_result(&"slow-ins".into(), 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(slow.duration_ms, Some(2_000));
+        assert_eq!(slow.sla_breached, Some(true));
+        let fast = store
+            .get_workflow_result(&"fast-ins".into(), 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fast.sla_breached, Some(false));
+
+        // a run that never started has no duration to compare
+        store
+            .record_workflow_statuses(vec![WorkflowStatusUpdate::new(
+                "wf".to_string(),
+                "crashed-ins".into(),
+                RunStatus::CRASHED.to_string(),
+                3_000,
+            )])
+            .await
+            .unwrap();
+        let breached = record_workflow_sla(&store, &"crashed-ins".into(), Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(breached, None);
+        let crashed = store
+            .get_workflow_result(&"crashed-ins".into(), 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(crashed.sla_breached, None);
+    }
+
+    #[tokio::test]
+    async fn test_get_workflow_result_unknown_instance() {
+        let store = InMemoryStatusStore::new();
+        let (response, code) = handle_get_workflow_result(&store, "missing-ins".into()).await;
+        assert_eq!(code, 0);
+        assert!(matches!(response, ClientResponseMessage::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_annotate_run() {
+        use cdktr_core::models::RunStatus;
+
+        let store = InMemoryStatusStore::new();
+        handle_agent_workflow_status_update(
+            &store,
+            "wf".to_string(),
+            "wf-ins".into(),
+            RunStatus::FAILED,
+        )
+        .await;
+        for value in ["investigating", "investigating failure #123"] {
+            let (resp, _) = handle_annotate_run(&store, &"wf-ins".into(), "incident", value).await;
+            assert_eq!(resp, ClientResponseMessage::Success);
+        }
+        handle_annotate_run(&store, &"wf-ins".into(), "owner", "data-eng").await;
+
+        let (response, _) = handle_get_workflow_result(&store, "wf-ins".into()).await;
+        let result: WorkflowResult = match response {
+            ClientResponseMessage::SuccessWithPayload(payload) => {
+                serde_json::from_str(&payload).unwrap()
+            }
+            other => panic!("Expected SuccessWithPayload, got {:?}", other),
+        };
+        assert_eq!(
+            result.annotations,
+            HashMap::from([
+                (
+                    "incident".to_string(),
+                    "investigating failure #123".to_string()
+                ),
+                ("owner".to_string(), "data-eng".to_string()),
+            ])
+        );
+
+        let (resp, _) = handle_annotate_run(&store, &"missing-ins".into(), "incident", "x").await;
+        assert!(matches!(resp, ClientResponseMessage::NotFound(_)));
+        for (key, value) in [
+            (String::new(), "x".to_string()),
+            ("k".repeat(MAX_ANNOTATION_KEY_LEN + 1), "x".to_string()),
+            (
+                "incident".to_string(),
+                "v".repeat(MAX_ANNOTATION_VALUE_LEN + 1),
+            ),
+        ] {
+            let (resp, _) = handle_annotate_run(&store, &"wf-ins".into(), &key, &value).await;
+            assert!(matches!(resp, ClientResponseMessage::Unprocessable(_)));
+        }
+    }
+
     #[tokio::test]
     async fn test_mark_workflows_as_crashed_empty_set() {
-        let db_client = DBClient::new(None).unwrap();
+        let store = InMemoryStatusStore::new();
         let empty_set = HashSet::new();
 
-        let result = mark_workflows_as_crashed(db_client, empty_set).await;
+        let result = mark_workflows_as_crashed(&store, empty_set).await;
         assert!(result.is_ok());
     }
 
     #[tokio::test]
     async fn test_mark_workflows_as_crashed_nonexistent_workflow() {
-        let db_client = DBClient::new(None).unwrap();
+        let store = InMemoryStatusStore::new();
 
         let mut workflow_set = HashSet::new();
         workflow_set.insert("nonexistent-instance".to_string());
 
         // Should not error even if workflow doesn't exist
-        let result = mark_workflows_as_crashed(db_client, workflow_set).await;
+        let result = mark_workflows_as_crashed(&store, workflow_set).await;
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_mark_workflows_as_crashed() {
+        let store = InMemoryStatusStore::new();
+        handle_agent_workflow_status_update(
+            &store,
+            "wf".to_string(),
+            "wf-ins".into(),
+            RunStatus::RUNNING,
+        )
+        .await;
+
+        let workflow_set = HashSet::from(["wf-ins".to_string()]);
+        mark_workflows_as_crashed(&store, workflow_set)
+            .await
+            .unwrap();
+
+        let statuses = store.get_recent_workflow_statuses(10).await.unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].status(), RunStatus::CRASHED.to_string());
+    }
 }
@@ -1,24 +1,44 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use cdktr_core::{
-    models::AgentMeta,
+    exceptions::GenericError,
+    get_cdktr_setting,
+    models::{AgentMeta, RunStatus, WorkflowInstanceId},
     utils::data_structures::{AgentPriorityQueue, AsyncQueue},
 };
-use cdktr_db::DBClient;
-use cdktr_workflow::{Workflow, WorkflowStore};
+use cdktr_workflow::{SingletonMode, Workflow, WorkflowStore};
 use chrono::Utc;
 
-use cdktr_api::PrincipalAPI;
+use cdktr_api::{AgentAPI, PROTOCOL_VERSION, PrincipalAPI};
 use log::{info, trace, warn};
 
-use crate::log_manager::read_logs;
+use crate::store::StatusStore;
 
-use super::traits::Server;
-use cdktr_api::models::ClientResponseMessage;
+use super::traits::{HoldFuture, OffloadFuture, Server};
+use cdktr_api::models::{AgentMetrics, ClientResponseMessage};
 
+pub mod artifacts;
+pub mod collisions;
+pub mod dispatch;
 pub mod helpers;
+mod notify;
+pub mod reservations;
+mod retries;
+mod scheduler_lease;
+pub mod singletons;
+
+use artifacts::ArtifactStore;
+use collisions::{AgentIdCollision, collision_message, is_collision};
+use dispatch::{DispatchMode, push_workflow};
+use notify::{RunNotification, send_notification};
+use reservations::AgentReservations;
+use retries::WorkflowRetries;
+use scheduler_lease::SchedulerLease;
+use singletons::SingletonRuns;
 
 pub struct PrincipalServer {
     #[allow(dead_code)]
@@ -26,37 +46,447 @@ pub struct PrincipalServer {
     live_agents: AgentPriorityQueue,
     task_queue: AsyncQueue<Workflow>,
     workflows: WorkflowStore,
-    db_client: DBClient,
+    store: Arc<dyn StatusStore>,
     /// Maps agent_id to set of workflow_instance_ids currently running on that agent
     agent_workflows: Arc<tokio::sync::Mutex<HashMap<String, HashSet<String>>>>,
+    /// Number of workflow runs that took longer than their SLA since the principal started
+    sla_breaches: Arc<AtomicU64>,
+    /// Runs of workflows with a retry policy that are yet to finish
+    retries: WorkflowRetries,
+    /// Files produced by tasks of running workflows for the tasks after them to consume
+    artifacts: ArtifactStore,
+    /// Latest metrics pushed by each agent
+    agent_metrics: HashMap<String, AgentMetrics>,
+    /// Running runs of singleton workflows
+    singletons: SingletonRuns,
+    /// Runs sent to each agent, so exclusive workflows get an agent to themselves
+    reservations: AgentReservations,
+    /// Posts run summaries to the webhooks of workflows with `notify`
+    http_client: reqwest::Client,
+    /// Whether queued runs are pushed to agents or left for them to fetch
+    dispatch_mode: DispatchMode,
+    /// Agents told to stop that haven't confirmed they are exiting yet
+    stopping_agents: HashSet<String>,
+    /// Whether an agent registering with the id of another live agent is refused
+    agent_id_collision: AgentIdCollision,
+    /// Which scheduler may fire scheduled workflows
+    scheduler_lease: SchedulerLease,
+    /// Longest a long-polling workflow fetch is held, whatever timeout it asks for
+    max_long_poll: Duration,
 }
 
 impl PrincipalServer {
-    pub fn new(instance_id: String, workflows: WorkflowStore, db_client: DBClient) -> Self {
+    pub fn new(instance_id: String, workflows: WorkflowStore, store: Arc<dyn StatusStore>) -> Self {
         Self {
             instance_id,
             live_agents: AgentPriorityQueue::new(),
             task_queue: AsyncQueue::new(),
             workflows,
-            db_client,
+            store,
             agent_workflows: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            sla_breaches: Arc::new(AtomicU64::new(0)),
+            retries: WorkflowRetries::new(),
+            artifacts: ArtifactStore::new(
+                get_cdktr_setting!(CDKTR_MAX_ARTIFACT_BYTES, usize),
+                get_cdktr_setting!(CDKTR_MAX_ARTIFACT_STORE_BYTES, usize),
+            ),
+            agent_metrics: HashMap::new(),
+            singletons: SingletonRuns::new(),
+            reservations: AgentReservations::new(),
+            http_client: reqwest::Client::new(),
+            dispatch_mode: DispatchMode::from_config(),
+            stopping_agents: HashSet::new(),
+            agent_id_collision: AgentIdCollision::from_config(),
+            scheduler_lease: SchedulerLease::new(),
+            max_long_poll: Duration::from_millis(
+                get_cdktr_setting!(CDKTR_MAX_LONG_POLL_MS, usize) as u64
+            ),
+        }
+    }
+
+    /// Checks a finished workflow run against the SLA of its workflow, if it has one
+    async fn check_sla(&self, workflow_id: &str, workflow_instance_id: &WorkflowInstanceId) {
+        let sla = match self
+            .workflows
+            .get(workflow_id)
+            .await
+            .and_then(|wf| wf.sla())
+        {
+            Some(sla) => sla,
+            None => return,
+        };
+        match helpers::record_workflow_sla(self.store.as_ref(), workflow_instance_id, sla).await {
+            Ok(Some(true)) => {
+                let breaches = self.sla_breaches.fetch_add(1, Ordering::SeqCst) + 1;
+                warn!(
+                    "sla_breached: run {} of workflow {} took longer than its SLA of {}s. {} SLA breach(es) since start",
+                    workflow_instance_id,
+                    workflow_id,
+                    sla.as_secs(),
+                    breaches
+                );
+            }
+            Ok(_) => (),
+            Err(e) => warn!(
+                "Failed to record SLA result of workflow run {}: {}",
+                workflow_instance_id, e
+            ),
+        }
+    }
+
+    /// Posts a summary of a finished run to the webhook of its workflow if the webhook
+    /// is notified of the run's status. The summary is sent in the background so that a
+    /// slow webhook doesn't hold up the principal
+    async fn notify(
+        &self,
+        agent_id: &str,
+        workflow_id: &str,
+        workflow_instance_id: &WorkflowInstanceId,
+        status: &RunStatus,
+    ) {
+        let workflow = match self.workflows.get(workflow_id).await {
+            Some(workflow) => workflow,
+            None => return,
+        };
+        let notify = match workflow.notify() {
+            Some(notify) if notify.triggered_by(status) => notify.clone(),
+            _ => return,
+        };
+        let result = match self
+            .store
+            .get_workflow_result(workflow_instance_id, 0)
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                warn!(
+                    "Failed to get the result of workflow run {workflow_instance_id} to notify: {e}"
+                );
+                None
+            }
+        };
+        let mut notification = RunNotification::new(
+            &workflow,
+            workflow_instance_id.as_str(),
+            agent_id,
+            status,
+            result.as_ref(),
+        );
+        if notify.redact() {
+            notification = notification.redacted();
+        }
+        let client = self.http_client.clone();
+        let workflow_instance_id = workflow_instance_id.to_string();
+        tokio::spawn(async move {
+            match send_notification(&client, notify.url(), &notification).await {
+                Ok(()) => info!(
+                    "Notified {} of workflow run {}",
+                    notify.url(),
+                    workflow_instance_id
+                ),
+                Err(e) => warn!("{e}"),
+            }
+        });
+    }
+
+    /// Queues the next attempt of a finished run if it FAILED and its workflow has
+    /// retries left
+    async fn retry_if_failed(
+        &mut self,
+        workflow_instance_id: &WorkflowInstanceId,
+        status: &RunStatus,
+    ) {
+        let retry = match self
+            .retries
+            .on_finished(workflow_instance_id.as_str(), status)
+        {
+            Some(retry) => retry,
+            None => return,
+        };
+        let retry_instance_id =
+            WorkflowInstanceId::from(retry.instance_id().cloned().unwrap_or_default());
+        let retry_of = WorkflowInstanceId::from(retry.retry_of().cloned().unwrap_or_default());
+        info!(
+            "Run {} of workflow {} failed - retrying as {} (attempt {} of {})",
+            workflow_instance_id,
+            retry.id(),
+            retry_instance_id,
+            retry.attempt(),
+            retry.retries() + 1
+        );
+        if let Err(e) = self
+            .store
+            .record_run_attempt(&retry_instance_id, &retry_of, retry.attempt())
+            .await
+        {
+            warn!(
+                "Failed to record attempt {} of workflow run {}: {}",
+                retry.attempt(),
+                retry_of,
+                e
+            );
+        }
+        helpers::enqueue_workflow(self.store.as_ref(), &mut self.task_queue, retry).await;
+    }
+
+    /// Queues the runs that were still waiting on the queue when the principal last stopped
+    pub async fn restore_queue(&mut self) -> Result<usize, GenericError> {
+        let restored =
+            helpers::restore_queued_workflows(self.store.as_ref(), &mut self.task_queue).await?;
+        for workflow in restored.iter() {
+            self.retries.resume(workflow);
+        }
+        if !restored.is_empty() {
+            info!(
+                "Restored {} queued workflow run(s) from the last principal",
+                restored.len()
+            );
         }
+        Ok(restored.len())
     }
 
     /// Registers the agent with the principal server. If it exists
     /// already then it simply updates with the latest timestamp
-    async fn register_agent(&mut self, agent_id: &String) -> (ClientResponseMessage, usize) {
+    async fn register_agent(
+        &mut self,
+        agent_id: &String,
+        protocol_version: Option<u32>,
+        max_concurrency: Option<usize>,
+        executors: Option<Vec<String>>,
+        push_address: Option<String>,
+        session: Option<String>,
+    ) -> (ClientResponseMessage, usize) {
+        match protocol_version {
+            Some(version) if version != PROTOCOL_VERSION => {
+                warn!(
+                    "Rejecting registration of agent {agent_id} using protocol version {version}"
+                );
+                return (
+                    ClientResponseMessage::ClientError(format!(
+                        "version mismatch: agent {agent_id} uses protocol version {version} but the principal uses version {PROTOCOL_VERSION}. Upgrade the agent and principal to the same cdktr version"
+                    )),
+                    0,
+                );
+            }
+            Some(_) => (),
+            None => warn!(
+                "Agent {agent_id} did not send a protocol version - it may be running an incompatible version of cdktr"
+            ),
+        }
         let now = Utc::now().timestamp_micros();
+        if let Ok(registered) = self.live_agents.get_agent(agent_id).await {
+            let timeout_micros =
+                get_cdktr_setting!(CDKTR_AGENT_HEARTBEAT_TIMEOUT_MS, usize) as i64 * 1000;
+            if is_collision(&registered, session.as_ref(), now, timeout_micros) {
+                match self.agent_id_collision {
+                    AgentIdCollision::Reject => {
+                        warn!(
+                            "Rejecting registration of agent {agent_id}: another agent is registered with the same id"
+                        );
+                        return (
+                            ClientResponseMessage::ClientError(collision_message(agent_id)),
+                            0,
+                        );
+                    }
+                    AgentIdCollision::Warn => warn!(
+                        "Agent {agent_id} registered from a different process than the agent registered with the same id - it is replacing it. Start each agent on a host with a unique --suffix"
+                    ),
+                }
+            }
+        }
         let update_result = self.live_agents.update_timestamp(agent_id, now).await;
+        let registered_afresh = update_result.is_err();
         match update_result {
-            Ok(_) => (),
+            Ok(_) => {
+                if let Err(e) = self.live_agents.update_session(agent_id, session).await {
+                    warn!("Failed to update session of agent {agent_id}: {e}");
+                }
+                if let Some(max) = max_concurrency
+                    && let Err(e) = self.live_agents.update_max_concurrency(agent_id, max).await
+                {
+                    warn!("Failed to update max concurrency of agent {agent_id}: {e}");
+                }
+                if let Err(e) = self.live_agents.update_executors(agent_id, executors).await {
+                    warn!("Failed to update executors of agent {agent_id}: {e}");
+                }
+                if let Err(e) = self
+                    .live_agents
+                    .update_push_address(agent_id, push_address)
+                    .await
+                {
+                    warn!("Failed to update push address of agent {agent_id}: {e}");
+                }
+            }
             Err(_e) => {
-                // agent not registered before so add new
-                let agent_meta = AgentMeta::new(agent_id.clone(), now);
+                // agent not registered before so add new. An agent registering afresh
+                // with the id of one that was stopped is a new process
+                self.stopping_agents.remove(agent_id);
+                if push_address.is_some() && self.dispatch_mode == DispatchMode::Pull {
+                    warn!(
+                        "Agent {agent_id} is waiting for workflows to be pushed to it but the principal is in pull mode - set CDKTR_DISPATCH_MODE to the same mode on both"
+                    );
+                }
+                let agent_meta = AgentMeta::new(agent_id.clone(), now)
+                    .with_max_concurrency(max_concurrency)
+                    .with_executors(executors)
+                    .with_push_address(push_address)
+                    .with_session(session);
                 self.live_agents.push(agent_meta).await
             }
         };
-        (ClientResponseMessage::Success, 0)
+        // the heartbeat is the only time the principal hears from an idle agent so
+        // a drained or stopped agent is told here to stop fetching workflows. An agent
+        // registering afresh can be neither, so it is told how often to send heartbeats
+        if registered_afresh {
+            let timeout_ms = get_cdktr_setting!(CDKTR_AGENT_HEARTBEAT_TIMEOUT_MS, usize) as u64;
+            (
+                ClientResponseMessage::SuccessWithPayload(
+                    AgentAPI::HeartbeatTimeout(timeout_ms).to_string(),
+                ),
+                0,
+            )
+        } else if self.stopping_agents.contains(agent_id) {
+            (
+                ClientResponseMessage::SuccessWithPayload(AgentAPI::Shutdown.to_string()),
+                0,
+            )
+        } else if self.is_drained(agent_id).await {
+            (
+                ClientResponseMessage::SuccessWithPayload(AgentAPI::SetDrain(true).to_string()),
+                0,
+            )
+        } else {
+            (ClientResponseMessage::Success, 0)
+        }
+    }
+
+    /// Refuses a run of a singleton workflow in reject mode while another run of it is
+    /// running. Singletons in queue mode are held back when agents fetch work instead
+    async fn reject_if_running(&self, workflow_id: &str) -> Option<(ClientResponseMessage, usize)> {
+        let workflow = self.workflows.get(workflow_id).await?;
+        if workflow.singleton() == Some(SingletonMode::Reject)
+            && self.singletons.is_running(workflow.id())
+        {
+            info!("Rejected run of workflow {workflow_id}: a run of it is already running");
+            Some((
+                ClientResponseMessage::Retryable(format!(
+                    "Workflow {workflow_id} is already running"
+                )),
+                0,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Refuses a run of a workflow that needs an executor no registered agent runs, as
+    /// no agent would ever fetch it. Runs are still queued when no agents are registered
+    async fn reject_if_unsupported(
+        &self,
+        workflow_id: &str,
+    ) -> Option<(ClientResponseMessage, usize)> {
+        let workflow = self.workflows.get(workflow_id).await?;
+        let agents = self.live_agents.get_all_agents().await;
+        if agents.is_empty()
+            || agents
+                .iter()
+                .any(|agent| helpers::can_run(agent, &workflow))
+        {
+            return None;
+        }
+        let executors: Vec<&str> = workflow.executors().into_iter().collect();
+        info!("Rejected run of workflow {workflow_id}: no agent runs its executors");
+        Some((
+            ClientResponseMessage::Unprocessable(format!(
+                "Missing agents: no running agent supports the executors needed by workflow {workflow_id} ({})",
+                executors.join(", ")
+            )),
+            0,
+        ))
+    }
+
+    /// Sends queued runs straight to the agents that listen for pushed workflows when the
+    /// principal is in push mode. Each run goes to the least loaded of those agents that
+    /// can run it and has a free slot. Runs are left on the queue while none has
+    async fn push_queued(&mut self) {
+        if self.dispatch_mode != DispatchMode::Push {
+            return;
+        }
+        // runs pushed in this pass are counted towards the load of their agent as the
+        // agent only reports them once they start
+        let mut agents: Vec<(AgentMeta, usize)> = self
+            .live_agents
+            .get_all_agents()
+            .await
+            .into_iter()
+            .filter(|agent| agent.push_address().is_some() && !agent.is_drained())
+            .map(|agent| {
+                let load = agent.utilisation();
+                (agent, load)
+            })
+            .collect();
+        while !self.task_queue.is_empty().await {
+            let Some(i) = agents
+                .iter()
+                .enumerate()
+                .filter(|(_, (agent, load))| *load < agent.max_concurrency().unwrap_or(1))
+                .min_by_key(|(_, (_, load))| *load)
+                .map(|(i, _)| i)
+            else {
+                break;
+            };
+            let (agent, load) = &mut agents[i];
+            match helpers::take_workflow(
+                self.store.as_ref(),
+                &mut self.task_queue,
+                &self.singletons,
+                &self.reservations,
+                agent,
+            )
+            .await
+            {
+                Some(workflow) => {
+                    *load += 1;
+                    tokio::spawn(push_workflow(
+                        agent.agent_id(),
+                        agent.push_address().cloned().unwrap_or_default(),
+                        workflow,
+                        self.store.clone(),
+                        self.task_queue.clone(),
+                        self.singletons.clone(),
+                        self.reservations.clone(),
+                    ));
+                }
+                // nothing left on the queue that this agent can run
+                None => {
+                    agents.swap_remove(i);
+                }
+            }
+        }
+    }
+
+    async fn is_drained(&self, agent_id: &str) -> bool {
+        self.live_agents
+            .get_agent(agent_id)
+            .await
+            .is_ok_and(|agent| agent.is_drained())
+    }
+
+    /// Returns the running runs of singleton workflows, for the heartbeat monitor to
+    /// release the runs of agents that die
+    pub fn get_singleton_runs(&self) -> SingletonRuns {
+        self.singletons.clone()
+    }
+
+    /// Returns the runs sent to each agent, for the heartbeat monitor to free up the
+    /// agents that die
+    pub fn get_agent_reservations(&self) -> AgentReservations {
+        self.reservations.clone()
+    }
+
+    /// Returns the task queue, for the admin loop to check for slow consumers
+    pub fn get_task_queue(&self) -> AsyncQueue<Workflow> {
+        self.task_queue.clone()
     }
 
     /// Returns references to the agent tracking structures for heartbeat monitoring
@@ -65,12 +495,12 @@ impl PrincipalServer {
     ) -> (
         AgentPriorityQueue,
         Arc<tokio::sync::Mutex<HashMap<String, HashSet<String>>>>,
-        DBClient,
+        Arc<dyn StatusStore>,
     ) {
         (
             self.live_agents.clone(),
             self.agent_workflows.clone(),
-            self.db_client.clone(),
+            self.store.clone(),
         )
     }
 }
@@ -81,15 +511,85 @@ impl Server<PrincipalAPI> for PrincipalServer {
         &mut self,
         cli_msg: PrincipalAPI,
     ) -> (ClientResponseMessage, usize) {
+        // requests that queue runs or free up agents give pushed runs somewhere to go
+        let dispatches = matches!(
+            cli_msg,
+            PrincipalAPI::RunTask(..)
+                | PrincipalAPI::DryRunTask(..)
+                | PrincipalAPI::ReplayRun(..)
+                | PrincipalAPI::RegisterAgent(..)
+                | PrincipalAPI::WorkflowStatusUpdate(..)
+                | PrincipalAPI::DrainAgent(..)
+        );
         let result = match cli_msg {
             PrincipalAPI::Ping => (ClientResponseMessage::Pong, 0),
             PrincipalAPI::ListWorkflowStore => {
                 helpers::handle_list_workflows(&self.workflows).await
             }
-            PrincipalAPI::RunTask(task_id) => {
-                helpers::handle_run_task(&task_id, &self.workflows, &mut self.task_queue).await
+            PrincipalAPI::RunTask(task_id, params, reject_without_agents, environment) => {
+                if let Some(rejected) = self.reject_if_running(&task_id).await {
+                    return rejected;
+                }
+                if let Some(rejected) = self.reject_if_unsupported(&task_id).await {
+                    return rejected;
+                }
+                let no_agents = self.live_agents.get_all_agents().await.is_empty();
+                if no_agents && reject_without_agents {
+                    info!("Rejected run of workflow {task_id}: no agents are registered");
+                    return (
+                        ClientResponseMessage::Retryable("No agents available".to_string()),
+                        0,
+                    );
+                }
+                helpers::handle_run_task(
+                    &task_id,
+                    &params,
+                    environment.as_deref(),
+                    &self.workflows,
+                    self.store.as_ref(),
+                    &mut self.task_queue,
+                    &mut self.retries,
+                    false,
+                    no_agents,
+                )
+                .await
+            }
+            PrincipalAPI::DryRunTask(task_id, params) => {
+                if let Some(rejected) = self.reject_if_unsupported(&task_id).await {
+                    return rejected;
+                }
+                let no_agents = self.live_agents.get_all_agents().await.is_empty();
+                helpers::handle_run_task(
+                    &task_id,
+                    &params,
+                    None,
+                    &self.workflows,
+                    self.store.as_ref(),
+                    &mut self.task_queue,
+                    &mut self.retries,
+                    true,
+                    no_agents,
+                )
+                .await
+            }
+            PrincipalAPI::RegisterAgent(
+                agent_id,
+                protocol_version,
+                max_concurrency,
+                executors,
+                push_address,
+                session,
+            ) => {
+                self.register_agent(
+                    &agent_id,
+                    protocol_version,
+                    max_concurrency,
+                    executors,
+                    push_address,
+                    session,
+                )
+                .await
             }
-            PrincipalAPI::RegisterAgent(agent_id) => self.register_agent(&agent_id).await,
             PrincipalAPI::WorkflowStatusUpdate(
                 agent_id,
                 workflow_id,
@@ -104,15 +604,14 @@ impl Server<PrincipalAPI> for PrincipalServer {
                         agent_wf_map
                             .entry(agent_id.clone())
                             .or_insert_with(HashSet::new)
-                            .insert(workflow_instance_id.clone());
+                            .insert(workflow_instance_id.to_string());
 
                         // Increment running tasks counter for this agent
                         if let Err(e) = self.live_agents.update_running_tasks(&agent_id, true).await
                         {
                             warn!(
                                 "Failed to increment running tasks for agent {}: {}",
-                                agent_id,
-                                e.to_string()
+                                agent_id, e
                             );
                         }
                     }
@@ -121,7 +620,7 @@ impl Server<PrincipalAPI> for PrincipalServer {
                     | cdktr_core::models::RunStatus::CRASHED => {
                         // Remove workflow from agent's active set
                         if let Some(workflows) = agent_wf_map.get_mut(&agent_id) {
-                            workflows.remove(&workflow_instance_id);
+                            workflows.remove(workflow_instance_id.as_str());
                             // Clean up empty entries
                             if workflows.is_empty() {
                                 agent_wf_map.remove(&agent_id);
@@ -136,8 +635,7 @@ impl Server<PrincipalAPI> for PrincipalServer {
                         {
                             warn!(
                                 "Failed to decrement running tasks for agent {}: {}",
-                                agent_id,
-                                e.to_string()
+                                agent_id, e
                             );
                         }
                     }
@@ -145,13 +643,31 @@ impl Server<PrincipalAPI> for PrincipalServer {
                 }
                 drop(agent_wf_map);
 
-                helpers::handle_agent_workflow_status_update(
-                    self.db_client.clone(),
-                    workflow_id,
-                    workflow_instance_id,
+                let finished = matches!(
                     status,
+                    cdktr_core::models::RunStatus::COMPLETED
+                        | cdktr_core::models::RunStatus::FAILED
+                        | cdktr_core::models::RunStatus::CRASHED
+                );
+                let result = helpers::handle_agent_workflow_status_update(
+                    self.store.as_ref(),
+                    workflow_id.clone(),
+                    workflow_instance_id.clone(),
+                    status.clone(),
                 )
-                .await
+                .await;
+                if finished {
+                    self.artifacts.clear(workflow_instance_id.as_str());
+                    self.singletons.release([&workflow_instance_id]);
+                    self.reservations.release([&workflow_instance_id]);
+                }
+                if finished && result.0 == ClientResponseMessage::Success {
+                    self.check_sla(&workflow_id, &workflow_instance_id).await;
+                    self.notify(&agent_id, &workflow_id, &workflow_instance_id, &status)
+                        .await;
+                    self.retry_if_failed(&workflow_instance_id, &status).await;
+                }
+                result
             }
             PrincipalAPI::TaskStatusUpdate(
                 _agent_id,
@@ -159,71 +675,351 @@ impl Server<PrincipalAPI> for PrincipalServer {
                 task_instance_id,
                 workflow_instance_id,
                 status,
+                exit_code,
+                truncated,
             ) => {
                 // TODO do something with agent id
                 helpers::handle_agent_task_status_update(
-                    self.db_client.clone(),
+                    self.store.as_ref(),
                     task_id,
                     task_instance_id,
                     workflow_instance_id,
                     status,
+                    exit_code,
+                    truncated,
                 )
                 .await
             }
-            PrincipalAPI::FetchWorkflow(agent_id) => {
-                helpers::handle_fetch_task(&mut self.task_queue, agent_id).await
-            }
-            PrincipalAPI::QueryLogs(end_ts, start_ts, wf_id, wf_ins_id, verbose) => {
-                info!("Fetching logs");
-                let logs_result =
-                    read_logs(self.db_client.clone(), start_ts, end_ts, wf_id, wf_ins_id).await;
-                match logs_result {
-                    Ok(logs) => match serde_json::to_string(
-                        &logs
-                            .iter()
-                            .map(|l| if verbose { l.format_full() } else { l.format() })
-                            .collect::<Vec<String>>(),
-                    ) {
-                        Ok(str_result) => {
-                            (ClientResponseMessage::SuccessWithPayload(str_result), 0)
-                        }
-                        Err(e) => (
-                            ClientResponseMessage::ServerError(format!(
-                                "Failed to read logs from db: {}",
-                                e.to_string()
-                            )),
-                            0,
-                        ),
-                    },
-                    Err(e) => (
-                        ClientResponseMessage::ServerError(format!(
-                            "Failed to read logs from db: {}",
-                            e.to_string()
+            PrincipalAPI::TaskProgress(_agent_id, task_instance_id, percent, message) => {
+                helpers::handle_task_progress(
+                    self.store.as_ref(),
+                    task_instance_id,
+                    percent,
+                    message,
+                )
+                .await
+            }
+            PrincipalAPI::TaskArtifacts(_agent_id, task_instance_id, artifacts) => {
+                helpers::handle_task_artifacts(self.store.as_ref(), task_instance_id, artifacts)
+                    .await
+            }
+            PrincipalAPI::WorkflowProgress(
+                _agent_id,
+                workflow_instance_id,
+                tasks_completed,
+                tasks_total,
+            ) => {
+                helpers::handle_workflow_progress(
+                    self.store.as_ref(),
+                    workflow_instance_id,
+                    tasks_completed,
+                    tasks_total,
+                )
+                .await
+            }
+            PrincipalAPI::WorkflowOutputs(
+                _agent_id,
+                workflow_id,
+                workflow_instance_id,
+                outputs,
+            ) => {
+                helpers::handle_workflow_outputs(
+                    self.store.as_ref(),
+                    workflow_id,
+                    workflow_instance_id,
+                    outputs,
+                )
+                .await
+            }
+            PrincipalAPI::FetchWorkflow(agent_id, _long_poll_timeout_ms) => {
+                if self.is_drained(&agent_id).await {
+                    // no work for a drained agent that hasn't been told yet
+                    (ClientResponseMessage::Success, 0)
+                } else {
+                    // agents that fetch work without registering are assumed to run
+                    // every executor
+                    let agent = self
+                        .live_agents
+                        .get_agent(&agent_id)
+                        .await
+                        .unwrap_or_else(|_| AgentMeta::new(agent_id, 0));
+                    helpers::handle_fetch_task(
+                        self.store.as_ref(),
+                        &mut self.task_queue,
+                        &self.singletons,
+                        &self.reservations,
+                        &agent,
+                    )
+                    .await
+                }
+            }
+            PrincipalAPI::QueryLogs(end_ts, start_ts, wf_id, wf_ins_id, _verbose, page) => {
+                helpers::handle_query_logs(
+                    self.store.as_ref(),
+                    end_ts,
+                    start_ts,
+                    wf_id,
+                    wf_ins_id,
+                    page,
+                )
+                .await
+            }
+            PrincipalAPI::GetRecentWorkflowStatuses => {
+                helpers::handle_get_recent_workflow_statuses(self.store.as_ref()).await
+            }
+            PrincipalAPI::GetRegisteredAgents => {
+                helpers::handle_get_registered_agents(self.live_agents.clone(), &self.agent_metrics)
+                    .await
+            }
+            PrincipalAPI::GetWorkflowResult(workflow_instance_id) => {
+                helpers::handle_get_workflow_result(self.store.as_ref(), workflow_instance_id).await
+            }
+            PrincipalAPI::GetWorkflowTail(workflow_instance_id, n) => {
+                helpers::handle_get_workflow_tail(self.store.as_ref(), workflow_instance_id, n)
+                    .await
+            }
+            PrincipalAPI::GetAuditLog(limit) => {
+                helpers::handle_get_audit_log(self.store.as_ref(), limit).await
+            }
+            PrincipalAPI::GetQueueMetrics => {
+                helpers::handle_get_queue_metrics(
+                    &self.task_queue,
+                    self.sla_breaches.load(Ordering::SeqCst),
+                )
+                .await
+            }
+            PrincipalAPI::GetClusterCapacity => {
+                helpers::handle_get_cluster_capacity(&self.live_agents, &self.task_queue).await
+            }
+            PrincipalAPI::AnnotateRun(workflow_instance_id, key, value) => {
+                helpers::handle_annotate_run(
+                    self.store.as_ref(),
+                    &workflow_instance_id,
+                    &key,
+                    &value,
+                )
+                .await
+            }
+            PrincipalAPI::PutArtifact(workflow_instance_id, path, offset, chunk) => {
+                helpers::handle_put_artifact(
+                    &mut self.artifacts,
+                    &workflow_instance_id,
+                    &path,
+                    offset,
+                    &chunk,
+                )
+            }
+            PrincipalAPI::GetArtifact(workflow_instance_id, path, offset) => {
+                helpers::handle_get_artifact(&self.artifacts, &workflow_instance_id, &path, offset)
+            }
+            PrincipalAPI::AgentMetrics(agent_id, metrics) => {
+                helpers::handle_agent_metrics(
+                    &self.live_agents,
+                    &mut self.agent_metrics,
+                    agent_id,
+                    metrics,
+                )
+                .await
+            }
+            PrincipalAPI::DrainAgent(agent_id, drained, _actor) => {
+                helpers::handle_drain_agent(&self.live_agents, &agent_id, drained).await
+            }
+            PrincipalAPI::StopAgent(agent_id, _actor) => {
+                let response =
+                    helpers::handle_drain_agent(&self.live_agents, &agent_id, true).await;
+                if response.0 == ClientResponseMessage::Success {
+                    info!("Stopping agent {agent_id} - it exits once its running workflows finish");
+                    self.stopping_agents.insert(agent_id);
+                }
+                response
+            }
+            PrincipalAPI::AgentStopped(agent_id) => {
+                self.stopping_agents.remove(&agent_id);
+                self.agent_metrics.remove(&agent_id);
+                self.reservations.release_agent(&agent_id);
+                self.agent_workflows.lock().await.remove(&agent_id);
+                match self.live_agents.remove(&agent_id).await {
+                    Ok(_) => {
+                        info!("Agent {agent_id} has stopped - deregistered it");
+                        (ClientResponseMessage::Success, 0)
+                    }
+                    Err(_e) => (
+                        ClientResponseMessage::NotFound(format!(
+                            "No agent registered with id {agent_id}"
                         )),
                         0,
                     ),
                 }
             }
-            PrincipalAPI::GetRecentWorkflowStatuses => {
-                helpers::handle_get_recent_workflow_statuses(self.db_client.clone()).await
+            PrincipalAPI::ReplayRun(workflow_instance_id) => {
+                helpers::handle_replay_run(
+                    self.store.as_ref(),
+                    &mut self.task_queue,
+                    &mut self.retries,
+                    &workflow_instance_id,
+                )
+                .await
             }
-            PrincipalAPI::GetRegisteredAgents => {
-                helpers::handle_get_registered_agents(self.live_agents.clone()).await
+            PrincipalAPI::FlushQueue(_actor) => {
+                helpers::handle_flush_queue(
+                    self.store.as_ref(),
+                    &mut self.task_queue,
+                    &mut self.retries,
+                )
+                .await
+            }
+            PrincipalAPI::ReloadConfig(_actor) => helpers::handle_reload_config(),
+            PrincipalAPI::AcquireSchedulerLease(scheduler_id, ttl_ms) => {
+                match self.scheduler_lease.acquire(
+                    &scheduler_id,
+                    Duration::from_millis(ttl_ms),
+                    std::time::Instant::now(),
+                ) {
+                    Ok(()) => (ClientResponseMessage::Success, 0),
+                    Err((holder, remaining)) => (
+                        ClientResponseMessage::Retryable(format!(
+                            "Scheduler lease is held by {} for another {}ms",
+                            holder,
+                            remaining.as_millis()
+                        )),
+                        0,
+                    ),
+                }
+            }
+            PrincipalAPI::DiagnoseDispatch(workflow_instance_id) => {
+                helpers::handle_diagnose_dispatch(
+                    self.store.as_ref(),
+                    &self.task_queue,
+                    &self.live_agents,
+                    &self.singletons,
+                    &self.reservations,
+                    &workflow_instance_id,
+                )
+                .await
             }
         };
+        if dispatches {
+            self.push_queued().await;
+        }
         trace!("Returning ({}): {}", result.1, result.0.to_string());
         result
     }
+
+    /// Administrative actions are recorded in the audit log with the actor that took them
+    /// and the client they came from, whether or not they succeeded
+    async fn handle_client_message_from(
+        &mut self,
+        cli_msg: PrincipalAPI,
+        client: &str,
+    ) -> (ClientResponseMessage, usize) {
+        let audited = helpers::audited_action(&cli_msg);
+        let result = self.handle_client_message(cli_msg).await;
+        if let Some((action, target, actor)) = audited {
+            helpers::record_audit_entry(
+                self.store.as_ref(),
+                actor,
+                client,
+                action,
+                target,
+                &result.0,
+            )
+            .await;
+        }
+        result
+    }
+
+    /// Long-polling workflow fetches are held until a workflow is available on the
+    /// task queue or the requested timeout elapses. The timeout is capped so a client
+    /// can't hold a request open for longer than the principal allows
+    fn hold_request(&self, cli_msg: &PrincipalAPI) -> Option<HoldFuture> {
+        match cli_msg {
+            PrincipalAPI::FetchWorkflow(_agent_id, Some(timeout_ms)) if *timeout_ms > 0 => {
+                let task_queue = self.task_queue.clone();
+                let timeout = Duration::from_millis(*timeout_ms).min(self.max_long_poll);
+                Some(Box::pin(async move {
+                    task_queue.wait_for_item(timeout).await;
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Queries that only read from the status store are handled on the query workers, as
+    /// reading logs and run history from the database can be slow
+    fn offload_request(&self, cli_msg: &PrincipalAPI) -> Option<OffloadFuture> {
+        let store = self.store.clone();
+        match cli_msg {
+            PrincipalAPI::QueryLogs(end_ts, start_ts, wf_id, wf_ins_id, _verbose, page) => {
+                let (end_ts, start_ts, page) = (*end_ts, *start_ts, *page);
+                let (wf_id, wf_ins_id) = (wf_id.clone(), wf_ins_id.clone());
+                Some(Box::pin(async move {
+                    helpers::handle_query_logs(
+                        store.as_ref(),
+                        end_ts,
+                        start_ts,
+                        wf_id,
+                        wf_ins_id,
+                        page,
+                    )
+                    .await
+                    .0
+                }))
+            }
+            PrincipalAPI::GetRecentWorkflowStatuses => Some(Box::pin(async move {
+                helpers::handle_get_recent_workflow_statuses(store.as_ref())
+                    .await
+                    .0
+            })),
+            PrincipalAPI::GetWorkflowResult(workflow_instance_id) => {
+                let workflow_instance_id = workflow_instance_id.clone();
+                Some(Box::pin(async move {
+                    helpers::handle_get_workflow_result(store.as_ref(), workflow_instance_id)
+                        .await
+                        .0
+                }))
+            }
+            PrincipalAPI::GetWorkflowTail(workflow_instance_id, n) => {
+                let (workflow_instance_id, n) = (workflow_instance_id.clone(), *n);
+                Some(Box::pin(async move {
+                    helpers::handle_get_workflow_tail(store.as_ref(), workflow_instance_id, n)
+                        .await
+                        .0
+                }))
+            }
+            PrincipalAPI::GetAuditLog(limit) => {
+                let limit = *limit;
+                Some(Box::pin(async move {
+                    helpers::handle_get_audit_log(store.as_ref(), limit).await.0
+                }))
+            }
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{thread::sleep, time::Duration};
 
-    use cdktr_core::zmq_helpers::format_zmq_msg_str;
+    use cdktr_core::zmq_helpers::{format_zmq_msg_str, get_server_tcp_uri, send_recv_with_timeout};
+    use tokio::time::Instant;
     use zeromq::ZmqMessage;
 
     use super::*;
+    use crate::log_manager::model::LogMessage;
+    use crate::store::InMemoryStatusStore;
+    use cdktr_api::models::{AuditEntry, LogPage, WorkflowStatusUpdate};
+    use cdktr_core::models::RunStatus;
+
+    /// What the principal answers an agent it didn't know about with
+    fn registered_afresh() -> ClientResponseMessage {
+        ClientResponseMessage::SuccessWithPayload(
+            AgentAPI::HeartbeatTimeout(
+                get_cdktr_setting!(CDKTR_AGENT_HEARTBEAT_TIMEOUT_MS, usize) as u64
+            )
+            .to_string(),
+        )
+    }
 
     async fn get_workflowstore() -> WorkflowStore {
         WorkflowStore::from_dir("./test_artifacts/workflows")
@@ -231,6 +1027,21 @@ mod tests {
             .unwrap()
     }
 
+    /// Writes each (file name, yaml) pair to a temporary directory and loads a store from
+    /// it. The directory is deleted once the returned `TempDir` is dropped
+    async fn workflow_store_with(
+        files: &[(impl AsRef<str>, impl AsRef<str>)],
+    ) -> (WorkflowStore, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, yaml) in files {
+            std::fs::write(dir.path().join(name.as_ref()), yaml.as_ref()).unwrap();
+        }
+        let workflows = WorkflowStore::from_dir(dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+        (workflows, dir)
+    }
+
     #[test]
     fn test_principal_request_from_zmq_str_all_happy() {
         let regis_str = format_zmq_msg_str(vec!["REGISTERAGENT", "8999", "2"]);
@@ -246,6 +1057,7 @@ mod tests {
     #[tokio::test]
     async fn test_handle_cli_message_all_happy() {
         // e2e integration test of db crudvia the server
+        let register_msg = format!("REGISTERAGENT\x01localhost-8999\x01{PROTOCOL_VERSION}");
         let test_params: Vec<(&str, Box<dyn Fn(ClientResponseMessage) -> bool>, usize)> = vec![
             // ("PING", Box::new(|r: ClientResponseMessage| r == ClientResponseMessage::Pong), 0),
             (
@@ -256,7 +1068,7 @@ mod tests {
                 0,
             ),
             (
-                "REGISTERAGENT\x01localhost-8999\x013",
+                &register_msg,
                 Box::new(|r: ClientResponseMessage| r == ClientResponseMessage::Success),
                 0,
             ),
@@ -265,7 +1077,7 @@ mod tests {
         let mut server = PrincipalServer::new(
             "fake_ins".to_string(),
             get_workflowstore().await,
-            DBClient::new(None).unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
         );
         for (zmq_s, assertion_fn, exp_exit_code) in test_params {
             println!("Testing {zmq_s}");
@@ -280,58 +1092,807 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_register_agent_new() {
+    async fn test_register_agent_version_mismatch() {
         let mut server = PrincipalServer::new(
             "fake_ins".to_string(),
             get_workflowstore().await,
-            DBClient::new(None).unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
         );
-        let agent_id = String::from("localhost-4567");
-        let (resp, exit_code) = server.register_agent(&agent_id).await;
-        {
-            server.live_agents.pop().await.unwrap();
+        let old_version = PROTOCOL_VERSION - 1;
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::RegisterAgent(
+                "old-agent".to_string(),
+                Some(old_version),
+                None,
+                None,
+                None,
+                None,
+            ))
+            .await;
+        match resp {
+            ClientResponseMessage::ClientError(msg) => {
+                assert!(msg.starts_with("version mismatch:"), "{msg}");
+                assert!(msg.contains(&format!("protocol version {old_version}")));
+            }
+            other => panic!("Expected a ClientError but got {:?}", other),
         }
-        assert!(resp == ClientResponseMessage::Success);
-        assert!(exit_code == 0)
+        // the rejected agent is not registered
+        assert!(server.live_agents.is_empty().await);
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::RegisterAgent(
+                "new-agent".to_string(),
+                Some(PROTOCOL_VERSION),
+                None,
+                None,
+                None,
+                None,
+            ))
+            .await;
+        assert_eq!(resp, registered_afresh());
     }
 
     #[tokio::test]
-    async fn test_register_agent_already_exists() {
+    async fn test_register_agent_id_collision() {
         let mut server = PrincipalServer::new(
             "fake_ins".to_string(),
             get_workflowstore().await,
-            DBClient::new(None).unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
         );
-        let agent_id = String::from("localhost-4567");
-        server.register_agent(&agent_id).await;
-        let old_timestamp = { server.live_agents.pop().await.unwrap().get_last_ping_ts() };
-        sleep(Duration::from_micros(10));
-        let (resp, exit_code) = server.register_agent(&agent_id).await;
-        let new_timestamp = { server.live_agents.pop().await.unwrap().get_last_ping_ts() };
-        assert!(new_timestamp > old_timestamp);
-        assert!(resp == ClientResponseMessage::Success);
-        assert!(exit_code == 0)
+        let agent_id = "cdktr@host/AG".to_string();
+        let registration = |session: &str| {
+            PrincipalAPI::RegisterAgent(
+                agent_id.clone(),
+                Some(PROTOCOL_VERSION),
+                None,
+                None,
+                None,
+                Some(session.to_string()),
+            )
+        };
+        let (resp, _) = server.handle_client_message(registration("first")).await;
+        assert_eq!(resp, registered_afresh());
+        // heartbeats of the same process aren't collisions
+        let (resp, _) = server.handle_client_message(registration("first")).await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+
+        let (resp, _) = server.handle_client_message(registration("second")).await;
+        match resp {
+            ClientResponseMessage::ClientError(msg) => {
+                assert!(msg.starts_with("agent id collision:"), "{msg}");
+                assert!(msg.contains(&agent_id) && msg.contains("--suffix"), "{msg}");
+            }
+            other => panic!("Expected a ClientError but got {:?}", other),
+        }
+        let registered = server.live_agents.get_agent(&agent_id).await.unwrap();
+        assert_eq!(registered.session().unwrap(), "first");
+
+        // in warn mode the new agent takes over the id
+        server.agent_id_collision = AgentIdCollision::Warn;
+        let (resp, _) = server.handle_client_message(registration("second")).await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        let registered = server.live_agents.get_agent(&agent_id).await.unwrap();
+        assert_eq!(registered.session().unwrap(), "second");
     }
 
     #[tokio::test]
-    async fn test_workflow_tracking_on_running_status() {
-        let mut server = PrincipalServer::new(
-            "fake_ins".to_string(),
-            get_workflowstore().await,
-            DBClient::new(None).unwrap(),
-        );
+    async fn test_sla_breach_recorded_on_completion() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "nightly.yml",
+            r#"
+name: Nightly
+start_time: 2025-01-20T12:00:00+00:00
+sla_s: 1
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )])
+        .await;
+        let store = Arc::new(InMemoryStatusStore::new());
+        let mut server = PrincipalServer::new("fake_ins".to_string(), workflows, store.clone());
 
-        let agent_id = "test-agent-001".to_string();
-        let workflow_id = "test-workflow".to_string();
-        let workflow_instance_id = "test-instance-001".to_string();
+        // one run started 5s ago and one just now, each with an SLA of 1s
+        let now = Utc::now().timestamp_millis() as u64;
+        for (wf_ins_id, started_at) in [("slow-ins", now - 5_000), ("fast-ins", now)] {
+            store
+                .record_workflow_statuses(vec![WorkflowStatusUpdate::new(
+                    "nightly".to_string(),
+                    wf_ins_id.to_string().into(),
+                    RunStatus::RUNNING.to_string(),
+                    started_at,
+                )])
+                .await
+                .unwrap();
+            let (resp, _) = server
+                .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                    "agent-1".to_string(),
+                    "nightly".to_string(),
+                    wf_ins_id.to_string().into(),
+                    RunStatus::COMPLETED,
+                ))
+                .await;
+            assert_eq!(resp, ClientResponseMessage::Success);
+        }
 
-        // Send RUNNING status update
-        let msg = PrincipalAPI::WorkflowStatusUpdate(
-            agent_id.clone(),
-            workflow_id.clone(),
-            workflow_instance_id.clone(),
-            cdktr_core::models::RunStatus::RUNNING,
-        );
+        let slow = store
+            .get_workflow_result(&"slow-ins".into(), 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(slow.sla_breached, Some(true));
+        let fast = store
+            .get_workflow_result(&"fast-ins".into(), 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fast.sla_breached, Some(false));
+        assert_eq!(server.sla_breaches.load(Ordering::SeqCst), 1);
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::GetQueueMetrics)
+            .await;
+        let metrics: serde_json::Value = serde_json::from_str(&resp.payload()).unwrap();
+        assert_eq!(metrics["sla_breaches"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_workflow_retried_until_success() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "flaky.yml",
+            r#"
+name: Flaky
+start_time: 2025-01-20T12:00:00+00:00
+retries: 3
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )])
+        .await;
+        let store = Arc::new(InMemoryStatusStore::new());
+        let mut server = PrincipalServer::new("fake_ins".to_string(), workflows, store.clone());
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::RunTask(
+                "flaky".to_string(),
+                HashMap::new(),
+                false,
+                None,
+            ))
+            .await;
+        assert!(matches!(resp, ClientResponseMessage::SuccessWithPayload(_)));
+
+        // fails twice then succeeds
+        let mut instance_ids = Vec::new();
+        for status in [RunStatus::FAILED, RunStatus::FAILED, RunStatus::COMPLETED] {
+            let workflow = server.task_queue.get().await.unwrap();
+            let wf_ins_id = workflow.instance_id().unwrap().clone();
+            assert_eq!(workflow.attempt() as usize, instance_ids.len() + 1);
+            for status in [RunStatus::RUNNING, status] {
+                let (resp, _) = server
+                    .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                        "agent-1".to_string(),
+                        "flaky".to_string(),
+                        wf_ins_id.clone().into(),
+                        status,
+                    ))
+                    .await;
+                assert_eq!(resp, ClientResponseMessage::Success);
+            }
+            instance_ids.push(wf_ins_id);
+        }
+        assert_eq!(server.task_queue.size().await, 0);
+        assert_eq!(instance_ids.iter().collect::<HashSet<_>>().len(), 3);
+
+        let last = store
+            .get_workflow_result(&instance_ids[2].as_str().into(), 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(last.status, RunStatus::COMPLETED.to_string());
+        assert_eq!(last.attempt, Some(3));
+        assert_eq!(last.retry_of.as_ref(), Some(&instance_ids[0]));
+        let first = store
+            .get_workflow_result(&instance_ids[0].as_str().into(), 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.attempt, None);
+    }
+
+    #[tokio::test]
+    async fn test_run_resolved_for_environment() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "load.yml",
+            r#"
+name: Load
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  load:
+    name: Load
+    config:
+      !Subprocess
+      cmd: ./load.sh
+      args: ["dev-db"]
+environments:
+  staging:
+    tasks:
+      load:
+        config:
+          args: ["staging-db"]
+  prod:
+    tasks:
+      load:
+        config:
+          cmd: ./load-prod.sh
+          args: ["prod-db"]
+"#,
+        )])
+        .await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+
+        for (environment, command) in [
+            ("staging", "./load.sh staging-db"),
+            ("prod", "./load-prod.sh prod-db"),
+        ] {
+            let (resp, _) = server
+                .handle_client_message(PrincipalAPI::RunTask(
+                    "load".to_string(),
+                    HashMap::new(),
+                    false,
+                    Some(environment.to_string()),
+                ))
+                .await;
+            assert!(matches!(resp, ClientResponseMessage::SuccessWithPayload(_)));
+            let workflow = server.task_queue.get().await.unwrap();
+            assert_eq!(
+                workflow.get_task("load").unwrap().get_exe_task().describe(),
+                command
+            );
+        }
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::RunTask(
+                "load".to_string(),
+                HashMap::new(),
+                false,
+                Some("qa".to_string()),
+            ))
+            .await;
+        assert!(
+            matches!(&resp, ClientResponseMessage::Unprocessable(e) if e.contains("no environment 'qa'")),
+            "{resp:?}"
+        );
+        assert_eq!(server.task_queue.size().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_exclusive_workflow_reserves_agent() {
+        let files = [("heavy.yml", "Heavy", true), ("light.yml", "Light", false)].map(
+            |(file, name, exclusive)| {
+                (
+                    file,
+                    format!(
+                        r#"
+name: {name}
+start_time: 2025-01-20T12:00:00+00:00
+exclusive_agent: {exclusive}
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#
+                    ),
+                )
+            },
+        );
+        let (workflows, _dir) = workflow_store_with(&files).await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        for agent_id in ["agent-1", "agent-2"] {
+            server
+                .handle_client_message(PrincipalAPI::RegisterAgent(
+                    agent_id.to_string(),
+                    None,
+                    Some(4),
+                    None,
+                    None,
+                    None,
+                ))
+                .await;
+        }
+        let run = |workflow_id: &str| {
+            PrincipalAPI::RunTask(workflow_id.to_string(), HashMap::new(), false, None)
+        };
+        let fetch = |agent_id: &str| PrincipalAPI::FetchWorkflow(agent_id.to_string(), None);
+
+        server.handle_client_message(run("heavy")).await;
+        server.handle_client_message(run("light")).await;
+        let (resp, _) = server.handle_client_message(fetch("agent-1")).await;
+        let heavy = Workflow::try_from(resp.payload()).unwrap();
+        assert!(heavy.exclusive_agent());
+
+        // the agent running the exclusive workflow is given nothing else
+        let (resp, _) = server.handle_client_message(fetch("agent-1")).await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        assert_eq!(server.task_queue.size().await, 1);
+        let (resp, _) = server.handle_client_message(fetch("agent-2")).await;
+        let light = Workflow::try_from(resp.payload()).unwrap();
+        assert!(!light.exclusive_agent());
+
+        // and an exclusive workflow waits for an agent running nothing else
+        server.handle_client_message(run("heavy")).await;
+        let (resp, _) = server.handle_client_message(fetch("agent-2")).await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        server
+            .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                "agent-1".to_string(),
+                heavy.id().clone(),
+                heavy.instance_id().unwrap().clone().into(),
+                RunStatus::COMPLETED,
+            ))
+            .await;
+        let (resp, _) = server.handle_client_message(fetch("agent-1")).await;
+        assert!(
+            Workflow::try_from(resp.payload())
+                .unwrap()
+                .exclusive_agent()
+        );
+        assert_eq!(server.task_queue.size().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stopped_agent_told_to_shut_down_then_deregistered() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "simple-cmd.yml",
+            r#"
+name: Simple
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )])
+        .await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let agent_id = "agent-1".to_string();
+        let heartbeat =
+            PrincipalAPI::RegisterAgent(agent_id.clone(), None, Some(2), None, None, None);
+        server.handle_client_message(heartbeat.clone()).await;
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::StopAgent(agent_id.clone(), None))
+            .await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        let (resp, _) = server.handle_client_message(heartbeat.clone()).await;
+        assert_eq!(
+            resp,
+            ClientResponseMessage::SuccessWithPayload(AgentAPI::Shutdown.to_string())
+        );
+
+        // the stopping agent is given no new work
+        server
+            .handle_client_message(PrincipalAPI::RunTask(
+                "simple-cmd".to_string(),
+                HashMap::new(),
+                false,
+                None,
+            ))
+            .await;
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::FetchWorkflow(agent_id.clone(), None))
+            .await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+
+        // and is deregistered once it confirms it is exiting
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::AgentStopped(agent_id.clone()))
+            .await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        assert!(server.live_agents.get_agent(&agent_id).await.is_err());
+
+        // an agent starting again with the same id isn't told to shut down
+        let (resp, _) = server.handle_client_message(heartbeat).await;
+        assert_eq!(resp, registered_afresh());
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::StopAgent("missing".to_string(), None))
+            .await;
+        assert!(matches!(resp, ClientResponseMessage::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_drained_agent_gets_no_new_workflows() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "simple-cmd.yml",
+            r#"
+name: Simple
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )])
+        .await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let agent_id = "agent-1".to_string();
+        let heartbeat =
+            PrincipalAPI::RegisterAgent(agent_id.clone(), None, Some(2), None, None, None);
+        let fetch = PrincipalAPI::FetchWorkflow(agent_id.clone(), None);
+        let run = PrincipalAPI::RunTask("simple-cmd".to_string(), HashMap::new(), false, None);
+        server.handle_client_message(heartbeat.clone()).await;
+
+        // one workflow in flight before the agent is drained
+        server.handle_client_message(run.clone()).await;
+        let (resp, _) = server.handle_client_message(fetch.clone()).await;
+        assert!(matches!(resp, ClientResponseMessage::SuccessWithPayload(_)));
+        server
+            .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                agent_id.clone(),
+                "simple-cmd".to_string(),
+                "in-flight".into(),
+                RunStatus::RUNNING,
+            ))
+            .await;
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::DrainAgent(agent_id.clone(), true, None))
+            .await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        let (resp, _) = server.handle_client_message(heartbeat.clone()).await;
+        assert_eq!(
+            resp,
+            ClientResponseMessage::SuccessWithPayload(AgentAPI::SetDrain(true).to_string())
+        );
+
+        // new work stays on the queue
+        server.handle_client_message(run).await;
+        let (resp, _) = server.handle_client_message(fetch.clone()).await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        assert_eq!(server.task_queue.size().await, 1);
+
+        // the in-flight workflow still finishes
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                agent_id.clone(),
+                "simple-cmd".to_string(),
+                "in-flight".into(),
+                RunStatus::COMPLETED,
+            ))
+            .await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        let agent = server.live_agents.get_agent(&agent_id).await.unwrap();
+        assert_eq!(agent.utilisation(), 0);
+        assert!(agent.is_drained());
+
+        // re-enabled agents pick up the waiting work
+        server
+            .handle_client_message(PrincipalAPI::DrainAgent(agent_id.clone(), false, None))
+            .await;
+        let (resp, _) = server.handle_client_message(heartbeat).await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        let (resp, _) = server.handle_client_message(fetch).await;
+        assert!(matches!(resp, ClientResponseMessage::SuccessWithPayload(_)));
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::DrainAgent("unknown".to_string(), true, None))
+            .await;
+        assert!(matches!(resp, ClientResponseMessage::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_server_survives_malformed_messages() {
+        use tokio::io::AsyncWriteExt;
+
+        let port = 9992;
+        let endpoint = get_server_tcp_uri("127.0.0.1", port);
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        tokio::spawn(async move { server.start("0.0.0.0", port).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // deterministic pseudo-random bytes
+        let mut seed: u32 = 42;
+        let mut random_bytes = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    (seed >> 16) as u8
+                })
+                .collect()
+        };
+
+        // something that doesn't speak ZMQ at all
+        for len in [1, 7, 64, 1024] {
+            let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port as u16))
+                .await
+                .unwrap();
+            let _ = stream.write_all(&random_bytes(len)).await;
+            drop(stream);
+        }
+
+        // valid frames with garbage or truncated requests inside
+        let mut bodies: Vec<ZmqMessage> = (0..5)
+            .map(|i| ZmqMessage::from(random_bytes(8 << i)))
+            .collect();
+        bodies.push(ZmqMessage::from("AGENTTASKSTATUS\x01agent"));
+        bodies.push(ZmqMessage::from("QUERYLOGS\x01notanumber"));
+        bodies.push(ZmqMessage::from(""));
+        for body in bodies {
+            let resp = send_recv_with_timeout(endpoint.clone(), body, Duration::from_secs(2))
+                .await
+                .expect("server should respond to malformed requests");
+            assert!(matches!(
+                ClientResponseMessage::from(resp),
+                ClientResponseMessage::ClientError(_)
+            ));
+        }
+
+        let resp =
+            send_recv_with_timeout(endpoint, PrincipalAPI::Ping.into(), Duration::from_secs(2))
+                .await
+                .unwrap();
+        assert_eq!(
+            ClientResponseMessage::from(resp),
+            ClientResponseMessage::Pong
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_rejects_oversized_messages() {
+        let port = 9991;
+        let endpoint = get_server_tcp_uri("127.0.0.1", port);
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        tokio::spawn(async move { server.start("0.0.0.0", port).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let max_bytes = get_cdktr_setting!(CDKTR_MAX_MESSAGE_BYTES, usize);
+        let huge_param = "x".repeat(max_bytes + 1);
+        let msg = PrincipalAPI::RunTask(
+            "simple-cmd".to_string(),
+            HashMap::from([("arg".to_string(), huge_param)]),
+            false,
+            None,
+        );
+        let resp = send_recv_with_timeout(endpoint.clone(), msg.into(), Duration::from_secs(10))
+            .await
+            .expect("server should respond to oversized requests");
+        assert_eq!(
+            ClientResponseMessage::from(resp),
+            ClientResponseMessage::ClientError("message too large".to_string())
+        );
+
+        let resp =
+            send_recv_with_timeout(endpoint, PrincipalAPI::Ping.into(), Duration::from_secs(2))
+                .await
+                .unwrap();
+        assert_eq!(
+            ClientResponseMessage::from(resp),
+            ClientResponseMessage::Pong
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_query_does_not_delay_ping() {
+        let port = 9989;
+        let endpoint = get_server_tcp_uri("0.0.0.0", port);
+        let store = InMemoryStatusStore::new();
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(store.clone()),
+        );
+        tokio::spawn(async move { server.start("0.0.0.0", port).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        store.stall(Duration::from_secs(2)).await;
+        let query_endpoint = endpoint.clone();
+        let query_handle = tokio::spawn(async move {
+            let start = Instant::now();
+            let resp = send_recv_with_timeout(
+                query_endpoint,
+                PrincipalAPI::QueryLogs(None, None, None, None, false, None).into(),
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+            (ClientResponseMessage::from(resp), start.elapsed())
+        });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let start = Instant::now();
+        let resp =
+            send_recv_with_timeout(endpoint, PrincipalAPI::Ping.into(), Duration::from_secs(1))
+                .await
+                .unwrap();
+        assert_eq!(
+            ClientResponseMessage::from(resp),
+            ClientResponseMessage::Pong
+        );
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let (resp, elapsed) = query_handle.await.unwrap();
+        assert!(matches!(resp, ClientResponseMessage::SuccessWithPayload(_)));
+        assert!(elapsed >= Duration::from_millis(1_500));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_workflow_long_poll_returns_enqueued_workflow() {
+        let port = 9993;
+        let endpoint = get_server_tcp_uri("0.0.0.0", port);
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let mut task_queue = server.task_queue.clone();
+        let workflow = Workflow::new(
+            "workflows/long-poll.yml".to_string(),
+            r#"
+name: Long Poll
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )
+        .unwrap();
+        tokio::spawn(async move { server.start("0.0.0.0", port).await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let fetch_endpoint = endpoint.clone();
+        let fetch_handle = tokio::spawn(async move {
+            let start = Instant::now();
+            let resp = send_recv_with_timeout(
+                fetch_endpoint,
+                PrincipalAPI::FetchWorkflow("agent-1".to_string(), Some(5_000)).into(),
+                Duration::from_secs(8),
+            )
+            .await
+            .unwrap();
+            (ClientResponseMessage::from(resp), start.elapsed())
+        });
+
+        // server keeps serving other clients while the fetch is held open
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let resp =
+            send_recv_with_timeout(endpoint, PrincipalAPI::Ping.into(), Duration::from_secs(1))
+                .await
+                .unwrap();
+        assert_eq!(
+            ClientResponseMessage::from(resp),
+            ClientResponseMessage::Pong
+        );
+
+        task_queue.put(workflow).await;
+        let (resp, elapsed) = fetch_handle.await.unwrap();
+        assert!(matches!(resp, ClientResponseMessage::SuccessWithPayload(_)));
+        assert!(elapsed < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_workflow_long_poll_capped() {
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        server.max_long_poll = Duration::from_millis(200);
+        let hold = server
+            .hold_request(&PrincipalAPI::FetchWorkflow(
+                "agent-1".to_string(),
+                Some(u64::MAX),
+            ))
+            .unwrap();
+        let start = Instant::now();
+        tokio::time::timeout(Duration::from_secs(5), hold)
+            .await
+            .expect("long poll should be capped at the server maximum");
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_register_agent_new() {
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let agent_id = String::from("localhost-4567");
+        let (resp, exit_code) = server
+            .register_agent(&agent_id, Some(PROTOCOL_VERSION), None, None, None, None)
+            .await;
+        {
+            server.live_agents.pop().await.unwrap();
+        }
+        assert!(resp == registered_afresh());
+        assert!(exit_code == 0)
+    }
+
+    #[tokio::test]
+    async fn test_register_agent_already_exists() {
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let agent_id = String::from("localhost-4567");
+        server
+            .register_agent(&agent_id, Some(PROTOCOL_VERSION), None, None, None, None)
+            .await;
+        let old_timestamp = { server.live_agents.pop().await.unwrap().get_last_ping_ts() };
+        sleep(Duration::from_micros(10));
+        let (resp, exit_code) = server
+            .register_agent(&agent_id, Some(PROTOCOL_VERSION), None, None, None, None)
+            .await;
+        let new_timestamp = { server.live_agents.pop().await.unwrap().get_last_ping_ts() };
+        assert!(new_timestamp > old_timestamp);
+        assert!(resp == registered_afresh());
+        assert!(exit_code == 0)
+    }
+
+    #[tokio::test]
+    async fn test_workflow_tracking_on_running_status() {
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+
+        let agent_id = "test-agent-001".to_string();
+        let workflow_id = "test-workflow".to_string();
+        let workflow_instance_id = "test-instance-001".to_string();
+
+        // Send RUNNING status update
+        let msg = PrincipalAPI::WorkflowStatusUpdate(
+            agent_id.clone(),
+            workflow_id.clone(),
+            workflow_instance_id.clone().into(),
+            cdktr_core::models::RunStatus::RUNNING,
+        );
 
         server.handle_client_message(msg).await;
 
@@ -351,7 +1912,7 @@ mod tests {
         let mut server = PrincipalServer::new(
             "fake_ins".to_string(),
             get_workflowstore().await,
-            DBClient::new(None).unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
         );
 
         let agent_id = "test-agent-001".to_string();
@@ -362,7 +1923,7 @@ mod tests {
             let msg = PrincipalAPI::WorkflowStatusUpdate(
                 agent_id.clone(),
                 workflow_id.clone(),
-                format!("test-instance-00{}", i),
+                format!("test-instance-00{}", i).into(),
                 cdktr_core::models::RunStatus::RUNNING,
             );
             server.handle_client_message(msg).await;
@@ -378,7 +1939,7 @@ mod tests {
         let mut server = PrincipalServer::new(
             "fake_ins".to_string(),
             get_workflowstore().await,
-            DBClient::new(None).unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
         );
 
         let agent_id = "test-agent-001".to_string();
@@ -389,7 +1950,7 @@ mod tests {
         let msg_running = PrincipalAPI::WorkflowStatusUpdate(
             agent_id.clone(),
             workflow_id.clone(),
-            workflow_instance_id.clone(),
+            workflow_instance_id.clone().into(),
             cdktr_core::models::RunStatus::RUNNING,
         );
         server.handle_client_message(msg_running).await;
@@ -398,7 +1959,7 @@ mod tests {
         let msg_completed = PrincipalAPI::WorkflowStatusUpdate(
             agent_id.clone(),
             workflow_id.clone(),
-            workflow_instance_id.clone(),
+            workflow_instance_id.clone().into(),
             cdktr_core::models::RunStatus::COMPLETED,
         );
         server.handle_client_message(msg_completed).await;
@@ -413,7 +1974,7 @@ mod tests {
         let mut server = PrincipalServer::new(
             "fake_ins".to_string(),
             get_workflowstore().await,
-            DBClient::new(None).unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
         );
 
         let agent_id = "test-agent-001".to_string();
@@ -424,7 +1985,7 @@ mod tests {
         let msg_running = PrincipalAPI::WorkflowStatusUpdate(
             agent_id.clone(),
             workflow_id.clone(),
-            workflow_instance_id.clone(),
+            workflow_instance_id.clone().into(),
             cdktr_core::models::RunStatus::RUNNING,
         );
         server.handle_client_message(msg_running).await;
@@ -433,7 +1994,7 @@ mod tests {
         let msg_failed = PrincipalAPI::WorkflowStatusUpdate(
             agent_id.clone(),
             workflow_id.clone(),
-            workflow_instance_id.clone(),
+            workflow_instance_id.clone().into(),
             cdktr_core::models::RunStatus::FAILED,
         );
         server.handle_client_message(msg_failed).await;
@@ -448,7 +2009,7 @@ mod tests {
         let mut server = PrincipalServer::new(
             "fake_ins".to_string(),
             get_workflowstore().await,
-            DBClient::new(None).unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
         );
 
         let agent_id = "test-agent-001".to_string();
@@ -459,7 +2020,7 @@ mod tests {
             let msg = PrincipalAPI::WorkflowStatusUpdate(
                 agent_id.clone(),
                 workflow_id.clone(),
-                format!("test-instance-00{}", i),
+                format!("test-instance-00{}", i).into(),
                 cdktr_core::models::RunStatus::RUNNING,
             );
             server.handle_client_message(msg).await;
@@ -469,7 +2030,7 @@ mod tests {
         let msg_completed = PrincipalAPI::WorkflowStatusUpdate(
             agent_id.clone(),
             workflow_id.clone(),
-            "test-instance-001".to_string(),
+            "test-instance-001".into(),
             cdktr_core::models::RunStatus::COMPLETED,
         );
         server.handle_client_message(msg_completed).await;
@@ -491,10 +2052,10 @@ mod tests {
         let server = PrincipalServer::new(
             "fake_ins".to_string(),
             get_workflowstore().await,
-            DBClient::new(None).unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
         );
 
-        let (_live_agents, agent_workflows, _db_client) = server.get_agent_tracking();
+        let (_live_agents, agent_workflows, _store) = server.get_agent_tracking();
 
         // Verify we can access the returned structures
         tokio::spawn(async move {
@@ -510,7 +2071,7 @@ mod tests {
         let mut server = PrincipalServer::new(
             "fake_ins".to_string(),
             get_workflowstore().await,
-            DBClient::new(None).unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
         );
 
         let (response, exit_code) = server
@@ -533,7 +2094,7 @@ mod tests {
         let mut server = PrincipalServer::new(
             "fake_ins".to_string(),
             get_workflowstore().await,
-            DBClient::new(None).unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
         );
 
         // Register two agents
@@ -541,10 +2102,24 @@ mod tests {
         let agent2_id = "agent-test-002".to_string();
 
         server
-            .handle_client_message(PrincipalAPI::RegisterAgent(agent1_id.clone()))
+            .handle_client_message(PrincipalAPI::RegisterAgent(
+                agent1_id.clone(),
+                Some(PROTOCOL_VERSION),
+                None,
+                None,
+                None,
+                None,
+            ))
             .await;
         server
-            .handle_client_message(PrincipalAPI::RegisterAgent(agent2_id.clone()))
+            .handle_client_message(PrincipalAPI::RegisterAgent(
+                agent2_id.clone(),
+                Some(PROTOCOL_VERSION),
+                None,
+                None,
+                None,
+                None,
+            ))
             .await;
 
         // Get registered agents
@@ -566,4 +2141,1282 @@ mod tests {
             _ => panic!("Expected SuccessWithPayload"),
         }
     }
+
+    #[tokio::test]
+    async fn test_agent_metrics_in_agent_listing() {
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let metrics = AgentMetrics {
+            timestamp_ms: 1_700_000_000_000,
+            running_workflows: 1,
+            cpu_percent: 42.0,
+            memory_used_bytes: 512,
+            memory_total_bytes: 1_024,
+            running_tasks: HashMap::from([("etl/extract".to_string(), 1)]),
+        };
+
+        // metrics from an agent that hasn't registered are rejected
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::AgentMetrics(
+                "agent-1".to_string(),
+                metrics.clone(),
+            ))
+            .await;
+        assert!(matches!(resp, ClientResponseMessage::NotFound(_)));
+
+        for agent_id in ["agent-1", "agent-2"] {
+            server
+                .handle_client_message(PrincipalAPI::RegisterAgent(
+                    agent_id.to_string(),
+                    Some(PROTOCOL_VERSION),
+                    None,
+                    None,
+                    None,
+                    None,
+                ))
+                .await;
+        }
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::AgentMetrics(
+                "agent-1".to_string(),
+                metrics.clone(),
+            ))
+            .await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::GetRegisteredAgents)
+            .await;
+        let agents: Vec<cdktr_api::models::AgentInfo> =
+            serde_json::from_str(&resp.payload()).unwrap();
+        let agent = |id: &str| agents.iter().find(|a| a.agent_id == id).unwrap();
+        assert_eq!(agent("agent-1").metrics, Some(metrics));
+        assert_eq!(agent("agent-2").metrics, None);
+    }
+
+    #[tokio::test]
+    async fn test_get_cluster_capacity() {
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        // an older agent that doesn't report its concurrency adds no slots
+        for (agent_id, max_concurrency) in
+            [("agent-1", Some(4)), ("agent-2", Some(2)), ("old", None)]
+        {
+            server
+                .handle_client_message(PrincipalAPI::RegisterAgent(
+                    agent_id.to_string(),
+                    Some(PROTOCOL_VERSION),
+                    max_concurrency,
+                    None,
+                    None,
+                    None,
+                ))
+                .await;
+        }
+        // re-registering with a new concurrency replaces the old one
+        server
+            .handle_client_message(PrincipalAPI::RegisterAgent(
+                "agent-2".to_string(),
+                Some(PROTOCOL_VERSION),
+                Some(3),
+                None,
+                None,
+                None,
+            ))
+            .await;
+        for (agent_id, wf_ins_id) in [
+            ("agent-1", "ins-1"),
+            ("agent-1", "ins-2"),
+            ("agent-2", "ins-3"),
+            ("old", "ins-4"),
+        ] {
+            server
+                .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                    agent_id.to_string(),
+                    "wf".to_string(),
+                    wf_ins_id.to_string().into(),
+                    RunStatus::RUNNING,
+                ))
+                .await;
+        }
+        server
+            .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                "agent-1".to_string(),
+                "wf".to_string(),
+                "ins-2".into(),
+                RunStatus::COMPLETED,
+            ))
+            .await;
+        let workflow = Workflow::new(
+            "workflows/queued.yml".to_string(),
+            r#"
+name: Queued
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )
+        .unwrap();
+        server
+            .task_queue
+            .put_multiple(vec![workflow.clone(), workflow])
+            .await;
+
+        let (response, _) = server
+            .handle_client_message(PrincipalAPI::GetClusterCapacity)
+            .await;
+        let capacity: cdktr_api::models::ClusterCapacity = match response {
+            ClientResponseMessage::SuccessWithPayload(payload) => {
+                serde_json::from_str(&payload).unwrap()
+            }
+            other => panic!("Expected SuccessWithPayload, got {:?}", other),
+        };
+        assert_eq!(
+            capacity,
+            cdktr_api::models::ClusterCapacity {
+                agents: 3,
+                total_slots: 7,
+                used_slots: 3,
+                queued: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_ids_not_found() {
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        for msg in [
+            PrincipalAPI::RunTask("no.such.flow".to_string(), HashMap::new(), false, None),
+            PrincipalAPI::DryRunTask("no.such.flow".to_string(), HashMap::new()),
+            PrincipalAPI::GetWorkflowResult("no-such-run".into()),
+            PrincipalAPI::DrainAgent("no-such-agent".to_string(), true, None),
+        ] {
+            let (resp, _) = server.handle_client_message(msg.clone()).await;
+            assert!(
+                matches!(resp, ClientResponseMessage::NotFound(_)),
+                "{:?} -> {:?}",
+                msg,
+                resp
+            );
+        }
+        assert_eq!(server.task_queue.size().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_workflow_progress_in_result() {
+        let store = Arc::new(InMemoryStatusStore::new());
+        store
+            .record_workflow_statuses(vec![WorkflowStatusUpdate::new(
+                "flow".to_string(),
+                "run-1".into(),
+                RunStatus::RUNNING.to_string(),
+                1_000,
+            )])
+            .await
+            .unwrap();
+        let mut server =
+            PrincipalServer::new("fake_ins".to_string(), get_workflowstore().await, store);
+        let mut progress = Vec::new();
+        for tasks_completed in 1..=3 {
+            let (resp, _) = server
+                .handle_client_message(PrincipalAPI::WorkflowProgress(
+                    "agent".to_string(),
+                    "run-1".into(),
+                    tasks_completed,
+                    3,
+                ))
+                .await;
+            assert_eq!(resp, ClientResponseMessage::Success);
+            let (resp, _) = server
+                .handle_client_message(PrincipalAPI::GetWorkflowResult("run-1".into()))
+                .await;
+            let result: cdktr_api::models::WorkflowResult =
+                serde_json::from_str(&resp.payload()).unwrap();
+            progress.push(result.progress.unwrap());
+        }
+        assert_eq!(
+            progress
+                .iter()
+                .map(|p| (p.tasks_completed, p.tasks_total))
+                .collect::<Vec<_>>(),
+            vec![(1, 3), (2, 3), (3, 3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_workflow_tail() {
+        let store = Arc::new(InMemoryStatusStore::new());
+        store
+            .record_workflow_statuses(vec![WorkflowStatusUpdate::new(
+                "flow".to_string(),
+                "run-1".into(),
+                RunStatus::RUNNING.to_string(),
+                1_000,
+            )])
+            .await
+            .unwrap();
+        let logs = (0..25)
+            .map(|i| {
+                LogMessage::new(
+                    "flow".to_string(),
+                    "Flow".to_string(),
+                    // logs of another run are left out
+                    if i % 5 == 0 { "run-2" } else { "run-1" }.to_string(),
+                    "task".to_string(),
+                    "task-1".to_string(),
+                    1_000 + i,
+                    "INFO".to_string(),
+                    format!("line {i}"),
+                )
+            })
+            .collect();
+        store.persist_logs(logs).await.unwrap();
+        let mut server =
+            PrincipalServer::new("fake_ins".to_string(), get_workflowstore().await, store);
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::GetWorkflowTail("run-1".into(), 3))
+            .await;
+        let lines: Vec<String> = serde_json::from_str(&resp.payload()).unwrap();
+        assert_eq!(lines.len(), 3);
+        for (line, i) in lines.iter().zip([22, 23, 24]) {
+            assert!(line.ends_with(&format!("line {i}")), "{line}");
+        }
+
+        // fewer logs than asked for returns them all
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::GetWorkflowTail("run-1".into(), 100))
+            .await;
+        let lines: Vec<String> = serde_json::from_str(&resp.payload()).unwrap();
+        assert_eq!(lines.len(), 20);
+        assert!(lines[0].ends_with("line 1"));
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::GetWorkflowTail("no-such-run".into(), 3))
+            .await;
+        assert!(matches!(resp, ClientResponseMessage::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_query_logs_paged() {
+        let store = Arc::new(InMemoryStatusStore::new());
+        let logs = (0..25)
+            .map(|i| {
+                LogMessage::new(
+                    "flow".to_string(),
+                    "Flow".to_string(),
+                    "run-1".to_string(),
+                    "task".to_string(),
+                    "task-1".to_string(),
+                    1_000 + i,
+                    "INFO".to_string(),
+                    format!("line {i}"),
+                )
+            })
+            .collect();
+        store.persist_logs(logs).await.unwrap();
+        let mut server =
+            PrincipalServer::new("fake_ins".to_string(), get_workflowstore().await, store);
+        let mut lines = Vec::new();
+        for offset in (0..).step_by(10) {
+            let msg = PrincipalAPI::QueryLogs(
+                Some(2_000),
+                Some(0),
+                None,
+                Some("run-1".into()),
+                false,
+                Some(LogPage { offset, limit: 10 }),
+            );
+            let (resp, _) = server.handle_client_message(msg).await;
+            let page: Vec<serde_json::Value> = serde_json::from_str(&resp.payload()).unwrap();
+            lines.extend(
+                page.iter()
+                    .map(|l| l["payload"].as_str().unwrap().to_string()),
+            );
+            if page.len() < 10 {
+                break;
+            }
+        }
+        assert_eq!(
+            lines,
+            (0..25).map(|i| format!("line {i}")).collect::<Vec<_>>()
+        );
+
+        // unpaged queries return records too, for the client to format
+        let msg =
+            PrincipalAPI::QueryLogs(Some(2_000), Some(0), None, Some("run-1".into()), true, None);
+        let (resp, _) = server.handle_client_message(msg).await;
+        let logs: Vec<LogMessage> = serde_json::from_str(&resp.payload()).unwrap();
+        assert_eq!(logs.len(), 25);
+        assert_eq!(logs[24].payload, "line 24");
+    }
+
+    #[tokio::test]
+    async fn test_replay_run() {
+        let workflow = |arg: &str| {
+            format!(
+                r#"
+name: Export
+start_time: 2025-01-20T12:00:00+00:00
+params:
+  table:
+    type: string
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["{arg}"]
+"#
+            )
+        };
+        let (workflows, dir) =
+            workflow_store_with(&[("export.yml", workflow("${params.table}"))]).await;
+        let store = Arc::new(InMemoryStatusStore::new());
+        let mut server = PrincipalServer::new("fake_ins".to_string(), workflows, store.clone());
+        server
+            .handle_client_message(PrincipalAPI::RunTask(
+                "export".to_string(),
+                HashMap::from([("table".to_string(), "customers".to_string())]),
+                false,
+                None,
+            ))
+            .await;
+        let original = server.task_queue.get().await.unwrap();
+        let original_id = original.instance_id().unwrap().clone();
+        for status in [RunStatus::RUNNING, RunStatus::COMPLETED] {
+            server
+                .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                    "agent-1".to_string(),
+                    "export".to_string(),
+                    original_id.clone().into(),
+                    status,
+                ))
+                .await;
+        }
+
+        // the workflow changes after the run
+        std::fs::write(dir.path().join("export.yml"), workflow("changed")).unwrap();
+        server.workflows.refresh_workflows().await;
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::ReplayRun(original_id.as_str().into()))
+            .await;
+        let replay_id = match resp {
+            ClientResponseMessage::SuccessWithPayload(replay_id) => replay_id,
+            other => panic!("Expected the replay instance id, got {:?}", other),
+        };
+        assert_ne!(replay_id, original_id);
+        let replay = server.task_queue.get().await.unwrap();
+        assert_eq!(replay.instance_id(), Some(&replay_id));
+        assert_eq!(replay.attempt(), 1);
+        let task = serde_json::to_string(replay.get_task("task1").unwrap()).unwrap();
+        assert!(task.contains("customers"), "{task}");
+        assert!(!task.contains("changed"), "{task}");
+
+        server
+            .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                "agent-1".to_string(),
+                "export".to_string(),
+                replay_id.clone().into(),
+                RunStatus::RUNNING,
+            ))
+            .await;
+        let result = store
+            .get_workflow_result(&replay_id.as_str().into(), 0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.annotations.get("replay_of"), Some(&original_id));
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::ReplayRun("missing".into()))
+            .await;
+        assert!(matches!(resp, ClientResponseMessage::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_singleton_workflow() {
+        let files = [("migrate", "queue"), ("exclusive", "reject")].map(|(name, mode)| {
+            (
+                format!("{name}.yml"),
+                format!(
+                    r#"
+name: {name}
+start_time: 2025-01-20T12:00:00+00:00
+singleton: true
+singleton_mode: {mode}
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#
+                ),
+            )
+        });
+        let (workflows, _dir) = workflow_store_with(&files).await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let fetch = PrincipalAPI::FetchWorkflow("agent-1".to_string(), None);
+        let run = |workflow_id: &str| {
+            PrincipalAPI::RunTask(workflow_id.to_string(), HashMap::new(), false, None)
+        };
+
+        // queue mode holds the second run on the queue until the first finishes
+        for _ in 0..2 {
+            let (resp, _) = server.handle_client_message(run("migrate")).await;
+            assert!(matches!(resp, ClientResponseMessage::SuccessWithPayload(_)));
+        }
+        let (resp, _) = server.handle_client_message(fetch.clone()).await;
+        let first = Workflow::try_from(resp.payload()).unwrap();
+        let (resp, _) = server.handle_client_message(fetch.clone()).await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        assert_eq!(server.task_queue.size().await, 1);
+        for status in [RunStatus::RUNNING, RunStatus::COMPLETED] {
+            server
+                .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                    "agent-1".to_string(),
+                    "migrate".to_string(),
+                    first.instance_id().unwrap().clone().into(),
+                    status,
+                ))
+                .await;
+        }
+        let (resp, _) = server.handle_client_message(fetch.clone()).await;
+        let second = Workflow::try_from(resp.payload()).unwrap();
+        assert_ne!(second.instance_id(), first.instance_id());
+
+        // reject mode refuses the second run until the first finishes
+        server.handle_client_message(run("exclusive")).await;
+        let (resp, _) = server.handle_client_message(fetch.clone()).await;
+        let running = Workflow::try_from(resp.payload()).unwrap();
+        assert_eq!(running.name(), "exclusive");
+        let (resp, _) = server.handle_client_message(run("exclusive")).await;
+        assert!(matches!(resp, ClientResponseMessage::Retryable(_)));
+        server
+            .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                "agent-1".to_string(),
+                "exclusive".to_string(),
+                running.instance_id().unwrap().clone().into(),
+                RunStatus::FAILED,
+            ))
+            .await;
+        let (resp, _) = server.handle_client_message(run("exclusive")).await;
+        assert!(matches!(resp, ClientResponseMessage::SuccessWithPayload(_)));
+    }
+
+    #[tokio::test]
+    async fn test_workflows_routed_to_agents_with_their_executors() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "python.yml",
+            r#"
+name: python
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  extract:
+    name: Extract
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+  transform:
+    name: Transform
+    depends: ["extract"]
+    config:
+      !UvPython
+      script_path: ./transform.py
+"#,
+        )])
+        .await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let register = |agent_id: &str, executors: &[&str]| {
+            PrincipalAPI::RegisterAgent(
+                agent_id.to_string(),
+                Some(PROTOCOL_VERSION),
+                None,
+                Some(executors.iter().map(|e| e.to_string()).collect()),
+                None,
+                None,
+            )
+        };
+        let fetch = |agent_id: &str| PrincipalAPI::FetchWorkflow(agent_id.to_string(), None);
+        let run = PrincipalAPI::RunTask("python".to_string(), HashMap::new(), false, None);
+
+        // no registered agent runs UvPython so the run would never be picked up
+        server
+            .handle_client_message(register("shell-agent", &["Subprocess"]))
+            .await;
+        let (resp, _) = server.handle_client_message(run.clone()).await;
+        assert!(
+            matches!(&resp, ClientResponseMessage::Unprocessable(msg) if msg.contains("Missing agents") && msg.contains("UvPython"))
+        );
+        assert!(server.task_queue.is_empty().await);
+
+        // once an agent that runs it registers, only that agent is sent the run
+        server
+            .handle_client_message(register("python-agent", &["Subprocess", "UvPython"]))
+            .await;
+        let (resp, _) = server.handle_client_message(run).await;
+        assert!(matches!(resp, ClientResponseMessage::SuccessWithPayload(_)));
+        let (resp, _) = server.handle_client_message(fetch("shell-agent")).await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        assert_eq!(server.task_queue.size().await, 1);
+        let (resp, _) = server.handle_client_message(fetch("python-agent")).await;
+        let workflow = Workflow::try_from(resp.payload()).unwrap();
+        assert_eq!(workflow.name(), "python");
+        assert!(server.task_queue.is_empty().await);
+    }
+
+    async fn diagnose(
+        server: &mut PrincipalServer,
+        workflow_instance_id: &str,
+    ) -> cdktr_api::models::DispatchDiagnosis {
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::DiagnoseDispatch(workflow_instance_id.into()))
+            .await;
+        serde_json::from_str(&resp.payload()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_dispatch_no_matching_agent() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "python.yml",
+            r#"
+name: python
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  transform:
+    name: Transform
+    config:
+      !UvPython
+      script_path: ./transform.py
+"#,
+        )])
+        .await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        // runs are queued while no agents are registered
+        server
+            .handle_client_message(PrincipalAPI::RunTask(
+                "python".to_string(),
+                HashMap::new(),
+                false,
+                None,
+            ))
+            .await;
+        let queued = server.task_queue.snapshot().await;
+        let instance_id = queued[0].instance_id().unwrap().clone();
+        let diagnosis = diagnose(&mut server, &instance_id).await;
+        assert_eq!(diagnosis.queue_position, Some(1));
+        assert_eq!(diagnosis.agents, 0);
+        assert_eq!(diagnosis.reasons, vec!["No agents are registered"]);
+
+        server
+            .handle_client_message(PrincipalAPI::RegisterAgent(
+                "shell-agent".to_string(),
+                Some(PROTOCOL_VERSION),
+                None,
+                Some(vec!["Subprocess".to_string()]),
+                None,
+                None,
+            ))
+            .await;
+        let diagnosis = diagnose(&mut server, &instance_id).await;
+        assert_eq!(diagnosis.agents, 1);
+        assert_eq!(diagnosis.matching_agents, 0);
+        assert_eq!(diagnosis.available_agents, 0);
+        assert_eq!(diagnosis.missing_executors, vec!["UvPython"]);
+        assert!(diagnosis.reasons[0].contains("UvPython"));
+        // diagnosing a run leaves it on the queue
+        assert_eq!(server.task_queue.size().await, 1);
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::DiagnoseDispatch("missing".into()))
+            .await;
+        assert!(matches!(resp, ClientResponseMessage::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_dispatch_singleton_blocked() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "migrate.yml",
+            r#"
+name: migrate
+start_time: 2025-01-20T12:00:00+00:00
+singleton: true
+singleton_mode: queue
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )])
+        .await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        server
+            .handle_client_message(PrincipalAPI::RegisterAgent(
+                "agent-1".to_string(),
+                Some(PROTOCOL_VERSION),
+                Some(4),
+                None,
+                None,
+                None,
+            ))
+            .await;
+        for _ in 0..2 {
+            server
+                .handle_client_message(PrincipalAPI::RunTask(
+                    "migrate".to_string(),
+                    HashMap::new(),
+                    false,
+                    None,
+                ))
+                .await;
+        }
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::FetchWorkflow("agent-1".to_string(), None))
+            .await;
+        let first = Workflow::try_from(resp.payload()).unwrap();
+        let queued = server.task_queue.snapshot().await;
+        let second = queued[0].instance_id().unwrap().clone();
+
+        let diagnosis = diagnose(&mut server, &second).await;
+        assert_eq!(diagnosis.queue_position, Some(1));
+        assert_eq!(diagnosis.queue_length, 1);
+        assert_eq!(diagnosis.matching_agents, 1);
+        assert_eq!(diagnosis.available_agents, 1);
+        assert_eq!(diagnosis.blocked_by, first.instance_id().cloned());
+        assert_eq!(diagnosis.reasons.len(), 1);
+        assert!(diagnosis.reasons[0].contains("singleton"));
+
+        // a run that has been sent to an agent reports its status instead
+        server
+            .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                "agent-1".to_string(),
+                first.id().clone(),
+                first.instance_id().unwrap().clone().into(),
+                RunStatus::RUNNING,
+            ))
+            .await;
+        let diagnosis = diagnose(&mut server, first.instance_id().unwrap()).await;
+        assert_eq!(diagnosis.queue_position, None);
+        assert_eq!(diagnosis.status.as_deref(), Some("RUNNING"));
+        assert!(diagnosis.workflow_id.ends_with("migrate"));
+    }
+
+    /// Webhook that answers the first `failures` requests with a 500 and sends the JSON
+    /// body of each request it accepts down the channel
+    async fn mock_webhook(
+        failures: usize,
+    ) -> (
+        String,
+        tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>,
+    ) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut received = 0;
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                let body = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length: usize = head
+                            .lines()
+                            .find_map(|line| {
+                                line.to_lowercase()
+                                    .strip_prefix("content-length: ")
+                                    .map(str::to_string)
+                            })
+                            .and_then(|len| len.parse().ok())
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            break body.to_string();
+                        }
+                    }
+                };
+                received += 1;
+                let status = if received <= failures {
+                    "500 Internal Server Error"
+                } else {
+                    "200 OK"
+                };
+                let _ = stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        )
+                        .as_bytes(),
+                    )
+                    .await;
+                if received > failures {
+                    let _ = tx.send(serde_json::from_str(&body).unwrap());
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn test_finished_runs_notify_webhook() {
+        let (url, mut notified) = mock_webhook(1).await;
+        let (redacted_url, mut redacted) = mock_webhook(0).await;
+        let files = [
+            ("nightly", format!("url: {url}")),
+            (
+                "payroll",
+                format!("url: {redacted_url}\n  on: failure\n  redact: true"),
+            ),
+        ]
+        .map(|(name, notify)| {
+            (
+                format!("{name}.yml"),
+                format!(
+                    r#"
+name: {name}
+start_time: 2025-01-20T12:00:00+00:00
+notify:
+  {notify}
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#
+                ),
+            )
+        });
+        let (workflows, _dir) = workflow_store_with(&files).await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let mut finish = async |workflow_id: &str, instance_id: &str, status: RunStatus| {
+            for status in [RunStatus::RUNNING, status] {
+                server
+                    .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                        "agent-1".to_string(),
+                        workflow_id.to_string(),
+                        instance_id.to_string().into(),
+                        status,
+                    ))
+                    .await;
+            }
+        };
+
+        // the first attempt fails so the notification is sent again
+        finish("nightly", "run-1", RunStatus::COMPLETED).await;
+        let notification = tokio::time::timeout(Duration::from_secs(10), notified.recv())
+            .await
+            .expect("webhook should be notified")
+            .unwrap();
+        assert_eq!(notification["workflow_name"], "nightly");
+        assert_eq!(notification["workflow_instance_id"], "run-1");
+        assert_eq!(notification["status"], "COMPLETED");
+        assert_eq!(notification["agent_id"], "agent-1");
+
+        // only failures are notified, without the details of the run
+        finish("payroll", "run-2", RunStatus::COMPLETED).await;
+        finish("payroll", "run-3", RunStatus::FAILED).await;
+        let notification = tokio::time::timeout(Duration::from_secs(10), redacted.recv())
+            .await
+            .expect("webhook should be notified")
+            .unwrap();
+        assert_eq!(notification["workflow_instance_id"], "run-3");
+        assert_eq!(notification["status"], "FAILED");
+        assert!(notification.get("agent_id").is_none());
+        assert!(notification.get("workflow_name").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_flush_queue() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "flaky.yml",
+            r#"
+name: Flaky
+start_time: 2025-01-20T12:00:00+00:00
+retries: 1
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )])
+        .await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        for _ in 0..3 {
+            server
+                .handle_client_message(PrincipalAPI::RunTask(
+                    "flaky".to_string(),
+                    HashMap::new(),
+                    false,
+                    None,
+                ))
+                .await;
+        }
+        assert_eq!(server.task_queue.size().await, 3);
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::FlushQueue(None))
+            .await;
+        assert_eq!(
+            resp,
+            ClientResponseMessage::SuccessWithPayload("3".to_string())
+        );
+        assert_eq!(server.task_queue.size().await, 0);
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::FlushQueue(None))
+            .await;
+        assert_eq!(
+            resp,
+            ClientResponseMessage::SuccessWithPayload("0".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_actions_recorded_in_audit_log() {
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            get_workflowstore().await,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        server
+            .register_agent(
+                &"agent-1".to_string(),
+                Some(PROTOCOL_VERSION),
+                None,
+                None,
+                None,
+                None,
+            )
+            .await;
+
+        server
+            .handle_client_message_from(
+                PrincipalAPI::FlushQueue(Some("alice@host-a".to_string())),
+                "conn-1",
+            )
+            .await;
+        // requests that aren't administrative aren't audited
+        server
+            .handle_client_message_from(PrincipalAPI::Ping, "conn-1")
+            .await;
+        server
+            .handle_client_message_from(
+                PrincipalAPI::DrainAgent("agent-1".to_string(), true, Some("bob@host-b".into())),
+                "conn-2",
+            )
+            .await;
+        // actions that fail are recorded too, and clients that don't send an actor are
+        // recorded by their connection
+        server
+            .handle_client_message_from(
+                PrincipalAPI::StopAgent("missing".to_string(), None),
+                "conn-2",
+            )
+            .await;
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::GetAuditLog(10))
+            .await;
+        let entries: Vec<AuditEntry> = serde_json::from_str(&resp.payload()).unwrap();
+        let summary: Vec<(&str, &str, &str, &str, &str)> = entries
+            .iter()
+            .map(|e| {
+                (
+                    e.actor.as_str(),
+                    e.client.as_str(),
+                    e.action.as_str(),
+                    e.target.as_str(),
+                    e.result.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("unknown", "conn-2", "StopAgent", "missing", "NOTFOUND"),
+                ("bob@host-b", "conn-2", "DrainAgent", "agent-1", "OK"),
+                ("alice@host-a", "conn-1", "FlushQueue", "", "SUCCESS"),
+            ]
+        );
+        assert!(entries.iter().all(|e| e.timestamp_ms > 0));
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::GetAuditLog(1))
+            .await;
+        let entries: Vec<AuditEntry> = serde_json::from_str(&resp.payload()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "StopAgent");
+    }
+
+    #[tokio::test]
+    async fn test_run_with_no_agents() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "lonely.yml",
+            r#"
+name: Lonely
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )])
+        .await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+
+        // the submitter asked to be told to come back later
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::RunTask(
+                "lonely".to_string(),
+                HashMap::new(),
+                true,
+                None,
+            ))
+            .await;
+        assert_eq!(
+            resp,
+            ClientResponseMessage::Retryable("No agents available".to_string())
+        );
+        assert_eq!(server.task_queue.size().await, 0);
+
+        // otherwise the run is held on the queue and shows up as waiting
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::RunTask(
+                "lonely".to_string(),
+                HashMap::new(),
+                false,
+                None,
+            ))
+            .await;
+        let ClientResponseMessage::SuccessWithPayload(instance_id) = resp else {
+            panic!("Expected the instance id of the run, got {resp:?}");
+        };
+        assert_eq!(server.task_queue.size().await, 1);
+        let statuses = server.store.get_recent_workflow_statuses(10).await.unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert!(statuses[0].workflow_id().ends_with("lonely"));
+        assert_eq!(statuses[0].workflow_instance_id().as_str(), instance_id);
+        assert_eq!(statuses[0].status(), "WAITING");
+    }
+
+    #[tokio::test]
+    async fn test_queue_restored_after_restart() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "simple.yml",
+            r#"
+name: Simple
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )])
+        .await;
+        let store = Arc::new(InMemoryStatusStore::new());
+
+        let mut server =
+            PrincipalServer::new("fake_ins".to_string(), workflows.clone(), store.clone());
+        server
+            .handle_client_message(PrincipalAPI::RunTask(
+                "simple".to_string(),
+                HashMap::new(),
+                false,
+                None,
+            ))
+            .await;
+        let queued_instance_id = server
+            .task_queue
+            .clone()
+            .get()
+            .await
+            .and_then(|wf| wf.instance_id().cloned())
+            .expect("queued run should have an instance id");
+        // the principal goes down before the run is dispatched
+        drop(server);
+
+        let mut restarted = PrincipalServer::new("fake_ins".to_string(), workflows, store.clone());
+        assert_eq!(restarted.restore_queue().await.unwrap(), 1);
+        assert_eq!(restarted.task_queue.size().await, 1);
+
+        let (resp, _) = restarted
+            .handle_client_message(PrincipalAPI::FetchWorkflow("agent-1".to_string(), None))
+            .await;
+        let dispatched = match resp {
+            ClientResponseMessage::SuccessWithPayload(payload) => {
+                Workflow::try_from(payload).unwrap()
+            }
+            other => panic!("expected a workflow, got {:?}", other),
+        };
+        assert_eq!(dispatched.name(), "Simple");
+        assert_eq!(dispatched.instance_id(), Some(&queued_instance_id));
+        // a dispatched run isn't restored again
+        assert!(store.get_queued_workflows().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_next_run_reads_last_run_outputs() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "incremental.yml",
+            r#"
+name: Incremental
+start_time: 2025-01-20T12:00:00+00:00
+outputs:
+  watermark:
+    task: extract
+    default: "1970-01-01"
+tasks:
+  extract:
+    name: Extract
+    config:
+      !Subprocess
+      cmd: extract
+      args: ["--since", "${last_run.watermark}"]
+"#,
+        )])
+        .await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let extract_args = |workflow: Workflow| {
+            let task = workflow.get_task("extract").unwrap();
+            task.get_exe_task().describe()
+        };
+
+        server
+            .handle_client_message(PrincipalAPI::RunTask(
+                "incremental".to_string(),
+                HashMap::new(),
+                false,
+                None,
+            ))
+            .await;
+        let first = server.task_queue.get().await.unwrap();
+        assert_eq!(extract_args(first), "extract --since 1970-01-01");
+
+        let (resp, _) = server
+            .handle_client_message(PrincipalAPI::WorkflowOutputs(
+                "agent".to_string(),
+                "incremental".to_string(),
+                "first-run".into(),
+                HashMap::from([("watermark".to_string(), "2025-06-01".to_string())]),
+            ))
+            .await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+
+        server
+            .handle_client_message(PrincipalAPI::RunTask(
+                "incremental".to_string(),
+                HashMap::new(),
+                false,
+                None,
+            ))
+            .await;
+        let second = server.task_queue.get().await.unwrap();
+        assert_eq!(extract_args(second), "extract --since 2025-06-01");
+    }
+
+    /// Listens like an agent in push mode, passing on the name of each workflow pushed to
+    /// it with when it arrived. Agents that don't accept refuse every workflow as full
+    /// Listens on a free port for pushed workflows like an agent would, returning the port
+    /// along with the name and arrival time of each workflow pushed to it
+    async fn mock_push_agent(
+        accept: bool,
+    ) -> (
+        usize,
+        tokio::sync::mpsc::UnboundedReceiver<(String, Instant)>,
+    ) {
+        use zeromq::{SocketRecv, SocketSend};
+
+        let port = crate::testing::free_port();
+        let mut rep_socket =
+            cdktr_core::zmq_helpers::get_zmq_rep(&get_server_tcp_uri("127.0.0.1", port))
+                .await
+                .unwrap();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let msg = rep_socket.recv().await.unwrap();
+                let Ok(AgentAPI::Run(workflow)) = AgentAPI::try_from(msg) else {
+                    panic!("expected a pushed workflow");
+                };
+                let workflow = Workflow::try_from(workflow).unwrap();
+                let _ = tx.send((workflow.name().clone(), Instant::now()));
+                let response = if accept {
+                    ClientResponseMessage::Success
+                } else {
+                    ClientResponseMessage::Retryable("full".to_string())
+                };
+                rep_socket.send(response.into()).await.unwrap();
+            }
+        });
+        (port, rx)
+    }
+
+    #[tokio::test]
+    async fn test_push_dispatch_reaches_least_loaded_agent() {
+        let (workflows, _dir) = workflow_store_with(&[(
+            "simple-cmd.yml",
+            r#"
+name: Simple
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )])
+        .await;
+        let mut server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            workflows,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        server.dispatch_mode = DispatchMode::Push;
+        let (busy_port, mut busy) = mock_push_agent(false).await;
+        let (idle_port, mut idle) = mock_push_agent(true).await;
+        for (agent_id, port) in [("busy", busy_port), ("idle", idle_port)] {
+            server
+                .handle_client_message(PrincipalAPI::RegisterAgent(
+                    agent_id.to_string(),
+                    Some(PROTOCOL_VERSION),
+                    Some(2),
+                    None,
+                    Some(get_server_tcp_uri("127.0.0.1", port)),
+                    None,
+                ))
+                .await;
+        }
+        server
+            .handle_client_message(PrincipalAPI::WorkflowStatusUpdate(
+                "busy".to_string(),
+                "simple-cmd".to_string(),
+                "running-1".into(),
+                RunStatus::RUNNING,
+            ))
+            .await;
+        let run = PrincipalAPI::RunTask("simple-cmd".to_string(), HashMap::new(), false, None);
+
+        // the run is pushed to the least loaded agent as soon as it is queued
+        let queued_at = Instant::now();
+        server.handle_client_message(run.clone()).await;
+        let (name, arrived) = tokio::time::timeout(Duration::from_secs(5), idle.recv())
+            .await
+            .expect("run was never pushed")
+            .unwrap();
+        let push_latency = arrived - queued_at;
+        assert_eq!(name, "Simple");
+        assert!(server.task_queue.is_empty().await);
+        assert!(busy.try_recv().is_err());
+
+        // an agent pulling its work only gets the run on the fetch after it is queued
+        server.dispatch_mode = DispatchMode::Pull;
+        let fetch = PrincipalAPI::FetchWorkflow("idle".to_string(), None);
+        let poll_interval = Duration::from_millis(200);
+        let (resp, _) = server.handle_client_message(fetch.clone()).await;
+        assert_eq!(resp, ClientResponseMessage::Success);
+        let queued_at = Instant::now();
+        server.handle_client_message(run.clone()).await;
+        let pull_latency = loop {
+            tokio::time::sleep(poll_interval).await;
+            let (resp, _) = server.handle_client_message(fetch.clone()).await;
+            if matches!(resp, ClientResponseMessage::SuccessWithPayload(_)) {
+                break queued_at.elapsed();
+            }
+        };
+        assert!(
+            push_latency < pull_latency,
+            "push took {push_latency:?} but pull took {pull_latency:?}"
+        );
+
+        // a run the agent refuses goes back on the queue
+        server.dispatch_mode = DispatchMode::Push;
+        server
+            .handle_client_message(PrincipalAPI::DrainAgent("idle".to_string(), true, None))
+            .await;
+        server.handle_client_message(run).await;
+        tokio::time::timeout(Duration::from_secs(5), busy.recv())
+            .await
+            .expect("run was never pushed")
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.task_queue.is_empty().await {
+            assert!(
+                Instant::now() < deadline,
+                "refused run was not queued again"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(idle.try_recv().is_err());
+    }
 }
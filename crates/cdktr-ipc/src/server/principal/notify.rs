@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use cdktr_api::models::WorkflowResult;
+use cdktr_core::exceptions::GenericError;
+use cdktr_core::models::RunStatus;
+use cdktr_workflow::Workflow;
+use log::warn;
+use serde::Serialize;
+
+/// Number of times a notification is sent before the webhook is given up on
+const NOTIFY_ATTEMPTS: u32 = 3;
+
+/// Wait before a failed notification is first sent again, doubled for each attempt after
+const NOTIFY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Time the webhook has to respond to a notification
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Summary of a finished workflow run posted to the webhook of its workflow
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RunNotification {
+    workflow_id: String,
+    workflow_instance_id: String,
+    status: String,
+    duration_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    workflow_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    agent_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed_tasks: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<HashMap<String, String>>,
+}
+
+impl RunNotification {
+    pub fn new(
+        workflow: &Workflow,
+        workflow_instance_id: &str,
+        agent_id: &str,
+        status: &RunStatus,
+        result: Option<&WorkflowResult>,
+    ) -> Self {
+        Self {
+            workflow_id: workflow.id().clone(),
+            workflow_instance_id: workflow_instance_id.to_string(),
+            status: status.to_string(),
+            duration_ms: result.and_then(|r| r.duration_ms),
+            workflow_name: Some(workflow.name().clone()),
+            agent_id: Some(agent_id.to_string()),
+            failed_tasks: Some(
+                result
+                    .map(|r| {
+                        r.tasks
+                            .iter()
+                            .filter(|task| task.status == "FAILED" || task.status == "CRASHED")
+                            .map(|task| task.task_id.clone())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            ),
+            annotations: Some(result.map(|r| r.annotations.clone()).unwrap_or_default()),
+        }
+    }
+
+    /// Leaves out everything but the ids, status and duration of the run
+    pub fn redacted(self) -> Self {
+        Self {
+            workflow_name: None,
+            agent_id: None,
+            failed_tasks: None,
+            annotations: None,
+            ..self
+        }
+    }
+}
+
+/// Posts a notification to a webhook. Notifications the webhook can't be reached for or
+/// doesn't answer with a success status are sent again with backoff
+pub async fn send_notification(
+    client: &reqwest::Client,
+    url: &str,
+    notification: &RunNotification,
+) -> Result<(), GenericError> {
+    let mut backoff = NOTIFY_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let error = match client
+            .post(url)
+            .timeout(NOTIFY_TIMEOUT)
+            .json(notification)
+            .send()
+            .await
+        {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) => format!("webhook responded with {}", resp.status()),
+            Err(e) => e.to_string(),
+        };
+        if attempt == NOTIFY_ATTEMPTS {
+            return Err(GenericError::ConnectionError(format!(
+                "Failed to notify {} after {} attempts: {}",
+                url, NOTIFY_ATTEMPTS, error
+            )));
+        }
+        warn!(
+            "Failed to notify {} (attempt {} of {}): {} - retrying in {}s",
+            url,
+            attempt,
+            NOTIFY_ATTEMPTS,
+            error,
+            backoff.as_secs()
+        );
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use cdktr_core::models::AgentMeta;
+use cdktr_workflow::Workflow;
+
+/// Runs that have been sent to each agent and haven't finished yet, so that a run of a
+/// workflow with `exclusive_agent` reserves the whole agent it is sent to. Shared with
+/// the agent heartbeat monitor so that agents that die don't stay reserved
+#[derive(Clone, Default)]
+pub struct AgentReservations {
+    /// Maps agent_id to the instance ids of the runs sent to it, and whether each run is
+    /// exclusive
+    runs: Arc<Mutex<HashMap<String, HashMap<String, bool>>>>,
+}
+
+/// Dry runs don't run anything so never need an agent to themselves
+fn is_exclusive(workflow: &Workflow) -> bool {
+    workflow.exclusive_agent() && !workflow.dry_run()
+}
+
+impl AgentReservations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a run can be sent to the agent now. Nothing is sent to an agent running an
+    /// exclusive run, and exclusive runs are only sent to agents running nothing else
+    pub fn can_start(&self, agent: &AgentMeta, workflow: &Workflow) -> bool {
+        let runs = self.runs.lock().expect("agent reservations lock poisoned");
+        let agent_runs = runs.get(&agent.agent_id());
+        if agent_runs.is_some_and(|runs| runs.values().any(|exclusive| *exclusive)) {
+            return false;
+        }
+        !is_exclusive(workflow) || (agent_runs.is_none() && agent.utilisation() == 0)
+    }
+
+    /// Records that a run has been sent to an agent
+    pub fn start(&self, agent_id: &str, workflow: &Workflow) {
+        if let Some(instance_id) = workflow.instance_id() {
+            self.runs
+                .lock()
+                .expect("agent reservations lock poisoned")
+                .entry(agent_id.to_string())
+                .or_default()
+                .insert(instance_id.clone(), is_exclusive(workflow));
+        }
+    }
+
+    /// Frees up the agents of finished runs
+    pub fn release(&self, workflow_instance_ids: impl IntoIterator<Item = impl AsRef<str>>) {
+        let mut runs = self.runs.lock().expect("agent reservations lock poisoned");
+        for workflow_instance_id in workflow_instance_ids {
+            for agent_runs in runs.values_mut() {
+                agent_runs.remove(workflow_instance_id.as_ref());
+            }
+        }
+        runs.retain(|_, agent_runs| !agent_runs.is_empty());
+    }
+
+    /// Forgets the runs sent to an agent that has gone away
+    pub fn release_agent(&self, agent_id: &str) {
+        self.runs
+            .lock()
+            .expect("agent reservations lock poisoned")
+            .remove(agent_id);
+    }
+}
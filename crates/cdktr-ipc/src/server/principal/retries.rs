@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use cdktr_core::models::RunStatus;
+use cdktr_workflow::Workflow;
+
+use crate::ids::InstanceIdGenerator;
+
+/// Runs of workflows with a retry policy that haven't finished yet. Every queued run is given
+/// its instance id by the principal rather than by the agent that picks it up, so that the
+/// queue can be persisted and a failed run can be queued again with the same params
+pub struct WorkflowRetries {
+    id_gen: InstanceIdGenerator,
+    pending: HashMap<String, Workflow>,
+}
+
+impl Default for WorkflowRetries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WorkflowRetries {
+    pub fn new() -> Self {
+        Self {
+            id_gen: InstanceIdGenerator::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Assigns an instance id to a run that is about to be queued
+    pub fn assign_instance_id(&mut self, workflow: Workflow) -> Workflow {
+        workflow.with_instance_id(self.id_gen.next())
+    }
+
+    /// Assigns an instance id to a run and, if its workflow has a retry policy, keeps track
+    /// of it until it finishes
+    pub fn track(&mut self, workflow: Workflow) -> Workflow {
+        let workflow = self.assign_instance_id(workflow);
+        self.resume(&workflow);
+        workflow
+    }
+
+    /// Keeps track of a run that already has an instance id, e.g. one restored from the
+    /// persisted queue after a restart
+    pub fn resume(&mut self, workflow: &Workflow) {
+        if workflow.retries() == 0 {
+            return;
+        }
+        if let Some(instance_id) = workflow.instance_id() {
+            self.pending.insert(instance_id.clone(), workflow.clone());
+        }
+    }
+
+    /// Stops tracking a run that will never finish, e.g. because it was flushed from the queue
+    pub fn forget(&mut self, workflow_instance_id: &str) {
+        self.pending.remove(workflow_instance_id);
+    }
+
+    /// Stops tracking a finished run. Returns the next attempt to queue if the run
+    /// FAILED and its workflow has retries left
+    pub fn on_finished(
+        &mut self,
+        workflow_instance_id: &str,
+        status: &RunStatus,
+    ) -> Option<Workflow> {
+        let workflow = self.pending.remove(workflow_instance_id)?;
+        if *status != RunStatus::FAILED {
+            return None;
+        }
+        let retry = workflow.next_attempt(self.id_gen.next())?;
+        self.pending.insert(
+            retry.instance_id().cloned().unwrap_or_default(),
+            retry.clone(),
+        );
+        Some(retry)
+    }
+}
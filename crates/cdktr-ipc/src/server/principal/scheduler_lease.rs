@@ -0,0 +1,66 @@
+use std::time::{Duration, Instant};
+
+/// The lease a scheduler must hold to fire scheduled workflows, so that a scheduler
+/// running in the principal and ones running in their own processes never fire the same
+/// run twice. The lease lives in memory, so after the principal restarts it goes to the
+/// first scheduler to ask for it
+#[derive(Default)]
+pub struct SchedulerLease {
+    /// The scheduler holding the lease and when its lease expires
+    holder: Option<(String, Instant)>,
+}
+
+impl SchedulerLease {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grants the lease to the scheduler for `ttl` if it is free, has expired or is already
+    /// held by the scheduler. Otherwise returns the scheduler holding it and how long until
+    /// its lease expires
+    pub fn acquire(
+        &mut self,
+        scheduler_id: &str,
+        ttl: Duration,
+        now: Instant,
+    ) -> Result<(), (String, Duration)> {
+        if let Some((holder, expires)) = &self.holder
+            && holder != scheduler_id
+            && *expires > now
+        {
+            return Err((holder.clone(), *expires - now));
+        }
+        self.holder = Some((scheduler_id.to_string(), now + ttl));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease_held_by_one_scheduler_at_a_time() {
+        let mut lease = SchedulerLease::new();
+        let ttl = Duration::from_secs(15);
+        let start = Instant::now();
+        assert!(lease.acquire("sched-a", ttl, start).is_ok());
+
+        // the holder renews while the other waits for it to expire
+        let renewed = start + Duration::from_secs(10);
+        assert!(lease.acquire("sched-a", ttl, renewed).is_ok());
+        let (holder, remaining) = lease
+            .acquire("sched-b", ttl, start + Duration::from_secs(20))
+            .unwrap_err();
+        assert_eq!(holder, "sched-a");
+        assert_eq!(remaining, Duration::from_secs(5));
+
+        // a holder that stops renewing loses the lease
+        assert!(
+            lease
+                .acquire("sched-b", ttl, renewed + ttl + Duration::from_millis(1))
+                .is_ok()
+        );
+        assert!(lease.acquire("sched-a", ttl, renewed + ttl * 2).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use cdktr_workflow::Workflow;
+
+/// Runs of singleton workflows that have been sent to an agent and haven't finished yet.
+/// Shared with the agent heartbeat monitor so that runs on an agent that dies don't
+/// block their workflow forever
+#[derive(Clone, Default)]
+pub struct SingletonRuns {
+    /// Maps workflow_id to the instance id of its running run
+    running: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl SingletonRuns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_running(&self, workflow_id: &str) -> bool {
+        self.running
+            .lock()
+            .expect("singleton runs lock poisoned")
+            .contains_key(workflow_id)
+    }
+
+    /// Instance id of the running run of the workflow, if it has one
+    pub fn running_instance(&self, workflow_id: &str) -> Option<String> {
+        self.running
+            .lock()
+            .expect("singleton runs lock poisoned")
+            .get(workflow_id)
+            .cloned()
+    }
+
+    /// Whether a run can be sent to an agent now. Dry runs don't run anything so are
+    /// never held back
+    pub fn can_start(&self, workflow: &Workflow) -> bool {
+        workflow.singleton().is_none() || workflow.dry_run() || !self.is_running(workflow.id())
+    }
+
+    /// Records that a run has been sent to an agent
+    pub fn start(&self, workflow: &Workflow) {
+        if workflow.singleton().is_none() || workflow.dry_run() {
+            return;
+        }
+        if let Some(instance_id) = workflow.instance_id() {
+            self.running
+                .lock()
+                .expect("singleton runs lock poisoned")
+                .insert(workflow.id().clone(), instance_id.clone());
+        }
+    }
+
+    /// Lets the next run of the workflows of finished runs start
+    pub fn release(&self, workflow_instance_ids: impl IntoIterator<Item = impl AsRef<str>>) {
+        let mut running = self.running.lock().expect("singleton runs lock poisoned");
+        for workflow_instance_id in workflow_instance_ids {
+            running.retain(|_, instance_id| instance_id != workflow_instance_id.as_ref());
+        }
+    }
+}
@@ -1,27 +1,131 @@
-use std::time::{Duration, SystemTime};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use async_trait::async_trait;
+use cdktr_api::API;
 use cdktr_api::models::ClientResponseMessage;
 use cdktr_core::exceptions::GenericError;
 use cdktr_core::get_cdktr_setting;
-use cdktr_core::zmq_helpers::{get_server_tcp_uri, get_zmq_rep};
-use log::info;
+use cdktr_core::utils::{LogRateLimiter, MALFORMED_MESSAGE_WARNING_INTERVAL};
+use cdktr_core::zmq_helpers::{get_server_tcp_uri, get_zmq_router, split_router_envelope};
+use log::{Level, LevelFilter, info, log, warn};
+use tokio::sync::{Semaphore, mpsc};
 
 use zeromq::{Socket, ZmqMessage};
 use zeromq::{SocketRecv, SocketSend};
 
+/// Future returned for a request that should be held open until it resolves
+pub type HoldFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Future that handles a request away from the request loop, resolving to its response
+pub type OffloadFuture = Pin<Box<dyn Future<Output = ClientResponseMessage> + Send>>;
+
+/// Level requests are written to the access log at, from `CDKTR_ACCESS_LOG_LEVEL`.
+/// `None` when the access log is off
+fn access_log_level() -> Option<Level> {
+    let setting = get_cdktr_setting!(CDKTR_ACCESS_LOG_LEVEL);
+    match setting.parse::<LevelFilter>() {
+        Ok(filter) => filter.to_level(),
+        Err(_) => {
+            warn!("Invalid CDKTR_ACCESS_LOG_LEVEL '{setting}' - logging requests at DEBUG");
+            Some(Level::Debug)
+        }
+    }
+}
+
+/// Identifies the client of a request by the identity the ROUTER socket gave its
+/// connection, which is the first frame of the routing envelope
+fn client_id(envelope: &ZmqMessage) -> String {
+    envelope
+        .get(0)
+        .map(|frame| frame.iter().map(|b| format!("{b:02x}")).collect())
+        .unwrap_or_default()
+}
+
+/// Size of a message's frames in bytes
+pub(crate) fn message_size(msg: &ZmqMessage) -> usize {
+    msg.iter().map(|frame| frame.len()).sum()
+}
+
+/// Line written to the access log for each request the server handles
+fn access_log_entry(
+    message_type: &str,
+    client: &str,
+    response: &ClientResponseMessage,
+    latency: Duration,
+) -> String {
+    format!(
+        "ACCESS type={message_type} client={client} response={} latency_ms={:.3}",
+        response.code(),
+        latency.as_secs_f64() * 1000.0
+    )
+}
+
 /// A standard ZMQ REP server that both the Agent and Principal instances
-/// implement
+/// implement. Under the hood the server binds a ROUTER socket so that
+/// long-polling requests can be held open without blocking other clients,
+/// but it remains wire-compatible with plain REQ clients.
 #[async_trait]
 pub trait Server<RT>
 where
-    RT: TryFrom<ZmqMessage, Error = GenericError> + Send,
+    RT: API + TryFrom<ZmqMessage, Error = GenericError> + Send + 'static,
 {
     /// Method to handle the client request. It returns a tuple of ClientResponseMessage
     /// and a restart flag. This flag is used to determine whether the
     /// instance should be restarted or not
     async fn handle_client_message(&mut self, cli_msg: RT) -> (ClientResponseMessage, usize);
 
+    /// Handles a request from `client`, the identity of the connection it came in on. By
+    /// default the client is only written to the access log
+    async fn handle_client_message_from(
+        &mut self,
+        cli_msg: RT,
+        _client: &str,
+    ) -> (ClientResponseMessage, usize) {
+        self.handle_client_message(cli_msg).await
+    }
+
+    /// Wraps `handle_client_message` to write the request to the access log with its
+    /// type, client, response and how long it took to handle
+    async fn handle_logged_client_message(
+        &mut self,
+        cli_msg: RT,
+        client: &str,
+        log_level: Option<Level>,
+    ) -> (ClientResponseMessage, usize) {
+        let message_type = cli_msg.message_type();
+        let start = Instant::now();
+        let result = self.handle_client_message_from(cli_msg, client).await;
+        if let Some(level) = log_level {
+            log!(
+                level,
+                "{}",
+                access_log_entry(message_type, client, &result.0, start.elapsed())
+            );
+        }
+        result
+    }
+
+    /// Returns a future for requests that should be held open (long-polled) rather
+    /// than handled immediately. The request is passed to `handle_client_message`
+    /// once the future resolves. Held requests are awaited outside of the request loop
+    /// so other clients continue to be served in the meantime. By default no
+    /// requests are held.
+    fn hold_request(&self, _cli_msg: &RT) -> Option<HoldFuture> {
+        None
+    }
+
+    /// Returns a future that handles the request on one of the query workers rather than
+    /// in the request loop, for slow read-only requests that would otherwise keep other
+    /// clients waiting. At most `CDKTR_PRINCIPAL_QUERY_WORKERS` run at once and the rest
+    /// wait for a worker. The future can't change the state of the server, so only
+    /// requests that only read are offloaded. By default no requests are offloaded.
+    fn offload_request(&self, _cli_msg: &RT) -> Option<OffloadFuture> {
+        None
+    }
+
     /// Method to run the REP listening loop. This is a default
     /// implementation and is exactly the same for both the Agent
     /// and Principal instances so it is not needed to override this
@@ -31,22 +135,113 @@ where
             "SERVER: Starting REP Server on tcp://{}:{}",
             current_host, rep_port
         );
-        let mut rep_socket = get_zmq_rep(&get_server_tcp_uri(current_host, rep_port)).await?;
+        let mut router_socket = get_zmq_router(&get_server_tcp_uri(current_host, rep_port)).await?;
         let rep_socket_refresh_fequency_ms =
             get_cdktr_setting!(CDKTR_DEFAULT_ZMQ_REP_FREFRESH_INTERVAL_MS, usize) as u64;
         let mut last_rep_socket_refresh_time = SystemTime::now();
         info!("SERVER: Successfully connected");
 
+        // held requests are sent back to the loop with their routing envelope once ready
+        let (held_tx, mut held_rx) = mpsc::unbounded_channel::<(ZmqMessage, RT)>();
+        let mut held_count: usize = 0;
+        // offloaded requests are sent back with their envelope and response once handled
+        let (offloaded_tx, mut offloaded_rx) =
+            mpsc::unbounded_channel::<(ZmqMessage, ClientResponseMessage)>();
+        let mut offloaded_count: usize = 0;
+        let query_workers = get_cdktr_setting!(CDKTR_PRINCIPAL_QUERY_WORKERS, usize);
+        let query_permits = Arc::new(Semaphore::new(query_workers));
+        let mut malformed_warnings = LogRateLimiter::new(MALFORMED_MESSAGE_WARNING_INTERVAL);
+        let access_log_level = access_log_level();
+        let max_message_bytes = get_cdktr_setting!(CDKTR_MAX_MESSAGE_BYTES, usize);
+
         let exit_code = loop {
-            let zmq_recv = rep_socket
-                .recv()
-                .await
-                .map_err(|e| GenericError::ZMQError(e.to_string()))?;
-            let msg_res: Result<RT, GenericError> = RT::try_from(zmq_recv.clone());
+            let (envelope, msg_res) = tokio::select! {
+                zmq_recv = router_socket.recv() => {
+                    let zmq_recv = zmq_recv.map_err(GenericError::from)?;
+                    let (envelope, body) = split_router_envelope(zmq_recv);
+                    // checked before parsing so an oversized request is never copied
+                    // into strings and collections
+                    let size = message_size(&body);
+                    if size > max_message_bytes {
+                        let warning = format!(
+                            "SERVER: Rejected request of {size} bytes, over the CDKTR_MAX_MESSAGE_BYTES limit of {max_message_bytes}"
+                        );
+                        (envelope, Err((warning, "message too large".to_string())))
+                    } else {
+                        match RT::try_from(body) {
+                            Ok(cli_msg) => match self.hold_request(&cli_msg) {
+                                Some(hold) => {
+                                    held_count += 1;
+                                    let held_tx = held_tx.clone();
+                                    tokio::spawn(async move {
+                                        hold.await;
+                                        let _ = held_tx.send((envelope, cli_msg));
+                                    });
+                                    continue;
+                                }
+                                None => match self.offload_request(&cli_msg) {
+                                    Some(query) if query_workers > 0 => {
+                                        offloaded_count += 1;
+                                        let offloaded_tx = offloaded_tx.clone();
+                                        let query_permits = query_permits.clone();
+                                        let message_type = cli_msg.message_type();
+                                        let client = client_id(&envelope);
+                                        tokio::spawn(async move {
+                                            let start = Instant::now();
+                                            let _permit = query_permits.acquire_owned().await;
+                                            let response = query.await;
+                                            if let Some(level) = access_log_level {
+                                                log!(
+                                                    level,
+                                                    "{}",
+                                                    access_log_entry(
+                                                        message_type,
+                                                        &client,
+                                                        &response,
+                                                        start.elapsed()
+                                                    )
+                                                );
+                                            }
+                                            let _ = offloaded_tx.send((envelope, response));
+                                        });
+                                        continue;
+                                    }
+                                    _ => (envelope, Ok(cli_msg)),
+                                },
+                            },
+                            Err(e) => {
+                                let warning = format!("SERVER: Rejected malformed request: {e}");
+                                (envelope, Err((warning, e.to_string())))
+                            }
+                        }
+                    }
+                }
+                Some((envelope, cli_msg)) = held_rx.recv() => {
+                    held_count -= 1;
+                    (envelope, Ok(cli_msg))
+                }
+                Some((envelope, response)) = offloaded_rx.recv() => {
+                    offloaded_count -= 1;
+                    let mut reply: ZmqMessage = response.into();
+                    reply.prepend(&envelope);
+                    if let Err(e) = router_socket.send(reply).await {
+                        warn!("SERVER: Failed to send query response to client: {}", e);
+                    }
+                    continue;
+                }
+            };
             match msg_res {
                 Ok(cli_msg) => {
-                    let (response, exit_code) = self.handle_client_message(cli_msg).await;
-                    let _ = rep_socket.send(response.into()).await;
+                    let (response, exit_code) = self
+                        .handle_logged_client_message(
+                            cli_msg,
+                            &client_id(&envelope),
+                            access_log_level,
+                        )
+                        .await;
+                    let mut reply: ZmqMessage = response.into();
+                    reply.prepend(&envelope);
+                    let _ = router_socket.send(reply).await;
                     if exit_code > 0 {
                         // received a non-zero exit code from the message handling function
                         // which means the server should perform some other kind of action
@@ -54,28 +249,63 @@ where
                         break exit_code;
                     };
                 }
-                Err(e) => {
-                    let error_msg = e.to_string();
+                Err((warning, error_msg)) => {
+                    // malformed and oversized requests are answered with an error rather
+                    // than ending the loop
+                    malformed_warnings.warn(&warning);
                     let response = ClientResponseMessage::ClientError(error_msg);
-                    rep_socket
-                        .send(response.into())
-                        .await
-                        .map_err(|e| GenericError::ZMQError(e.to_string()))?;
+                    let mut reply: ZmqMessage = response.into();
+                    reply.prepend(&envelope);
+                    if let Err(e) = router_socket.send(reply).await {
+                        warn!("SERVER: Failed to send error response to client: {}", e);
+                    }
                 }
             };
 
             // fix to refresh the rep socket to prevent FD leak from new connections
-            // done in this loop to avoid any potential dropped messages
+            // done in this loop to avoid any potential dropped messages. Skipped while
+            // requests are held open or offloaded so their clients aren't disconnected
             // TODO: not an ideal solution. Need to fix reqs to re-use sockets as much as possible to avoid doing this so frequently
-            if SystemTime::now()
-                .duration_since(last_rep_socket_refresh_time)
-                .expect("failed to get duration for rep socket refresh")
-                > Duration::from_millis(rep_socket_refresh_fequency_ms)
+            if held_count == 0
+                && offloaded_count == 0
+                && SystemTime::now()
+                    .duration_since(last_rep_socket_refresh_time)
+                    .expect("failed to get duration for rep socket refresh")
+                    > Duration::from_millis(rep_socket_refresh_fequency_ms)
             {
-                rep_socket.backend().shutdown();
+                router_socket.backend().shutdown();
                 last_rep_socket_refresh_time = SystemTime::now();
             }
         };
         Ok(exit_code)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdktr_api::PrincipalAPI;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_access_log_entry() {
+        let msg = PrincipalAPI::RunTask("etl".to_string(), HashMap::new(), false, None);
+        let entry = access_log_entry(
+            msg.message_type(),
+            "0080a1b2c3",
+            &ClientResponseMessage::Success,
+            Duration::from_micros(1_500),
+        );
+        assert_eq!(
+            entry,
+            "ACCESS type=RunTask client=0080a1b2c3 response=OK latency_ms=1.500"
+        );
+    }
+
+    #[test]
+    fn test_client_id_from_envelope() {
+        let mut envelope = ZmqMessage::from(Vec::<u8>::new());
+        envelope.push_front(vec![0u8, 0x80, 0xa1].into());
+        assert_eq!(client_id(&envelope), "0080a1");
+    }
+}
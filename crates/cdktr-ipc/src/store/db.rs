@@ -0,0 +1,847 @@
+use async_trait::async_trait;
+use cdktr_api::models::{
+    ArtifactInfo, AuditEntry, LogPage, TaskProgress, TaskResult, TaskStatusUpdate,
+    WorkflowProgress, WorkflowResult, WorkflowStatusUpdate,
+};
+use cdktr_core::{
+    exceptions::GenericError,
+    models::{TaskInstanceId, WorkflowInstanceId},
+};
+use cdktr_db::DBClient;
+use log::debug;
+use std::collections::HashMap;
+
+use super::StatusStore;
+use crate::log_manager::model::LogMessage;
+
+/// Columns of the logstore selected as the fields of a `LogMessage`
+const LOG_MESSAGE_COLUMNS: &str = "workflow_id, workflow_name, workflow_instance_id, task_name, task_instance_id, CAST(timestamp_ms AS UBIGINT) AS timestamp_ms, level, payload";
+
+fn db_err(e: duckdb::Error) -> GenericError {
+    GenericError::DBError(e.to_string())
+}
+
+/// Time from the first RUNNING status of a workflow run to its latest terminal status
+fn workflow_duration_ms(
+    conn: &duckdb::Connection,
+    workflow_instance_id: &WorkflowInstanceId,
+) -> Result<Option<i64>, GenericError> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT CAST(
+                max(timestamp_ms) FILTER (WHERE status IN ('COMPLETED', 'FAILED', 'CRASHED'))
+                - min(timestamp_ms) FILTER (WHERE status = 'RUNNING')
+                AS BIGINT
+            ) as duration_ms
+            FROM workflow_run_status
+            WHERE workflow_instance_id = ?",
+        )
+        .map_err(db_err)?;
+    let duration_ms = stmt
+        .query_map(duckdb::params![workflow_instance_id.as_str()], |row| {
+            row.get::<_, Option<i64>>(0)
+        })
+        .map_err(db_err)?
+        .next()
+        .transpose()
+        .map_err(db_err)?
+        .flatten();
+    Ok(duration_ms)
+}
+
+/// DuckDB is the default backend of the principal
+#[async_trait]
+impl StatusStore for DBClient {
+    async fn record_task_status(
+        &self,
+        update: TaskStatusUpdate,
+        exit_code: Option<i32>,
+        truncated: bool,
+    ) -> Result<(), GenericError> {
+        if truncated {
+            self.lock_inner_client()
+                .await
+                .execute(
+                    "INSERT INTO task_output_truncations VALUES (?, ?)",
+                    duckdb::params![
+                        update.task_instance_id().as_str(),
+                        update.workflow_instance_id().as_str(),
+                    ],
+                )
+                .map_err(db_err)?;
+        }
+        if let Some(code) = exit_code {
+            self.lock_inner_client()
+                .await
+                .execute(
+                    "INSERT INTO task_exit_codes VALUES (?, ?, ?)",
+                    duckdb::params![
+                        update.task_instance_id().as_str(),
+                        update.workflow_instance_id().as_str(),
+                        code
+                    ],
+                )
+                .map_err(db_err)?;
+        }
+        self.batch_load("task_run_status", vec![update])
+            .await
+            .map_err(|_| GenericError::DBError("Failed to load task status update".to_string()))
+    }
+
+    async fn record_workflow_statuses(
+        &self,
+        updates: Vec<WorkflowStatusUpdate>,
+    ) -> Result<(), GenericError> {
+        self.batch_load("workflow_run_status", updates)
+            .await
+            .map_err(|failed| {
+                GenericError::DBError(format!(
+                    "Failed to load {} workflow status update(s)",
+                    failed.len()
+                ))
+            })
+    }
+
+    async fn persist_logs(&self, logs: Vec<LogMessage>) -> Result<(), Vec<LogMessage>> {
+        self.batch_load("logstore", logs).await
+    }
+
+    async fn read_logs(
+        &self,
+        start_timestamp_ms: u64,
+        end_timestamp_ms: u64,
+        workflow_id: Option<String>,
+        workflow_instance_id: Option<WorkflowInstanceId>,
+        page: Option<LogPage>,
+    ) -> Result<Vec<LogMessage>, GenericError> {
+        let mut stmt_str = format!(
+            "SELECT {LOG_MESSAGE_COLUMNS} FROM logstore WHERE timestamp_ms >= {start_timestamp_ms} AND timestamp_ms < {end_timestamp_ms} "
+        );
+        let mut params = Vec::new();
+        if let Some(wf_id) = workflow_id {
+            stmt_str.push_str("AND workflow_id = ? ");
+            params.push(wf_id);
+        };
+        if let Some(wf_ins_id) = workflow_instance_id {
+            stmt_str.push_str("AND workflow_instance_id = ? ");
+            params.push(wf_ins_id.into_inner());
+        };
+        if let Some(page) = page {
+            // rowid breaks ties between logs with the same timestamp so pages don't overlap
+            stmt_str.push_str(&format!(
+                "ORDER BY timestamp_ms, rowid LIMIT {} OFFSET {} ",
+                page.limit, page.offset
+            ));
+        }
+        debug!("stmt_str: {}", &stmt_str);
+        self.query_as(&stmt_str, duckdb::params_from_iter(params))
+            .await
+    }
+
+    async fn read_log_tail(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        n: usize,
+    ) -> Result<Vec<LogMessage>, GenericError> {
+        // queried newest first to apply the limit
+        let mut msgs: Vec<LogMessage> = self
+            .query_as(
+                &format!(
+                    "SELECT {LOG_MESSAGE_COLUMNS} FROM logstore
+                     WHERE workflow_instance_id = ?
+                     ORDER BY timestamp_ms DESC, rowid DESC
+                     LIMIT {n}"
+                ),
+                duckdb::params_from_iter([workflow_instance_id]),
+            )
+            .await?;
+        msgs.reverse();
+        Ok(msgs)
+    }
+
+    async fn get_recent_workflow_statuses(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<WorkflowStatusUpdate>, GenericError> {
+        // Using a window function to get the latest record per workflow
+        let query = "
+            WITH ranked_statuses AS (
+                SELECT
+                    workflow_id,
+                    workflow_instance_id,
+                    status,
+                    timestamp_ms,
+                    ROW_NUMBER() OVER (PARTITION BY workflow_id ORDER BY timestamp_ms DESC) as rn
+                FROM workflow_run_status
+            )
+            SELECT
+                workflow_id,
+                workflow_instance_id,
+                CAST(status AS VARCHAR) as status,
+                CAST(timestamp_ms AS UBIGINT) as timestamp_ms
+            FROM ranked_statuses
+            WHERE rn = 1
+            ORDER BY timestamp_ms DESC
+            LIMIT ?
+        ";
+        self.query_as(query, duckdb::params_from_iter([limit as i64]))
+            .await
+    }
+
+    async fn get_workflow_id(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<Option<String>, GenericError> {
+        let locked_client = self.lock_inner_client().await;
+        let mut stmt = locked_client
+            .prepare(
+                "SELECT workflow_id FROM workflow_run_status
+                 WHERE workflow_instance_id = ?
+                 LIMIT 1",
+            )
+            .map_err(db_err)?;
+        let workflow_id = stmt
+            .query_map(duckdb::params![workflow_instance_id.as_str()], |row| {
+                row.get::<_, String>(0)
+            })
+            .map_err(db_err)?
+            .next()
+            .transpose()
+            .map_err(db_err)?;
+        Ok(workflow_id)
+    }
+
+    async fn get_workflow_duration_ms(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<Option<i64>, GenericError> {
+        workflow_duration_ms(&*self.lock_inner_client().await, workflow_instance_id)
+    }
+
+    async fn record_sla_result(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        sla_ms: u64,
+        breached: bool,
+    ) -> Result<(), GenericError> {
+        self.lock_inner_client()
+            .await
+            .execute(
+                "INSERT INTO workflow_sla_results VALUES (?, ?, ?)",
+                duckdb::params![workflow_instance_id.as_str(), sla_ms as i64, breached],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    async fn record_run_attempt(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        retry_of: &WorkflowInstanceId,
+        attempt: u32,
+    ) -> Result<(), GenericError> {
+        self.lock_inner_client()
+            .await
+            .execute(
+                "INSERT INTO workflow_run_attempts VALUES (?, ?, ?)",
+                duckdb::params![workflow_instance_id.as_str(), retry_of.as_str(), attempt],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    async fn record_task_progress(
+        &self,
+        task_instance_id: &TaskInstanceId,
+        percent: u8,
+        message: &str,
+    ) -> Result<(), GenericError> {
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        self.lock_inner_client()
+            .await
+            .execute(
+                "INSERT INTO task_progress VALUES (?, ?, ?, ?)",
+                duckdb::params![task_instance_id.as_str(), percent, message, timestamp_ms],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    async fn record_task_artifacts(
+        &self,
+        task_instance_id: &TaskInstanceId,
+        artifacts: &[ArtifactInfo],
+    ) -> Result<(), GenericError> {
+        let locked_client = self.lock_inner_client().await;
+        let mut stmt = locked_client
+            .prepare("INSERT INTO task_artifacts VALUES (?, ?, ?)")
+            .map_err(db_err)?;
+        for artifact in artifacts {
+            stmt.execute(duckdb::params![
+                task_instance_id.as_str(),
+                artifact.path,
+                artifact.size_bytes
+            ])
+            .map_err(db_err)?;
+        }
+        Ok(())
+    }
+
+    async fn record_workflow_progress(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        tasks_completed: usize,
+        tasks_total: usize,
+    ) -> Result<(), GenericError> {
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        self.lock_inner_client()
+            .await
+            .execute(
+                "INSERT INTO workflow_progress VALUES (?, ?, ?, ?)",
+                duckdb::params![
+                    workflow_instance_id.as_str(),
+                    tasks_completed as u64,
+                    tasks_total as u64,
+                    timestamp_ms
+                ],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    async fn record_workflow_outputs(
+        &self,
+        workflow_id: &str,
+        workflow_instance_id: &WorkflowInstanceId,
+        outputs: &HashMap<String, String>,
+    ) -> Result<(), GenericError> {
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let client = self.lock_inner_client().await;
+        for (name, value) in outputs {
+            client
+                .execute(
+                    "INSERT INTO workflow_outputs VALUES (?, ?, ?, ?, ?)",
+                    duckdb::params![
+                        workflow_id,
+                        workflow_instance_id.as_str(),
+                        name,
+                        value,
+                        timestamp_ms
+                    ],
+                )
+                .map_err(db_err)?;
+        }
+        Ok(())
+    }
+
+    async fn get_last_workflow_outputs(
+        &self,
+        workflow_id: &str,
+    ) -> Result<HashMap<String, String>, GenericError> {
+        let locked_client = self.lock_inner_client().await;
+        let mut stmt = locked_client
+            .prepare(
+                "SELECT name, arg_max(value, timestamp_ms) FROM workflow_outputs
+                 WHERE workflow_id = ?
+                 GROUP BY name",
+            )
+            .map_err(db_err)?;
+        let outputs = stmt
+            .query_map(duckdb::params![workflow_id], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(db_err)?
+            .collect::<Result<HashMap<String, String>, _>>()
+            .map_err(db_err)?;
+        Ok(outputs)
+    }
+
+    async fn record_queued_workflow(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        workflow: &str,
+        front: bool,
+    ) -> Result<(), GenericError> {
+        let queued_at_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        // runs at the front are ordered just before the earliest run on the queue
+        let query = if front {
+            "INSERT INTO queued_workflows
+             SELECT ?::TEXT, ?::TEXT, coalesce(min(queued_at_ms), ?::BIGINT) - 1
+             FROM queued_workflows"
+        } else {
+            "INSERT INTO queued_workflows VALUES (?, ?, ?)"
+        };
+        self.lock_inner_client()
+            .await
+            .execute(
+                query,
+                duckdb::params![workflow_instance_id.as_str(), workflow, queued_at_ms],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    async fn remove_queued_workflow(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<(), GenericError> {
+        self.lock_inner_client()
+            .await
+            .execute(
+                "DELETE FROM queued_workflows WHERE workflow_instance_id = ?",
+                duckdb::params![workflow_instance_id.as_str()],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    async fn get_queued_workflows(&self) -> Result<Vec<String>, GenericError> {
+        let locked_client = self.lock_inner_client().await;
+        let mut stmt = locked_client
+            .prepare("SELECT workflow FROM queued_workflows ORDER BY queued_at_ms, rowid")
+            .map_err(db_err)?;
+        let workflows = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(db_err)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(db_err)?;
+        Ok(workflows)
+    }
+
+    async fn record_run_snapshot(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        workflow: &str,
+    ) -> Result<(), GenericError> {
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        self.lock_inner_client()
+            .await
+            .execute(
+                "INSERT INTO workflow_run_snapshots VALUES (?, ?, ?)",
+                duckdb::params![workflow_instance_id.as_str(), workflow, timestamp_ms],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    async fn get_run_snapshot(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<Option<String>, GenericError> {
+        let locked_client = self.lock_inner_client().await;
+        let mut stmt = locked_client
+            .prepare(
+                "SELECT workflow FROM workflow_run_snapshots
+                WHERE workflow_instance_id = ?
+                ORDER BY timestamp_ms DESC
+                LIMIT 1",
+            )
+            .map_err(db_err)?;
+        let workflow = stmt
+            .query_map(duckdb::params![workflow_instance_id.as_str()], |row| {
+                row.get::<_, String>(0)
+            })
+            .map_err(db_err)?
+            .next()
+            .transpose()
+            .map_err(db_err)?;
+        Ok(workflow)
+    }
+
+    async fn annotate_run(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        key: &str,
+        value: &str,
+    ) -> Result<(), GenericError> {
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        self.lock_inner_client()
+            .await
+            .execute(
+                "INSERT INTO run_annotations VALUES (?, ?, ?, ?)",
+                duckdb::params![workflow_instance_id.as_str(), key, value, timestamp_ms],
+            )
+            .map_err(db_err)?;
+        Ok(())
+    }
+
+    async fn record_audit_entry(&self, entry: AuditEntry) -> Result<(), GenericError> {
+        self.batch_load("audit_log", vec![entry])
+            .await
+            .map_err(|_| GenericError::DBError("Failed to load audit entry".to_string()))
+    }
+
+    async fn read_audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>, GenericError> {
+        // rowid breaks ties between actions taken in the same millisecond
+        self.query_as(
+            "SELECT timestamp_ms, actor, action, target, result, client FROM audit_log
+             ORDER BY timestamp_ms DESC, rowid DESC
+             LIMIT ?",
+            duckdb::params_from_iter([limit as i64]),
+        )
+        .await
+    }
+
+    async fn get_workflow_result(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        output_tail_lines: usize,
+    ) -> Result<Option<WorkflowResult>, GenericError> {
+        let workflow_query = "
+            SELECT
+                workflow_id,
+                arg_max(CAST(status AS VARCHAR), timestamp_ms) as status
+            FROM workflow_run_status
+            WHERE workflow_instance_id = ?
+            GROUP BY workflow_id
+        ";
+        let tasks_query = "
+            WITH task_statuses AS (
+                SELECT
+                    task_id,
+                    task_instance_id,
+                    arg_max(CAST(status AS VARCHAR), timestamp_ms) as status,
+                    min(timestamp_ms) FILTER (WHERE status = 'RUNNING') as start_ts,
+                    max(timestamp_ms) FILTER (WHERE status IN ('COMPLETED', 'FAILED', 'CRASHED')) as end_ts
+                FROM task_run_status
+                WHERE workflow_instance_id = ?
+                GROUP BY task_id, task_instance_id
+            ),
+            exit_codes AS (
+                SELECT task_instance_id, max(exit_code) as exit_code
+                FROM task_exit_codes
+                WHERE workflow_instance_id = ?
+                GROUP BY task_instance_id
+            ),
+            truncations AS (
+                SELECT DISTINCT task_instance_id
+                FROM task_output_truncations
+                WHERE workflow_instance_id = ?
+            ),
+            progress AS (
+                SELECT
+                    task_instance_id,
+                    arg_max(percent, timestamp_ms) as percent,
+                    arg_max(message, timestamp_ms) as message
+                FROM task_progress
+                WHERE task_instance_id IN (SELECT task_instance_id FROM task_statuses)
+                GROUP BY task_instance_id
+            )
+            SELECT
+                t.task_id,
+                t.task_instance_id,
+                t.status,
+                e.exit_code,
+                CAST(t.end_ts - t.start_ts AS BIGINT) as duration_ms,
+                p.percent,
+                p.message,
+                tr.task_instance_id IS NOT NULL as truncated
+            FROM task_statuses t
+            LEFT JOIN exit_codes e ON t.task_instance_id = e.task_instance_id
+            LEFT JOIN truncations tr ON t.task_instance_id = tr.task_instance_id
+            LEFT JOIN progress p ON t.task_instance_id = p.task_instance_id
+            ORDER BY t.start_ts NULLS LAST, t.task_id
+        ";
+        let output_query = "
+            SELECT payload
+            FROM logstore
+            WHERE workflow_instance_id = ? AND task_instance_id = ?
+            ORDER BY timestamp_ms DESC
+            LIMIT ?
+        ";
+
+        let locked_client = self.lock_inner_client().await;
+        let mut stmt = locked_client.prepare(workflow_query).map_err(db_err)?;
+        let workflow_row = stmt
+            .query_map(duckdb::params![workflow_instance_id.as_str()], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(db_err)?
+            .next();
+        let (workflow_id, status) = match workflow_row {
+            Some(row) => row.map_err(db_err)?,
+            None => return Ok(None),
+        };
+
+        let duration_ms = workflow_duration_ms(&locked_client, workflow_instance_id)?;
+
+        let mut stmt = locked_client
+            .prepare(
+                "SELECT bool_or(breached) FROM workflow_sla_results
+                 WHERE workflow_instance_id = ?",
+            )
+            .map_err(db_err)?;
+        let sla_breached = stmt
+            .query_map(duckdb::params![workflow_instance_id.as_str()], |row| {
+                row.get::<_, Option<bool>>(0)
+            })
+            .map_err(db_err)?
+            .next()
+            .transpose()
+            .map_err(db_err)?
+            .flatten();
+
+        let mut stmt = locked_client
+            .prepare(
+                "SELECT retry_of, attempt FROM workflow_run_attempts
+                 WHERE workflow_instance_id = ?",
+            )
+            .map_err(db_err)?;
+        let run_attempt = stmt
+            .query_map(duckdb::params![workflow_instance_id.as_str()], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+            })
+            .map_err(db_err)?
+            .next()
+            .transpose()
+            .map_err(db_err)?;
+
+        let mut stmt = locked_client
+            .prepare(
+                "SELECT key, arg_max(value, timestamp_ms) FROM run_annotations
+                 WHERE workflow_instance_id = ?
+                 GROUP BY key",
+            )
+            .map_err(db_err)?;
+        let annotations = stmt
+            .query_map(duckdb::params![workflow_instance_id.as_str()], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(db_err)?
+            .collect::<Result<HashMap<String, String>, _>>()
+            .map_err(db_err)?;
+
+        let mut stmt = locked_client
+            .prepare(
+                "SELECT arg_max(tasks_completed, timestamp_ms), arg_max(tasks_total, timestamp_ms)
+                 FROM workflow_progress
+                 WHERE workflow_instance_id = ?",
+            )
+            .map_err(db_err)?;
+        let progress = stmt
+            .query_map(duckdb::params![workflow_instance_id.as_str()], |row| {
+                Ok(
+                    match (row.get::<_, Option<u64>>(0)?, row.get::<_, Option<u64>>(1)?) {
+                        (Some(tasks_completed), Some(tasks_total)) => Some(WorkflowProgress {
+                            tasks_completed: tasks_completed as usize,
+                            tasks_total: tasks_total as usize,
+                        }),
+                        _ => None,
+                    },
+                )
+            })
+            .map_err(db_err)?
+            .next()
+            .transpose()
+            .map_err(db_err)?
+            .flatten();
+
+        let mut stmt = locked_client.prepare(tasks_query).map_err(db_err)?;
+        let mut tasks = stmt
+            .query_map(
+                duckdb::params![
+                    workflow_instance_id.as_str(),
+                    workflow_instance_id.as_str(),
+                    workflow_instance_id.as_str()
+                ],
+                |row| {
+                    Ok(TaskResult {
+                        task_id: row.get(0)?,
+                        task_instance_id: row.get(1)?,
+                        status: row.get(2)?,
+                        exit_code: row.get(3)?,
+                        duration_ms: row.get(4)?,
+                        output_tail: Vec::new(),
+                        progress: match (row.get::<_, Option<u8>>(5)?, row.get(6)?) {
+                            (Some(percent), Some(message)) => {
+                                Some(TaskProgress { percent, message })
+                            }
+                            _ => None,
+                        },
+                        artifacts: Vec::new(),
+                        truncated: row.get(7)?,
+                    })
+                },
+            )
+            .map_err(db_err)?
+            .map(|r| r.map_err(db_err))
+            .collect::<Result<Vec<TaskResult>, GenericError>>()?;
+
+        let mut stmt = locked_client.prepare(output_query).map_err(db_err)?;
+        for task in tasks.iter_mut() {
+            let mut output_tail = stmt
+                .query_map(
+                    duckdb::params![
+                        workflow_instance_id.as_str(),
+                        task.task_instance_id,
+                        output_tail_lines as i64
+                    ],
+                    |row| row.get::<_, String>(0),
+                )
+                .map_err(db_err)?
+                .map(|r| r.map_err(db_err))
+                .collect::<Result<Vec<String>, GenericError>>()?;
+            // queried newest first to apply the limit
+            output_tail.reverse();
+            task.output_tail = output_tail;
+        }
+
+        let mut stmt = locked_client
+            .prepare(
+                "SELECT path, size_bytes FROM task_artifacts
+                 WHERE task_instance_id = ?
+                 ORDER BY path",
+            )
+            .map_err(db_err)?;
+        for task in tasks.iter_mut() {
+            task.artifacts = stmt
+                .query_map(duckdb::params![task.task_instance_id], |row| {
+                    Ok(ArtifactInfo {
+                        path: row.get(0)?,
+                        size_bytes: row.get(1)?,
+                    })
+                })
+                .map_err(db_err)?
+                .map(|r| r.map_err(db_err))
+                .collect::<Result<Vec<ArtifactInfo>, GenericError>>()?;
+        }
+
+        Ok(Some(WorkflowResult {
+            workflow_id,
+            workflow_instance_id: workflow_instance_id.to_string(),
+            status,
+            duration_ms,
+            sla_breached,
+            retry_of: run_attempt.as_ref().map(|(retry_of, _)| retry_of.clone()),
+            attempt: run_attempt.map(|(_, attempt)| attempt),
+            annotations,
+            progress,
+            tasks,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdktr_core::models::RunStatus;
+
+    #[tokio::test]
+    async fn test_get_workflow_result() {
+        let db_client = DBClient::new(None).unwrap();
+        db_client
+            .record_workflow_statuses(vec![
+                WorkflowStatusUpdate::new(
+                    "wf".to_string(),
+                    "wf-ins".into(),
+                    RunStatus::RUNNING.to_string(),
+                    1_000,
+                ),
+                WorkflowStatusUpdate::new(
+                    "wf".to_string(),
+                    "wf-ins".into(),
+                    RunStatus::COMPLETED.to_string(),
+                    3_000,
+                ),
+            ])
+            .await
+            .unwrap();
+        for (status, ts, exit_code) in [
+            (RunStatus::RUNNING, 1_000, None),
+            (RunStatus::COMPLETED, 2_500, Some(0)),
+        ] {
+            db_client
+                .record_task_status(
+                    TaskStatusUpdate::new(
+                        "task1".to_string(),
+                        "task1-ins".into(),
+                        "wf-ins".into(),
+                        status.to_string(),
+                        ts,
+                    ),
+                    exit_code,
+                    false,
+                )
+                .await
+                .unwrap();
+        }
+        let logs: Vec<LogMessage> = (0..5)
+            .map(|i| {
+                LogMessage::new(
+                    "wf".to_string(),
+                    "Workflow".to_string(),
+                    "wf-ins".to_string(),
+                    "Task 1".to_string(),
+                    "task1-ins".to_string(),
+                    1_000 + i as u64,
+                    "INFO".to_string(),
+                    format!("STDOUT line {i}"),
+                )
+            })
+            .collect();
+        db_client.persist_logs(logs).await.unwrap();
+
+        let result = db_client
+            .get_workflow_result(&"wf-ins".into(), 3)
+            .await
+            .unwrap()
+            .expect("workflow run should be found");
+        assert_eq!(result.workflow_id, "wf");
+        assert_eq!(result.status, RunStatus::COMPLETED.to_string());
+        assert_eq!(result.duration_ms, Some(2_000));
+        assert_eq!(result.sla_breached, None);
+        assert_eq!(
+            result.tasks,
+            vec![TaskResult {
+                task_id: "task1".to_string(),
+                task_instance_id: "task1-ins".to_string(),
+                status: RunStatus::COMPLETED.to_string(),
+                exit_code: Some(0),
+                duration_ms: Some(1_500),
+                output_tail: vec![
+                    "STDOUT line 2".to_string(),
+                    "STDOUT line 3".to_string(),
+                    "STDOUT line 4".to_string(),
+                ],
+                progress: None,
+                artifacts: Vec::new(),
+                truncated: false,
+            }]
+        );
+        db_client
+            .record_sla_result(&"wf-ins".into(), 1_000, true)
+            .await
+            .unwrap();
+        let result = db_client
+            .get_workflow_result(&"wf-ins".into(), 3)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.sla_breached, Some(true));
+        assert!(
+            db_client
+                .get_workflow_result(&"missing-ins".into(), 3)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,550 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use cdktr_api::models::{
+    ArtifactInfo, AuditEntry, LogPage, TaskProgress, TaskResult, TaskStatusUpdate,
+    WorkflowProgress, WorkflowResult, WorkflowStatusUpdate,
+};
+use cdktr_core::{
+    exceptions::GenericError,
+    models::{RunStatus, TaskInstanceId, WorkflowInstanceId},
+};
+use tokio::sync::Mutex;
+
+use super::StatusStore;
+use crate::log_manager::model::LogMessage;
+
+#[derive(Debug, Default)]
+struct InMemoryState {
+    task_statuses: Vec<TaskStatusUpdate>,
+    workflow_statuses: Vec<WorkflowStatusUpdate>,
+    logs: Vec<LogMessage>,
+    // task_instance_id -> exit code
+    exit_codes: HashMap<String, i32>,
+    // task_instance_id of tasks whose output was truncated
+    truncated_tasks: HashSet<String>,
+    // workflow_instance_id -> whether the run breached its SLA
+    sla_results: HashMap<String, bool>,
+    // workflow_instance_id -> (retry_of, attempt) of runs that are retries
+    run_attempts: HashMap<String, (String, u32)>,
+    // task_instance_id -> latest progress reported by the task
+    task_progress: HashMap<String, TaskProgress>,
+    // task_instance_id -> files matching the artifact globs of the task
+    task_artifacts: HashMap<String, Vec<ArtifactInfo>>,
+    // workflow_instance_id -> latest task counts reported for the run
+    workflow_progress: HashMap<String, WorkflowProgress>,
+    // workflow_id -> latest value of each named output
+    workflow_outputs: HashMap<String, HashMap<String, String>>,
+    // workflow_instance_id -> annotation key -> value
+    run_annotations: HashMap<String, HashMap<String, String>>,
+    // (workflow_instance_id, workflow json) of queued runs in queue order
+    queued_workflows: Vec<(String, String)>,
+    // workflow_instance_id -> workflow json the run was queued with
+    run_snapshots: HashMap<String, String>,
+    // administrative actions in the order they were taken
+    audit_log: Vec<AuditEntry>,
+}
+
+/// A `StatusStore` that keeps everything in memory. Nothing survives a restart
+/// so this is mainly useful as a test double for the principal
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryStatusStore {
+    inner: Arc<Mutex<InMemoryState>>,
+}
+
+impl InMemoryStatusStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps every call to the store waiting for `duration`, standing in for a slow
+    /// database
+    #[cfg(test)]
+    pub(crate) async fn stall(&self, duration: std::time::Duration) {
+        let state = self.inner.clone().lock_owned().await;
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            drop(state);
+        });
+    }
+}
+
+fn is_terminal(status: &str) -> bool {
+    status == RunStatus::COMPLETED.to_string()
+        || status == RunStatus::FAILED.to_string()
+        || status == RunStatus::CRASHED.to_string()
+}
+
+/// Time from the first RUNNING status of a workflow run to its latest terminal status
+fn workflow_duration_ms(
+    state: &InMemoryState,
+    workflow_instance_id: &WorkflowInstanceId,
+) -> Option<i64> {
+    let updates = || {
+        state
+            .workflow_statuses
+            .iter()
+            .filter(|s| s.workflow_instance_id() == workflow_instance_id)
+    };
+    let start_ts = updates()
+        .filter(|s| s.status() == RunStatus::RUNNING.to_string())
+        .map(|s| s.timestamp_ms())
+        .min()?;
+    let end_ts = updates()
+        .filter(|s| is_terminal(s.status()))
+        .map(|s| s.timestamp_ms())
+        .max()?;
+    Some(end_ts as i64 - start_ts as i64)
+}
+
+#[async_trait]
+impl StatusStore for InMemoryStatusStore {
+    async fn record_task_status(
+        &self,
+        update: TaskStatusUpdate,
+        exit_code: Option<i32>,
+        truncated: bool,
+    ) -> Result<(), GenericError> {
+        let mut state = self.inner.lock().await;
+        if truncated {
+            state
+                .truncated_tasks
+                .insert(update.task_instance_id().to_string());
+        }
+        if let Some(code) = exit_code {
+            state
+                .exit_codes
+                .insert(update.task_instance_id().to_string(), code);
+        }
+        state.task_statuses.push(update);
+        Ok(())
+    }
+
+    async fn record_workflow_statuses(
+        &self,
+        updates: Vec<WorkflowStatusUpdate>,
+    ) -> Result<(), GenericError> {
+        self.inner.lock().await.workflow_statuses.extend(updates);
+        Ok(())
+    }
+
+    async fn persist_logs(&self, logs: Vec<LogMessage>) -> Result<(), Vec<LogMessage>> {
+        self.inner.lock().await.logs.extend(logs);
+        Ok(())
+    }
+
+    async fn read_logs(
+        &self,
+        start_timestamp_ms: u64,
+        end_timestamp_ms: u64,
+        workflow_id: Option<String>,
+        workflow_instance_id: Option<WorkflowInstanceId>,
+        page: Option<LogPage>,
+    ) -> Result<Vec<LogMessage>, GenericError> {
+        let state = self.inner.lock().await;
+        let mut logs: Vec<LogMessage> = state
+            .logs
+            .iter()
+            .filter(|l| l.timestamp_ms >= start_timestamp_ms && l.timestamp_ms < end_timestamp_ms)
+            .filter(|l| workflow_id.as_ref().is_none_or(|id| &l.workflow_id == id))
+            .filter(|l| {
+                workflow_instance_id
+                    .as_ref()
+                    .is_none_or(|id| l.workflow_instance_id == id.as_str())
+            })
+            .cloned()
+            .collect();
+        if let Some(page) = page {
+            // stable so that logs with the same timestamp stay in the order they arrived
+            logs.sort_by_key(|l| l.timestamp_ms);
+            logs = logs
+                .into_iter()
+                .skip(page.offset)
+                .take(page.limit)
+                .collect();
+        }
+        Ok(logs)
+    }
+
+    async fn read_log_tail(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        n: usize,
+    ) -> Result<Vec<LogMessage>, GenericError> {
+        let state = self.inner.lock().await;
+        let mut logs: Vec<LogMessage> = state
+            .logs
+            .iter()
+            .filter(|l| l.workflow_instance_id == workflow_instance_id.as_str())
+            .cloned()
+            .collect();
+        // stable so that logs with the same timestamp stay in the order they arrived
+        logs.sort_by_key(|l| l.timestamp_ms);
+        Ok(logs.split_off(logs.len().saturating_sub(n)))
+    }
+
+    async fn get_recent_workflow_statuses(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<WorkflowStatusUpdate>, GenericError> {
+        let state = self.inner.lock().await;
+        let mut latest: HashMap<&str, &WorkflowStatusUpdate> = HashMap::new();
+        for update in state.workflow_statuses.iter() {
+            match latest.get(update.workflow_id()) {
+                Some(current) if current.timestamp_ms() > update.timestamp_ms() => (),
+                _ => {
+                    latest.insert(update.workflow_id(), update);
+                }
+            }
+        }
+        let mut statuses: Vec<WorkflowStatusUpdate> = latest.into_values().cloned().collect();
+        statuses.sort_by_key(|s| Reverse(s.timestamp_ms()));
+        statuses.truncate(limit);
+        Ok(statuses)
+    }
+
+    async fn get_workflow_id(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<Option<String>, GenericError> {
+        let state = self.inner.lock().await;
+        Ok(state
+            .workflow_statuses
+            .iter()
+            .find(|s| s.workflow_instance_id() == workflow_instance_id)
+            .map(|s| s.workflow_id().to_string()))
+    }
+
+    async fn get_workflow_duration_ms(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<Option<i64>, GenericError> {
+        let state = self.inner.lock().await;
+        Ok(workflow_duration_ms(&state, workflow_instance_id))
+    }
+
+    async fn record_sla_result(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        _sla_ms: u64,
+        breached: bool,
+    ) -> Result<(), GenericError> {
+        self.inner
+            .lock()
+            .await
+            .sla_results
+            .insert(workflow_instance_id.to_string(), breached);
+        Ok(())
+    }
+
+    async fn record_run_attempt(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        retry_of: &WorkflowInstanceId,
+        attempt: u32,
+    ) -> Result<(), GenericError> {
+        self.inner.lock().await.run_attempts.insert(
+            workflow_instance_id.to_string(),
+            (retry_of.to_string(), attempt),
+        );
+        Ok(())
+    }
+
+    async fn record_task_progress(
+        &self,
+        task_instance_id: &TaskInstanceId,
+        percent: u8,
+        message: &str,
+    ) -> Result<(), GenericError> {
+        self.inner.lock().await.task_progress.insert(
+            task_instance_id.to_string(),
+            TaskProgress {
+                percent,
+                message: message.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn record_task_artifacts(
+        &self,
+        task_instance_id: &TaskInstanceId,
+        artifacts: &[ArtifactInfo],
+    ) -> Result<(), GenericError> {
+        self.inner
+            .lock()
+            .await
+            .task_artifacts
+            .insert(task_instance_id.to_string(), artifacts.to_vec());
+        Ok(())
+    }
+
+    async fn record_workflow_progress(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        tasks_completed: usize,
+        tasks_total: usize,
+    ) -> Result<(), GenericError> {
+        self.inner.lock().await.workflow_progress.insert(
+            workflow_instance_id.to_string(),
+            WorkflowProgress {
+                tasks_completed,
+                tasks_total,
+            },
+        );
+        Ok(())
+    }
+
+    async fn record_workflow_outputs(
+        &self,
+        workflow_id: &str,
+        _workflow_instance_id: &WorkflowInstanceId,
+        outputs: &HashMap<String, String>,
+    ) -> Result<(), GenericError> {
+        self.inner
+            .lock()
+            .await
+            .workflow_outputs
+            .entry(workflow_id.to_string())
+            .or_default()
+            .extend(outputs.clone());
+        Ok(())
+    }
+
+    async fn get_last_workflow_outputs(
+        &self,
+        workflow_id: &str,
+    ) -> Result<HashMap<String, String>, GenericError> {
+        Ok(self
+            .inner
+            .lock()
+            .await
+            .workflow_outputs
+            .get(workflow_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn record_queued_workflow(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        workflow: &str,
+        front: bool,
+    ) -> Result<(), GenericError> {
+        let queued = (workflow_instance_id.to_string(), workflow.to_string());
+        let queued_workflows = &mut self.inner.lock().await.queued_workflows;
+        if front {
+            queued_workflows.insert(0, queued);
+        } else {
+            queued_workflows.push(queued);
+        }
+        Ok(())
+    }
+
+    async fn remove_queued_workflow(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<(), GenericError> {
+        self.inner
+            .lock()
+            .await
+            .queued_workflows
+            .retain(|(instance_id, _)| instance_id != workflow_instance_id.as_str());
+        Ok(())
+    }
+
+    async fn get_queued_workflows(&self) -> Result<Vec<String>, GenericError> {
+        Ok(self
+            .inner
+            .lock()
+            .await
+            .queued_workflows
+            .iter()
+            .map(|(_, workflow)| workflow.clone())
+            .collect())
+    }
+
+    async fn record_run_snapshot(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        workflow: &str,
+    ) -> Result<(), GenericError> {
+        self.inner
+            .lock()
+            .await
+            .run_snapshots
+            .insert(workflow_instance_id.to_string(), workflow.to_string());
+        Ok(())
+    }
+
+    async fn get_run_snapshot(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<Option<String>, GenericError> {
+        Ok(self
+            .inner
+            .lock()
+            .await
+            .run_snapshots
+            .get(workflow_instance_id.as_str())
+            .cloned())
+    }
+
+    async fn annotate_run(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        key: &str,
+        value: &str,
+    ) -> Result<(), GenericError> {
+        self.inner
+            .lock()
+            .await
+            .run_annotations
+            .entry(workflow_instance_id.to_string())
+            .or_default()
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    async fn record_audit_entry(&self, entry: AuditEntry) -> Result<(), GenericError> {
+        self.inner.lock().await.audit_log.push(entry);
+        Ok(())
+    }
+
+    async fn read_audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>, GenericError> {
+        Ok(self
+            .inner
+            .lock()
+            .await
+            .audit_log
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_workflow_result(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        output_tail_lines: usize,
+    ) -> Result<Option<WorkflowResult>, GenericError> {
+        let state = self.inner.lock().await;
+        let latest_workflow_status = match state
+            .workflow_statuses
+            .iter()
+            .filter(|s| s.workflow_instance_id() == workflow_instance_id)
+            .max_by_key(|s| s.timestamp_ms())
+        {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+
+        // group the status updates by task instance, keeping first-seen order
+        let mut task_updates: Vec<(&str, Vec<&TaskStatusUpdate>)> = Vec::new();
+        for update in state
+            .task_statuses
+            .iter()
+            .filter(|s| s.workflow_instance_id() == workflow_instance_id)
+        {
+            match task_updates
+                .iter_mut()
+                .find(|(id, _)| *id == update.task_instance_id().as_str())
+            {
+                Some((_, updates)) => updates.push(update),
+                None => task_updates.push((update.task_instance_id().as_str(), vec![update])),
+            }
+        }
+
+        let mut tasks: Vec<(Option<u64>, TaskResult)> = task_updates
+            .into_iter()
+            .map(|(task_instance_id, updates)| {
+                let latest = updates
+                    .iter()
+                    .max_by_key(|s| s.timestamp_ms())
+                    .expect("every group has at least one update");
+                let start_ts = updates
+                    .iter()
+                    .filter(|s| s.status() == RunStatus::RUNNING.to_string())
+                    .map(|s| s.timestamp_ms())
+                    .min();
+                let end_ts = updates
+                    .iter()
+                    .filter(|s| is_terminal(s.status()))
+                    .map(|s| s.timestamp_ms())
+                    .max();
+                let duration_ms = match (start_ts, end_ts) {
+                    (Some(start), Some(end)) => Some(end as i64 - start as i64),
+                    _ => None,
+                };
+                let mut task_logs: Vec<&LogMessage> = state
+                    .logs
+                    .iter()
+                    .filter(|l| {
+                        l.workflow_instance_id == workflow_instance_id.as_str()
+                            && l.task_instance_id == task_instance_id
+                    })
+                    .collect();
+                task_logs.sort_by_key(|l| l.timestamp_ms);
+                let output_tail = task_logs
+                    .iter()
+                    .skip(task_logs.len().saturating_sub(output_tail_lines))
+                    .map(|l| l.payload.clone())
+                    .collect();
+                (
+                    start_ts,
+                    TaskResult {
+                        task_id: latest.task_id().to_string(),
+                        task_instance_id: task_instance_id.to_string(),
+                        status: latest.status().to_string(),
+                        exit_code: state.exit_codes.get(task_instance_id).copied(),
+                        duration_ms,
+                        output_tail,
+                        progress: state.task_progress.get(task_instance_id).cloned(),
+                        artifacts: state
+                            .task_artifacts
+                            .get(task_instance_id)
+                            .cloned()
+                            .unwrap_or_default(),
+                        truncated: state.truncated_tasks.contains(task_instance_id),
+                    },
+                )
+            })
+            .collect();
+        // same ordering as the DuckDB backend - started tasks first
+        tasks.sort_by(|(a_start, a), (b_start, b)| {
+            (a_start.is_none(), a_start, &a.task_id).cmp(&(b_start.is_none(), b_start, &b.task_id))
+        });
+
+        let run_attempt = state
+            .run_attempts
+            .get(workflow_instance_id.as_str())
+            .cloned();
+        Ok(Some(WorkflowResult {
+            workflow_id: latest_workflow_status.workflow_id().to_string(),
+            workflow_instance_id: workflow_instance_id.to_string(),
+            status: latest_workflow_status.status().to_string(),
+            duration_ms: workflow_duration_ms(&state, workflow_instance_id),
+            sla_breached: state
+                .sla_results
+                .get(workflow_instance_id.as_str())
+                .copied(),
+            retry_of: run_attempt.as_ref().map(|(retry_of, _)| retry_of.clone()),
+            attempt: run_attempt.map(|(_, attempt)| attempt),
+            annotations: state
+                .run_annotations
+                .get(workflow_instance_id.as_str())
+                .cloned()
+                .unwrap_or_default(),
+            progress: state
+                .workflow_progress
+                .get(workflow_instance_id.as_str())
+                .copied(),
+            tasks: tasks.into_iter().map(|(_, task)| task).collect(),
+        }))
+    }
+}
@@ -0,0 +1,189 @@
+/// Persistence backends for the run history of the principal. The principal only
+/// talks to its storage through the `StatusStore` trait so that DuckDB (the default,
+/// implemented on `DBClient`) can be swapped for another database or the in-memory
+/// store used in tests
+use async_trait::async_trait;
+use cdktr_api::models::{
+    ArtifactInfo, AuditEntry, LogPage, TaskStatusUpdate, WorkflowResult, WorkflowStatusUpdate,
+};
+use cdktr_core::{
+    exceptions::GenericError,
+    models::{TaskInstanceId, WorkflowInstanceId},
+};
+use std::collections::HashMap;
+
+use crate::log_manager::model::LogMessage;
+
+mod db;
+mod memory;
+
+pub use memory::InMemoryStatusStore;
+
+#[async_trait]
+pub trait StatusStore: Send + Sync {
+    /// Persists a task status update along with the exit code of the task process
+    /// if it has one and whether its output was truncated
+    async fn record_task_status(
+        &self,
+        update: TaskStatusUpdate,
+        exit_code: Option<i32>,
+        truncated: bool,
+    ) -> Result<(), GenericError>;
+
+    /// Persists a batch of workflow status updates
+    async fn record_workflow_statuses(
+        &self,
+        updates: Vec<WorkflowStatusUpdate>,
+    ) -> Result<(), GenericError>;
+
+    /// Persists a batch of log messages. Returns the batch as the Err variant
+    /// so the caller can retry it later
+    async fn persist_logs(&self, logs: Vec<LogMessage>) -> Result<(), Vec<LogMessage>>;
+
+    /// Reads the logs in the time range [start, end), optionally filtered to a
+    /// workflow and/or a specific workflow run. With a page, only that page of the
+    /// logs ordered by timestamp is read
+    async fn read_logs(
+        &self,
+        start_timestamp_ms: u64,
+        end_timestamp_ms: u64,
+        workflow_id: Option<String>,
+        workflow_instance_id: Option<WorkflowInstanceId>,
+        page: Option<LogPage>,
+    ) -> Result<Vec<LogMessage>, GenericError>;
+
+    /// Reads the last `n` logs of a workflow run, oldest first
+    async fn read_log_tail(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        n: usize,
+    ) -> Result<Vec<LogMessage>, GenericError>;
+
+    /// Gets the latest status update of the `limit` most recently updated workflows
+    async fn get_recent_workflow_statuses(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<WorkflowStatusUpdate>, GenericError>;
+
+    /// Looks up the workflow id of a workflow run
+    async fn get_workflow_id(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<Option<String>, GenericError>;
+
+    /// Time from a workflow run first starting to it finishing, from the persisted
+    /// status timestamps. None if the run hasn't both started and finished
+    async fn get_workflow_duration_ms(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<Option<i64>, GenericError>;
+
+    /// Persists whether a finished workflow run took longer than its SLA
+    async fn record_sla_result(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        sla_ms: u64,
+        breached: bool,
+    ) -> Result<(), GenericError>;
+
+    /// Persists that a workflow run is a retry of the failed run `retry_of`
+    async fn record_run_attempt(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        retry_of: &WorkflowInstanceId,
+        attempt: u32,
+    ) -> Result<(), GenericError>;
+
+    /// Persists the progress reported by a running task
+    async fn record_task_progress(
+        &self,
+        task_instance_id: &TaskInstanceId,
+        percent: u8,
+        message: &str,
+    ) -> Result<(), GenericError>;
+
+    /// Persists the files matching the artifact globs of a finished task
+    async fn record_task_artifacts(
+        &self,
+        task_instance_id: &TaskInstanceId,
+        artifacts: &[ArtifactInfo],
+    ) -> Result<(), GenericError>;
+
+    /// Persists the number of tasks of a running workflow that have finished
+    async fn record_workflow_progress(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        tasks_completed: usize,
+        tasks_total: usize,
+    ) -> Result<(), GenericError>;
+
+    /// Persists the named outputs of a successful workflow run
+    async fn record_workflow_outputs(
+        &self,
+        workflow_id: &str,
+        workflow_instance_id: &WorkflowInstanceId,
+        outputs: &HashMap<String, String>,
+    ) -> Result<(), GenericError>;
+
+    /// The most recently recorded value of each named output of a workflow. Empty if
+    /// no run of the workflow has recorded any outputs
+    async fn get_last_workflow_outputs(
+        &self,
+        workflow_id: &str,
+    ) -> Result<HashMap<String, String>, GenericError>;
+
+    /// Persists a workflow run waiting on the principal queue, as the JSON the run is sent
+    /// to agents as, so it can be queued again if the principal restarts. Runs put back
+    /// at the `front` of the queue are restored ahead of the rest
+    async fn record_queued_workflow(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        workflow: &str,
+        front: bool,
+    ) -> Result<(), GenericError>;
+
+    /// Removes a workflow run from the persisted queue once it has left the queue
+    async fn remove_queued_workflow(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<(), GenericError>;
+
+    /// The workflow runs still waiting on the persisted queue, in the order they were queued
+    async fn get_queued_workflows(&self) -> Result<Vec<String>, GenericError>;
+
+    /// Persists the workflow a run was queued with, as the JSON the run is sent to
+    /// agents as, so the run can be replayed later
+    async fn record_run_snapshot(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        workflow: &str,
+    ) -> Result<(), GenericError>;
+
+    /// The workflow a run was queued with. None if no snapshot of the run was recorded
+    async fn get_run_snapshot(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+    ) -> Result<Option<String>, GenericError>;
+
+    /// Sets an annotation of a workflow run, replacing any earlier value of the same key
+    async fn annotate_run(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        key: &str,
+        value: &str,
+    ) -> Result<(), GenericError>;
+
+    /// Persists an administrative action taken on the principal
+    async fn record_audit_entry(&self, entry: AuditEntry) -> Result<(), GenericError>;
+
+    /// The latest administrative actions taken on the principal, newest first
+    async fn read_audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>, GenericError>;
+
+    /// Aggregates the outcome of a workflow run and each of its tasks. Returns None
+    /// if the workflow run has never been recorded
+    async fn get_workflow_result(
+        &self,
+        workflow_instance_id: &WorkflowInstanceId,
+        output_tail_lines: usize,
+    ) -> Result<Option<WorkflowResult>, GenericError>;
+}
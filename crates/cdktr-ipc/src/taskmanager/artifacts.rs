@@ -0,0 +1,581 @@
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use cdktr_api::{
+    API, PrincipalAPI,
+    models::{ArtifactInfo, ClientResponseMessage},
+};
+use cdktr_core::exceptions::GenericError;
+use log::info;
+use std::path::{Component, Path, PathBuf};
+
+use crate::server::principal::artifacts::ARTIFACT_CHUNK_BYTES;
+
+/// Sends artifact chunks to and from the principal
+#[async_trait]
+pub trait ArtifactTransport: Send + Sync {
+    async fn request(&self, msg: PrincipalAPI) -> Result<ClientResponseMessage, GenericError>;
+}
+
+/// Transfers artifacts with the principal the agent is connected to
+pub struct PrincipalTransport;
+
+#[async_trait]
+impl ArtifactTransport for PrincipalTransport {
+    async fn request(&self, msg: PrincipalAPI) -> Result<ClientResponseMessage, GenericError> {
+        msg.send().await
+    }
+}
+
+/// Where a `produces`, `consumes` or `artifacts` path of a task is on the agent. Relative
+/// paths are resolved from the working directory of the task, if it sets one
+fn local_path(working_directory: Option<&str>, path: &str) -> PathBuf {
+    match working_directory {
+        Some(dir) => Path::new(dir).join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Uploads the files a task produced to the principal so tasks later in the workflow
+/// run can consume them. Artifacts are stored under the path the task declares them
+/// with, so a consumer finds them whatever its working directory
+pub async fn upload_artifacts(
+    transport: &dyn ArtifactTransport,
+    workflow_instance_id: &str,
+    working_directory: Option<&str>,
+    paths: &[String],
+) -> Result<(), GenericError> {
+    for path in paths {
+        let local = local_path(working_directory, path);
+        let contents = tokio::fs::read(&local).await.map_err(|e| {
+            GenericError::RuntimeError(format!("Failed to read artifact {}: {}", path, e))
+        })?;
+        // an empty file is still sent as a single empty chunk so that it exists
+        let mut chunks: Vec<&[u8]> = contents.chunks(ARTIFACT_CHUNK_BYTES).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let msg = PrincipalAPI::PutArtifact(
+                workflow_instance_id.into(),
+                path.clone(),
+                i * ARTIFACT_CHUNK_BYTES,
+                BASE64.encode(chunk),
+            );
+            match transport.request(msg).await? {
+                ClientResponseMessage::Success => (),
+                other => {
+                    return Err(GenericError::RuntimeError(format!(
+                        "Failed to upload artifact {}: {}",
+                        path,
+                        other.payload()
+                    )));
+                }
+            }
+        }
+        info!("Uploaded artifact {} ({} bytes)", path, contents.len());
+    }
+    Ok(())
+}
+
+/// Downloads the files a task consumes from the principal, writing each one to its path
+/// on the agent before the task runs
+pub async fn download_artifacts(
+    transport: &dyn ArtifactTransport,
+    workflow_instance_id: &str,
+    working_directory: Option<&str>,
+    paths: &[String],
+) -> Result<(), GenericError> {
+    for path in paths {
+        let mut contents = Vec::new();
+        loop {
+            let msg = PrincipalAPI::GetArtifact(
+                workflow_instance_id.into(),
+                path.clone(),
+                contents.len(),
+            );
+            match transport.request(msg).await? {
+                ClientResponseMessage::Success => break,
+                ClientResponseMessage::SuccessWithPayload(chunk) => {
+                    let chunk = BASE64.decode(chunk).map_err(|e| {
+                        GenericError::RuntimeError(format!(
+                            "Failed to decode chunk of artifact {}: {}",
+                            path, e
+                        ))
+                    })?;
+                    contents.extend(chunk);
+                }
+                other => {
+                    return Err(GenericError::RuntimeError(format!(
+                        "Failed to download artifact {}: {}",
+                        path,
+                        other.payload()
+                    )));
+                }
+            }
+        }
+        let local = local_path(working_directory, path);
+        if let Some(parent) = local.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            tokio::fs::create_dir_all(parent).await.map_err(|e| {
+                GenericError::RuntimeError(format!(
+                    "Failed to create directory for artifact {}: {}",
+                    path, e
+                ))
+            })?;
+        }
+        tokio::fs::write(&local, &contents).await.map_err(|e| {
+            GenericError::RuntimeError(format!("Failed to write artifact {}: {}", path, e))
+        })?;
+        info!("Downloaded artifact {} ({} bytes)", path, contents.len());
+    }
+    Ok(())
+}
+
+/// Lists the files matching the `artifacts` globs of a finished task with the principal.
+/// The files themselves stay on the agent
+pub async fn report_artifacts(
+    transport: &dyn ArtifactTransport,
+    agent_id: &str,
+    task_execution_id: &str,
+    working_directory: Option<&str>,
+    globs: &[String],
+) -> Result<(), GenericError> {
+    let (working_directory, globs) = (working_directory.map(str::to_string), globs.to_vec());
+    let artifacts = tokio::task::spawn_blocking(move || {
+        collect_artifacts(working_directory.as_deref(), &globs)
+    })
+    .await
+    .map_err(|e| GenericError::RuntimeError(format!("Failed to resolve artifacts: {}", e)))?;
+    info!(
+        "Task {} left {} artifact(s)",
+        task_execution_id,
+        artifacts.len()
+    );
+    let msg =
+        PrincipalAPI::TaskArtifacts(agent_id.to_string(), task_execution_id.into(), artifacts);
+    match transport.request(msg).await? {
+        ClientResponseMessage::Success => Ok(()),
+        other => Err(GenericError::RuntimeError(format!(
+            "Failed to report artifacts: {}",
+            other.payload()
+        ))),
+    }
+}
+
+/// Resolves globs to the files matching them, sorted by path. Relative globs are resolved
+/// from `working_directory`, if set. A glob matching nothing adds nothing
+pub fn collect_artifacts(working_directory: Option<&str>, globs: &[String]) -> Vec<ArtifactInfo> {
+    let mut paths: Vec<PathBuf> = globs
+        .iter()
+        .flat_map(|glob| expand_glob(&local_path(working_directory, glob).to_string_lossy()))
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let metadata = std::fs::metadata(&path).ok().filter(|m| m.is_file())?;
+            Some(ArtifactInfo {
+                path: path.to_string_lossy().to_string(),
+                size_bytes: metadata.len(),
+            })
+        })
+        .collect()
+}
+
+/// Expands `*` and `?` within a path component and `**` to any number of directories.
+/// Wildcards don't match names starting with a dot unless the pattern does
+fn expand_glob(glob: &str) -> Vec<PathBuf> {
+    let mut matches = vec![PathBuf::new()];
+    for component in Path::new(glob).components() {
+        let pattern = component.as_os_str().to_string_lossy();
+        matches = match component {
+            Component::Normal(_) if pattern == "**" => {
+                matches.into_iter().flat_map(with_subdirectories).collect()
+            }
+            Component::Normal(_) if pattern.contains(['*', '?']) => matches
+                .iter()
+                .flat_map(|dir| {
+                    entries(dir)
+                        .filter(|(name, _)| {
+                            (!name.starts_with('.') || pattern.starts_with('.'))
+                                && wildcard_match(&pattern, name)
+                        })
+                        .map(|(_, path)| path)
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+            _ => matches
+                .into_iter()
+                .map(|path| path.join(component))
+                .collect(),
+        };
+    }
+    matches
+}
+
+/// Names and paths of the entries of a directory, the working directory if `dir` is empty
+fn entries(dir: &Path) -> impl Iterator<Item = (String, PathBuf)> {
+    let read_from = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    std::fs::read_dir(read_from)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(move |entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = dir.join(&name);
+            (name, path)
+        })
+}
+
+/// The directory and every directory below it. Symlinked directories aren't followed
+fn with_subdirectories(dir: PathBuf) -> Vec<PathBuf> {
+    let mut dirs = vec![dir];
+    let mut i = 0;
+    while i < dirs.len() {
+        let read_from = if dirs[i].as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            &dirs[i]
+        };
+        let children: Vec<PathBuf> = std::fs::read_dir(read_from)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .map(|entry| dirs[i].join(entry.file_name()))
+            .collect();
+        dirs.extend(children);
+        i += 1;
+    }
+    dirs
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters and `?`
+/// any single character
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and of the name when it was reached, to backtrack to
+    let mut last_star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            last_star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = last_star {
+            // let the star take one more character
+            last_star = Some((star_p, star_n + 1));
+            p = star_p + 1;
+            n = star_n + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::principal::PrincipalServer;
+    use crate::server::traits::Server;
+    use crate::store::InMemoryStatusStore;
+    use crate::taskmanager::{TaskResultCache, execute_task};
+    use cdktr_core::models::{FlowExecutionResult, RunStatus};
+    use cdktr_workflow::{Task, WorkflowStore};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{Mutex, mpsc};
+
+    /// Hands messages straight to a principal server instead of over ZMQ
+    struct LocalTransport(Mutex<PrincipalServer>);
+
+    #[async_trait]
+    impl ArtifactTransport for LocalTransport {
+        async fn request(&self, msg: PrincipalAPI) -> Result<ClientResponseMessage, GenericError> {
+            Ok(self.0.lock().await.handle_client_message(msg).await.0)
+        }
+    }
+
+    fn subprocess_task(script: &str) -> Task {
+        serde_json::from_value(serde_json::json!({
+            "name": "task",
+            "description": null,
+            "depends": null,
+            "matrix": null,
+            "config": {
+                "Subprocess": {
+                    "cmd": "sh",
+                    "args": ["-c", script],
+                    "run_as_user": null
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    async fn run(task: &Task) -> (FlowExecutionResult, Vec<String>) {
+        let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+        let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+        let cache = TaskResultCache::new(Duration::from_secs(60), 0);
+        let run = execute_task(task, &cache, None, 0, false, None, stdout_tx, stderr_tx);
+        let (run, lines) = tokio::join!(run, async {
+            let mut lines = Vec::new();
+            while let Some(line) = stdout_rx.recv().await {
+                lines.push(line);
+            }
+            lines
+        });
+        (run.result, lines)
+    }
+
+    #[tokio::test]
+    async fn test_artifact_passed_between_tasks() {
+        let server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            WorkflowStore::from_dir("./test_artifacts/workflows")
+                .await
+                .unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let transport = LocalTransport(Mutex::new(server));
+        let dir = std::env::temp_dir().join(format!("cdktr-artifacts-{}", std::process::id()));
+        let path = dir.join("out").join("data.csv");
+        let paths = vec![path.to_string_lossy().to_string()];
+
+        // task A produces a file bigger than a single chunk
+        let produce = subprocess_task(&format!(
+            "mkdir -p {0} && (echo id,value; seq 1 100000 | sed 's/$/,x/') > {1}",
+            path.parent().unwrap().display(),
+            path.display()
+        ));
+        assert!(matches!(
+            run(&produce).await.0,
+            FlowExecutionResult::SUCCESS
+        ));
+        let produced = std::fs::read(&path).unwrap();
+        assert!(produced.len() > ARTIFACT_CHUNK_BYTES);
+        upload_artifacts(&transport, "run-1", None, &paths)
+            .await
+            .unwrap();
+
+        // task B runs on an agent without the file
+        std::fs::remove_dir_all(&dir).unwrap();
+        download_artifacts(&transport, "run-1", None, &paths)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), produced);
+        let consume = subprocess_task(&format!("head -n 2 {}", path.display()));
+        let (result, lines) = run(&consume).await;
+        assert!(matches!(result, FlowExecutionResult::SUCCESS));
+        assert_eq!(lines, vec!["id,value", "1,x"]);
+
+        // artifacts are only visible to their own run
+        assert!(
+            download_artifacts(&transport, "run-2", None, &paths)
+                .await
+                .is_err()
+        );
+
+        // and are dropped once the run finishes
+        transport
+            .request(PrincipalAPI::WorkflowStatusUpdate(
+                "agent".to_string(),
+                "flow".to_string(),
+                "run-1".into(),
+                RunStatus::COMPLETED,
+            ))
+            .await
+            .unwrap();
+        assert!(
+            download_artifacts(&transport, "run-1", None, &paths)
+                .await
+                .is_err()
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_artifact_globs_listed_in_result() {
+        let server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            WorkflowStore::from_dir("./test_artifacts/workflows")
+                .await
+                .unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let transport = LocalTransport(Mutex::new(server));
+        let dir = std::env::temp_dir().join(format!("cdktr-artifact-globs-{}", std::process::id()));
+        let task = subprocess_task(&format!(
+            "mkdir -p {0}/out/nested && printf 'id\\n1' > {0}/out/orders.csv \
+             && printf abc > {0}/out/nested/items.csv && touch {0}/out/notes.txt",
+            dir.display()
+        ));
+        assert!(matches!(run(&task).await.0, FlowExecutionResult::SUCCESS));
+
+        transport
+            .request(PrincipalAPI::WorkflowStatusUpdate(
+                "agent".to_string(),
+                "flow".to_string(),
+                "run-1".into(),
+                RunStatus::RUNNING,
+            ))
+            .await
+            .unwrap();
+        for task_id in ["export", "report"] {
+            transport
+                .request(PrincipalAPI::TaskStatusUpdate(
+                    "agent".to_string(),
+                    task_id.to_string(),
+                    format!("{task_id}-ins").into(),
+                    "run-1".into(),
+                    RunStatus::COMPLETED,
+                    Some(0),
+                    false,
+                ))
+                .await
+                .unwrap();
+        }
+        let out = dir.join("out");
+        // overlapping globs list each file once
+        let globs = vec![
+            format!("{}/*.csv", out.display()),
+            format!("{}/**/*.csv", out.display()),
+        ];
+        report_artifacts(&transport, "agent", "export-ins", None, &globs)
+            .await
+            .unwrap();
+        // a glob matching nothing is an empty list rather than an error
+        let missing = vec![format!("{}/*.parquet", out.display())];
+        report_artifacts(&transport, "agent", "report-ins", None, &missing)
+            .await
+            .unwrap();
+
+        let result = match transport
+            .request(PrincipalAPI::GetWorkflowResult("run-1".into()))
+            .await
+            .unwrap()
+        {
+            ClientResponseMessage::SuccessWithPayload(payload) => {
+                serde_json::from_str::<cdktr_api::models::WorkflowResult>(&payload).unwrap()
+            }
+            other => panic!("unexpected response {:?}", other),
+        };
+        let artifacts = |task_id: &str| {
+            result
+                .tasks
+                .iter()
+                .find(|t| t.task_id == task_id)
+                .unwrap()
+                .artifacts
+                .clone()
+        };
+        assert_eq!(
+            artifacts("export"),
+            vec![
+                ArtifactInfo {
+                    path: out.join("nested/items.csv").to_string_lossy().to_string(),
+                    size_bytes: 3,
+                },
+                ArtifactInfo {
+                    path: out.join("orders.csv").to_string_lossy().to_string(),
+                    size_bytes: 4,
+                },
+            ]
+        );
+        assert!(artifacts("report").is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_artifact_paths_relative_to_working_directory() {
+        let server = PrincipalServer::new(
+            "fake_ins".to_string(),
+            WorkflowStore::from_dir("./test_artifacts/workflows")
+                .await
+                .unwrap(),
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        let transport = LocalTransport(Mutex::new(server));
+        let dir = std::env::temp_dir().join(format!("cdktr-artifact-cwd-{}", std::process::id()));
+        let (producer_dir, consumer_dir) = (dir.join("producer"), dir.join("consumer"));
+        std::fs::create_dir_all(producer_dir.join("out")).unwrap();
+        std::fs::write(producer_dir.join("out/data.csv"), "id\n1").unwrap();
+        let paths = vec!["out/data.csv".to_string()];
+
+        let producer_dir = producer_dir.to_string_lossy().to_string();
+        upload_artifacts(&transport, "run-1", Some(&producer_dir), &paths)
+            .await
+            .unwrap();
+        let consumer_dir = consumer_dir.to_string_lossy().to_string();
+        download_artifacts(&transport, "run-1", Some(&consumer_dir), &paths)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("consumer/out/data.csv")).unwrap(),
+            "id\n1"
+        );
+
+        assert_eq!(
+            collect_artifacts(Some(&producer_dir), &["out/*.csv".to_string()]),
+            vec![ArtifactInfo {
+                path: dir
+                    .join("producer/out/data.csv")
+                    .to_string_lossy()
+                    .to_string(),
+                size_bytes: 4,
+            }]
+        );
+        // absolute paths ignore the working directory
+        assert_eq!(
+            local_path(Some(&producer_dir), "/data/orders.csv"),
+            PathBuf::from("/data/orders.csv")
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.csv", "orders.csv"));
+        assert!(wildcard_match("part-?.csv", "part-1.csv"));
+        assert!(wildcard_match("*-*.csv", "a-b-c.csv"));
+        assert!(!wildcard_match("*.csv", "orders.csv.bak"));
+        assert!(!wildcard_match("part-?.csv", "part-10.csv"));
+    }
+
+    #[tokio::test]
+    async fn test_artifact_over_limit_rejected() {
+        let mut store = crate::server::principal::artifacts::ArtifactStore::new(4, 100);
+        assert!(store.put_chunk("run-1", "a.txt", 0, b"abc").is_ok());
+        assert!(store.put_chunk("run-1", "a.txt", 3, b"de").is_err());
+        assert!(store.get_chunk("run-1", "a.txt", 0).is_none());
+        // chunks must arrive in order
+        assert!(store.put_chunk("run-1", "b.txt", 2, b"ab").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_artifact_store_over_budget_rejected() {
+        let mut store = crate::server::principal::artifacts::ArtifactStore::new(4, 6);
+        assert!(store.put_chunk("run-1", "a.txt", 0, b"abcd").is_ok());
+        // the budget is shared between runs
+        assert!(store.put_chunk("run-2", "b.txt", 0, b"ab").is_ok());
+        assert!(store.put_chunk("run-2", "c.txt", 0, b"a").is_err());
+        assert!(store.get_chunk("run-2", "c.txt", 0).is_none());
+        // replacing an artifact frees what it held
+        assert!(store.put_chunk("run-1", "a.txt", 0, b"a").is_ok());
+        assert!(store.put_chunk("run-2", "c.txt", 0, b"abc").is_ok());
+        // as does a run finishing
+        store.clear("run-1");
+        assert!(store.put_chunk("run-2", "d.txt", 0, b"a").is_ok());
+    }
+}
@@ -0,0 +1,113 @@
+use cdktr_api::models::AgentMetrics;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use sysinfo::System;
+
+/// Number of running instances of each task on the agent, keyed by `workflow_id/task_id`
+#[derive(Clone, Default, Debug)]
+pub struct RunningTasks(Arc<Mutex<HashMap<String, usize>>>);
+
+impl RunningTasks {
+    /// Counts a task as running until the returned guard is dropped
+    pub fn track(&self, workflow_id: &str, task_id: &str) -> RunningTaskGuard {
+        let key = format!("{workflow_id}/{task_id}");
+        *self
+            .0
+            .lock()
+            .expect("running tasks lock poisoned")
+            .entry(key.clone())
+            .or_default() += 1;
+        RunningTaskGuard {
+            tasks: self.clone(),
+            key,
+        }
+    }
+
+    pub fn counts(&self) -> HashMap<String, usize> {
+        self.0.lock().expect("running tasks lock poisoned").clone()
+    }
+}
+
+pub struct RunningTaskGuard {
+    tasks: RunningTasks,
+    key: String,
+}
+
+impl Drop for RunningTaskGuard {
+    fn drop(&mut self) {
+        let mut counts = self.tasks.0.lock().expect("running tasks lock poisoned");
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Samples the CPU and memory usage of the agent's host
+pub struct HostStats {
+    system: System,
+}
+
+impl HostStats {
+    pub fn new() -> Self {
+        let mut system = System::new();
+        // CPU usage is measured between refreshes so the first sample needs a baseline
+        system.refresh_cpu_usage();
+        Self { system }
+    }
+
+    /// Collects the current metrics of the agent
+    pub fn sample(
+        &mut self,
+        running_workflows: usize,
+        running_tasks: &RunningTasks,
+    ) -> AgentMetrics {
+        self.system.refresh_cpu_usage();
+        self.system.refresh_memory();
+        AgentMetrics {
+            timestamp_ms: Utc::now().timestamp_millis(),
+            running_workflows,
+            cpu_percent: self.system.global_cpu_usage(),
+            memory_used_bytes: self.system.used_memory(),
+            memory_total_bytes: self.system.total_memory(),
+            running_tasks: running_tasks.counts(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_running_tasks_counted_until_dropped() {
+        let tasks = RunningTasks::default();
+        let first = tasks.track("etl", "extract");
+        let second = tasks.track("etl", "extract");
+        let other = tasks.track("etl", "load");
+        assert_eq!(tasks.counts().get("etl/extract"), Some(&2));
+        drop(first);
+        drop(other);
+        assert_eq!(
+            tasks.counts(),
+            HashMap::from([("etl/extract".to_string(), 1)])
+        );
+        drop(second);
+        assert!(tasks.counts().is_empty());
+    }
+
+    #[test]
+    fn test_host_stats_sampled() {
+        let tasks = RunningTasks::default();
+        let _running = tasks.track("etl", "extract");
+        let metrics = HostStats::new().sample(1, &tasks);
+        assert_eq!(metrics.running_workflows, 1);
+        assert!(metrics.memory_total_bytes > 0);
+        assert!(metrics.memory_used_bytes <= metrics.memory_total_bytes);
+        assert!((0.0..=100.0).contains(&metrics.cpu_percent));
+        assert_eq!(metrics.running_tasks.get("etl/extract"), Some(&1));
+    }
+}
@@ -1,23 +1,48 @@
-use cdktr_api::{API, PrincipalAPI};
+use cdktr_api::{
+    API, AgentAPI, PrincipalAPI,
+    models::{ClientResponseMessage, TaskProgress},
+};
+use cdktr_core::get_cdktr_setting;
 use cdktr_core::models::{FlowExecutionResult, RunStatus};
 use cdktr_core::utils::get_principal_uri;
+use cdktr_core::zmq_helpers::{check_port_available, get_server_tcp_uri, get_zmq_rep};
 use cdktr_core::{exceptions::GenericError, models::traits::Executor};
-use cdktr_workflow::Task;
-use log::{debug, error, info, warn};
-use rustyrs::EternalSlugGenerator;
+use cdktr_workflow::{Condition, Redaction, SecretSource, Task};
+use log::{debug, error, info, log, warn};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Duration;
 use task_tracker::TaskTracker;
 use task_tracker::ThreadSafeTaskTracker;
 use tokio::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::sync::Semaphore;
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tokio::task::JoinSet;
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout};
+use zeromq::{SocketRecv, SocketSend, ZmqMessage};
 
 use crate::client::PrincipalClient;
+use crate::ids::InstanceIdGenerator;
 use crate::log_manager::publisher::LogsPublisher;
+use crate::server::principal::dispatch::{DispatchMode, agent_push_address};
+use crate::server::traits::message_size;
+use artifacts::{PrincipalTransport, download_artifacts, report_artifacts, upload_artifacts};
+use metrics::{HostStats, RunningTasks};
+use progress::{ProgressReporter, parse_progress_line};
+use readiness::{LogLineWatch, wait_until_ready};
+use result_cache::TaskResultCache;
+use retries::{RetryBudget, execute_with_retries};
+use wait_for::wait_for_condition;
+mod artifacts;
+mod metrics;
+mod progress;
+mod readiness;
+mod result_cache;
+mod retries;
 mod task_tracker;
+mod wait_for;
 
 const WAIT_TASK_SLEEP_INTERVAL_MS: Duration = Duration::from_millis(500);
 
@@ -49,9 +74,50 @@ impl TaskExecutionHandle {
     }
 }
 
+/// Holds one of the agent's workflow slots for as long as it is alive. The slot is
+/// released on drop so that it is reclaimed however the workflow thread exits - including
+/// early returns and panics
+#[derive(Debug)]
+struct WorkflowSlotGuard {
+    workflow_counter: Arc<AtomicUsize>,
+    slot_freed: Arc<Notify>,
+    slots: usize,
+}
+
+impl WorkflowSlotGuard {
+    fn acquire(workflow_counter: Arc<AtomicUsize>, slot_freed: Arc<Notify>) -> Self {
+        Self::acquire_many(workflow_counter, slot_freed, 1)
+    }
+
+    /// Holds several slots at once, e.g. all of them for a workflow that needs the
+    /// agent to itself
+    fn acquire_many(
+        workflow_counter: Arc<AtomicUsize>,
+        slot_freed: Arc<Notify>,
+        slots: usize,
+    ) -> Self {
+        let previous = workflow_counter.fetch_add(slots, Ordering::SeqCst);
+        debug!("Incrementing workflow counter (currently {})", previous);
+        Self {
+            workflow_counter,
+            slot_freed,
+            slots,
+        }
+    }
+}
+
+impl Drop for WorkflowSlotGuard {
+    fn drop(&mut self) {
+        let previous = self
+            .workflow_counter
+            .fetch_sub(self.slots, Ordering::SeqCst);
+        debug!("Decrementing workflow counter (currently {})", previous);
+        self.slot_freed.notify_one();
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum TaskManagerError {
-    #[allow(dead_code)]
     TooManyThreadsError,
     FailedTaskError(String),
 }
@@ -73,29 +139,81 @@ impl TaskManagerError {
 /// - `instance_id`: A `String` identifier for the instance of `TaskManager`. This can be used to differentiate between multiple instances.
 /// - `max_concurrency`: The maximum number of workflows that a single agent can handle simultaneously. Also applies to tasks within workflows
 ///     where multpple tasks can be executed in parallel.
-/// - `workflow_counter`: An `Arc<Mutex<usize>>` that safely counts the number of active threads. This is shared across tasks to ensure thread-safe updates.
+/// - `workflow_counter`: An `Arc<AtomicUsize>` that counts the number of active workflows. Each workflow thread holds a
+///   `WorkflowSlotGuard` which decrements the counter when the thread ends, even if it panics.
+/// - `slot_freed`: Notified by a `WorkflowSlotGuard` when it is released, so that an agent with every slot taken
+///   fetches again as soon as one frees up.
+/// - `min_poll_interval`: How long the agent waits after a slot frees up before fetching again.
+/// - `task_permits`: An agent-wide `Semaphore` limiting the number of tasks executing at once across all workflows,
+///   including the parallel expansions of matrix tasks.
+/// - `result_cache`: Output of successful tasks marked with `cache: true`, replayed when the same task runs again.
+/// - `secret_source`: Where secrets referenced by tasks are resolved from. `None` if the configured source is invalid,
+///   in which case tasks that need secrets fail.
+/// - `running_tasks`: Running instances of each task, reported to the principal with the agent's metrics.
+/// - `dispatch_mode`: Whether the agent fetches its workflows from the principal or listens for the principal
+///   to push them.
 ///
 pub struct TaskManager {
     instance_id: String,
     max_concurrent_workflows: usize,
-    workflow_counter: Arc<Mutex<usize>>,
+    workflow_counter: Arc<AtomicUsize>,
+    slot_freed: Arc<Notify>,
+    min_poll_interval: Duration,
+    task_permits: Arc<Semaphore>,
+    result_cache: TaskResultCache,
+    secret_source: Option<SecretSource>,
+    running_tasks: RunningTasks,
     principal_client: PrincipalClient,
-    name_gen: Arc<Mutex<EternalSlugGenerator>>,
+    id_gen: Arc<Mutex<InstanceIdGenerator>>,
+    dispatch_mode: DispatchMode,
 }
 
 impl TaskManager {
     pub async fn new(instance_id: String, max_concurrent_workflows: usize) -> Self {
-        let principal_client = PrincipalClient::new(instance_id.clone());
+        let dispatch_mode = DispatchMode::from_config();
+        let mut principal_client =
+            PrincipalClient::new(instance_id.clone(), max_concurrent_workflows);
+        if dispatch_mode == DispatchMode::Push {
+            principal_client = principal_client.with_push_address(agent_push_address());
+        }
+        let secret_source = match SecretSource::from_config() {
+            Ok(source) => Some(source),
+            Err(e) => {
+                error!(
+                    "Invalid secrets source - tasks using secrets will fail: {}",
+                    e
+                );
+                None
+            }
+        };
         Self {
             instance_id,
             max_concurrent_workflows,
-            workflow_counter: Arc::new(Mutex::new(0)),
+            workflow_counter: Arc::new(AtomicUsize::new(0)),
+            slot_freed: Arc::new(Notify::new()),
+            min_poll_interval: Duration::from_millis(get_cdktr_setting!(
+                CDKTR_AGENT_MIN_POLL_INTERVAL_MS,
+                usize
+            ) as u64),
+            task_permits: Arc::new(Semaphore::new(max_concurrent_workflows)),
+            result_cache: TaskResultCache::new(
+                Duration::from_secs(get_cdktr_setting!(CDKTR_AGENT_TASK_CACHE_TTL_S, usize) as u64),
+                get_cdktr_setting!(CDKTR_AGENT_MAX_TASK_OUTPUT_BYTES, usize),
+            ),
+            secret_source,
+            running_tasks: RunningTasks::default(),
             principal_client,
-            name_gen: Arc::new(Mutex::new(EternalSlugGenerator::new(2).unwrap())),
+            id_gen: Arc::new(Mutex::new(InstanceIdGenerator::new())),
+            dispatch_mode,
         }
     }
 
     pub async fn start(&mut self) -> Result<(), GenericError> {
+        // fail fast before registering if another process holds the port workflows are
+        // pushed to
+        if self.dispatch_mode == DispatchMode::Push {
+            check_port_available("0.0.0.0", get_cdktr_setting!(CDKTR_AGENT_PUSH_PORT, usize))?;
+        }
         let register_result = self.principal_client.register_with_principal().await;
         if let Err(e) = register_result {
             error!(
@@ -109,27 +227,63 @@ impl TaskManager {
         let heartbeat_client = self.principal_client.clone();
         let heartbeat_handle = tokio::spawn(async move {
             loop {
-                sleep(Duration::from_secs(5)).await;
+                sleep(heartbeat_client.heartbeat_interval()).await;
                 if let Err(e) = heartbeat_client.send_heartbeat().await {
-                    error!("Failed to send heartbeat to principal: {}", e.to_string());
+                    let connection = heartbeat_client.connection();
+                    error!(
+                        "Failed to send heartbeat to principal: {} (connection lost {} time(s), restored {} time(s))",
+                        e,
+                        connection.losses(),
+                        connection.reconnects()
+                    );
                 }
             }
         });
 
+        // Spawn metrics task to report how loaded the agent is
+        let metrics_interval_s = get_cdktr_setting!(CDKTR_AGENT_METRICS_INTERVAL_S, usize) as u64;
+        let metrics_handle = if metrics_interval_s > 0 {
+            let metrics_client = self.principal_client.clone();
+            let workflow_counter = self.workflow_counter.clone();
+            let running_tasks = self.running_tasks.clone();
+            Some(tokio::spawn(async move {
+                let mut host_stats = HostStats::new();
+                loop {
+                    sleep(Duration::from_secs(metrics_interval_s)).await;
+                    let metrics =
+                        host_stats.sample(workflow_counter.load(Ordering::SeqCst), &running_tasks);
+                    if let Err(e) = metrics_client.send_metrics(metrics).await {
+                        warn!("Failed to send metrics to principal: {}", e);
+                    }
+                }
+            }))
+        } else {
+            None
+        };
+
         info!(
             "TASKMANAGER-{}: Beginning task execution loop",
             self.instance_id
         );
-        let loop_res = self.workflow_execution_loop().await;
+        let loop_res = match self.dispatch_mode {
+            DispatchMode::Pull => self.workflow_execution_loop().await,
+            DispatchMode::Push => {
+                self.workflow_push_loop(get_cdktr_setting!(CDKTR_AGENT_PUSH_PORT, usize))
+                    .await
+            }
+        };
 
-        // Abort heartbeat task when workflow loop exits
+        // Abort heartbeat and metrics tasks when workflow loop exits
         heartbeat_handle.abort();
+        if let Some(metrics_handle) = metrics_handle {
+            metrics_handle.abort();
+        }
 
         if let Err(e) = loop_res {
             //TODO: currently just aborts on errors - maybe split errors up into those that we should fully
             // abort on and others that are fine to re-engage the loop on?
-            error!("{}", e.to_string());
-            while *self.workflow_counter.lock().await > 0 {
+            error!("{}", e);
+            while self.workflow_counter.load(Ordering::SeqCst) > 0 {
                 warn!(
                     "Tasks still running after principal loss - awaiting completion before aborting"
                 );
@@ -137,265 +291,743 @@ impl TaskManager {
             }
             Err(e)
         } else {
+            if self.principal_client.is_stopping() {
+                info!("Running workflows finished - stopping agent");
+                if let Err(e) = self.principal_client.confirm_stopped().await {
+                    warn!(
+                        "Failed to confirm the agent stopped with the principal: {}",
+                        e
+                    );
+                }
+            }
             Ok(())
         }
     }
 
+    /// Fetches and runs workflows until the agent has been stopped and its running
+    /// workflows have finished
     async fn workflow_execution_loop(&mut self) -> Result<(), GenericError> {
+        let long_poll_ms = get_cdktr_setting!(CDKTR_WORKFLOW_FETCH_LONG_POLL_MS, usize) as u64;
+        let long_poll = if long_poll_ms > 0 {
+            Some(Duration::from_millis(long_poll_ms))
+        } else {
+            None
+        };
         loop {
-            {
-                let counter = self.workflow_counter.lock().await;
-                if *counter >= self.max_concurrent_workflows {
-                    debug!("Max workflows reached - waiting for free slot before requesting");
-                    sleep(WAIT_TASK_SLEEP_INTERVAL_MS).await;
-                    continue;
+            if self.principal_client.is_stopping() {
+                if self.workflow_counter.load(Ordering::SeqCst) == 0 {
+                    return Ok(());
                 }
+                sleep(WAIT_TASK_SLEEP_INTERVAL_MS).await;
+                continue;
+            }
+            if self.workflow_counter.load(Ordering::SeqCst) >= self.max_concurrent_workflows {
+                debug!("Max workflows reached - waiting for free slot before requesting");
+                self.wait_for_free_slot().await;
+                continue;
+            }
+            if self.principal_client.is_drained() {
+                // running workflows carry on in their own threads
+                sleep(WAIT_TASK_SLEEP_INTERVAL_MS).await;
+                continue;
             }
-            let workflow_counter = self.workflow_counter.clone();
             let workflow_result = self
                 .principal_client
-                .wait_next_workflow(WAIT_TASK_SLEEP_INTERVAL_MS)
+                .wait_next_workflow(WAIT_TASK_SLEEP_INTERVAL_MS, long_poll)
                 .await;
-            let workflow: cdktr_workflow::Workflow = {
-                let mut counter = workflow_counter.lock().await;
-                match workflow_result {
-                    Ok(workflow) => {
-                        debug!("Incrementing workflow counter (currently {})", *counter);
-                        *counter += 1;
-                        workflow
-                    }
-                    Err(e) => {
-                        error!("{}", e.to_string());
-                        return Err(e);
+            let workflow: cdktr_workflow::Workflow = match workflow_result {
+                Ok(workflow) => workflow,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(e);
+                }
+            };
+            let slot = self.workflow_slot(&workflow);
+            self.spawn_workflow(workflow, slot);
+        }
+    }
+
+    /// Waits for one of the agent's workflow slots to free up, for at most the usual
+    /// interval between checks. Once one does, the agent fetches again after
+    /// `CDKTR_AGENT_MIN_POLL_INTERVAL_MS` rather than idling for the rest of the interval
+    /// while work waits on the queue
+    async fn wait_for_free_slot(&self) {
+        if timeout(WAIT_TASK_SLEEP_INTERVAL_MS, self.slot_freed.notified())
+            .await
+            .is_ok()
+        {
+            sleep(self.min_poll_interval).await;
+        }
+    }
+
+    /// Runs the workflows the principal pushes to the agent in push mode. The agent listens
+    /// on `port`, its `CDKTR_AGENT_PUSH_PORT`, instead of fetching its work. Returns once
+    /// the agent has been stopped and its running workflows have finished
+    async fn workflow_push_loop(&mut self, port: usize) -> Result<(), GenericError> {
+        let max_message_bytes = get_cdktr_setting!(CDKTR_MAX_MESSAGE_BYTES, usize);
+        let mut rep_socket = get_zmq_rep(&get_server_tcp_uri("0.0.0.0", port)).await?;
+        info!(
+            "TASKMANAGER-{}: Listening for workflows pushed by the principal on port {}",
+            self.instance_id, port
+        );
+        loop {
+            let msg = tokio::select! {
+                msg = rep_socket.recv() => msg,
+                // the principal pushes nothing to a stopped agent so check in between
+                _ = sleep(WAIT_TASK_SLEEP_INTERVAL_MS) => {
+                    if self.principal_client.is_stopping()
+                        && self.workflow_counter.load(Ordering::SeqCst) == 0
+                    {
+                        return Ok(());
                     }
+                    continue;
                 }
             };
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Failed to receive pushed workflow: {}", e);
+                    continue;
+                }
+            };
+            let response = self.handle_agent_command(msg, max_message_bytes);
+            if let Err(e) = rep_socket.send(response.into()).await {
+                warn!("Failed to respond to principal: {}", e);
+            }
+        }
+    }
 
-            debug!("MAX WF -> {}", self.max_concurrent_workflows);
-            let name_gen_cl = self.name_gen.clone();
-            // spawn workflow thread so we can return to request another workflow
-            let agent_id = self.instance_id.clone();
-            let workflow_id = workflow.id().clone();
-            let _wf_handle: JoinHandle<Result<(), GenericError>> = tokio::spawn(async move {
-                let workflow_instance_id = { name_gen_cl.lock().await.next() };
-                if PrincipalAPI::WorkflowStatusUpdate(
-                    agent_id.clone(),
-                    workflow_id.clone(),
-                    workflow_instance_id.clone(),
-                    RunStatus::RUNNING,
+    /// Starts a workflow pushed by the principal. Pushed workflows are refused with a
+    /// Retryable response while the agent is drained or running as many workflows as it
+    /// can, so that the principal queues them again. Messages bigger than
+    /// `max_message_bytes` are rejected before they are parsed
+    fn handle_agent_command(
+        &self,
+        msg: ZmqMessage,
+        max_message_bytes: usize,
+    ) -> ClientResponseMessage {
+        let size = message_size(&msg);
+        if size > max_message_bytes {
+            warn!(
+                "TASKMANAGER-{}: Rejected pushed message of {size} bytes, over the CDKTR_MAX_MESSAGE_BYTES limit of {max_message_bytes}",
+                self.instance_id
+            );
+            return ClientResponseMessage::ClientError("message too large".to_string());
+        }
+        let workflow_str = match AgentAPI::try_from(msg) {
+            Ok(AgentAPI::Run(workflow_str)) => workflow_str,
+            Ok(other) => {
+                return ClientResponseMessage::ClientError(format!("Unsupported command: {other}"));
+            }
+            Err(e) => return ClientResponseMessage::ClientError(e.to_string()),
+        };
+        if self.principal_client.is_drained() {
+            return ClientResponseMessage::Retryable("Agent is drained".to_string());
+        }
+        if self.workflow_counter.load(Ordering::SeqCst) >= self.max_concurrent_workflows {
+            return ClientResponseMessage::Retryable(
+                "Agent is running its maximum number of workflows".to_string(),
+            );
+        }
+        match cdktr_workflow::Workflow::try_from(workflow_str) {
+            Ok(workflow) => {
+                info!("Workflow pushed by principal -> {}", workflow.name());
+                let slot = self.workflow_slot(&workflow);
+                self.spawn_workflow(workflow, slot);
+                ClientResponseMessage::Success
+            }
+            Err(e) => {
+                ClientResponseMessage::ClientError(format!("Failed to read Workflow JSON: {}", e))
+            }
+        }
+    }
+
+    /// Takes a workflow slot for a run. A run of a workflow with `exclusive_agent` takes
+    /// all of them so that the agent fetches nothing else, and reports itself as fully
+    /// busy, until it finishes
+    fn workflow_slot(&self, workflow: &cdktr_workflow::Workflow) -> WorkflowSlotGuard {
+        if workflow.exclusive_agent() && !workflow.dry_run() {
+            info!(
+                "Workflow {} needs the agent to itself - no other workflows are taken until it finishes",
+                workflow.name()
+            );
+            WorkflowSlotGuard::acquire_many(
+                self.workflow_counter.clone(),
+                self.slot_freed.clone(),
+                self.max_concurrent_workflows,
+            )
+        } else {
+            WorkflowSlotGuard::acquire(self.workflow_counter.clone(), self.slot_freed.clone())
+        }
+    }
+
+    /// Runs a workflow in its own thread, holding one of the agent's workflow slots
+    /// until it ends
+    fn spawn_workflow(&self, workflow: cdktr_workflow::Workflow, slot: WorkflowSlotGuard) {
+        debug!("MAX WF -> {}", self.max_concurrent_workflows);
+        let id_gen = self.id_gen.clone();
+        let task_permits = self.task_permits.clone();
+        let result_cache = self.result_cache.clone();
+        let secret_source = self.secret_source.clone();
+        let running_tasks = self.running_tasks.clone();
+        // spawn workflow thread so we can return to request another workflow
+        let agent_id = self.instance_id.clone();
+        let workflow_id = workflow.id().clone();
+        let _wf_handle: JoinHandle<Result<(), GenericError>> = tokio::spawn(async move {
+            // released when this thread ends, however it ends
+            let _slot = slot;
+            // runs queued by the principal are given their instance id there
+            let workflow_instance_id = match workflow.instance_id() {
+                Some(instance_id) => instance_id.clone(),
+                None => id_gen.lock().await.next(),
+            };
+            if PrincipalAPI::WorkflowStatusUpdate(
+                agent_id.clone(),
+                workflow_id.clone(),
+                workflow_instance_id.clone().into(),
+                RunStatus::RUNNING,
+            )
+            .send()
+            .await
+            .is_err()
+            {
+                error!(
+                    "Failed to send status update of RUNNING to principal for: {workflow_id}/{workflow_instance_id}"
                 )
-                .send()
-                .await
-                .is_err()
-                {
-                    error!(
-                        "Failed to send status update of RUNNING to principal for: {workflow_id}/{workflow_instance_id}"
-                    )
+            };
+            let mut task_tracker = ThreadSafeTaskTracker::from_workflow(&workflow)?;
+            if task_tracker.is_finished() {
+                warn!(
+                    "Workflow {} doesn't have any tasks defined - skipping",
+                    workflow.name()
+                );
+                return Ok(());
+            }
+            let dry_run = workflow.dry_run();
+            let retry_budget = RetryBudget::new(workflow.retry_budget());
+            if dry_run {
+                info!(
+                    "Dry run of workflow {}->{} - tasks will not be run",
+                    workflow.name(),
+                    workflow_instance_id
+                );
+            }
+            let mut read_handles = JoinSet::new();
+            let mut reported_completed = 0;
+            while !task_tracker.is_finished() {
+                report_workflow_progress(
+                    &agent_id,
+                    &workflow_instance_id,
+                    &task_tracker,
+                    &mut reported_completed,
+                )
+                .await;
+                let task_id = if let Some(task_id) = task_tracker.get_next_task() {
+                    task_id
+                } else {
+                    debug!("All tasks busy - sleeping");
+                    sleep(WAIT_TASK_SLEEP_INTERVAL_MS).await;
+                    continue;
                 };
-                let mut task_tracker = ThreadSafeTaskTracker::from_workflow(&workflow)?;
-                if task_tracker.is_finished() {
-                    warn!(
-                        "Workflow {} doesn't have any tasks defined - skipping",
-                        workflow.name()
-                    );
-                    return Ok(());
+                let task = (&workflow).get_task(&task_id).expect(
+                    "Passed an incorrect task id to the workflow from the task mgr - this is a bug",
+                );
+                let task_execution_id = { id_gen.lock().await.next() };
+                let task_name = task.name().to_string();
+                if let Some(when) = task.when() {
+                    let should_run = Condition::parse(when)
+                        .and_then(|c| c.evaluate(&|dep| task_tracker.get_output(dep)));
+                    let status = match should_run {
+                        Ok(true) => None,
+                        Ok(false) => {
+                            info!("Condition '{when}' of task {task_id} does not hold - skipping");
+                            task_tracker.mark_skipped(&task_id)?;
+                            Some(RunStatus::SKIPPED)
+                        }
+                        Err(e) => {
+                            error!("Failed to evaluate condition of task {task_id}: {e}");
+                            task_tracker.mark_failed(&task_id)?;
+                            Some(RunStatus::FAILED)
+                        }
+                    };
+                    if let Some(status) = status {
+                        PrincipalAPI::TaskStatusUpdate(
+                            agent_id.clone(),
+                            task_id.clone(),
+                            task_execution_id.clone().into(),
+                            workflow_instance_id.clone().into(),
+                            status,
+                            None,
+                            false,
+                        )
+                        .send()
+                        .await?;
+                        continue;
+                    }
                 }
-                let mut read_handles = JoinSet::new();
-                while !task_tracker.is_finished() {
-                    let task_id = if let Some(task_id) = task_tracker.get_next_task() {
-                        task_id
+                // fan-in tasks are given the results of the matrix expansions they aggregate
+                let task = if task.matrix_results().is_empty() {
+                    task.clone()
+                } else {
+                    match task.with_matrix_results(&task_tracker.matrix_results(task)) {
+                        Ok(task) => task,
+                        Err(e) => {
+                            error!("Failed to pass matrix results to task {task_id}: {e}");
+                            task_tracker.mark_failed(&task_id)?;
+                            PrincipalAPI::TaskStatusUpdate(
+                                agent_id.clone(),
+                                task_id.clone(),
+                                task_execution_id.clone().into(),
+                                workflow_instance_id.clone().into(),
+                                RunStatus::FAILED,
+                                None,
+                                false,
+                            )
+                            .send()
+                            .await?;
+                            continue;
+                        }
+                    }
+                };
+                // cleanup tasks only start once every other task has finished, so the
+                // status of the workflow is already known
+                let task = if task.cleanup() {
+                    let status = if task_tracker.all_tasks_successful() {
+                        RunStatus::COMPLETED
                     } else {
-                        debug!("All tasks busy - sleeping");
-                        sleep(WAIT_TASK_SLEEP_INTERVAL_MS).await;
-                        continue;
+                        RunStatus::FAILED
                     };
-                    let task = (&workflow).get_task(&task_id).expect(
-                        "Passed an incorrect task id to the workflow from the task mgr - this is a bug",
+                    info!(
+                        "Running cleanup task {task_id} of workflow that finished {}",
+                        status.to_string()
                     );
-                    let task_execution_id = { name_gen_cl.lock().await.next() };
-                    let task_name = task.name().to_string();
-                    PrincipalAPI::TaskStatusUpdate(
+                    task.with_workflow_status(&status)
+                } else {
+                    task
+                };
+                PrincipalAPI::TaskStatusUpdate(
+                    agent_id.clone(),
+                    task_id.clone(),
+                    task_execution_id.clone().into(),
+                    workflow_instance_id.clone().into(),
+                    RunStatus::PENDING,
+                    None,
+                    false,
+                )
+                .send()
+                .await?;
+                let mut task_exe = loop {
+                    let task_exe_result = run_in_executor(
+                        task_permits.clone(),
+                        result_cache.clone(),
+                        secret_source.clone(),
+                        task_tracker.clone(),
                         agent_id.clone(),
                         task_id.clone(),
+                        task.clone(),
                         task_execution_id.clone(),
                         workflow_instance_id.clone(),
-                        RunStatus::PENDING,
+                        retry_budget.clone(),
+                        dry_run,
                     )
-                    .send()
-                    .await?;
-                    let mut task_exe = loop {
-                        let task_exe_result = run_in_executor(
-                            task_tracker.clone(),
-                            agent_id.clone(),
-                            task_id.clone(),
-                            task.clone(),
-                            task_execution_id.clone(),
-                            workflow_instance_id.clone(),
-                        )
-                        .await;
-                        match task_exe_result {
-                            Ok(task_exe) => break task_exe,
-                            Err(e) => match e {
-                                TaskManagerError::TooManyThreadsError => {
-                                    debug!("Max number of child threads reached - waiting..");
-                                    sleep(Duration::from_millis(1000)).await;
-                                    continue;
-                                }
-                                TaskManagerError::FailedTaskError(e) => {
-                                    error!("{}", e);
-                                    match task_tracker.mark_failed(&task_id) {
-                                        Ok(_) => {
-                                            error!(
-                                                "Marked {}->{} as failure",
-                                                task_id, task_execution_id
-                                            );
-                                        }
-                                        Err(e) => {
+                    .await;
+                    match task_exe_result {
+                        Ok(task_exe) => break task_exe,
+                        Err(e) => match e {
+                            TaskManagerError::TooManyThreadsError => {
+                                debug!("Max number of child threads reached - waiting..");
+                                sleep(Duration::from_millis(1000)).await;
+                                continue;
+                            }
+                            TaskManagerError::FailedTaskError(e) => {
+                                error!("{}", e);
+                                match task_tracker.mark_failed(&task_id) {
+                                    Ok(_) => {
+                                        error!(
+                                            "Marked {}->{} as failure",
+                                            task_id, task_execution_id
+                                        );
+                                    }
+                                    Err(e) => {
+                                        error!("Error marking task as failure - aborting workflow");
+                                        if PrincipalAPI::WorkflowStatusUpdate(
+                                            agent_id.clone(),
+                                            workflow_id.clone(),
+                                            workflow_instance_id.clone().into(),
+                                            RunStatus::CRASHED,
+                                        )
+                                        .send()
+                                        .await
+                                        .is_err()
+                                        {
                                             error!(
-                                                "Error marking task as failure - aborting workflow"
-                                            );
-                                            if PrincipalAPI::WorkflowStatusUpdate(
-                                                agent_id.clone(),
-                                                workflow_id.clone(),
-                                                workflow_instance_id.clone(),
-                                                RunStatus::CRASHED,
+                                                "Failed to send status update of CRASHED to principal for: {workflow_id}/{workflow_instance_id}"
                                             )
-                                            .send()
-                                            .await
-                                            .is_err()
-                                            {
-                                                error!(
-                                                    "Failed to send status update of CRASHED to principal for: {workflow_id}/{workflow_instance_id}"
-                                                )
-                                            };
-                                            return Err(e);
-                                        }
+                                        };
+                                        return Err(e);
                                     }
                                 }
-                            },
-                        };
+                            }
+                        },
                     };
-                    // need to spawn the reading of the logs of the run task in order to free this thread
-                    // to go back to looking at the queue
-                    let mut logs_pub = LogsPublisher::new(
-                        workflow.id().clone(),
-                        workflow.name().clone(),
-                        workflow_instance_id.clone(),
-                    )
-                    .await?;
-                    read_handles.spawn(async move {
-                        let mut task_logger = logs_pub
-                            .get_task_logger(&task_name, &task_execution_id)
-                            .await;
-                        while let Some(msg) = task_exe.wait_stdout().await {
-                            let log_msg = format!("STDOUT {msg}");
-                            info!("{}", &log_msg);
-                            task_logger.info(&log_msg).await;
-                        }
-                        while let Some(msg) = task_exe.wait_stderr().await {
-                            let log_msg = format!("STDERR {msg}");
-                            error!("{}", &log_msg);
-                            task_logger.error(&log_msg).await;
-                        }
-                        info!("Ended task {task_execution_id} ({task_name})");
-                    });
-                }
-                read_handles.join_all().await;
-                {
-                    let mut counter = workflow_counter.lock().await;
-                    debug!("Decrementing workflow counter (currently {})", *counter);
-                    *counter -= 1;
-                }
-                info!(
-                    "All tasks for workflow {}->{} complete",
+                };
+                // need to spawn the reading of the logs of the run task in order to free this thread
+                // to go back to looking at the queue
+                let mut logs_pub = LogsPublisher::new(
+                    workflow.id().clone(),
+                    workflow.name().clone(),
+                    workflow_instance_id.clone(),
+                )
+                .await?;
+                // patterns are checked when the workflow is validated
+                let output_levels = task.output_log_levels().unwrap_or_default();
+                // the task is counted as running until its output ends
+                let running = running_tasks.track(&workflow_id, &task_id);
+                read_handles.spawn(async move {
+                    let _running = running;
+                    let mut task_logger = logs_pub
+                        .get_task_logger(&task_name, &task_execution_id)
+                        .await;
+                    while let Some(msg) = task_exe.wait_stdout().await {
+                        let level = output_levels.stdout_level(&msg).into();
+                        let log_msg = format!("STDOUT {msg}");
+                        log!(level, "{}", &log_msg);
+                        task_logger.log(level, &log_msg).await;
+                    }
+                    while let Some(msg) = task_exe.wait_stderr().await {
+                        let level = output_levels.stderr_level(&msg).into();
+                        let log_msg = format!("STDERR {msg}");
+                        log!(level, "{}", &log_msg);
+                        task_logger.log(level, &log_msg).await;
+                    }
+                    info!("Ended task {task_execution_id} ({task_name})");
+                });
+            }
+            read_handles.join_all().await;
+            report_workflow_progress(
+                &agent_id,
+                &workflow_instance_id,
+                &task_tracker,
+                &mut reported_completed,
+            )
+            .await;
+            info!(
+                "All tasks for workflow {}->{} complete",
+                workflow.name(),
+                workflow_instance_id,
+            );
+            let failed_cleanup = task_tracker.failed_cleanup_tasks();
+            if !failed_cleanup.is_empty() {
+                warn!(
+                    "Cleanup task(s) {} of workflow {}->{} failed - the workflow status is left as it was",
+                    failed_cleanup.join(", "),
                     workflow.name(),
                     workflow_instance_id,
                 );
-                match task_tracker.all_tasks_successful() {
-                    true => {
-                        info!(
-                            "Workflow {}->{} completed successfully",
-                            workflow.name(),
-                            workflow_instance_id,
-                        );
-                        if PrincipalAPI::WorkflowStatusUpdate(
+            }
+            match task_tracker.all_tasks_successful() {
+                true => {
+                    info!(
+                        "Workflow {}->{} completed successfully",
+                        workflow.name(),
+                        workflow_instance_id,
+                    );
+                    let outputs =
+                        workflow.collect_outputs(|task_id| task_tracker.get_output(task_id));
+                    // outputs are sent ahead of the COMPLETED status so that a run queued
+                    // as soon as this one completes already sees them
+                    if !dry_run
+                        && !outputs.is_empty()
+                        && PrincipalAPI::WorkflowOutputs(
                             agent_id.clone(),
                             workflow_id.clone(),
-                            workflow_instance_id.clone(),
-                            RunStatus::COMPLETED,
+                            workflow_instance_id.clone().into(),
+                            outputs,
                         )
                         .send()
                         .await
                         .is_err()
-                        {
-                            error!(
-                                "Failed to send status update of COMPLETED to principal for: {workflow_id}/{workflow_instance_id}"
-                            )
-                        };
-                        Ok(())
-                    }
-                    false => {
-                        warn!(
-                            "Workflow {}->{} completed with failures",
-                            workflow.name(),
-                            workflow_instance_id,
-                        );
-                        if PrincipalAPI::WorkflowStatusUpdate(
-                            agent_id.clone(),
-                            workflow_id.clone(),
-                            workflow_instance_id.clone(),
-                            RunStatus::FAILED,
+                    {
+                        error!(
+                            "Failed to send the outputs of {workflow_id}/{workflow_instance_id} to principal"
                         )
-                        .send()
-                        .await
-                        .is_err()
-                        {
-                            error!(
-                                "Failed to send status update of FAILED to principal for: {workflow_id}/{workflow_instance_id}"
-                            )
-                        };
-                        Ok(())
-                    }
+                    };
+                    if PrincipalAPI::WorkflowStatusUpdate(
+                        agent_id.clone(),
+                        workflow_id.clone(),
+                        workflow_instance_id.clone().into(),
+                        RunStatus::COMPLETED,
+                    )
+                    .send()
+                    .await
+                    .is_err()
+                    {
+                        error!(
+                            "Failed to send status update of COMPLETED to principal for: {workflow_id}/{workflow_instance_id}"
+                        )
+                    };
+                    Ok(())
                 }
-            });
-        }
+                false => {
+                    warn!(
+                        "Workflow {}->{} completed with failures",
+                        workflow.name(),
+                        workflow_instance_id,
+                    );
+                    if PrincipalAPI::WorkflowStatusUpdate(
+                        agent_id.clone(),
+                        workflow_id.clone(),
+                        workflow_instance_id.clone().into(),
+                        RunStatus::FAILED,
+                    )
+                    .send()
+                    .await
+                    .is_err()
+                    {
+                        error!(
+                            "Failed to send status update of FAILED to principal for: {workflow_id}/{workflow_instance_id}"
+                        )
+                    };
+                    Ok(())
+                }
+            }
+        });
+    }
+}
+
+/// Tells the principal a task is running
+async fn report_task_running(
+    agent_id: &str,
+    task_id: &str,
+    task_execution_id: &str,
+    workflow_instance_id: &str,
+) {
+    if PrincipalAPI::TaskStatusUpdate(
+        agent_id.to_string(),
+        task_id.to_string(),
+        task_execution_id.into(),
+        workflow_instance_id.into(),
+        RunStatus::RUNNING,
+        None,
+        false,
+    )
+    .send()
+    .await
+    .is_err()
+    {
+        error!(
+            "Failed to send status update of RUNNING to principal for task: {task_id}/{task_execution_id}"
+        )
+    };
+}
+
+/// Tells the principal how many tasks of a workflow run have finished when more have
+/// finished since the count it was last told
+async fn report_workflow_progress(
+    agent_id: &str,
+    workflow_instance_id: &str,
+    task_tracker: &ThreadSafeTaskTracker,
+    reported_completed: &mut usize,
+) {
+    let (tasks_completed, tasks_total) = task_tracker.progress();
+    if tasks_completed == *reported_completed {
+        return;
+    }
+    *reported_completed = tasks_completed;
+    if let Err(e) = PrincipalAPI::WorkflowProgress(
+        agent_id.to_string(),
+        workflow_instance_id.into(),
+        tasks_completed,
+        tasks_total,
+    )
+    .send()
+    .await
+    {
+        warn!("Failed to send progress of {workflow_instance_id} to principal: {e}");
     }
 }
 
 /// This function takes a given task and runs it in the relevant executor depending on the type
-/// of member of the Task enum it pertains to.
+/// of member of the Task enum it pertains to. Returns a `TooManyThreadsError` if the agent
+/// has no free task slots so that the caller can wait and retry.
+#[allow(clippy::too_many_arguments)]
 async fn run_in_executor(
+    task_permits: Arc<Semaphore>,
+    result_cache: TaskResultCache,
+    secret_source: Option<SecretSource>,
     mut task_tracker: ThreadSafeTaskTracker,
     agent_id: String,
     task_id: String,
     task: Task,
     task_execution_id: String,
     workflow_instance_id: String,
+    retry_budget: RetryBudget,
+    dry_run: bool,
 ) -> Result<TaskExecutionHandle, TaskManagerError> {
+    let permit = match task_permits.try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => return Err(TaskManagerError::TooManyThreadsError),
+    };
     let (handle, stdout_rx, stderr_rx) = {
         let (stdout_tx, stdout_rx) = mpsc::channel(32);
         let (stderr_tx, stderr_rx) = mpsc::channel(32);
-        let executable_task = task.get_exe_task();
         let task_exe_id_clone = task_execution_id.clone();
         let workflow_ins_id_clone = workflow_instance_id.clone();
+        let (progress_tx, mut progress_rx) = mpsc::channel::<TaskProgress>(8);
+        let progress_agent_id = agent_id.clone();
+        let progress_task_exe_id = task_execution_id.clone();
+        tokio::spawn(async move {
+            while let Some(progress) = progress_rx.recv().await {
+                if PrincipalAPI::TaskProgress(
+                    progress_agent_id.clone(),
+                    progress_task_exe_id.clone().into(),
+                    progress.percent,
+                    progress.message,
+                )
+                .send()
+                .await
+                .is_err()
+                {
+                    warn!(
+                        "Failed to send progress update to principal for task: {progress_task_exe_id}"
+                    )
+                }
+            }
+        });
         let handle = tokio::spawn(async move {
+            // hold the slot until the task has finished executing, or until it is ready if
+            // it has a readiness check
+            let mut permit = Some(permit);
             info!("Spawning task {task_exe_id_clone}");
-            if PrincipalAPI::TaskStatusUpdate(
-                agent_id.clone(),
-                task_id.clone(),
-                task_execution_id.clone(),
-                workflow_ins_id_clone.clone(),
-                RunStatus::RUNNING,
-            )
-            .send()
-            .await
-            .is_err()
-            {
-                error!(
-                    "Failed to send status update of RUNNING to principal for task: {task_id}/{task_execution_id}"
+            // tasks with a readiness check only count as running once it passes
+            let readiness = task.readiness().filter(|_| !dry_run);
+            if readiness.is_none() {
+                report_task_running(
+                    &agent_id,
+                    &task_id,
+                    &task_execution_id,
+                    &workflow_ins_id_clone,
+                )
+                .await;
+            }
+            let max_output_bytes = get_cdktr_setting!(CDKTR_AGENT_MAX_TASK_OUTPUT_BYTES, usize);
+            // the command only starts once the files it consumes are downloaded and its
+            // wait_for condition holds
+            let consumed = if dry_run {
+                Ok(())
+            } else {
+                match download_artifacts(
+                    &PrincipalTransport,
+                    &workflow_ins_id_clone,
+                    task.working_directory(),
+                    task.consumes(),
                 )
+                .await
+                {
+                    Ok(()) => match task.wait_for() {
+                        Some(wait_for) => wait_for_condition(&task_id, wait_for).await,
+                        None => Ok(()),
+                    },
+                    Err(e) => Err(e.to_string()),
+                }
             };
-            let flow_result = executable_task.run(stdout_tx, stderr_tx).await;
+            let TaskRun {
+                result: flow_result,
+                output,
+                truncated,
+            } = match consumed {
+                Ok(()) => {
+                    let log_line = readiness.and_then(LogLineWatch::for_readiness);
+                    let stdout_tx = match &log_line {
+                        Some(watch) => watch.tap(stdout_tx),
+                        None => stdout_tx,
+                    };
+                    let (task_ref, result_cache) = (&task, &result_cache);
+                    let secret_source = secret_source.as_ref();
+                    // the output channels close once the closure is dropped after the last attempt
+                    let execution =
+                        execute_with_retries(&task_id, task.retries(), &retry_budget, move || {
+                            execute_task(
+                                task_ref,
+                                result_cache,
+                                secret_source,
+                                max_output_bytes,
+                                dry_run,
+                                Some(progress_tx.clone()),
+                                stdout_tx.clone(),
+                                stderr_tx.clone(),
+                            )
+                        });
+                    match readiness {
+                        None => execution.await,
+                        Some(readiness) => {
+                            tokio::pin!(execution);
+                            tokio::select! {
+                                run = &mut execution => run,
+                                ready = wait_until_ready(readiness, log_line.as_ref()) => match ready {
+                                    Ok(()) => {
+                                        info!("Task {task_id}->{task_execution_id} is ready");
+                                        // a service keeps running alongside its dependents, so
+                                        // it gives up its slot for them to run in
+                                        drop(permit.take());
+                                        report_task_running(
+                                            &agent_id,
+                                            &task_id,
+                                            &task_execution_id,
+                                            &workflow_ins_id_clone,
+                                        )
+                                        .await;
+                                        if let Err(e) = task_tracker.mark_ready(&task_id) {
+                                            error!("Failed to start the tasks depending on {task_id}: {e}");
+                                        }
+                                        execution.await
+                                    }
+                                    // dropping the execution kills the task process
+                                    Err(e) => TaskRun::crashed(e),
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => TaskRun::crashed(e),
+            };
+            // a task only succeeds once the files it produces are with the principal
+            let flow_result = match flow_result {
+                FlowExecutionResult::SUCCESS if !dry_run => {
+                    match upload_artifacts(
+                        &PrincipalTransport,
+                        &workflow_ins_id_clone,
+                        task.working_directory(),
+                        task.produces(),
+                    )
+                    .await
+                    {
+                        Ok(()) => FlowExecutionResult::SUCCESS,
+                        Err(e) => FlowExecutionResult::CRASHED(e.to_string()),
+                    }
+                }
+                flow_result => flow_result,
+            };
+            // listed before the final status so they are in the result once it is finished
+            if !dry_run
+                && !task.artifacts().is_empty()
+                && let Err(e) = report_artifacts(
+                    &PrincipalTransport,
+                    &agent_id,
+                    &task_execution_id,
+                    task.working_directory(),
+                    task.artifacts(),
+                )
+                .await
+            {
+                warn!("Failed to report artifacts of task {task_id}->{task_execution_id}: {e}");
+            }
+            if truncated {
+                warn!(
+                    "Output of task {}->{} was truncated after {} bytes",
+                    &task_id, &task_execution_id, max_output_bytes
+                );
+            }
             match flow_result {
                 FlowExecutionResult::SUCCESS => {
                     info!(
@@ -405,9 +1037,11 @@ async fn run_in_executor(
                     if PrincipalAPI::TaskStatusUpdate(
                         agent_id.clone(),
                         task_id.clone(),
-                        task_execution_id.clone(),
-                        workflow_ins_id_clone.clone(),
+                        task_execution_id.clone().into(),
+                        workflow_ins_id_clone.clone().into(),
                         RunStatus::COMPLETED,
+                        Some(0),
+                        truncated,
                     )
                     .send()
                     .await
@@ -417,15 +1051,18 @@ async fn run_in_executor(
                             "Failed to send status update of COMPLETED to principal for task: {task_id}/{task_execution_id}"
                         )
                     };
+                    if let Some(output) = output {
+                        task_tracker.set_output(&task_id, output);
+                    }
                     match task_tracker.mark_success(&task_id) {
                         Ok(_) => Ok(()),
                         Err(e) => Err(TaskManagerError::FailedTaskError(format!(
                             "Failed to mark task as success. Error: {}",
-                            e.to_string()
+                            e
                         ))),
                     }
                 }
-                FlowExecutionResult::FAILURE(err_msg) => {
+                FlowExecutionResult::FAILURE(err_msg, exit_code) => {
                     error!(
                         "Task {}->{} experienced a critical failure. Error: {}",
                         &task_id, &task_execution_id, err_msg
@@ -433,9 +1070,11 @@ async fn run_in_executor(
                     if PrincipalAPI::TaskStatusUpdate(
                         agent_id.clone(),
                         task_id.clone(),
-                        task_execution_id.clone(),
-                        workflow_ins_id_clone.clone(),
+                        task_execution_id.clone().into(),
+                        workflow_ins_id_clone.clone().into(),
                         RunStatus::FAILED,
+                        exit_code,
+                        truncated,
                     )
                     .send()
                     .await
@@ -445,6 +1084,9 @@ async fn run_in_executor(
                             "Failed to send status update of FAILED to principal for task: {task_id}/{task_execution_id}"
                         )
                     };
+                    if let Some(exit_code) = exit_code {
+                        task_tracker.set_exit_code(&task_id, exit_code);
+                    }
                     match task_tracker.mark_failed(&task_id) {
                         Ok(_) => {
                             warn!("Marked {}->{} as failure", &task_id, &task_execution_id);
@@ -452,7 +1094,7 @@ async fn run_in_executor(
                         }
                         Err(e) => Err(TaskManagerError::FailedTaskError(format!(
                             "Failed to mark task as success. Error: {}",
-                            e.to_string()
+                            e
                         ))),
                     }
                 }
@@ -464,9 +1106,11 @@ async fn run_in_executor(
                     if PrincipalAPI::TaskStatusUpdate(
                         agent_id.clone(),
                         task_id.clone(),
-                        task_execution_id.clone(),
-                        workflow_ins_id_clone.clone(),
+                        task_execution_id.clone().into(),
+                        workflow_ins_id_clone.clone().into(),
                         RunStatus::FAILED,
+                        None,
+                        truncated,
                     )
                     .send()
                     .await
@@ -483,7 +1127,7 @@ async fn run_in_executor(
                         }
                         Err(e) => Err(TaskManagerError::FailedTaskError(format!(
                             "Failed to mark task as success. Error: {}",
-                            e.to_string()
+                            e
                         ))),
                     }
                 }
@@ -496,6 +1140,853 @@ async fn run_in_executor(
     Ok(TaskExecutionHandle::new(handle, stdout_rx, stderr_rx))
 }
 
+/// Outcome of executing a single task
+#[derive(Debug)]
+struct TaskRun {
+    result: FlowExecutionResult,
+    /// last non-empty line of stdout
+    output: Option<String>,
+    /// whether output was dropped for exceeding the output limit
+    truncated: bool,
+}
+
+impl TaskRun {
+    fn crashed(msg: String) -> Self {
+        Self {
+            result: FlowExecutionResult::CRASHED(msg),
+            output: None,
+            truncated: false,
+        }
+    }
+}
+
+/// Caps the combined stdout and stderr forwarded from a task. Once a line would take the
+/// output over the limit, it and every line after it are dropped and a single marker
+/// is sent in their place. The task itself keeps running
+#[derive(Debug, Clone)]
+struct OutputLimit {
+    max_bytes: usize,
+    forwarded: Arc<AtomicUsize>,
+    truncated: Arc<AtomicBool>,
+}
+
+impl OutputLimit {
+    /// `max_bytes` of 0 means no limit
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            forwarded: Arc::new(AtomicUsize::new(0)),
+            truncated: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns the line if it is within the limit, the truncation marker if this line is
+    /// the first past the limit, or `None` if the output has already been truncated
+    fn admit(&self, line: String) -> Option<Admitted> {
+        if self.max_bytes == 0 {
+            return Some(Admitted::Line(line));
+        }
+        if self.truncated.load(Ordering::SeqCst) {
+            return None;
+        }
+        let within_limit = self
+            .forwarded
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                (used + line.len() <= self.max_bytes).then_some(used + line.len())
+            })
+            .is_ok();
+        if within_limit {
+            Some(Admitted::Line(line))
+        } else if !self.truncated.swap(true, Ordering::SeqCst) {
+            Some(Admitted::Marker(format!(
+                "[output truncated after {} bytes]",
+                self.max_bytes
+            )))
+        } else {
+            None
+        }
+    }
+
+    fn truncated(&self) -> bool {
+        self.truncated.load(Ordering::SeqCst)
+    }
+}
+
+/// A line forwarded by an `OutputLimit`
+#[derive(Debug, PartialEq)]
+enum Admitted {
+    /// A line of output of the task
+    Line(String),
+    /// The marker sent in place of the output past the limit
+    Marker(String),
+}
+
+/// Resolves the secrets of a task and runs it, redacting the secret values and the matches
+/// of its `redact` patterns from its output and capping the output at `max_output_bytes`.
+/// Progress lines written to stdout are sent on `progress_tx` rather than as output. On a
+/// dry run the command the task would run is sent as its only line of output instead,
+/// without resolving its secrets
+#[allow(clippy::too_many_arguments)]
+async fn execute_task(
+    task: &Task,
+    result_cache: &TaskResultCache,
+    secret_source: Option<&SecretSource>,
+    max_output_bytes: usize,
+    dry_run: bool,
+    progress_tx: Option<mpsc::Sender<TaskProgress>>,
+    stdout_tx: mpsc::Sender<String>,
+    stderr_tx: mpsc::Sender<String>,
+) -> TaskRun {
+    if dry_run {
+        let _ = stdout_tx
+            .send(format!(
+                "DRY RUN: would run {}",
+                task.get_exe_task().describe()
+            ))
+            .await;
+        return TaskRun {
+            result: FlowExecutionResult::SUCCESS,
+            output: None,
+            truncated: false,
+        };
+    }
+    let (task, secrets) = match secret_source {
+        _ if task.secret_refs().is_empty() => (task.clone(), Vec::new()),
+        Some(source) => match source.resolve(task).await {
+            Ok(resolved) => resolved,
+            Err(e) => return TaskRun::crashed(e.to_string()),
+        },
+        None => {
+            return TaskRun::crashed(
+                "Task uses secrets but the agent has no valid secrets source. Check CDKTR_AGENT_SECRETS_SOURCE"
+                    .to_string(),
+            );
+        }
+    };
+    // patterns are checked when the workflow is validated
+    let redaction = match task.redact_patterns() {
+        Ok(patterns) => Redaction::new(secrets, patterns),
+        Err(e) => return TaskRun::crashed(e.to_string()),
+    };
+    let limit = OutputLimit::new(max_output_bytes);
+    let (task_stdout_tx, task_stdout_rx) = mpsc::channel(32);
+    let (task_stderr_tx, task_stderr_rx) = mpsc::channel(32);
+    let output = tokio::spawn(forward_output(
+        task_stdout_rx,
+        stdout_tx,
+        redaction.clone(),
+        limit.clone(),
+        progress_tx.map(ProgressReporter::new),
+    ));
+    let errors = tokio::spawn(forward_output(
+        task_stderr_rx,
+        stderr_tx,
+        redaction,
+        limit.clone(),
+        None,
+    ));
+    let result = if task.cache() {
+        result_cache
+            .run(&task, task_stdout_tx, task_stderr_tx)
+            .await
+    } else {
+        task.get_exe_task()
+            .run(task_stdout_tx, task_stderr_tx)
+            .await
+    };
+    let _ = errors.await;
+    TaskRun {
+        result,
+        output: output.await.unwrap_or_default(),
+        truncated: limit.truncated(),
+    }
+}
+
+/// Forwards the output of a task with any secret values and pattern matches redacted and
+/// within the output limit, returning the last non-empty line. For stdout this is the
+/// output of the task that the conditions of downstream tasks can reference. Progress
+/// lines are passed to `progress` instead when it is given
+async fn forward_output(
+    mut rx: mpsc::Receiver<String>,
+    tx: mpsc::Sender<String>,
+    redaction: Redaction,
+    limit: OutputLimit,
+    mut progress: Option<ProgressReporter>,
+) -> Option<String> {
+    let mut last_line = None;
+    while let Some(line) = rx.recv().await {
+        if let Some(reporter) = progress.as_mut()
+            && let Some(update) = parse_progress_line(&line)
+        {
+            reporter.report(update);
+            continue;
+        }
+        let line = redaction.apply(line);
+        // keep draining once truncated so the executor isn't blocked
+        let line = match limit.admit(line) {
+            Some(Admitted::Line(line)) => {
+                if !line.trim().is_empty() {
+                    last_line = Some(line.clone());
+                }
+                line
+            }
+            // the marker isn't output of the task so conditions never see it
+            Some(Admitted::Marker(marker)) => marker,
+            None => continue,
+        };
+        // keep reading even if the consumer has gone so the executor isn't blocked
+        let _ = tx.send(line).await;
+    }
+    last_line
+}
+
 // TODO: fix the broken pipe error
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_workflow_slot_released_on_panic() {
+        let workflow_counter = Arc::new(AtomicUsize::new(0));
+        let slot = WorkflowSlotGuard::acquire(workflow_counter.clone(), Arc::new(Notify::new()));
+        assert_eq!(workflow_counter.load(Ordering::SeqCst), 1);
+        let handle = tokio::spawn(async move {
+            let _slot = slot;
+            panic!("workflow thread panicked");
+        });
+        assert!(handle.await.unwrap_err().is_panic());
+        assert_eq!(workflow_counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_workflow_slot_released_on_early_return() {
+        let workflow_counter = Arc::new(AtomicUsize::new(0));
+        let slot = WorkflowSlotGuard::acquire(workflow_counter.clone(), Arc::new(Notify::new()));
+        let handle: JoinHandle<Result<(), GenericError>> = tokio::spawn(async move {
+            let _slot = slot;
+            Err(GenericError::RuntimeError("workflow failed".to_string()))?;
+            Ok(())
+        });
+        assert!(handle.await.unwrap().is_err());
+        assert_eq!(workflow_counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_push_port_in_use() {
+        let listener = std::net::TcpListener::bind("0.0.0.0:0")
+            .expect("failed to bind an ephemeral port to hold");
+        let port = listener.local_addr().unwrap().port();
+        // SAFETY: no other test reads the push port of the agent
+        unsafe { std::env::set_var("CDKTR_AGENT_PUSH_PORT", port.to_string()) };
+        let mut tm = TaskManager::new("agent".to_string(), 1).await;
+        tm.dispatch_mode = DispatchMode::Push;
+        match tm.start().await {
+            Err(GenericError::RuntimeError(msg)) => {
+                assert!(msg.contains("port already in use"), "{msg}")
+            }
+            other => panic!("Expected port in use error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exclusive_workflow_takes_every_slot() {
+        let tm = TaskManager::new("agent".to_string(), 3).await;
+        let exclusive = cdktr_workflow::Workflow::new(
+            "fake/path/etl.yml".to_string(),
+            r#"
+name: ETL
+exclusive_agent: true
+tasks:
+  task1:
+    name: Task 1
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["hello"]
+"#,
+        )
+        .unwrap();
+        let slot = tm.workflow_slot(&exclusive);
+        assert_eq!(tm.workflow_counter.load(Ordering::SeqCst), 3);
+        let push = AgentAPI::Run(r#"{"name":"etl","tasks":{}}"#.to_string());
+        assert!(matches!(
+            tm.handle_agent_command(ZmqMessage::from(push.to_string()), usize::MAX),
+            ClientResponseMessage::Retryable(_)
+        ));
+        drop(slot);
+        assert_eq!(tm.workflow_counter.load(Ordering::SeqCst), 0);
+        let _slot = tm.workflow_slot(&exclusive.with_dry_run(true));
+        assert_eq!(tm.workflow_counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stopped_agent_finishes_running_workflows_then_exits() {
+        let mut tm = TaskManager::new("agent".to_string(), 2).await;
+        let slot = WorkflowSlotGuard::acquire(tm.workflow_counter.clone(), tm.slot_freed.clone());
+        tm.principal_client.handle_registration_response(
+            &ClientResponseMessage::SuccessWithPayload(AgentAPI::Shutdown.to_string()),
+        );
+        let workflow_loop = tokio::spawn(async move { tm.workflow_execution_loop().await });
+
+        // the running workflow keeps the agent going
+        sleep(WAIT_TASK_SLEEP_INTERVAL_MS * 2).await;
+        assert!(!workflow_loop.is_finished());
+        drop(slot);
+        tokio::time::timeout(Duration::from_secs(5), workflow_loop)
+            .await
+            .expect("agent did not exit once its workflows finished")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetches_again_soon_after_slot_frees() {
+        let tm = TaskManager::new("agent".to_string(), 1).await;
+        let slot = WorkflowSlotGuard::acquire(tm.workflow_counter.clone(), tm.slot_freed.clone());
+        let free_after = Duration::from_millis(100);
+        let started = tokio::time::Instant::now();
+        tokio::join!(tm.wait_for_free_slot(), async {
+            sleep(free_after).await;
+            drop(slot);
+        });
+        let pickup_latency = started.elapsed() - free_after;
+        assert!(
+            pickup_latency < WAIT_TASK_SLEEP_INTERVAL_MS / 2,
+            "took {pickup_latency:?} to fetch again after the slot freed"
+        );
+        assert!(pickup_latency >= tm.min_poll_interval);
+    }
+
+    #[tokio::test]
+    async fn test_pushed_workflow_refused_when_full() {
+        let tm = TaskManager::new("agent".to_string(), 1).await;
+        let _slot = WorkflowSlotGuard::acquire(tm.workflow_counter.clone(), tm.slot_freed.clone());
+        let push = AgentAPI::Run(r#"{"name":"etl","tasks":{}}"#.to_string());
+        assert!(matches!(
+            tm.handle_agent_command(ZmqMessage::from(push.to_string()), usize::MAX),
+            ClientResponseMessage::Retryable(_)
+        ));
+        assert!(matches!(
+            tm.handle_agent_command(
+                ZmqMessage::from(AgentAPI::SetDrain(true).to_string()),
+                usize::MAX
+            ),
+            ClientResponseMessage::ClientError(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pushed_message_over_limit_rejected() {
+        let port = crate::testing::free_port();
+        let mut tm = TaskManager::new("agent".to_string(), 1).await;
+        let push_loop = tokio::spawn(async move { tm.workflow_push_loop(port).await });
+        sleep(Duration::from_millis(100)).await;
+
+        let max_bytes = get_cdktr_setting!(CDKTR_MAX_MESSAGE_BYTES, usize);
+        let push = AgentAPI::Run("x".repeat(max_bytes + 1));
+        let resp = cdktr_core::zmq_helpers::send_recv_with_timeout(
+            get_server_tcp_uri("127.0.0.1", port),
+            ZmqMessage::from(push.to_string()),
+            Duration::from_secs(10),
+        )
+        .await
+        .expect("agent should respond to oversized pushes");
+        assert_eq!(
+            ClientResponseMessage::from(resp),
+            ClientResponseMessage::ClientError("message too large".to_string())
+        );
+        push_loop.abort();
+    }
+
+    #[tokio::test]
+    async fn test_secret_in_env_but_redacted_from_output() {
+        let unique = format!(
+            "cdktr-secrets-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        );
+        let secrets_file = std::env::temp_dir().join(format!("{unique}.env"));
+        let env_dump = std::env::temp_dir().join(format!("{unique}.out"));
+        std::fs::write(&secrets_file, "API_KEY=s3cr3t-value\n").unwrap();
+        let task: Task = serde_json::from_value(serde_json::json!({
+            "name": "uses secret",
+            "description": null,
+            "depends": null,
+            "matrix": null,
+            "secrets": ["API_KEY"],
+            "config": {
+                "Subprocess": {
+                    "cmd": "sh",
+                    "args": ["-c", format!(
+                        "printenv API_KEY > {}; echo \"key is $API_KEY\"; echo \"bad ${{secret.API_KEY}}\" >&2",
+                        env_dump.display()
+                    )],
+                    "run_as_user": null
+                }
+            }
+        }))
+        .unwrap();
+
+        let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+        let (stderr_tx, mut stderr_rx) = mpsc::channel(32);
+        let run = execute_task(
+            &task,
+            &TaskResultCache::new(Duration::from_secs(60), 0),
+            Some(&SecretSource::EnvFile(secrets_file.clone())),
+            0,
+            false,
+            None,
+            stdout_tx,
+            stderr_tx,
+        )
+        .await;
+        let mut logs = Vec::new();
+        while let Some(line) = stdout_rx.recv().await {
+            logs.push(line);
+        }
+        while let Some(line) = stderr_rx.recv().await {
+            logs.push(line);
+        }
+
+        assert!(matches!(run.result, FlowExecutionResult::SUCCESS));
+        assert_eq!(
+            std::fs::read_to_string(&env_dump).unwrap().trim(),
+            "s3cr3t-value"
+        );
+        assert_eq!(logs, vec!["key is ***", "bad ***"]);
+        assert_eq!(run.output, Some("key is ***".to_string()));
+        std::fs::remove_file(&secrets_file).unwrap();
+        std::fs::remove_file(&env_dump).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_redact_patterns_applied_before_logs_are_stored() {
+        use crate::log_manager::model::LogMessage;
+        use crate::store::{InMemoryStatusStore, StatusStore};
+
+        let task: Task = serde_json::from_value(serde_json::json!({
+            "name": "charges card",
+            "redact": [r"\b\d{4}-\d{4}-\d{4}-\d{4}\b", "token=[^&\\s]+"],
+            "config": {
+                "Subprocess": {
+                    "cmd": "sh",
+                    "args": ["-c", "echo \"charging 4111-1111-1111-1111\"; echo \"GET /pay?token=t0k&v=2\" >&2"],
+                    "run_as_user": null
+                }
+            }
+        }))
+        .unwrap();
+
+        let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+        let (stderr_tx, mut stderr_rx) = mpsc::channel(32);
+        let run = execute_task(
+            &task,
+            &TaskResultCache::new(Duration::from_secs(60), 0),
+            None,
+            0,
+            false,
+            None,
+            stdout_tx,
+            stderr_tx,
+        )
+        .await;
+        let mut streamed = Vec::new();
+        while let Some(line) = stdout_rx.recv().await {
+            streamed.push(line);
+        }
+        while let Some(line) = stderr_rx.recv().await {
+            streamed.push(line);
+        }
+        assert!(matches!(run.result, FlowExecutionResult::SUCCESS));
+        assert_eq!(streamed, vec!["charging ***", "GET /pay?***&v=2"]);
+        assert_eq!(run.output, Some("charging ***".to_string()));
+
+        // the lines the log publisher sends on are what is stored
+        let store = InMemoryStatusStore::new();
+        let logs = streamed
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                LogMessage::new(
+                    "wf".to_string(),
+                    "Workflow".to_string(),
+                    "wf-ins".to_string(),
+                    "charges card".to_string(),
+                    "task-ins".to_string(),
+                    i as u64,
+                    "INFO".to_string(),
+                    line.clone(),
+                )
+            })
+            .collect();
+        store.persist_logs(logs).await.unwrap();
+        let stored = store.read_logs(0, 10, None, None, None).await.unwrap();
+        assert!(
+            stored
+                .iter()
+                .all(|log| !log.payload.contains("4111") && !log.payload.contains("t0k"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_secrets_fail_without_source() {
+        let task: Task = serde_json::from_value(serde_json::json!({
+            "name": "uses secret",
+            "description": null,
+            "depends": null,
+            "matrix": null,
+            "secrets": ["API_KEY"],
+            "config": {"Subprocess": {"cmd": "true", "args": [], "run_as_user": null}}
+        }))
+        .unwrap();
+        let (stdout_tx, _stdout_rx) = mpsc::channel(32);
+        let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+        let run = execute_task(
+            &task,
+            &TaskResultCache::new(Duration::from_secs(60), 0),
+            None,
+            0,
+            false,
+            None,
+            stdout_tx,
+            stderr_tx,
+        )
+        .await;
+        assert!(matches!(run.result, FlowExecutionResult::CRASHED(_)));
+    }
+
+    #[tokio::test]
+    async fn test_output_truncated_at_limit() {
+        // ~700KB of output against a 1KB limit
+        let task: Task = serde_json::from_value(serde_json::json!({
+            "name": "chatty",
+            "description": null,
+            "depends": null,
+            "matrix": null,
+            "config": {
+                "Subprocess": {
+                    "cmd": "sh",
+                    "args": ["-c", "i=0; while [ $i -lt 20000 ]; do echo \"line $i of a very chatty task\"; i=$((i+1)); done; echo done >&2"],
+                    "run_as_user": null
+                }
+            }
+        }))
+        .unwrap();
+        let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+        let (stderr_tx, mut stderr_rx) = mpsc::channel(32);
+        let run = tokio::spawn(async move {
+            execute_task(
+                &task,
+                &TaskResultCache::new(Duration::from_secs(60), 0),
+                None,
+                1_024,
+                false,
+                None,
+                stdout_tx,
+                stderr_tx,
+            )
+            .await
+        });
+        let mut lines = Vec::new();
+        while let Some(line) = stdout_rx.recv().await {
+            lines.push(line);
+        }
+        while let Some(line) = stderr_rx.recv().await {
+            lines.push(line);
+        }
+        let run = run.await.unwrap();
+
+        // the process ran to completion even though its output was cut off
+        assert!(matches!(run.result, FlowExecutionResult::SUCCESS));
+        assert!(run.truncated);
+        // the output of the task is its last line within the limit
+        assert!(run.output.unwrap().contains("of a very chatty task"));
+        let marker = "[output truncated after 1024 bytes]";
+        assert_eq!(lines.last().unwrap(), marker);
+        assert_eq!(lines.iter().filter(|l| *l == marker).count(), 1);
+        let forwarded: usize = lines[..lines.len() - 1].iter().map(|l| l.len()).sum();
+        assert!(forwarded <= 1_024);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_logs_commands_in_dag_order() {
+        let marker = std::env::temp_dir().join(format!(
+            "cdktr-dry-run-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let yaml = format!(
+            r#"
+name: Dry run
+start_time: 2025-01-20T12:30:00+00:00
+params:
+  table:
+    type: string
+    default: orders
+tasks:
+  load:
+    name: Load
+    depends: ["extract"]
+    config: !Subprocess
+      cmd: touch
+      args: ["{marker}-load"]
+  extract:
+    name: Extract
+    config: !Subprocess
+      cmd: touch
+      args: ["{marker}-${{params.table}}"]
+      env:
+        STAGE: extract
+  archive:
+    name: Archive
+    depends: ["load"]
+    config: !Subprocess
+      cmd: touch
+      args: ["{marker}-archive"]
+"#,
+            marker = marker.display()
+        );
+        let workflow = cdktr_workflow::Workflow::new("dry-run.yml".to_string(), &yaml)
+            .unwrap()
+            .with_params(&std::collections::HashMap::new())
+            .unwrap()
+            .with_dry_run(true);
+        let mut task_tracker = ThreadSafeTaskTracker::from_workflow(&workflow).unwrap();
+        let mut logs = Vec::new();
+        while let Some(task_id) = task_tracker.get_next_task() {
+            let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+            let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+            let run = execute_task(
+                workflow.get_task(&task_id).unwrap(),
+                &TaskResultCache::new(Duration::from_secs(60), 0),
+                None,
+                0,
+                workflow.dry_run(),
+                None,
+                stdout_tx,
+                stderr_tx,
+            )
+            .await;
+            assert!(matches!(run.result, FlowExecutionResult::SUCCESS));
+            while let Some(line) = stdout_rx.recv().await {
+                logs.push(line);
+            }
+            task_tracker.mark_success(&task_id).unwrap();
+        }
+
+        let marker = marker.display();
+        assert_eq!(
+            logs,
+            vec![
+                format!("DRY RUN: would run touch {marker}-orders [env: STAGE=extract]"),
+                format!("DRY RUN: would run touch {marker}-load"),
+                format!("DRY RUN: would run touch {marker}-archive"),
+            ]
+        );
+        assert!(task_tracker.all_tasks_successful());
+        // nothing was spawned
+        for suffix in ["orders", "load", "archive"] {
+            assert!(!std::path::Path::new(&format!("{marker}-{suffix}")).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matrix_results_passed_to_aggregator() {
+        let yaml = r#"
+name: Map Reduce
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  count:
+    name: Count ${matrix.item}
+    matrix: ["3", "5", "7"]
+    config: !Subprocess
+      cmd: echo
+      args: ["${matrix.item}"]
+  total:
+    name: Total
+    depends: ["count"]
+    config: !Subprocess
+      cmd: sh
+      args:
+        - "-c"
+        - 'n=$(printf "%s" "$1" | grep -o "\"status\":\"COMPLETED\"" | wc -l); test "$n" -eq 3 && echo "received $n results"'
+        - sh
+        - "${matrix.results}"
+"#;
+        let workflow = cdktr_workflow::Workflow::new("map-reduce.yml".to_string(), yaml).unwrap();
+        let mut task_tracker = ThreadSafeTaskTracker::from_workflow(&workflow).unwrap();
+        let mut total_output = None;
+        while let Some(task_id) = task_tracker.get_next_task() {
+            let mut task = workflow.get_task(&task_id).unwrap().clone();
+            if !task.matrix_results().is_empty() {
+                let results = task_tracker.matrix_results(&task);
+                let counts: Vec<serde_json::Value> = serde_json::from_str(&results).unwrap();
+                assert_eq!(
+                    counts
+                        .iter()
+                        .map(|result| result["output"].as_str().unwrap())
+                        .collect::<Vec<_>>(),
+                    vec!["3", "5", "7"]
+                );
+                task = task.with_matrix_results(&results).unwrap();
+            }
+            let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+            let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+            let run = execute_task(
+                &task,
+                &TaskResultCache::new(Duration::from_secs(60), 0),
+                None,
+                0,
+                false,
+                None,
+                stdout_tx,
+                stderr_tx,
+            )
+            .await;
+            while stdout_rx.recv().await.is_some() {}
+            assert!(matches!(run.result, FlowExecutionResult::SUCCESS));
+            if task_id == "total" {
+                total_output = run.output.clone();
+            }
+            if let Some(output) = run.output {
+                task_tracker.set_output(&task_id, output);
+            }
+            task_tracker.mark_success(&task_id).unwrap();
+        }
+        assert!(task_tracker.is_finished());
+        assert_eq!(total_output, Some("received 3 results".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_dependent_waits_for_port_to_open() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("failed to find a free port")
+            .port();
+        let yaml = format!(
+            r#"
+name: Service
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  server:
+    name: Server
+    readiness:
+      tcp: 127.0.0.1:{port}
+      timeout_s: 10
+      interval_ms: 50
+    config: !Subprocess
+      cmd: sleep
+      args: ["2"]
+  client:
+    name: Client
+    depends: ["server"]
+    config: !Subprocess
+      cmd: echo
+      args: ["queried"]
+"#
+        );
+        let workflow = cdktr_workflow::Workflow::new("service.yml".to_string(), &yaml).unwrap();
+        let mut task_tracker = ThreadSafeTaskTracker::from_workflow(&workflow).unwrap();
+        assert_eq!(task_tracker.get_next_task(), Some("server".to_string()));
+        let server = workflow.get_task("server").unwrap().clone();
+        let server_run = tokio::spawn(async move {
+            let (stdout_tx, _stdout_rx) = mpsc::channel(32);
+            let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+            execute_task(
+                &server,
+                &TaskResultCache::new(Duration::from_secs(60), 0),
+                None,
+                0,
+                false,
+                None,
+                stdout_tx,
+                stderr_tx,
+            )
+            .await
+        });
+        // the service the task starts takes a while to listen
+        let started = std::time::Instant::now();
+        let listener = tokio::spawn(async move {
+            sleep(Duration::from_millis(300)).await;
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+                .await
+                .unwrap();
+            sleep(Duration::from_secs(5)).await;
+            drop(listener);
+        });
+        assert_eq!(task_tracker.get_next_task(), None);
+        let readiness = workflow.get_task("server").unwrap().readiness().unwrap();
+        wait_until_ready(readiness, None).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        task_tracker.mark_ready("server").unwrap();
+
+        // the client starts while the server is still running
+        assert_eq!(task_tracker.get_next_task(), Some("client".to_string()));
+        assert!(!server_run.is_finished());
+        task_tracker.mark_success("client").unwrap();
+        assert!(!task_tracker.is_finished());
+
+        let server_run = server_run.await.unwrap();
+        assert!(matches!(server_run.result, FlowExecutionResult::SUCCESS));
+        task_tracker.mark_success("server").unwrap();
+        assert_eq!(task_tracker.get_next_task(), None);
+        assert!(task_tracker.is_finished());
+        assert!(task_tracker.all_tasks_successful());
+        listener.abort();
+    }
+
+    #[tokio::test]
+    async fn test_progress_lines_forwarded_separately() {
+        let task: Task = serde_json::from_value(serde_json::json!({
+            "name": "long task",
+            "description": null,
+            "depends": null,
+            "matrix": null,
+            "config": {
+                "Subprocess": {
+                    "cmd": "sh",
+                    "args": ["-c", "echo starting; echo '::cdktr-progress::10::step 1 of 10'; echo '::cdktr-progress::20::step 2 of 10'; echo 'progress: 50%'; echo '::cdktr-progress::100::done'"],
+                    "run_as_user": null
+                }
+            }
+        }))
+        .unwrap();
+        let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+        let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+        let (progress_tx, mut progress_rx) = mpsc::channel(32);
+        let run = execute_task(
+            &task,
+            &TaskResultCache::new(Duration::from_secs(60), 0),
+            None,
+            0,
+            false,
+            Some(progress_tx),
+            stdout_tx,
+            stderr_tx,
+        )
+        .await;
+        let mut lines = Vec::new();
+        while let Some(line) = stdout_rx.recv().await {
+            lines.push(line);
+        }
+        let mut progress = Vec::new();
+        while let Some(update) = progress_rx.recv().await {
+            progress.push((update.percent, update.message));
+        }
+
+        assert!(matches!(run.result, FlowExecutionResult::SUCCESS));
+        // normal output is untouched, including lines that only look like progress
+        assert_eq!(lines, vec!["starting", "progress: 50%"]);
+        // the second update came too soon after the first so was dropped
+        assert_eq!(
+            progress,
+            vec![(10, "step 1 of 10".to_string()), (100, "done".to_string())]
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use cdktr_api::models::TaskProgress;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Tasks report progress by writing a line to stdout in the form
+/// `::cdktr-progress::<percent>::<message>`, e.g. `::cdktr-progress::30::step 3 of 10`
+pub const PROGRESS_LINE_PREFIX: &str = "::cdktr-progress::";
+
+/// Minimum time between progress updates forwarded for a task so a chatty task can't
+/// flood the principal. Completion (100%) is always forwarded
+const MIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Parses a progress line written by a task. Lines that aren't progress lines, or whose
+/// percent isn't a whole number from 0 to 100, are None and treated as normal output
+pub fn parse_progress_line(line: &str) -> Option<TaskProgress> {
+    let rest = line.trim_end().strip_prefix(PROGRESS_LINE_PREFIX)?;
+    let (percent, message) = rest.split_once("::").unwrap_or((rest, ""));
+    let percent = percent.trim().parse::<u8>().ok().filter(|p| *p <= 100)?;
+    Some(TaskProgress {
+        percent,
+        message: message.trim().to_string(),
+    })
+}
+
+/// Forwards the progress updates of a single task, dropping any that come in less than
+/// `MIN_PROGRESS_INTERVAL` after the last one forwarded
+#[derive(Debug)]
+pub struct ProgressReporter {
+    tx: mpsc::Sender<TaskProgress>,
+    min_interval: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl ProgressReporter {
+    pub fn new(tx: mpsc::Sender<TaskProgress>) -> Self {
+        Self::with_min_interval(tx, MIN_PROGRESS_INTERVAL)
+    }
+
+    pub fn with_min_interval(tx: mpsc::Sender<TaskProgress>, min_interval: Duration) -> Self {
+        Self {
+            tx,
+            min_interval,
+            last_sent: None,
+        }
+    }
+
+    /// Forwards the update unless it comes too soon after the last one. Never waits on
+    /// the receiver so the task's output isn't held up
+    pub fn report(&mut self, progress: TaskProgress) {
+        let now = Instant::now();
+        let too_soon = self
+            .last_sent
+            .is_some_and(|last| now.duration_since(last) < self.min_interval);
+        if too_soon && progress.percent < 100 {
+            return;
+        }
+        if self.tx.try_send(progress).is_ok() {
+            self.last_sent = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_progress_line() {
+        assert_eq!(
+            parse_progress_line("::cdktr-progress::30::step 3 of 10"),
+            Some(TaskProgress {
+                percent: 30,
+                message: "step 3 of 10".to_string()
+            })
+        );
+        assert_eq!(
+            parse_progress_line("::cdktr-progress::100")
+                .unwrap()
+                .message,
+            ""
+        );
+        assert!(parse_progress_line("::cdktr-progress::101::too far").is_none());
+        assert!(parse_progress_line("::cdktr-progress::half::way").is_none());
+        assert!(parse_progress_line("30% done").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_progress_reporter_throttles() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let mut reporter = ProgressReporter::with_min_interval(tx, Duration::from_secs(60));
+        for percent in [10, 20, 100] {
+            reporter.report(TaskProgress {
+                percent,
+                message: String::new(),
+            });
+        }
+        drop(reporter);
+        let mut forwarded = Vec::new();
+        while let Some(progress) = rx.recv().await {
+            forwarded.push(progress.percent);
+        }
+        assert_eq!(forwarded, vec![10, 100]);
+    }
+}
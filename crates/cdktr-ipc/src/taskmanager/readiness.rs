@@ -0,0 +1,141 @@
+use cdktr_workflow::{Readiness, ReadinessProbe};
+use regex::Regex;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{Notify, mpsc};
+use tokio::time::{sleep, timeout};
+
+/// Longest a single tcp or http check can take, however short the interval between them
+const MIN_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Watches the stdout of a task for the line its `log_line` readiness check waits for
+#[derive(Debug, Clone)]
+pub struct LogLineWatch {
+    pattern: Regex,
+    matched: Arc<Notify>,
+}
+
+impl LogLineWatch {
+    /// A watch for the readiness check if it is a `log_line` check
+    pub fn for_readiness(readiness: &Readiness) -> Option<Self> {
+        match readiness.probe() {
+            Ok(ReadinessProbe::LogLine(pattern)) => Some(Self {
+                pattern,
+                matched: Arc::new(Notify::new()),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Passes the lines sent on the returned sender on to `tx`, checking each one on
+    /// the way
+    pub fn tap(&self, tx: mpsc::Sender<String>) -> mpsc::Sender<String> {
+        let (tap_tx, mut tap_rx) = mpsc::channel::<String>(32);
+        let watch = self.clone();
+        tokio::spawn(async move {
+            while let Some(line) = tap_rx.recv().await {
+                if watch.pattern.is_match(&line) {
+                    watch.matched.notify_one();
+                }
+                let _ = tx.send(line).await;
+            }
+        });
+        tap_tx
+    }
+}
+
+/// Waits for the readiness check of a task to pass, erroring once its timeout is up.
+/// `log_line` checks need the watch tapping the output of the task
+pub async fn wait_until_ready(
+    readiness: &Readiness,
+    log_line: Option<&LogLineWatch>,
+) -> Result<(), String> {
+    let probe = readiness.probe().map_err(|e| e.to_string())?;
+    let interval = readiness.interval();
+    let client = reqwest::Client::new();
+    let ready = async {
+        match (&probe, log_line) {
+            (ReadinessProbe::LogLine(_), Some(watch)) => watch.matched.notified().await,
+            (ReadinessProbe::LogLine(_), None) => std::future::pending().await,
+            (probe, _) => {
+                while !timeout(interval.max(MIN_CHECK_TIMEOUT), check(probe, &client))
+                    .await
+                    .unwrap_or(false)
+                {
+                    sleep(interval).await;
+                }
+            }
+        }
+    };
+    timeout(readiness.timeout(), ready).await.map_err(|_| {
+        format!(
+            "Task was not ready {}s after it started",
+            readiness.timeout().as_secs()
+        )
+    })
+}
+
+async fn check(probe: &ReadinessProbe, client: &reqwest::Client) -> bool {
+    match probe {
+        ReadinessProbe::Tcp(address) => TcpStream::connect(address).await.is_ok(),
+        ReadinessProbe::Http(url) => client
+            .get(url)
+            .send()
+            .await
+            .is_ok_and(|response| response.status().is_success()),
+        ReadinessProbe::LogLine(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdktr_workflow::Workflow;
+
+    fn readiness(readiness: &str) -> Readiness {
+        let yaml = format!(
+            r#"
+name: Service
+tasks:
+  server:
+    name: Server
+    readiness:
+{readiness}
+    config: !Subprocess
+      cmd: ./serve.sh
+      args: []
+"#
+        );
+        Workflow::new("service.yml".to_string(), &yaml)
+            .unwrap()
+            .get_task("server")
+            .unwrap()
+            .readiness()
+            .unwrap()
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn test_log_line_readiness() {
+        let readiness = readiness("      log_line: \"^Listening on [0-9]+$\"");
+        let watch = LogLineWatch::for_readiness(&readiness).unwrap();
+        let (tx, mut rx) = mpsc::channel(32);
+        let tapped = watch.tap(tx);
+        tapped.send("Starting up".to_string()).await.unwrap();
+        tapped.send("Listening on 8080".to_string()).await.unwrap();
+        wait_until_ready(&readiness, Some(&watch)).await.unwrap();
+        // lines are still passed on
+        assert_eq!(rx.recv().await.unwrap(), "Starting up");
+        assert_eq!(rx.recv().await.unwrap(), "Listening on 8080");
+    }
+
+    #[tokio::test]
+    async fn test_readiness_times_out() {
+        let readiness =
+            readiness("      tcp: 127.0.0.1:39818\n      timeout_s: 1\n      interval_ms: 50");
+        assert!(LogLineWatch::for_readiness(&readiness).is_none());
+        let err = wait_until_ready(&readiness, None).await.unwrap_err();
+        assert!(err.contains("not ready 1s after"), "{err}");
+    }
+}
@@ -0,0 +1,243 @@
+use cdktr_core::models::{FlowExecutionResult, traits::Executor};
+use cdktr_workflow::Task;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, mpsc};
+
+#[derive(Debug, Clone)]
+struct CachedTaskResult {
+    stdout: Vec<String>,
+    stderr: Vec<String>,
+    cached_at: Instant,
+}
+
+/// Agent-local cache of the output of successful tasks that are marked with `cache: true`.
+/// Entries are keyed by a hash of the task's executor config and expire after the TTL.
+/// The cache lives in memory so it is cleared when the agent restarts. Runs with more output
+/// than `max_output_bytes` aren't cached, so a chatty task can't fill the agent's memory
+#[derive(Debug, Clone)]
+pub struct TaskResultCache {
+    ttl: Duration,
+    max_output_bytes: usize,
+    inner: Arc<Mutex<HashMap<String, CachedTaskResult>>>,
+}
+
+impl TaskResultCache {
+    pub fn new(ttl: Duration, max_output_bytes: usize) -> Self {
+        Self {
+            ttl,
+            max_output_bytes,
+            inner: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Option<CachedTaskResult> {
+        let mut inner = self.inner.lock().await;
+        inner.retain(|_, cached| cached.cached_at.elapsed() < self.ttl);
+        inner.get(key).cloned()
+    }
+
+    /// Runs the task, unless a result for the same config is cached in which case the
+    /// cached output is replayed instead of spawning the task. Only successful runs
+    /// are cached
+    pub async fn run(
+        &self,
+        task: &Task,
+        stdout_tx: mpsc::Sender<String>,
+        stderr_tx: mpsc::Sender<String>,
+    ) -> FlowExecutionResult {
+        let key = task.cache_key().await;
+        if let Some(cached) = self.get(&key).await {
+            debug!(
+                "Cache hit for task {} ({}) - replaying output",
+                task.name(),
+                key
+            );
+            for line in cached.stdout {
+                if stdout_tx.send(line).await.is_err() {
+                    break;
+                }
+            }
+            for line in cached.stderr {
+                if stderr_tx.send(line).await.is_err() {
+                    break;
+                }
+            }
+            return FlowExecutionResult::SUCCESS;
+        }
+
+        let (inner_stdout_tx, inner_stdout_rx) = mpsc::channel(32);
+        let (inner_stderr_tx, inner_stderr_rx) = mpsc::channel(32);
+        let captured = Arc::new(AtomicUsize::new(0));
+        let stdout_tee = tokio::spawn(tee(
+            inner_stdout_rx,
+            stdout_tx,
+            captured.clone(),
+            self.max_output_bytes,
+        ));
+        let stderr_tee = tokio::spawn(tee(
+            inner_stderr_rx,
+            stderr_tx,
+            captured,
+            self.max_output_bytes,
+        ));
+        let result = task
+            .get_exe_task()
+            .run(inner_stdout_tx, inner_stderr_tx)
+            .await;
+        // the executor has dropped its senders so both tees run to completion
+        let stdout = stdout_tee.await.unwrap_or_default();
+        let stderr = stderr_tee.await.unwrap_or_default();
+        if let (FlowExecutionResult::SUCCESS, Some(stdout), Some(stderr)) =
+            (&result, stdout, stderr)
+        {
+            self.inner.lock().await.insert(
+                key,
+                CachedTaskResult {
+                    stdout,
+                    stderr,
+                    cached_at: Instant::now(),
+                },
+            );
+        }
+        result
+    }
+}
+
+/// Forwards every line from `rx` to `tx`, returning a copy of the lines once `rx` closes.
+/// `captured` is shared by the tees of a task; once it passes `max_bytes` (0 for no limit)
+/// the copy is dropped and `None` is returned
+async fn tee(
+    mut rx: mpsc::Receiver<String>,
+    tx: mpsc::Sender<String>,
+    captured: Arc<AtomicUsize>,
+    max_bytes: usize,
+) -> Option<Vec<String>> {
+    let mut lines = Some(Vec::new());
+    while let Some(line) = rx.recv().await {
+        if let Some(copy) = lines.as_mut() {
+            let total = captured.fetch_add(line.len(), Ordering::SeqCst) + line.len();
+            if max_bytes > 0 && total > max_bytes {
+                lines = None;
+            } else {
+                copy.push(line.clone());
+            }
+        }
+        // keep reading even if the consumer has gone so the executor isn't blocked
+        let _ = tx.send(line).await;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counting_task(counter_path: &str, cmd: &str) -> Task {
+        serde_json::from_value(serde_json::json!({
+            "name": "cached",
+            "description": null,
+            "depends": null,
+            "matrix": null,
+            "cache": true,
+            "config": {
+                "Subprocess": {
+                    "cmd": "sh",
+                    "args": ["-c", format!("echo run >> {counter_path}; {cmd}")],
+                    "run_as_user": null
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    async fn run_and_collect(
+        cache: &TaskResultCache,
+        task: &Task,
+    ) -> (FlowExecutionResult, Vec<String>) {
+        let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+        let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+        let result = cache.run(task, stdout_tx, stderr_tx).await;
+        let mut stdout = Vec::new();
+        while let Some(line) = stdout_rx.recv().await {
+            stdout.push(line);
+        }
+        (result, stdout)
+    }
+
+    fn temp_counter_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "cdktr-{name}-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn test_cached_task_does_not_respawn() {
+        let counter = temp_counter_path("cache-hit");
+        let task = counting_task(counter.to_str().unwrap(), "echo hello");
+        let cache = TaskResultCache::new(Duration::from_secs(60), 0);
+
+        let (first, first_stdout) = run_and_collect(&cache, &task).await;
+        let (second, second_stdout) = run_and_collect(&cache, &task).await;
+
+        assert!(matches!(first, FlowExecutionResult::SUCCESS));
+        assert!(matches!(second, FlowExecutionResult::SUCCESS));
+        assert_eq!(first_stdout, vec!["hello".to_string()]);
+        assert_eq!(second_stdout, first_stdout);
+        // the process was only spawned on the first run
+        assert_eq!(
+            std::fs::read_to_string(&counter).unwrap().lines().count(),
+            1
+        );
+        std::fs::remove_file(&counter).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_and_expired_results_are_not_replayed() {
+        let counter = temp_counter_path("cache-miss");
+        let failing = counting_task(counter.to_str().unwrap(), "exit 1");
+        let cache = TaskResultCache::new(Duration::from_secs(60), 0);
+        run_and_collect(&cache, &failing).await;
+        run_and_collect(&cache, &failing).await;
+        assert_eq!(
+            std::fs::read_to_string(&counter).unwrap().lines().count(),
+            2
+        );
+
+        let expiring = counting_task(counter.to_str().unwrap(), "echo hello");
+        let cache = TaskResultCache::new(Duration::ZERO, 0);
+        run_and_collect(&cache, &expiring).await;
+        run_and_collect(&cache, &expiring).await;
+        assert_eq!(
+            std::fs::read_to_string(&counter).unwrap().lines().count(),
+            4
+        );
+        std::fs::remove_file(&counter).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_output_over_limit_is_not_cached() {
+        let counter = temp_counter_path("cache-limit");
+        let task = counting_task(counter.to_str().unwrap(), "echo hello");
+        let cache = TaskResultCache::new(Duration::from_secs(60), 3);
+        let (_, first_stdout) = run_and_collect(&cache, &task).await;
+        run_and_collect(&cache, &task).await;
+        // output is still forwarded in full, it just isn't kept
+        assert_eq!(first_stdout, vec!["hello".to_string()]);
+        assert_eq!(
+            std::fs::read_to_string(&counter).unwrap().lines().count(),
+            2
+        );
+        std::fs::remove_file(&counter).unwrap();
+    }
+}
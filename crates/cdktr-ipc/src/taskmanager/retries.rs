@@ -0,0 +1,120 @@
+use cdktr_core::models::FlowExecutionResult;
+use log::warn;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::TaskRun;
+
+/// Retries a workflow run has left across all of its tasks. Unlimited when the workflow
+/// doesn't set a `retry_budget`
+#[derive(Debug, Clone)]
+pub struct RetryBudget(Option<Arc<AtomicU32>>);
+
+impl RetryBudget {
+    pub fn new(budget: Option<u32>) -> Self {
+        Self(budget.map(|budget| Arc::new(AtomicU32::new(budget))))
+    }
+
+    /// Uses up a retry, returning false if there are none left
+    pub fn take(&self) -> bool {
+        match &self.0 {
+            None => true,
+            Some(left) => left
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok(),
+        }
+    }
+}
+
+/// Runs a task with `execute`, running it again each time it fails until it has been
+/// retried `retries` times or the retry budget of the workflow run is used up
+pub async fn execute_with_retries<F, Fut>(
+    task_id: &str,
+    retries: u32,
+    budget: &RetryBudget,
+    mut execute: F,
+) -> TaskRun
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = TaskRun>,
+{
+    let mut retried = 0;
+    loop {
+        let run = execute().await;
+        if matches!(run.result, FlowExecutionResult::SUCCESS) || retried >= retries {
+            return run;
+        }
+        if !budget.take() {
+            warn!("Retry budget of the workflow run is used up - not retrying task {task_id}");
+            return run;
+        }
+        retried += 1;
+        warn!("Task {task_id} failed - retrying ({retried}/{retries})");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::taskmanager::execute_task;
+    use crate::taskmanager::result_cache::TaskResultCache;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_retry_budget_shared_across_tasks() {
+        let runs = std::env::temp_dir().join(format!("cdktr-retry-budget-{}", std::process::id()));
+        let _ = std::fs::remove_file(&runs);
+        let flaky = |name: &str| {
+            format!(
+                r#"
+  {name}:
+    name: {name}
+    retries: 3
+    config: !Subprocess
+      cmd: sh
+      args: ["-c", "echo {name} >> {}; exit 1"]"#,
+                runs.display()
+            )
+        };
+        let yaml = format!(
+            "name: Flaky\nstart_time: 2025-01-20T12:30:00+00:00\nretry_budget: 2\ntasks:{}{}{}\n",
+            flaky("first"),
+            flaky("second"),
+            flaky("third")
+        );
+        let workflow = cdktr_workflow::Workflow::new("flaky.yml".to_string(), &yaml).unwrap();
+        workflow.validate().unwrap();
+        let budget = RetryBudget::new(workflow.retry_budget());
+        let cache = TaskResultCache::new(Duration::from_secs(60), 0);
+        for task_id in ["first", "second", "third"] {
+            let task = workflow.get_task(task_id).unwrap();
+            let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+            let (stderr_tx, mut stderr_rx) = mpsc::channel(32);
+            tokio::spawn(async move { while stdout_rx.recv().await.is_some() {} });
+            tokio::spawn(async move { while stderr_rx.recv().await.is_some() {} });
+            let run = execute_with_retries(task_id, task.retries(), &budget, || {
+                execute_task(
+                    task,
+                    &cache,
+                    None,
+                    0,
+                    false,
+                    None,
+                    stdout_tx.clone(),
+                    stderr_tx.clone(),
+                )
+            })
+            .await;
+            assert!(!matches!(run.result, FlowExecutionResult::SUCCESS));
+        }
+        // each task runs once, and only two of the failures are retried
+        let runs_made = std::fs::read_to_string(&runs).unwrap();
+        let _ = std::fs::remove_file(&runs);
+        assert_eq!(runs_made.lines().count(), 5);
+        assert_eq!(runs_made.lines().filter(|run| *run == "first").count(), 3);
+        assert!(!budget.take());
+    }
+}
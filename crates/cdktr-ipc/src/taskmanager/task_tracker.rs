@@ -1,7 +1,11 @@
-use std::{collections::VecDeque, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
 
 use cdktr_core::exceptions::GenericError;
-use cdktr_workflow::{WorkFlowDAG, Workflow};
+use cdktr_core::models::RunStatus;
+use cdktr_workflow::{Task, WorkFlowDAG, Workflow};
 use std::sync::Mutex;
 
 pub trait TaskTracker
@@ -12,8 +16,27 @@ where
     fn get_next_task(&mut self) -> Option<String>;
     fn mark_success(&mut self, task_id: &str) -> Result<(), GenericError>;
     fn mark_failed(&mut self, task_id: &str) -> Result<(), GenericError>;
+    /// Marks a task whose `when` condition did not hold. Its dependents treat it as
+    /// satisfied and still run
+    fn mark_skipped(&mut self, task_id: &str) -> Result<(), GenericError>;
+    /// Marks a running task whose readiness check has passed. Its dependents treat it as
+    /// satisfied and start while it keeps running
+    fn mark_ready(&mut self, task_id: &str) -> Result<(), GenericError>;
+    fn set_output(&mut self, task_id: &str, output: String);
+    fn get_output(&self, task_id: &str) -> Option<String>;
+    fn set_exit_code(&mut self, task_id: &str, exit_code: i32);
+    /// Results of the matrix expansions a task aggregates as a JSON array, in the order of
+    /// the matrix items, for the task to be given as `${matrix.results}`
+    fn matrix_results(&self, task: &Task) -> String;
     fn is_finished(&self) -> bool;
+    /// Whether none of the tasks failed. Failed cleanup tasks don't count, so this is
+    /// the status the rest of the workflow finished with
     fn all_tasks_successful(&self) -> bool;
+    /// Cleanup tasks that failed
+    fn failed_cleanup_tasks(&self) -> Vec<String>;
+    /// Number of tasks that have succeeded, failed or been skipped, and the total
+    /// number of tasks in the workflow
+    fn progress(&self) -> (usize, usize);
 }
 
 /// Struct required to manage execution dependency.
@@ -26,21 +49,94 @@ struct BaseTaskTracker {
     failed_stack: Vec<String>,
     skipped_stack: Vec<String>,
     success_stack: Vec<String>,
+    /// tasks not run because their condition did not hold
+    condition_skipped_stack: Vec<String>,
+    /// running tasks whose readiness check has passed. Their dependents have already
+    /// been released
+    ready_stack: Vec<String>,
+    outputs: HashMap<String, String>,
+    /// exit codes of the tasks that failed with one
+    exit_codes: HashMap<String, i32>,
+    /// cleanup tasks held back until every other task has finished
+    held_cleanup: Vec<String>,
     processed_count: usize,
 }
+impl BaseTaskTracker {
+    /// Whether `dependency` is one of the matrix expansions `task_id` aggregates
+    fn aggregates(&self, task_id: &str, dependency: &str) -> bool {
+        self.dag.get_task(task_id).is_some_and(|task| {
+            task.matrix_results()
+                .iter()
+                .any(|expansion| expansion.task_id() == dependency)
+        })
+    }
+
+    /// Fan-in tasks are only ready once every one of their dependencies has succeeded or
+    /// been skipped by its condition. Tasks aggregating matrix results also accept failed
+    /// expansions
+    fn deps_complete(&self, task_id: &str) -> bool {
+        match self.dag.get_task(task_id) {
+            Some(task) => task
+                .get_dependencies()
+                .unwrap_or_default()
+                .iter()
+                .all(|dep| {
+                    self.success_stack.contains(dep)
+                        || self.condition_skipped_stack.contains(dep)
+                        || self.ready_stack.contains(dep)
+                        || (self.failed_stack.contains(dep) && self.aggregates(task_id, dep))
+                }),
+            None => true,
+        }
+    }
+
+    fn is_cleanup(&self, task_id: &str) -> bool {
+        self.dag.get_task(task_id).is_some_and(Task::cleanup)
+    }
+
+    /// Queues the cleanup tasks once every other task has succeeded, failed or been
+    /// skipped
+    fn release_cleanup(&mut self) {
+        if !self.held_cleanup.is_empty()
+            && self.processed_count + self.held_cleanup.len() == self.dag.node_count()
+        {
+            self.ready_q.extend(self.held_cleanup.drain(..));
+        }
+    }
+
+    /// Queues the dependents of a task that are now ready to run
+    fn release_dependents(&mut self, task_id: &str) -> Result<(), GenericError> {
+        for next_task_id in self.dag.get_dependents(task_id)? {
+            if self.deps_complete(next_task_id) && !self.ready_q.contains(next_task_id) {
+                self.ready_q.push_back(next_task_id.clone());
+            }
+        }
+        Ok(())
+    }
+}
 impl TaskTracker for BaseTaskTracker {
     fn from_workflow(workflow: &Workflow) -> Result<Self, GenericError> {
         workflow.validate()?;
         let dag = workflow.get_dag().clone();
-        let ready_q = dag.get_first_tasks().into();
-        Ok(Self {
+        let (held_cleanup, ready_q): (Vec<String>, Vec<String>) = dag
+            .get_first_tasks()
+            .into_iter()
+            .partition(|task_id| dag.get_task(task_id).is_some_and(Task::cleanup));
+        let mut tracker = Self {
             dag: dag,
-            ready_q,
+            ready_q: ready_q.into(),
             failed_stack: Vec::new(),
             skipped_stack: Vec::new(),
             success_stack: Vec::new(),
+            condition_skipped_stack: Vec::new(),
+            ready_stack: Vec::new(),
+            outputs: HashMap::new(),
+            exit_codes: HashMap::new(),
+            held_cleanup,
             processed_count: 0,
-        })
+        };
+        tracker.release_cleanup();
+        Ok(tracker)
     }
 
     fn get_next_task(&mut self) -> Option<String> {
@@ -48,38 +144,118 @@ impl TaskTracker for BaseTaskTracker {
     }
 
     fn mark_success(&mut self, task_id: &str) -> Result<(), GenericError> {
-        for next_task_id in self.dag.get_dependents(task_id)? {
-            self.ready_q.push_back(next_task_id.clone());
-        }
         self.success_stack.push(task_id.to_string());
         self.processed_count += 1;
+        self.release_cleanup();
+        if self.ready_stack.iter().any(|t| t == task_id) {
+            return Ok(());
+        }
+        self.release_dependents(task_id)
+    }
+
+    fn mark_skipped(&mut self, task_id: &str) -> Result<(), GenericError> {
+        self.condition_skipped_stack.push(task_id.to_string());
+        self.processed_count += 1;
+        self.release_dependents(task_id)?;
+        self.release_cleanup();
         Ok(())
     }
 
+    fn mark_ready(&mut self, task_id: &str) -> Result<(), GenericError> {
+        self.ready_stack.push(task_id.to_string());
+        self.release_dependents(task_id)
+    }
+
+    fn set_output(&mut self, task_id: &str, output: String) {
+        self.outputs.insert(task_id.to_string(), output);
+    }
+
+    fn get_output(&self, task_id: &str) -> Option<String> {
+        self.outputs.get(task_id).cloned()
+    }
+
     fn mark_failed(&mut self, task_id: &str) -> Result<(), GenericError> {
         self.failed_stack.push(task_id.to_string());
         self.processed_count += 1;
+        // the dependents of a task that was ready have already started
+        if self.ready_stack.iter().any(|t| t == task_id) {
+            self.release_cleanup();
+            return Ok(());
+        }
         let mut skip_q: VecDeque<&String> = VecDeque::new();
         for next_task_id in self.dag.get_dependents(task_id)? {
-            skip_q.push_back(next_task_id);
+            // tasks aggregating the results of a matrix still run when expansions fail
+            if !self.aggregates(next_task_id, task_id) {
+                skip_q.push_back(next_task_id);
+            } else if self.deps_complete(next_task_id) && !self.ready_q.contains(next_task_id) {
+                self.ready_q.push_back(next_task_id.clone());
+            }
         }
         while !skip_q.is_empty() {
             let task_to_skip = skip_q.pop_front().unwrap();
+            if self.skipped_stack.contains(task_to_skip) {
+                // already skipped via another failed dependency
+                continue;
+            }
             self.skipped_stack.push(task_to_skip.clone());
             self.processed_count += 1;
             for next_task_id in self.dag.get_dependents(task_to_skip)? {
                 skip_q.push_back(next_task_id);
             }
         }
+        self.release_cleanup();
         Ok(())
     }
 
+    fn set_exit_code(&mut self, task_id: &str, exit_code: i32) {
+        self.exit_codes.insert(task_id.to_string(), exit_code);
+    }
+
+    fn matrix_results(&self, task: &Task) -> String {
+        let results: Vec<serde_json::Value> = task
+            .matrix_results()
+            .iter()
+            .map(|expansion| {
+                let task_id = expansion.task_id();
+                let (status, exit_code) = if self.success_stack.iter().any(|t| t == task_id) {
+                    (RunStatus::COMPLETED, Some(0))
+                } else if self.failed_stack.iter().any(|t| t == task_id) {
+                    (RunStatus::FAILED, self.exit_codes.get(task_id).copied())
+                } else {
+                    (RunStatus::SKIPPED, None)
+                };
+                serde_json::json!({
+                    "task_id": task_id,
+                    "item": expansion.item(),
+                    "status": status.to_string(),
+                    "exit_code": exit_code,
+                    "output": self.outputs.get(task_id),
+                })
+            })
+            .collect();
+        serde_json::Value::Array(results).to_string()
+    }
+
     fn is_finished(&self) -> bool {
         self.dag.node_count() == self.processed_count
     }
 
     fn all_tasks_successful(&self) -> bool {
-        self.failed_stack.is_empty()
+        self.failed_stack
+            .iter()
+            .all(|task_id| self.is_cleanup(task_id))
+    }
+
+    fn failed_cleanup_tasks(&self) -> Vec<String> {
+        self.failed_stack
+            .iter()
+            .filter(|task_id| self.is_cleanup(task_id))
+            .cloned()
+            .collect()
+    }
+
+    fn progress(&self) -> (usize, usize) {
+        (self.processed_count, self.dag.node_count())
     }
 }
 
@@ -106,6 +282,30 @@ impl TaskTracker for ThreadSafeTaskTracker {
         (*self.tt.lock().unwrap()).mark_failed(task_id)
     }
 
+    fn mark_skipped(&mut self, task_id: &str) -> Result<(), GenericError> {
+        (*self.tt.lock().unwrap()).mark_skipped(task_id)
+    }
+
+    fn mark_ready(&mut self, task_id: &str) -> Result<(), GenericError> {
+        (*self.tt.lock().unwrap()).mark_ready(task_id)
+    }
+
+    fn set_output(&mut self, task_id: &str, output: String) {
+        (*self.tt.lock().unwrap()).set_output(task_id, output)
+    }
+
+    fn get_output(&self, task_id: &str) -> Option<String> {
+        (*self.tt.lock().unwrap()).get_output(task_id)
+    }
+
+    fn set_exit_code(&mut self, task_id: &str, exit_code: i32) {
+        (*self.tt.lock().unwrap()).set_exit_code(task_id, exit_code)
+    }
+
+    fn matrix_results(&self, task: &Task) -> String {
+        (*self.tt.lock().unwrap()).matrix_results(task)
+    }
+
     fn is_finished(&self) -> bool {
         (*self.tt.lock().unwrap()).is_finished()
     }
@@ -113,4 +313,242 @@ impl TaskTracker for ThreadSafeTaskTracker {
     fn all_tasks_successful(&self) -> bool {
         (*self.tt.lock().unwrap()).all_tasks_successful()
     }
+
+    fn failed_cleanup_tasks(&self) -> Vec<String> {
+        (*self.tt.lock().unwrap()).failed_cleanup_tasks()
+    }
+
+    fn progress(&self) -> (usize, usize) {
+        (*self.tt.lock().unwrap()).progress()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix_workflow() -> Workflow {
+        let yaml = r#"
+name: Matrix Flow
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  extract:
+    name: Extract ${matrix.item}
+    matrix: ["a", "b", "c"]
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["${matrix.item}"]
+  aggregate:
+    name: Aggregate
+    depends: ["extract"]
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["done"]
+        "#;
+        Workflow::new("fake/path/matrix.yml".to_string(), yaml).unwrap()
+    }
+
+    #[test]
+    fn test_matrix_fan_in_waits_for_all_expansions() {
+        let mut tt = ThreadSafeTaskTracker::from_workflow(&matrix_workflow()).unwrap();
+        let mut expansions = Vec::new();
+        while let Some(task_id) = tt.get_next_task() {
+            expansions.push(task_id);
+        }
+        expansions.sort();
+        assert_eq!(expansions, vec!["extract[0]", "extract[1]", "extract[2]"]);
+
+        tt.mark_success("extract[0]").unwrap();
+        tt.mark_success("extract[1]").unwrap();
+        assert_eq!(tt.get_next_task(), None);
+
+        tt.mark_success("extract[2]").unwrap();
+        assert_eq!(tt.get_next_task(), Some("aggregate".to_string()));
+        assert_eq!(tt.get_next_task(), None);
+
+        tt.mark_success("aggregate").unwrap();
+        assert!(tt.is_finished());
+        assert!(tt.all_tasks_successful());
+    }
+
+    #[test]
+    fn test_matrix_results_aggregator_runs_after_failures() {
+        let yaml = r#"
+name: Map Reduce
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  count:
+    name: Count ${matrix.item}
+    matrix: ["a", "b", "c"]
+    config:
+      !Subprocess
+      cmd: ./count.sh
+      args: ["${matrix.item}"]
+  total:
+    name: Total
+    depends: ["count"]
+    config:
+      !Subprocess
+      cmd: ./total.sh
+      args: ["${matrix.results}"]
+        "#;
+        let workflow = Workflow::new("fake/path/map_reduce.yml".to_string(), yaml).unwrap();
+        let mut tt = ThreadSafeTaskTracker::from_workflow(&workflow).unwrap();
+        while tt.get_next_task().is_some() {}
+        tt.set_output("count[0]", "12".to_string());
+        tt.mark_success("count[0]").unwrap();
+        tt.set_exit_code("count[1]", 2);
+        tt.mark_failed("count[1]").unwrap();
+        assert_eq!(tt.get_next_task(), None);
+        tt.set_output("count[2]", "30".to_string());
+        tt.mark_success("count[2]").unwrap();
+        // the aggregator isn't skipped by the failed expansion
+        assert_eq!(tt.get_next_task(), Some("total".to_string()));
+
+        let results: serde_json::Value =
+            serde_json::from_str(&tt.matrix_results(workflow.get_task("total").unwrap())).unwrap();
+        assert_eq!(
+            results,
+            serde_json::json!([
+                {"task_id": "count[0]", "item": "a", "status": "COMPLETED", "exit_code": 0, "output": "12"},
+                {"task_id": "count[1]", "item": "b", "status": "FAILED", "exit_code": 2, "output": null},
+                {"task_id": "count[2]", "item": "c", "status": "COMPLETED", "exit_code": 0, "output": "30"},
+            ])
+        );
+        tt.mark_success("total").unwrap();
+        assert!(tt.is_finished());
+        assert!(!tt.all_tasks_successful());
+    }
+
+    fn conditional_workflow() -> Workflow {
+        let yaml = r#"
+name: Conditional Flow
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  check:
+    name: Check
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["deploy"]
+  deploy:
+    name: Deploy
+    depends: ["check"]
+    when: "${outputs.check} == 'deploy'"
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["deploying"]
+  notify:
+    name: Notify
+    depends: ["deploy"]
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["done"]
+        "#;
+        Workflow::new("fake/path/conditional.yml".to_string(), yaml).unwrap()
+    }
+
+    #[test]
+    fn test_condition_skipped_task_satisfies_dependents() {
+        let mut tt = ThreadSafeTaskTracker::from_workflow(&conditional_workflow()).unwrap();
+        assert_eq!(tt.get_next_task(), Some("check".to_string()));
+        tt.set_output("check", "deploy".to_string());
+        tt.mark_success("check").unwrap();
+        assert_eq!(tt.get_output("check"), Some("deploy".to_string()));
+
+        assert_eq!(tt.get_next_task(), Some("deploy".to_string()));
+        tt.mark_skipped("deploy").unwrap();
+        // the dependent of a skipped task still runs
+        assert_eq!(tt.get_next_task(), Some("notify".to_string()));
+        tt.mark_success("notify").unwrap();
+        assert!(tt.is_finished());
+        assert!(tt.all_tasks_successful());
+    }
+
+    #[test]
+    fn test_progress_counts_finished_tasks() {
+        let mut tt = ThreadSafeTaskTracker::from_workflow(&matrix_workflow()).unwrap();
+        assert_eq!(tt.progress(), (0, 4));
+        let mut reported = vec![tt.progress()];
+        while let Some(task_id) = tt.get_next_task() {
+            tt.mark_success(&task_id).unwrap();
+            reported.push(tt.progress());
+        }
+        assert_eq!(reported, vec![(0, 4), (1, 4), (2, 4), (3, 4), (4, 4)]);
+        assert!(tt.is_finished());
+
+        // dependents skipped by a failure count as finished
+        let mut tt = ThreadSafeTaskTracker::from_workflow(&conditional_workflow()).unwrap();
+        tt.get_next_task();
+        tt.mark_failed("check").unwrap();
+        assert_eq!(tt.progress(), (3, 3));
+    }
+
+    #[test]
+    fn test_cleanup_runs_after_other_tasks() {
+        let yaml = r#"
+name: Cleanup Flow
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  extract:
+    name: Extract
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["extracted"]
+  load:
+    name: Load
+    depends: ["extract"]
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["loaded"]
+  teardown:
+    name: Teardown
+    cleanup: true
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["cleaned up"]
+        "#;
+        let workflow = Workflow::new("fake/path/cleanup.yml".to_string(), yaml).unwrap();
+
+        let mut tt = ThreadSafeTaskTracker::from_workflow(&workflow).unwrap();
+        assert_eq!(tt.get_next_task(), Some("extract".to_string()));
+        assert_eq!(tt.get_next_task(), None);
+        tt.mark_success("extract").unwrap();
+        assert_eq!(tt.get_next_task(), Some("load".to_string()));
+        tt.mark_success("load").unwrap();
+        assert_eq!(tt.get_next_task(), Some("teardown".to_string()));
+        tt.mark_success("teardown").unwrap();
+        assert!(tt.is_finished());
+        assert!(tt.all_tasks_successful());
+
+        // runs when the tasks before it fail, and its own failure is reported separately
+        let mut tt = ThreadSafeTaskTracker::from_workflow(&workflow).unwrap();
+        tt.get_next_task();
+        tt.mark_failed("extract").unwrap();
+        assert_eq!(tt.get_next_task(), Some("teardown".to_string()));
+        assert!(!tt.all_tasks_successful());
+        tt.mark_failed("teardown").unwrap();
+        assert!(tt.is_finished());
+        assert_eq!(tt.failed_cleanup_tasks(), vec!["teardown".to_string()]);
+    }
+
+    #[test]
+    fn test_matrix_fan_in_skipped_once_on_failures() {
+        let mut tt = ThreadSafeTaskTracker::from_workflow(&matrix_workflow()).unwrap();
+        while tt.get_next_task().is_some() {}
+        tt.mark_failed("extract[0]").unwrap();
+        tt.mark_failed("extract[1]").unwrap();
+        assert!(!tt.is_finished());
+        tt.mark_success("extract[2]").unwrap();
+        assert_eq!(tt.get_next_task(), None);
+        assert!(tt.is_finished());
+        assert!(!tt.all_tasks_successful());
+    }
 }
@@ -0,0 +1,107 @@
+use cdktr_core::get_cdktr_setting;
+use cdktr_workflow::{WaitCondition, WaitFor, shell_command};
+use log::info;
+use std::process::Stdio;
+use tokio::time::{Instant, sleep, timeout};
+
+/// Waits for the `wait_for` condition of a task to hold, logging while it doesn't and
+/// erroring once its timeout is up
+pub async fn wait_for_condition(task_id: &str, wait_for: &WaitFor) -> Result<(), String> {
+    let condition = wait_for.condition().map_err(|e| e.to_string())?;
+    let started = Instant::now();
+    let waiting = async {
+        while !is_met(&condition).await {
+            info!(
+                "Task {task_id} is waiting for {} ({}s so far)",
+                describe(&condition),
+                started.elapsed().as_secs()
+            );
+            sleep(wait_for.interval()).await;
+        }
+    };
+    timeout(wait_for.timeout(), waiting).await.map_err(|_| {
+        format!(
+            "Gave up waiting for {} after {}s",
+            describe(&condition),
+            wait_for.timeout().as_secs()
+        )
+    })
+}
+
+async fn is_met(condition: &WaitCondition) -> bool {
+    match condition {
+        WaitCondition::File(path) => tokio::fs::try_exists(path).await.unwrap_or(false),
+        WaitCondition::Command(command_line) => {
+            let mut cmd =
+                shell_command(&get_cdktr_setting!(CDKTR_AGENT_DEFAULT_SHELL), command_line);
+            cmd.stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .kill_on_drop(true);
+            cmd.status().await.is_ok_and(|status| status.success())
+        }
+    }
+}
+
+fn describe(condition: &WaitCondition) -> String {
+    match condition {
+        WaitCondition::File(path) => format!("file {path}"),
+        WaitCondition::Command(command_line) => format!("command `{command_line}` to succeed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdktr_workflow::Workflow;
+    use std::time::Duration;
+
+    fn wait_for(wait_for: &str) -> WaitFor {
+        let yaml = format!(
+            r#"
+name: Feed
+tasks:
+  load:
+    name: Load
+    wait_for:
+{wait_for}
+    config: !Subprocess
+      cmd: ./load.sh
+      args: []
+"#
+        );
+        Workflow::new("feed.yml".to_string(), &yaml)
+            .unwrap()
+            .get_task("load")
+            .unwrap()
+            .wait_for()
+            .unwrap()
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn test_waits_for_file_created_later() {
+        let path = std::env::temp_dir().join(format!("cdktr-wait-for-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let condition = wait_for(&format!(
+            "      file: {}\n      timeout_s: 10\n      interval_ms: 50",
+            path.display()
+        ));
+        let created = path.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(300)).await;
+            std::fs::write(created, "ready").unwrap();
+        });
+        let started = Instant::now();
+        wait_for_condition("load", &condition).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        let _ = std::fs::remove_file(&path);
+
+        let err = wait_for_condition(
+            "load",
+            &wait_for("      command: exit 1\n      timeout_s: 1\n      interval_ms: 50"),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.contains("after 1s"), "{err}");
+    }
+}
@@ -0,0 +1,384 @@
+//! Harness for end-to-end tests that runs a principal and an agent in the test process,
+//! so dispatch, status updates and log flow can be exercised without starting `cdktr`.
+//! They talk to each other over loopback sockets on ports picked for each harness, and
+//! the principal keeps its state in an `InMemoryStatusStore`
+
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+use cdktr_api::models::{ClientResponseMessage, WorkflowResult};
+use cdktr_api::{API, PrincipalAPI};
+use cdktr_core::utils::data_structures::AsyncQueue;
+use cdktr_workflow::WorkflowStore;
+use tokio::sync::{Mutex, MutexGuard, Notify};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep};
+
+use crate::client::PrincipalClient;
+use crate::log_manager::{manager::LogManager, model::LogMessage, persister::start_listener};
+use crate::server::{principal::PrincipalServer, traits::Server};
+use crate::store::InMemoryStatusStore;
+use crate::taskmanager::TaskManager;
+
+/// Each harness points the settings of the process at its own ports, so only one runs
+/// at a time
+static HARNESS_LOCK: LazyLock<Mutex<()>> = LazyLock::new(|| Mutex::new(()));
+
+/// How long a harness waits for its agent to register
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long `run` waits for a run to finish
+const RUN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A port nothing is listening on, for tests that need to bind one
+pub(crate) fn free_port() -> usize {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("failed to find a free port")
+        .port() as usize
+}
+
+/// A principal and one agent running in the test process. Both are stopped when the
+/// harness is dropped
+pub struct Harness {
+    _lock: MutexGuard<'static, ()>,
+    workflow_dir: PathBuf,
+    services: JoinSet<()>,
+    logs: AsyncQueue<LogMessage>,
+}
+
+impl Harness {
+    /// Starts a principal with the workflows given as `(id, yaml)` pairs and an agent
+    /// running up to two workflows at once, returning once the agent has registered
+    pub async fn start(workflows: &[(&str, &str)]) -> Self {
+        Self::start_with_max_concurrency(workflows, 2).await
+    }
+
+    /// Like `start`, with an agent running up to `max_concurrency` workflows and tasks
+    /// at once
+    pub async fn start_with_max_concurrency(
+        workflows: &[(&str, &str)],
+        max_concurrency: usize,
+    ) -> Self {
+        let lock = HARNESS_LOCK.lock().await;
+        let principal_port = free_port();
+        // SAFETY: harnesses are run one at a time and set these before starting anything
+        // that reads them
+        unsafe {
+            std::env::set_var("CDKTR_PRINCIPAL_HOST", "127.0.0.1");
+            std::env::set_var("CDKTR_PRINCIPAL_PORT", principal_port.to_string());
+            std::env::set_var("CDKTR_LOGS_LISTENING_PORT", free_port().to_string());
+            std::env::set_var("CDKTR_LOGS_PUBLISHING_PORT", free_port().to_string());
+        }
+
+        let workflow_dir = std::env::temp_dir().join(format!(
+            "cdktr-harness-{}-{}",
+            std::process::id(),
+            principal_port
+        ));
+        std::fs::create_dir_all(&workflow_dir).unwrap();
+        for (id, yaml) in workflows {
+            std::fs::write(workflow_dir.join(format!("{id}.yml")), yaml).unwrap();
+        }
+        let store = WorkflowStore::from_dir(workflow_dir.to_str().unwrap())
+            .await
+            .unwrap();
+
+        let mut services = JoinSet::new();
+        let mut principal = PrincipalServer::new(
+            "harness-principal".to_string(),
+            store,
+            Arc::new(InMemoryStatusStore::new()),
+        );
+        services.spawn(async move {
+            principal.start("127.0.0.1", principal_port).await.unwrap();
+        });
+        let subscribed = Arc::new(Notify::new());
+        let mut log_manager = LogManager::new(subscribed.clone()).await.unwrap();
+        services.spawn(async move { log_manager.start().await });
+        let logs = AsyncQueue::new();
+        let listener_logs = logs.clone();
+        services.spawn(async move {
+            start_listener(listener_logs, subscribed).await.unwrap();
+        });
+        // agents of earlier harnesses may still be sending heartbeats, so each one has
+        // its own id
+        let agent_id = format!("harness-agent-{principal_port}");
+        let mut agent = TaskManager::new(agent_id.clone(), max_concurrency).await;
+        services.spawn(async move {
+            agent.start().await.unwrap();
+        });
+
+        let harness = Self {
+            _lock: lock,
+            workflow_dir,
+            services,
+            logs,
+        };
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            if let Ok(ClientResponseMessage::SuccessWithPayload(agents)) =
+                PrincipalAPI::GetRegisteredAgents.send().await
+                && agents.contains(&agent_id)
+            {
+                return harness;
+            }
+            assert!(Instant::now() < deadline, "harness agent never registered");
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Queues a run of the workflow, returning its instance id
+    pub async fn submit(&self, workflow_id: &str) -> String {
+        match PrincipalAPI::RunTask(workflow_id.to_string(), Default::default(), false, None)
+            .send()
+            .await
+        {
+            Ok(ClientResponseMessage::SuccessWithPayload(instance_id)) => instance_id,
+            other => panic!("Failed to run workflow {workflow_id}: {:?}", other),
+        }
+    }
+
+    /// Waits for the run to finish
+    pub async fn await_result(&self, workflow_instance_id: &str) -> WorkflowResult {
+        PrincipalClient::wait_for_completion(workflow_instance_id, RUN_TIMEOUT)
+            .await
+            .unwrap()
+    }
+
+    /// Runs the workflow, returning the result of the run once it has finished
+    pub async fn run(&self, workflow_id: &str) -> WorkflowResult {
+        let instance_id = self.submit(workflow_id).await;
+        self.await_result(&instance_id).await
+    }
+
+    /// Log messages of the run published so far
+    pub async fn logs(&self, workflow_instance_id: &str) -> Vec<LogMessage> {
+        self.logs
+            .snapshot()
+            .await
+            .into_iter()
+            .filter(|msg| msg.workflow_instance_id == workflow_instance_id)
+            .collect()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.services.abort_all();
+        let _ = std::fs::remove_dir_all(&self.workflow_dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWO_TASKS: &str = r#"
+name: Two Tasks
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  extract:
+    name: Extract
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["extracted"]
+  load:
+    name: Load
+    depends: ["extract"]
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["loaded"]
+"#;
+
+    const EVERY_SECOND: &str = r#"
+name: Every Second
+cron: "* * * * * *"
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  tick:
+    name: Tick
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["tick"]
+"#;
+
+    async fn total_enqueued() -> u64 {
+        match PrincipalAPI::GetQueueMetrics.send().await {
+            Ok(ClientResponseMessage::SuccessWithPayload(json)) => {
+                serde_json::from_str::<serde_json::Value>(&json).unwrap()["total_enqueued"]
+                    .as_u64()
+                    .unwrap()
+            }
+            other => panic!("Failed to get queue metrics: {:?}", other),
+        }
+    }
+
+    fn cleanup_workflow(extract_cmd: &str, teardown_cmd: &str) -> String {
+        format!(
+            r#"
+name: Cleanup
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  extract:
+    name: Extract
+    config:
+      !Subprocess
+      cmd: sh
+      args: ["-c", "{extract_cmd}"]
+  load:
+    name: Load
+    depends: ["extract"]
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["loaded"]
+  teardown:
+    name: Teardown
+    cleanup: true
+    config:
+      !Subprocess
+      cmd: sh
+      args: ["-c", "{teardown_cmd}"]
+"#
+        )
+    }
+
+    #[tokio::test]
+    async fn test_two_task_workflow_end_to_end() {
+        let harness = Harness::start(&[("two-tasks", TWO_TASKS)]).await;
+        let result = harness.run("two-tasks").await;
+        assert_eq!(result.status, "COMPLETED", "{result:?}");
+        let mut tasks: Vec<_> = result
+            .tasks
+            .iter()
+            .map(|task| (task.task_id.as_str(), task.status.as_str()))
+            .collect();
+        tasks.sort();
+        assert_eq!(tasks, vec![("extract", "COMPLETED"), ("load", "COMPLETED")]);
+
+        // log lines can arrive after the final status
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let payloads: Vec<String> = harness
+                .logs(&result.workflow_instance_id)
+                .await
+                .into_iter()
+                .map(|msg| msg.payload)
+                .collect();
+            let extracted = payloads.iter().position(|p| p.contains("extracted"));
+            let loaded = payloads.iter().position(|p| p.contains("loaded"));
+            if let (Some(extracted), Some(loaded)) = (extracted, loaded) {
+                assert!(extracted < loaded, "{payloads:?}");
+                break;
+            }
+            assert!(Instant::now() < deadline, "{payloads:?}");
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_standalone_scheduler_enqueues_on_cron_fire() {
+        let harness = Harness::start(&[("every-second", EVERY_SECOND)]).await;
+        // two schedulers against the same principal, only one of which may fire
+        let mut schedulers = JoinSet::new();
+        for scheduler_id in ["sched-a", "sched-b"] {
+            schedulers.spawn(crate::instance::start_scheduler_instance(
+                scheduler_id.to_string(),
+            ));
+        }
+        let started = Instant::now();
+        let deadline = started + Duration::from_secs(10);
+        while total_enqueued().await < 3 {
+            assert!(Instant::now() < deadline, "scheduler never queued a run");
+            sleep(Duration::from_millis(100)).await;
+        }
+        sleep(Duration::from_secs(2)).await;
+        let enqueued = total_enqueued().await;
+        schedulers.abort_all();
+
+        // a run fires each second, so a second scheduler firing as well would queue
+        // about twice as many
+        let fires = started.elapsed().as_secs() + 1;
+        assert!(enqueued <= fires, "{enqueued} runs queued in {fires} fires");
+        drop(harness);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_task_runs_after_success_and_failure() {
+        let teardown = "echo cleanup after $CDKTR_WORKFLOW_STATUS";
+        let succeeding = cleanup_workflow("echo extracted", teardown);
+        let failing = cleanup_workflow("exit 1", teardown);
+        let failing_cleanup = cleanup_workflow("echo extracted", "exit 1");
+        let harness = Harness::start(&[
+            ("succeeding", &succeeding),
+            ("failing", &failing),
+            ("failing-cleanup", &failing_cleanup),
+        ])
+        .await;
+
+        for (workflow_id, status) in [("succeeding", "COMPLETED"), ("failing", "FAILED")] {
+            let result = harness.run(workflow_id).await;
+            assert_eq!(result.status, status, "{result:?}");
+            let teardown = result.tasks.iter().find(|task| task.task_id == "teardown");
+            assert_eq!(
+                teardown.map(|task| task.status.as_str()),
+                Some("COMPLETED"),
+                "{result:?}"
+            );
+            let expected = format!("cleanup after {status}");
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !harness
+                .logs(&result.workflow_instance_id)
+                .await
+                .iter()
+                .any(|msg| msg.payload.contains(&expected))
+            {
+                assert!(Instant::now() < deadline, "no log line '{expected}'");
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+
+        // a failed cleanup task doesn't change the status of the workflow
+        let result = harness.run("failing-cleanup").await;
+        assert_eq!(result.status, "COMPLETED", "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_ready_service_and_dependent_share_one_slot() {
+        let marker = std::env::temp_dir().join(format!("cdktr-service-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        // the service only exits once its dependent has run
+        let service = format!(
+            r#"
+name: Service
+start_time: 2025-01-20T12:00:00+00:00
+tasks:
+  server:
+    name: Server
+    readiness:
+      log_line: listening
+      timeout_s: 10
+    config:
+      !Subprocess
+      cmd: sh
+      args: ["-c", "echo listening; while [ ! -f {0} ]; do sleep 0.1; done"]
+  client:
+    name: Client
+    depends: ["server"]
+    config:
+      !Subprocess
+      cmd: touch
+      args: ["{0}"]
+"#,
+            marker.display()
+        );
+        let harness = Harness::start_with_max_concurrency(&[("service", &service)], 1).await;
+        let result = harness.run("service").await;
+        assert_eq!(result.status, "COMPLETED", "{result:?}");
+        let _ = std::fs::remove_file(&marker);
+    }
+}
@@ -81,6 +81,10 @@ pub enum Action {
     /// Principal status was updated (online/offline)
     PrincipalStatusUpdated(bool),
 
+    /// A request to the principal is being retried after losing the connection (true),
+    /// or has stopped retrying (false)
+    Reconnecting(bool),
+
     /// Recent workflow status updates received
     RecentWorkflowStatusesUpdated(Vec<WorkflowStatusUpdate>),
 
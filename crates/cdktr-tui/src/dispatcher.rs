@@ -39,4 +39,10 @@ impl ActionReceiver {
     pub async fn recv(&mut self) -> Option<Action> {
         self.rx.recv().await
     }
+
+    /// Receive the next action if one has already been dispatched
+    #[cfg(test)]
+    pub fn try_recv(&mut self) -> Option<Action> {
+        self.rx.try_recv().ok()
+    }
 }
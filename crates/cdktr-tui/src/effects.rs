@@ -3,12 +3,17 @@
 use crate::actions::Action;
 use crate::dispatcher::Dispatcher;
 use crate::stores::{LogViewerStore, WorkflowsStore};
-use cdktr_api::{API, PrincipalAPI, models::WorkflowStatusUpdate};
-use cdktr_core::get_cdktr_setting;
+use cdktr_api::{
+    API, ConnectionMonitor, PrincipalAPI,
+    models::{ClientResponseMessage, WorkflowStatusUpdate},
+    retry_with_monitor,
+};
+use cdktr_core::{exceptions::GenericError, get_cdktr_setting, utils::get_default_zmq_timeout};
 use cdktr_ipc::log_manager::{client::LogsClient, model::LogMessage};
 use cdktr_workflow::Workflow;
 use chrono::Utc;
 use std::collections::HashMap;
+use std::future::Future;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::task;
@@ -16,6 +21,9 @@ use tokio::task;
 /// Effects handler that executes side effects based on actions
 pub struct Effects {
     dispatcher: Dispatcher,
+    /// Shared by every request to the principal so that the UI can tell when the
+    /// connection drops and comes back, e.g. when the principal restarts
+    connection: ConnectionMonitor,
     log_viewer_store: Option<LogViewerStore>,
     workflows_store: Option<WorkflowsStore>,
 }
@@ -24,6 +32,7 @@ impl Effects {
     pub fn new(dispatcher: Dispatcher) -> Self {
         Self {
             dispatcher,
+            connection: ConnectionMonitor::new(),
             log_viewer_store: None,
             workflows_store: None,
         }
@@ -48,13 +57,14 @@ impl Effects {
     /// Spawn a background task to monitor registered agents
     fn spawn_agent_monitor(&self) {
         let dispatcher = self.dispatcher.clone();
+        let connection = self.connection.clone();
         let interval_ms = get_cdktr_setting!(CDKTR_TUI_STATUS_REFRESH_INTERVAL_MS, usize) as u64;
 
         task::spawn(async move {
             loop {
                 tokio::time::sleep(Duration::from_millis(interval_ms)).await;
 
-                match fetch_registered_agents().await {
+                match fetch_registered_agents(&dispatcher, &connection).await {
                     Ok(agents) => {
                         dispatcher.dispatch(Action::RegisteredAgentsUpdated(agents));
                     }
@@ -70,13 +80,14 @@ impl Effects {
     /// Spawn a background task to monitor recent workflow statuses
     fn spawn_workflow_status_monitor(&self) {
         let dispatcher = self.dispatcher.clone();
+        let connection = self.connection.clone();
         let interval_ms = get_cdktr_setting!(CDKTR_TUI_STATUS_REFRESH_INTERVAL_MS, usize) as u64;
 
         task::spawn(async move {
             loop {
                 tokio::time::sleep(Duration::from_millis(interval_ms)).await;
 
-                match fetch_recent_workflow_statuses().await {
+                match fetch_recent_workflow_statuses(&dispatcher, &connection).await {
                     Ok(status_updates) => {
                         dispatcher.dispatch(Action::RecentWorkflowStatusesUpdated(status_updates));
                     }
@@ -106,6 +117,7 @@ impl Effects {
     /// Spawn a background task to refresh workflows periodically
     fn spawn_workflow_refresh(&self) {
         let dispatcher = self.dispatcher.clone();
+        let connection = self.connection.clone();
         let interval_ms = get_cdktr_setting!(CDKTR_TUI_STATUS_REFRESH_INTERVAL_MS, usize) as u64;
 
         task::spawn(async move {
@@ -113,7 +125,7 @@ impl Effects {
                 tokio::time::sleep(Duration::from_millis(interval_ms)).await;
 
                 log::debug!("Auto-refreshing workflows from principal...");
-                match fetch_workflows_from_backend().await {
+                match fetch_workflows_from_backend(&dispatcher, &connection).await {
                     Ok(workflows) => {
                         log::debug!("Auto-refresh: loaded {} workflows", workflows.len());
                         dispatcher.dispatch(Action::WorkflowListLoaded(workflows));
@@ -158,10 +170,11 @@ impl Effects {
     /// Fetch workflows from the backend via ZMQ
     fn fetch_workflows(&self) {
         let dispatcher = self.dispatcher.clone();
+        let connection = self.connection.clone();
 
         task::spawn(async move {
             log::info!("Fetching workflows from backend...");
-            let result = fetch_workflows_from_backend().await;
+            let result = fetch_workflows_from_backend(&dispatcher, &connection).await;
 
             match result {
                 Ok(workflows) => {
@@ -210,6 +223,7 @@ impl Effects {
     /// Query logs from the backend based on time range
     fn query_logs(&self) {
         let dispatcher = self.dispatcher.clone();
+        let connection = self.connection.clone();
 
         // Get time range and workflow_id from log viewer store
         let (start_ts, end_ts, workflow_id, verbose) =
@@ -233,7 +247,16 @@ impl Effects {
         task::spawn(async move {
             log::info!("Querying logs from {} to {}", start_ts, end_ts);
 
-            match query_logs_from_backend(start_ts, end_ts, workflow_id, verbose).await {
+            match query_logs_from_backend(
+                &dispatcher,
+                &connection,
+                start_ts,
+                end_ts,
+                workflow_id,
+                verbose,
+            )
+            .await
+            {
                 Ok(logs) => {
                     log::info!("Successfully queried {} log entries", logs.len());
                     dispatcher.dispatch(Action::QueryLogsResult(logs));
@@ -247,8 +270,53 @@ impl Effects {
     }
 }
 
+/// Send a request to the principal, reconnecting and retrying it while the principal
+/// can't be reached, e.g. while it restarts
+async fn send_reconnecting(
+    dispatcher: &Dispatcher,
+    connection: &ConnectionMonitor,
+    api_msg: PrincipalAPI,
+) -> Result<ClientResponseMessage, GenericError> {
+    reconnecting(dispatcher, connection, get_default_zmq_timeout(), || {
+        api_msg.clone().send()
+    })
+    .await
+}
+
+/// Retry a request up to `CDKTR_TUI_RECONNECT_ATTEMPTS` times while the principal can't
+/// be reached. The UI shows it is reconnecting from the first retry until the request
+/// goes through or the attempts run out
+async fn reconnecting<T, F, Fut>(
+    dispatcher: &Dispatcher,
+    connection: &ConnectionMonitor,
+    delay: Duration,
+    mut request: F,
+) -> Result<T, GenericError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, GenericError>>,
+{
+    let max_attempts = get_cdktr_setting!(CDKTR_TUI_RECONNECT_ATTEMPTS, usize).max(1);
+    let mut attempts = 0;
+    let result = retry_with_monitor(connection, max_attempts, delay, || {
+        attempts += 1;
+        if attempts == 2 {
+            dispatcher.dispatch(Action::Reconnecting(true));
+        }
+        request()
+    })
+    .await;
+    if attempts > 1 {
+        dispatcher.dispatch(Action::Reconnecting(false));
+        dispatcher.dispatch(Action::PrincipalStatusUpdated(connection.is_connected()));
+    }
+    result
+}
+
 /// Query logs from the backend (ZMQ call to PrincipalAPI)
 async fn query_logs_from_backend(
+    dispatcher: &Dispatcher,
+    connection: &ConnectionMonitor,
     start_ts: u64,
     end_ts: u64,
     workflow_id: Option<String>,
@@ -260,15 +328,19 @@ async fn query_logs_from_backend(
         workflow_id, // Use the workflow_id from the viewer
         None,        // workflow_instance_id
         verbose,     // verbose
+        None,        // page
     );
 
-    match api_msg.send().await {
+    match send_reconnecting(dispatcher, connection, api_msg).await {
         Ok(response) => {
             let payload = response.payload();
             log::debug!("Got log query payload: {} bytes", payload.len());
 
-            match serde_json::from_str::<Vec<String>>(&payload) {
-                Ok(logs) => Ok(logs),
+            match serde_json::from_str::<Vec<LogMessage>>(&payload) {
+                Ok(logs) => Ok(logs
+                    .iter()
+                    .map(|l| if verbose { l.format_full() } else { l.format() })
+                    .collect()),
                 Err(e) => Err(format!("Failed to parse log data: {}", e)),
             }
         }
@@ -277,10 +349,13 @@ async fn query_logs_from_backend(
 }
 
 /// Fetch workflows from the backend (ZMQ call to PrincipalAPI)
-async fn fetch_workflows_from_backend() -> Result<Vec<Workflow>, String> {
+async fn fetch_workflows_from_backend(
+    dispatcher: &Dispatcher,
+    connection: &ConnectionMonitor,
+) -> Result<Vec<Workflow>, String> {
     let api_msg = PrincipalAPI::ListWorkflowStore;
 
-    match api_msg.send().await {
+    match send_reconnecting(dispatcher, connection, api_msg).await {
         Ok(response) => {
             let payload = response.payload();
 
@@ -311,9 +386,12 @@ async fn ping_principal() -> bool {
 }
 
 /// Fetch the latest status updates for recent workflows
-async fn fetch_recent_workflow_statuses() -> Result<Vec<WorkflowStatusUpdate>, String> {
+async fn fetch_recent_workflow_statuses(
+    dispatcher: &Dispatcher,
+    connection: &ConnectionMonitor,
+) -> Result<Vec<WorkflowStatusUpdate>, String> {
     let api_msg = PrincipalAPI::GetRecentWorkflowStatuses;
-    match api_msg.send().await {
+    match send_reconnecting(dispatcher, connection, api_msg).await {
         Ok(response) => {
             let payload = response.payload();
 
@@ -327,9 +405,12 @@ async fn fetch_recent_workflow_statuses() -> Result<Vec<WorkflowStatusUpdate>, S
 }
 
 /// Fetch the list of registered agents
-async fn fetch_registered_agents() -> Result<Vec<cdktr_api::models::AgentInfo>, String> {
+async fn fetch_registered_agents(
+    dispatcher: &Dispatcher,
+    connection: &ConnectionMonitor,
+) -> Result<Vec<cdktr_api::models::AgentInfo>, String> {
     let api_msg = PrincipalAPI::GetRegisteredAgents;
-    match api_msg.send().await {
+    match send_reconnecting(dispatcher, connection, api_msg).await {
         Ok(response) => {
             let payload = response.payload();
 
@@ -347,3 +428,90 @@ async fn fetch_registered_agents() -> Result<Vec<cdktr_api::models::AgentInfo>,
 //     // TODO: Implement using PrincipalAPI::QueryLogs
 //     Ok(Vec::new())
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatcher::ActionReceiver;
+    use crate::stores::UIStore;
+
+    /// Fails as if the principal were down `failures` times before answering
+    fn restarting_principal(
+        failures: usize,
+    ) -> impl FnMut() -> std::future::Ready<Result<(), GenericError>> {
+        let mut calls = 0;
+        move || {
+            calls += 1;
+            std::future::ready(if calls > failures {
+                Ok(())
+            } else {
+                Err(GenericError::PrincipalTimeoutError)
+            })
+        }
+    }
+
+    fn drain_into(rx: &mut ActionReceiver, store: &UIStore) -> Vec<Action> {
+        let mut actions = Vec::new();
+        while let Some(action) = rx.try_recv() {
+            store.reduce(&action);
+            actions.push(action);
+        }
+        actions
+    }
+
+    #[tokio::test]
+    async fn test_ui_recovers_when_principal_restarts() {
+        let (dispatcher, rx) = Dispatcher::new();
+        let mut rx = ActionReceiver::new(rx);
+        let store = UIStore::new();
+        store.reduce(&Action::PrincipalStatusUpdated(true));
+        let connection = ConnectionMonitor::new();
+
+        // the connection drops and comes back before the attempts run out
+        reconnecting(
+            &dispatcher,
+            &connection,
+            Duration::ZERO,
+            restarting_principal(1),
+        )
+        .await
+        .unwrap();
+        let actions = drain_into(&mut rx, &store);
+        assert!(matches!(actions[0], Action::Reconnecting(true)));
+        let state = store.get_state();
+        assert!(state.principal_online);
+        assert!(!state.reconnecting);
+        assert_eq!(connection.reconnects(), 1);
+
+        // while it stays down the call fails and the UI shows it is disconnected
+        assert!(
+            reconnecting(
+                &dispatcher,
+                &connection,
+                Duration::ZERO,
+                restarting_principal(usize::MAX),
+            )
+            .await
+            .is_err()
+        );
+        drain_into(&mut rx, &store);
+        let state = store.get_state();
+        assert!(!state.principal_online);
+        assert!(!state.reconnecting);
+        assert!(state.disconnect_since.is_some());
+
+        // and the next call that gets through brings it back
+        reconnecting(
+            &dispatcher,
+            &connection,
+            Duration::ZERO,
+            restarting_principal(1),
+        )
+        .await
+        .unwrap();
+        drain_into(&mut rx, &store);
+        let state = store.get_state();
+        assert!(state.principal_online);
+        assert!(state.disconnect_since.is_none());
+    }
+}
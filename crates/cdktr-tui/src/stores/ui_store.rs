@@ -27,6 +27,9 @@ pub struct UIState {
     /// Timestamp (Unix seconds) when the principal first went offline
     pub disconnect_since: Option<i64>,
 
+    /// Whether a request is being retried after losing the connection to the principal
+    pub reconnecting: bool,
+
     /// Command input for colon commands like :q
     pub command_input: String,
 }
@@ -41,6 +44,7 @@ impl Default for UIState {
             should_exit: false,
             principal_online: false,
             disconnect_since: None,
+            reconnecting: false,
             command_input: String::new(),
         }
     }
@@ -118,6 +122,10 @@ impl UIStore {
                 // If already disconnected, keep the original timestamp
             }
 
+            Action::Reconnecting(reconnecting) => {
+                state.reconnecting = *reconnecting;
+            }
+
             _ => {
                 // Ignore actions not relevant to this store
             }
@@ -170,4 +178,18 @@ mod tests {
 
         assert_eq!(store.should_exit(), true);
     }
+
+    #[test]
+    fn test_reconnecting() {
+        let store = UIStore::new();
+        store.reduce(&Action::PrincipalStatusUpdated(true));
+        store.reduce(&Action::Reconnecting(true));
+        assert!(store.get_state().reconnecting);
+
+        store.reduce(&Action::Reconnecting(false));
+        store.reduce(&Action::PrincipalStatusUpdated(true));
+        let state = store.get_state();
+        assert!(!state.reconnecting);
+        assert!(state.principal_online);
+    }
 }
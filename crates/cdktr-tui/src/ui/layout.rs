@@ -163,6 +163,9 @@ fn render_admin_content(frame: &mut Frame, area: Rect, app_logs_store: &AppLogsS
     admin_panel.render(area, frame.buffer_mut());
 }
 
+/// Frames of the spinner shown while reconnecting to the principal
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
 fn render_header(
     frame: &mut Frame,
     area: Rect,
@@ -175,6 +178,10 @@ fn render_header(
     let status_string;
     let status = if workflows_state.is_loading {
         "Loading..."
+    } else if ui_state.reconnecting {
+        let frame = (chrono::Utc::now().timestamp_millis() / 100) as usize % SPINNER.len();
+        status_string = format!("Reconnecting {}", SPINNER[frame]);
+        &status_string
     } else if let Some(err) = &workflows_state.error {
         err.as_str()
     } else if !ui_state.principal_online {
@@ -189,7 +196,9 @@ fn render_header(
         "Connected"
     };
 
-    let status_color = if workflows_state.error.is_some() {
+    let status_color = if ui_state.reconnecting {
+        Color::Yellow
+    } else if workflows_state.error.is_some() {
         Color::Red
     } else if workflows_state.is_loading {
         Color::Yellow
@@ -53,7 +53,7 @@ impl RunInfoPanel {
                     .iter()
                     .filter(|status| {
                         regex.is_match(status.workflow_id())
-                            || regex.is_match(status.workflow_instance_id())
+                            || regex.is_match(status.workflow_instance_id().as_str())
                     })
                     .collect(),
                 Err(_) => self.recent_statuses.iter().collect(), // Invalid regex, show all
@@ -107,7 +107,7 @@ impl RunInfoPanel {
 
                 Row::new(vec![
                     Cell::from(status.workflow_id()),
-                    Cell::from(status.workflow_instance_id()),
+                    Cell::from(status.workflow_instance_id().as_str()),
                     Cell::from(status_str).style(Style::default().fg(status_color)),
                     Cell::from(formatted_time),
                 ])
@@ -0,0 +1,150 @@
+use cdktr_core::exceptions::GenericError;
+use chrono::{DateTime, Duration, FixedOffset, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Time of day, such as `09:00-17:00`, during which scheduled runs don't fire. Fires that
+/// fall inside the window are deferred to its end. Times are in UTC, the same as cron
+/// schedules, unless the window ends with an offset, e.g. `09:00-17:00 +01:00`. Windows
+/// whose end is before their start cross midnight
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct BlackoutWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    offset: FixedOffset,
+}
+
+impl BlackoutWindow {
+    /// When the window ends if `at` falls inside it
+    pub fn end_after(&self, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = at.with_timezone(&self.offset);
+        let time = local.time();
+        let date = local.date_naive();
+        let end_date = if self.start < self.end {
+            (self.start <= time && time < self.end).then_some(date)
+        } else if time >= self.start {
+            Some(date + Duration::days(1))
+        } else {
+            (time < self.end).then_some(date)
+        }?;
+        self.offset
+            .from_local_datetime(&end_date.and_time(self.end))
+            .single()
+            .map(|end| end.to_utc())
+    }
+}
+
+/// Defers a fire that falls inside any of the windows to the first time after it that is
+/// outside all of them
+pub fn defer_past_blackout(at: DateTime<Utc>, windows: &[BlackoutWindow]) -> DateTime<Utc> {
+    let mut deferred = at;
+    // windows can overlap each other, but can't push the fire back more than once each
+    for _ in 0..=windows.len() {
+        match windows.iter().find_map(|w| w.end_after(deferred)) {
+            Some(end) => deferred = end,
+            None => break,
+        }
+    }
+    deferred
+}
+
+/// Parses a comma-separated list of windows, as in `CDKTR_SCHEDULER_BLACKOUT`
+pub fn parse_blackout_windows(windows: &str) -> Result<Vec<BlackoutWindow>, GenericError> {
+    windows
+        .split(',')
+        .map(str::trim)
+        .filter(|window| !window.is_empty())
+        .map(BlackoutWindow::from_str)
+        .collect()
+}
+
+impl FromStr for BlackoutWindow {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            GenericError::ParseError(format!(
+                "'{}' is not a valid blackout window. {}. Expected e.g. 09:00-17:00 or 09:00-17:00 +01:00",
+                s, reason
+            ))
+        };
+        let (times, offset) = match s.trim().split_once(' ') {
+            Some((times, offset)) => (times, offset.trim()),
+            None => (s.trim(), "+00:00"),
+        };
+        let offset = match offset {
+            utc if utc.eq_ignore_ascii_case("utc") => FixedOffset::east_opt(0).unwrap(),
+            offset => offset
+                .parse::<FixedOffset>()
+                .map_err(|_| invalid("Invalid offset"))?,
+        };
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| invalid("Missing '-' between the start and end"))?;
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|_| invalid("Invalid time"))
+        };
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end {
+            return Err(invalid("The start and end are the same"));
+        }
+        Ok(Self { start, end, offset })
+    }
+}
+
+impl TryFrom<String> for BlackoutWindow {
+    type Error = GenericError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<BlackoutWindow> for String {
+    fn from(window: BlackoutWindow) -> Self {
+        format!(
+            "{}-{} {}",
+            window.start.format("%H:%M"),
+            window.end.format("%H:%M"),
+            window.offset
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(h: u32, m: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 1, 20, h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn test_fires_inside_window_deferred_to_its_end() {
+        let windows = parse_blackout_windows("09:00-17:00").unwrap();
+        assert_eq!(defer_past_blackout(at(12, 30), &windows), at(17, 0));
+        assert_eq!(defer_past_blackout(at(9, 0), &windows), at(17, 0));
+        assert_eq!(defer_past_blackout(at(17, 0), &windows), at(17, 0));
+        assert_eq!(defer_past_blackout(at(8, 59), &windows), at(8, 59));
+    }
+
+    #[test]
+    fn test_window_across_midnight_and_offset() {
+        let windows = parse_blackout_windows("22:00-02:00, 09:00-17:00 +01:00").unwrap();
+        assert_eq!(
+            defer_past_blackout(at(23, 0), &windows),
+            Utc.with_ymd_and_hms(2025, 1, 21, 2, 0, 0).unwrap()
+        );
+        assert_eq!(defer_past_blackout(at(1, 0), &windows), at(2, 0));
+        // 08:30 UTC is 09:30 at +01:00, so it waits until 17:00 there
+        assert_eq!(defer_past_blackout(at(8, 30), &windows), at(16, 0));
+        assert_eq!(defer_past_blackout(at(16, 0), &windows), at(16, 0));
+    }
+
+    #[test]
+    fn test_invalid_windows() {
+        for window in ["9-17", "09:00", "09:00-09:00", "09:00-17:00 CET"] {
+            assert!(parse_blackout_windows(window).is_err(), "{window}");
+        }
+        assert!(parse_blackout_windows("").unwrap().is_empty());
+    }
+}
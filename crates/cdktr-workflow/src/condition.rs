@@ -0,0 +1,364 @@
+use cdktr_core::exceptions::GenericError;
+
+/// Prefix of the placeholder used to reference the output of another task in a condition
+const OUTPUT_PLACEHOLDER_PREFIX: &str = "${outputs.";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Str(String),
+    Word(String),
+    Eq,
+    Ne,
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Literal(String),
+    /// output of the task with this id
+    Output(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Value(Operand),
+    Eq(Operand, Operand),
+    Ne(Operand, Operand),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+/// A parsed `when` condition of a task. Conditions are deliberately small: string
+/// comparisons with `==` and `!=`, combined with `&&`/`and`, `||`/`or`, `!`/`not` and
+/// parentheses. Values are either quoted strings, bare words such as `true` or `deploy`,
+/// or `${outputs.<task_id>}` which resolves to the output of another task. All values
+/// are compared as strings
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(condition: &str) -> Result<Self, GenericError> {
+        let tokens = tokenize(condition)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(condition_error(
+                condition,
+                &format!("unexpected {:?}", token),
+            ));
+        }
+        Ok(Self { expr })
+    }
+
+    /// Ids of the tasks whose output the condition references
+    pub fn output_refs(&self) -> Vec<&str> {
+        let mut refs = Vec::new();
+        collect_refs(&self.expr, &mut refs);
+        refs
+    }
+
+    /// Evaluates the condition. `outputs` resolves the output of a task by its id; outputs
+    /// of tasks that haven't produced any are treated as an empty string
+    pub fn evaluate(&self, outputs: &dyn Fn(&str) -> Option<String>) -> Result<bool, GenericError> {
+        eval(&self.expr, outputs)
+    }
+}
+
+/// Quotes a value so that it is read as a single string literal when substituted into a
+/// condition, whatever characters it contains
+pub(crate) fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn condition_error(condition: &str, reason: &str) -> GenericError {
+    GenericError::WorkflowError(format!("Invalid condition '{}': {}", condition, reason))
+}
+
+fn tokenize(condition: &str) -> Result<Vec<Token>, GenericError> {
+    let mut tokens = Vec::new();
+    let mut chars = condition.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            '\'' | '"' => {
+                let quote = c;
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => s.push(escaped),
+                            None => return Err(condition_error(condition, "unterminated string")),
+                        },
+                        Some(ch) if ch == quote => break,
+                        Some(ch) => s.push(ch),
+                        None => return Err(condition_error(condition, "unterminated string")),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            '=' | '!' | '&' | '|' => {
+                chars.next();
+                let next = chars.peek().copied();
+                let token = match (c, next) {
+                    ('=', Some('=')) => Token::Eq,
+                    ('!', Some('=')) => Token::Ne,
+                    ('&', Some('&')) => Token::And,
+                    ('|', Some('|')) => Token::Or,
+                    ('!', _) => Token::Not,
+                    _ => {
+                        return Err(condition_error(
+                            condition,
+                            &format!("unexpected character '{}'", c),
+                        ));
+                    }
+                };
+                if token != Token::Not {
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_whitespace() || "()'\"=!&|".contains(ch) {
+                        break;
+                    }
+                    word.push(ch);
+                    chars.next();
+                }
+                tokens.push(match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Word(word),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn error(&self, reason: &str) -> GenericError {
+        GenericError::WorkflowError(format!("Invalid condition: {}", reason))
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, GenericError> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, GenericError> {
+        let mut expr = self.parse_not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, GenericError> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, GenericError> {
+        if self.peek() == Some(&Token::LParen) {
+            self.next();
+            let expr = self.parse_or()?;
+            return match self.next() {
+                Some(Token::RParen) => Ok(expr),
+                _ => Err(self.error("missing closing parenthesis")),
+            };
+        }
+        let left = self.parse_operand()?;
+        match self.peek() {
+            Some(Token::Eq) => {
+                self.next();
+                Ok(Expr::Eq(left, self.parse_operand()?))
+            }
+            Some(Token::Ne) => {
+                self.next();
+                Ok(Expr::Ne(left, self.parse_operand()?))
+            }
+            _ => Ok(Expr::Value(left)),
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, GenericError> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Operand::Literal(s)),
+            Some(Token::Word(word)) => {
+                match word
+                    .strip_prefix(OUTPUT_PLACEHOLDER_PREFIX)
+                    .and_then(|rest| rest.strip_suffix('}'))
+                {
+                    Some(task_id) => Ok(Operand::Output(task_id.to_string())),
+                    None => Ok(Operand::Literal(word)),
+                }
+            }
+            Some(token) => Err(self.error(&format!("expected a value but found {:?}", token))),
+            None => Err(self.error("expected a value but reached the end")),
+        }
+    }
+}
+
+fn collect_refs<'a>(expr: &'a Expr, refs: &mut Vec<&'a str>) {
+    fn add<'a>(operand: &'a Operand, refs: &mut Vec<&'a str>) {
+        if let Operand::Output(task_id) = operand {
+            refs.push(task_id.as_str());
+        }
+    }
+    match expr {
+        Expr::Value(v) => add(v, refs),
+        Expr::Eq(l, r) | Expr::Ne(l, r) => {
+            add(l, refs);
+            add(r, refs);
+        }
+        Expr::Not(e) => collect_refs(e, refs),
+        Expr::And(l, r) | Expr::Or(l, r) => {
+            collect_refs(l, refs);
+            collect_refs(r, refs);
+        }
+    }
+}
+
+fn resolve(operand: &Operand, outputs: &dyn Fn(&str) -> Option<String>) -> String {
+    match operand {
+        Operand::Literal(s) => s.clone(),
+        Operand::Output(task_id) => outputs(task_id).unwrap_or_default(),
+    }
+}
+
+fn eval(expr: &Expr, outputs: &dyn Fn(&str) -> Option<String>) -> Result<bool, GenericError> {
+    match expr {
+        Expr::Value(operand) => {
+            let value = resolve(operand, outputs);
+            match value.as_str() {
+                "true" => Ok(true),
+                "false" => Ok(false),
+                _ => Err(GenericError::WorkflowError(format!(
+                    "Condition value '{}' is not a boolean. Compare it with == or != instead",
+                    value
+                ))),
+            }
+        }
+        Expr::Eq(l, r) => Ok(resolve(l, outputs) == resolve(r, outputs)),
+        Expr::Ne(l, r) => Ok(resolve(l, outputs) != resolve(r, outputs)),
+        Expr::Not(e) => Ok(!eval(e, outputs)?),
+        Expr::And(l, r) => Ok(eval(l, outputs)? && eval(r, outputs)?),
+        Expr::Or(l, r) => Ok(eval(l, outputs)? || eval(r, outputs)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn eval_with(condition: &str, outputs: &[(&str, &str)]) -> Result<bool, GenericError> {
+        let outputs: HashMap<String, String> = outputs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Condition::parse(condition)?.evaluate(&|task_id| outputs.get(task_id).cloned())
+    }
+
+    #[test]
+    fn test_conditions() {
+        let outputs = [("check", "deploy"), ("flag", "true")];
+        let cases = [
+            ("${outputs.check} == 'deploy'", true),
+            ("${outputs.check} != 'deploy'", false),
+            ("${outputs.check} == \"skip\"", false),
+            ("${outputs.flag}", true),
+            ("!${outputs.flag}", false),
+            ("not (${outputs.check} == deploy)", false),
+            ("${outputs.check} == 'skip' || ${outputs.flag}", true),
+            (
+                "${outputs.check} == 'deploy' and ${outputs.flag} == 'false'",
+                false,
+            ),
+            ("(true || false) && !false", true),
+            ("${outputs.missing} == ''", true),
+            ("'it\\'s' == \"it's\"", true),
+        ];
+        for (condition, expected) in cases {
+            assert_eq!(
+                eval_with(condition, &outputs).unwrap(),
+                expected,
+                "Failed on condition: {}",
+                condition
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_conditions() {
+        for condition in [
+            "",
+            "${outputs.check} ==",
+            "(true",
+            "'unterminated",
+            "a = b",
+            "true false",
+        ] {
+            assert!(
+                Condition::parse(condition).is_err(),
+                "Expected parse failure for: {}",
+                condition
+            );
+        }
+        // non-boolean values can't be used on their own
+        assert!(eval_with("${outputs.check}", &[("check", "deploy")]).is_err());
+    }
+
+    #[test]
+    fn test_output_refs_and_quoting() {
+        let condition =
+            Condition::parse("${outputs.a} == 'x' || ${outputs.b} != ${outputs.a}").unwrap();
+        assert_eq!(condition.output_refs(), vec!["a", "b", "a"]);
+        let quoted = format!("{} == 'it\\'s'", quote_literal("it's"));
+        assert!(eval_with(&quoted, &[]).unwrap());
+    }
+}
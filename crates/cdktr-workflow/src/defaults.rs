@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::output_levels::{LogLevelPattern, OutputLogLevel};
+
+/// Settings under the `defaults` of a workflow that every task of the workflow inherits
+/// unless the task sets them itself. `env` is merged into the env of each task and
+/// `secrets`, `log_level_patterns` and `redact` are added to those of each task, with the
+/// values of the task taking precedence
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct TaskDefaults {
+    pub(crate) env: Option<HashMap<String, String>>,
+    pub(crate) clean_env: Option<bool>,
+    pub(crate) timeout_s: Option<u64>,
+    pub(crate) term_grace_s: Option<u64>,
+    pub(crate) cache: Option<bool>,
+    pub(crate) secrets: Option<Vec<String>>,
+    pub(crate) stdout_log_level: Option<OutputLogLevel>,
+    pub(crate) stderr_log_level: Option<OutputLogLevel>,
+    pub(crate) log_level_patterns: Option<Vec<LogLevelPattern>>,
+    pub(crate) redact: Option<Vec<String>>,
+}
+
+/// Env of a task with the default env merged in. Variables the task sets itself win
+pub(crate) fn merge_env(
+    defaults: Option<&HashMap<String, String>>,
+    env: Option<HashMap<String, String>>,
+) -> Option<HashMap<String, String>> {
+    match defaults {
+        Some(defaults) => {
+            let mut merged = defaults.clone();
+            merged.extend(env.unwrap_or_default());
+            Some(merged)
+        }
+        None => env,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executors::ExecutableTask;
+    use crate::{OutputLogLevel, Workflow};
+
+    fn workflow() -> Workflow {
+        let yaml = r#"
+name: Reports
+defaults:
+  timeout_s: 600
+  cache: true
+  secrets: ["DB_PASSWORD"]
+  stderr_log_level: WARN
+  env:
+    REGION: eu-west-1
+    STAGE: prod
+tasks:
+  extract:
+    name: Extract
+    config:
+      !Subprocess
+      cmd: ./extract.sh
+      args: []
+  report:
+    name: Report
+    depends: ["extract"]
+    cache: false
+    secrets: ["SMTP_PASSWORD"]
+    stderr_log_level: ERROR
+    config:
+      !UvPython
+      script_path: report.py
+      timeout_s: 60
+      env:
+        STAGE: dev
+        REPORT: daily
+"#;
+        Workflow::new("fake/path/reports.yml".to_string(), yaml).unwrap()
+    }
+
+    #[test]
+    fn test_tasks_inherit_defaults() {
+        let workflow = workflow();
+        let task = workflow.get_task("extract").unwrap();
+        let config = match task.get_exe_task() {
+            ExecutableTask::Subprocess(config) => config,
+            _ => panic!("Wrong enum type"),
+        };
+        assert_eq!(config.timeout_s, Some(600));
+        assert_eq!(
+            config.env.unwrap(),
+            [("REGION", "eu-west-1"), ("STAGE", "prod")]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        );
+        assert!(task.cache());
+        assert_eq!(task.secret_refs(), vec!["DB_PASSWORD"]);
+        assert_eq!(
+            task.output_log_levels().unwrap().stderr_level("retrying"),
+            OutputLogLevel::Warn
+        );
+    }
+
+    #[test]
+    fn test_tasks_override_defaults() {
+        let workflow = workflow();
+        let task = workflow.get_task("report").unwrap();
+        let config = match task.get_exe_task() {
+            ExecutableTask::UvPython(config) => config,
+            _ => panic!("Wrong enum type"),
+        };
+        assert_eq!(config.timeout_s, Some(60));
+        // env is merged, with the variables of the task winning
+        assert_eq!(
+            config.env.unwrap(),
+            [
+                ("REGION", "eu-west-1"),
+                ("STAGE", "dev"),
+                ("REPORT", "daily")
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+        );
+        assert!(!task.cache());
+        assert_eq!(task.secret_refs(), vec!["SMTP_PASSWORD", "DB_PASSWORD"]);
+        assert_eq!(
+            task.output_log_levels().unwrap().stderr_level("retrying"),
+            OutputLogLevel::Error
+        );
+    }
+}
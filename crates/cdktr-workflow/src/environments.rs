@@ -0,0 +1,104 @@
+use serde_norway::Value;
+
+/// Patches a workflow definition with the overlay of one of its `environments`. Mappings
+/// are merged key by key, so an overlay only needs the settings that differ, and any other
+/// value in the overlay replaces the one in the definition. A task config can be patched
+/// without repeating its `!Subprocess` or `!UvPython` tag
+pub fn apply_overlay(definition: &mut Value, overlay: Value) {
+    match (definition, overlay) {
+        (Value::Mapping(definition), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match definition.get_mut(&key) {
+                    Some(existing) => apply_overlay(existing, value),
+                    None => {
+                        definition.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Tagged(definition), Value::Tagged(overlay)) if definition.tag == overlay.tag => {
+            apply_overlay(&mut definition.value, overlay.value)
+        }
+        (Value::Tagged(definition), overlay @ Value::Mapping(_)) => {
+            apply_overlay(&mut definition.value, overlay)
+        }
+        (definition, overlay) => *definition = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Workflow;
+    use crate::executors::ExecutableTask;
+
+    const YAML: &str = r#"
+name: Load Orders
+start_time: 2025-01-20T12:30:00+00:00
+defaults:
+  env:
+    STAGE: dev
+tasks:
+  load:
+    name: Load
+    config:
+      !Subprocess
+      cmd: ./load.sh
+      args: ["--dry"]
+environments:
+  staging:
+    defaults:
+      env:
+        STAGE: staging
+  prod:
+    defaults:
+      env:
+        STAGE: prod
+    tasks:
+      load:
+        config:
+          cmd: ./load-prod.sh
+          args: []
+"#;
+
+    fn resolved(workflow: &Workflow) -> (String, Vec<String>, String) {
+        match workflow.get_task("load").unwrap().get_exe_task() {
+            ExecutableTask::Subprocess(task) => (
+                task.cmd.clone(),
+                task.args.clone(),
+                task.env.as_ref().unwrap()["STAGE"].clone(),
+            ),
+            other => panic!("Expected a subprocess task, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_environment_overlays() {
+        let workflow = Workflow::new("fake/path/orders.yml".to_string(), YAML).unwrap();
+        workflow.validate().unwrap();
+        assert_eq!(
+            resolved(&workflow),
+            (
+                "./load.sh".to_string(),
+                vec!["--dry".to_string()],
+                "dev".to_string()
+            )
+        );
+        let staging = workflow.for_environment("staging").unwrap();
+        assert_eq!(
+            resolved(&staging),
+            (
+                "./load.sh".to_string(),
+                vec!["--dry".to_string()],
+                "staging".to_string()
+            )
+        );
+        let prod = workflow.for_environment("prod").unwrap();
+        assert_eq!(prod.id(), workflow.id());
+        assert_eq!(
+            resolved(&prod),
+            ("./load-prod.sh".to_string(), vec![], "prod".to_string())
+        );
+        let err = workflow.for_environment("qa").unwrap_err();
+        assert!(err.to_string().contains("no environment 'qa'"), "{err}");
+    }
+}
@@ -1,12 +1,22 @@
 use async_trait::async_trait;
+use cdktr_core::get_cdktr_setting;
 use cdktr_core::models::{FlowExecutionResult, traits};
+use log::warn;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::defaults::{TaskDefaults, merge_env};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
 use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
 
 mod subprocess;
 mod uv_python;
 
-pub use subprocess::SubprocessTask;
+pub use subprocess::{SubprocessTask, shell_command};
 pub use uv_python::UvPythonTask;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -15,6 +25,316 @@ pub enum ExecutableTask {
     UvPython(UvPythonTask),
 }
 
+impl ExecutableTask {
+    /// Sets an environment variable on the process the task runs in
+    pub(crate) fn set_env(&mut self, name: &str, value: &str) {
+        let env = match self {
+            ExecutableTask::Subprocess(sptask) => &mut sptask.env,
+            ExecutableTask::UvPython(uvptask) => &mut uvptask.env,
+        };
+        env.get_or_insert_with(HashMap::new)
+            .insert(name.to_string(), value.to_string());
+    }
+
+    /// Fills in the process settings the task doesn't set itself from the defaults of its
+    /// workflow
+    pub(crate) fn apply_defaults(&mut self, defaults: &TaskDefaults) {
+        let (env, clean_env, timeout_s, term_grace_s) = match self {
+            ExecutableTask::Subprocess(sptask) => (
+                &mut sptask.env,
+                &mut sptask.clean_env,
+                &mut sptask.timeout_s,
+                &mut sptask.term_grace_s,
+            ),
+            ExecutableTask::UvPython(uvptask) => (
+                &mut uvptask.env,
+                &mut uvptask.clean_env,
+                &mut uvptask.timeout_s,
+                &mut uvptask.term_grace_s,
+            ),
+        };
+        *env = merge_env(defaults.env.as_ref(), env.take());
+        *clean_env = clean_env.or(defaults.clean_env);
+        *timeout_s = timeout_s.or(defaults.timeout_s);
+        *term_grace_s = term_grace_s.or(defaults.term_grace_s);
+    }
+
+    /// The command line the task would be run with on this agent, along with any env vars
+    /// and working directory it sets. Used for dry runs
+    pub fn describe(&self) -> String {
+        match self {
+            ExecutableTask::Subprocess(sptask) => sptask.describe(),
+            ExecutableTask::UvPython(uvptask) => uvptask.describe(),
+        }
+    }
+
+    /// Directory the task's process runs in, if it sets one rather than using the agent's
+    pub fn working_directory(&self) -> Option<&str> {
+        match self {
+            ExecutableTask::Subprocess(_) => None,
+            ExecutableTask::UvPython(uvptask) => uvptask.working_directory.as_deref(),
+        }
+    }
+
+    /// Name of the executor that runs the task, as agents list it in
+    /// `CDKTR_AGENT_EXECUTORS`
+    pub fn executor(&self) -> &'static str {
+        match self {
+            ExecutableTask::Subprocess(_) => "Subprocess",
+            ExecutableTask::UvPython(_) => "UvPython",
+        }
+    }
+}
+
+/// Names of every executor tasks can be run with
+const EXECUTORS: [&str; 2] = ["Subprocess", "UvPython"];
+
+/// Executors this agent runs tasks with, from `CDKTR_AGENT_EXECUTORS`. `None` when the
+/// setting is empty, in which case the agent runs every executor
+pub fn agent_executors() -> Option<Vec<String>> {
+    parse_executors(&get_cdktr_setting!(CDKTR_AGENT_EXECUTORS))
+}
+
+fn parse_executors(setting: &str) -> Option<Vec<String>> {
+    let executors: Vec<String> = setting
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    for name in &executors {
+        if !EXECUTORS.contains(&name.as_str()) {
+            warn!(
+                "Unknown executor {name} in CDKTR_AGENT_EXECUTORS - expected one of {}",
+                EXECUTORS.join(", ")
+            );
+        }
+    }
+    (!executors.is_empty()).then_some(executors)
+}
+
+/// Variables of the agent's environment that are still passed to tasks run with `clean_env`
+const CLEAN_ENV_VARS: [&str; 2] = ["PATH", "HOME"];
+
+/// Sets the environment of a task process. The process inherits the agent's environment
+/// unless `clean_env` is set, in which case it only gets the task's `env` along with
+/// the agent's PATH and HOME
+fn apply_env(cmd: &mut Command, env: Option<&HashMap<String, String>>, clean_env: bool) {
+    if clean_env {
+        cmd.env_clear();
+        for name in CLEAN_ENV_VARS {
+            if let Some(value) = std::env::var_os(name) {
+                cmd.env(name, value);
+            }
+        }
+    }
+    if let Some(env) = env {
+        cmd.envs(env);
+    }
+}
+
+/// Formats a command as `program args.. [env: K=V, ..] [cwd: dir]`
+fn describe_command(cmd: &Command) -> String {
+    let cmd = cmd.as_std();
+    let mut description: Vec<String> = std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|part| part.to_string_lossy().to_string())
+        .collect();
+    let mut envs: Vec<String> = cmd
+        .get_envs()
+        .filter_map(|(name, value)| {
+            Some(format!(
+                "{}={}",
+                name.to_string_lossy(),
+                value?.to_string_lossy()
+            ))
+        })
+        .collect();
+    if !envs.is_empty() {
+        envs.sort();
+        description.push(format!("[env: {}]", envs.join(", ")));
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        description.push(format!("[cwd: {}]", dir.display()));
+    }
+    description.join(" ")
+}
+
+/// Whether the agent is shutting down, so running task processes should be stopped
+static SHUTDOWN: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+/// Number of task processes currently running on the agent
+static RUNNING: LazyLock<watch::Sender<usize>> = LazyLock::new(|| watch::channel(0).0);
+
+/// Stops every running task process, giving each its grace period to exit after SIGTERM,
+/// and waits for them all to exit. Used when the agent shuts down
+pub async fn stop_running_tasks() {
+    SHUTDOWN.send_replace(true);
+    let _ = RUNNING.subscribe().wait_for(|running| *running == 0).await;
+}
+
+/// Counts a task process as running until dropped
+struct RunningGuard;
+
+impl RunningGuard {
+    fn acquire() -> Self {
+        RUNNING.send_modify(|running| *running += 1);
+        Self
+    }
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.send_modify(|running| *running -= 1);
+    }
+}
+
+/// How a process ended after being asked to stop
+#[derive(Debug, PartialEq)]
+pub enum Termination {
+    /// The process exited by itself within its grace period
+    Exited,
+    /// The process was still running after its grace period and was killed
+    Killed,
+}
+
+/// Limits on how long a task process can run and how it is stopped
+#[derive(Debug, Clone, Copy)]
+struct StopPolicy {
+    timeout: Option<Duration>,
+    term_grace: Duration,
+}
+
+impl StopPolicy {
+    fn new(timeout_s: Option<u64>, term_grace_s: Option<u64>) -> Self {
+        Self {
+            timeout: timeout_s.map(Duration::from_secs),
+            term_grace: Duration::from_secs(
+                term_grace_s
+                    .unwrap_or_else(|| get_cdktr_setting!(CDKTR_AGENT_TERM_GRACE_S, usize) as u64),
+            ),
+        }
+    }
+}
+
+/// Spawns a task process in its own process group so that stopping it also stops any
+/// processes it started. The process is killed if its execution is dropped before it
+/// exits, e.g. when its readiness check fails
+fn spawn(cmd: &mut Command) -> std::io::Result<Child> {
+    #[cfg(unix)]
+    cmd.process_group(0);
+    cmd.kill_on_drop(true);
+    cmd.spawn()
+}
+
+/// Kills the process group of a task process whose execution is dropped before it exits,
+/// as `kill_on_drop` only kills the process itself
+struct ProcessGroupGuard(Option<u32>);
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.0 {
+            // SAFETY: kill has no memory safety preconditions
+            unsafe { libc::kill(-(pid as i32), libc::SIGKILL) };
+        }
+    }
+}
+
+/// Asks a process to stop with SIGTERM, sent to its whole process group, and kills it if
+/// it hasn't exited after `grace`
+pub async fn terminate(child: &mut Child, grace: Duration) -> std::io::Result<Termination> {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: kill has no memory safety preconditions
+        unsafe { libc::kill(-(pid as i32), libc::SIGTERM) };
+        if tokio::time::timeout(grace, child.wait()).await.is_ok() {
+            return Ok(Termination::Exited);
+        }
+        // SAFETY: as above
+        unsafe { libc::kill(-(pid as i32), libc::SIGKILL) };
+    }
+    child.kill().await?;
+    Ok(Termination::Killed)
+}
+
+/// Forwards the output of a task process and waits for it to exit. The process is stopped
+/// with its grace period if it runs past its timeout or the agent shuts down
+async fn supervise(
+    mut child: Child,
+    policy: StopPolicy,
+    stdout_tx: Sender<String>,
+    stderr_tx: Sender<String>,
+) -> FlowExecutionResult {
+    let _running = RunningGuard::acquire();
+    let mut group = ProcessGroupGuard(child.id());
+    let stdout = child.stdout.take().expect("unable to acquire stdout");
+    let stderr = child.stderr.take().expect("unable to acquire stderr");
+    let forward = tokio::spawn(async move {
+        let mut stdout_reader = BufReader::new(stdout).lines();
+        let mut stderr_reader = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = stdout_reader.next_line().await {
+            let _ = stdout_tx.send(line).await;
+        }
+        while let Ok(Some(line)) = stderr_reader.next_line().await {
+            let _ = stderr_tx.send(line).await;
+        }
+    });
+    let mut shutdown = SHUTDOWN.subscribe();
+    let shutting_down = async {
+        // the guard borrowing the flag isn't held so the future stays Send
+        let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+    };
+    let timed_out = async {
+        match policy.timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    let result = tokio::select! {
+        exit_status = child.wait() => match exit_status {
+            Ok(exit_status) => match exit_status.success() {
+                true => FlowExecutionResult::SUCCESS,
+                false => {
+                    FlowExecutionResult::FAILURE("Process failed".to_string(), exit_status.code())
+                }
+            },
+            Err(e) => {
+                FlowExecutionResult::CRASHED(format!("Process failed to exit cleanly - {}", e))
+            }
+        },
+        _ = timed_out => {
+            let msg = format!(
+                "Process timed out after {}s",
+                policy.timeout.unwrap_or_default().as_secs()
+            );
+            stop(&mut child, policy.term_grace, &msg).await;
+            FlowExecutionResult::FAILURE(msg, None)
+        }
+        _ = shutting_down => {
+            let msg = "Process stopped as the agent is shutting down".to_string();
+            stop(&mut child, policy.term_grace, &msg).await;
+            FlowExecutionResult::CRASHED(msg)
+        }
+    };
+    group.0 = None;
+    let _ = forward.await;
+    result
+}
+
+/// Terminates a process that is being stopped early, logging if it had to be killed
+async fn stop(child: &mut Child, grace: Duration, reason: &str) {
+    match terminate(child, grace).await {
+        Ok(Termination::Exited) => (),
+        Ok(Termination::Killed) => warn!(
+            "{} - killed as it was still running {}s after SIGTERM",
+            reason,
+            grace.as_secs()
+        ),
+        Err(e) => warn!("{} - failed to kill it: {}", reason, e),
+    }
+}
+
 #[async_trait]
 impl traits::Executor for ExecutableTask {
     async fn run(
@@ -1,17 +1,78 @@
 use async_trait::async_trait;
+use cdktr_core::get_cdktr_setting;
 use cdktr_core::models::{FlowExecutionResult, traits};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
 use std::process::Stdio;
-use tokio::{
-    io::{AsyncBufReadExt, BufReader},
-    process::Command,
-    sync::mpsc::Sender,
-};
+use tokio::{process::Command, sync::mpsc::Sender};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct SubprocessTask {
     pub cmd: String,
     pub args: Vec<String>,
+    /// OS user to run the process as. Requires user switching to be enabled on the
+    /// agent with CDKTR_AGENT_ALLOW_RUN_AS_USER and the agent to be running as root
+    pub run_as_user: Option<String>,
+    /// Extra environment variables set on the process
+    pub env: Option<HashMap<String, String>>,
+    /// Start the process with only `env` and the agent's PATH and HOME rather than the
+    /// agent's whole environment. Defaults to false
+    pub clean_env: Option<bool>,
+    /// Run `cmd` and `args` as a command line through the agent's shell, set with
+    /// CDKTR_AGENT_DEFAULT_SHELL, rather than spawning `cmd` directly
+    pub shell: Option<bool>,
+    /// Seconds the process can run for before it is stopped and the task fails
+    pub timeout_s: Option<u64>,
+    /// Seconds the process is given to exit after SIGTERM before it is killed when it is
+    /// stopped. Defaults to the agent's CDKTR_AGENT_TERM_GRACE_S
+    pub term_grace_s: Option<u64>,
+}
+
+impl SubprocessTask {
+    /// Builds the command to spawn, running the command line through `shell` in shell mode
+    fn build_command(&self, shell: &str) -> Command {
+        let mut cmd = if self.shell.unwrap_or(false) {
+            let command_line: Vec<&str> = std::iter::once(self.cmd.as_str())
+                .chain(self.args.iter().map(String::as_str))
+                .collect();
+            shell_command(shell, &command_line.join(" "))
+        } else {
+            let mut cmd = Command::new(&self.cmd);
+            cmd.args(&self.args);
+            cmd
+        };
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        super::apply_env(&mut cmd, self.env.as_ref(), self.clean_env.unwrap_or(false));
+        cmd
+    }
+
+    /// The command line the task would be run with
+    pub fn describe(&self) -> String {
+        super::describe_command(&self.build_command(&get_cdktr_setting!(CDKTR_AGENT_DEFAULT_SHELL)))
+    }
+}
+
+/// Command running `command_line` through `shell`
+pub fn shell_command(shell: &str, command_line: &str) -> Command {
+    let mut cmd = Command::new(shell);
+    cmd.arg(shell_command_flag(shell));
+    cmd.arg(command_line);
+    cmd
+}
+
+/// Flag that makes the shell run the command line passed after it
+fn shell_command_flag(shell: &str) -> &'static str {
+    let name = Path::new(shell)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(shell);
+    if name.eq_ignore_ascii_case("cmd") {
+        "/C"
+    } else {
+        "-c"
+    }
 }
 
 #[async_trait]
@@ -21,37 +82,22 @@ impl traits::Executor for SubprocessTask {
         stdout_tx: Sender<String>,
         stderr_tx: Sender<String>,
     ) -> FlowExecutionResult {
-        let mut cmd = Command::new(&self.cmd);
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
-        cmd.args(self.args.clone());
+        let mut cmd = self.build_command(&get_cdktr_setting!(CDKTR_AGENT_DEFAULT_SHELL));
 
-        let child_process = cmd.spawn();
+        if let Some(user) = &self.run_as_user {
+            let allow_user_switching =
+                get_cdktr_setting!(CDKTR_AGENT_ALLOW_RUN_AS_USER).to_lowercase() == "true";
+            if let Err(e) = apply_run_as_user(&mut cmd, user, allow_user_switching) {
+                return FlowExecutionResult::CRASHED(e);
+            }
+        }
+
+        let child_process = super::spawn(&mut cmd);
 
         match child_process {
-            Ok(mut child) => {
-                // handle process
-                let stdout = child.stdout.take().expect("unable to acquire stdout");
-                let stderr = child.stderr.take().expect("unable to acquire stderr");
-                let mut stdout_reader = BufReader::new(stdout).lines();
-                let mut stderr_reader = BufReader::new(stderr).lines();
-
-                while let Some(line) = stdout_reader.next_line().await.unwrap() {
-                    stdout_tx.send(line).await.unwrap();
-                }
-                while let Some(line) = stderr_reader.next_line().await.unwrap() {
-                    stderr_tx.send(line).await.unwrap()
-                }
-                match child.wait().await {
-                    Ok(exit_status) => match exit_status.success() {
-                        true => FlowExecutionResult::SUCCESS,
-                        false => FlowExecutionResult::FAILURE("Process failed".to_string()),
-                    },
-                    Err(e) => FlowExecutionResult::CRASHED(format!(
-                        "Process failed to exit cleanly - {}",
-                        e.to_string()
-                    )),
-                }
+            Ok(child) => {
+                let policy = super::StopPolicy::new(self.timeout_s, self.term_grace_s);
+                super::supervise(child, policy, stdout_tx, stderr_tx).await
             }
             Err(e) => {
                 // check for errors starting up the process
@@ -64,3 +110,280 @@ impl traits::Executor for SubprocessTask {
         }
     }
 }
+
+/// Configures the command to drop to the uid and primary gid of the given user before exec.
+/// Errors if user switching is disabled, the user doesn't exist or the agent doesn't have
+/// the privilege to switch to that user
+#[cfg(unix)]
+fn apply_run_as_user(
+    cmd: &mut Command,
+    user: &str,
+    allow_user_switching: bool,
+) -> Result<(), String> {
+    if !allow_user_switching {
+        return Err(format!(
+            "Task requested to run as user '{}' but user switching is disabled on this agent. Set CDKTR_AGENT_ALLOW_RUN_AS_USER=true to enable it",
+            user
+        ));
+    }
+    let (uid, gid) = lookup_user(user)?;
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let euid = unsafe { libc::geteuid() };
+    if euid != 0 && euid != uid {
+        return Err(format!(
+            "Agent lacks permission to run tasks as user '{}'. Switching users requires the agent to run as root",
+            user
+        ));
+    }
+    cmd.uid(uid);
+    cmd.gid(gid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_run_as_user(
+    _cmd: &mut Command,
+    user: &str,
+    _allow_user_switching: bool,
+) -> Result<(), String> {
+    Err(format!(
+        "Task requested to run as user '{}' but run_as_user is only supported on unix agents",
+        user
+    ))
+}
+
+/// Resolves a user name to its uid and primary gid from the system user database
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(u32, u32), String> {
+    let c_user =
+        std::ffi::CString::new(user).map_err(|_e| format!("Invalid user name '{}'", user))?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16_384];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    // SAFETY: all pointers are valid for the duration of the call and buf.len() is the
+    // true length of the buffer backing the string fields of pwd
+    let ret = unsafe {
+        libc::getpwnam_r(
+            c_user.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        return Err(format!("User '{}' does not exist on this agent", user));
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::CLEAN_ENV_VARS;
+    use super::*;
+
+    fn echo_task(shell: Option<bool>) -> SubprocessTask {
+        SubprocessTask {
+            cmd: "echo".to_string(),
+            args: vec!["hello".to_string(), "$HOME".to_string()],
+            run_as_user: None,
+            env: None,
+            clean_env: None,
+            shell,
+            timeout_s: None,
+            term_grace_s: None,
+        }
+    }
+
+    #[test]
+    fn test_shell_mode_uses_configured_shell() {
+        let cmd = echo_task(Some(true)).build_command("/bin/bash");
+        assert_eq!(cmd.as_std().get_program(), "/bin/bash");
+        assert_eq!(
+            cmd.as_std().get_args().collect::<Vec<_>>(),
+            vec!["-c", "echo hello $HOME"]
+        );
+
+        let cmd = echo_task(Some(true)).build_command("cmd.exe");
+        assert_eq!(
+            cmd.as_std().get_args().collect::<Vec<_>>(),
+            vec!["/C", "echo hello $HOME"]
+        );
+
+        // without shell mode the command is spawned directly
+        let cmd = echo_task(None).build_command("/bin/bash");
+        assert_eq!(cmd.as_std().get_program(), "echo");
+        assert_eq!(
+            cmd.as_std().get_args().collect::<Vec<_>>(),
+            vec!["hello", "$HOME"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shell_mode_runs_command_line() {
+        use tokio::sync::mpsc;
+        let task = SubprocessTask {
+            cmd: "echo".to_string(),
+            args: vec![
+                "abc".to_string(),
+                "|".to_string(),
+                "tr".to_string(),
+                "a".to_string(),
+                "x".to_string(),
+            ],
+            run_as_user: None,
+            env: None,
+            clean_env: None,
+            shell: Some(true),
+            timeout_s: None,
+            term_grace_s: None,
+        };
+        let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+        let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+        let result = traits::Executor::run(&task, stdout_tx, stderr_tx).await;
+        assert!(matches!(result, FlowExecutionResult::SUCCESS));
+        assert_eq!(stdout_rx.recv().await.unwrap(), "xbc");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_timeout_gives_process_grace_to_clean_up() {
+        use std::time::{Duration, Instant};
+        use tokio::sync::mpsc;
+        let task = SubprocessTask {
+            cmd: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "trap 'echo cleaned up; exit 0' TERM; echo started; sleep 30 & wait".to_string(),
+            ],
+            run_as_user: None,
+            env: None,
+            clean_env: None,
+            shell: None,
+            timeout_s: Some(1),
+            term_grace_s: Some(5),
+        };
+        let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+        let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+        let started = Instant::now();
+        let result = traits::Executor::run(&task, stdout_tx, stderr_tx).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(
+            result,
+            FlowExecutionResult::FAILURE(msg, None) if msg.contains("timed out after 1s")
+        ));
+        assert_eq!(stdout_rx.recv().await.unwrap(), "started");
+        assert_eq!(stdout_rx.recv().await.unwrap(), "cleaned up");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_process_ignoring_sigterm_is_killed() {
+        use super::super::{Termination, spawn, terminate};
+        use std::time::Duration;
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "trap '' TERM; sleep 30 & wait"]);
+        let mut child = spawn(&mut cmd).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let termination = terminate(&mut child, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(termination, Termination::Killed);
+        assert!(child.try_wait().unwrap().is_some());
+
+        // a process that exits on SIGTERM isn't killed
+        let mut cmd = Command::new("sleep");
+        cmd.arg("30");
+        let mut child = spawn(&mut cmd).unwrap();
+        let termination = terminate(&mut child, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(termination, Termination::Exited);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_clean_env_only_passes_declared_vars() {
+        use tokio::sync::mpsc;
+        // SAFETY: no other test in this crate reads or writes this variable
+        unsafe { std::env::set_var("CDKTR_TEST_AGENT_ONLY_VAR", "agent") };
+        let run_env = |clean_env| async move {
+            let task = SubprocessTask {
+                cmd: "env".to_string(),
+                args: vec![],
+                run_as_user: None,
+                env: Some(HashMap::from([(
+                    "TASK_VAR".to_string(),
+                    "task".to_string(),
+                )])),
+                clean_env: Some(clean_env),
+                shell: None,
+                timeout_s: None,
+                term_grace_s: None,
+            };
+            let (stdout_tx, mut stdout_rx) = mpsc::channel(256);
+            let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+            let result = traits::Executor::run(&task, stdout_tx, stderr_tx).await;
+            assert!(matches!(result, FlowExecutionResult::SUCCESS));
+            let mut names = Vec::new();
+            while let Some(line) = stdout_rx.recv().await {
+                names.push(line.split('=').next().unwrap().to_string());
+            }
+            names.sort();
+            names
+        };
+
+        let inherited = run_env(false).await;
+        assert!(inherited.contains(&"CDKTR_TEST_AGENT_ONLY_VAR".to_string()));
+        assert!(inherited.contains(&"TASK_VAR".to_string()));
+
+        let mut expected = vec!["TASK_VAR".to_string()];
+        expected.extend(
+            CLEAN_ENV_VARS
+                .iter()
+                .filter(|name| std::env::var_os(name).is_some())
+                .map(|name| name.to_string()),
+        );
+        expected.sort();
+        assert_eq!(run_env(true).await, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_as_user_rejected_when_switching_disabled() {
+        let mut cmd = Command::new("id");
+        let err = apply_run_as_user(&mut cmd, "nobody", false).unwrap_err();
+        assert!(err.contains("user switching is disabled"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_as_user_unknown_user() {
+        let mut cmd = Command::new("id");
+        let err = apply_run_as_user(&mut cmd, "cdktr-no-such-user", true).unwrap_err();
+        assert!(err.contains("does not exist"));
+    }
+
+    /// Requires the tests to be run as root, e.g.
+    /// `sudo cargo test -p cdktr-workflow --features run-as-user-tests`
+    #[cfg(all(unix, feature = "run-as-user-tests"))]
+    #[tokio::test]
+    async fn test_run_as_user_sets_child_uid() {
+        use tokio::sync::mpsc;
+        // SAFETY: no other test in this crate reads or writes this variable
+        unsafe { std::env::set_var("CDKTR_AGENT_ALLOW_RUN_AS_USER", "true") };
+        let (nobody_uid, _gid) = lookup_user("nobody").unwrap();
+        let task = SubprocessTask {
+            cmd: "id".to_string(),
+            args: vec!["-u".to_string()],
+            run_as_user: Some("nobody".to_string()),
+            env: None,
+            clean_env: None,
+            shell: None,
+            timeout_s: None,
+            term_grace_s: None,
+        };
+        let (stdout_tx, mut stdout_rx) = mpsc::channel(32);
+        let (stderr_tx, _stderr_rx) = mpsc::channel(32);
+        let result = traits::Executor::run(&task, stdout_tx, stderr_tx).await;
+        assert!(matches!(result, FlowExecutionResult::SUCCESS));
+        assert_eq!(stdout_rx.recv().await.unwrap(), nobody_uid.to_string());
+    }
+}
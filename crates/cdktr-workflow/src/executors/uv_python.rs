@@ -1,14 +1,14 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 
 use async_trait::async_trait;
+use cdktr_core::get_cdktr_setting;
 use cdktr_core::models::{FlowExecutionResult, traits};
 use log::info;
 use serde::{Deserialize, Serialize};
-use tokio::{
-    io::{AsyncBufReadExt, BufReader},
-    process::Command,
-    sync::mpsc::Sender,
-};
+use sha2::{Digest, Sha256};
+use tokio::{process::Command, sync::mpsc::Sender};
 
 /// Special executor for running python scripts using uv
 /// to manage custom package installs and virtualenvs
@@ -16,19 +16,32 @@ use tokio::{
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct UvPythonTask {
     pub script_path: String,
+    /// Expected SHA-256 of the script file, as hex. The task fails without running the
+    /// script if the file on the agent doesn't match
+    pub script_sha256: Option<String>,
     pub is_uv_project: Option<bool>,
     pub packages: Option<Vec<String>>,
     pub uv_path: Option<String>,
     pub working_directory: Option<String>,
+    /// Extra environment variables set on the process
+    pub env: Option<HashMap<String, String>>,
+    /// Start the process with only `env` and the agent's PATH and HOME rather than the
+    /// agent's whole environment. Defaults to false
+    pub clean_env: Option<bool>,
+    /// Python version or interpreter to run the script with, e.g. `3.12`. Versions listed
+    /// in the agent's CDKTR_AGENT_PYTHON_INTERPRETERS are run with the mapped interpreter
+    pub python: Option<String>,
+    /// Seconds the process can run for before it is stopped and the task fails
+    pub timeout_s: Option<u64>,
+    /// Seconds the process is given to exit after SIGTERM before it is killed when it is
+    /// stopped. Defaults to the agent's CDKTR_AGENT_TERM_GRACE_S
+    pub term_grace_s: Option<u64>,
 }
 
-#[async_trait]
-impl traits::Executor for UvPythonTask {
-    async fn run(
-        &self,
-        stdout_tx: Sender<String>,
-        stderr_tx: Sender<String>,
-    ) -> FlowExecutionResult {
+impl UvPythonTask {
+    /// Builds the `uv run` command to spawn, resolving the requested python version
+    /// against the agent's `interpreters`
+    fn build_command(&self, interpreters: &HashMap<String, String>) -> Command {
         let uv_executable = match &self.uv_path {
             Some(path) => path.clone(),
             None => "uv".to_string(),
@@ -52,40 +65,103 @@ impl traits::Executor for UvPythonTask {
             }
         }
 
+        if let Some(python) = &self.python {
+            cmd.arg("--python");
+            cmd.arg(interpreters.get(python).unwrap_or(python));
+        }
+
         cmd.arg(&self.script_path);
 
         if let Some(dir) = &self.working_directory {
             cmd.current_dir(dir);
         }
+        super::apply_env(&mut cmd, self.env.as_ref(), self.clean_env.unwrap_or(false));
+        cmd
+    }
+
+    /// Path of the script file. uv resolves the script path from the working directory of
+    /// the process
+    pub fn script_file(&self) -> PathBuf {
+        match &self.working_directory {
+            Some(dir) => Path::new(dir).join(&self.script_path),
+            None => Path::new(&self.script_path).to_path_buf(),
+        }
+    }
+
+    /// Checks the script file against `script_sha256`, if it is set, so that a script
+    /// that has changed since the workflow was written isn't run
+    async fn verify_script(&self) -> Result<(), String> {
+        let expected = match &self.script_sha256 {
+            Some(expected) => expected.trim().to_lowercase(),
+            None => return Ok(()),
+        };
+        let path = self.script_file();
+        let contents = tokio::fs::read(&path).await.map_err(|e| {
+            format!(
+                "Failed to read script {} to verify its checksum: {}",
+                path.display(),
+                e
+            )
+        })?;
+        let actual: String = Sha256::digest(&contents)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if actual == expected {
+            Ok(())
+        } else {
+            Err(format!(
+                "Checksum mismatch for script {}: expected sha256 {} but found {}",
+                path.display(),
+                expected,
+                actual
+            ))
+        }
+    }
+
+    /// The `uv run` command line the task would be run with
+    pub fn describe(&self) -> String {
+        let interpreters = parse_interpreters(&get_cdktr_setting!(CDKTR_AGENT_PYTHON_INTERPRETERS));
+        super::describe_command(&self.build_command(&interpreters))
+    }
+}
+
+/// Parses the comma-separated `version=path` pairs of CDKTR_AGENT_PYTHON_INTERPRETERS
+fn parse_interpreters(setting: &str) -> HashMap<String, String> {
+    setting
+        .split(',')
+        .filter_map(|pair| {
+            let (version, path) = pair.split_once('=')?;
+            Some((version.trim().to_string(), path.trim().to_string()))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl traits::Executor for UvPythonTask {
+    async fn run(
+        &self,
+        stdout_tx: Sender<String>,
+        stderr_tx: Sender<String>,
+    ) -> FlowExecutionResult {
+        if let Err(e) = self.verify_script().await {
+            return FlowExecutionResult::FAILURE(e, None);
+        }
+        let interpreters = parse_interpreters(&get_cdktr_setting!(CDKTR_AGENT_PYTHON_INTERPRETERS));
+        let mut cmd = self.build_command(&interpreters);
 
-        let child_process = cmd.spawn();
+        let child_process = super::spawn(&mut cmd);
 
-        info!("Starting UV Python process: {:?}", cmd);
+        // the command isn't logged in full as its env can hold secrets
+        info!(
+            "Starting UV Python process for script: {}",
+            self.script_path
+        );
 
         match child_process {
-            Ok(mut child) => {
-                // handle process
-                let stdout = child.stdout.take().expect("unable to acquire stdout");
-                let stderr = child.stderr.take().expect("unable to acquire stderr");
-                let mut stdout_reader = BufReader::new(stdout).lines();
-                let mut stderr_reader = BufReader::new(stderr).lines();
-
-                while let Some(line) = stdout_reader.next_line().await.unwrap() {
-                    stdout_tx.send(line).await.unwrap();
-                }
-                while let Some(line) = stderr_reader.next_line().await.unwrap() {
-                    stderr_tx.send(line).await.unwrap()
-                }
-                match child.wait().await {
-                    Ok(exit_status) => match exit_status.success() {
-                        true => FlowExecutionResult::SUCCESS,
-                        false => FlowExecutionResult::FAILURE("Process failed".to_string()),
-                    },
-                    Err(e) => FlowExecutionResult::CRASHED(format!(
-                        "Process failed to exit cleanly - {}",
-                        e.to_string()
-                    )),
-                }
+            Ok(child) => {
+                let policy = super::StopPolicy::new(self.timeout_s, self.term_grace_s);
+                super::supervise(child, policy, stdout_tx, stderr_tx).await
             }
             Err(e) => {
                 // check for errors starting up the process
@@ -98,3 +174,79 @@ impl traits::Executor for UvPythonTask {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(python: Option<&str>) -> UvPythonTask {
+        UvPythonTask {
+            script_path: "main.py".to_string(),
+            script_sha256: None,
+            is_uv_project: None,
+            packages: Some(vec!["pandas".to_string()]),
+            uv_path: None,
+            working_directory: None,
+            env: None,
+            clean_env: None,
+            python: python.map(str::to_string),
+            timeout_s: None,
+            term_grace_s: None,
+        }
+    }
+
+    #[test]
+    fn test_python_version_uses_interpreter_map() {
+        let interpreters =
+            parse_interpreters("3.11=/opt/py311/bin/python, 3.12 = /usr/bin/python3.12");
+        assert_eq!(interpreters.len(), 2);
+
+        let cmd = task(Some("3.12")).build_command(&interpreters);
+        assert_eq!(cmd.as_std().get_program(), "uv");
+        assert_eq!(
+            cmd.as_std().get_args().collect::<Vec<_>>(),
+            vec![
+                "run",
+                "--with",
+                "pandas",
+                "--python",
+                "/usr/bin/python3.12",
+                "main.py"
+            ]
+        );
+
+        // versions the agent doesn't map are left for uv to resolve
+        let cmd = task(Some("3.13")).build_command(&interpreters);
+        assert!(cmd.as_std().get_args().any(|arg| arg == "3.13"));
+
+        let cmd = task(None).build_command(&interpreters);
+        assert!(!cmd.as_std().get_args().any(|arg| arg == "--python"));
+    }
+
+    #[tokio::test]
+    async fn test_script_checksum_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("main.py"), "print('hello')\n").unwrap();
+        let mut task = task(None);
+        task.working_directory = Some(dir.path().to_string_lossy().to_string());
+        assert_eq!(task.verify_script().await, Ok(()));
+
+        // sha256 of "print('hello')\n", in upper case to check the case is ignored
+        task.script_sha256 =
+            Some("03E693D9F2F687E0F40E36A8DF7FCB4D1C22974012B7C2A55C000EB30F305824".to_string());
+        assert_eq!(task.verify_script().await, Ok(()));
+
+        std::fs::write(dir.path().join("main.py"), "print('tampered')\n").unwrap();
+        let err = task.verify_script().await.unwrap_err();
+        assert!(err.starts_with("Checksum mismatch for script"), "{err}");
+        assert!(matches!(
+            traits::Executor::run(
+                &task,
+                tokio::sync::mpsc::channel(1).0,
+                tokio::sync::mpsc::channel(1).0
+            )
+            .await,
+            FlowExecutionResult::FAILURE(_, None)
+        ));
+    }
+}
@@ -0,0 +1,118 @@
+use cdktr_core::exceptions::GenericError;
+use log::debug;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// A Git repository that workflow definitions are synced from. The repo is checked out
+/// into a local cache directory, and workflows are loaded from `subdir` within it
+#[derive(Debug, Clone)]
+pub struct GitSource {
+    url: String,
+    git_ref: String,
+    subdir: String,
+    cache_dir: PathBuf,
+    deploy_key: Option<String>,
+}
+
+impl GitSource {
+    pub fn new(url: &str, git_ref: &str, subdir: &str, cache_dir: &Path) -> Self {
+        Self {
+            url: url.to_string(),
+            git_ref: git_ref.to_string(),
+            subdir: subdir.to_string(),
+            cache_dir: cache_dir.to_path_buf(),
+            deploy_key: None,
+        }
+    }
+
+    /// Path to a private SSH key used to authenticate against private repos
+    pub fn with_deploy_key(mut self, deploy_key: &str) -> Self {
+        self.deploy_key = Some(deploy_key.to_string());
+        self
+    }
+
+    /// The directory within the checkout that workflows are loaded from
+    pub fn workflow_dir(&self) -> PathBuf {
+        self.cache_dir.join(&self.subdir)
+    }
+
+    /// Clones the repo into the cache dir if it isn't there yet, then fetches the
+    /// configured ref and checks it out. Refs that are force-pushed are followed
+    /// rather than merged, and a cache left by a different url is pointed at the
+    /// configured one
+    pub async fn sync(&self) -> Result<(), GenericError> {
+        if !self.cache_dir.join(".git").exists() {
+            debug!(
+                "Initialising workflow repo cache at {}",
+                self.cache_dir.display()
+            );
+            tokio::fs::create_dir_all(&self.cache_dir)
+                .await
+                .map_err(|e| {
+                    GenericError::WorkflowError(format!(
+                        "Unable to create workflow repo cache dir {}: {}",
+                        self.cache_dir.display(),
+                        e
+                    ))
+                })?;
+            self.git(&["init", "--quiet"]).await?;
+            self.git(&["remote", "add", "origin", &self.url]).await?;
+        } else {
+            self.git(&["remote", "set-url", "origin", &self.url])
+                .await?;
+        }
+        self.git(&["fetch", "--quiet", "--depth", "1", "origin", &self.git_ref])
+            .await?;
+        self.git(&["checkout", "--quiet", "--force", "--detach", "FETCH_HEAD"])
+            .await?;
+        debug!("Synced workflow repo {} at ref {}", self.url, self.git_ref);
+        Ok(())
+    }
+
+    async fn git(&self, args: &[&str]) -> Result<(), GenericError> {
+        let mut cmd = Command::new("git");
+        cmd.args(args).current_dir(&self.cache_dir);
+        if let Some(deploy_key) = &self.deploy_key {
+            cmd.env("GIT_SSH_COMMAND", ssh_command(deploy_key));
+        }
+        let output = cmd.output().await.map_err(|e| {
+            GenericError::WorkflowError(format!("Unable to run git. Is it installed? {}", e))
+        })?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(GenericError::WorkflowError(format!(
+                "git {} failed for {}: {}",
+                args[0],
+                self.url,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+/// The ssh command git authenticates with the deploy key through. Git runs it with a
+/// shell, so the path is single-quoted in case it holds spaces or quotes
+fn ssh_command(deploy_key: &str) -> String {
+    format!(
+        "ssh -i '{}' -o IdentitiesOnly=yes",
+        deploy_key.replace('\'', r"'\''")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ssh_command_quotes_deploy_key() {
+        assert_eq!(
+            ssh_command("/keys/deploy key"),
+            "ssh -i '/keys/deploy key' -o IdentitiesOnly=yes"
+        );
+        assert_eq!(
+            ssh_command("/keys/it's"),
+            r"ssh -i '/keys/it'\''s' -o IdentitiesOnly=yes"
+        );
+    }
+}
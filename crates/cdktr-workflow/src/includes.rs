@@ -0,0 +1,221 @@
+use cdktr_core::exceptions::GenericError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::models::Task;
+use super::yaml::from_yaml;
+
+/// Files whose name starts with this prefix are task libraries to be included by workflows
+/// rather than workflows themselves, so they are skipped when loading the workflow store
+const LIBRARY_FILE_PREFIX: &str = "_";
+
+/// The top-level keys a task library may define
+const LIBRARY_KEYS: [&str; 2] = ["include", "tasks"];
+
+/// A shared block of tasks that workflows pull in with `include:`. A library may itself
+/// include other libraries
+#[derive(Debug, Deserialize)]
+struct TaskLibrary {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    tasks: HashMap<String, Task>,
+}
+
+/// Whether the file is a task library, e.g. `_common.yml`
+pub fn is_library_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(LIBRARY_FILE_PREFIX))
+}
+
+/// Top-level keys of a library file that libraries don't use, such as the `name` or `cron`
+/// of a workflow that was given a library file name by mistake
+pub fn unexpected_library_keys(contents: &str) -> Vec<String> {
+    match serde_norway::from_str::<serde_norway::Value>(contents) {
+        Ok(serde_norway::Value::Mapping(mapping)) => mapping
+            .keys()
+            .filter_map(|key| key.as_str())
+            .filter(|key| !LIBRARY_KEYS.contains(key))
+            .map(|key| key.to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Loads the tasks of the libraries included by the workflow at `workflow_path`. Include
+/// paths are relative to the file that includes them. Tasks defined directly in a file take
+/// precedence over those it includes, but the same task id coming from two different
+/// includes is an error. Missing and circular includes are errors too
+pub fn resolve_includes(
+    workflow_path: &Path,
+    includes: &[String],
+) -> Result<HashMap<String, Task>, GenericError> {
+    let mut stack = Vec::new();
+    if let Ok(canonical) = workflow_path.canonicalize() {
+        stack.push(canonical);
+    }
+    load_includes(workflow_path, includes, &mut stack)
+}
+
+fn load_includes(
+    including_path: &Path,
+    includes: &[String],
+    stack: &mut Vec<PathBuf>,
+) -> Result<HashMap<String, Task>, GenericError> {
+    let base_dir = including_path.parent().unwrap_or(Path::new(""));
+    let mut tasks: HashMap<String, Task> = HashMap::new();
+    for include in includes {
+        let path = base_dir.join(include);
+        let canonical = path.canonicalize().map_err(|e| {
+            GenericError::WorkflowError(format!(
+                "Invalid Workflow. Included file '{}' of {} not found: {}",
+                include,
+                including_path.display(),
+                e
+            ))
+        })?;
+        if stack.contains(&canonical) {
+            let chain: Vec<String> = stack
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(GenericError::WorkflowError(format!(
+                "Invalid Workflow. Circular include: {}",
+                chain.join(" -> ")
+            )));
+        }
+        let library = read_library(&canonical)?;
+        stack.push(canonical.clone());
+        let mut library_tasks = load_includes(&canonical, &library.include, stack)?;
+        stack.pop();
+        library_tasks.extend(library.tasks);
+        for (task_id, task) in library_tasks {
+            if tasks.contains_key(&task_id) {
+                return Err(GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Task '{}' is defined by more than one include of {}",
+                    task_id,
+                    including_path.display()
+                )));
+            }
+            tasks.insert(task_id, task);
+        }
+    }
+    Ok(tasks)
+}
+
+fn read_library(path: &Path) -> Result<TaskLibrary, GenericError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        GenericError::WorkflowError(format!(
+            "Error reading included file {}. Error: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let library = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str::<TaskLibrary>(&contents).map_err(|e| e.to_string()),
+        _ => from_yaml::<TaskLibrary>(&contents),
+    };
+    library.map_err(|e| {
+        GenericError::ParseError(format!(
+            "Failed to parse included file {}. Error: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Workflow;
+
+    const LIBRARY: &str = r#"
+tasks:
+  notify:
+    name: Notify
+    config: !Subprocess
+      cmd: echo
+      args: ["done"]
+"#;
+
+    fn workflow_yaml(include: &str) -> String {
+        format!(
+            r#"
+name: Uses library
+start_time: 2025-01-20T12:30:00+00:00
+include: [{include}]
+tasks:
+  build:
+    name: Build
+    config: !Subprocess
+      cmd: echo
+      args: ["build"]
+  finish:
+    name: Finish
+    depends: ["build", "notify"]
+    config: !Subprocess
+      cmd: echo
+      args: ["finish"]
+"#
+        )
+    }
+
+    #[test]
+    fn test_is_library_file() {
+        assert!(is_library_file(Path::new("workflows/lib/_common.yml")));
+        assert!(!is_library_file(Path::new("workflows/_lib/common.yml")));
+        assert!(!is_library_file(Path::new("workflows/nightly.yml")));
+    }
+
+    #[test]
+    fn test_unexpected_library_keys() {
+        assert!(unexpected_library_keys(LIBRARY).is_empty());
+        assert_eq!(
+            unexpected_library_keys(&workflow_yaml("_common.yml")),
+            vec!["name", "start_time"]
+        );
+        assert!(unexpected_library_keys("not: [valid").is_empty());
+    }
+
+    #[test]
+    fn test_workflow_includes_shared_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("lib")).unwrap();
+        std::fs::write(dir.path().join("lib/_common.yml"), LIBRARY).unwrap();
+        let path = dir.path().join("nightly.yml");
+        let yaml = workflow_yaml("lib/_common.yml");
+        std::fs::write(&path, &yaml).unwrap();
+
+        let workflow = Workflow::new(path.to_str().unwrap().to_string(), &yaml).unwrap();
+        let notify = workflow.get_task("notify").unwrap();
+        assert_eq!(notify.name(), "Notify");
+        assert_eq!(
+            workflow.get_dag().get_dependents("notify").unwrap(),
+            vec!["finish"]
+        );
+    }
+
+    #[test]
+    fn test_missing_include() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nightly.yml");
+        let yaml = workflow_yaml("_missing.yml");
+        std::fs::write(&path, &yaml).unwrap();
+        let err = Workflow::new(path.to_str().unwrap().to_string(), &yaml).unwrap_err();
+        assert!(err.to_string().contains("'_missing.yml'"));
+        assert!(err.to_string().contains("not found"));
+    }
+
+    #[test]
+    fn test_circular_include() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("_a.yml"), "include: [_b.yml]\n").unwrap();
+        std::fs::write(dir.path().join("_b.yml"), "include: [_a.yml]\n").unwrap();
+        let err =
+            resolve_includes(&dir.path().join("nightly.yml"), &["_a.yml".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Circular include"));
+    }
+}
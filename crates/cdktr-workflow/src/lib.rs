@@ -1,22 +1,85 @@
+mod blackout;
+mod condition;
+mod defaults;
+mod environments;
 mod executors;
+mod git;
+mod includes;
 mod models;
-use cdktr_core::exceptions::GenericError;
+mod output_levels;
+mod readiness;
+mod secrets;
+mod wait_for;
+mod yaml;
+use cdktr_core::{exceptions::GenericError, get_cdktr_setting};
 use log::{debug, error, warn};
 use std::{
     collections::{HashMap, VecDeque},
     path::{Path, PathBuf},
     sync::Arc,
 };
-use tokio::{fs, sync::Mutex};
+use tokio::{fs, sync::Mutex, task::JoinSet};
 
+pub use blackout::{BlackoutWindow, defer_past_blackout, parse_blackout_windows};
+pub use condition::Condition;
+pub use executors::{agent_executors, shell_command, stop_running_tasks};
+pub use git::GitSource;
+use includes::{is_library_file, unexpected_library_keys};
 use models::key_from_path;
-pub use models::{FromYaml, Task, WorkFlowDAG, Workflow};
+pub use models::{
+    FromYaml, MatrixExpansion, NotifyOn, OverlapPolicy, SingletonMode, Task, WorkFlowDAG, Workflow,
+    WorkflowNotify, WorkflowOutput,
+};
+pub use output_levels::{OutputLogLevel, OutputLogLevels};
+pub use readiness::{Readiness, ReadinessProbe};
+pub use secrets::{Redaction, SecretSource, redact};
+pub use wait_for::{WaitCondition, WaitFor};
+
+/// File extensions loaded as workflow definitions. YAML is the primary format, JSON is
+/// accepted for workflows generated by other tools
+const WORKFLOW_EXTENSIONS: [&str; 3] = ["yaml", "yml", "json"];
+
+/// Maximum number of workflow files read and parsed at once, so that loading a large
+/// workflow directory doesn't run the process out of file descriptors
+const MAX_CONCURRENT_WORKFLOW_LOADS: usize = 64;
 
 /// BFS traversal of the workflow directory to find all workflows. Will log and skip
 /// any items that failed to parse. If none parse, this reutrns an empty hashmap
-pub async fn get_yaml_map<T: FromYaml>(workflow_dir: &str) -> HashMap<String, T> {
+pub async fn get_yaml_map<T: FromYaml + Send + 'static>(workflow_dir: &str) -> HashMap<String, T> {
+    let files = find_workflow_files(workflow_dir).await;
+    let (workflows, failures) =
+        load_workflow_files(workflow_dir, files, MAX_CONCURRENT_WORKFLOW_LOADS).await;
+    for failure in failures {
+        warn!("Skipping workflow - {}", failure);
+    }
+    workflows
+}
+
+/// Same as `get_yaml_map` but fails instead of skipping workflows that don't parse,
+/// listing every file that failed
+pub async fn try_get_yaml_map<T: FromYaml + Send + 'static>(
+    workflow_dir: &str,
+) -> Result<HashMap<String, T>, GenericError> {
+    let files = find_workflow_files(workflow_dir).await;
+    let (workflows, failures) =
+        load_workflow_files(workflow_dir, files, MAX_CONCURRENT_WORKFLOW_LOADS).await;
+    if failures.is_empty() {
+        Ok(workflows)
+    } else {
+        Err(GenericError::ParseError(format!(
+            "{} workflow(s) in {} failed to load:\n{}",
+            failures.len(),
+            workflow_dir,
+            failures.join("\n")
+        )))
+    }
+}
+
+/// Paths of the workflow definitions in the workflow directory and its subdirectories,
+/// in the order they were found
+async fn find_workflow_files(workflow_dir: &str) -> Vec<PathBuf> {
     let dir = Path::new(workflow_dir).to_owned();
-    let mut workflows = HashMap::new();
+    let mut files = Vec::new();
     let mut dirs_to_scan: VecDeque<PathBuf> = VecDeque::new();
     dirs_to_scan.push_back(dir);
 
@@ -35,62 +98,172 @@ pub async fn get_yaml_map<T: FromYaml>(workflow_dir: &str) -> HashMap<String, T>
                 }
                 for entry in valid_entries {
                     let path = entry.path();
-                    if path.is_file()
-                        && ["yaml", "yml"].contains(
-                            &path
-                                .extension()
-                                .expect("Unable to acquire file extension")
-                                .to_str()
-                                .expect("Extension to str yielded None"),
-                        )
-                    {
-                        let workflow = match T::from_yaml(
-                            path.to_str().expect("failed to get apth as str"),
-                        )
-                        .await
-                        {
-                            Ok(workflow) => workflow,
-                            Err(e) => {
-                                warn!(
-                                    "Parsing failure for {}. Not a valid workflow definition. Original error: {}",
-                                    path.display(),
-                                    e.to_string()
-                                );
-                                warn!("Skipping workflow {}", path.display());
-                                continue;
-                            }
-                        };
-                        workflows
-                            .insert(key_from_path(path, PathBuf::from(workflow_dir)), workflow);
+                    let is_workflow_file = path.is_file()
+                        && path
+                            .extension()
+                            .and_then(|ext| ext.to_str())
+                            .is_some_and(|ext| WORKFLOW_EXTENSIONS.contains(&ext));
+                    if is_workflow_file && is_library_file(&path) {
+                        log_skipped_library(&path).await;
+                    } else if is_workflow_file {
+                        files.push(path);
                     } else if path.is_dir() {
                         dirs_to_scan.push_back(path);
                     }
                 }
             }
             Err(e) => {
-                error!(
-                    "Unable to read directory {}: {}",
-                    dir.display(),
-                    e.to_string()
-                );
+                error!("Unable to read directory {}: {}", dir.display(), e);
             }
         }
     }
-    workflows
+    files
+}
+
+/// Task libraries aren't loaded as workflows, so a workflow given a library file name by
+/// mistake would otherwise disappear without a trace
+async fn log_skipped_library(path: &Path) {
+    debug!("Skipping task library {}", path.display());
+    let Ok(contents) = fs::read_to_string(path).await else {
+        return;
+    };
+    let keys = unexpected_library_keys(&contents);
+    if !keys.is_empty() {
+        warn!(
+            "{} looks like a workflow (it defines {}) but is skipped because file names \
+             starting with '_' are task libraries. Rename it to load it as a workflow",
+            path.display(),
+            keys.join(", ")
+        );
+    }
+}
+
+/// Parses the workflow files with up to `max_concurrent` in flight at once. Results are
+/// inserted in the order the files were found, so the map is the same however the loads
+/// interleave. Files that failed to load are returned with their error, in the same order
+async fn load_workflow_files<T: FromYaml + Send + 'static>(
+    workflow_dir: &str,
+    files: Vec<PathBuf>,
+    max_concurrent: usize,
+) -> (HashMap<String, T>, Vec<String>) {
+    let mut loaded: Vec<Option<Result<T, String>>> =
+        std::iter::repeat_with(|| None).take(files.len()).collect();
+    let mut loads = JoinSet::new();
+    for (ix, path) in files.iter().cloned().enumerate() {
+        while loads.len() >= max_concurrent.max(1) {
+            store_load_result(&mut loaded, loads.join_next().await);
+        }
+        loads.spawn(async move {
+            let result = T::from_yaml(path.to_str().expect("failed to get apth as str"))
+                .await
+                .map_err(|e| e.to_string());
+            (ix, result)
+        });
+    }
+    while let Some(result) = loads.join_next().await {
+        store_load_result(&mut loaded, Some(result));
+    }
+
+    let mut workflows = HashMap::new();
+    let mut failures = Vec::new();
+    for (path, workflow) in files.into_iter().zip(loaded) {
+        match workflow {
+            Some(Ok(workflow)) => {
+                workflows.insert(key_from_path(path, PathBuf::from(workflow_dir)), workflow);
+            }
+            Some(Err(e)) => failures.push(format!(
+                "{}: not a valid workflow definition. Original error: {}",
+                path.display(),
+                e
+            )),
+            None => failures.push(format!("{}: failed to load the file", path.display())),
+        }
+    }
+    (workflows, failures)
+}
+
+/// Index of the file in the load order and the parsed workflow or parse error
+type LoadResult<T> = (usize, Result<T, String>);
+
+fn store_load_result<T>(
+    loaded: &mut [Option<Result<T, String>>],
+    result: Option<Result<LoadResult<T>, tokio::task::JoinError>>,
+) {
+    match result {
+        Some(Ok((ix, result))) => loaded[ix] = Some(result),
+        Some(Err(e)) => error!("Failed to load a workflow file: {}", e),
+        None => (),
+    }
+}
+
+/// Workflow ids are normally derived from their path relative to `CDKTR_WORKFLOW_DIR`, so
+/// for a git checkout they are replaced with their path relative to the checkout instead
+async fn git_workflow_map(workflow_dir: &str) -> HashMap<String, Workflow> {
+    get_yaml_map::<Workflow>(workflow_dir)
+        .await
+        .into_iter()
+        .map(|(id, workflow)| (id.clone(), workflow.with_id(id)))
+        .collect()
 }
 
 #[derive(Debug, Clone)]
 pub struct WorkflowStore {
     dir: String,
+    git: Option<GitSource>,
+    /// Whether workflows that don't parse fail loading instead of being skipped
+    strict: bool,
     inner: Arc<Mutex<HashMap<String, Workflow>>>,
 }
 impl WorkflowStore {
     pub async fn from_dir(workflow_dir: &str) -> Result<Self, GenericError> {
         Ok(Self {
             dir: workflow_dir.to_string(),
+            git: None,
+            strict: false,
             inner: Arc::new(Mutex::new(get_yaml_map(workflow_dir).await)),
         })
     }
+
+    /// Loads the workflow directory like `from_dir` but returns an error listing every
+    /// workflow that failed to parse instead of skipping them. Refreshes of the store
+    /// keep the last set of workflows if any fail to parse
+    pub async fn from_dir_strict(workflow_dir: &str) -> Result<Self, GenericError> {
+        Ok(Self {
+            dir: workflow_dir.to_string(),
+            git: None,
+            strict: true,
+            inner: Arc::new(Mutex::new(try_get_yaml_map(workflow_dir).await?)),
+        })
+    }
+
+    /// Syncs workflows from a Git repository, checked out into `CDKTR_WORKFLOW_GIT_CACHE_DIR`.
+    /// Private repos can be accessed with the SSH key at `CDKTR_WORKFLOW_GIT_DEPLOY_KEY`
+    pub async fn from_git(url: &str, git_ref: &str, subdir: &str) -> Result<Self, GenericError> {
+        let cache_dir = get_cdktr_setting!(CDKTR_WORKFLOW_GIT_CACHE_DIR);
+        let cache_dir = match std::env::var("HOME") {
+            Ok(home) => cache_dir.replace("$HOME", &home),
+            Err(_) => cache_dir,
+        };
+        let mut source = GitSource::new(url, git_ref, subdir, Path::new(&cache_dir));
+        let deploy_key = get_cdktr_setting!(CDKTR_WORKFLOW_GIT_DEPLOY_KEY);
+        if !deploy_key.is_empty() {
+            source = source.with_deploy_key(&deploy_key);
+        }
+        Self::from_git_source(source).await
+    }
+
+    pub async fn from_git_source(source: GitSource) -> Result<Self, GenericError> {
+        source.sync().await?;
+        let dir = source.workflow_dir().to_string_lossy().to_string();
+        let workflows = git_workflow_map(&dir).await;
+        Ok(Self {
+            dir,
+            git: Some(source),
+            strict: false,
+            inner: Arc::new(Mutex::new(workflows)),
+        })
+    }
+
     pub async fn get(&self, workflow_id: &str) -> Option<Workflow> {
         let inner_mutex = self.inner.lock().await;
         match (*inner_mutex).get(workflow_id) {
@@ -107,9 +280,37 @@ impl WorkflowStore {
         self.inner.lock().await.len()
     }
 
+    /// Reloads the workflows from the directory, or the git checkout. A reload that finds
+    /// no workflows in a directory that still exists keeps the loaded workflows, as the
+    /// directory is more likely to be mid-deploy than emptied on purpose
     pub async fn refresh_workflows(&mut self) {
+        let workflows = match &self.git {
+            Some(source) => {
+                if let Err(e) = source.sync().await {
+                    // keep serving the last good checkout
+                    error!("Failed to sync workflow repo: {}", e);
+                }
+                git_workflow_map(&self.dir).await
+            }
+            None if self.strict => match try_get_yaml_map(&self.dir).await {
+                Ok(workflows) => workflows,
+                Err(e) => {
+                    error!("Keeping the loaded workflows as the refresh failed: {}", e);
+                    return;
+                }
+            },
+            None => get_yaml_map(&self.dir).await,
+        };
         let mut inner_mutex = self.inner.lock().await;
-        *inner_mutex = get_yaml_map(&self.dir).await;
+        if workflows.is_empty() && !inner_mutex.is_empty() && Path::new(&self.dir).is_dir() {
+            warn!(
+                "Found no workflows in {} - keeping the {} loaded workflows in case it was a transient read",
+                self.dir,
+                inner_mutex.len()
+            );
+            return;
+        }
+        *inner_mutex = workflows;
         debug!(
             "Workflow store refreshed with {} workflows",
             inner_mutex.len()
@@ -234,6 +435,187 @@ mod tests {
         // the file descriptors are being properly released
     }
 
+    #[tokio::test]
+    async fn test_get_workflow_map_mixes_yaml_and_json() {
+        let tmp_dir = tempdir().unwrap();
+        let wf_dir = tmp_dir.path();
+        let yaml = fs::read_to_string("./test_artifacts/workflows/multi-cmd.yml").unwrap();
+        fs::write(wf_dir.join("from_yaml.yml"), &yaml).unwrap();
+        fs::write(
+            wf_dir.join("from_json.json"),
+            r#"{
+                "name": "Generated Workflow",
+                "start_time": "2025-01-20T12:30:00+00:00",
+                "tasks": {
+                    "task1": {
+                        "name": "Task 1",
+                        "config": {"Subprocess": {"cmd": "echo", "args": ["hello"]}}
+                    },
+                    "task2": {
+                        "name": "Task 2",
+                        "depends": ["task1"],
+                        "config": {"Subprocess": {"cmd": "echo", "args": ["world"]}}
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        // files of other types are ignored
+        fs::write(wf_dir.join("notes.txt"), "not a workflow").unwrap();
+        fs::write(wf_dir.join("README"), "not a workflow").unwrap();
+
+        let result = get_yaml_map::<Workflow>(wf_dir.to_str().unwrap()).await;
+        assert_eq!(result.len(), 2);
+        assert!(result.contains_key("from_yaml"));
+        let json_workflow = result.get("from_json").unwrap();
+        assert_eq!(json_workflow.name(), "Generated Workflow");
+        assert_eq!(json_workflow.get_dag().node_count(), 2);
+        assert_eq!(
+            json_workflow.get_dag().get_first_tasks(),
+            vec!["task1".to_string()]
+        );
+    }
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[tokio::test]
+    async fn test_workflow_store_from_git() {
+        let tmp_dir = tempdir().unwrap();
+        let remote = tmp_dir.path().join("remote.git");
+        let work = tmp_dir.path().join("work");
+        fs::create_dir_all(&remote).unwrap();
+        fs::create_dir_all(work.join("flows")).unwrap();
+        git(&remote, &["init", "--quiet", "--bare"]);
+        git(&work, &["init", "--quiet"]);
+        git(&work, &["checkout", "--quiet", "-b", "main"]);
+        let wf1 = fs::read_to_string("./test_artifacts/workflows/multi-cmd.yml").unwrap();
+        fs::write(work.join("flows/wf1.yaml"), &wf1).unwrap();
+        git(&work, &["add", "."]);
+        git(
+            &work,
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "-m",
+                "first",
+            ],
+        );
+        git(
+            &work,
+            &["push", "--quiet", remote.to_str().unwrap(), "main"],
+        );
+
+        let source = GitSource::new(
+            remote.to_str().unwrap(),
+            "main",
+            "flows",
+            &tmp_dir.path().join("cache"),
+        );
+        let mut store = WorkflowStore::from_git_source(source).await.unwrap();
+        assert_eq!(store.count().await, 1);
+        assert_eq!(store.get("wf1").await.unwrap().id(), "wf1");
+
+        // a new commit on the remote is picked up on refresh
+        fs::write(work.join("flows/wf2.yaml"), &wf1).unwrap();
+        git(&work, &["add", "."]);
+        git(
+            &work,
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "-m",
+                "second",
+            ],
+        );
+        git(
+            &work,
+            &["push", "--quiet", remote.to_str().unwrap(), "main"],
+        );
+        store.refresh_workflows().await;
+        assert_eq!(store.count().await, 2);
+        assert!(store.get("wf2").await.is_some());
+    }
+
+    /// Creates a bare repo with one commit on main holding the workflow `<name>.yaml`
+    fn remote_with_workflow(root: &Path, name: &str) -> PathBuf {
+        let remote = root.join(format!("{name}.git"));
+        let work = root.join(format!("{name}-work"));
+        fs::create_dir_all(&remote).unwrap();
+        fs::create_dir_all(&work).unwrap();
+        git(&remote, &["init", "--quiet", "--bare"]);
+        git(&work, &["init", "--quiet"]);
+        git(&work, &["checkout", "--quiet", "-b", "main"]);
+        let wf = fs::read_to_string("./test_artifacts/workflows/multi-cmd.yml").unwrap();
+        fs::write(work.join(format!("{name}.yaml")), wf).unwrap();
+        git(&work, &["add", "."]);
+        git(
+            &work,
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "-m",
+                "first",
+            ],
+        );
+        git(
+            &work,
+            &["push", "--quiet", remote.to_str().unwrap(), "main"],
+        );
+        remote
+    }
+
+    #[tokio::test]
+    async fn test_workflow_store_from_git_follows_changed_url() {
+        let tmp_dir = tempdir().unwrap();
+        let cache = tmp_dir.path().join("cache");
+        let old_remote = remote_with_workflow(tmp_dir.path(), "old");
+        let new_remote = remote_with_workflow(tmp_dir.path(), "new");
+
+        let source = GitSource::new(old_remote.to_str().unwrap(), "main", "", &cache);
+        let store = WorkflowStore::from_git_source(source).await.unwrap();
+        assert!(store.get("old").await.is_some());
+
+        // the cache is reused but synced from the new url
+        let source = GitSource::new(new_remote.to_str().unwrap(), "main", "", &cache);
+        let store = WorkflowStore::from_git_source(source).await.unwrap();
+        assert!(store.get("new").await.is_some());
+        assert!(store.get("old").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_workflow_store_from_git_bad_ref() {
+        let tmp_dir = tempdir().unwrap();
+        let remote = tmp_dir.path().join("remote.git");
+        fs::create_dir_all(&remote).unwrap();
+        git(&remote, &["init", "--quiet", "--bare"]);
+        let source = GitSource::new(
+            remote.to_str().unwrap(),
+            "missing",
+            "",
+            &tmp_dir.path().join("cache"),
+        );
+        assert!(WorkflowStore::from_git_source(source).await.is_err());
+    }
+
     #[tokio::test]
     async fn test_workflow_store_refresh_no_fd_leak() {
         // Test the WorkflowStore refresh_workflows method specifically
@@ -252,4 +634,117 @@ mod tests {
 
         // Success means no file descriptor leaks
     }
+
+    #[tokio::test]
+    async fn test_strict_loading_fails_on_broken_workflow() {
+        let tmp_dir = tempdir().unwrap();
+        let wf_dir = tmp_dir.path();
+        fs::write(
+            wf_dir.join("good.yml"),
+            fs::read_to_string("./test_artifacts/workflows/multi-cmd.yml").unwrap(),
+        )
+        .unwrap();
+        fs::write(wf_dir.join("broken.yml"), "name: [not valid").unwrap();
+        let wf_dir = wf_dir.to_str().unwrap();
+
+        let lenient = WorkflowStore::from_dir(wf_dir).await.unwrap();
+        assert_eq!(lenient.count().await, 1);
+        assert!(lenient.get("good").await.is_some());
+
+        let err = WorkflowStore::from_dir_strict(wf_dir).await.unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("1 workflow(s)"), "{msg}");
+        assert!(msg.contains("broken.yml"), "{msg}");
+        assert!(!msg.contains("good.yml"), "{msg}");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_keeps_workflows_on_empty_read() {
+        let tmp_dir = tempdir().unwrap();
+        let wf_dir = tmp_dir.path().join("workflows");
+        fs::create_dir_all(&wf_dir).unwrap();
+        let good = fs::read_to_string("./test_artifacts/workflows/multi-cmd.yml").unwrap();
+        fs::write(wf_dir.join("good.yml"), &good).unwrap();
+        let mut store = WorkflowStore::from_dir(wf_dir.to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(store.count().await, 1);
+
+        // mid-deploy the files have been moved out of the directory
+        fs::rename(wf_dir.join("good.yml"), tmp_dir.path().join("good.yml")).unwrap();
+        store.refresh_workflows().await;
+        assert!(store.get("good").await.is_some());
+
+        // once the deploy is done the refresh picks up the new set of workflows
+        fs::write(wf_dir.join("other.yml"), &good).unwrap();
+        store.refresh_workflows().await;
+        assert_eq!(store.count().await, 1);
+        assert!(store.get("other").await.is_some());
+
+        // a directory that has gone altogether is taken as having no workflows
+        fs::remove_dir_all(&wf_dir).unwrap();
+        store.refresh_workflows().await;
+        assert_eq!(store.count().await, 0);
+    }
+
+    /// Writes `count` workflows spread across nested directories, plus one that fails to parse
+    fn write_large_fixture(root: &Path, count: usize) {
+        for i in 0..count {
+            let dir = root.join(format!("team{}/project{}", i % 7, i % 3));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(
+                dir.join(format!("flow{i}.yml")),
+                format!(
+                    "name: Flow {i}\nstart_time: 2025-01-20T12:30:00+00:00\ntasks:\n  task1:\n    name: Task 1\n    config: !Subprocess\n      cmd: echo\n      args: [\"{i}\"]\n"
+                ),
+            )
+            .unwrap();
+        }
+        fs::write(root.join("team0/broken.yml"), "name: [not valid").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_parallel_load_matches_sequential() {
+        let tmp_dir = tempdir().unwrap();
+        write_large_fixture(tmp_dir.path(), 200);
+        let wf_dir = tmp_dir.path().to_str().unwrap();
+
+        let files = find_workflow_files(wf_dir).await;
+        assert_eq!(files.len(), 201);
+        let (sequential, sequential_failures) =
+            load_workflow_files::<Workflow>(wf_dir, files.clone(), 1).await;
+        let (parallel, parallel_failures) =
+            load_workflow_files::<Workflow>(wf_dir, files, 16).await;
+        // the broken workflow is skipped either way
+        assert_eq!(sequential.len(), 200);
+        assert_eq!(parallel, sequential);
+        assert_eq!(parallel_failures.len(), 1);
+        assert_eq!(parallel_failures, sequential_failures);
+        assert_eq!(
+            parallel.get("team3.project1.flow10").unwrap().name(),
+            "Flow 10"
+        );
+    }
+
+    /// Rough timing of sequential against parallel loading of a large workflow directory.
+    /// Run with `cargo test -p cdktr-workflow bench_load -- --ignored --nocapture`
+    #[ignore]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bench_load_large_workflow_dir() {
+        let tmp_dir = tempdir().unwrap();
+        write_large_fixture(tmp_dir.path(), 5_000);
+        let wf_dir = tmp_dir.path().to_str().unwrap();
+        let files = find_workflow_files(wf_dir).await;
+
+        let start = std::time::Instant::now();
+        load_workflow_files::<Workflow>(wf_dir, files.clone(), 1).await;
+        let sequential = start.elapsed();
+        let start = std::time::Instant::now();
+        load_workflow_files::<Workflow>(wf_dir, files, MAX_CONCURRENT_WORKFLOW_LOADS).await;
+        let parallel = start.elapsed();
+        println!(
+            "loaded 5000 workflows: sequential {:?}, parallel {:?}",
+            sequential, parallel
+        );
+    }
 }
@@ -1,15 +1,51 @@
 use async_trait::async_trait;
 use cdktr_core::exceptions::GenericError;
 use cdktr_core::get_cdktr_setting;
+use cdktr_core::models::RunStatus;
 use daggy::{self, Dag, NodeIndex, Walker};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::Display;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs;
 
+use super::blackout::BlackoutWindow;
+use super::condition::{Condition, quote_literal};
+use super::defaults::TaskDefaults;
+use super::environments::apply_overlay;
 use super::executors::ExecutableTask;
+use super::includes::resolve_includes;
+use super::output_levels::{LogLevelPattern, OutputLogLevel, OutputLogLevels};
+use super::readiness::Readiness;
+use super::wait_for::WaitFor;
+use super::yaml::from_yaml;
+
+/// Upper limit on `retries` so a workflow or task that always fails can't keep the cluster busy
+const MAX_WORKFLOW_RETRIES: u32 = 10;
+
+/// Placeholder replaced with the current item when a task template is expanded from its `matrix`
+const MATRIX_ITEM_PLACEHOLDER: &str = "${matrix.item}";
+
+/// Placeholder replaced with the results of the matrix tasks a task depends on, as JSON
+const MATRIX_RESULTS_PLACEHOLDER: &str = "${matrix.results}";
+
+/// Env var cleanup tasks are given the status the rest of the workflow finished with in
+const WORKFLOW_STATUS_ENV: &str = "CDKTR_WORKFLOW_STATUS";
+
+fn param_placeholder(name: &str) -> String {
+    format!("${{params.{name}}}")
+}
+
+fn secret_placeholder(name: &str) -> String {
+    format!("${{secret.{name}}}")
+}
+
+fn last_run_placeholder(name: &str) -> String {
+    format!("${{last_run.{name}}}")
+}
 
 pub fn key_from_path(path: PathBuf, workflow_dir: PathBuf) -> String {
     path.strip_prefix(workflow_dir)
@@ -30,7 +66,38 @@ pub struct Task {
     name: String,
     description: Option<String>,
     depends: Option<Vec<String>>,
+    matrix: Option<Vec<String>>,
     config: ExecutableTask,
+    cache: Option<bool>,
+    when: Option<String>,
+    /// secrets set as env vars of the same name when the task runs
+    secrets: Option<Vec<String>>,
+    /// files the task writes that are uploaded to the principal once it succeeds
+    produces: Option<Vec<String>>,
+    /// files produced by upstream tasks that are downloaded before the task runs
+    consumes: Option<Vec<String>>,
+    /// globs of the files the task writes that are listed in the run result once it finishes
+    artifacts: Option<Vec<String>>,
+    /// level stdout is logged at. Defaults to INFO
+    stdout_log_level: Option<OutputLogLevel>,
+    /// level stderr is logged at. Defaults to ERROR
+    stderr_log_level: Option<OutputLogLevel>,
+    /// output lines matching a pattern are logged at its level instead
+    log_level_patterns: Option<Vec<LogLevelPattern>>,
+    /// check the task has to pass before it counts as running and its dependents start
+    readiness: Option<Readiness>,
+    /// external condition the task waits for before its command is started
+    wait_for: Option<WaitFor>,
+    /// patterns whose matches are replaced with `***` in the output of the task
+    redact: Option<Vec<String>>,
+    /// times the task is run again after it fails, while the `retry_budget` of the
+    /// workflow lasts
+    retries: Option<u32>,
+    /// run the task once the rest of the workflow has finished, however it finished
+    cleanup: Option<bool>,
+    /// expansions of the matrix tasks the task aggregates. Set when the workflow is loaded
+    /// for tasks that use `${matrix.results}`
+    matrix_results: Option<Vec<MatrixExpansion>>,
 }
 impl Task {
     pub fn get_dependencies(&self) -> Option<Vec<String>> {
@@ -45,6 +112,554 @@ impl Task {
     pub fn description(&self) -> Option<String> {
         self.description.clone()
     }
+    pub fn matrix(&self) -> Option<&Vec<String>> {
+        self.matrix.as_ref()
+    }
+
+    /// Expansions whose results the task is given as `${matrix.results}`. The task runs
+    /// once every one of them has finished, even if some of them failed
+    pub fn matrix_results(&self) -> &[MatrixExpansion] {
+        self.matrix_results.as_deref().unwrap_or_default()
+    }
+
+    /// Creates a copy of this task with `${matrix.results}` replaced by the results of the
+    /// expansions it aggregates
+    pub fn with_matrix_results(&self, results: &str) -> Result<Task, GenericError> {
+        self.substitute(MATRIX_RESULTS_PLACEHOLDER, results)
+    }
+
+    fn uses_matrix_results(&self) -> bool {
+        let config =
+            serde_json::to_string(&self.config).expect("Task config could not be serialised");
+        config.contains(MATRIX_RESULTS_PLACEHOLDER)
+    }
+
+    /// Condition that must hold for the task to run. If it doesn't, the task is skipped
+    pub fn when(&self) -> Option<&str> {
+        self.when.as_deref()
+    }
+
+    /// Paths of the files the task writes for downstream tasks
+    pub fn produces(&self) -> &[String] {
+        self.produces.as_deref().unwrap_or_default()
+    }
+
+    /// Paths of the files from upstream tasks the task reads
+    pub fn consumes(&self) -> &[String] {
+        self.consumes.as_deref().unwrap_or_default()
+    }
+
+    /// Globs of the output files of the task to list in the result of the run. The
+    /// files stay on the agent
+    pub fn artifacts(&self) -> &[String] {
+        self.artifacts.as_deref().unwrap_or_default()
+    }
+
+    /// Directory the task runs in, if it sets one. Relative `produces`, `consumes` and
+    /// `artifacts` paths are resolved from it
+    pub fn working_directory(&self) -> Option<&str> {
+        self.config.working_directory()
+    }
+
+    /// Levels the stdout and stderr of the task are logged at
+    pub fn output_log_levels(&self) -> Result<OutputLogLevels, GenericError> {
+        OutputLogLevels::new(
+            self.stdout_log_level,
+            self.stderr_log_level,
+            self.log_level_patterns.as_deref().unwrap_or_default(),
+        )
+    }
+
+    /// Check the task has to pass before it counts as running. Tasks depending on it
+    /// start once it passes rather than once the task has finished
+    pub fn readiness(&self) -> Option<&Readiness> {
+        self.readiness.as_ref()
+    }
+
+    /// Condition, like a file existing, that has to hold before the command of the task
+    /// is started
+    pub fn wait_for(&self) -> Option<&WaitFor> {
+        self.wait_for.as_ref()
+    }
+
+    /// Patterns whose matches are redacted from the output of the task
+    pub fn redact_patterns(&self) -> Result<Vec<Regex>, GenericError> {
+        self.redact
+            .iter()
+            .flatten()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| {
+                    GenericError::WorkflowError(format!("Invalid redact pattern '{pattern}': {e}"))
+                })
+            })
+            .collect()
+    }
+
+    /// Whether the result of this task can be replayed from the agent's cache
+    pub fn cache(&self) -> bool {
+        self.cache.unwrap_or(false)
+    }
+
+    /// Number of times the task is run again after it fails
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(0)
+    }
+
+    /// Whether the task runs after all the other tasks of the workflow have finished,
+    /// whether they succeeded or not, like a `finally` block
+    pub fn cleanup(&self) -> bool {
+        self.cleanup.unwrap_or(false)
+    }
+
+    /// Creates a copy of this cleanup task with the status the rest of the workflow
+    /// finished with set in its `CDKTR_WORKFLOW_STATUS` env var
+    pub fn with_workflow_status(&self, status: &RunStatus) -> Task {
+        let mut config = self.config.clone();
+        config.set_env(WORKFLOW_STATUS_ENV, &status.to_string());
+        Task {
+            config,
+            ..self.clone()
+        }
+    }
+
+    /// Fills in the settings the task doesn't set itself from the defaults of its workflow
+    fn apply_defaults(&mut self, defaults: &TaskDefaults) {
+        self.config.apply_defaults(defaults);
+        self.cache = self.cache.or(defaults.cache);
+        if let Some(default_secrets) = &defaults.secrets {
+            let secrets = self.secrets.get_or_insert_with(Vec::new);
+            for name in default_secrets {
+                if !secrets.contains(name) {
+                    secrets.push(name.clone());
+                }
+            }
+        }
+        self.stdout_log_level = self.stdout_log_level.or(defaults.stdout_log_level);
+        self.stderr_log_level = self.stderr_log_level.or(defaults.stderr_log_level);
+        // patterns of the task are checked first so they take precedence
+        if let Some(patterns) = &defaults.log_level_patterns {
+            self.log_level_patterns
+                .get_or_insert_with(Vec::new)
+                .extend(patterns.iter().cloned());
+        }
+        if let Some(default_redact) = &defaults.redact {
+            let redact = self.redact.get_or_insert_with(Vec::new);
+            for pattern in default_redact {
+                if !redact.contains(pattern) {
+                    redact.push(pattern.clone());
+                }
+            }
+        }
+    }
+
+    /// SHA-256 of the executor inputs. Tasks with the same config share a cache entry. The
+    /// config is hashed with its keys sorted so the key is the same in every process, and
+    /// the key of a UvPython task includes its script so editing the script invalidates it
+    pub async fn cache_key(&self) -> String {
+        let config =
+            serde_json::to_value(&self.config).expect("Task config could not be serialised");
+        let mut hasher = Sha256::new();
+        hasher.update(canonical_json(config).to_string());
+        if let ExecutableTask::UvPython(uvptask) = &self.config
+            && let Ok(script) = fs::read(uvptask.script_file()).await
+        {
+            hasher.update([0]);
+            hasher.update(script);
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Creates a copy of this task with `placeholder` replaced by `value` in the name,
+    /// description and any string values of the task config
+    fn substitute(&self, placeholder: &str, value: &str) -> Result<Task, GenericError> {
+        let config_value = serde_json::to_value(&self.config).map_err(|e| {
+            GenericError::WorkflowError(format!(
+                "Failed to substitute {} for task '{}'. Error: {}",
+                placeholder, self.name, e
+            ))
+        })?;
+        let config =
+            serde_json::from_value(substitute_placeholder(config_value, placeholder, value))
+                .map_err(|e| {
+                    GenericError::WorkflowError(format!(
+                        "Failed to substitute {} for task '{}'. Error: {}",
+                        placeholder, self.name, e
+                    ))
+                })?;
+        Ok(Task {
+            name: self.name.replace(placeholder, value),
+            description: self
+                .description
+                .as_ref()
+                .map(|d| d.replace(placeholder, value)),
+            depends: self.depends.clone(),
+            matrix: self.matrix.clone(),
+            config,
+            cache: self.cache,
+            secrets: self.secrets.clone(),
+            produces: self.produces.clone(),
+            consumes: self.consumes.clone(),
+            artifacts: self.artifacts.clone(),
+            stdout_log_level: self.stdout_log_level,
+            stderr_log_level: self.stderr_log_level,
+            log_level_patterns: self.log_level_patterns.clone(),
+            readiness: self.readiness.clone(),
+            wait_for: self
+                .wait_for
+                .as_ref()
+                .map(|w| w.substitute(placeholder, value)),
+            redact: self.redact.clone(),
+            retries: self.retries,
+            cleanup: self.cleanup,
+            matrix_results: self.matrix_results.clone(),
+            // values are substituted in as string literals so they can't change the
+            // structure of the condition
+            when: self
+                .when
+                .as_ref()
+                .map(|w| w.replace(placeholder, &quote_literal(value))),
+        })
+    }
+
+    /// Names of the secrets the task needs: those listed under `secrets` and any
+    /// referenced with `${secret.NAME}` in its config
+    pub fn secret_refs(&self) -> Vec<String> {
+        let re = Regex::new(r"\$\{secret\.([A-Za-z0-9_\-]+)\}").unwrap();
+        let config =
+            serde_json::to_string(&self.config).expect("Task config could not be serialised");
+        let mut refs: Vec<String> = self.secrets.clone().unwrap_or_default();
+        for cap in re.captures_iter(&config) {
+            let name = cap[1].to_string();
+            if !refs.contains(&name) {
+                refs.push(name);
+            }
+        }
+        refs
+    }
+
+    /// Creates a copy of this task with the `${secret.NAME}` references in its config
+    /// replaced by the resolved values and the secrets listed under `secrets` set in
+    /// its env. The name and description are left untouched so secrets can't leak into
+    /// status updates
+    pub fn with_secrets(&self, values: &HashMap<String, String>) -> Result<Task, GenericError> {
+        let mut config_value = serde_json::to_value(&self.config).map_err(|e| {
+            GenericError::WorkflowError(format!(
+                "Failed to resolve secrets for task '{}'. Error: {}",
+                self.name, e
+            ))
+        })?;
+        for (name, value) in values {
+            config_value = substitute_placeholder(config_value, &secret_placeholder(name), value);
+        }
+        let mut config: ExecutableTask = serde_json::from_value(config_value).map_err(|e| {
+            GenericError::WorkflowError(format!(
+                "Failed to resolve secrets for task '{}'. Error: {}",
+                self.name, e
+            ))
+        })?;
+        for name in self.secrets.iter().flatten() {
+            match values.get(name) {
+                Some(value) => config.set_env(name, value),
+                None => {
+                    return Err(GenericError::WorkflowError(format!(
+                        "Secret '{}' of task '{}' was not resolved",
+                        name, self.name
+                    )));
+                }
+            }
+        }
+        Ok(Task {
+            config,
+            ..self.clone()
+        })
+    }
+
+    /// Creates a concrete copy of this task template for a single matrix item
+    fn expand_for_item(&self, item: &str) -> Result<Task, GenericError> {
+        let mut task = self.substitute(MATRIX_ITEM_PLACEHOLDER, item)?;
+        task.matrix = None;
+        Ok(task)
+    }
+}
+
+/// Rebuilds JSON with the keys of every object in sorted order, so that maps like `env`
+/// serialise the same way however they were built
+fn canonical_json(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = map.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            serde_json::Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, canonical_json(v)))
+                    .collect(),
+            )
+        }
+        serde_json::Value::Array(items) => {
+            serde_json::Value::Array(items.into_iter().map(canonical_json).collect())
+        }
+        other => other,
+    }
+}
+
+fn substitute_placeholder(
+    value: serde_json::Value,
+    placeholder: &str,
+    replacement: &str,
+) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(s) => {
+            serde_json::Value::String(s.replace(placeholder, replacement))
+        }
+        serde_json::Value::Array(values) => serde_json::Value::Array(
+            values
+                .into_iter()
+                .map(|v| substitute_placeholder(v, placeholder, replacement))
+                .collect(),
+        ),
+        serde_json::Value::Object(map) => serde_json::Value::Object(
+            map.into_iter()
+                .map(|(k, v)| (k, substitute_placeholder(v, placeholder, replacement)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+/// What the principal does with a run of a singleton workflow submitted while another
+/// run of it is running
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SingletonMode {
+    /// Keep the run on the queue until the running one finishes
+    #[default]
+    Queue,
+    /// Refuse the run so the caller can submit it again later
+    Reject,
+}
+
+/// What the scheduler does with a fire of the workflow that comes due while the run it
+/// last fired is still queued or running
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapPolicy {
+    /// Fire anyway, so runs can overlap
+    #[default]
+    Allow,
+    /// Drop the fire and wait for the next one
+    Skip,
+    /// Hold the fire back until the running run finishes
+    Queue,
+}
+
+/// Webhook the principal posts a summary of each finished run of the workflow to
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WorkflowNotify {
+    url: String,
+    /// Which finished runs are notified. Defaults to all of them
+    #[serde(default)]
+    on: NotifyOn,
+    /// Whether the summary leaves out everything but the ids, status and duration of
+    /// the run, for webhooks outside of the trust boundary
+    #[serde(default)]
+    redact: bool,
+}
+
+impl WorkflowNotify {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn redact(&self) -> bool {
+        self.redact
+    }
+
+    /// Whether a run that finished with the status is notified
+    pub fn triggered_by(&self, status: &RunStatus) -> bool {
+        match self.on {
+            NotifyOn::All => matches!(
+                status,
+                RunStatus::COMPLETED | RunStatus::FAILED | RunStatus::CRASHED
+            ),
+            NotifyOn::Success => *status == RunStatus::COMPLETED,
+            NotifyOn::Failure => matches!(status, RunStatus::FAILED | RunStatus::CRASHED),
+        }
+    }
+}
+
+/// Finished runs of a workflow that are notified
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyOn {
+    Success,
+    Failure,
+    #[default]
+    All,
+}
+
+/// Type of a workflow parameter. Parameter values are always passed as strings
+/// and are checked to be parseable as the declared type on submission
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+impl ParamType {
+    fn accepts(&self, value: &str) -> bool {
+        match self {
+            Self::String => true,
+            Self::Integer => value.parse::<i64>().is_ok(),
+            Self::Number => value.parse::<f64>().is_ok(),
+            Self::Boolean => value.parse::<bool>().is_ok(),
+        }
+    }
+}
+
+/// Declaration of a parameter that can be passed to a workflow when it is run. Values
+/// are substituted into the tasks wherever `${params.<name>}` appears
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WorkflowParam {
+    #[serde(rename = "type")]
+    param_type: ParamType,
+    #[serde(default)]
+    required: bool,
+    default: Option<serde_json::Value>,
+    description: Option<String>,
+}
+impl WorkflowParam {
+    pub fn param_type(&self) -> ParamType {
+        self.param_type
+    }
+    pub fn required(&self) -> bool {
+        self.required
+    }
+    pub fn description(&self) -> Option<&String> {
+        self.description.as_ref()
+    }
+    /// The default value as it would be passed on submission
+    pub fn default_value(&self) -> Option<String> {
+        self.default.as_ref().map(|v| match v {
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        })
+    }
+}
+
+/// Declaration of a named output of a workflow run, taken from the output of one of its
+/// tasks. The values from the last successful run are substituted into the next run of
+/// the workflow wherever `${last_run.<name>}` appears
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WorkflowOutput {
+    /// id of the task whose output (the last non-empty line it printed to stdout) is kept
+    task: String,
+    /// value used when the workflow has never completed a run that set this output
+    default: Option<String>,
+    description: Option<String>,
+}
+impl WorkflowOutput {
+    pub fn task(&self) -> &str {
+        &self.task
+    }
+    pub fn default_value(&self) -> Option<&String> {
+        self.default.as_ref()
+    }
+    pub fn description(&self) -> Option<&String> {
+        self.description.as_ref()
+    }
+}
+
+/// A single task expanded from a matrix task template
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct MatrixExpansion {
+    task_id: String,
+    item: String,
+}
+impl MatrixExpansion {
+    pub fn task_id(&self) -> &str {
+        &self.task_id
+    }
+    pub fn item(&self) -> &str {
+        &self.item
+    }
+}
+
+/// Expands any task templates that define a `matrix` into one task per item, with ids of the
+/// form `task_id[i]`. Tasks that depend on a template are rewired to depend on every expansion
+/// so that they only run once the whole fan-out has completed, and tasks that use
+/// `${matrix.results}` are given the expansions they aggregate.
+fn expand_matrix_tasks(
+    tasks: &HashMap<String, Task>,
+) -> Result<HashMap<String, Task>, GenericError> {
+    let mut expanded_ids: HashMap<String, Vec<MatrixExpansion>> = HashMap::new();
+    let mut expanded_tasks = HashMap::new();
+    for (task_id, task) in tasks {
+        let items = match &task.matrix {
+            Some(items) => items,
+            None => {
+                expanded_tasks.insert(task_id.clone(), task.clone());
+                continue;
+            }
+        };
+        if items.is_empty() {
+            return Err(GenericError::WorkflowError(format!(
+                "Invalid Workflow. Task '{}' defines an empty matrix",
+                task_id
+            )));
+        }
+        let mut ids = Vec::new();
+        for (i, item) in items.iter().enumerate() {
+            let expanded_id = format!("{}[{}]", task_id, i);
+            if tasks.contains_key(&expanded_id) {
+                return Err(GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Matrix expansion '{}' clashes with an existing task id",
+                    expanded_id
+                )));
+            }
+            expanded_tasks.insert(expanded_id.clone(), task.expand_for_item(item)?);
+            ids.push(MatrixExpansion {
+                task_id: expanded_id,
+                item: item.clone(),
+            });
+        }
+        expanded_ids.insert(task_id.clone(), ids);
+    }
+    for (task_id, task) in expanded_tasks.iter_mut() {
+        let aggregated: Vec<MatrixExpansion> = task
+            .depends
+            .iter()
+            .flatten()
+            .filter_map(|dep| expanded_ids.get(dep))
+            .flatten()
+            .cloned()
+            .collect();
+        if task.uses_matrix_results() {
+            if aggregated.is_empty() {
+                return Err(GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Task '{}' uses {} but does not depend on a matrix task",
+                    task_id, MATRIX_RESULTS_PLACEHOLDER
+                )));
+            }
+            task.matrix_results = Some(aggregated);
+        }
+        if let Some(deps) = task.depends.as_mut() {
+            *deps = deps
+                .iter()
+                .flat_map(|dep| match expanded_ids.get(dep) {
+                    Some(ids) => ids.iter().map(|id| id.task_id.clone()).collect(),
+                    None => vec![dep.clone()],
+                })
+                .collect();
+        }
+    }
+    Ok(expanded_tasks)
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -53,13 +668,47 @@ struct InnerWorkflow {
     cron: Option<String>,
     description: Option<String>,
     start_time: Option<String>,
+    sla_s: Option<u64>,
+    retries: Option<u32>,
+    retry_budget: Option<u32>,
+    params: Option<HashMap<String, WorkflowParam>>,
+    outputs: Option<HashMap<String, WorkflowOutput>>,
+    singleton: Option<bool>,
+    singleton_mode: Option<SingletonMode>,
+    overlap_policy: Option<OverlapPolicy>,
+    exclusive_agent: Option<bool>,
+    blackout: Option<Vec<BlackoutWindow>>,
+    notify: Option<WorkflowNotify>,
+    /// settings every task inherits unless it sets them itself
+    defaults: Option<TaskDefaults>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
     tasks: HashMap<String, Task>,
+    /// overlays patching the workflow for each environment it runs in
+    environments: Option<HashMap<String, serde_norway::Value>>,
 }
 impl InnerWorkflow {
-    /// Checks for cycles and returns a WorkFlowDAG. Returns
+    /// Merges in the tasks of any included libraries. Tasks defined in the workflow
+    /// itself take precedence over included ones with the same id
+    fn resolve_includes(&mut self, path: &str) -> Result<(), GenericError> {
+        if !self.include.is_empty() {
+            let mut tasks = resolve_includes(Path::new(path), &self.include)?;
+            tasks.extend(std::mem::take(&mut self.tasks));
+            self.tasks = tasks;
+        }
+        if self.tasks.is_empty() {
+            return Err(GenericError::WorkflowError(
+                "Invalid Workflow. No tasks defined".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Expands any matrix tasks, checks for cycles and returns a WorkFlowDAG. Returns
     /// error if dag cannot be constructed owing to cycles
     fn gen_dag(&self, name: &str) -> Result<WorkFlowDAG, GenericError> {
-        WorkFlowDAG::from_tasks(name.to_string(), &self.tasks)
+        WorkFlowDAG::from_tasks(name.to_string(), &expand_matrix_tasks(&self.tasks)?)
     }
 }
 
@@ -102,9 +751,7 @@ impl WorkFlowDAG {
                         ) {
                             return Err(GenericError::WorkflowError(format!(
                                 "Invalid Workflow. DAG edge '{}'->'{}' causes a cycle. Error: {}",
-                                dep,
-                                task_id,
-                                e.to_string()
+                                dep, task_id, e
                             )));
                         }
                     }
@@ -123,6 +770,24 @@ impl WorkFlowDAG {
         })
     }
 
+    /// Number of tasks in the longest chain of dependencies
+    pub fn depth(&self) -> usize {
+        let order = daggy::petgraph::algo::toposort(self.inner.graph(), None)
+            .expect("Workflow DAG has a cycle - this is a bug");
+        let mut depths = vec![0; self.inner.node_count()];
+        for node in order {
+            depths[node.index()] = self
+                .inner
+                .parents(node)
+                .iter(&self.inner)
+                .map(|(_, parent)| depths[parent.index()])
+                .max()
+                .unwrap_or(0)
+                + 1;
+        }
+        depths.into_iter().max().unwrap_or(0)
+    }
+
     pub fn get_first_tasks(&self) -> Vec<String> {
         self.first_tasks
             .iter()
@@ -158,9 +823,51 @@ impl WorkFlowDAG {
     pub fn node_count(&self) -> usize {
         self.inner.node_count()
     }
+
+    /// Replaces `placeholder` with `value` across every task in the DAG
+    fn substitute_tasks(&mut self, placeholder: &str, value: &str) -> Result<(), GenericError> {
+        for task in self.task_map.values_mut() {
+            *task = task.substitute(placeholder, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Rejects a definition larger than `CDKTR_WORKFLOW_MAX_FILE_BYTES`
+fn check_definition_size(path: &str, bytes: u64) -> Result<(), GenericError> {
+    let max_bytes = get_cdktr_setting!(CDKTR_WORKFLOW_MAX_FILE_BYTES, usize) as u64;
+    if bytes > max_bytes {
+        return Err(GenericError::WorkflowError(format!(
+            "Invalid Workflow. {path} is {bytes} bytes, more than the {max_bytes} allowed by CDKTR_WORKFLOW_MAX_FILE_BYTES"
+        )));
+    }
+    Ok(())
+}
+
+/// Rejects a workflow with more tasks than `CDKTR_WORKFLOW_MAX_TASKS` or a longer chain of
+/// dependencies than `CDKTR_WORKFLOW_MAX_DEPTH`
+fn check_dag_size(dag: &WorkFlowDAG) -> Result<(), GenericError> {
+    let max_tasks = get_cdktr_setting!(CDKTR_WORKFLOW_MAX_TASKS, usize);
+    if dag.task_map.len() > max_tasks {
+        return Err(GenericError::WorkflowError(format!(
+            "Invalid Workflow. It has {} tasks, more than the {} allowed by CDKTR_WORKFLOW_MAX_TASKS",
+            dag.task_map.len(),
+            max_tasks
+        )));
+    }
+    let max_depth = get_cdktr_setting!(CDKTR_WORKFLOW_MAX_DEPTH, usize);
+    let depth = dag.depth();
+    if depth > max_depth {
+        return Err(GenericError::WorkflowError(format!(
+            "Invalid Workflow. It has a chain of {depth} dependent tasks, more than the {max_depth} allowed by CDKTR_WORKFLOW_MAX_DEPTH"
+        )));
+    }
+    Ok(())
 }
 
 #[async_trait::async_trait]
+/// Loads a definition from a file in the workflow directory. Despite the name, files
+/// with a `.json` extension are read as JSON
 pub trait FromYaml: Sized {
     type Error: Display;
     async fn from_yaml(file_path: &str) -> Result<Self, Self::Error>;
@@ -175,52 +882,193 @@ pub struct Workflow {
     dag: WorkFlowDAG,
     cron: Option<String>,
     start_time: Option<String>,
+    #[serde(default)]
+    sla_s: Option<u64>,
+    #[serde(default)]
+    retries: Option<u32>,
+    /// Most task retries a run of the workflow makes across all of its tasks
+    #[serde(default)]
+    retry_budget: Option<u32>,
+    #[serde(default)]
+    params: HashMap<String, WorkflowParam>,
+    #[serde(default)]
+    outputs: HashMap<String, WorkflowOutput>,
+    /// Whether at most one run of the workflow can be running at a time
+    #[serde(default)]
+    singleton: bool,
+    #[serde(default)]
+    singleton_mode: SingletonMode,
+    /// Whether the scheduler fires the workflow while the run it last fired is running
+    #[serde(default)]
+    overlap_policy: OverlapPolicy,
+    /// Whether a run of the workflow takes up a whole agent, with no other workflows
+    /// running on the agent alongside it
+    #[serde(default)]
+    exclusive_agent: bool,
+    /// Times of day the schedule of the workflow doesn't fire during
+    #[serde(default)]
+    blackout: Vec<BlackoutWindow>,
+    #[serde(default)]
+    notify: Option<WorkflowNotify>,
+    /// Instance id given to the run by the principal before it starts. Agents generate
+    /// one for runs that don't have it
+    #[serde(default)]
+    instance_id: Option<String>,
+    /// Attempt number of the run within a chain of whole-workflow retries, from 1
+    #[serde(default)]
+    attempt: u32,
+    /// Instance id of the first run of the chain of retries the run belongs to
+    #[serde(default)]
+    retry_of: Option<String>,
+    /// Whether the run only logs the commands its tasks would run instead of running them
+    #[serde(default)]
+    dry_run: bool,
+    /// The workflow as patched by each of its `environments`. The principal picks one
+    /// before a run is queued, so they aren't sent on to agents
+    #[serde(skip)]
+    environments: HashMap<String, Workflow>,
 }
 #[async_trait]
 impl FromYaml for Workflow {
     type Error = GenericError;
     async fn from_yaml(file_path: &str) -> Result<Self, GenericError> {
         let file = Path::new(file_path);
+        // checked before reading so an oversized file is never loaded into memory
+        if let Ok(metadata) = fs::metadata(file).await {
+            check_definition_size(file_path, metadata.len())?;
+        }
         let contents = match fs::read_to_string(file).await {
             Ok(s) => s,
             Err(e) => {
                 return Err(GenericError::WorkflowError(format!(
                     "Error reading yaml file {:?}. Error: {}",
                     file.to_str(),
-                    e.to_string()
+                    e
                 )));
             }
         };
         // let name = key_from_path(file.to_path_buf(), file.parent().unwrap().to_path_buf());
 
-        let workflow = Self::new(file_path.to_string(), &contents)?;
+        let workflow = match file.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(file_path.to_string(), &contents)?,
+            _ => Self::new(file_path.to_string(), &contents)?,
+        };
         workflow.validate()?;
         Ok(workflow)
     }
 }
 impl Workflow {
     pub fn new(path: String, contents: &str) -> Result<Self, GenericError> {
-        let inner_res = serde_norway::from_str::<InnerWorkflow>(contents);
+        check_definition_size(&path, contents.len() as u64)?;
+        let inner_res = from_yaml::<InnerWorkflow>(contents);
         match inner_res {
-            Ok(inner) => {
-                let dag = inner.gen_dag(&inner.name)?;
-                Ok(Self {
-                    id: path_to_workflow_id(&path)?,
-                    name: inner.name,
-                    description: inner.description,
-                    path,
-                    dag,
-                    cron: inner.cron,
-                    start_time: inner.start_time,
-                })
-            }
+            Ok(inner) => Self::from_inner(path, inner),
             Err(e) => Err(GenericError::ParseError(format!(
                 "Failed to parse workflow yaml. Error: {}",
-                e.to_string()
+                e
             ))),
         }
     }
 
+    /// Same as `new` for a definition written as JSON. Task configs are given as
+    /// `{"Subprocess": {...}}` in place of the YAML `!Subprocess` tag
+    pub fn from_json(path: String, contents: &str) -> Result<Self, GenericError> {
+        check_definition_size(&path, contents.len() as u64)?;
+        let inner_res = serde_json::from_str::<InnerWorkflow>(contents);
+        match inner_res {
+            Ok(inner) => Self::from_inner(path, inner),
+            Err(e) => Err(GenericError::ParseError(format!(
+                "Failed to parse workflow json. Error: {}",
+                e
+            ))),
+        }
+    }
+
+    fn from_inner(path: String, mut inner: InnerWorkflow) -> Result<Self, GenericError> {
+        let environments = match inner.environments.take() {
+            Some(overlays) => Self::resolve_environments(&path, &inner, overlays)?,
+            None => HashMap::new(),
+        };
+        inner.resolve_includes(&path)?;
+        if let Some(defaults) = &inner.defaults {
+            for task in inner.tasks.values_mut() {
+                task.apply_defaults(defaults);
+            }
+        }
+        let dag = inner.gen_dag(&inner.name)?;
+        check_dag_size(&dag)?;
+        Ok(Self {
+            id: path_to_workflow_id(&path)?,
+            name: inner.name,
+            description: inner.description,
+            path,
+            dag,
+            cron: inner.cron,
+            start_time: inner.start_time,
+            sla_s: inner.sla_s,
+            retries: inner.retries,
+            retry_budget: inner.retry_budget,
+            params: inner.params.unwrap_or_default(),
+            outputs: inner.outputs.unwrap_or_default(),
+            singleton: inner.singleton.unwrap_or(false),
+            singleton_mode: inner.singleton_mode.unwrap_or_default(),
+            overlap_policy: inner.overlap_policy.unwrap_or_default(),
+            exclusive_agent: inner.exclusive_agent.unwrap_or(false),
+            blackout: inner.blackout.unwrap_or_default(),
+            notify: inner.notify,
+            instance_id: None,
+            attempt: 1,
+            retry_of: None,
+            dry_run: false,
+            environments,
+        })
+    }
+
+    /// Builds the workflow for each environment by applying its overlay to the definition
+    /// as written, before includes and defaults are resolved
+    fn resolve_environments(
+        path: &str,
+        inner: &InnerWorkflow,
+        overlays: HashMap<String, serde_norway::Value>,
+    ) -> Result<HashMap<String, Self>, GenericError> {
+        let definition = serde_norway::to_value(inner).map_err(|e| {
+            GenericError::ParseError(format!("Failed to read workflow definition. Error: {}", e))
+        })?;
+        overlays
+            .into_iter()
+            .map(|(environment, overlay)| {
+                let mut patched = definition.clone();
+                apply_overlay(&mut patched, overlay);
+                let mut inner =
+                    serde_norway::from_value::<InnerWorkflow>(patched).map_err(|e| {
+                        GenericError::ParseError(format!(
+                            "Failed to apply environment '{}'. Error: {}",
+                            environment, e
+                        ))
+                    })?;
+                // environments can't be nested
+                inner.environments = None;
+                let workflow = Self::from_inner(path.to_string(), inner)?;
+                Ok((environment, workflow))
+            })
+            .collect()
+    }
+
+    /// The workflow as patched for one of its `environments`. Errors if the workflow
+    /// doesn't define the environment
+    pub fn for_environment(&self, environment: &str) -> Result<Self, GenericError> {
+        self.environments.get(environment).cloned().ok_or_else(|| {
+            let mut defined: Vec<&str> = self.environments.keys().map(String::as_str).collect();
+            defined.sort();
+            GenericError::WorkflowError(format!(
+                "Workflow {} has no environment '{}'. Defined environments: [{}]",
+                self.id,
+                environment,
+                defined.join(", ")
+            ))
+        })
+    }
+
     pub fn get_dag(&self) -> &WorkFlowDAG {
         &self.dag
     }
@@ -238,6 +1086,13 @@ impl Workflow {
         &self.id
     }
 
+    /// Overrides the id derived from the workflow path, for workflows loaded from
+    /// outside of `CDKTR_WORKFLOW_DIR`
+    pub(crate) fn with_id(mut self, id: String) -> Self {
+        self.id = id;
+        self
+    }
+
     pub fn path(&self) -> &String {
         &self.path
     }
@@ -252,8 +1107,220 @@ impl Workflow {
     pub fn description(&self) -> Option<&String> {
         self.description.as_ref()
     }
+
+    /// Expected maximum duration of a run. Runs that take longer aren't cancelled,
+    /// they are only reported as having breached the SLA
+    pub fn sla(&self) -> Option<Duration> {
+        self.sla_s.map(Duration::from_secs)
+    }
+
+    pub fn params(&self) -> &HashMap<String, WorkflowParam> {
+        &self.params
+    }
+
+    pub fn outputs(&self) -> &HashMap<String, WorkflowOutput> {
+        &self.outputs
+    }
+
+    /// Values of the declared outputs of a finished run, looked up from the outputs of
+    /// its tasks. Outputs whose task didn't print anything are left out
+    pub fn collect_outputs(
+        &self,
+        task_output: impl Fn(&str) -> Option<String>,
+    ) -> HashMap<String, String> {
+        self.outputs
+            .iter()
+            .filter_map(|(name, output)| task_output(&output.task).map(|v| (name.clone(), v)))
+            .collect()
+    }
+
+    /// Returns a copy of the workflow for a single run with the outputs of the last
+    /// successful run substituted into its tasks. Outputs the last run didn't set fall
+    /// back to their declared default, or an empty string if they have none
+    pub fn with_last_run(&self, last_run: &HashMap<String, String>) -> Result<Self, GenericError> {
+        let mut workflow = self.clone();
+        for (name, output) in &self.outputs {
+            let value = last_run
+                .get(name)
+                .or(output.default.as_ref())
+                .map(String::as_str)
+                .unwrap_or("");
+            workflow
+                .dag
+                .substitute_tasks(&last_run_placeholder(name), value)?;
+        }
+        Ok(workflow)
+    }
+
+    /// Executors needed to run every task of the workflow
+    pub fn executors(&self) -> BTreeSet<&'static str> {
+        self.dag
+            .task_map
+            .values()
+            .map(|task| task.config.executor())
+            .collect()
+    }
+
+    /// How runs submitted while another run of the workflow is running are handled, if
+    /// the workflow is a singleton
+    pub fn singleton(&self) -> Option<SingletonMode> {
+        self.singleton.then_some(self.singleton_mode)
+    }
+
+    /// What the scheduler does when the workflow comes due while the run it last fired
+    /// is still running
+    pub fn overlap_policy(&self) -> OverlapPolicy {
+        self.overlap_policy
+    }
+
+    /// Whether runs of the workflow need an agent that isn't running anything else
+    pub fn exclusive_agent(&self) -> bool {
+        self.exclusive_agent
+    }
+
+    /// Windows the schedule of the workflow is deferred past, on top of those set for
+    /// every workflow with CDKTR_SCHEDULER_BLACKOUT
+    pub fn blackout(&self) -> &[BlackoutWindow] {
+        &self.blackout
+    }
+
+    /// Webhook notified when runs of the workflow finish, if any
+    pub fn notify(&self) -> Option<&WorkflowNotify> {
+        self.notify.as_ref()
+    }
+
+    /// Number of times a run of the workflow that ends FAILED is rerun from the start
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(0)
+    }
+
+    /// Most times a run of the workflow retries failed tasks across all of its tasks.
+    /// None if the retries of each task are the only limit
+    pub fn retry_budget(&self) -> Option<u32> {
+        self.retry_budget
+    }
+
+    pub fn instance_id(&self) -> Option<&String> {
+        self.instance_id.as_ref()
+    }
+
+    /// Gives the run its instance id ahead of it starting
+    pub fn with_instance_id(mut self, instance_id: String) -> Self {
+        self.instance_id = Some(instance_id);
+        self
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt.max(1)
+    }
+
+    pub fn retry_of(&self) -> Option<&String> {
+        self.retry_of.as_ref()
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Makes the run a dry run, where agents log the resolved command of each task in
+    /// DAG order rather than spawning it
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// The rerun of this run after it failed, with the same params and a fresh instance
+    /// id. None once the workflow has used up all of its retries
+    pub fn next_attempt(&self, instance_id: String) -> Option<Self> {
+        if self.attempt() > self.retries().min(MAX_WORKFLOW_RETRIES) {
+            return None;
+        }
+        let mut retry = self.clone();
+        retry.retry_of = self.retry_of.clone().or_else(|| self.instance_id.clone());
+        retry.instance_id = Some(instance_id);
+        retry.attempt = self.attempt() + 1;
+        Some(retry)
+    }
+
+    /// A new run of this run with the same workflow definition and params, e.g. to
+    /// reproduce it. The run starts from its first attempt and has no instance id yet
+    pub fn replay(&self) -> Self {
+        let mut replay = self.clone();
+        replay.instance_id = None;
+        replay.retry_of = None;
+        replay.attempt = 1;
+        replay
+    }
     //
 
+    /// Validates the params provided for a run against the params declared by the
+    /// workflow, filling in any defaults. Unknown params, missing required params and
+    /// values that don't match the declared type are all rejected
+    pub fn resolve_params(
+        &self,
+        provided: &HashMap<String, String>,
+    ) -> Result<HashMap<String, String>, GenericError> {
+        let mut errors = Vec::new();
+        let mut unknown: Vec<&String> = provided
+            .keys()
+            .filter(|name| !self.params.contains_key(*name))
+            .collect();
+        unknown.sort();
+        for name in unknown {
+            errors.push(format!("unknown param '{name}'"));
+        }
+
+        let mut resolved = HashMap::new();
+        let mut declared: Vec<(&String, &WorkflowParam)> = self.params.iter().collect();
+        declared.sort_by_key(|(name, _)| *name);
+        for (name, param) in declared {
+            let value = match provided.get(name) {
+                Some(v) => v.clone(),
+                None => match param.default_value() {
+                    Some(v) => v,
+                    None if param.required => {
+                        errors.push(format!("missing required param '{name}'"));
+                        continue;
+                    }
+                    None => continue,
+                },
+            };
+            if !param.param_type.accepts(&value) {
+                errors.push(format!(
+                    "param '{name}' expects a value of type {:?} but got '{value}'",
+                    param.param_type
+                ));
+                continue;
+            }
+            resolved.insert(name.clone(), value);
+        }
+
+        if errors.is_empty() {
+            Ok(resolved)
+        } else {
+            Err(GenericError::WorkflowError(format!(
+                "Invalid params for workflow '{}': {}",
+                self.id,
+                errors.join(", ")
+            )))
+        }
+    }
+
+    /// Returns a copy of the workflow for a single run with the provided params validated
+    /// and substituted into its tasks. Declared params that were neither provided nor
+    /// have a default are substituted with an empty string
+    pub fn with_params(&self, provided: &HashMap<String, String>) -> Result<Self, GenericError> {
+        let resolved = self.resolve_params(provided)?;
+        let mut workflow = self.clone();
+        for name in self.params.keys() {
+            let value = resolved.get(name).map(String::as_str).unwrap_or("");
+            workflow
+                .dag
+                .substitute_tasks(&param_placeholder(name), value)?;
+        }
+        Ok(workflow)
+    }
+
     pub fn start_time_utc(&self) -> Result<chrono::DateTime<chrono::Utc>, GenericError> {
         let start_time = if let Some(t) = &self.start_time {
             t
@@ -293,6 +1360,133 @@ impl Workflow {
 
     pub fn validate(&self) -> Result<(), GenericError> {
         self.start_time_utc()?;
+        for (environment, workflow) in &self.environments {
+            workflow.validate().map_err(|e| {
+                GenericError::WorkflowError(format!("Environment '{}': {}", environment, e))
+            })?;
+        }
+        if self.retries() > MAX_WORKFLOW_RETRIES {
+            return Err(GenericError::WorkflowError(format!(
+                "Invalid Workflow. retries can't be more than {}",
+                MAX_WORKFLOW_RETRIES
+            )));
+        }
+        if self.sla_s == Some(0) {
+            return Err(GenericError::WorkflowError(
+                "Invalid Workflow. sla_s must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(notify) = &self.notify
+            && !(notify.url.starts_with("http://") || notify.url.starts_with("https://"))
+        {
+            return Err(GenericError::WorkflowError(format!(
+                "Invalid Workflow. notify url '{}' must be an http or https URL",
+                notify.url
+            )));
+        }
+        for (name, param) in &self.params {
+            if let Some(default) = param.default_value()
+                && !param.param_type.accepts(&default)
+            {
+                return Err(GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Default '{}' of param '{}' is not of type {:?}",
+                    default, name, param.param_type
+                )));
+            }
+        }
+        for (name, output) in &self.outputs {
+            if self.dag.get_task(&output.task).is_none() {
+                return Err(GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Output '{}' is taken from task '{}' which does not exist",
+                    name, output.task
+                )));
+            }
+        }
+        for (task_id, task) in &self.dag.task_map {
+            let deps = task.get_dependencies().unwrap_or_default();
+            // artifacts are only guaranteed to be uploaded before the tasks downstream run
+            if let Some(path) = task.consumes().iter().find(|path| {
+                !deps.iter().any(|dep| {
+                    self.dag
+                        .get_task(dep)
+                        .is_some_and(|dep| dep.produces().contains(path))
+                })
+            }) {
+                return Err(GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Task '{}' consumes '{}' which none of the tasks it depends on produces",
+                    task_id, path
+                )));
+            }
+            if task.retries() > MAX_WORKFLOW_RETRIES {
+                return Err(GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Task '{}' can't have more than {} retries",
+                    task_id, MAX_WORKFLOW_RETRIES
+                )));
+            }
+            // cleanup tasks run once every other task has finished
+            if task.cleanup() && !deps.is_empty() {
+                return Err(GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Cleanup task '{}' can't depend on other tasks as it runs after all of them",
+                    task_id
+                )));
+            }
+            if let Some(dep) = deps
+                .iter()
+                .find(|dep| self.dag.get_task(dep).is_some_and(Task::cleanup))
+            {
+                return Err(GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Task '{}' can't depend on cleanup task '{}'",
+                    task_id, dep
+                )));
+            }
+            task.output_log_levels().map_err(|e| {
+                GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Task '{}' has an invalid log level pattern. {}",
+                    task_id, e
+                ))
+            })?;
+            task.redact_patterns().map_err(|e| {
+                GenericError::WorkflowError(format!(
+                    "Invalid Workflow. Task '{}' has an invalid redact pattern. {}",
+                    task_id, e
+                ))
+            })?;
+            if let Some(readiness) = task.readiness() {
+                readiness.probe().map_err(|e| {
+                    GenericError::WorkflowError(format!(
+                        "Invalid Workflow. Task '{}' has an invalid readiness check. {}",
+                        task_id, e
+                    ))
+                })?;
+            }
+            if let Some(wait_for) = task.wait_for() {
+                wait_for.condition().map_err(|e| {
+                    GenericError::WorkflowError(format!(
+                        "Invalid Workflow. Task '{}' has an invalid wait_for condition. {}",
+                        task_id, e
+                    ))
+                })?;
+            }
+            if let Some(when) = task.when() {
+                let condition = Condition::parse(when).map_err(|e| {
+                    GenericError::WorkflowError(format!(
+                        "Invalid Workflow. Task '{}' has an invalid condition. {}",
+                        task_id, e
+                    ))
+                })?;
+                // outputs are only guaranteed to be available from upstream tasks
+                if let Some(output_ref) = condition
+                    .output_refs()
+                    .into_iter()
+                    .find(|output_ref| !deps.iter().any(|dep| dep == output_ref))
+                {
+                    return Err(GenericError::WorkflowError(format!(
+                        "Invalid Workflow. Condition of task '{}' uses the output of '{}' which it does not depend on",
+                        task_id, output_ref
+                    )));
+                }
+            }
+        }
         Ok(())
     }
 }
@@ -437,6 +1631,481 @@ tasks:
         assert_eq!(deps, vec!["task3", "task4"]);
     }
 
+    #[test]
+    fn test_matrix_expansion() {
+        let yaml = r#"
+name: Matrix Flow
+tasks:
+  extract:
+    name: Extract ${matrix.item}
+    matrix: ["customers", "orders", "products"]
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["--table", "${matrix.item}"]
+  aggregate:
+    name: Aggregate
+    depends: ["extract"]
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["done"]
+        "#;
+        let workflow = Workflow::new("fake/path/matrix.yml".to_string(), yaml).unwrap();
+        let dag = workflow.get_dag();
+        assert_eq!(dag.node_count(), 4);
+
+        let mut first_tasks = dag.get_first_tasks();
+        first_tasks.sort();
+        assert_eq!(first_tasks, vec!["extract[0]", "extract[1]", "extract[2]"]);
+        assert!(workflow.get_task("extract").is_none());
+
+        for (i, table) in ["customers", "orders", "products"].iter().enumerate() {
+            let task_id = format!("extract[{}]", i);
+            assert_eq!(dag.get_dependents(&task_id).unwrap(), vec!["aggregate"]);
+            let task = workflow.get_task(&task_id).unwrap();
+            assert_eq!(task.name(), format!("Extract {}", table));
+            assert_eq!(
+                vec!["--table".to_string(), table.to_string()],
+                match &task.config {
+                    ExecutableTask::Subprocess(cfg) => cfg.args.clone(),
+                    _ => panic!("Wrong enum type"),
+                }
+            );
+        }
+    }
+
+    fn chain_of_tasks(n: usize, padding: usize) -> String {
+        let mut yaml = format!(
+            "name: Long Flow\ndescription: {}\ntasks:\n",
+            "x".repeat(padding)
+        );
+        for i in 0..n {
+            let depends = if i == 0 {
+                String::new()
+            } else {
+                format!("    depends: [\"t{}\"]\n", i - 1)
+            };
+            yaml.push_str(&format!(
+                "  t{i}:\n    name: T{i}\n{depends}    config: !Subprocess\n      cmd: echo\n      args: []\n"
+            ));
+        }
+        yaml
+    }
+
+    #[test]
+    fn test_oversized_workflows_rejected() {
+        let workflow = Workflow::new("fake/path/long.yml".to_string(), &chain_of_tasks(100, 0));
+        assert_eq!(workflow.unwrap().get_dag().depth(), 100);
+
+        for (yaml, setting) in [
+            (
+                chain_of_tasks(1, 1_048_576),
+                "CDKTR_WORKFLOW_MAX_FILE_BYTES",
+            ),
+            (chain_of_tasks(101, 0), "CDKTR_WORKFLOW_MAX_DEPTH"),
+        ] {
+            let err = Workflow::new("fake/path/long.yml".to_string(), &yaml).unwrap_err();
+            assert!(err.to_string().contains(setting), "{err}");
+        }
+
+        let matrix = format!(
+            "name: Wide Flow\ntasks:\n  t:\n    name: T\n    matrix: [{}]\n    config: !Subprocess\n      cmd: echo\n      args: []\n",
+            (0..1_001)
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let err = Workflow::new("fake/path/wide.yml".to_string(), &matrix).unwrap_err();
+        assert!(
+            err.to_string().contains("CDKTR_WORKFLOW_MAX_TASKS"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_empty_matrix_is_invalid() {
+        let yaml = r#"
+name: Matrix Flow
+tasks:
+  extract:
+    name: Extract
+    matrix: []
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["${matrix.item}"]
+        "#;
+        assert!(Workflow::new("fake/path/matrix.yml".to_string(), yaml).is_err());
+    }
+
+    #[test]
+    fn test_matrix_results_only_from_matrix_tasks() {
+        let yaml = r#"
+name: Count Flow
+tasks:
+  count:
+    name: Count ${matrix.item}
+    matrix: ["a", "b"]
+    config:
+      !Subprocess
+      cmd: wc
+      args: ["-l", "${matrix.item}.csv"]
+  total:
+    name: Total
+    depends: ["count"]
+    config:
+      !Subprocess
+      cmd: ./total.sh
+      args: ["${matrix.results}"]
+        "#;
+        let workflow = Workflow::new("fake/path/count.yml".to_string(), yaml).unwrap();
+        let aggregated: Vec<(&str, &str)> = workflow
+            .get_task("total")
+            .unwrap()
+            .matrix_results()
+            .iter()
+            .map(|expansion| (expansion.task_id(), expansion.item()))
+            .collect();
+        assert_eq!(aggregated, vec![("count[0]", "a"), ("count[1]", "b")]);
+        assert!(
+            workflow
+                .get_task("count[0]")
+                .unwrap()
+                .matrix_results()
+                .is_empty()
+        );
+
+        let yaml = yaml.replace("depends: [\"count\"]", "depends: []");
+        let err = Workflow::new("fake/path/count.yml".to_string(), &yaml).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("uses ${matrix.results} but does not depend on a matrix task"),
+            "{err}"
+        );
+    }
+
+    fn get_params_workflow() -> Workflow {
+        let yaml = r#"
+name: Params Flow
+params:
+  table:
+    type: string
+    required: true
+  batch_size:
+    type: integer
+    default: 100
+  dry_run:
+    type: boolean
+tasks:
+  extract:
+    name: Extract ${params.table}
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["--table", "${params.table}", "--batch-size", "${params.batch_size}"]
+        "#;
+        Workflow::new("fake/path/params.yml".to_string(), yaml).unwrap()
+    }
+
+    fn get_conditional_workflow(when: &str, depends: &str) -> Result<Workflow, GenericError> {
+        let yaml = format!(
+            r#"
+name: Conditional Flow
+start_time: 2025-01-20T12:30:00+00:00
+params:
+  env:
+    type: string
+    default: dev
+tasks:
+  check:
+    name: Check
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["deploy"]
+  deploy:
+    name: Deploy
+    depends: {depends}
+    when: "{when}"
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["deploying"]
+        "#
+        );
+        let workflow = Workflow::new("fake/path/conditional.yml".to_string(), &yaml)?;
+        workflow.validate()?;
+        Ok(workflow)
+    }
+
+    #[test]
+    fn test_when_condition_validation() {
+        assert!(get_conditional_workflow("${outputs.check} == 'deploy'", "[\"check\"]").is_ok());
+        // invalid syntax
+        assert!(get_conditional_workflow("${outputs.check} ==", "[\"check\"]").is_err());
+        // output of a task that isn't upstream
+        assert!(get_conditional_workflow("${outputs.check} == 'deploy'", "[]").is_err());
+    }
+
+    fn get_artifact_workflow(depends: &str) -> Result<Workflow, GenericError> {
+        let yaml = format!(
+            r#"
+name: Artifact Flow
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  extract:
+    name: Extract
+    produces: [/tmp/extract.csv]
+    config:
+      !Subprocess
+      cmd: touch
+      args: ["/tmp/extract.csv"]
+  load:
+    name: Load
+    depends: {depends}
+    consumes: [/tmp/extract.csv]
+    config:
+      !Subprocess
+      cmd: cat
+      args: ["/tmp/extract.csv"]
+        "#
+        );
+        let workflow = Workflow::new("fake/path/artifacts.yml".to_string(), &yaml)?;
+        workflow.validate()?;
+        Ok(workflow)
+    }
+
+    #[test]
+    fn test_consumed_artifact_validation() {
+        let workflow = get_artifact_workflow("[\"extract\"]").unwrap();
+        let load = workflow.get_task("load").unwrap();
+        assert_eq!(load.consumes(), ["/tmp/extract.csv"]);
+        assert!(load.produces().is_empty());
+        // artifact of a task that isn't upstream
+        assert!(get_artifact_workflow("[]").is_err());
+    }
+
+    fn get_cleanup_workflow(extract: &str, teardown: &str) -> Result<Workflow, GenericError> {
+        let yaml = format!(
+            r#"
+name: Cleanup Flow
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  extract:
+    name: Extract
+    {extract}
+    config:
+      !Subprocess
+      cmd: echo
+      args: ["extracted"]
+  teardown:
+    name: Teardown
+    cleanup: true
+    {teardown}
+    config:
+      !Subprocess
+      cmd: rm
+      args: ["-f", "/tmp/extract.csv"]
+        "#
+        );
+        let workflow = Workflow::new("fake/path/cleanup.yml".to_string(), &yaml)?;
+        workflow.validate()?;
+        Ok(workflow)
+    }
+
+    #[test]
+    fn test_cleanup_task_validation() {
+        let workflow = get_cleanup_workflow("", "").unwrap();
+        let teardown = workflow.get_task("teardown").unwrap();
+        assert!(teardown.cleanup());
+        assert!(!workflow.get_task("extract").unwrap().cleanup());
+        match teardown
+            .with_workflow_status(&RunStatus::FAILED)
+            .get_exe_task()
+        {
+            ExecutableTask::Subprocess(task) => {
+                assert_eq!(task.env.unwrap()["CDKTR_WORKFLOW_STATUS"], "FAILED")
+            }
+            other => panic!("unexpected config {:?}", other),
+        }
+        // cleanup tasks run after every other task so can't be ordered against them
+        assert!(get_cleanup_workflow("", "depends: [\"extract\"]").is_err());
+        assert!(get_cleanup_workflow("depends: [\"teardown\"]", "").is_err());
+    }
+
+    #[test]
+    fn test_when_condition_params_substituted_as_literals() {
+        let workflow =
+            get_conditional_workflow("${params.env} == 'prod' && true", "[\"check\"]").unwrap();
+        let provided = HashMap::from([("env".to_string(), "prod' || 'x".to_string())]);
+        let run = workflow.with_params(&provided).unwrap();
+        let when = run.get_task("deploy").unwrap().when().unwrap();
+        // the quote in the value can't break out of the string literal
+        assert!(!Condition::parse(when).unwrap().evaluate(&|_| None).unwrap());
+
+        let provided = HashMap::from([("env".to_string(), "prod".to_string())]);
+        let run = workflow.with_params(&provided).unwrap();
+        let when = run.get_task("deploy").unwrap().when().unwrap();
+        assert!(Condition::parse(when).unwrap().evaluate(&|_| None).unwrap());
+    }
+
+    #[test]
+    fn test_with_params_valid() {
+        let workflow = get_params_workflow();
+        let provided = HashMap::from([("table".to_string(), "orders".to_string())]);
+        let resolved = workflow.resolve_params(&provided).unwrap();
+        assert_eq!(
+            resolved,
+            HashMap::from([
+                ("table".to_string(), "orders".to_string()),
+                ("batch_size".to_string(), "100".to_string()),
+            ])
+        );
+
+        let run = workflow.with_params(&provided).unwrap();
+        let task = run.get_task("extract").unwrap();
+        assert_eq!(task.name(), "Extract orders");
+        assert_eq!(
+            vec!["--table", "orders", "--batch-size", "100"],
+            match &task.config {
+                ExecutableTask::Subprocess(cfg) => cfg.args.clone(),
+                _ => panic!("Wrong enum type"),
+            }
+        );
+        // the stored workflow template is left untouched
+        assert_eq!(
+            workflow.get_task("extract").unwrap().name(),
+            "Extract ${params.table}"
+        );
+    }
+
+    #[test]
+    fn test_with_params_missing_required() {
+        let workflow = get_params_workflow();
+        let provided = HashMap::from([("batch_size".to_string(), "5".to_string())]);
+        match workflow.with_params(&provided) {
+            Err(GenericError::WorkflowError(msg)) => {
+                assert!(msg.contains("missing required param 'table'"))
+            }
+            other => panic!("Expected missing param error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_with_params_unknown_param() {
+        let workflow = get_params_workflow();
+        let provided = HashMap::from([
+            ("table".to_string(), "orders".to_string()),
+            ("tabel".to_string(), "orders".to_string()),
+        ]);
+        match workflow.with_params(&provided) {
+            Err(GenericError::WorkflowError(msg)) => {
+                assert!(msg.contains("unknown param 'tabel'"))
+            }
+            other => panic!("Expected unknown param error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_with_params_wrong_type() {
+        let workflow = get_params_workflow();
+        let provided = HashMap::from([
+            ("table".to_string(), "orders".to_string()),
+            ("batch_size".to_string(), "lots".to_string()),
+        ]);
+        assert!(workflow.with_params(&provided).is_err());
+    }
+
+    fn get_incremental_workflow(output_task: &str) -> Result<Workflow, GenericError> {
+        let yaml = format!(
+            r#"
+name: Incremental
+start_time: 2025-01-20T12:30:00+00:00
+outputs:
+  watermark:
+    task: {output_task}
+    default: "1970-01-01"
+tasks:
+  extract:
+    name: Extract since ${{last_run.watermark}}
+    config:
+      !Subprocess
+      cmd: extract
+      args: ["--since", "${{last_run.watermark}}"]
+        "#
+        );
+        let workflow = Workflow::new("fake/path/incremental.yml".to_string(), &yaml)?;
+        workflow.validate()?;
+        Ok(workflow)
+    }
+
+    #[test]
+    fn test_with_last_run_chains_outputs() {
+        let workflow = get_incremental_workflow("extract").unwrap();
+        // the first ever run has no previous values so uses the default
+        let first = workflow.with_last_run(&HashMap::new()).unwrap();
+        assert_eq!(
+            first.get_task("extract").unwrap().name(),
+            "Extract since 1970-01-01"
+        );
+        let outputs = first.collect_outputs(|task_id| {
+            (task_id == "extract").then(|| "2025-06-01T00:00:00Z".to_string())
+        });
+        assert_eq!(
+            outputs,
+            HashMap::from([("watermark".to_string(), "2025-06-01T00:00:00Z".to_string())])
+        );
+
+        let second = workflow.with_last_run(&outputs).unwrap();
+        assert_eq!(
+            vec!["--since", "2025-06-01T00:00:00Z"],
+            match &second.get_task("extract").unwrap().config {
+                ExecutableTask::Subprocess(cfg) => cfg.args.clone(),
+                _ => panic!("Wrong enum type"),
+            }
+        );
+        // an output the task didn't print is left out rather than recorded as empty
+        assert!(second.collect_outputs(|_| None).is_empty());
+    }
+
+    #[test]
+    fn test_output_of_unknown_task() {
+        let err = get_incremental_workflow("load").unwrap_err();
+        assert!(err.to_string().contains("Output 'watermark'"));
+    }
+
+    #[test]
+    fn test_next_attempt() {
+        let yaml = r#"
+name: Flaky
+start_time: 2025-01-20T12:30:00+00:00
+retries: 2
+tasks:
+  task1:
+    name: Task 1
+    config: !Subprocess
+      cmd: "false"
+      args: []
+"#;
+        let workflow = Workflow::new("fake/path/flaky.yml".to_string(), yaml)
+            .unwrap()
+            .with_instance_id("first".to_string());
+        assert_eq!(workflow.attempt(), 1);
+        let second = workflow.next_attempt("second".to_string()).unwrap();
+        assert_eq!(second.attempt(), 2);
+        assert_eq!(second.instance_id().unwrap(), "second");
+        assert_eq!(second.retry_of().unwrap(), "first");
+        let third = second.next_attempt("third".to_string()).unwrap();
+        assert_eq!(third.attempt(), 3);
+        assert_eq!(third.retry_of().unwrap(), "first");
+        assert!(third.next_attempt("fourth".to_string()).is_none());
+
+        let too_many = yaml.replace("retries: 2", "retries: 11");
+        let workflow = Workflow::new("fake/path/flaky.yml".to_string(), &too_many).unwrap();
+        assert!(workflow.validate().is_err());
+    }
+
     #[test]
     fn test_path_to_workflow_id() {
         let cases = vec![
@@ -454,4 +2123,35 @@ tasks:
             assert_eq!(result, expected, "Failed on input: {}", input);
         }
     }
+
+    fn cache_key_task(config: &str) -> Task {
+        from_yaml(&format!("name: Cached\ncache: true\nconfig:\n{config}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cache_key_stable_and_tracks_script() {
+        let env_ab = "  !Subprocess\n  cmd: echo\n  args: []\n  env:\n    A: \"1\"\n    B: \"2\"\n";
+        let env_ba = "  !Subprocess\n  cmd: echo\n  args: []\n  env:\n    B: \"2\"\n    A: \"1\"\n";
+        let key = cache_key_task(env_ab).cache_key().await;
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, cache_key_task(env_ba).cache_key().await);
+        assert_ne!(
+            key,
+            cache_key_task("  !Subprocess\n  cmd: echo\n  args: []\n")
+                .cache_key()
+                .await
+        );
+
+        // editing the script of a UvPython task gives it a new key
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("job.py"), "print('one')\n").unwrap();
+        let task = cache_key_task(&format!(
+            "  !UvPython\n  script_path: job.py\n  working_directory: {}\n",
+            dir.path().display()
+        ));
+        let before = task.cache_key().await;
+        assert_eq!(before, task.cache_key().await);
+        std::fs::write(dir.path().join("job.py"), "print('two')\n").unwrap();
+        assert_ne!(before, task.cache_key().await);
+    }
 }
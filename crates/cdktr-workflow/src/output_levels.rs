@@ -0,0 +1,176 @@
+use cdktr_core::exceptions::GenericError;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Level a line of task output is logged at
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum OutputLogLevel {
+    Debug,
+    Info,
+    #[serde(alias = "WARNING")]
+    Warn,
+    Error,
+}
+
+impl From<OutputLogLevel> for log::Level {
+    fn from(level: OutputLogLevel) -> Self {
+        match level {
+            OutputLogLevel::Debug => log::Level::Debug,
+            OutputLogLevel::Info => log::Level::Info,
+            OutputLogLevel::Warn => log::Level::Warn,
+            OutputLogLevel::Error => log::Level::Error,
+        }
+    }
+}
+
+/// Logs the lines of task output matching `pattern` at `level`, whichever stream they
+/// were written to, e.g. to pick up levels the program writes into its own output
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct LogLevelPattern {
+    pub(crate) pattern: String,
+    pub(crate) level: OutputLogLevel,
+}
+
+/// Resolves the level each line of the output of a task is logged at. Lines matching
+/// one of the patterns are logged at the level of the first that matches, the rest at
+/// the level of the stream they were written to
+#[derive(Debug, Clone)]
+pub struct OutputLogLevels {
+    stdout: OutputLogLevel,
+    stderr: OutputLogLevel,
+    patterns: Vec<(Regex, OutputLogLevel)>,
+}
+
+impl Default for OutputLogLevels {
+    fn default() -> Self {
+        Self {
+            stdout: OutputLogLevel::Info,
+            stderr: OutputLogLevel::Error,
+            patterns: Vec::new(),
+        }
+    }
+}
+
+impl OutputLogLevels {
+    pub fn new(
+        stdout: Option<OutputLogLevel>,
+        stderr: Option<OutputLogLevel>,
+        patterns: &[LogLevelPattern],
+    ) -> Result<Self, GenericError> {
+        let default = Self::default();
+        let patterns = patterns
+            .iter()
+            .map(|p| {
+                Regex::new(&p.pattern).map(|re| (re, p.level)).map_err(|e| {
+                    GenericError::WorkflowError(format!(
+                        "Invalid log level pattern '{}': {}",
+                        p.pattern, e
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            stdout: stdout.unwrap_or(default.stdout),
+            stderr: stderr.unwrap_or(default.stderr),
+            patterns,
+        })
+    }
+
+    pub fn stdout_level(&self, line: &str) -> OutputLogLevel {
+        self.pattern_level(line).unwrap_or(self.stdout)
+    }
+
+    pub fn stderr_level(&self, line: &str) -> OutputLogLevel {
+        self.pattern_level(line).unwrap_or(self.stderr)
+    }
+
+    fn pattern_level(&self, line: &str) -> Option<OutputLogLevel> {
+        self.patterns
+            .iter()
+            .find(|(re, _)| re.is_match(line))
+            .map(|(_, level)| *level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Workflow;
+
+    fn workflow(task_levels: &str) -> Result<Workflow, GenericError> {
+        let yaml = format!(
+            r#"
+name: Noisy Flow
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  build:
+    name: Build
+{task_levels}
+    config:
+      !Subprocess
+      cmd: make
+      args: ["all"]
+"#
+        );
+        Workflow::new("fake/path/noisy.yml".to_string(), &yaml)
+    }
+
+    #[test]
+    fn test_default_output_levels() {
+        let levels = workflow("")
+            .unwrap()
+            .get_task("build")
+            .unwrap()
+            .output_log_levels()
+            .unwrap();
+        assert_eq!(levels.stdout_level("compiling"), OutputLogLevel::Info);
+        assert_eq!(levels.stderr_level("compiling"), OutputLogLevel::Error);
+    }
+
+    #[test]
+    fn test_stderr_logged_at_configured_level() {
+        let levels = workflow(
+            r#"    stderr_log_level: WARN
+    stdout_log_level: DEBUG
+    log_level_patterns:
+      - pattern: "^error(\\[E[0-9]+\\])?:"
+        level: ERROR
+      - pattern: "^info:"
+        level: INFO"#,
+        )
+        .unwrap()
+        .get_task("build")
+        .unwrap()
+        .output_log_levels()
+        .unwrap();
+        assert_eq!(levels.stderr_level("Compiling cdktr"), OutputLogLevel::Warn);
+        assert_eq!(
+            levels.stdout_level("Compiling cdktr"),
+            OutputLogLevel::Debug
+        );
+        // the first matching pattern wins on either stream
+        assert_eq!(
+            levels.stderr_level("error[E0308]: mismatched types"),
+            OutputLogLevel::Error
+        );
+        assert_eq!(levels.stdout_level("info: done"), OutputLogLevel::Info);
+        assert_eq!(log::Level::from(OutputLogLevel::Warn), log::Level::Warn);
+    }
+
+    #[test]
+    fn test_invalid_pattern_rejected() {
+        let err = workflow(
+            r#"    log_level_patterns:
+      - pattern: "(unclosed"
+        level: WARN"#,
+        )
+        .unwrap()
+        .validate()
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("invalid log level pattern"),
+            "{err}"
+        );
+    }
+}
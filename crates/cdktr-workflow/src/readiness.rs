@@ -0,0 +1,120 @@
+use cdktr_core::exceptions::GenericError;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const DEFAULT_READINESS_TIMEOUT_S: u64 = 60;
+const DEFAULT_READINESS_INTERVAL_MS: u64 = 500;
+
+/// Check that a task starting a service has to pass before the task counts as running
+/// and the tasks depending on it start. Exactly one of `tcp`, `http` or `log_line` is set
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Readiness {
+    /// `host:port` that accepts connections once the task is ready
+    tcp: Option<String>,
+    /// URL that responds with a 2xx status once the task is ready
+    http: Option<String>,
+    /// pattern matching a line the task writes to stdout once it is ready
+    log_line: Option<String>,
+    /// how long to wait for the probe to pass before failing the task. Defaults to 60
+    timeout_s: Option<u64>,
+    /// how often the tcp and http probes are tried. Defaults to 500
+    interval_ms: Option<u64>,
+}
+
+/// The check a `Readiness` makes
+#[derive(Debug, Clone)]
+pub enum ReadinessProbe {
+    Tcp(String),
+    Http(String),
+    LogLine(Regex),
+}
+
+impl Readiness {
+    pub fn probe(&self) -> Result<ReadinessProbe, GenericError> {
+        match (&self.tcp, &self.http, &self.log_line) {
+            (Some(address), None, None) => Ok(ReadinessProbe::Tcp(address.clone())),
+            (None, Some(url), None) => {
+                if url.starts_with("http://") || url.starts_with("https://") {
+                    Ok(ReadinessProbe::Http(url.clone()))
+                } else {
+                    Err(GenericError::WorkflowError(format!(
+                        "Readiness url '{url}' must be an http or https URL"
+                    )))
+                }
+            }
+            (None, None, Some(pattern)) => Regex::new(pattern)
+                .map(ReadinessProbe::LogLine)
+                .map_err(|e| {
+                    GenericError::WorkflowError(format!(
+                        "Invalid readiness log_line pattern '{pattern}': {e}"
+                    ))
+                }),
+            _ => Err(GenericError::WorkflowError(
+                "Readiness must set exactly one of tcp, http or log_line".to_string(),
+            )),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_s.unwrap_or(DEFAULT_READINESS_TIMEOUT_S))
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms.unwrap_or(DEFAULT_READINESS_INTERVAL_MS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Workflow;
+
+    fn workflow(readiness: &str) -> Workflow {
+        let yaml = format!(
+            r#"
+name: Service Flow
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  server:
+    name: Server
+    readiness:
+{readiness}
+    config:
+      !Subprocess
+      cmd: ./serve.sh
+      args: []
+  client:
+    name: Client
+    depends: ["server"]
+    config:
+      !Subprocess
+      cmd: ./query.sh
+      args: []
+"#
+        );
+        Workflow::new("fake/path/service.yml".to_string(), &yaml).unwrap()
+    }
+
+    #[test]
+    fn test_readiness_parsed() {
+        let workflow = workflow("      tcp: localhost:8080\n      timeout_s: 5");
+        workflow.validate().unwrap();
+        let readiness = workflow.get_task("server").unwrap().readiness().unwrap();
+        assert_eq!(readiness.timeout().as_secs(), 5);
+        assert_eq!(readiness.interval().as_millis(), 500);
+        assert!(workflow.get_task("client").unwrap().readiness().is_none());
+    }
+
+    #[test]
+    fn test_invalid_readiness_rejected() {
+        for readiness in [
+            "      timeout_s: 5",
+            "      tcp: localhost:8080\n      log_line: Listening",
+            "      http: localhost:8080/health",
+            "      log_line: \"(unclosed\"",
+        ] {
+            let err = workflow(readiness).validate().unwrap_err();
+            assert!(err.to_string().contains("readiness"), "{err}");
+        }
+    }
+}
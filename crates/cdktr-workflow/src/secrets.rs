@@ -0,0 +1,317 @@
+use cdktr_core::{exceptions::GenericError, get_cdktr_setting};
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+use super::models::Task;
+
+/// Replacement for secret values and redact pattern matches in task output
+const REDACTED: &str = "***";
+
+/// Where agents look up the values of secrets referenced by tasks. Secrets are resolved
+/// on the agent at execution time so they never pass through the principal
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecretSource {
+    /// An env file of `NAME=value` lines
+    EnvFile(PathBuf),
+    /// The OS keyring, with secrets stored under this service name
+    Keyring(String),
+    /// A shell command printing the value of the secret named by its first argument
+    Command(String),
+}
+
+impl SecretSource {
+    /// Builds the source from `CDKTR_AGENT_SECRETS_SOURCE` and its related settings
+    pub fn from_config() -> Result<Self, GenericError> {
+        let source = get_cdktr_setting!(CDKTR_AGENT_SECRETS_SOURCE);
+        match source.as_str() {
+            "env_file" => {
+                let path = get_cdktr_setting!(CDKTR_AGENT_SECRETS_ENV_FILE);
+                let path = match std::env::var("HOME") {
+                    Ok(home) => path.replace("$HOME", &home),
+                    Err(_) => path,
+                };
+                Ok(Self::EnvFile(PathBuf::from(path)))
+            }
+            "keyring" => Ok(Self::Keyring(get_cdktr_setting!(
+                CDKTR_AGENT_SECRETS_KEYRING_SERVICE
+            ))),
+            "command" => {
+                let cmd = get_cdktr_setting!(CDKTR_AGENT_SECRETS_COMMAND);
+                if cmd.is_empty() {
+                    Err(GenericError::ParseError(
+                        "CDKTR_AGENT_SECRETS_COMMAND must be set to use the command secrets source"
+                            .to_string(),
+                    ))
+                } else {
+                    Ok(Self::Command(cmd))
+                }
+            }
+            other => Err(GenericError::ParseError(format!(
+                "Unknown secrets source '{}'. Expected one of env_file, keyring or command",
+                other
+            ))),
+        }
+    }
+
+    /// Looks up the values of the given secrets. Errors name the secret but never
+    /// include any value
+    pub async fn get_secrets(
+        &self,
+        names: &[String],
+    ) -> Result<HashMap<String, String>, GenericError> {
+        let mut values = HashMap::new();
+        match self {
+            Self::EnvFile(path) => {
+                let env = read_env_file(path).await?;
+                for name in names {
+                    match env.get(name) {
+                        Some(value) => values.insert(name.clone(), value.clone()),
+                        None => {
+                            return Err(GenericError::WorkflowError(format!(
+                                "Secret '{}' is not defined in {}",
+                                name,
+                                path.display()
+                            )));
+                        }
+                    };
+                }
+            }
+            Self::Keyring(service) => {
+                for name in names {
+                    values.insert(name.clone(), keyring_lookup(service, name).await?);
+                }
+            }
+            Self::Command(cmd) => {
+                for name in names {
+                    let output = Command::new("sh")
+                        .args(["-c", cmd, "cdktr-secret", name])
+                        .output()
+                        .await;
+                    values.insert(name.clone(), command_output(name, output)?);
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// Resolves the secrets a task references, returning the task ready to run along with
+    /// the secret values so they can be redacted from its output
+    pub async fn resolve(&self, task: &Task) -> Result<(Task, Vec<String>), GenericError> {
+        let names = task.secret_refs();
+        if names.is_empty() {
+            return Ok((task.clone(), Vec::new()));
+        }
+        let values = self.get_secrets(&names).await?;
+        let resolved = task.with_secrets(&values)?;
+        Ok((resolved, values.into_values().collect()))
+    }
+}
+
+/// Replaces any occurrence of the secret values in a line of output
+pub fn redact(line: &str, secrets: &[String]) -> String {
+    let mut secrets: Vec<&String> = secrets.iter().filter(|s| !s.is_empty()).collect();
+    // longest first so a secret containing another is redacted whole
+    secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+    let mut line = line.to_string();
+    for secret in secrets {
+        line = line.replace(secret.as_str(), REDACTED);
+    }
+    line
+}
+
+/// What is redacted from the output of a task: the values of its secrets and the matches
+/// of its `redact` patterns
+#[derive(Debug, Clone, Default)]
+pub struct Redaction {
+    secrets: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+impl Redaction {
+    pub fn new(secrets: Vec<String>, patterns: Vec<Regex>) -> Self {
+        Self { secrets, patterns }
+    }
+
+    /// Replaces the secret values and then the pattern matches in a line of output
+    pub fn apply(&self, line: String) -> String {
+        if self.secrets.is_empty() && self.patterns.is_empty() {
+            return line;
+        }
+        let mut line = redact(&line, &self.secrets);
+        for pattern in &self.patterns {
+            line = pattern.replace_all(&line, REDACTED).into_owned();
+        }
+        line
+    }
+}
+
+async fn read_env_file(path: &Path) -> Result<HashMap<String, String>, GenericError> {
+    let contents = tokio::fs::read_to_string(path).await.map_err(|e| {
+        GenericError::WorkflowError(format!(
+            "Unable to read secrets file {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    Ok(parse_env_file(&contents))
+}
+
+fn parse_env_file(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (name, value) = line.split_once('=')?;
+            let value = value.trim();
+            let value = ['"', '\'']
+                .iter()
+                .find_map(|q| value.strip_prefix(*q).and_then(|v| v.strip_suffix(*q)))
+                .unwrap_or(value);
+            Some((name.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(target_os = "macos")]
+async fn keyring_lookup(service: &str, name: &str) -> Result<String, GenericError> {
+    let output = Command::new("security")
+        .args(["find-generic-password", "-s", service, "-a", name, "-w"])
+        .output()
+        .await;
+    command_output(name, output)
+}
+
+#[cfg(not(target_os = "macos"))]
+async fn keyring_lookup(service: &str, name: &str) -> Result<String, GenericError> {
+    let output = Command::new("secret-tool")
+        .args(["lookup", "service", service, "account", name])
+        .output()
+        .await;
+    command_output(name, output)
+}
+
+fn command_output(
+    name: &str,
+    output: std::io::Result<std::process::Output>,
+) -> Result<String, GenericError> {
+    match output {
+        Ok(output) if output.status.success() => {
+            let value = String::from_utf8_lossy(&output.stdout);
+            Ok(value.trim_end_matches(['\r', '\n']).to_string())
+        }
+        Ok(output) => Err(GenericError::WorkflowError(format!(
+            "Unable to resolve secret '{}': {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+        Err(e) => Err(GenericError::WorkflowError(format!(
+            "Unable to resolve secret '{}': {}",
+            name, e
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task_with_secrets() -> Task {
+        serde_norway::from_str(
+            r#"
+name: deploy
+secrets: [API_KEY]
+config: !Subprocess
+  cmd: curl
+  args: ["-u", "admin:${secret.PASSWORD}"]
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let env = parse_env_file(
+            "# comment\nAPI_KEY=abc123\nexport PASSWORD=\"p@ss=word\"\n\nTOKEN='x y'\n",
+        );
+        assert_eq!(env.get("API_KEY").unwrap(), "abc123");
+        assert_eq!(env.get("PASSWORD").unwrap(), "p@ss=word");
+        assert_eq!(env.get("TOKEN").unwrap(), "x y");
+        assert_eq!(env.len(), 3);
+    }
+
+    #[test]
+    fn test_redact() {
+        let secrets = vec!["abc".to_string(), "abcdef".to_string(), "".to_string()];
+        assert_eq!(redact("key=abcdef and abc", &secrets), "key=*** and ***");
+        assert_eq!(redact("nothing here", &secrets), "nothing here");
+    }
+
+    #[test]
+    fn test_redaction_patterns() {
+        let redaction = Redaction::new(
+            vec!["abcdef".to_string()],
+            vec![
+                Regex::new(r"\b\d{4}-\d{4}-\d{4}-\d{4}\b").unwrap(),
+                Regex::new(r"token=[^&\s]+").unwrap(),
+            ],
+        );
+        assert_eq!(
+            redaction.apply("card 4111-1111-1111-1111 key abcdef".to_string()),
+            "card *** key ***"
+        );
+        assert_eq!(
+            redaction.apply("GET https://api?token=t0k&page=2".to_string()),
+            "GET https://api?***&page=2"
+        );
+        assert_eq!(Redaction::default().apply("as is".to_string()), "as is");
+    }
+
+    #[tokio::test]
+    async fn test_resolve_from_env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.env");
+        std::fs::write(&path, "API_KEY=abc123\nPASSWORD=hunter2\n").unwrap();
+        let source = SecretSource::EnvFile(path);
+
+        let task = task_with_secrets();
+        assert_eq!(task.secret_refs(), vec!["API_KEY", "PASSWORD"]);
+        let (resolved, mut values) = source.resolve(&task).await.unwrap();
+        values.sort();
+        assert_eq!(values, vec!["abc123", "hunter2"]);
+        let config = serde_json::to_value(resolved.get_exe_task()).unwrap();
+        assert_eq!(
+            config["Subprocess"]["args"],
+            serde_json::json!(["-u", "admin:hunter2"])
+        );
+        assert_eq!(
+            config["Subprocess"]["env"],
+            serde_json::json!({"API_KEY": "abc123"})
+        );
+        // secrets aren't substituted outside of the config
+        assert_eq!(resolved.name(), "deploy");
+    }
+
+    #[tokio::test]
+    async fn test_resolve_missing_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.env");
+        std::fs::write(&path, "API_KEY=abc123\n").unwrap();
+        let err = SecretSource::EnvFile(path)
+            .resolve(&task_with_secrets())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("PASSWORD"));
+        assert!(!err.to_string().contains("abc123"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_from_command() {
+        let source = SecretSource::Command("echo \"value-of-$1\"".to_string());
+        let values = source.get_secrets(&["API_KEY".to_string()]).await.unwrap();
+        assert_eq!(values.get("API_KEY").unwrap(), "value-of-API_KEY");
+    }
+}
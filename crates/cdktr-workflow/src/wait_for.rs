@@ -0,0 +1,107 @@
+use cdktr_core::exceptions::GenericError;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const DEFAULT_WAIT_FOR_TIMEOUT_S: u64 = 3_600;
+const DEFAULT_WAIT_FOR_INTERVAL_MS: u64 = 5_000;
+
+/// External condition a task waits for before its command is started, e.g. an input file
+/// landing or a lock being released. Exactly one of `file` or `command` is set
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WaitFor {
+    /// path that has to exist
+    file: Option<String>,
+    /// command line, run through the agent's shell, that has to exit with 0
+    command: Option<String>,
+    /// how long to wait for the condition before failing the task. Defaults to 3600
+    timeout_s: Option<u64>,
+    /// how often the condition is checked. Defaults to 5000
+    interval_ms: Option<u64>,
+}
+
+/// The check a `WaitFor` makes
+#[derive(Debug, Clone, PartialEq)]
+pub enum WaitCondition {
+    File(String),
+    Command(String),
+}
+
+impl WaitFor {
+    pub fn condition(&self) -> Result<WaitCondition, GenericError> {
+        match (&self.file, &self.command) {
+            (Some(path), None) if !path.is_empty() => Ok(WaitCondition::File(path.clone())),
+            (None, Some(command)) if !command.is_empty() => {
+                Ok(WaitCondition::Command(command.clone()))
+            }
+            _ => Err(GenericError::WorkflowError(
+                "wait_for must set exactly one of file or command".to_string(),
+            )),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_s.unwrap_or(DEFAULT_WAIT_FOR_TIMEOUT_S))
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms.unwrap_or(DEFAULT_WAIT_FOR_INTERVAL_MS))
+    }
+
+    /// Copy with `placeholder` replaced by `value` in the file path and command
+    pub(crate) fn substitute(&self, placeholder: &str, value: &str) -> Self {
+        Self {
+            file: self.file.as_ref().map(|f| f.replace(placeholder, value)),
+            command: self.command.as_ref().map(|c| c.replace(placeholder, value)),
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Workflow;
+
+    fn workflow(wait_for: &str) -> Workflow {
+        let yaml = format!(
+            r#"
+name: Load Feed
+start_time: 2025-01-20T12:30:00+00:00
+tasks:
+  load:
+    name: Load
+    wait_for:
+{wait_for}
+    config:
+      !Subprocess
+      cmd: ./load.sh
+      args: []
+"#
+        );
+        Workflow::new("fake/path/feed.yml".to_string(), &yaml).unwrap()
+    }
+
+    #[test]
+    fn test_wait_for_parsed() {
+        let workflow = workflow("      file: /data/feed.csv\n      timeout_s: 60");
+        workflow.validate().unwrap();
+        let wait_for = workflow.get_task("load").unwrap().wait_for().unwrap();
+        assert_eq!(
+            wait_for.condition().unwrap(),
+            WaitCondition::File("/data/feed.csv".to_string())
+        );
+        assert_eq!(wait_for.timeout().as_secs(), 60);
+        assert_eq!(wait_for.interval().as_millis(), 5_000);
+    }
+
+    #[test]
+    fn test_invalid_wait_for_rejected() {
+        for wait_for in [
+            "      timeout_s: 5",
+            "      file: /data/feed.csv\n      command: test -f /data/lock",
+        ] {
+            let err = workflow(wait_for).validate().unwrap_err();
+            assert!(err.to_string().contains("wait_for"), "{err}");
+        }
+    }
+}
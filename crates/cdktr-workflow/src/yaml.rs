@@ -0,0 +1,117 @@
+use serde::de::DeserializeOwned;
+use serde_norway::Value;
+
+/// Deserializes a YAML definition, expanding `<<: *anchor` merge keys first so that a
+/// mapping can be shared between tasks and extended or overridden key by key. Definitions
+/// without merge keys are read directly so errors keep their line and column
+pub fn from_yaml<T: DeserializeOwned>(contents: &str) -> Result<T, String> {
+    let mut value: Value =
+        serde_norway::from_str(contents).map_err(|e| describe_error(contents, e))?;
+    if !has_merge_keys(&value) {
+        return serde_norway::from_str(contents).map_err(|e| describe_error(contents, e));
+    }
+    value
+        .apply_merge()
+        .map_err(|e| format!("invalid merge key: {}", e))?;
+    serde_norway::from_value(value).map_err(|e| e.to_string())
+}
+
+fn has_merge_keys(value: &Value) -> bool {
+    match value {
+        Value::Mapping(mapping) => {
+            mapping.contains_key("<<") || mapping.values().any(has_merge_keys)
+        }
+        Value::Sequence(sequence) => sequence.iter().any(has_merge_keys),
+        Value::Tagged(tagged) => has_merge_keys(&tagged.value),
+        _ => false,
+    }
+}
+
+/// Names the alias when the error is an alias whose anchor isn't defined before it
+fn describe_error(contents: &str, e: serde_norway::Error) -> String {
+    if !e.to_string().starts_with("unknown anchor") {
+        return e.to_string();
+    }
+    let alias = e.location().and_then(|location| {
+        let line = contents.lines().nth(location.line().checked_sub(1)?)?;
+        let rest = line.get(location.column().checked_sub(1)?..)?;
+        let name: String = rest
+            .strip_prefix('*')?
+            .chars()
+            .take_while(|c| !c.is_whitespace() && !",[]{}".contains(*c))
+            .collect();
+        Some(name)
+    });
+    match alias {
+        Some(name) => format!(
+            "alias *{name} refers to an anchor that isn't defined. Anchors have to be defined with &{name} before they are used ({e})"
+        ),
+        None => format!("alias refers to an anchor that isn't defined ({e})"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Workflow;
+    use crate::executors::ExecutableTask;
+
+    const YAML: &str = r#"
+name: Load Tables
+start_time: 2025-01-20T12:30:00+00:00
+x-load: &load
+  cmd: ./load.sh
+  args: [orders]
+  env:
+    WAREHOUSE: prod
+x-task: &task
+  retries: 2
+  config: !Subprocess
+    <<: *load
+tasks:
+  orders:
+    <<: *task
+    name: Load Orders
+  customers:
+    <<: *task
+    name: Load Customers
+    retries: 5
+    depends: [orders]
+    config: !Subprocess
+      <<: *load
+      args: [customers]
+"#;
+
+    #[test]
+    fn test_anchor_merged_into_tasks() {
+        let workflow = Workflow::new("fake/path/tables.yml".to_string(), YAML).unwrap();
+        workflow.validate().unwrap();
+        for (task_id, name, retries, table) in [
+            ("orders", "Load Orders", 2, "orders"),
+            ("customers", "Load Customers", 5, "customers"),
+        ] {
+            let task = workflow.get_task(task_id).unwrap();
+            assert_eq!(task.name(), name);
+            assert_eq!(task.retries(), retries);
+            match task.get_exe_task() {
+                ExecutableTask::Subprocess(subprocess) => {
+                    assert_eq!(subprocess.cmd, "./load.sh");
+                    assert_eq!(subprocess.args, vec![table.to_string()]);
+                    assert_eq!(subprocess.env.unwrap()["WAREHOUSE"], "prod");
+                }
+                other => panic!("unexpected config {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_undefined_anchor_named_in_error() {
+        let yaml = YAML.replace(
+            "<<: *task\n    name: Load Customers",
+            "<<: *tsak\n    name: Load Customers",
+        );
+        let err = Workflow::new("fake/path/tables.yml".to_string(), &yaml).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("alias *tsak"), "{msg}");
+        assert!(msg.contains("line 18"), "{msg}");
+    }
+}
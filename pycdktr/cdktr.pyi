@@ -4,7 +4,7 @@ Type stubs for the cdktr Python module.
 This module provides Python bindings for the cdktr (Cloud DevKit Task Runner) API.
 """
 
-from typing import Optional
+from typing import Any, Callable, Dict, Iterator, Optional
 
 class Result:
     """
@@ -14,17 +14,21 @@ class Result:
         success: Whether the operation succeeded.
         error: Optional error message if the operation failed.
         payload: Optional payload data from successful operations.
+        not_found: Whether the operation failed because the workflow, workflow run
+            or agent it was given doesn't exist.
     """
 
     success: bool
     error: Optional[str]
     payload: Optional[ str | dict | list | float | int]
+    not_found: bool
 
     def __init__(
         self,
         success: bool,
         error: Optional[str] = None,
-        payload: Optional[ str | dict | list | float | int] = None
+        payload: Optional[ str | dict | list | float | int] = None,
+        not_found: bool = False
     ) -> None:
         """
         Create a new Result.
@@ -33,6 +37,7 @@ class Result:
             success: Whether the operation succeeded.
             error: Optional error message.
             payload: Optional payload data.
+            not_found: Whether the requested id doesn't exist.
         """
         ...
 
@@ -40,6 +45,35 @@ class Result:
         """Return a string representation of the Result."""
         ...
 
+class LogStream:
+    """
+    Iterator over the results of a log query, returned by `Principal.stream_logs`.
+
+    Logs are read from the principal a page at a time as the iterator reaches them,
+    so large result sets are never held in memory at once. Each log is a dict of its
+    fields: workflow_id, workflow_name, workflow_instance_id, task_name,
+    task_instance_id, timestamp_ms, level and payload.
+    """
+
+    def __iter__(self) -> Iterator[Dict[str, Any]]: ...
+    def __next__(self) -> Dict[str, Any]: ...
+
+class CompletionWatch:
+    """
+    Handle to a callback registered with `Principal.on_complete`.
+    """
+
+    @property
+    def done(self) -> bool:
+        """Whether the callback has been called or the watch was cancelled."""
+        ...
+
+    def cancel(self) -> None:
+        """Stop waiting for the run. The callback isn't called unless it already has been."""
+        ...
+
+    def __repr__(self) -> str: ...
+
 class Principal:
     """
     Python wrapper for the Principal API client.
@@ -76,15 +110,74 @@ class Principal:
         """
         ...
 
-    def run_workflow(self, workflow_id: str) -> Result:
+    def run_workflow(
+        self,
+        workflow_id: str,
+        params: Optional[Dict[str, str]] = None,
+        dry_run: bool = False,
+        reject_if_no_agents: bool = False,
+    ) -> Result:
         """
         Run a workflow by ID.
 
         Args:
             workflow_id: The ID of the workflow to run.
+            params: Values for the params declared by the workflow. The run is
+                rejected if a param is unknown, missing or of the wrong type.
+            dry_run: Log the resolved command of each task in DAG order instead
+                of running it. The run is still reported as COMPLETED.
+            reject_if_no_agents: Refuse the run with a retryable error when no
+                agents are registered instead of holding it on the queue as WAITING.
+
+        Returns:
+            Result indicating whether the workflow was started successfully, with the
+            instance ID of the run as payload.
+        """
+        ...
+
+    def run_and_wait(
+        self,
+        workflow_id: str,
+        timeout_s: int = 3600,
+        params: Optional[Dict[str, str]] = None,
+    ) -> Result:
+        """
+        Run a workflow and block until the run completes, fails or crashes.
+
+        Args:
+            workflow_id: The ID of the workflow to run.
+            timeout_s: How long to wait for the run to finish, in seconds.
+            params: Values for the params declared by the workflow.
+
+        Returns:
+            Result that succeeds only if the run completed, with the result of the run
+            as payload, as returned by get_workflow_result. If the run hasn't finished
+            within timeout_s the payload is its last known result and the run carries
+            on in the background.
+        """
+        ...
+
+    def on_complete(
+        self,
+        instance_id: str,
+        callback: Callable[[Result], Any],
+        timeout_s: int = 86400,
+    ) -> CompletionWatch:
+        """
+        Call `callback` once a run completes, fails or crashes, without blocking.
+
+        The run is waited for on a background thread, which only holds the GIL while
+        the callback runs. Exceptions raised by the callback are printed.
+
+        Args:
+            instance_id: The instance ID of the workflow run.
+            callback: Called with a Result that succeeds only if the run completed, with
+                the result of the run as payload, as for run_and_wait. If the run hasn't
+                finished within timeout_s it is called with the last known result.
+            timeout_s: How long to wait for the run to finish, in seconds.
 
         Returns:
-            Result indicating whether the workflow was started successfully.
+            CompletionWatch that can cancel the callback.
         """
         ...
 
@@ -107,7 +200,35 @@ class Principal:
             verbose: Whether to include verbose log details. Defaults to False.
 
         Returns:
-            Result with payload containing JSON array of log entries.
+            Result with payload containing a list of formatted log lines. Timestamps are
+            displayed using CDKTR_LOG_TIMEZONE and CDKTR_LOG_TIMESTAMP_FORMAT of this process.
+        """
+        ...
+
+    def stream_logs(
+        self,
+        start_timestamp_ms: Optional[int] = None,
+        end_timestamp_ms: Optional[int] = None,
+        workflow_id: Optional[str] = None,
+        workflow_instance_id: Optional[str] = None,
+        page_size: int = 1000
+    ) -> LogStream:
+        """
+        Iterate over the logs of a query, reading them from the principal a page at a time.
+
+        Args:
+            start_timestamp_ms: Optional start timestamp in milliseconds.
+            end_timestamp_ms: Optional end timestamp in milliseconds. Defaults to the
+                time the stream is created.
+            workflow_id: Optional workflow ID to filter logs.
+            workflow_instance_id: Optional workflow instance ID to filter logs.
+            page_size: Number of logs read from the principal per request. Defaults to 1000.
+
+        Returns:
+            LogStream yielding each log as a dict, ordered by timestamp.
+
+        Raises:
+            RuntimeError: If a page can't be read from the principal.
         """
         ...
 
@@ -129,6 +250,122 @@ class Principal:
         """
         ...
 
+    def get_cluster_capacity(self) -> Result:
+        """
+        Get the aggregate capacity of the agent fleet, e.g. to drive an autoscaler.
+
+        Returns:
+            Result with payload containing a JSON object with the number of
+            `agents`, their `total_slots` and `used_slots`, and the number of
+            workflows `queued` on the principal. Agents that don't report their
+            max concurrency add no slots.
+        """
+        ...
+
+    def drain_agent(self, agent_id: str, drained: bool = True) -> Result:
+        """
+        Drain an agent for maintenance. A drained agent finishes the workflows it is
+        running but doesn't fetch any new ones until it is re-enabled.
+
+        Args:
+            agent_id: ID of the agent as shown by `get_registered_agents`
+            drained: True to drain the agent, False to re-enable it
+
+        Returns:
+            Result indicating success, or an error if no agent with the id is registered
+        """
+        ...
+
+    def flush_queue(self, confirm: bool = False) -> Result:
+        """
+        Remove every workflow waiting on the queue, e.g. after a bad bulk submit.
+        Workflows that are already running are unaffected.
+
+        Args:
+            confirm: Must be True for the queue to be flushed
+
+        Returns:
+            Result with the number of workflows removed as its payload
+        """
+        ...
+
+    def annotate_run(self, instance_id: str, key: str, value: str) -> Result:
+        """
+        Annotate a workflow run, e.g. with the incident it's being investigated under.
+        Annotating a key again replaces its value.
+
+        Args:
+            instance_id: The workflow instance ID of the run
+            key: Name of the annotation, up to 64 characters
+            value: Value of the annotation, up to 1024 characters
+
+        Returns:
+            Result indicating success, or an error if the key or value is too long
+            or the instance ID is unknown
+        """
+        ...
+
+    def get_workflow_result(self, instance_id: str) -> Result:
+        """
+        Get the aggregated result of a workflow run.
+
+        Args:
+            instance_id: The workflow instance ID of the run
+
+        Returns:
+            Result with payload containing the workflow status, its duration,
+            whether it breached the workflow's SLA, its annotations and, for each task,
+            its status, exit code, duration and the tail of its output. Fails if the
+            instance ID is unknown.
+        """
+        ...
+
+    def get_tail(self, instance_id: str, n: int = 100) -> Result:
+        """
+        Get the most recent log lines of a workflow run without streaming its logs,
+        e.g. for a quick look at a running workflow.
+
+        Args:
+            instance_id: The workflow instance ID of the run
+            n: Number of lines to get
+
+        Returns:
+            Result with payload containing up to n formatted log lines, oldest
+            first. Fails if the instance ID is unknown.
+        """
+        ...
+
+    def get_audit_log(self, limit: int = 100) -> Result:
+        """
+        Get the latest administrative actions taken on the principal, such as
+        draining agents or flushing the queue, and who took them.
+
+        Args:
+            limit: Maximum number of actions to get
+
+        Returns:
+            Result with payload containing the actions, newest first. Each has its
+            timestamp, the client that took it, the action, what it was taken on and
+            the response code the principal answered with.
+        """
+        ...
+
+    def diagnose_dispatch(self, instance_id: str) -> Result:
+        """
+        Explain why a queued workflow run hasn't been sent to an agent yet.
+
+        Args:
+            instance_id: The workflow instance ID of the run
+
+        Returns:
+            Result with payload containing the run's position on the queue, the
+            number of registered agents, how many of them can run it and how many
+            could be sent it now, the executors no agent runs, the singleton run it
+            is waiting on and the reasons it is held back. Runs that aren't queued
+            report their latest status instead. Fails if the instance ID is unknown.
+        """
+        ...
+
     def __repr__(self) -> str:
         """Return a string representation of the Principal client."""
         ...
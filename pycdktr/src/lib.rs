@@ -1,8 +1,20 @@
-use cdktr_api::{API, PrincipalAPI, models::ClientResponseMessage};
-use pyo3::exceptions::PyRuntimeError;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use cdktr_api::{
+    models::{ClientResponseMessage, LogPage, WorkflowResult},
+    PrincipalAPI, API,
+};
+use cdktr_core::utils::get_instance_id;
+use cdktr_ipc::client::PrincipalClient;
+use cdktr_ipc::log_manager::model::LogMessage;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::types::{PyDict, PyList, PyString};
 use serde_json::Value as JsonValue;
+use tokio::sync::Notify;
 
 /// Result returned from Principal API calls
 #[pyclass]
@@ -13,17 +25,26 @@ pub struct Result {
     pub error: Option<String>,
     #[pyo3(get)]
     pub payload: Option<PyObject>,
+    /// Whether the operation failed because the id it was given doesn't exist
+    #[pyo3(get)]
+    pub not_found: bool,
 }
 
 #[pymethods]
 impl Result {
     #[new]
-    #[pyo3(signature = (success, error=None, payload=None))]
-    fn new(success: bool, error: Option<String>, payload: Option<PyObject>) -> Self {
+    #[pyo3(signature = (success, error=None, payload=None, not_found=false))]
+    fn new(
+        success: bool,
+        error: Option<String>,
+        payload: Option<PyObject>,
+        not_found: bool,
+    ) -> Self {
         Self {
             success,
             error,
             payload,
+            not_found,
         }
     }
 
@@ -37,6 +58,11 @@ impl Result {
                 None => "None".to_string(),
             };
             format!("Result(success=True, payload={})", payload_str)
+        } else if self.not_found {
+            format!(
+                "Result(success=False, not_found=True, error={})",
+                self.error.as_ref().unwrap_or(&"Unknown error".to_string())
+            )
         } else {
             format!(
                 "Result(success=False, error={})",
@@ -95,24 +121,155 @@ impl Result {
                 success: true,
                 error: None,
                 payload: None,
+                not_found: false,
             }),
             ClientResponseMessage::SuccessWithPayload(payload) => Ok(Result {
                 success: true,
                 error: None,
                 payload: Some(json_to_python(py, &payload)?),
+                not_found: false,
+            }),
+            ClientResponseMessage::NotFound(err) => Ok(Result {
+                success: false,
+                error: Some(err),
+                payload: None,
+                not_found: true,
             }),
             ClientResponseMessage::ClientError(err)
             | ClientResponseMessage::ServerError(err)
             | ClientResponseMessage::Unprocessable(err)
+            | ClientResponseMessage::Retryable(err)
             | ClientResponseMessage::NetworkError(err) => Ok(Result {
                 success: false,
                 error: Some(err),
                 payload: None,
+                not_found: false,
             }),
         }
     }
 }
 
+/// Iterator over the results of a log query that reads them from the principal a page
+/// at a time, so that large result sets never have to be held in memory at once
+#[pyclass]
+pub struct LogStream {
+    rt: tokio::runtime::Runtime,
+    end_timestamp_ms: u64,
+    start_timestamp_ms: Option<u64>,
+    workflow_id: Option<String>,
+    workflow_instance_id: Option<String>,
+    page_size: usize,
+    offset: usize,
+    page: VecDeque<PyObject>,
+    exhausted: bool,
+}
+
+impl LogStream {
+    /// Reads the next page of logs from the principal. Each page is its own request on
+    /// a fresh socket, so no connection is held open between pages and an iterator that
+    /// is abandoned part way through doesn't leave anything to clean up
+    fn fetch_page(&mut self, py: Python) -> PyResult<()> {
+        let api = PrincipalAPI::QueryLogs(
+            Some(self.end_timestamp_ms),
+            self.start_timestamp_ms,
+            self.workflow_id.clone(),
+            self.workflow_instance_id.clone().map(Into::into),
+            false,
+            Some(LogPage {
+                offset: self.offset,
+                limit: self.page_size,
+            }),
+        );
+        let payload = match self.rt.block_on(api.send()) {
+            Ok(ClientResponseMessage::SuccessWithPayload(payload)) => payload,
+            Ok(msg) => {
+                return Err(PyRuntimeError::new_err(format!(
+                    "Failed to read logs: {}",
+                    msg.payload()
+                )));
+            }
+            Err(e) => return Err(PyRuntimeError::new_err(e.to_string())),
+        };
+        let logs = json_to_python(py, &payload)?;
+        let logs = logs.downcast_bound::<PyList>(py)?;
+        self.offset += logs.len();
+        self.exhausted = logs.len() < self.page_size;
+        self.page.extend(logs.iter().map(|log| log.unbind()));
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl LogStream {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> PyResult<Option<PyObject>> {
+        if self.page.is_empty() && !self.exhausted {
+            self.fetch_page(py)?;
+        }
+        Ok(self.page.pop_front())
+    }
+}
+
+/// Result of waiting for a run. Succeeds only if the run completed, with the result of
+/// the run as payload
+fn completion_result(
+    py: Python,
+    waited: std::result::Result<WorkflowResult, Result>,
+    timeout_s: u64,
+) -> PyResult<Result> {
+    let result = match waited {
+        Ok(result) => result,
+        Err(failed) => return Ok(failed),
+    };
+    let error = match result.status.as_str() {
+        "COMPLETED" => None,
+        status if result.is_finished() => Some(format!(
+            "Workflow run {} {}",
+            result.workflow_instance_id, status
+        )),
+        status => Some(format!(
+            "Timed out after {}s waiting for workflow run {} - last status {}",
+            timeout_s, result.workflow_instance_id, status
+        )),
+    };
+    let json = serde_json::to_string(&result)
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to read run result: {}", e)))?;
+    Ok(Result {
+        success: error.is_none(),
+        error,
+        payload: Some(json_to_python(py, &json)?),
+        not_found: false,
+    })
+}
+
+/// Handle to a callback registered with `Principal.on_complete`
+#[pyclass]
+pub struct CompletionWatch {
+    cancelled: Arc<Notify>,
+    done: Arc<AtomicBool>,
+}
+
+#[pymethods]
+impl CompletionWatch {
+    /// Stop waiting for the run. The callback isn't called unless it already has been
+    fn cancel(&self) {
+        self.cancelled.notify_one();
+    }
+
+    /// Whether the callback has been called or the watch was cancelled
+    #[getter]
+    fn done(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("CompletionWatch(done={})", self.done())
+    }
+}
+
 /// Python wrapper for the Principal API client
 #[pyclass]
 pub struct Principal {
@@ -144,6 +301,7 @@ impl Principal {
                     success: false,
                     error: Some(e.to_string()),
                     payload: None,
+                    not_found: false,
                 }),
             }
         })
@@ -162,29 +320,138 @@ impl Principal {
                     success: false,
                     error: Some(e.to_string()),
                     payload: None,
+                    not_found: false,
                 }),
             }
         })
     }
 
-    /// Run a workflow by ID
-    fn run_workflow(&self, py: Python, workflow_id: String) -> PyResult<Result> {
+    /// Run a workflow by ID, optionally passing values for the params it declares.
+    /// A dry run logs the command each task would run without running it. With
+    /// `reject_if_no_agents` the run is refused rather than held when no agents are registered
+    #[pyo3(signature = (workflow_id, params=None, dry_run=false, reject_if_no_agents=false))]
+    fn run_workflow(
+        &self,
+        py: Python,
+        workflow_id: String,
+        params: Option<HashMap<String, String>>,
+        dry_run: bool,
+        reject_if_no_agents: bool,
+    ) -> PyResult<Result> {
         let rt = tokio::runtime::Runtime::new()
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
 
         rt.block_on(async {
-            let api = PrincipalAPI::RunTask(workflow_id);
+            let params = params.unwrap_or_default();
+            let api = if dry_run {
+                PrincipalAPI::DryRunTask(workflow_id, params)
+            } else {
+                PrincipalAPI::RunTask(workflow_id, params, reject_if_no_agents, None)
+            };
             match api.send().await {
                 Ok(msg) => Result::from_response_with_py(py, msg),
                 Err(e) => Ok(Result {
                     success: false,
                     error: Some(e.to_string()),
                     payload: None,
+                    not_found: false,
                 }),
             }
         })
     }
 
+    /// Run a workflow and wait up to `timeout_s` for it to finish. Succeeds only if the run
+    /// completed. The payload is the result of the run, which is still going if it timed out
+    #[pyo3(signature = (workflow_id, timeout_s=3600, params=None))]
+    fn run_and_wait(
+        &self,
+        py: Python,
+        workflow_id: String,
+        timeout_s: u64,
+        params: Option<HashMap<String, String>>,
+    ) -> PyResult<Result> {
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+        let failed = |error: String| Result {
+            success: false,
+            error: Some(error),
+            payload: None,
+            not_found: false,
+        };
+
+        // other python threads can run while this one waits
+        let waited = py.allow_threads(|| {
+            rt.block_on(async {
+                let api =
+                    PrincipalAPI::RunTask(workflow_id, params.unwrap_or_default(), false, None);
+                let instance_id = match api.send().await {
+                    Ok(ClientResponseMessage::SuccessWithPayload(instance_id)) => instance_id,
+                    Ok(ClientResponseMessage::NotFound(err)) => {
+                        return Err(Result {
+                            not_found: true,
+                            ..failed(err)
+                        })
+                    }
+                    Ok(other) => return Err(failed(other.payload())),
+                    Err(e) => return Err(failed(e.to_string())),
+                };
+                PrincipalClient::wait_for_completion(&instance_id, Duration::from_secs(timeout_s))
+                    .await
+                    .map_err(|e| failed(e.to_string()))
+            })
+        });
+        completion_result(py, waited, timeout_s)
+    }
+
+    /// Call `callback` with the result of a run once it completes, fails or crashes,
+    /// without blocking. The run is waited for on a background thread that only takes
+    /// the GIL to call the callback
+    #[pyo3(signature = (instance_id, callback, timeout_s=86400))]
+    fn on_complete(
+        &self,
+        instance_id: String,
+        callback: PyObject,
+        timeout_s: u64,
+    ) -> PyResult<CompletionWatch> {
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+        let watch = CompletionWatch {
+            cancelled: Arc::new(Notify::new()),
+            done: Arc::new(AtomicBool::new(false)),
+        };
+        let (cancelled, done) = (watch.cancelled.clone(), watch.done.clone());
+        std::thread::spawn(move || {
+            let waited = rt.block_on(async {
+                tokio::select! {
+                    waited = PrincipalClient::wait_for_completion(
+                        &instance_id,
+                        Duration::from_secs(timeout_s),
+                    ) => Some(waited),
+                    _ = cancelled.notified() => None,
+                }
+            });
+            if let Some(waited) = waited {
+                Python::with_gil(|py| {
+                    let waited = waited.map_err(|e| Result {
+                        success: false,
+                        error: Some(e.to_string()),
+                        payload: None,
+                        not_found: false,
+                    });
+                    // errors raised by the callback are printed as there is no caller
+                    // to raise them to
+                    if let Err(e) = completion_result(py, waited, timeout_s)
+                        .and_then(|result| callback.call1(py, (result,)))
+                    {
+                        e.print(py);
+                    }
+                });
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        Ok(watch)
+    }
+
     /// Query logs from the database
     #[pyo3(signature = (start_timestamp_ms=None, end_timestamp_ms=None, workflow_id=None, workflow_instance_id=None, verbose=false))]
     fn query_logs(
@@ -204,20 +471,75 @@ impl Principal {
                 end_timestamp_ms,
                 start_timestamp_ms,
                 workflow_id,
-                workflow_instance_id,
+                workflow_instance_id.map(Into::into),
                 verbose,
+                None,
             );
             match api.send().await {
+                // the principal returns log records, which are formatted here
+                Ok(ClientResponseMessage::SuccessWithPayload(payload)) => {
+                    let logs: Vec<LogMessage> = serde_json::from_str(&payload).map_err(|e| {
+                        PyRuntimeError::new_err(format!("Failed to read logs: {}", e))
+                    })?;
+                    let lines: Vec<String> = logs
+                        .iter()
+                        .map(|l| if verbose { l.format_full() } else { l.format() })
+                        .collect();
+                    Ok(Result {
+                        success: true,
+                        error: None,
+                        payload: Some(lines.into_py(py)),
+                        not_found: false,
+                    })
+                }
                 Ok(msg) => Result::from_response_with_py(py, msg),
                 Err(e) => Ok(Result {
                     success: false,
                     error: Some(e.to_string()),
                     payload: None,
+                    not_found: false,
                 }),
             }
         })
     }
 
+    /// Iterate over the logs of a query, reading them from the principal a page at a time.
+    /// Each log is yielded as a dict of its fields
+    #[pyo3(signature = (start_timestamp_ms=None, end_timestamp_ms=None, workflow_id=None, workflow_instance_id=None, page_size=1000))]
+    fn stream_logs(
+        &self,
+        start_timestamp_ms: Option<u64>,
+        end_timestamp_ms: Option<u64>,
+        workflow_id: Option<String>,
+        workflow_instance_id: Option<String>,
+        page_size: usize,
+    ) -> PyResult<LogStream> {
+        if page_size == 0 {
+            return Err(PyValueError::new_err("page_size must be greater than 0"));
+        }
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+        // the end of the query is fixed up front so that logs written while the stream
+        // is being read don't shift the pages
+        let end_timestamp_ms = end_timestamp_ms.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64
+        });
+        Ok(LogStream {
+            rt,
+            end_timestamp_ms,
+            start_timestamp_ms,
+            workflow_id,
+            workflow_instance_id,
+            page_size,
+            offset: 0,
+            page: VecDeque::new(),
+            exhausted: false,
+        })
+    }
+
     /// Get recent workflow statuses (last 10 workflows)
     fn get_recent_workflow_statuses(&self, py: Python) -> PyResult<Result> {
         let rt = tokio::runtime::Runtime::new()
@@ -231,6 +553,7 @@ impl Principal {
                     success: false,
                     error: Some(e.to_string()),
                     payload: None,
+                    not_found: false,
                 }),
             }
         })
@@ -249,6 +572,182 @@ impl Principal {
                     success: false,
                     error: Some(e.to_string()),
                     payload: None,
+                    not_found: false,
+                }),
+            }
+        })
+    }
+
+    /// Get the total and used workflow slots of the agents and the number of queued workflows
+    fn get_cluster_capacity(&self, py: Python) -> PyResult<Result> {
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+
+        rt.block_on(async {
+            let api = PrincipalAPI::GetClusterCapacity;
+            match api.send().await {
+                Ok(msg) => Result::from_response_with_py(py, msg),
+                Err(e) => Ok(Result {
+                    success: false,
+                    error: Some(e.to_string()),
+                    payload: None,
+                    not_found: false,
+                }),
+            }
+        })
+    }
+
+    /// Stop an agent fetching new workflows while its running workflows finish, or re-enable it
+    #[pyo3(signature = (agent_id, drained=true))]
+    fn drain_agent(&self, py: Python, agent_id: String, drained: bool) -> PyResult<Result> {
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+
+        rt.block_on(async {
+            let api = PrincipalAPI::DrainAgent(agent_id, drained, Some(get_instance_id()));
+            match api.send().await {
+                Ok(msg) => Result::from_response_with_py(py, msg),
+                Err(e) => Ok(Result {
+                    success: false,
+                    error: Some(e.to_string()),
+                    payload: None,
+                    not_found: false,
+                }),
+            }
+        })
+    }
+
+    /// Remove every workflow waiting on the queue. Must be called with confirm=True
+    #[pyo3(signature = (confirm=false))]
+    fn flush_queue(&self, py: Python, confirm: bool) -> PyResult<Result> {
+        if !confirm {
+            return Ok(Result {
+                success: false,
+                error: Some(
+                    "Flushing removes every queued workflow and can't be undone. Pass confirm=True to flush"
+                        .to_string(),
+                ),
+                payload: None,
+                not_found: false,
+            });
+        }
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+
+        rt.block_on(async {
+            match PrincipalAPI::FlushQueue(Some(get_instance_id()))
+                .send()
+                .await
+            {
+                Ok(msg) => Result::from_response_with_py(py, msg),
+                Err(e) => Ok(Result {
+                    success: false,
+                    error: Some(e.to_string()),
+                    payload: None,
+                    not_found: false,
+                }),
+            }
+        })
+    }
+
+    /// Add or replace an annotation of a workflow run
+    fn annotate_run(
+        &self,
+        py: Python,
+        instance_id: String,
+        key: String,
+        value: String,
+    ) -> PyResult<Result> {
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+
+        rt.block_on(async {
+            let api = PrincipalAPI::AnnotateRun(instance_id.into(), key, value);
+            match api.send().await {
+                Ok(msg) => Result::from_response_with_py(py, msg),
+                Err(e) => Ok(Result {
+                    success: false,
+                    error: Some(e.to_string()),
+                    payload: None,
+                    not_found: false,
+                }),
+            }
+        })
+    }
+
+    /// Get the aggregated result of a workflow run by its instance id
+    fn get_workflow_result(&self, py: Python, instance_id: String) -> PyResult<Result> {
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+
+        rt.block_on(async {
+            let api = PrincipalAPI::GetWorkflowResult(instance_id.into());
+            match api.send().await {
+                Ok(msg) => Result::from_response_with_py(py, msg),
+                Err(e) => Ok(Result {
+                    success: false,
+                    error: Some(e.to_string()),
+                    payload: None,
+                    not_found: false,
+                }),
+            }
+        })
+    }
+
+    /// Get the most recent log lines of a workflow run, oldest first
+    #[pyo3(signature = (instance_id, n=100))]
+    fn get_tail(&self, py: Python, instance_id: String, n: usize) -> PyResult<Result> {
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+
+        rt.block_on(async {
+            let api = PrincipalAPI::GetWorkflowTail(instance_id.into(), n);
+            match api.send().await {
+                Ok(msg) => Result::from_response_with_py(py, msg),
+                Err(e) => Ok(Result {
+                    success: false,
+                    error: Some(e.to_string()),
+                    payload: None,
+                    not_found: false,
+                }),
+            }
+        })
+    }
+
+    /// Get the latest administrative actions taken on the principal, newest first
+    #[pyo3(signature = (limit=100))]
+    fn get_audit_log(&self, py: Python, limit: usize) -> PyResult<Result> {
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+
+        rt.block_on(async {
+            let api = PrincipalAPI::GetAuditLog(limit);
+            match api.send().await {
+                Ok(msg) => Result::from_response_with_py(py, msg),
+                Err(e) => Ok(Result {
+                    success: false,
+                    error: Some(e.to_string()),
+                    payload: None,
+                    not_found: false,
+                }),
+            }
+        })
+    }
+
+    /// Explain why a queued workflow run hasn't been sent to an agent yet
+    fn diagnose_dispatch(&self, py: Python, instance_id: String) -> PyResult<Result> {
+        let rt = tokio::runtime::Runtime::new()
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create runtime: {}", e)))?;
+
+        rt.block_on(async {
+            let api = PrincipalAPI::DiagnoseDispatch(instance_id.into());
+            match api.send().await {
+                Ok(msg) => Result::from_response_with_py(py, msg),
+                Err(e) => Ok(Result {
+                    success: false,
+                    error: Some(e.to_string()),
+                    payload: None,
+                    not_found: false,
                 }),
             }
         })
@@ -264,5 +763,7 @@ impl Principal {
 fn cdktr(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Principal>()?;
     m.add_class::<Result>()?;
+    m.add_class::<LogStream>()?;
+    m.add_class::<CompletionWatch>()?;
     Ok(())
 }
@@ -0,0 +1,103 @@
+"""
+Tests for registering a callback for the completion of a workflow run.
+
+A fake principal answers GETWORKFLOWRESULT requests over ZMQ so the tests don't need a
+running cdktr instance. Build the module first with `maturin develop`.
+"""
+
+import json
+import socket
+import threading
+
+import zmq
+
+import cdktr
+
+SEP = "\x01"
+
+
+def free_port() -> int:
+    with socket.socket() as s:
+        s.bind(("127.0.0.1", 0))
+        return s.getsockname()[1]
+
+
+def run_result(status: str) -> dict:
+    return {
+        "workflow_id": "flow",
+        "workflow_instance_id": "run-1",
+        "status": status,
+        "tasks": [],
+    }
+
+
+class FakePrincipal:
+    """Reports the run as running for the first `running_polls` requests and as
+    `final_status` after that"""
+
+    def __init__(self, running_polls: int, final_status: str):
+        self.running_polls = running_polls
+        self.final_status = final_status
+        self.port = free_port()
+        self.requests: list[list[str]] = []
+        self._stop = threading.Event()
+        self._ready = threading.Event()
+        self._thread = threading.Thread(target=self._serve, daemon=True)
+
+    def __enter__(self) -> "FakePrincipal":
+        self._thread.start()
+        self._ready.wait()
+        return self
+
+    def __exit__(self, *_) -> None:
+        self._stop.set()
+        self._thread.join(timeout=5)
+
+    def _serve(self) -> None:
+        ctx = zmq.Context()
+        rep = ctx.socket(zmq.REP)
+        rep.bind(f"tcp://127.0.0.1:{self.port}")
+        self._ready.set()
+        while not self._stop.is_set():
+            if not rep.poll(100):
+                continue
+            args = rep.recv_string().split(SEP)
+            self.requests.append(args)
+            status = (
+                "RUNNING" if len(self.requests) <= self.running_polls else self.final_status
+            )
+            rep.send_string(f"SUCCESS{SEP}{json.dumps(run_result(status))}")
+        rep.close()
+        ctx.term()
+
+
+def test_on_complete_called_with_final_status():
+    results = []
+    called = threading.Event()
+
+    def callback(result):
+        results.append(result)
+        called.set()
+
+    with FakePrincipal(running_polls=1, final_status="FAILED") as principal:
+        client = cdktr.Principal(host="127.0.0.1", port=principal.port)
+        watch = client.on_complete("run-1", callback)
+        # registering doesn't wait for the run
+        assert not watch.done
+        assert called.wait(timeout=10)
+
+    assert len(results) == 1
+    assert not results[0].success
+    assert results[0].payload["status"] == "FAILED"
+    assert [req[0] for req in principal.requests] == ["GETWORKFLOWRESULT"] * 2
+    assert principal.requests[0][1] == "run-1"
+
+
+def test_cancelled_watch_never_calls_back():
+    called = threading.Event()
+    with FakePrincipal(running_polls=1_000, final_status="COMPLETED") as principal:
+        client = cdktr.Principal(host="127.0.0.1", port=principal.port)
+        watch = client.on_complete("run-1", lambda _: called.set())
+        watch.cancel()
+        assert not called.wait(timeout=2)
+    assert watch.done
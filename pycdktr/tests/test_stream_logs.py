@@ -0,0 +1,99 @@
+"""
+Tests for streaming log queries from the principal a page at a time.
+
+A fake principal answers the paged QUERYLOGS requests over ZMQ so the tests don't need
+a running cdktr instance. Build the module first with `maturin develop`.
+"""
+
+import json
+import socket
+import threading
+
+import zmq
+
+import cdktr
+
+SEP = "\x01"
+
+
+def free_port() -> int:
+    with socket.socket() as s:
+        s.bind(("127.0.0.1", 0))
+        return s.getsockname()[1]
+
+
+class FakePrincipal:
+    """Serves paged log queries from an in-memory list of log records"""
+
+    def __init__(self, logs: list[dict]):
+        self.logs = logs
+        self.port = free_port()
+        self.requests: list[list[str]] = []
+        self._ready = threading.Event()
+        self._thread = threading.Thread(target=self._serve, daemon=True)
+
+    def __enter__(self) -> "FakePrincipal":
+        self._thread.start()
+        self._ready.wait()
+        return self
+
+    def __exit__(self, *_) -> None:
+        self._thread.join(timeout=5)
+
+    def _serve(self) -> None:
+        ctx = zmq.Context()
+        rep = ctx.socket(zmq.REP)
+        rep.bind(f"tcp://127.0.0.1:{self.port}")
+        self._ready.set()
+        # answers until the client reads a short page, which ends the stream
+        while True:
+            args = rep.recv_string().split(SEP)
+            self.requests.append(args)
+            offset, limit = int(args[6]), int(args[7])
+            page = self.logs[offset : offset + limit]
+            rep.send_string(f"SUCCESS{SEP}{json.dumps(page)}")
+            if len(page) < limit:
+                break
+        rep.close()
+        ctx.term()
+
+
+def make_logs(n: int) -> list[dict]:
+    return [
+        {
+            "workflow_id": "flow",
+            "workflow_name": "Flow",
+            "workflow_instance_id": "run-1",
+            "task_name": "task",
+            "task_instance_id": "task-1",
+            "timestamp_ms": 1_000 + i,
+            "level": "INFO",
+            "payload": f"line {i}",
+        }
+        for i in range(n)
+    ]
+
+
+def test_stream_logs_reads_every_page():
+    logs = make_logs(250)
+    with FakePrincipal(logs) as principal:
+        client = cdktr.Principal(host="127.0.0.1", port=principal.port)
+        stream = client.stream_logs(workflow_instance_id="run-1", page_size=100)
+        streamed = list(stream)
+    assert streamed == logs
+    # pages are only read as the iterator gets to them
+    assert [req[0] for req in principal.requests] == ["QUERYLOGS"] * 3
+    assert [req[6] for req in principal.requests] == ["0", "100", "200"]
+    # every page queries the same time range so that pages don't shift
+    assert len({req[1] for req in principal.requests}) == 1
+
+
+def test_stream_logs_is_lazy():
+    logs = make_logs(30)
+    with FakePrincipal(logs) as principal:
+        client = cdktr.Principal(host="127.0.0.1", port=principal.port)
+        stream = client.stream_logs(page_size=10)
+        assert principal.requests == []
+        assert next(stream)["payload"] == "line 0"
+        assert len(principal.requests) == 1
+        assert len(list(stream)) == 29